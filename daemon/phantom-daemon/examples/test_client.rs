@@ -23,7 +23,7 @@ async fn main() -> Result<()> {
         .with_custom_certificate_verifier(Arc::new(AcceptAnyCert))
         .with_no_client_auth();
 
-    rustls_config.alpn_protocols = vec![b"phantom/0".to_vec()];
+    rustls_config.alpn_protocols = vec![b"phantom/1".to_vec()];
 
     let client_config = quinn::ClientConfig::new(Arc::new(
         quinn::crypto::rustls::QuicClientConfig::try_from(rustls_config)?,
@@ -85,14 +85,9 @@ async fn main() -> Result<()> {
     let recv_handle = tokio::spawn(async move {
         let mut buf = [0u8; 4096];
         let mut stdout = io::stdout();
-        loop {
-            match recv.read(&mut buf).await {
-                Ok(Some(n)) => {
-                    let _ = stdout.write_all(&buf[..n]);
-                    let _ = stdout.flush();
-                }
-                Ok(None) | Err(_) => break,
-            }
+        while let Ok(Some(n)) = recv.read(&mut buf).await {
+            let _ = stdout.write_all(&buf[..n]);
+            let _ = stdout.flush();
         }
     });
 
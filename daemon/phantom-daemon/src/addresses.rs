@@ -0,0 +1,439 @@
+//! Addresses clients can reach the daemon on, for the pairing payload and
+//! the `server_info` control message.
+//!
+//! Clients race the candidates happy-eyeballs style, in the order given:
+//! the default-route LAN address first, then other IPv4, IPv6 (the
+//! default-route and other global addresses before unique local ones), the
+//! mDNS name, and Tailscale last as the off-LAN fallback. Interfaces are only
+//! listed on Unix: on Windows the candidates are the default-route address
+//! and the mDNS name.
+//!
+//! Where detection finds the wrong addresses, as in a container, whose
+//! interfaces aren't the ones clients reach, `PHANTOM_HOSTS` lists the
+//! candidates instead (comma-separated addresses or names, best first), and
+//! `PHANTOM_HOSTNAME` names the host.
+
+use serde::Serialize;
+#[cfg(unix)]
+use std::ffi::CStr;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Candidate {
+    /// IP address or hostname
+    pub host: String,
+    /// `lan`, `ipv6`, `mdns`, `tailscale`, or `dns` for other names given
+    /// in `PHANTOM_HOSTS`
+    pub kind: &'static str,
+}
+
+/// Every usable address of this host, in the order clients should try them.
+pub fn candidates() -> Vec<Candidate> {
+    if let Some(hosts) = configured() {
+        return hosts;
+    }
+    let primary: Vec<IpAddr> = primary_ip().into_iter().chain(primary_ipv6()).collect();
+    order(&interface_addrs(), &primary, mdns_name().as_deref())
+}
+
+/// The candidates in `PHANTOM_HOSTS`, if set.
+pub fn configured() -> Option<Vec<Candidate>> {
+    parse_hosts(&std::env::var("PHANTOM_HOSTS").ok()?)
+}
+
+fn parse_hosts(list: &str) -> Option<Vec<Candidate>> {
+    let hosts: Vec<Candidate> = list
+        .split(',')
+        .map(str::trim)
+        .filter(|host| !host.is_empty())
+        .map(|host| Candidate { host: host.to_string(), kind: kind_of(host) })
+        .collect();
+    (!hosts.is_empty()).then_some(hosts)
+}
+
+fn kind_of(host: &str) -> &'static str {
+    match host.parse::<IpAddr>() {
+        Ok(addr) if is_tailscale(addr) => "tailscale",
+        Ok(IpAddr::V4(_)) => "lan",
+        Ok(IpAddr::V6(_)) => "ipv6",
+        Err(_) if host.ends_with(".local") => "mdns",
+        Err(_) => "dns",
+    }
+}
+
+/// The address of the interface holding the default route: a UDP socket
+/// "connected" to a public address picks it without sending anything.
+pub fn primary_ip() -> Option<IpAddr> {
+    route_source("0.0.0.0:0", "8.8.8.8:80")
+}
+
+/// The IPv6 address of the interface holding the IPv6 default route, if
+/// the host has one.
+pub fn primary_ipv6() -> Option<IpAddr> {
+    route_source("[::]:0", "[2001:4860:4860::8888]:80").filter(|&addr| usable(addr))
+}
+
+fn route_source(bind: &str, public: &str) -> Option<IpAddr> {
+    let socket = UdpSocket::bind(bind).ok()?;
+    socket.connect(public).ok()?;
+    Some(socket.local_addr().ok()?.ip())
+}
+
+/// `addrs` in the order clients should try them, the default-route
+/// addresses in `primary` first in their family.
+fn order(addrs: &[IpAddr], primary: &[IpAddr], mdns: Option<&str>) -> Vec<Candidate> {
+    let mut lan = Vec::new();
+    let mut ipv6 = Vec::new();
+    let mut tailscale = Vec::new();
+    for &addr in primary.iter().chain(addrs) {
+        if !usable(addr) {
+            continue;
+        }
+        let bucket = if is_tailscale(addr) {
+            &mut tailscale
+        } else if addr.is_ipv4() {
+            &mut lan
+        } else {
+            &mut ipv6
+        };
+        if !bucket.contains(&addr) {
+            bucket.push(addr);
+        }
+    }
+    // Global addresses reach clients off this network; unique local ones
+    // only reach clients on it. Stable, so the default route stays first
+    ipv6.sort_by_key(|&addr| is_unique_local(addr));
+
+    let candidate = |kind| move |addr: IpAddr| Candidate { host: addr.to_string(), kind };
+    let mut out: Vec<Candidate> = lan.into_iter().map(candidate("lan")).collect();
+    out.extend(ipv6.into_iter().map(candidate("ipv6")));
+    if let Some(name) = mdns {
+        out.push(Candidate { host: name.to_string(), kind: "mdns" });
+    }
+    out.extend(tailscale.into_iter().map(candidate("tailscale")));
+    out
+}
+
+/// `host:port`, with IPv6 addresses in brackets.
+pub fn host_port(host: &str, port: u16) -> String {
+    match host.parse::<IpAddr>() {
+        Ok(IpAddr::V6(_)) => format!("[{host}]:{port}"),
+        _ => format!("{host}:{port}"),
+    }
+}
+
+/// Excludes loopback, link-local, unspecified and multicast addresses, and
+/// on IPv6 the deprecated site-local ones and IPv4-mapped ones (the IPv4
+/// address is listed as itself).
+fn usable(addr: IpAddr) -> bool {
+    match addr {
+        IpAddr::V4(v4) => !(v4.is_loopback() || v4.is_link_local() || v4.is_unspecified() || v4.is_multicast()),
+        IpAddr::V6(v6) => {
+            let scoped = matches!(v6.segments()[0] & 0xffc0, 0xfe80 | 0xfec0);
+            let mapped = v6.to_ipv4_mapped().is_some();
+            !(v6.is_loopback() || v6.is_unspecified() || v6.is_multicast() || scoped || mapped)
+        }
+    }
+}
+
+/// fc00::/7, routed only within a site.
+fn is_unique_local(addr: IpAddr) -> bool {
+    matches!(addr, IpAddr::V6(v6) if v6.segments()[0] & 0xfe00 == 0xfc00)
+}
+
+/// Bind the QUIC socket on `addr`. An unspecified IPv6 address binds
+/// dual-stack, taking IPv4 clients as IPv4-mapped addresses, whatever the
+/// system's default for `IPV6_V6ONLY` (on by default on the BSDs, and
+/// settable per host on Linux).
+pub fn bind_udp(addr: SocketAddr) -> std::io::Result<UdpSocket> {
+    match addr {
+        SocketAddr::V6(v6) if v6.ip().is_unspecified() => bind_dual_stack(v6.port()),
+        _ => UdpSocket::bind(addr),
+    }
+}
+
+#[cfg(unix)]
+fn bind_dual_stack(port: u16) -> std::io::Result<UdpSocket> {
+    use std::io::Error;
+    use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+
+    let fd = unsafe { libc::socket(libc::AF_INET6, libc::SOCK_DGRAM, 0) };
+    if fd < 0 {
+        return Err(Error::last_os_error());
+    }
+    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+    let raw = fd.as_raw_fd();
+    let off: libc::c_int = 0;
+    let set = unsafe {
+        libc::fcntl(raw, libc::F_SETFD, libc::FD_CLOEXEC) == 0
+            && libc::setsockopt(
+                raw,
+                libc::IPPROTO_IPV6,
+                libc::IPV6_V6ONLY,
+                &off as *const libc::c_int as *const libc::c_void,
+                std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+            ) == 0
+    };
+    if !set {
+        return Err(Error::last_os_error());
+    }
+    let mut sin6: libc::sockaddr_in6 = unsafe { std::mem::zeroed() };
+    sin6.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+    sin6.sin6_port = port.to_be();
+    #[cfg(any(target_os = "macos", target_os = "freebsd", target_os = "openbsd", target_os = "netbsd"))]
+    {
+        sin6.sin6_len = std::mem::size_of::<libc::sockaddr_in6>() as u8;
+    }
+    let bound = unsafe {
+        libc::bind(
+            raw,
+            &sin6 as *const libc::sockaddr_in6 as *const libc::sockaddr,
+            std::mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t,
+        )
+    };
+    if bound != 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(UdpSocket::from(fd))
+}
+
+#[cfg(not(unix))]
+fn bind_dual_stack(port: u16) -> std::io::Result<UdpSocket> {
+    UdpSocket::bind((Ipv6Addr::UNSPECIFIED, port))
+}
+
+/// Tailscale hands out 100.64.0.0/10 and fd7a:115c:a1e0::/48.
+fn is_tailscale(addr: IpAddr) -> bool {
+    match addr {
+        IpAddr::V4(v4) => {
+            let [a, b, ..] = v4.octets();
+            a == 100 && (64..128).contains(&b)
+        }
+        IpAddr::V6(v6) => v6.segments()[..3] == [0xfd7a, 0x115c, 0xa1e0],
+    }
+}
+
+/// Hardware address of the interface holding the default route, for
+/// Wake-on-LAN.
+#[cfg(unix)]
+pub fn primary_mac() -> Option<[u8; 6]> {
+    let primary = primary_ip()?;
+    let mut interface = None;
+    let mut macs = Vec::new();
+    for_each_interface(|name, addr| {
+        if ip_of(addr) == Some(primary) {
+            interface = Some(name.to_owned());
+        } else if let Some(mac) = mac_of(addr) {
+            macs.push((name.to_owned(), mac));
+        }
+    });
+    let interface = interface?;
+    macs.into_iter()
+        .find(|(name, mac)| *name == interface && *mac != [0; 6])
+        .map(|(_, mac)| mac)
+}
+
+#[cfg(not(unix))]
+pub fn primary_mac() -> Option<[u8; 6]> {
+    None
+}
+
+/// Addresses of interfaces that are up.
+#[cfg(unix)]
+fn interface_addrs() -> Vec<IpAddr> {
+    let mut addrs = Vec::new();
+    for_each_interface(|_, addr| addrs.extend(ip_of(addr)));
+    addrs
+}
+
+#[cfg(not(unix))]
+fn interface_addrs() -> Vec<IpAddr> {
+    Vec::new()
+}
+
+/// Call `f` with the name and address of every address of interfaces that
+/// are up.
+#[cfg(unix)]
+fn for_each_interface(mut f: impl FnMut(&CStr, &libc::sockaddr)) {
+    let mut ifaddrs: *mut libc::ifaddrs = std::ptr::null_mut();
+    if unsafe { libc::getifaddrs(&mut ifaddrs) } != 0 {
+        return;
+    }
+    let mut cursor = ifaddrs;
+    while let Some(ifa) = unsafe { cursor.as_ref() } {
+        cursor = ifa.ifa_next;
+        if ifa.ifa_addr.is_null() || ifa.ifa_flags & libc::IFF_UP as u32 == 0 {
+            continue;
+        }
+        let name = unsafe { CStr::from_ptr(ifa.ifa_name) };
+        f(name, unsafe { &*ifa.ifa_addr });
+    }
+    unsafe { libc::freeifaddrs(ifaddrs) };
+}
+
+#[cfg(unix)]
+fn ip_of(addr: &libc::sockaddr) -> Option<IpAddr> {
+    match addr.sa_family as libc::c_int {
+        libc::AF_INET => {
+            let sin = unsafe { &*(addr as *const libc::sockaddr as *const libc::sockaddr_in) };
+            Some(IpAddr::V4(Ipv4Addr::from(u32::from_be(sin.sin_addr.s_addr))))
+        }
+        libc::AF_INET6 => {
+            let sin6 = unsafe { &*(addr as *const libc::sockaddr as *const libc::sockaddr_in6) };
+            Some(IpAddr::V6(Ipv6Addr::from(sin6.sin6_addr.s6_addr)))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn mac_of(addr: &libc::sockaddr) -> Option<[u8; 6]> {
+    if addr.sa_family as libc::c_int != libc::AF_PACKET {
+        return None;
+    }
+    let sll = unsafe { &*(addr as *const libc::sockaddr as *const libc::sockaddr_ll) };
+    (sll.sll_halen == 6).then(|| sll.sll_addr[..6].try_into().expect("six bytes"))
+}
+
+#[cfg(target_os = "macos")]
+fn mac_of(addr: &libc::sockaddr) -> Option<[u8; 6]> {
+    if addr.sa_family as libc::c_int != libc::AF_LINK {
+        return None;
+    }
+    let sdl = unsafe { &*(addr as *const libc::sockaddr as *const libc::sockaddr_dl) };
+    if sdl.sdl_alen != 6 {
+        return None;
+    }
+    // The link address follows the interface name in sdl_data, which is
+    // longer than declared
+    let data = sdl.sdl_data.as_ptr() as *const u8;
+    let mac = unsafe { std::slice::from_raw_parts(data.add(sdl.sdl_nlen as usize), 6) };
+    mac.try_into().ok()
+}
+
+#[cfg(all(unix, not(any(target_os = "linux", target_os = "macos"))))]
+fn mac_of(_addr: &libc::sockaddr) -> Option<[u8; 6]> {
+    None
+}
+
+/// `<hostname>.local`, as advertised over mDNS/Bonjour.
+fn mdns_name() -> Option<String> {
+    let name = std::env::var("PHANTOM_HOSTNAME").ok().or_else(hostname)?;
+    let short = name.split('.').next().filter(|s| !s.is_empty())?;
+    Some(format!("{short}.local"))
+}
+
+#[cfg(unix)]
+fn hostname() -> Option<String> {
+    let mut buf = [0 as libc::c_char; 256];
+    if unsafe { libc::gethostname(buf.as_mut_ptr(), buf.len()) } != 0 {
+        return None;
+    }
+    unsafe { CStr::from_ptr(buf.as_ptr()) }.to_str().ok().map(str::to_string)
+}
+
+#[cfg(windows)]
+fn hostname() -> Option<String> {
+    std::env::var("COMPUTERNAME").ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn orders_candidates_for_happy_eyeballs() {
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        let addrs = [
+            ip("127.0.0.1"),
+            ip("100.101.102.103"),
+            ip("fe80::1"),
+            ip("10.0.0.5"),
+            ip("2001:db8::5"),
+            ip("192.168.1.20"),
+            ip("fd7a:115c:a1e0::1"),
+            ip("169.254.3.4"),
+        ];
+        let hosts: Vec<(String, &str)> = order(&addrs, &[ip("192.168.1.20")], Some("studio.local"))
+            .into_iter()
+            .map(|c| (c.host, c.kind))
+            .collect();
+        let expected = [
+            ("192.168.1.20", "lan"),
+            ("10.0.0.5", "lan"),
+            ("2001:db8::5", "ipv6"),
+            ("studio.local", "mdns"),
+            ("100.101.102.103", "tailscale"),
+            ("fd7a:115c:a1e0::1", "tailscale"),
+        ];
+        assert_eq!(hosts, expected.map(|(h, k)| (h.to_string(), k)));
+    }
+
+    #[test]
+    fn prefers_global_ipv6_addresses() {
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        let addrs = [
+            ip("fd12:3456::7"),
+            ip("2001:db8::5"),
+            ip("fec0::9"),
+            ip("::ffff:192.168.1.20"),
+            ip("ff02::1"),
+            ip("2a00:1450::1"),
+            ip("192.168.1.20"),
+        ];
+        let hosts: Vec<(String, &str)> = order(&addrs, &[ip("192.168.1.20"), ip("2a00:1450::1")], None)
+            .into_iter()
+            .map(|c| (c.host, c.kind))
+            .collect();
+        let expected = [
+            ("192.168.1.20", "lan"),
+            ("2a00:1450::1", "ipv6"),
+            ("2001:db8::5", "ipv6"),
+            ("fd12:3456::7", "ipv6"),
+        ];
+        assert_eq!(hosts, expected.map(|(h, k)| (h.to_string(), k)));
+        assert_eq!(host_port("2a00:1450::1", 4433), "[2a00:1450::1]:4433");
+        assert_eq!(host_port("studio.local", 4433), "studio.local:4433");
+    }
+
+    #[test]
+    fn unspecified_ipv6_binds_dual_stack() {
+        let socket = bind_udp("[::]:0".parse().unwrap()).unwrap();
+        let port = socket.local_addr().unwrap().port();
+        socket.set_read_timeout(Some(std::time::Duration::from_secs(2))).unwrap();
+
+        // An IPv4 client reaches it, as an IPv4-mapped address
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.send_to(b"v4", ("127.0.0.1", port)).unwrap();
+        let mut buf = [0u8; 8];
+        let (len, from) = socket.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"v4");
+        assert_eq!(from.ip(), IpAddr::V6(Ipv4Addr::LOCALHOST.to_ipv6_mapped()));
+
+        // Specific addresses bind as given
+        let v4 = bind_udp("127.0.0.1:0".parse().unwrap()).unwrap();
+        assert!(v4.local_addr().unwrap().is_ipv4());
+    }
+
+    #[test]
+    fn configured_hosts_keep_their_order() {
+        let hosts = parse_hosts(" 192.168.1.20, devbox.example.com,,fd7a:115c:a1e0::1 ,devbox.local").unwrap();
+        let listed: Vec<_> = hosts.iter().map(|c| (c.host.as_str(), c.kind)).collect();
+        assert_eq!(
+            listed,
+            [
+                ("192.168.1.20", "lan"),
+                ("devbox.example.com", "dns"),
+                ("fd7a:115c:a1e0::1", "tailscale"),
+                ("devbox.local", "mdns"),
+            ]
+        );
+        assert_eq!(parse_hosts(" , "), None);
+    }
+
+    #[test]
+    fn finds_this_hosts_addresses() {
+        // Whatever the sandbox has, loopback never makes the list
+        assert!(candidates().iter().all(|c| c.host != "127.0.0.1" && c.host != "::1"));
+    }
+}
@@ -0,0 +1,109 @@
+//! SSH agent forwarding to the client device.
+//!
+//! A session created with agent forwarding gets its own `SSH_AUTH_SOCK`: a
+//! Unix socket the daemon listens on (a named pipe on Windows, which its
+//! OpenSSH accepts too, see [`crate::local_socket`]). Each connection to it (typically one
+//! per `ssh` invocation) is relayed over a new QUIC stream that the daemon
+//! opens to the device that created the session. The stream starts with a
+//! length-prefixed JSON header, `{"type": "agent_forward", "session_id": ...}`,
+//! after which both directions carry raw SSH agent protocol bytes, answered
+//! by the device with its own (Secure Enclave) keys.
+
+use anyhow::{Context, Result};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::io::AsyncWriteExt;
+use tokio::task::JoinHandle;
+use tracing::{info, warn};
+
+use crate::local_socket::{Listener, Stream};
+
+/// Header `type` of a daemon-opened agent stream.
+pub const STREAM_TYPE: &str = "agent_forward";
+
+/// Finds the QUIC connection of the device that answers agent requests, if
+/// it is currently connected.
+pub type DeviceLookup = Arc<dyn Fn() -> Option<quinn::Connection> + Send + Sync>;
+
+/// Whether a device may still forward its agent, checked on every request so
+/// that denying it takes effect on sessions already running.
+pub type AgentPermission = Arc<dyn Fn(&str) -> bool + Send + Sync>;
+
+/// A session's `SSH_AUTH_SOCK`. Dropping it stops listening and removes the
+/// socket file; relays already in flight run to completion.
+pub struct AgentSocket {
+    path: PathBuf,
+    listener: JoinHandle<()>,
+}
+
+impl AgentSocket {
+    /// Listen at `<dir>/<session_id>.sock`, relaying connections to the
+    /// device found by `device`. `dir` is created private to the user.
+    pub fn bind(dir: &Path, session_id: &str, device: DeviceLookup) -> Result<Self> {
+        std::fs::create_dir_all(dir).context("create agent socket dir")?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(dir, std::fs::Permissions::from_mode(0o700))
+                .context("restrict agent socket dir")?;
+        }
+        // Replacing any leftover from a daemon that didn't shut down cleanly
+        let mut listener = Listener::bind(&dir.join(format!("{session_id}.sock"))).context("bind agent socket")?;
+        let path = listener.address().to_path_buf();
+
+        let session_id = session_id.to_string();
+        let listener = tokio::spawn(async move {
+            loop {
+                let local = match listener.accept().await {
+                    Ok(local) => local,
+                    Err(e) => {
+                        warn!("agent socket accept error for session {session_id}: {e}");
+                        break;
+                    }
+                };
+                let Some(connection) = device() else {
+                    // Dropping the connection fails the request on the ssh side
+                    warn!("agent request for session {session_id}, but its device is not connected");
+                    continue;
+                };
+                let session_id = session_id.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = relay(local, &connection, &session_id).await {
+                        info!("agent relay for session {session_id} ended: {e:#}");
+                    }
+                });
+            }
+        });
+
+        Ok(Self { path, listener })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for AgentSocket {
+    fn drop(&mut self) {
+        self.listener.abort();
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Relay one agent connection over a new stream to the device.
+async fn relay(mut local: impl Stream, connection: &quinn::Connection, session_id: &str) -> Result<()> {
+    let (mut send, recv) = connection.open_bi().await.context("open agent stream")?;
+    let header = serde_json::to_vec(&serde_json::json!({
+        "type": STREAM_TYPE,
+        "session_id": session_id,
+    }))?;
+    send.write_all(&(header.len() as u32).to_be_bytes()).await?;
+    send.write_all(&header).await?;
+
+    let mut remote = tokio::io::join(recv, send);
+    tokio::io::copy_bidirectional(&mut local, &mut remote)
+        .await
+        .context("relay agent traffic")?;
+    remote.shutdown().await.ok();
+    Ok(())
+}
@@ -0,0 +1,255 @@
+//! Portable session archives (`phantom session export` / `import`).
+//!
+//! An archive is zstd-compressed JSON: the session's metadata plus its
+//! scrollback, base64-encoded. Importing one on another machine starts a new
+//! shell with the archived history replayed above its prompt; the original
+//! processes don't travel.
+//!
+//! Archives written with a [`Vault`] are encrypted and only import on the
+//! host that wrote them.
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::io::{Read, Write};
+use std::path::Path;
+
+use crate::device_store::private_file;
+use crate::errors::ErrorCode;
+use crate::vault::Vault;
+
+/// `format` of every archive.
+pub const FORMAT: &str = "phantom-session";
+/// Current archive version; newer versions are refused.
+pub const VERSION: u32 = 1;
+/// Largest accepted decompressed archive.
+const MAX_ARCHIVE_BYTES: u64 = 64 * 1024 * 1024;
+/// zstd level for writing archives.
+const ARCHIVE_LEVEL: i32 = 9;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SessionArchive {
+    pub format: String,
+    pub version: u32,
+    pub exported_at: chrono::DateTime<chrono::Utc>,
+    /// Host the session was exported from
+    pub host: String,
+    pub session: ArchivedSession,
+    #[serde(with = "base64_bytes")]
+    pub scrollback: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ArchivedSession {
+    pub id: String,
+    pub shell: String,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_by_device_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tmux_session: Option<String>,
+    pub last_activity_at: chrono::DateTime<chrono::Utc>,
+}
+
+impl SessionArchive {
+    pub fn new(session: ArchivedSession, scrollback: Vec<u8>) -> Self {
+        Self {
+            format: FORMAT.to_string(),
+            version: VERSION,
+            exported_at: chrono::Utc::now(),
+            host: crate::device_store::hostname(),
+            session,
+            scrollback,
+        }
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let json = serde_json::to_vec(self).context("serialize session archive")?;
+        zstd::bulk::compress(&json, ARCHIVE_LEVEL).context("compress session archive")
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let mut json = Vec::new();
+        zstd::Decoder::new(bytes)
+            .and_then(|decoder| decoder.take(MAX_ARCHIVE_BYTES + 1).read_to_end(&mut json))
+            .context("not a phantom session archive")?;
+        if json.len() as u64 > MAX_ARCHIVE_BYTES {
+            bail!("session archive too large");
+        }
+        let archive: Self = serde_json::from_slice(&json).context("not a phantom session archive")?;
+        if archive.format != FORMAT {
+            bail!("not a phantom session archive");
+        }
+        if archive.version > VERSION {
+            bail!("session archive version {} is newer than this daemon supports", archive.version);
+        }
+        Ok(archive)
+    }
+
+    /// Write the archive to `path`, readable only by the user and sealed
+    /// with `vault` if given: scrollback can hold anything that was on screen.
+    /// Never replaces an existing file, nor follows a symlink at `path`.
+    pub fn write(&self, path: &Path, vault: Option<&Vault>) -> Result<()> {
+        let mut bytes = self.to_bytes()?;
+        if let Some(vault) = vault {
+            bytes = vault.seal(&bytes).context("encrypt session archive")?;
+        }
+        let mut file = private_file()
+            .write(true)
+            .create_new(true)
+            .open(path)
+            .map_err(|e| match e.kind() {
+                std::io::ErrorKind::AlreadyExists => ErrorCode::BadRequest.err(format!("{} already exists", path.display())),
+                _ => anyhow::Error::new(e).context(format!("create {}", path.display())),
+            })?;
+        file.write_all(&bytes)
+            .with_context(|| format!("write {}", path.display()))
+    }
+
+    /// Read the archive at `path`, opening it with `vault` if it is sealed.
+    /// A symlink at `path` is refused.
+    pub fn read(path: &Path, vault: Option<&Vault>) -> Result<Self> {
+        if std::fs::symlink_metadata(path).is_ok_and(|m| m.file_type().is_symlink()) {
+            return Err(ErrorCode::BadRequest.err(format!("{} is a symlink", path.display())));
+        }
+        let mut options = std::fs::OpenOptions::new();
+        options.read(true);
+        // Nor one swapped in since
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::custom_flags(&mut options, libc::O_NOFOLLOW);
+        let mut file = options.open(path).with_context(|| format!("read {}", path.display()))?;
+        let mut bytes = Vec::new();
+        file.read_to_end(&mut bytes).with_context(|| format!("read {}", path.display()))?;
+        if Vault::is_sealed(&bytes) {
+            let Some(vault) = vault else {
+                return Err(ErrorCode::Unavailable.err("session archive is encrypted and scrollback encryption is off"));
+            };
+            bytes = vault.open(&bytes).context("decrypt session archive")?;
+        }
+        Self::from_bytes(&bytes)
+    }
+
+    /// Banner shown between the restored history and the new shell. The
+    /// archive's host and session id are stripped of control characters, so
+    /// a crafted archive can't slip escape sequences into the terminal.
+    pub fn banner(&self) -> String {
+        let printable = |s: &str| s.chars().filter(|c| !c.is_control()).collect::<String>();
+        format!(
+            "\r\n\x1b[2m[phantom: restored session {} from {}, exported {}]\x1b[0m\r\n",
+            printable(&self.session.id),
+            printable(&self.host),
+            self.exported_at.format("%Y-%m-%d %H:%M UTC"),
+        )
+    }
+}
+
+pub(crate) mod base64_bytes {
+    use base64::Engine;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&base64::engine::general_purpose::STANDARD.encode(bytes))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let encoded = String::deserialize(deserializer)?;
+        base64::engine::general_purpose::STANDARD
+            .decode(encoded)
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn archive() -> SessionArchive {
+        let now = chrono::Utc::now();
+        SessionArchive::new(
+            ArchivedSession {
+                id: "abc123".to_string(),
+                shell: "/bin/zsh".to_string(),
+                created_at: now,
+                created_by_device_id: Some("dev-1".to_string()),
+                tmux_session: None,
+                last_activity_at: now,
+            },
+            b"$ make\r\n\x1b[32mok\x1b[0m\r\n\xff".to_vec(),
+        )
+    }
+
+    #[test]
+    fn round_trips_through_a_file() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("abc123.phantom-session");
+        let original = archive();
+        original.write(&path, None).unwrap();
+
+        use std::os::unix::fs::PermissionsExt;
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        assert_eq!(SessionArchive::read(&path, None).unwrap(), original);
+    }
+
+    #[test]
+    fn encrypted_archives_need_this_hosts_key() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("abc123.phantom-session");
+        let vault = Vault::new(&[1; 32]);
+        let original = archive();
+        original.write(&path, Some(&vault)).unwrap();
+
+        let bytes = std::fs::read(&path).unwrap();
+        assert!(Vault::is_sealed(&bytes));
+        assert!(SessionArchive::from_bytes(&bytes).is_err());
+        assert_eq!(SessionArchive::read(&path, Some(&vault)).unwrap(), original);
+
+        let err = SessionArchive::read(&path, None).unwrap_err();
+        assert_eq!(ErrorCode::of(&err), ErrorCode::Unavailable);
+        let err = SessionArchive::read(&path, Some(&Vault::new(&[2; 32]))).unwrap_err();
+        assert_eq!(ErrorCode::of(&err), ErrorCode::BadRequest);
+    }
+
+    #[test]
+    fn never_overwrites_or_follows_symlinks() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("abc123.phantom-session");
+        let original = archive();
+        original.write(&path, None).unwrap();
+        let err = original.write(&path, None).unwrap_err();
+        assert_eq!(ErrorCode::of(&err), ErrorCode::BadRequest);
+
+        let target = dir.path().join("authorized_keys");
+        let link = dir.path().join("link.phantom-session");
+        std::os::unix::fs::symlink(&target, &link).unwrap();
+        assert!(original.write(&link, None).is_err());
+        assert!(!target.exists());
+        std::fs::copy(&path, &target).unwrap();
+        let err = SessionArchive::read(&link, None).unwrap_err();
+        assert_eq!(ErrorCode::of(&err), ErrorCode::BadRequest);
+    }
+
+    #[test]
+    fn banner_strips_control_characters() {
+        let mut crafted = archive();
+        crafted.host = "evil\x1b]0;pwned\x07host".to_string();
+        crafted.session.id = "id\r\nfake prompt$ ".to_string();
+        let banner = crafted.banner();
+        assert!(banner.contains("restored session idfake prompt$  from evil]0;pwnedhost,"), "{banner:?}");
+        assert!(!banner.contains('\x07'));
+        assert_eq!(banner.matches('\x1b').count(), 2, "{banner:?}");
+    }
+
+    #[test]
+    fn rejects_foreign_and_future_archives() {
+        assert!(SessionArchive::from_bytes(b"plain text").is_err());
+
+        let mut other = archive();
+        other.format = "something-else".to_string();
+        assert!(SessionArchive::from_bytes(&other.to_bytes().unwrap()).is_err());
+
+        let mut future = archive();
+        future.version = VERSION + 1;
+        let err = SessionArchive::from_bytes(&future.to_bytes().unwrap()).unwrap_err();
+        assert!(err.to_string().contains("newer"), "{err}");
+    }
+}
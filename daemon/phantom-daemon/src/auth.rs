@@ -1,14 +1,112 @@
 use anyhow::{bail, Context, Result};
+use phantom_frame::CloseCode;
 use quinn::{Connection, RecvStream, SendStream};
 use serde::{Deserialize, Serialize};
+use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::Instant;
 use tracing::{info, warn};
 
+use crate::clock::{self, SharedClock};
+use crate::connections;
 use crate::device_store::DeviceStore;
+use crate::errors::ErrorCode;
+use crate::hooks::{HookEvent, Hooks};
+use crate::messages::{self, Language};
+use crate::ratelimit::RateLimiter;
+use crate::restrictions::Restrictions;
+use crate::resume::ResumeTokens;
+use crate::session::DeviceNotifier;
+use crate::vouch::{self, PairingRequests, Verdict};
+use crate::webauthn::{self, Assertion, CredentialRef, WebAuthnCredential};
+
+/// How long a client may take to send each auth message.
+pub const MESSAGE_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long the whole auth exchange may take, however it's paced.
+pub const AUTH_BUDGET: Duration = Duration::from_secs(20);
+/// Failed auths a device may have from one IP within
+/// [`DEFAULT_FAILURE_WINDOW`] unless configured with
+/// [`Authenticator::with_failure_limit`].
+const DEFAULT_FAILURE_LIMIT: usize = 3;
+const DEFAULT_FAILURE_WINDOW: Duration = Duration::from_secs(300);
+/// Vouched pairing requests an IP may open within [`VOUCH_WINDOW`], so one
+/// stranger can't keep the single pending slot taken.
+const VOUCH_LIMIT: usize = 3;
+const VOUCH_WINDOW: Duration = Duration::from_secs(3600);
+
+/// Who an authenticated connection belongs to.
+#[derive(Debug, Clone)]
+pub enum Peer {
+    /// A paired device, with the access its policy grants
+    Device { id: String, policy: DevicePolicy },
+    /// A guest who redeemed a share link
+    Guest(Guest),
+}
+
+/// An authenticated control stream, handed back for reuse.
+pub struct Authenticated {
+    pub peer: Peer,
+    pub send: SendStream,
+    pub recv: RecvStream,
+    /// Set (to the auth request id) when the client asked for a
+    /// `server_info` announcement
+    pub server_info: Option<String>,
+    /// Set (to the auth request id) when the client asked for a
+    /// `version_advisory` announcement
+    pub version_advisory: Option<String>,
+    /// Set (to the auth request id) when the client said `hello`, to be
+    /// answered with what the connection uses (see [`crate::hello`])
+    pub hello: Option<String>,
+    /// Set (to the auth request id) when a device resumed with a resume
+    /// token, to be told which sessions to reattach
+    pub resumed: Option<String>,
+}
+
+/// What a paired device may do, read from the device store at auth.
+#[derive(Debug, Clone, Default)]
+pub struct DevicePolicy {
+    /// May forward its SSH agent
+    pub agent_forwarding: bool,
+    /// User the device was paired for (multi-user mode)
+    pub user: Option<String>,
+    /// Set for a low-trust device
+    pub restrictions: Option<Restrictions>,
+}
+
+/// Read-only access to a single session, granted by a guest token.
+#[derive(Debug, Clone)]
+pub struct Guest {
+    /// `guest-<client device_id>`, distinct from any paired device
+    pub id: String,
+    pub session_id: String,
+    pub expires_at: chrono::DateTime<chrono::Utc>,
+}
+
+impl Peer {
+    /// Identifier for logs and session attach metadata.
+    pub fn id(&self) -> &str {
+        match self {
+            Peer::Device { id, .. } => id,
+            Peer::Guest(guest) => &guest.id,
+        }
+    }
+}
 
 /// Handles authentication for incoming connections.
 pub struct Authenticator {
     device_store: Arc<DeviceStore>,
+    hooks: Arc<Hooks>,
+    resume: ResumeTokens,
+    /// Failed auths by IP and the device id claimed from it, so nobody can
+    /// lock a device out by failing in its name from elsewhere
+    device_failures: RateLimiter<(IpAddr, String)>,
+    /// Where vouched pairing requests wait, and how devices are asked to
+    /// approve them (None = vouching unavailable)
+    vouching: Option<(Arc<PairingRequests>, DeviceNotifier)>,
+    /// Vouched pairing requests opened, by IP
+    vouch_requests: RateLimiter<IpAddr>,
+    clock: SharedClock,
 }
 
 // Control message types for auth
@@ -26,6 +124,54 @@ struct AuthRequest {
     pairing_token: Option<String>,
     #[serde(default)]
     signature: Option<String>,
+    #[serde(default)]
+    guest_token: Option<String>,
+    /// From the previous connection's auth response; skips the challenge
+    #[serde(default)]
+    resume_token: Option<String>,
+    /// Pair by having an already-paired device approve, instead of with
+    /// a pairing token
+    #[serde(default)]
+    vouch: bool,
+    /// With `vouch`: the user to be paired for, whose devices approve
+    #[serde(default)]
+    user: Option<String>,
+    /// Pairing: `public_key` is this WebAuthn credential's
+    #[serde(default)]
+    webauthn: Option<CredentialRef>,
+    /// Answering a WebAuthn challenge, with `signature`
+    #[serde(default)]
+    authenticator_data: Option<String>,
+    #[serde(default)]
+    client_data_json: Option<String>,
+    /// Announce capabilities with a `server_info` message after a
+    /// successful auth response (older clients don't expect one)
+    #[serde(default)]
+    server_info: bool,
+    /// Announce the daemon version and the oldest supported app version
+    /// with a `version_advisory` message after a successful auth response
+    #[serde(default)]
+    version_advisory: bool,
+    /// BCP 47 tag of the language to put `message` in errors in
+    #[serde(default)]
+    language: Option<String>,
+    /// Protocol version and what the client decodes, to negotiate the wire
+    #[serde(default)]
+    hello: Option<serde_json::Value>,
+}
+
+/// A vouched pairing request, and what the auth exchange carries through it.
+struct VouchRequest<'a> {
+    remote: SocketAddr,
+    request_id: &'a str,
+    device_id: &'a str,
+    public_key: &'a str,
+    device_name: &'a str,
+    user: Option<&'a str>,
+    webauthn: Option<WebAuthnCredential>,
+    server_info: Option<String>,
+    version_advisory: Option<String>,
+    hello: Option<String>,
 }
 
 #[derive(Debug, Serialize)]
@@ -34,6 +180,9 @@ struct AuthChallenge {
     type_: String,
     request_id: String,
     challenge: String,
+    /// For devices paired with a hardware key: the credential to assert with
+    #[serde(skip_serializing_if = "Option::is_none")]
+    webauthn: Option<CredentialRef>,
 }
 
 #[derive(Debug, Serialize)]
@@ -43,26 +192,189 @@ struct AuthResult {
     request_id: String,
     success: bool,
     #[serde(skip_serializing_if = "Option::is_none")]
+    code: Option<ErrorCode>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     error: Option<String>,
+    /// What `code` means, in the language the client asked for
+    #[serde(skip_serializing_if = "Option::is_none")]
+    message: Option<&'static str>,
+    /// For guests: the one session they may attach to (read-only)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    session_id: Option<String>,
+    /// For devices without a hardware key: present it on the next reconnect
+    /// within [`crate::resume::TTL`] to skip the challenge
+    #[serde(skip_serializing_if = "Option::is_none")]
+    resume_token: Option<String>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    resumed: bool,
+    /// With `RATE_LIMITED`: when the next attempt may succeed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    retry_after_secs: Option<u64>,
+}
+
+impl AuthResult {
+    fn success(request_id: String) -> Self {
+        Self {
+            type_: "auth_response".to_string(),
+            request_id,
+            success: true,
+            code: None,
+            error: None,
+            message: None,
+            session_id: None,
+            resume_token: None,
+            resumed: false,
+            retry_after_secs: None,
+        }
+    }
+
+    fn failure(request_id: &str, code: ErrorCode, error: &str) -> Self {
+        Self {
+            success: false,
+            code: Some(code),
+            error: Some(error.to_string()),
+            message: Some(messages::localized(code)),
+            ..Self::success(request_id.to_string())
+        }
+    }
 }
 
 impl Authenticator {
     pub fn new(device_store: Arc<DeviceStore>) -> Self {
-        Self { device_store }
+        Self {
+            device_store,
+            hooks: Hooks::disabled(),
+            resume: ResumeTokens::new(),
+            device_failures: RateLimiter::new(DEFAULT_FAILURE_LIMIT, DEFAULT_FAILURE_WINDOW),
+            vouching: None,
+            vouch_requests: RateLimiter::new(VOUCH_LIMIT, VOUCH_WINDOW),
+            clock: clock::system(),
+        }
+    }
+
+    /// Read time from `clock` instead of the system's, for resume token
+    /// expiry and failed auth windows.
+    pub fn with_clock(self, clock: SharedClock) -> Self {
+        Self {
+            resume: self.resume.with_clock(clock.clone()),
+            device_failures: self.device_failures.with_clock(clock.clone()),
+            vouch_requests: self.vouch_requests.with_clock(clock.clone()),
+            clock,
+            ..self
+        }
+    }
+
+    /// Fire `hooks` on pairing.
+    pub fn with_hooks(self, hooks: Arc<Hooks>) -> Self {
+        Self { hooks, ..self }
+    }
+
+    /// Refuse a device from an IP after `limit` failed auths from there
+    /// within `window`, until the oldest of them is out of it.
+    pub fn with_failure_limit(self, limit: usize, window: Duration) -> Self {
+        let device_failures = RateLimiter::new(limit, window).with_clock(self.clock.clone());
+        Self { device_failures, ..self }
+    }
+
+    /// Let paired devices vouch for new ones: pairing requests wait in
+    /// `requests`, and connected devices are asked via `notifier`.
+    pub fn with_vouching(self, requests: Arc<PairingRequests>, notifier: DeviceNotifier) -> Self {
+        Self { vouching: Some((requests, notifier)), ..self }
     }
 
     /// Authenticate a connection via the control stream.
-    /// Returns (device_id, send, recv) on success so the streams can be reused.
+    /// Returns the peer and the streams on success so they can be reused.
+    ///
+    /// Each client message must arrive within [`MESSAGE_TIMEOUT`], and the
+    /// whole exchange finish within [`AUTH_BUDGET`] (plus up to
+    /// [`vouch::TIMEOUT`] waiting for a vouch); otherwise the connection is
+    /// closed and the error carries [`ErrorCode::Timeout`].
     pub async fn handle_auth(
         &self,
-        _connection: &Connection,
+        connection: &Connection,
+        send: SendStream,
+        recv: RecvStream,
+    ) -> Result<Authenticated> {
+        let deadline = Instant::now() + AUTH_BUDGET;
+        // Reads are held to `deadline`; only a vouch waits past it
+        let authenticating = self.authenticate(send, recv, connection.remote_address(), deadline);
+        let result = tokio::time::timeout_at(deadline + vouch::TIMEOUT, authenticating)
+            .await
+            .unwrap_or_else(|_| Err(ErrorCode::Timeout.err(format!("auth not done within {AUTH_BUDGET:?}"))));
+        if let Err(e) = &result {
+            // Failure responses are already delivered (see `refuse`)
+            let code = match ErrorCode::of(e) {
+                ErrorCode::Timeout => CloseCode::AuthTimeout,
+                ErrorCode::RateLimited => CloseCode::RateLimited,
+                _ => CloseCode::AuthFailed,
+            };
+            connections::close(connection, code, code.name().as_bytes());
+        }
+        result
+    }
+
+    /// Answer a connection from an IP with too many failed auths: read its
+    /// auth request (for the request id), reply `RATE_LIMITED` with when to
+    /// retry, and close the connection.
+    pub async fn refuse_rate_limited(
+        &self,
+        connection: &Connection,
+        mut send: SendStream,
+        mut recv: RecvStream,
+        retry_after: Duration,
+    ) {
+        let deadline = Instant::now() + MESSAGE_TIMEOUT;
+        let request_id = match read_auth_message(&mut recv, deadline, "auth request").await {
+            Ok(msg) => {
+                let req = serde_json::from_slice::<serde_json::Value>(&msg).unwrap_or_default();
+                if let Some(language) = req["language"].as_str().and_then(Language::from_tag) {
+                    messages::set_current(language);
+                }
+                req["request_id"].as_str().map(str::to_string).unwrap_or_default()
+            }
+            Err(_) => String::new(),
+        };
+        let resp = rate_limited(&request_id, retry_after);
+        let _ = deliver(&mut send, &resp).await;
+        connections::close(connection, CloseCode::RateLimited, b"rate limited");
+    }
+
+    /// Send a failed auth response and wait (briefly) until the client has
+    /// it, so closing the connection next doesn't lose it. Returns the
+    /// error to fail auth with.
+    async fn refuse(&self, send: &mut SendStream, request_id: &str, code: ErrorCode, message: &str) -> anyhow::Error {
+        if let Err(e) = deliver(send, &AuthResult::failure(request_id, code, message)).await {
+            warn!("auth failure response not delivered: {e:#}");
+        }
+        code.err(message)
+    }
+
+    async fn authenticate(
+        &self,
         mut send: SendStream,
         mut recv: RecvStream,
-    ) -> Result<(String, SendStream, RecvStream)> {
+        remote: SocketAddr,
+        deadline: Instant,
+    ) -> Result<Authenticated> {
         // Read length-prefixed JSON auth request
-        let msg = read_control_message(&mut recv).await?;
+        let msg = read_auth_message(&mut recv, deadline, "auth request").await?;
         let req: AuthRequest =
             serde_json::from_slice(&msg).context("parse auth request")?;
+        if let Some(language) = req.language.as_deref().and_then(Language::from_tag) {
+            messages::set_current(language);
+        }
+        let server_info = req.server_info.then(|| req.request_id.clone());
+        let version_advisory = req.version_advisory.then(|| req.request_id.clone());
+        let hello = match req.hello.as_ref().map(crate::hello::negotiate).transpose() {
+            Ok(wire) => wire.map(|wire| {
+                crate::hello::set_current(wire);
+                req.request_id.clone()
+            }),
+            Err(e) => {
+                let err = self.refuse(&mut send, &req.request_id, ErrorCode::BadRequest, &format!("{e:#}")).await;
+                return Err(err.context(format!("bad hello from {}", req.device_id)));
+            }
+        };
 
         match req.type_.as_str() {
             "auth_request" => {}
@@ -79,39 +391,135 @@ impl Authenticator {
             bail!("invalid device_id characters");
         }
 
+        let failure_key = (remote.ip(), device_id.clone());
+        if let Some(retry_after) = self.device_failures.retry_after(&failure_key) {
+            warn!("auth from {device_id} at {remote} rate limited for {}s", retry_after.as_secs());
+            if let Err(e) = deliver(&mut send, &rate_limited(&req.request_id, retry_after)).await {
+                warn!("auth failure response not delivered: {e:#}");
+            }
+            return Err(ErrorCode::RateLimited.err(format!("too many failed auths from {device_id}")));
+        }
+
+        // Guest share link: no pairing, read-only access to one session
+        if let Some(token) = &req.guest_token {
+            let guest_id = format!("guest-{device_id}");
+            let Some(grant) = self.device_store.redeem_guest_token(token) else {
+                warn!("invalid guest token from {device_id}");
+                self.device_store.record_guest_auth(&guest_id, false);
+                let err = self.refuse(&mut send, &req.request_id, ErrorCode::Unauthenticated, "invalid or expired guest link").await;
+                return Err(err.context(format!("invalid guest token from {device_id}")));
+            };
+
+            info!("guest {guest_id} admitted to session {}", grant.session_id);
+            self.device_store.record_guest_auth(&guest_id, true);
+            let resp = AuthResult {
+                session_id: Some(grant.session_id.clone()),
+                ..AuthResult::success(req.request_id)
+            };
+            write_control_message(&mut send, &resp).await?;
+            let guest = Guest {
+                id: guest_id,
+                session_id: grant.session_id,
+                expires_at: chrono::DateTime::from_timestamp(grant.expires_at as i64, 0)
+                    .unwrap_or_else(chrono::Utc::now),
+            };
+            let peer = Peer::Guest(guest);
+            return Ok(Authenticated { peer, send, recv, server_info, version_advisory, hello, resumed: None });
+        }
+
+        // Pairing a hardware key: `public_key` is its credential's
+        let webauthn = match &req.webauthn {
+            Some(registration) => match WebAuthnCredential::new(&registration.credential_id, &registration.rp_id) {
+                Ok(credential) => Some(credential),
+                Err(e) => {
+                    let err = self.refuse(&mut send, &req.request_id, ErrorCode::BadRequest, &e.to_string()).await;
+                    return Err(err.context(format!("bad webauthn registration from {device_id}")));
+                }
+            },
+            None => None,
+        };
+
         // Check if this is a pairing request (has pairing_token + public_key)
         if let (Some(token), Some(pub_key), Some(name)) =
             (&req.pairing_token, &req.public_key, &req.device_name)
         {
             // Pairing flow
-            if self.device_store.validate_pairing_token(token)? {
-                self.device_store.add_device(
-                    &device_id,
-                    pub_key,
-                    name,
-                )?;
-                info!("paired new device: {device_id} ({name})");
+            if let Some(grant) = self.device_store.redeem_pairing_token(token) {
+                let hardware_key = webauthn.is_some();
+                self.device_store.add_device_with(&device_id, pub_key, name, grant.user.as_deref(), None, webauthn)?;
+                match hardware_key {
+                    true => info!("paired new device: {device_id} ({name}) with a WebAuthn credential"),
+                    false => info!("paired new device: {device_id} ({name})"),
+                }
+                self.hooks.fire(HookEvent::DevicePaired {
+                    device_id: device_id.clone(),
+                    device_name: name.clone(),
+                });
 
                 let resp = AuthResult {
-                    type_: "auth_response".to_string(),
-                    request_id: req.request_id,
-                    success: true,
-                    error: None,
+                    resume_token: self.resume_token(&device_id),
+                    ..AuthResult::success(req.request_id)
                 };
                 write_control_message(&mut send, &resp).await?;
-                return Ok((device_id, send, recv));
+                let policy = DevicePolicy { user: grant.user, ..Default::default() };
+                let peer = Peer::Device { id: device_id, policy };
+                return Ok(Authenticated { peer, send, recv, server_info, version_advisory, hello, resumed: None });
             } else {
-                warn!("invalid pairing attempt from {device_id}");
-                self.device_store.record_auth(&device_id, false);
-                let resp = AuthResult {
-                    type_: "auth_response".to_string(),
-                    request_id: req.request_id,
-                    success: false,
-                    error: Some("invalid or expired pairing token".to_string()),
+                warn!("invalid pairing attempt from {device_id} at {remote}");
+                let miss = self.device_store.record_pairing_miss(&device_id, &remote.to_string());
+                self.device_failures.record(&failure_key);
+                if miss.outstanding == 0 || miss.withdrawn > 0 {
+                    // Nobody is pairing, or the pairing is being guessed at
+                    warn!("pairing probe from {device_id} at {remote}, {} token(s) withdrawn", miss.withdrawn);
+                    self.hooks.fire(HookEvent::PairingProbed {
+                        remote: remote.to_string(),
+                        device_id: device_id.clone(),
+                        withdrawn: miss.withdrawn,
+                    });
+                }
+                let err = self.refuse(&mut send, &req.request_id, ErrorCode::Unauthenticated, "invalid or expired pairing token").await;
+                return Err(err.context(format!("invalid pairing token from {device_id}")));
+            }
+        }
+
+        // Vouched pairing: wait for a paired device to approve
+        if let (true, Some(public_key), Some(device_name)) = (req.vouch, &req.public_key, &req.device_name) {
+            let vouch = VouchRequest {
+                remote,
+                request_id: &req.request_id,
+                device_id: &device_id,
+                public_key,
+                device_name,
+                user: req.user.as_deref(),
+                webauthn,
+                server_info,
+                version_advisory,
+                hello,
+            };
+            return self.pair_vouched(send, recv, vouch).await;
+        }
+
+        // Resume token from the previous connection: no challenge. A stale
+        // one just falls through to the challenge, as does any for a hardware
+        // key, whose user proves presence on every connection.
+        if let Some(token) = &req.resume_token {
+            if self.resume.redeem(token, &device_id)
+                && self.device_store.get_public_key(&device_id).is_ok()
+                && self.device_store.webauthn_credential(&device_id).is_none()
+            {
+                info!("device {device_id} resumed without a challenge");
+                let result = AuthResult {
+                    resume_token: self.resume_token(&device_id),
+                    resumed: true,
+                    ..AuthResult::success(req.request_id.clone())
                 };
-                write_control_message(&mut send, &resp).await?;
-                bail!("invalid pairing token from {device_id}");
+                write_control_message(&mut send, &result).await?;
+                self.device_store.record_auth(&device_id, true);
+                let peer = Peer::Device { policy: self.device_policy(&device_id), id: device_id };
+                let resumed = Some(req.request_id);
+                return Ok(Authenticated { peer, send, recv, server_info, version_advisory, hello, resumed });
             }
+            info!("resume token from {device_id} not accepted, challenging");
         }
 
         // Challenge-response flow for already-paired devices
@@ -120,14 +528,9 @@ impl Authenticator {
             Err(_) => {
                 warn!("auth attempt from unknown device {device_id}");
                 self.device_store.record_auth(&device_id, false);
-                let resp = AuthResult {
-                    type_: "auth_response".to_string(),
-                    request_id: req.request_id,
-                    success: false,
-                    error: Some("device not paired".to_string()),
-                };
-                write_control_message(&mut send, &resp).await?;
-                bail!("unknown device {device_id}");
+                self.device_failures.record(&failure_key);
+                let err = self.refuse(&mut send, &req.request_id, ErrorCode::NotPaired, "device not paired").await;
+                return Err(err.context(format!("unknown device {device_id}")));
             }
         };
 
@@ -135,18 +538,20 @@ impl Authenticator {
         let challenge_bytes: [u8; 32] = rand::Rng::gen(&mut rand::thread_rng());
         let challenge_b64 = {
             use base64::Engine;
-            base64::engine::general_purpose::STANDARD.encode(&challenge_bytes)
+            base64::engine::general_purpose::STANDARD.encode(challenge_bytes)
         };
 
+        let hardware_key = self.device_store.webauthn_credential(&device_id);
         let challenge_msg = AuthChallenge {
             type_: "auth_challenge".to_string(),
             request_id: req.request_id.clone(),
             challenge: challenge_b64,
+            webauthn: hardware_key.as_ref().map(WebAuthnCredential::to_ref),
         };
         write_control_message(&mut send, &challenge_msg).await?;
 
         // Read signed challenge response
-        let resp_msg = read_control_message(&mut recv).await?;
+        let resp_msg = read_auth_message(&mut recv, deadline, "challenge response").await?;
         let resp: AuthRequest =
             serde_json::from_slice(&resp_msg).context("parse auth response")?;
 
@@ -155,30 +560,143 @@ impl Authenticator {
             .as_ref()
             .context("missing signature in auth response")?;
 
-        // Verify P256 signature against the challenge bytes.
+        // Verify P256 signature against the challenge bytes, or for a
+        // hardware key the WebAuthn assertion over them.
         // TODO: add TLS exporter binding once the iOS client supports it.
-        let valid = verify_p256_signature(&stored_key, &challenge_bytes, signature_b64)?;
+        let valid = match &hardware_key {
+            Some(credential) => {
+                let assertion = Assertion {
+                    authenticator_data: resp.authenticator_data.clone().context("missing authenticator_data")?,
+                    client_data_json: resp.client_data_json.clone().context("missing client_data_json")?,
+                    signature: signature_b64.clone(),
+                };
+                match webauthn::verify(credential, &stored_key, &challenge_bytes, &assertion) {
+                    Ok(sign_count) => {
+                        if let Err(e) = self.device_store.record_sign_count(&device_id, sign_count) {
+                            warn!("could not save signature counter of {device_id}: {e:#}");
+                        }
+                        true
+                    }
+                    Err(e) => {
+                        warn!("WebAuthn assertion from {device_id} rejected: {e:#}");
+                        false
+                    }
+                }
+            }
+            None => verify_p256_signature(&stored_key, &challenge_bytes, signature_b64)?,
+        };
 
         if valid {
             let result = AuthResult {
-                type_: "auth_response".to_string(),
-                request_id: req.request_id,
-                success: true,
-                error: None,
+                resume_token: self.resume_token(&device_id),
+                ..AuthResult::success(req.request_id)
             };
             write_control_message(&mut send, &result).await?;
             self.device_store.record_auth(&device_id, true);
-            Ok((device_id, send, recv))
+            let peer = Peer::Device { policy: self.device_policy(&device_id), id: device_id };
+            Ok(Authenticated { peer, send, recv, server_info, version_advisory, hello, resumed: None })
         } else {
-            let result = AuthResult {
-                type_: "auth_response".to_string(),
-                request_id: req.request_id,
-                success: false,
-                error: Some("signature verification failed".to_string()),
-            };
-            write_control_message(&mut send, &result).await?;
             self.device_store.record_auth(&device_id, false);
-            bail!("auth failed: bad signature from {device_id}");
+            self.device_failures.record(&failure_key);
+            let err = self.refuse(&mut send, &req.request_id, ErrorCode::Unauthenticated, "signature verification failed").await;
+            Err(err.context(format!("auth failed: bad signature from {device_id}")))
+        }
+    }
+
+    /// Ask the user's devices to approve pairing the requesting device, and
+    /// pair it if one does within [`vouch::TIMEOUT`].
+    async fn pair_vouched(&self, mut send: SendStream, recv: RecvStream, req: VouchRequest<'_>) -> Result<Authenticated> {
+        let VouchRequest { remote, request_id, device_id, public_key, device_name, user, .. } = req;
+        let Some((requests, notifier)) = &self.vouching else {
+            let err = self.refuse(&mut send, request_id, ErrorCode::Unavailable, "pairing by approval is not available").await;
+            return Err(err.context(format!("vouched pairing attempt from {device_id}")));
+        };
+        if self.device_store.get_public_key(device_id).is_ok() {
+            let err = self.refuse(&mut send, request_id, ErrorCode::BadRequest, "device is already paired").await;
+            return Err(err.context(format!("vouched pairing attempt from paired device {device_id}")));
+        }
+        if let Some(user) = user {
+            if let Err(e) = crate::users::validate_user(user) {
+                let err = self.refuse(&mut send, request_id, ErrorCode::BadRequest, &format!("{e:#}")).await;
+                return Err(err.context(format!("vouched pairing from {device_id}")));
+            }
+        }
+        if let Some(retry_after) = self.vouch_requests.retry_after(&remote.ip()) {
+            warn!("vouched pairing from {device_id} at {remote} rate limited for {}s", retry_after.as_secs());
+            if let Err(e) = deliver(&mut send, &rate_limited(request_id, retry_after)).await {
+                warn!("auth failure response not delivered: {e:#}");
+            }
+            return Err(ErrorCode::RateLimited.err(format!("too many pairing requests from {remote}")));
+        }
+        let Some(mut ticket) = requests.open(device_id, user) else {
+            let err = self.refuse(&mut send, request_id, ErrorCode::Unavailable, "another device is waiting to be approved").await;
+            return Err(err.context(format!("vouched pairing from {device_id} while another is pending")));
+        };
+        self.vouch_requests.record(&remote.ip());
+
+        let code = vouch::verification_code(public_key);
+        info!("device {device_id} ({device_name}) at {remote} asked to be paired, code {code}");
+        // Only devices that could approve: the new device gets their user
+        let approvers = |id: &str| {
+            let policy = self.device_policy(id);
+            policy.restrictions.is_none() && policy.user.as_deref() == user
+        };
+        notifier.notify_where(approvers, &serde_json::json!({
+            "type": "pairing_request",
+            "pairing_id": ticket.id,
+            "device_id": device_id,
+            "device_name": device_name,
+            "verification_code": code,
+            "expires_in_secs": vouch::TIMEOUT.as_secs(),
+        }));
+
+        match ticket.verdict(vouch::TIMEOUT).await {
+            Some(Verdict::Approved { by }) => {
+                self.device_store.add_device_with(device_id, public_key, device_name, user, Some(&by), req.webauthn)?;
+                info!("paired new device: {device_id} ({device_name}), vouched for by {by}");
+                self.hooks.fire(HookEvent::DevicePaired {
+                    device_id: device_id.to_string(),
+                    device_name: device_name.to_string(),
+                });
+
+                let resp = AuthResult {
+                    resume_token: self.resume_token(device_id),
+                    ..AuthResult::success(request_id.to_string())
+                };
+                write_control_message(&mut send, &resp).await?;
+                let policy = DevicePolicy { user: user.map(str::to_string), ..Default::default() };
+                let peer = Peer::Device { id: device_id.to_string(), policy };
+                let VouchRequest { server_info, version_advisory, hello, .. } = req;
+                Ok(Authenticated { peer, send, recv, server_info, version_advisory, hello, resumed: None })
+            }
+            Some(Verdict::Denied { by }) => {
+                warn!("pairing of {device_id} denied by {by}");
+                self.device_failures.record(&(remote.ip(), device_id.to_string()));
+                let err = self.refuse(&mut send, request_id, ErrorCode::PermissionDenied, "pairing was denied").await;
+                Err(err.context(format!("pairing of {device_id} denied")))
+            }
+            None => {
+                let message = "nobody approved pairing in time";
+                let err = self.refuse(&mut send, request_id, ErrorCode::Timeout, message).await;
+                Err(err.context(format!("pairing of {device_id} not approved")))
+            }
+        }
+    }
+
+    fn device_policy(&self, device_id: &str) -> DevicePolicy {
+        DevicePolicy {
+            agent_forwarding: self.device_store.agent_forwarding_allowed(device_id),
+            user: self.device_store.device_user(device_id),
+            restrictions: self.device_store.device_restrictions(device_id),
+        }
+    }
+
+    /// A resume token for `device_id`, unless it pairs with a hardware key:
+    /// resuming would skip the touch each of its connections needs.
+    fn resume_token(&self, device_id: &str) -> Option<String> {
+        match self.device_store.webauthn_credential(device_id) {
+            Some(_) => None,
+            None => Some(self.resume.issue(device_id)),
         }
     }
 }
@@ -208,6 +726,27 @@ fn verify_p256_signature(
     Ok(verifying_key.verify(message, &signature).is_ok())
 }
 
+/// A `RATE_LIMITED` auth response; `retry_after` is rounded up to seconds.
+fn rate_limited(request_id: &str, retry_after: Duration) -> AuthResult {
+    let secs = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+    AuthResult {
+        retry_after_secs: Some(secs),
+        ..AuthResult::failure(request_id, ErrorCode::RateLimited, "too many failed attempts")
+    }
+}
+
+/// Write a final auth message, finish the stream and wait for the client
+/// to have read it, up to [`MESSAGE_TIMEOUT`].
+async fn deliver(send: &mut SendStream, resp: &AuthResult) -> Result<()> {
+    write_control_message(send, resp).await?;
+    send.finish().context("finish auth stream")?;
+    let _ = tokio::time::timeout(MESSAGE_TIMEOUT, send.stopped()).await;
+    Ok(())
+}
+
+/// Largest control message either side may send.
+pub const MAX_CONTROL_MESSAGE: usize = 65536;
+
 /// Read a length-prefixed JSON message from a QUIC stream.
 async fn read_control_message(recv: &mut RecvStream) -> Result<Vec<u8>> {
     let mut len_buf = [0u8; 4];
@@ -216,7 +755,7 @@ async fn read_control_message(recv: &mut RecvStream) -> Result<Vec<u8>> {
         .context("read control message length")?;
     let len = u32::from_be_bytes(len_buf) as usize;
 
-    if len > 65536 {
+    if len > MAX_CONTROL_MESSAGE {
         anyhow::bail!("control message too large: {len}");
     }
 
@@ -228,6 +767,15 @@ async fn read_control_message(recv: &mut RecvStream) -> Result<Vec<u8>> {
     Ok(buf)
 }
 
+/// Read the next auth message, allowing [`MESSAGE_TIMEOUT`] for it but
+/// never past `deadline`.
+async fn read_auth_message(recv: &mut RecvStream, deadline: Instant, stage: &str) -> Result<Vec<u8>> {
+    let stage_deadline = deadline.min(Instant::now() + MESSAGE_TIMEOUT);
+    tokio::time::timeout_at(stage_deadline, read_control_message(recv))
+        .await
+        .map_err(|_| ErrorCode::Timeout.err(format!("timed out waiting for {stage}")))?
+}
+
 /// Write a length-prefixed JSON message to a QUIC stream.
 async fn write_control_message<T: Serialize>(
     send: &mut SendStream,
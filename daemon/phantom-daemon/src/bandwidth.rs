@@ -0,0 +1,228 @@
+//! Bytes each device sent and received over its session streams, by day
+//! and session, for users on metered connections.
+//!
+//! Bridges count their traffic; [`run`] moves the counts into the ledger
+//! every [`ACCOUNT_INTERVAL`] and writes `bandwidth.json` in the data dir,
+//! where `phantom device list` reads it. Days older than [`RETAIN_DAYS`]
+//! are dropped.
+
+use anyhow::{Context, Result};
+use chrono::NaiveDate;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+use tracing::warn;
+
+use crate::device_store::{read_with_backup, write_atomic};
+use crate::session::SessionManager;
+
+/// How often bridge traffic is moved into the ledger and saved.
+pub const ACCOUNT_INTERVAL: Duration = Duration::from_secs(60);
+/// Days of counters kept.
+pub const RETAIN_DAYS: i64 = 31;
+
+/// Bytes sent to and received from a device.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Usage {
+    pub sent: u64,
+    pub received: u64,
+}
+
+impl Usage {
+    pub fn total(&self) -> u64 {
+        self.sent + self.received
+    }
+
+    fn add(&mut self, other: Usage) {
+        self.sent += other.sent;
+        self.received += other.received;
+    }
+}
+
+/// One device's traffic on one day.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DeviceDay {
+    #[serde(flatten)]
+    pub usage: Usage,
+    /// By session id
+    #[serde(default)]
+    pub sessions: BTreeMap<String, Usage>,
+}
+
+/// A device's traffic today and over the retained days.
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+pub struct DeviceUsage {
+    pub today: DeviceDay,
+    pub last_30_days: Usage,
+}
+
+/// Day → device id → traffic.
+type Days = BTreeMap<NaiveDate, BTreeMap<String, DeviceDay>>;
+
+#[derive(Default)]
+pub struct BandwidthLedger {
+    /// None keeps counters in memory only
+    path: Option<PathBuf>,
+    days: Mutex<Days>,
+    dirty: AtomicBool,
+}
+
+impl BandwidthLedger {
+    /// Counters persisted at `path`, starting empty if it can't be read.
+    pub fn load(path: PathBuf) -> Self {
+        let days = read_with_backup(&path, |s| serde_json::from_str(s).context("parse bandwidth.json"))
+            .unwrap_or_else(|e| {
+                warn!("ignoring unreadable {}: {e:#}", path.display());
+                None
+            })
+            .unwrap_or_default();
+        Self {
+            path: Some(path),
+            days: Mutex::new(days),
+            dirty: AtomicBool::new(false),
+        }
+    }
+
+    /// Count `usage` for `device_id` in session `session_id`, today.
+    pub fn record(&self, device_id: &str, session_id: &str, usage: Usage) {
+        self.record_on(today(), device_id, session_id, usage);
+    }
+
+    fn record_on(&self, day: NaiveDate, device_id: &str, session_id: &str, usage: Usage) {
+        if usage.total() == 0 {
+            return;
+        }
+        let mut days = self.days.lock().expect("bandwidth lock");
+        let device = days.entry(day).or_default().entry(device_id.to_string()).or_default();
+        device.usage.add(usage);
+        device.sessions.entry(session_id.to_string()).or_default().add(usage);
+        self.dirty.store(true, Ordering::Relaxed);
+    }
+
+    /// `device_id`'s traffic today and over the retained days.
+    pub fn device_usage(&self, device_id: &str) -> DeviceUsage {
+        self.device_usage_on(today(), device_id)
+    }
+
+    fn device_usage_on(&self, today: NaiveDate, device_id: &str) -> DeviceUsage {
+        let days = self.days.lock().expect("bandwidth lock");
+        let mut usage = DeviceUsage::default();
+        let since = today - chrono::Duration::days(29);
+        for (day, devices) in days.range(since..=today) {
+            let Some(device) = devices.get(device_id) else {
+                continue;
+            };
+            usage.last_30_days.add(device.usage);
+            if *day == today {
+                usage.today = device.clone();
+            }
+        }
+        usage
+    }
+
+    /// Drop old days and save, if anything changed.
+    pub fn flush(&self) -> Result<()> {
+        if !self.dirty.swap(false, Ordering::Relaxed) {
+            return Ok(());
+        }
+        let mut days = self.days.lock().expect("bandwidth lock");
+        let oldest = today() - chrono::Duration::days(RETAIN_DAYS - 1);
+        days.retain(|day, _| *day >= oldest);
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let json = serde_json::to_string_pretty(&*days)?;
+        write_atomic(path, json.as_bytes()).context("write bandwidth.json")
+    }
+}
+
+fn today() -> NaiveDate {
+    chrono::Local::now().date_naive()
+}
+
+/// Move bridge traffic into the ledger and save it every
+/// [`ACCOUNT_INTERVAL`], and once more on shutdown.
+pub async fn run(session_manager: Arc<SessionManager>, cancel: CancellationToken) {
+    let mut interval = tokio::time::interval(ACCOUNT_INTERVAL);
+    loop {
+        let stop = tokio::select! {
+            _ = interval.tick() => false,
+            _ = cancel.cancelled() => true,
+        };
+        session_manager.account_traffic();
+        if let Err(e) = session_manager.bandwidth().flush() {
+            warn!("bandwidth counters not saved: {e:#}");
+        }
+        if stop {
+            return;
+        }
+    }
+}
+
+/// `bytes` for humans, e.g. `1.5 MB`.
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KB", "MB", "GB", "TB"];
+    if bytes < 1000 {
+        return format!("{bytes} B");
+    }
+    let mut value = bytes as f64;
+    let mut unit = "B";
+    for u in UNITS {
+        if value < 1000.0 {
+            break;
+        }
+        value /= 1000.0;
+        unit = u;
+    }
+    format!("{value:.1} {unit}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn day(d: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(2026, 3, d).unwrap()
+    }
+
+    #[test]
+    fn counts_per_device_day_and_session() {
+        let ledger = BandwidthLedger::default();
+        let usage = |sent, received| Usage { sent, received };
+        ledger.record_on(day(1), "phone", "s1", usage(1000, 10));
+        ledger.record_on(day(2), "phone", "s1", usage(500, 5));
+        ledger.record_on(day(2), "phone", "s2", usage(200, 0));
+        ledger.record_on(day(2), "laptop", "s1", usage(7, 7));
+
+        let phone = ledger.device_usage_on(day(2), "phone");
+        assert_eq!(phone.today.usage, usage(700, 5));
+        assert_eq!(phone.today.sessions["s1"], usage(500, 5));
+        assert_eq!(phone.last_30_days, usage(1700, 15));
+        assert_eq!(ledger.device_usage_on(day(2), "tablet"), DeviceUsage::default());
+    }
+
+    #[test]
+    fn persists_only_when_changed() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("bandwidth.json");
+        let ledger = BandwidthLedger::load(path.clone());
+        ledger.flush().unwrap();
+        assert!(!path.exists());
+
+        ledger.record("phone", "s1", Usage { sent: 3, received: 4 });
+        ledger.flush().unwrap();
+        let reloaded = BandwidthLedger::load(path);
+        assert_eq!(reloaded.device_usage("phone").today.usage.total(), 7);
+    }
+
+    #[test]
+    fn formats_bytes() {
+        assert_eq!(format_bytes(999), "999 B");
+        assert_eq!(format_bytes(1_500_000), "1.5 MB");
+        assert_eq!(format_bytes(2_000_000_000), "2.0 GB");
+    }
+}
@@ -1,85 +1,1063 @@
 use anyhow::{Context, Result};
-use phantom_frame::{self as frame, Frame, FrameDecoder, FrameType};
+use base64::Engine;
+use bytes::{Bytes, BytesMut};
+use phantom_frame::{self as frame, CompressionStats, Frame, FrameCompressor, FrameDecoder, FrameError, FrameType};
 use quinn::{RecvStream, SendStream};
 use std::io::{Read, Write};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
-use tokio::sync::{mpsc, Notify};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::error::SendError;
+use tokio::sync::{broadcast, mpsc, Notify};
 use tokio_util::sync::CancellationToken;
-use tracing::{error, info, warn};
+use tokio_util::task::TaskTracker;
+use tracing::{debug, error, info, warn, Instrument};
 
-use crate::session::{PtySession, ScrollbackBuffer, SessionManager};
+use crate::auth::{DevicePolicy, Guest};
+use crate::bandwidth::Usage;
+use crate::budget::Budget;
+use crate::config::{BridgeConfig, InputLimit, UnknownFramePolicy};
+use crate::dedup::Claim;
+use crate::env::EnvWatch;
+use crate::errors::{ErrorCode, SessionError, StreamTimeout};
+use crate::exec::{self, Exec};
+use crate::handoff;
+use crate::hello::{self, Wire};
+use crate::history::EventKind;
+use crate::input_limit::{Admission, InputLimiter};
+use crate::memory::Reservation;
+use crate::messages;
+use crate::naming::{self, CommandCapture};
+use crate::paste::{self, ModeWatch, Paste};
+use crate::restrictions::Restrictions;
+use crate::session::{DeviceNotifier, PtySession, ScrollbackBuffer, SessionManager};
+use crate::terminal::{signal, Launch, NativePty, TerminalCaps};
+use crate::throttle::{OutputPacer, OutputThrottle};
+use crate::tmux;
+use crate::users;
+use crate::vouch::Verdict;
+use crate::watchdog::ReadWatch;
 
 /// Default client receive window (256KB).
 const DEFAULT_WINDOW: u64 = 262144;
+/// Upper bound for a client-requested coalescing window.
+const MAX_COALESCE_MS: u64 = 20;
+/// Output at or above this size is sent immediately, even with coalescing on.
+const COALESCE_BYPASS_BYTES: usize = 1024;
+/// PTY output chunks queued between the reader thread and the send task.
+const OUTPUT_QUEUE_CHUNKS: usize = 128;
+/// Client input chunks queued between the receive task and the writer thread.
+const INPUT_QUEUE_CHUNKS: usize = 64;
+/// Most queued input the writer thread merges into one PTY write.
+const INPUT_COALESCE_BYTES: usize = 16384;
+/// Size of a single PTY read.
+const READ_CHUNK_BYTES: usize = 16384;
+/// Backing allocation that PTY reads are split off.
+const READ_SLAB_BYTES: usize = READ_CHUNK_BYTES * 16;
+/// Backing allocation that outgoing payloads are gathered in and split off.
+const PAYLOAD_SLAB_BYTES: usize = frame::MAX_PAYLOAD * 4;
+/// Backing allocation that frame headers are split off.
+const HEADER_SLAB_BYTES: usize = frame::HEADER_SIZE * 256;
+/// After a client Close, output keeps flowing until the PTY is quiet this long.
+const CLOSE_QUIET: Duration = Duration::from_millis(200);
+/// Upper bound on the output drain during a close handshake.
+const CLOSE_DRAIN_MAX: Duration = Duration::from_secs(2);
+/// How long to wait for the client to acknowledge the finished stream.
+const CLOSE_ACK_TIMEOUT: Duration = Duration::from_secs(2);
+/// Reason sent in the Close frame that answers a client Close.
+const CLOSE_REASON_CLIENT: &str = "client_close";
+/// Reason in a client Close that asks to detach and keep the stream for
+/// further control requests (see [`Detached`]). The client sends nothing
+/// more until the `session_detached` acknowledgement.
+const CLOSE_REASON_DETACH_REQUEST: &str = "detach_session";
+/// Reason sent to mirrors when the attached client detaches, and in the
+/// Close answering a detach request.
+const CLOSE_REASON_DETACHED: &str = "detached";
+/// Reason sent to a mirror that fell too far behind the session's output.
+const CLOSE_REASON_LAGGED: &str = "lagged";
+/// fetch_scrollback page size when the client doesn't ask for one.
+const DEFAULT_PAGE_BYTES: usize = 16 * 1024;
+/// Largest fetch_scrollback page (base64 in a JSON reply).
+const MAX_PAGE_BYTES: usize = 32 * 1024;
+
+/// Scrollback replay is sent in Scrollback frames of at most this much,
+/// each waiting for the client's flow control window like live output.
+const REPLAY_CHUNK_BYTES: usize = 16 * 1024;
+/// Client input held during scrollback replay beyond this is written through.
+const HELD_INPUT_CAP: usize = 64 * 1024;
+/// Longest client input is held while scrollback replay is in flight.
+const HELD_INPUT_TIMEOUT: Duration = Duration::from_secs(3);
+/// Longest a resent attach waits for the bridge it replaces to detach.
+const TAKEOVER_TIMEOUT: Duration = Duration::from_secs(2);
+/// How often an idle bridge pings the client. Clients echo the ping's
+/// Heartbeat frame (payload and all), which gives a round-trip sample.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
+/// Pings sent within the liveness timeout, so a client is only given up
+/// on after missing several.
+const PINGS_PER_LIVENESS_TIMEOUT: u32 = 3;
+/// Worst-case buffer memory of one attached bridge, reserved against the
+/// daemon memory budget: a full PTY channel, the send slabs, and the decoder.
+const BRIDGE_MEMORY_BYTES: usize =
+    OUTPUT_QUEUE_CHUNKS * READ_CHUNK_BYTES + READ_SLAB_BYTES + PAYLOAD_SLAB_BYTES + 2 * frame::MAX_FRAME;
+/// Output chunks a mirror may trail the attached bridge by before it is closed.
+const MIRROR_FEED_CHUNKS: usize = 32;
+/// Worst-case buffer memory of one mirror: a full feed backlog, the send
+/// slabs, and the decoder.
+const MIRROR_MEMORY_BYTES: usize =
+    MIRROR_FEED_CHUNKS * frame::MAX_PAYLOAD + PAYLOAD_SLAB_BYTES + 2 * frame::MAX_FRAME;
+
+/// Live state of an attached session's bridge, shared by its tasks and read
+/// by `inspect_session` and `connection_stats`.
+#[derive(Debug)]
+pub struct BridgeProbe {
+    /// Client flow control window, in bytes
+    window: Arc<AtomicU64>,
+    /// PTY output chunks waiting for the send task
+    queued: AtomicUsize,
+    frames_sent: AtomicU64,
+    frames_received: AtomicU64,
+    heartbeats_sent: AtomicU64,
+    /// Outstanding heartbeat ping: its id, and when it was sent
+    ping: Mutex<Option<(u64, Instant)>>,
+    rtt: Mutex<RttStats>,
+    /// Time output waited on a closed flow control window, in microseconds
+    stalled_us: AtomicU64,
+    /// Bytes sent and received since [`BridgeProbe::take_traffic`] last ran
+    traffic_sent: AtomicU64,
+    traffic_received: AtomicU64,
+    /// When the client was last heard from, in microseconds since `attached_at`
+    heard_us: AtomicU64,
+    attached_at: Instant,
+    pty_reader: Arc<AtomicBool>,
+    pty_writer: Arc<AtomicBool>,
+    sender: Arc<AtomicBool>,
+    receiver: Arc<AtomicBool>,
+}
+
+/// Snapshot of a [`BridgeProbe`].
+#[derive(Debug, serde::Serialize)]
+pub struct BridgeState {
+    pub pty_reader_running: bool,
+    pub pty_writer_running: bool,
+    pub sender_running: bool,
+    pub receiver_running: bool,
+    pub output_queued: usize,
+    pub output_queue_capacity: usize,
+    pub window: u64,
+    pub frames_sent: u64,
+}
+
+/// Heartbeat round trips of a bridge, in milliseconds.
+#[derive(Debug, Default, Clone, Copy, PartialEq, serde::Serialize)]
+pub struct RttStats {
+    pub samples: u64,
+    pub last_ms: Option<f64>,
+    /// Moving average with TCP's gain of 1/8
+    pub smoothed_ms: Option<f64>,
+    pub min_ms: Option<f64>,
+    pub max_ms: Option<f64>,
+}
+
+impl RttStats {
+    fn sample(&mut self, rtt: Duration) {
+        let ms = rtt.as_secs_f64() * 1000.0;
+        self.samples += 1;
+        self.last_ms = Some(ms);
+        self.smoothed_ms = Some(self.smoothed_ms.map_or(ms, |s| s + (ms - s) / 8.0));
+        self.min_ms = Some(self.min_ms.map_or(ms, |m| m.min(ms)));
+        self.max_ms = Some(self.max_ms.map_or(ms, |m| m.max(ms)));
+    }
+}
+
+/// Counters of a session's current attachment, answer to `connection_stats`.
+#[derive(Debug, serde::Serialize)]
+pub struct ConnectionStats {
+    pub attached_secs: u64,
+    pub frames_sent: u64,
+    pub frames_received: u64,
+    /// Scrollback replays sent to reattaching clients over the session's life
+    pub resyncs: u64,
+    pub heartbeats_sent: u64,
+    pub heartbeat_rtt: RttStats,
+    /// Time output waited on the client's flow control window
+    pub stall_secs: f64,
+    pub window: u64,
+    pub output_queued: usize,
+}
+
+/// What became of a session's output frames under compression, counted
+/// by its bridges.
+#[derive(Debug, Default)]
+pub struct CompressionCounters {
+    compressed: AtomicU64,
+    bypassed: AtomicU64,
+    incompressible: AtomicU64,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+}
+
+/// Snapshot of [`CompressionCounters`], for `inspect_session`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, serde::Serialize)]
+pub struct CompressionReport {
+    pub compressed: u64,
+    /// Not tried: they looked compressed already
+    pub bypassed: u64,
+    /// Tried, but they didn't get smaller
+    pub incompressible: u64,
+    /// Share of the frames tried or skipped that were skipped
+    pub bypass_ratio: f64,
+    /// Compressed size over original size, of the frames compressed
+    pub compression_ratio: Option<f64>,
+}
+
+impl CompressionCounters {
+    fn add(&self, stats: CompressionStats) {
+        self.compressed.fetch_add(stats.compressed, Ordering::Relaxed);
+        self.bypassed.fetch_add(stats.bypassed, Ordering::Relaxed);
+        self.incompressible.fetch_add(stats.incompressible, Ordering::Relaxed);
+        self.bytes_in.fetch_add(stats.bytes_in, Ordering::Relaxed);
+        self.bytes_out.fetch_add(stats.bytes_out, Ordering::Relaxed);
+    }
+
+    pub fn report(&self) -> CompressionReport {
+        let compressed = self.compressed.load(Ordering::Relaxed);
+        let bypassed = self.bypassed.load(Ordering::Relaxed);
+        let incompressible = self.incompressible.load(Ordering::Relaxed);
+        let bytes_in = self.bytes_in.load(Ordering::Relaxed);
+        let considered = compressed + bypassed + incompressible;
+        CompressionReport {
+            compressed,
+            bypassed,
+            incompressible,
+            bypass_ratio: if considered == 0 { 0.0 } else { bypassed as f64 / considered as f64 },
+            compression_ratio: (bytes_in > 0).then(|| self.bytes_out.load(Ordering::Relaxed) as f64 / bytes_in as f64),
+        }
+    }
+}
+
+impl BridgeProbe {
+    fn new(window: Arc<AtomicU64>) -> Self {
+        Self {
+            window,
+            queued: AtomicUsize::new(0),
+            frames_sent: AtomicU64::new(0),
+            frames_received: AtomicU64::new(0),
+            heartbeats_sent: AtomicU64::new(0),
+            ping: Mutex::new(None),
+            rtt: Mutex::default(),
+            stalled_us: AtomicU64::new(0),
+            traffic_sent: AtomicU64::new(0),
+            traffic_received: AtomicU64::new(0),
+            heard_us: AtomicU64::new(0),
+            attached_at: Instant::now(),
+            pty_reader: Arc::default(),
+            pty_writer: Arc::default(),
+            sender: Arc::default(),
+            receiver: Arc::default(),
+        }
+    }
+
+    pub fn state(&self) -> BridgeState {
+        BridgeState {
+            pty_reader_running: self.pty_reader.load(Ordering::Relaxed),
+            pty_writer_running: self.pty_writer.load(Ordering::Relaxed),
+            sender_running: self.sender.load(Ordering::Relaxed),
+            receiver_running: self.receiver.load(Ordering::Relaxed),
+            output_queued: self.queued.load(Ordering::Relaxed),
+            output_queue_capacity: OUTPUT_QUEUE_CHUNKS,
+            window: self.window.load(Ordering::Relaxed),
+            frames_sent: self.frames_sent.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Counters of this attachment; `resyncs` is kept by the session.
+    pub fn stats(&self, resyncs: u64) -> ConnectionStats {
+        ConnectionStats {
+            attached_secs: self.attached_at.elapsed().as_secs(),
+            frames_sent: self.frames_sent.load(Ordering::Relaxed),
+            frames_received: self.frames_received.load(Ordering::Relaxed),
+            resyncs,
+            heartbeats_sent: self.heartbeats_sent.load(Ordering::Relaxed),
+            heartbeat_rtt: *self.rtt.lock().expect("rtt lock"),
+            stall_secs: self.stalled_us.load(Ordering::Relaxed) as f64 / 1e6,
+            window: self.window.load(Ordering::Relaxed),
+            output_queued: self.queued.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Traffic since the last call, for the bandwidth ledger.
+    pub fn take_traffic(&self) -> Usage {
+        Usage {
+            sent: self.traffic_sent.swap(0, Ordering::Relaxed),
+            received: self.traffic_received.swap(0, Ordering::Relaxed),
+        }
+    }
+
+    /// Start a heartbeat ping, returning its id (the Heartbeat payload).
+    fn ping(&self) -> u64 {
+        let id = self.heartbeats_sent.fetch_add(1, Ordering::Relaxed) + 1;
+        *self.ping.lock().expect("ping lock") = Some((id, Instant::now()));
+        id
+    }
+
+    /// Anything arrived from the client.
+    fn heard(&self) {
+        self.heard_us.store(self.attached_at.elapsed().as_micros() as u64, Ordering::Relaxed);
+    }
+
+    /// How long since the client was last heard from.
+    fn silent_for(&self) -> Duration {
+        self.attached_at.elapsed().saturating_sub(Duration::from_micros(self.heard_us.load(Ordering::Relaxed)))
+    }
+
+    /// The client has echoed a ping, so its silence means it's gone rather
+    /// than that it predates heartbeat echoes.
+    fn answers_pings(&self) -> bool {
+        self.rtt.lock().expect("rtt lock").samples > 0
+    }
+
+    /// A Heartbeat from the client: an echo of the outstanding ping is a
+    /// round-trip sample. Late echoes of earlier pings are ignored.
+    fn pong(&self, payload: &[u8]) {
+        let Ok(id) = <[u8; 8]>::try_from(payload).map(u64::from_be_bytes) else {
+            return;
+        };
+        let mut ping = self.ping.lock().expect("ping lock");
+        if let Some((outstanding, sent_at)) = *ping {
+            if outstanding == id {
+                *ping = None;
+                self.rtt.lock().expect("rtt lock").sample(sent_at.elapsed());
+            }
+        }
+    }
+}
+
+/// Marks a bridge task running until dropped, however the task ends.
+struct Running(Arc<AtomicBool>);
+
+impl Running {
+    fn new(flag: &Arc<AtomicBool>) -> Self {
+        flag.store(true, Ordering::Relaxed);
+        Self(flag.clone())
+    }
+}
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::Relaxed);
+    }
+}
+
+/// Per-attachment bridge settings.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BridgeOptions {
+    /// Nagle-style micro-batching window for small output chunks.
+    /// `None` sends each chunk as soon as it is read.
+    pub coalesce: Option<Duration>,
+    /// Drop client input and resizes (guest viewers)
+    pub read_only: bool,
+    /// End the bridge at this time (guest access expiry)
+    pub deadline: Option<tokio::time::Instant>,
+    /// Throttle output to what's left on screen every
+    /// [`crate::throttle::FLUSH_INTERVAL`] (metered connections)
+    pub low_bandwidth: bool,
+    /// Cap output at this many bytes a second (metered connections)
+    pub max_bytes_per_sec: Option<u64>,
+    /// End Data frames on UTF-8 codepoint boundaries, and flag frames that
+    /// aren't UTF-8 with `FLAG_BINARY`
+    pub utf8_frames: bool,
+    /// End the bridge on client frame types this build doesn't know,
+    /// instead of skipping them
+    pub strict_frames: bool,
+    /// Detach a client that answers pings once it's been silent this long
+    pub liveness: Option<Duration>,
+    /// Rate limit on the client's input
+    pub input_limit: InputLimit,
+    /// Skip input frames numbered at or below the last this device wrote to
+    /// the session: the client resent them after a reconnect
+    pub dedup_input: bool,
+    /// Replace a PTY reader blocked this long with output waiting (see
+    /// [`crate::watchdog`])
+    pub read_stall: Option<Duration>,
+    /// Send output with this much entropy per byte uncompressed untried
+    pub compression_bypass: Option<f64>,
+    /// Frame types, compression and payload size the client takes
+    pub wire: Wire,
+}
+
+impl BridgeOptions {
+    /// Resolve options from a create/attach request, falling back to daemon defaults.
+    fn from_request(req: &serde_json::Value, defaults: &BridgeConfig) -> Self {
+        let coalesce_ms = req["coalesce_ms"]
+            .as_u64()
+            .unwrap_or(defaults.coalesce_ms)
+            .min(MAX_COALESCE_MS);
+        Self {
+            coalesce: (coalesce_ms > 0).then(|| Duration::from_millis(coalesce_ms)),
+            low_bandwidth: req["low_bandwidth"].as_bool().unwrap_or(false),
+            max_bytes_per_sec: req["max_bytes_per_sec"].as_u64().filter(|&cap| cap > 0),
+            utf8_frames: req["utf8_frames"].as_bool().unwrap_or(false),
+            dedup_input: req["dedup_input"].as_bool().unwrap_or(false),
+            strict_frames: defaults.unknown_frames == UnknownFramePolicy::Close,
+            liveness: (defaults.liveness_timeout_secs > 0).then(|| Duration::from_secs(defaults.liveness_timeout_secs)),
+            input_limit: defaults.input_limit,
+            read_stall: (defaults.read_stall_secs > 0).then(|| Duration::from_secs(defaults.read_stall_secs)),
+            compression_bypass: Some(defaults.compression_bypass_entropy).filter(|&bits| bits > 0.0),
+            wire: hello::current(),
+            ..Self::default()
+        }
+    }
+}
+
+/// The daemon's effective settings that shape what the app shows: limits,
+/// timeouts and feature toggles, without paths, commands or addresses.
+fn daemon_config(session_manager: &SessionManager, policy: &DevicePolicy, request_id: &str) -> serde_json::Value {
+    let bridge = session_manager.bridge_config();
+    let secs = |secs: u64| (secs > 0).then_some(secs);
+    serde_json::json!({
+        "type": "daemon_config",
+        "request_id": request_id,
+        "session": {
+            "scrollback_bytes": session_manager.scrollback_bytes(),
+            "max_sessions": session_manager.max_sessions(),
+            "exited_retention_secs": session_manager.exited_retention().as_secs(),
+            "damaged": session_manager.damaged_policy(),
+        },
+        "bridge": {
+            "coalesce_ms": bridge.coalesce_ms,
+            "strict_frames": bridge.unknown_frames == UnknownFramePolicy::Close,
+            "liveness_timeout_secs": secs(bridge.liveness_timeout_secs),
+            "stream_first_message_secs": secs(bridge.stream_first_message_secs),
+            "stream_idle_secs": secs(bridge.stream_idle_secs),
+            "max_streams": (bridge.max_streams > 0).then_some(bridge.max_streams),
+            "read_stall_secs": secs(bridge.read_stall_secs),
+            // This device's
+            "input": Access::Device(policy).input_limit(bridge),
+        },
+        // As in server_info
+        "features": server_info(session_manager, policy, request_id)["features"].take(),
+    })
+}
+
+/// What the peer on a control stream may do.
+#[derive(Clone, Copy)]
+enum Access<'a> {
+    /// A paired device, with the access its policy grants: full, unless it
+    /// is restricted or (multi-user mode) scoped to a user
+    Device(&'a DevicePolicy),
+    /// Only read-only attach to (or mirror of) the guest's session, until it expires
+    Guest(&'a Guest),
+}
+
+impl Access<'_> {
+    /// User this peer's sessions run as, in multi-user mode.
+    fn user(&self) -> Option<&str> {
+        match self {
+            Access::Device(policy) => policy.user.as_deref(),
+            Access::Guest(_) => None,
+        }
+    }
+
+    fn restrictions(&self) -> Option<&Restrictions> {
+        match self {
+            Access::Device(policy) => policy.restrictions.as_ref(),
+            Access::Guest(_) => None,
+        }
+    }
+
+    /// The rate limit on input for this peer's role.
+    fn input_limit(&self, config: &BridgeConfig) -> InputLimit {
+        match self.restrictions() {
+            Some(_) => config.restricted_input_limit,
+            None => config.input_limit,
+        }
+    }
+
+    /// Whether `device_id` may see a session running as `owner`, created by
+    /// `created_by`: restricted devices only see their own sessions. Guests
+    /// are limited to their own session before requests get this far.
+    fn can_see(&self, device_id: &str, owner: Option<&str>, created_by: Option<&str>) -> bool {
+        if self.restrictions().is_some() && created_by != Some(device_id) {
+            return false;
+        }
+        match self.user() {
+            Some(user) => owner == Some(user),
+            None => true,
+        }
+    }
+}
+
+/// Session `id`, if it exists and `device_id` may see it.
+fn visible_session(
+    session_manager: &SessionManager,
+    access: &Access<'_>,
+    device_id: &str,
+    id: &str,
+) -> Option<Arc<Mutex<PtySession>>> {
+    session_manager.get_session(id).filter(|session| {
+        let s = session.lock().expect("session lock");
+        access.can_see(device_id, s.user.as_deref(), s.created_by_device_id.as_deref())
+    })
+}
+
+/// Signals `kill_foreground` may send, by name.
+fn foreground_signal(name: &str) -> Option<i32> {
+    match name {
+        "INT" => Some(signal::SIGINT),
+        "TERM" => Some(signal::SIGTERM),
+        "HUP" => Some(signal::SIGHUP),
+        "KILL" => Some(signal::SIGKILL),
+        _ => None,
+    }
+}
+
+/// When a guest's access ends, as a tokio instant.
+fn guest_deadline(guest: &Guest) -> tokio::time::Instant {
+    let remaining = (guest.expires_at - chrono::Utc::now()).to_std().unwrap_or_default();
+    tokio::time::Instant::now() + remaining
+}
 
 /// Handle session requests on a control stream from an authenticated client.
 /// Loops to handle multiple control requests (list, destroy) on the same stream.
-/// Exits when create/attach transitions to bridge mode, or the stream ends.
+/// Exits when a create/attach bridge ends, or the stream ends; a bridge the
+/// client detaches from with a `detach_session` Close goes back to the loop.
 pub async fn handle_session_stream(
+    send: SendStream,
+    recv: RecvStream,
+    session_manager: &SessionManager,
+    device_id: &str,
+    policy: &DevicePolicy,
+    deadlines: StreamDeadlines,
+) -> Result<()> {
+    let policy = effective_policy(session_manager, policy);
+    serve_session_stream(send, recv, session_manager, device_id, Access::Device(&policy), deadlines).await
+}
+
+/// How long a session stream may go without a request before it is closed
+/// with a `TIMEOUT` error: `first` after it is opened, `idle` after each
+/// request (or detach). `None` waits indefinitely.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StreamDeadlines {
+    pub first: Option<Duration>,
+    pub idle: Option<Duration>,
+}
+
+impl StreamDeadlines {
+    /// `[bridge] stream_first_message_secs` and `stream_idle_secs`.
+    pub fn from_config(config: &BridgeConfig) -> Self {
+        let secs = |secs: u64| (secs > 0).then(|| Duration::from_secs(secs));
+        Self { first: secs(config.stream_first_message_secs), idle: secs(config.stream_idle_secs) }
+    }
+}
+
+/// The user a device was paired for only applies in multi-user mode.
+fn effective_policy(session_manager: &SessionManager, policy: &DevicePolicy) -> DevicePolicy {
+    let mut policy = policy.clone();
+    if !session_manager.multi_user() {
+        policy.user = None;
+    }
+    policy
+}
+
+/// Announce the daemon's capabilities right after auth, to clients that
+/// asked for it, as a reply to their auth request.
+pub async fn send_server_info(
+    send: &mut SendStream,
+    session_manager: &SessionManager,
+    policy: &DevicePolicy,
+    request_id: &str,
+) -> Result<()> {
+    let policy = effective_policy(session_manager, policy);
+    write_json(send, &server_info(session_manager, &policy, request_id)).await
+}
+
+/// Answer a client that said hello at auth with what the connection uses.
+pub async fn send_hello(send: &mut SendStream, request_id: &str) -> Result<()> {
+    let mut msg = hello::current().describe();
+    msg["type"] = "hello".into();
+    msg["request_id"] = request_id.into();
+    msg["version"] = crate::VERSION.into();
+    write_json(send, &msg).await
+}
+
+/// Tell a client that asked for it at auth which daemon it's talking to and
+/// the oldest app version that daemon supports, so it can ask for an update
+/// (of itself or of the daemon) before anything fails to decode.
+pub async fn send_version_advisory(
+    send: &mut SendStream,
+    session_manager: &SessionManager,
+    request_id: &str,
+) -> Result<()> {
+    let clients = session_manager.clients_config();
+    let msg = serde_json::json!({
+        "type": "version_advisory",
+        "request_id": request_id,
+        "version": crate::VERSION,
+        "protocol_version": crate::PROTOCOL_VERSION,
+        "min_client_version": clients.min_version,
+        "release_notes": clients.release_notes,
+    });
+    write_json(send, &msg).await
+}
+
+/// Tell a device that resumed with a resume token which sessions it still
+/// has attached, to reattach each with `takeover`.
+pub async fn send_resumable_sessions(send: &mut SendStream, session_ids: &[String], request_id: &str) -> Result<()> {
+    let msg = serde_json::json!({
+        "type": "resumable_sessions",
+        "request_id": request_id,
+        "session_ids": session_ids,
+    });
+    write_json(send, &msg).await
+}
+
+/// Version, features this device may use, limits and current addresses, so
+/// clients feature-detect instead of probing with requests that fail.
+fn server_info(session_manager: &SessionManager, policy: &DevicePolicy, request_id: &str) -> serde_json::Value {
+    let restricted = policy.restrictions.is_some();
+    let scoped = restricted || policy.user.is_some();
+    serde_json::json!({
+        "type": "server_info",
+        "request_id": request_id,
+        "name": crate::device_store::hostname(),
+        "version": crate::VERSION,
+        "protocol_version": crate::PROTOCOL_VERSION,
+        "features": {
+            // One driver plus any number of read-only mirrors per session
+            "multi_attach": true,
+            // attach_session with `takeover` replaces this device's own stale bridge
+            "takeover": true,
+            // create/attach with `low_bandwidth` throttles output to what's on screen
+            "low_bandwidth": true,
+            // create/attach with `max_bytes_per_sec` caps the attachment's output rate
+            "bandwidth_cap": true,
+            // Counters of an attachment, with heartbeat round trips from echoed pings
+            "connection_stats": true,
+            // create/attach with `utf8_frames` ends Data frames on codepoint boundaries
+            "utf8_frames": true,
+            // create/attach with `dedup_input` skips input the session already took
+            "dedup_input": true,
+            // Devices can pair a hardware key and answer challenges with WebAuthn assertions
+            "webauthn": true,
+            // get_daemon_config returns the settings that shape the app's UI
+            "daemon_config": !restricted,
+            // record_macro / list_macros / delete_macro / run_macro
+            "macros": true,
+            // list_schedules / scheduled_run
+            "schedules": !scoped,
+            // Unnamed sessions are named after their first command, pushed as
+            // `session_renamed` on a unidirectional stream
+            "session_names": true,
+            // The reaper pushes `session_removed` (with its reason and exit code)
+            "session_removed": true,
+            // create_session with `budget` limits a session's CPU time and
+            // lifetime, warned of with `session_budget_warning`
+            "session_budgets": true,
+            // handoff_session moves an attached session to another connected
+            // device, which attaches with the pushed `handoff_offered`
+            "handoff": true,
+            // attach_session with `replay_bytes`, then fetch_scrollback for older pages
+            "scrollback_paging": true,
+            // auth_response carries a `resume_token`; presenting it on reconnect
+            // skips the challenge and is answered with `resumable_sessions`
+            "resume_tokens": true,
+            // Devices are pushed `pairing_request` when a new device asks to be
+            // paired, and answer with approve_pairing / deny_pairing
+            "vouched_pairing": !restricted,
+            "exec": !restricted,
+            "tmux": !scoped,
+            "agent_forwarding": policy.agent_forwarding && !scoped,
+            "clipboard": session_manager.clipboard().allows_push() && !scoped,
+            // create_session with `log_output`, and set_output_log, tee a
+            // session's output into a rotated log on the host
+            "output_log": session_manager.output_logging() && !scoped,
+            // Paste frames are bracketed when the app asks for it, written
+            // in paced chunks and acknowledged with the bytes written
+            "paste": true,
+            // auth_request with `hello` settles frame types, compression and
+            // payload size, answered with a `hello` message
+            "hello": true,
+            "compression": true,
+            "multi_user": session_manager.multi_user(),
+            "file_transfer": false,
+            "datagrams": false,
+            "cbor": false,
+        },
+        // Languages `message` in errors is translated to, picked at auth
+        "languages": messages::Language::ALL.map(messages::Language::tag),
+        "limits": {
+            "max_payload": frame::MAX_PAYLOAD,
+            "max_control_message": crate::auth::MAX_CONTROL_MESSAGE,
+            "max_sessions": session_manager.max_sessions(),
+            "max_exec_timeout_secs": exec::MAX_TIMEOUT.as_secs(),
+            // Input past this is queued, then dropped with an Error frame
+            "input": Access::Device(policy).input_limit(session_manager.bridge_config()),
+        },
+        "hosts": crate::addresses::candidates(),
+        "wake": session_manager.wake_info(),
+        // Set while this host moves to another: trust its fingerprint too
+        "successor": session_manager.successor(),
+        // What a connection's close code means, to explain a disconnect
+        "close_codes": crate::connections::close_codes(),
+    })
+}
+
+/// Handle a control stream from a guest: the only permitted requests are
+/// attaching to or mirroring the guest's session, read-only, until the guest
+/// link expires.
+pub async fn handle_guest_stream(
+    send: SendStream,
+    recv: RecvStream,
+    session_manager: &SessionManager,
+    guest: &Guest,
+    deadlines: StreamDeadlines,
+) -> Result<()> {
+    serve_session_stream(send, recv, session_manager, &guest.id, Access::Guest(guest), deadlines).await
+}
+
+async fn serve_session_stream(
     mut send: SendStream,
     mut recv: RecvStream,
     session_manager: &SessionManager,
     device_id: &str,
+    access: Access<'_>,
+    deadlines: StreamDeadlines,
 ) -> Result<()> {
+    let mut deadline = deadlines.first.map(StreamTimeout::FirstMessage);
     loop {
-        // Read the session request (length-prefixed JSON like control messages)
-        let mut len_buf = [0u8; 4];
-        match recv.read_exact(&mut len_buf).await {
-            Ok(()) => {}
-            Err(e) => {
-                // Stream closed or reset — normal disconnect
-                info!("session stream read ended: {e}");
-                return Ok(());
-            }
-        }
-        let len = u32::from_be_bytes(len_buf) as usize;
-
-        let mut msg_buf = vec![0u8; len];
-        recv.read_exact(&mut msg_buf)
-            .await
-            .context("read session request body")?;
+        let read = match deadline {
+            Some(timeout) => match tokio::time::timeout(timeout.limit(), read_request(&mut recv)).await {
+                Ok(read) => read?,
+                Err(_) => {
+                    let _ = write_error(&mut send, "", ErrorCode::Timeout, &timeout.to_string()).await;
+                    let _ = send.finish();
+                    return Err(timeout.into());
+                }
+            },
+            None => read_request(&mut recv).await?,
+        };
+        let Some(msg_buf) = read else {
+            return Ok(());
+        };
+        deadline = deadlines.idle.map(StreamTimeout::Idle);
 
         let req: serde_json::Value =
             serde_json::from_slice(&msg_buf).context("parse session request")?;
 
         let msg_type = req["type"].as_str().unwrap_or("");
 
+        if let Access::Guest(guest) = access {
+            let request_id = req["request_id"].as_str().unwrap_or("");
+            let own_session = req["session_id"].as_str() == Some(guest.session_id.as_str());
+            if !matches!(msg_type, "attach_session" | "mirror_session") || !own_session {
+                warn!("guest {} denied {msg_type}", guest.id);
+                write_error(&mut send, request_id, ErrorCode::PermissionDenied, "not permitted for guest access").await?;
+                continue;
+            }
+            let busy = msg_type == "attach_session"
+                && session_manager
+                    .get_session(&guest.session_id)
+                    .is_some_and(|s| s.lock().expect("session lock").attached);
+            if busy {
+                write_failure(&mut send, request_id, &SessionError::AlreadyAttached("session is in use; mirror it instead").into()).await?;
+                continue;
+            }
+        }
+
+        // In multi-user mode a device without a user would get the daemon's
+        // (root's) shells
+        if session_manager.multi_user() && matches!(access, Access::Device(DevicePolicy { user: None, .. })) {
+            let request_id = req["request_id"].as_str().unwrap_or("");
+            warn!("device {device_id} denied {msg_type}: not paired for a user");
+            write_error(&mut send, request_id, ErrorCode::NotPaired, "device is not paired for a user; re-pair it with `phantom pair --user`").await?;
+            continue;
+        }
+
+        let owner_only = matches!(
+            msg_type,
+            "exec" | "list_tmux_sessions" | "set_clipboard" | "approve_pairing" | "deny_pairing" | "get_daemon_config"
+        );
+        if access.restrictions().is_some() && owner_only {
+            let request_id = req["request_id"].as_str().unwrap_or("");
+            warn!("restricted device {device_id} denied {msg_type}");
+            write_error(&mut send, request_id, ErrorCode::PermissionDenied, "not permitted for this device").await?;
+            continue;
+        }
+
+        // A resend of a request that already ran gets the original response.
+        // For create/attach that means (re)attaching the session it named.
+        let mut pending = None;
+        let mut replayed = None;
+        let request_id = req["request_id"].as_str().unwrap_or("");
+        if matches!(msg_type, "create_session" | "attach_session" | "destroy_session") && !request_id.is_empty() {
+            match session_manager.recent_requests().claim(device_id, msg_type, request_id).await {
+                Ok(Claim::New(claimed)) => pending = Some(claimed),
+                Ok(Claim::Replay(resp)) => {
+                    info!("device {device_id} resent {msg_type} {request_id}");
+                    if msg_type == "destroy_session" {
+                        write_json(&mut send, &resp).await?;
+                        continue;
+                    }
+                    replayed = Some(resp);
+                }
+                Err(e) => {
+                    write_failure(&mut send, request_id, &e).await?;
+                    continue;
+                }
+            }
+        }
+        let msg_type = if replayed.is_some() { "attach_session" } else { msg_type };
+
         match msg_type {
             "create_session" => {
                 let rows = (req["rows"].as_u64().unwrap_or(24) as u16).clamp(1, 500);
                 let cols = (req["cols"].as_u64().unwrap_or(80) as u16).clamp(1, 500);
                 let request_id = req["request_id"].as_str().unwrap_or("");
+                let mut opts = BridgeOptions::from_request(&req, session_manager.bridge_config());
+                opts.input_limit = access.input_limit(session_manager.bridge_config());
+                // Passthrough mode: attach to an existing tmux session instead of a new shell
+                let tmux_session = req["tmux_session"].as_str();
+                let agent_forwarding = req["agent_forwarding"].as_bool().unwrap_or(false);
+                let log_output = req["log_output"].as_bool().unwrap_or(false);
+                let requested_command = req["command"].as_str().filter(|c| !c.trim().is_empty());
+                let name = match req["name"].as_str().map(naming::validate).transpose() {
+                    Ok(name) => name,
+                    Err(e) => {
+                        write_error(&mut send, request_id, ErrorCode::BadRequest, &e).await?;
+                        continue;
+                    }
+                };
+                let terminal = match TerminalCaps::from_request(&req) {
+                    Ok(terminal) => terminal,
+                    Err(e) => {
+                        write_failure(&mut send, request_id, &e).await?;
+                        continue;
+                    }
+                };
+                let budget = match Budget::from_request(&req) {
+                    Ok(budget) => budget,
+                    Err(e) => {
+                        write_failure(&mut send, request_id, &e).await?;
+                        continue;
+                    }
+                };
+                if requested_command.is_some() && (agent_forwarding || tmux_session.is_some()) {
+                    let refusal = "command can't be combined with tmux_session or agent_forwarding";
+                    write_error(&mut send, request_id, ErrorCode::BadRequest, refusal).await?;
+                    continue;
+                }
+                if access.restrictions().is_some() && (agent_forwarding || tmux_session.is_some()) {
+                    write_error(&mut send, request_id, ErrorCode::PermissionDenied, "not permitted for this device").await?;
+                    continue;
+                }
+                let command = match access.restrictions() {
+                    Some(restrictions) => match restrictions.session_command(requested_command) {
+                        Ok(command) => Some(command),
+                        Err(e) => {
+                            warn!("create_session rejected for device {device_id}: {e:#}");
+                            write_failure(&mut send, request_id, &e).await?;
+                            continue;
+                        }
+                    },
+                    None => requested_command.map(str::to_string),
+                };
+                if access.user().is_some() && (agent_forwarding || tmux_session.is_some()) {
+                    let refusal = "tmux passthrough and agent forwarding are not available in multi-user mode";
+                    write_error(&mut send, request_id, ErrorCode::PermissionDenied, refusal).await?;
+                    continue;
+                }
+                if access.user().is_some() && log_output {
+                    let refusal = "output logging is not available in multi-user mode";
+                    write_error(&mut send, request_id, ErrorCode::PermissionDenied, refusal).await?;
+                    continue;
+                }
+                if agent_forwarding {
+                    let refusal = if !matches!(access, Access::Device(DevicePolicy { agent_forwarding: true, .. })) {
+                        Some((ErrorCode::PermissionDenied, "agent forwarding is not permitted for this device"))
+                    } else if tmux_session.is_some() {
+                        Some((ErrorCode::BadRequest, "agent forwarding is not available for tmux sessions"))
+                    } else {
+                        None
+                    };
+                    if let Some((code, refusal)) = refusal {
+                        write_error(&mut send, request_id, code, refusal).await?;
+                        continue;
+                    }
+                }
+                if let Some(name) = tmux_session {
+                    let found = match tmux::validate_name(name) {
+                        Ok(()) => session_manager.tmux().has_session(name).await,
+                        Err(e) => Err(e),
+                    };
+                    match found {
+                        Ok(true) => {}
+                        Ok(false) => {
+                            write_error(&mut send, request_id, ErrorCode::NotFound, &format!("tmux session not found: {name}")).await?;
+                            continue;
+                        }
+                        Err(e) => {
+                            warn!("create_session rejected: {e:#}");
+                            write_failure(&mut send, request_id, &e).await?;
+                            continue;
+                        }
+                    }
+                }
 
-                let session_id = session_manager
-                    .create_session(rows, cols, Some(device_id))
-                    .context("create session")?;
+                let created = session_manager.reserve(BRIDGE_MEMORY_BYTES).and_then(|memory| {
+                    let id = match tmux_session {
+                        Some(name) => session_manager.create_tmux_session(name, rows, cols, Some(device_id), &terminal)?,
+                        None if agent_forwarding => {
+                            session_manager.create_agent_session(rows, cols, device_id, &terminal)?
+                        }
+                        None => {
+                            let launch = Launch {
+                                user: access.user(),
+                                command: command.as_deref(),
+                                restrictions: access.restrictions(),
+                                terminal: Some(&terminal),
+                            };
+                            session_manager.create_session(rows, cols, Some(device_id), launch)?
+                        }
+                    };
+                    if let Some(budget) = budget {
+                        session_manager.set_budget(&id, budget)?;
+                    }
+                    Ok((id, memory))
+                });
+                let (session_id, memory) = match created {
+                    Ok(created) => created,
+                    Err(e) => {
+                        warn!("create_session rejected: {e:#}");
+                        write_failure(&mut send, request_id, &e).await?;
+                        continue;
+                    }
+                };
 
                 // Set initial attach metadata (create immediately enters bridge)
                 if let Some(session) = session_manager.get_session(&session_id) {
                     let mut s = session.lock().expect("session lock");
                     s.last_attached_at = Some(chrono::Utc::now());
                     s.last_attached_by = Some(device_id.to_string());
+                    s.name = name.map(str::to_string);
                 }
 
-                let resp = serde_json::json!({
+                let mut resp = serde_json::json!({
                     "type": "session_created",
                     "request_id": request_id,
                     "session_id": session_id,
+                    "terminal": terminal,
                 });
+                if let Some(name) = tmux_session {
+                    resp["tmux_session"] = name.into();
+                }
+                if let Some(name) = name {
+                    resp["name"] = name.into();
+                }
+                if agent_forwarding {
+                    resp["agent_forwarding"] = true.into();
+                }
+                if let Some(budget) = budget {
+                    resp["budget"] = serde_json::json!(budget);
+                }
+                if log_output {
+                    // The session is running either way; a log that can't be
+                    // opened just leaves `output_log` out
+                    match session_manager.set_output_log(&session_id, true) {
+                        Ok(path) => resp["output_log"] = serde_json::json!(path),
+                        Err(e) => warn!("session {session_id}: output log not opened: {e:#}"),
+                    }
+                }
+                if let Some(pending) = pending {
+                    pending.complete(&resp);
+                }
                 write_json(&mut send, &resp).await?;
 
                 // Transition to bridge mode (consumes the stream)
-                return run_bridge(send, recv, session_manager, &session_id).await;
+                let Some(detached) =
+                    run_bridge(send, recv, session_manager, &session_id, opts, Vec::new(), memory, false).await?
+                else {
+                    return Ok(());
+                };
+                (send, recv) = ack_detach(detached, session_manager, &session_id).await?;
+                // Continue looping for more requests
             }
             "attach_session" => {
-                let session_id = req["session_id"]
-                    .as_str()
-                    .context("missing session_id")?;
                 let request_id = req["request_id"].as_str().unwrap_or("");
+                // A resent create names its session only in the original response
+                let session_id = match &replayed {
+                    Some(resp) => resp["session_id"].as_str(),
+                    None => req["session_id"].as_str(),
+                };
+                let Some(session_id) = session_id else {
+                    write_error(&mut send, request_id, ErrorCode::BadRequest, "missing session_id").await?;
+                    continue;
+                };
+                let mut opts = BridgeOptions::from_request(&req, session_manager.bridge_config());
+                opts.input_limit = access.input_limit(session_manager.bridge_config());
+                if let Access::Guest(guest) = access {
+                    opts.read_only = true;
+                    opts.deadline = Some(guest_deadline(guest));
+                }
+
+                let Some(session) = visible_session(session_manager, &access, device_id, session_id) else {
+                    write_failure(&mut send, request_id, &SessionError::NotFound.into()).await?;
+                    continue;
+                };
+                let (damaged, exited, attached, attached_here) = {
+                    let s = session.lock().expect("session lock");
+                    (s.damaged, s.exited.is_some(), s.attached, s.last_attached_by.as_deref() == Some(device_id))
+                };
+                if damaged {
+                    write_failure(&mut send, request_id, &SessionError::Damaged.into()).await?;
+                    continue;
+                }
+                if exited {
+                    write_failure(&mut send, request_id, &SessionError::Exited.into()).await?;
+                    continue;
+                }
+                // Handed over by the device driving it: its bridge gives way
+                let handoff = match req["handoff_id"].as_str() {
+                    Some(id) => match session_manager.handoffs().claim(id, device_id, session_id) {
+                        Ok(claim) => Some(claim),
+                        Err(e) => {
+                            write_failure(&mut send, request_id, &e).await?;
+                            continue;
+                        }
+                    },
+                    None => None,
+                };
+                // A resend's original bridge is likely on a stream the client
+                // gave up on, as is the bridge of an app relaunched before its
+                // old stream timed out (`takeover`): take the session over
+                let takeover = replayed.is_some() || req["takeover"].as_bool().unwrap_or(false);
+                let handing_off = handoff.is_some();
+                let claimed = attached && (handing_off || attached_here && takeover) && take_over(&session).await;
+                if attached && !claimed {
+                    let refusal = match (attached_here || handing_off, takeover || handing_off) {
+                        (true, true) => "the previous bridge did not detach in time; try again",
+                        (true, false) => "session is already attached from this device; attach with takeover to replace it",
+                        (false, _) => "session is attached elsewhere; mirror it instead",
+                    };
+                    write_failure(&mut send, request_id, &SessionError::AlreadyAttached(refusal).into()).await?;
+                    continue;
+                }
+                if claimed && handing_off {
+                    info!("device {device_id} took over session {session_id} by handoff");
+                } else if claimed {
+                    info!("device {device_id} took over session {session_id}");
+                }
 
-                let session = session_manager
-                    .get_session(session_id)
-                    .context("session not found")?;
+                let memory = match session_manager.reserve(BRIDGE_MEMORY_BYTES) {
+                    Ok(memory) => memory,
+                    Err(e) => {
+                        warn!("attach_session rejected: {e:#}");
+                        if claimed {
+                            session.lock().expect("session lock").attached = false;
+                        }
+                        write_failure(&mut send, request_id, &e).await?;
+                        continue;
+                    }
+                };
 
                 // Update attach metadata
                 {
@@ -88,102 +1066,744 @@ pub async fn handle_session_stream(
                     s.last_attached_by = Some(device_id.to_string());
                 }
 
-                let resp = serde_json::json!({
-                    "type": "session_attached",
-                    "request_id": request_id,
-                    "session_id": session_id,
-                });
-                write_json(&mut send, &resp).await?;
-
-                // Send scrollback before live data
-                let scrollback_data = {
-                    let s = session.lock().expect("session lock");
-                    let sb = s.scrollback.clone();
-                    drop(s);
-                    let data = sb.lock().expect("scrollback lock").read_from_clean_point();
-                    data
+                // Scrollback is replayed by the bridge before live data: all of
+                // it, or with `replay_bytes` only the latest, the rest paged
+                // in with fetch_scrollback. A handoff replays what came since
+                // its handoff point, which the previous device may not have had.
+                let (scrollback_offset, scrollback_data, scrollback_sizes) = {
+                    let sb = session.lock().expect("session lock").scrollback.clone();
+                    let sb = sb.lock().expect("scrollback lock");
+                    let since_handoff = handoff.as_ref().map(|h| sb.end_offset().saturating_sub(h.offset));
+                    let (offset, data) = match since_handoff.or(req["replay_bytes"].as_u64()) {
+                        Some(max) => sb.page(sb.end_offset(), max.try_into().unwrap_or(usize::MAX)),
+                        None => (sb.start_offset(), sb.read_from_clean_point()),
+                    };
+                    (offset, data, sb.sizes(offset, u64::MAX))
                 };
-                if !scrollback_data.is_empty() {
-                    let frame = Frame::scrollback(0, scrollback_data);
-                    let encoded = frame::encode(&frame, true)
-                        .context("encode scrollback frame")?;
-                    send.write_all(&encoded).await.context("send scrollback")?;
+                let last_input_seq = session.lock().expect("session lock").input_marks.device(device_id).load(Ordering::Relaxed);
+
+                let resp = replayed.clone().unwrap_or_else(|| {
+                    serde_json::json!({
+                        "type": "session_attached",
+                        "request_id": request_id,
+                        "session_id": session_id,
+                        // What the session's programs were told, which may
+                        // differ from this client's terminal
+                        "terminal": session.lock().expect("session lock").terminal,
+                        "scrollback_offset": scrollback_offset,
+                        // The terminal sizes the replay was laid out for, to
+                        // reflow it by or mark where the layout changes
+                        "scrollback_sizes": scrollback_sizes,
+                        // Input up to here was written; number new input after it
+                        "last_input_sequence": last_input_seq,
+                    })
+                });
+                if let Some(pending) = pending {
+                    pending.complete(&resp);
+                }
+                if let Err(e) = write_json(&mut send, &resp).await {
+                    if claimed {
+                        session.lock().expect("session lock").attached = false;
+                    }
+                    return Err(e);
+                }
+                if let Some(handoff) = handoff {
+                    handoff.complete();
                 }
 
                 // Transition to bridge mode (consumes the stream)
-                return run_bridge(send, recv, session_manager, session_id).await;
+                let Some(detached) =
+                    run_bridge(send, recv, session_manager, session_id, opts, scrollback_data, memory, claimed).await?
+                else {
+                    return Ok(());
+                };
+                (send, recv) = ack_detach(detached, session_manager, session_id).await?;
+                // Continue looping for more requests
             }
-            "list_sessions" => {
+            "fetch_scrollback" => {
                 let request_id = req["request_id"].as_str().unwrap_or("");
-                let sessions = session_manager.list_sessions();
+                let (Some(session_id), Some(before)) = (req["session_id"].as_str(), req["before_offset"].as_u64()) else {
+                    write_error(&mut send, request_id, ErrorCode::BadRequest, "missing session_id or before_offset").await?;
+                    continue;
+                };
+                let max = req["max_bytes"].as_u64().map_or(DEFAULT_PAGE_BYTES, |n| (n as usize).min(MAX_PAGE_BYTES));
+                let Some(session) = visible_session(session_manager, &access, device_id, session_id) else {
+                    write_failure(&mut send, request_id, &SessionError::NotFound.into()).await?;
+                    continue;
+                };
+                let sb = session.lock().expect("session lock").scrollback.clone();
+                let (offset, data, start, sizes) = {
+                    let sb = sb.lock().expect("scrollback lock");
+                    let (offset, data) = sb.page(before, max);
+                    let sizes = sb.sizes(offset, offset + data.len() as u64);
+                    (offset, data, sb.start_offset(), sizes)
+                };
                 let resp = serde_json::json!({
-                    "type": "session_list",
+                    "type": "scrollback_page",
                     "request_id": request_id,
-                    "sessions": sessions,
+                    "session_id": session_id,
+                    // Where `data` starts; page on from here
+                    "offset": offset,
+                    "data": base64::engine::general_purpose::STANDARD.encode(&data),
+                    // Older output is still held
+                    "more": offset > start,
+                    "sizes": sizes,
                 });
                 write_json(&mut send, &resp).await?;
                 // Continue looping for more requests
             }
-            "destroy_session" => {
-                let session_id = req["session_id"]
-                    .as_str()
-                    .context("missing session_id")?;
+            "mirror_session" => {
                 let request_id = req["request_id"].as_str().unwrap_or("");
+                let Some(session_id) = req["session_id"].as_str() else {
+                    write_error(&mut send, request_id, ErrorCode::BadRequest, "missing session_id").await?;
+                    continue;
+                };
+                let Some(session) = visible_session(session_manager, &access, device_id, session_id) else {
+                    write_failure(&mut send, request_id, &SessionError::NotFound.into()).await?;
+                    continue;
+                };
+                let (feed, scrollback) = {
+                    let s = session.lock().expect("session lock");
+                    (s.mirror_feed.clone(), s.scrollback.clone())
+                };
+                let Some(feed) = feed else {
+                    write_failure(&mut send, request_id, &SessionError::NotAttached.into()).await?;
+                    continue;
+                };
+
+                let memory = match session_manager.reserve(MIRROR_MEMORY_BYTES) {
+                    Ok(memory) => memory,
+                    Err(e) => {
+                        warn!("mirror_session rejected: {e:#}");
+                        write_failure(&mut send, request_id, &e).await?;
+                        continue;
+                    }
+                };
+
+                // The bridge feeds mirrors under the scrollback lock, so the
+                // replay ends exactly where the feed begins
+                let (output, replay, replay_offset, replay_sizes) = {
+                    let sb = scrollback.lock().expect("scrollback lock");
+                    let offset = sb.start_offset();
+                    (feed.subscribe(), sb.read_from_clean_point(), offset, sb.sizes(offset, u64::MAX))
+                };
+                // Only the bridge may keep the feed open
+                drop(feed);
+                info!("device {device_id} mirroring session {session_id}");
+                session
+                    .lock()
+                    .expect("session lock")
+                    .history
+                    .record(EventKind::Mirrored { by: Some(device_id.to_string()) });
 
-                let result = session_manager.destroy_session(session_id);
                 let resp = serde_json::json!({
-                    "type": "session_destroyed",
+                    "type": "session_mirrored",
                     "request_id": request_id,
-                    "success": result.is_ok(),
-                    "error": result.err().map(|e| e.to_string()),
+                    "session_id": session_id,
+                    "scrollback_offset": replay_offset,
+                    "scrollback_sizes": replay_sizes,
                 });
                 write_json(&mut send, &resp).await?;
-                // Continue looping for more requests
+
+                let deadline = match access {
+                    Access::Guest(guest) => Some(guest_deadline(guest)),
+                    Access::Device(_) => None,
+                };
+                // Output streams until the attached client detaches (consumes the stream)
+                return run_mirror(send, recv, output, replay, deadline, memory).await;
             }
-            "remove_device" => {
+            "exec" => {
                 let request_id = req["request_id"].as_str().unwrap_or("");
-                info!("device requested self-removal");
+                let command = match req["command"].as_str() {
+                    Some(command) if !command.trim().is_empty() => command,
+                    _ => {
+                        write_error(&mut send, request_id, ErrorCode::BadRequest, "missing command").await?;
+                        continue;
+                    }
+                };
+                let rows = (req["rows"].as_u64().unwrap_or(24) as u16).clamp(1, 500);
+                let cols = (req["cols"].as_u64().unwrap_or(80) as u16).clamp(1, 500);
+                let timeout = exec::timeout_from_request(req["timeout_secs"].as_u64());
+
+                let started = session_manager.reserve(exec::EXEC_MEMORY_BYTES).and_then(|memory| {
+                    let pty = match access.user() {
+                        Some(user) => NativePty::spawn_command(rows, cols, users::login_command(user, Some(command)))?,
+                        None => NativePty::exec(rows, cols, command)?,
+                    };
+                    Ok((Exec::start(Box::new(pty), timeout, session_manager.tasks().clone())?, memory))
+                });
+                let (exec, _memory) = match started {
+                    Ok(started) => started,
+                    Err(e) => {
+                        warn!("exec rejected: {e:#}");
+                        write_failure(&mut send, request_id, &e).await?;
+                        continue;
+                    }
+                };
+                info!("exec for device {device_id}: {command}");
+
                 let resp = serde_json::json!({
-                    "type": "device_removed",
+                    "type": "exec_started",
                     "request_id": request_id,
-                    "success": true,
                 });
                 write_json(&mut send, &resp).await?;
-                return Ok(());
+
+                // Output streams until the command ends (consumes the stream)
+                return exec::stream_exec(send, recv, exec).await;
             }
-            other => {
-                warn!("unknown session request type: {other}");
-                let resp = serde_json::json!({
-                    "type": "error",
-                    "error": format!("unknown request type: {other}"),
-                });
-                write_json(&mut send, &resp).await?;
+            "server_info" => {
+                let request_id = req["request_id"].as_str().unwrap_or("");
+                // Guests were refused above
+                let Access::Device(policy) = access else {
+                    write_error(&mut send, request_id, ErrorCode::PermissionDenied, "not permitted for guest access").await?;
+                    continue;
+                };
+                // Also carries current addresses, so clients can refresh what they stored at pairing
+                write_json(&mut send, &server_info(session_manager, policy, request_id)).await?;
+                // Continue looping for more requests
+            }
+            "get_daemon_config" => {
+                let request_id = req["request_id"].as_str().unwrap_or("");
+                // Guests and restricted devices were refused above
+                let Access::Device(policy) = access else {
+                    write_error(&mut send, request_id, ErrorCode::PermissionDenied, "not permitted for guest access").await?;
+                    continue;
+                };
+                write_json(&mut send, &daemon_config(session_manager, policy, request_id)).await?;
+                // Continue looping for more requests
+            }
+            "list_sessions" => {
+                let request_id = req["request_id"].as_str().unwrap_or("");
+                // Only sessions in this project (by name or root)
+                let project = req["project"].as_str();
+                let sessions: Vec<_> = session_manager
+                    .list_sessions()
+                    .into_iter()
+                    .filter(|s| access.can_see(device_id, s.user.as_deref(), s.created_by_device_id.as_deref()))
+                    .filter(|s| project.is_none_or(|p| s.in_project(p)))
+                    .collect();
+                let projects = crate::project::group(sessions.iter().map(|s| (s.id.as_str(), s.project.as_ref())));
+                let resp = serde_json::json!({
+                    "type": "session_list",
+                    "request_id": request_id,
+                    "sessions": sessions,
+                    "projects": projects,
+                });
+                write_json(&mut send, &resp).await?;
+                // Continue looping for more requests
+            }
+            "session_history" => {
+                let request_id = req["request_id"].as_str().unwrap_or("");
+                let Some(session_id) = req["session_id"].as_str() else {
+                    write_error(&mut send, request_id, ErrorCode::BadRequest, "missing session_id").await?;
+                    continue;
+                };
+                let history = session_manager
+                    .session_history(session_id)
+                    .filter(|h| access.can_see(device_id, h.user.as_deref(), h.created_by_device_id.as_deref()));
+                let Some(history) = history else {
+                    write_failure(&mut send, request_id, &SessionError::NotFound.into()).await?;
+                    continue;
+                };
+                let resp = serde_json::json!({
+                    "type": "session_history",
+                    "request_id": request_id,
+                    "session_id": session_id,
+                    "ended": history.ended,
+                    "events": history.events,
+                });
+                write_json(&mut send, &resp).await?;
+                // Continue looping for more requests
+            }
+            "inspect_session" => {
+                let request_id = req["request_id"].as_str().unwrap_or("");
+                let Some(session_id) = req["session_id"].as_str() else {
+                    write_error(&mut send, request_id, ErrorCode::BadRequest, "missing session_id").await?;
+                    continue;
+                };
+                let diagnostics = visible_session(session_manager, &access, device_id, session_id)
+                    .and_then(|_| session_manager.inspect_session(session_id));
+                let Some(diagnostics) = diagnostics else {
+                    write_failure(&mut send, request_id, &SessionError::NotFound.into()).await?;
+                    continue;
+                };
+                let resp = serde_json::json!({
+                    "type": "session_inspected",
+                    "request_id": request_id,
+                    "session": diagnostics,
+                });
+                write_json(&mut send, &resp).await?;
+                // Continue looping for more requests
+            }
+            "connection_stats" => {
+                let request_id = req["request_id"].as_str().unwrap_or("");
+                let Some(session_id) = req["session_id"].as_str() else {
+                    write_error(&mut send, request_id, ErrorCode::BadRequest, "missing session_id").await?;
+                    continue;
+                };
+                let stats = match visible_session(session_manager, &access, device_id, session_id) {
+                    Some(_) => session_manager.connection_stats(session_id),
+                    None => Err(SessionError::NotFound.into()),
+                };
+                let stats = match stats {
+                    Ok(stats) => stats,
+                    Err(e) => {
+                        write_failure(&mut send, request_id, &e).await?;
+                        continue;
+                    }
+                };
+                let resp = serde_json::json!({
+                    "type": "connection_stats",
+                    "request_id": request_id,
+                    "session_id": session_id,
+                    "stats": stats,
+                });
+                write_json(&mut send, &resp).await?;
+                // Continue looping for more requests
+            }
+            "resize_session" => {
+                let request_id = req["request_id"].as_str().unwrap_or("");
+                let Some(session_id) = req["session_id"].as_str() else {
+                    write_error(&mut send, request_id, ErrorCode::BadRequest, "missing session_id").await?;
+                    continue;
+                };
+                let (Some(rows), Some(cols)) = (req["rows"].as_u64(), req["cols"].as_u64()) else {
+                    write_error(&mut send, request_id, ErrorCode::BadRequest, "missing rows or cols").await?;
+                    continue;
+                };
+                let (rows, cols) = (rows.clamp(1, 500) as u16, cols.clamp(1, 500) as u16);
+                let result = match visible_session(session_manager, &access, device_id, session_id) {
+                    Some(_) => session_manager.resize_session(session_id, rows, cols, Some(device_id)),
+                    None => Err(SessionError::NotFound.into()),
+                };
+                if let Err(e) = result {
+                    write_failure(&mut send, request_id, &e).await?;
+                    continue;
+                }
+                let resp = serde_json::json!({
+                    "type": "session_resized",
+                    "request_id": request_id,
+                    "session_id": session_id,
+                    "rows": rows,
+                    "cols": cols,
+                });
+                write_json(&mut send, &resp).await?;
+                // Continue looping for more requests
+            }
+            "kill_foreground" => {
+                let request_id = req["request_id"].as_str().unwrap_or("");
+                let Some(session_id) = req["session_id"].as_str() else {
+                    write_error(&mut send, request_id, ErrorCode::BadRequest, "missing session_id").await?;
+                    continue;
+                };
+                let name = req["signal"].as_str().unwrap_or("TERM");
+                let Some(signal) = foreground_signal(name) else {
+                    let error = format!("unsupported signal: {name} (use INT, TERM, HUP or KILL)");
+                    write_error(&mut send, request_id, ErrorCode::BadRequest, &error).await?;
+                    continue;
+                };
+                let result = match visible_session(session_manager, &access, device_id, session_id) {
+                    Some(_) => session_manager.kill_foreground(session_id, signal),
+                    None => Err(SessionError::NotFound.into()),
+                };
+                let pgid = match result {
+                    Ok(pgid) => pgid,
+                    Err(e) => {
+                        write_failure(&mut send, request_id, &e).await?;
+                        continue;
+                    }
+                };
+                info!("device {device_id} sent SIG{name} to the foreground of session {session_id}");
+                let resp = serde_json::json!({
+                    "type": "foreground_killed",
+                    "request_id": request_id,
+                    "session_id": session_id,
+                    "signal": name,
+                    "process_group": pgid,
+                });
+                write_json(&mut send, &resp).await?;
+                // Continue looping for more requests
+            }
+            "set_output_log" => {
+                let request_id = req["request_id"].as_str().unwrap_or("");
+                let (Some(session_id), Some(enabled)) = (req["session_id"].as_str(), req["enabled"].as_bool()) else {
+                    write_error(&mut send, request_id, ErrorCode::BadRequest, "missing session_id or enabled").await?;
+                    continue;
+                };
+                if access.user().is_some() {
+                    write_error(&mut send, request_id, ErrorCode::PermissionDenied, "output logging is not available in multi-user mode").await?;
+                    continue;
+                }
+                let result = match visible_session(session_manager, &access, device_id, session_id) {
+                    Some(_) => session_manager.set_output_log(session_id, enabled),
+                    None => Err(SessionError::NotFound.into()),
+                };
+                let path = match result {
+                    Ok(path) => path,
+                    Err(e) => {
+                        write_failure(&mut send, request_id, &e).await?;
+                        continue;
+                    }
+                };
+                info!("device {device_id} turned output logging {} for session {session_id}", if enabled { "on" } else { "off" });
+                let resp = serde_json::json!({
+                    "type": "output_log_set",
+                    "request_id": request_id,
+                    "session_id": session_id,
+                    "enabled": enabled,
+                    "path": path,
+                });
+                write_json(&mut send, &resp).await?;
+                // Continue looping for more requests
+            }
+            "list_tmux_sessions" => {
+                let request_id = req["request_id"].as_str().unwrap_or("");
+                if access.user().is_some() {
+                    write_error(&mut send, request_id, ErrorCode::PermissionDenied, "tmux passthrough is not available in multi-user mode").await?;
+                    continue;
+                }
+                match session_manager.tmux().list_sessions().await {
+                    Ok(sessions) => {
+                        let resp = serde_json::json!({
+                            "type": "tmux_session_list",
+                            "request_id": request_id,
+                            "sessions": sessions,
+                        });
+                        write_json(&mut send, &resp).await?;
+                    }
+                    Err(e) => {
+                        warn!("list_tmux_sessions failed: {e:#}");
+                        write_failure(&mut send, request_id, &e).await?;
+                    }
+                }
+                // Continue looping for more requests
+            }
+            "set_clipboard" => {
+                let request_id = req["request_id"].as_str().unwrap_or("");
+                let Some(text) = req["text"].as_str() else {
+                    write_error(&mut send, request_id, ErrorCode::BadRequest, "missing text").await?;
+                    continue;
+                };
+                if access.user().is_some() {
+                    write_error(&mut send, request_id, ErrorCode::PermissionDenied, "clipboard push is not available in multi-user mode").await?;
+                    continue;
+                }
+                if let Err(e) = session_manager.clipboard().set(text).await {
+                    warn!("clipboard push from device {device_id} refused: {e:#}");
+                    write_failure(&mut send, request_id, &e).await?;
+                    continue;
+                }
+                info!("device {device_id} set the host clipboard ({} bytes)", text.len());
+                let resp = serde_json::json!({
+                    "type": "clipboard_set",
+                    "request_id": request_id,
+                });
+                write_json(&mut send, &resp).await?;
+                // Continue looping for more requests
+            }
+            "record_macro" => {
+                let request_id = req["request_id"].as_str().unwrap_or("");
+                let (Some(name), Some(input)) = (req["name"].as_str(), req["input"].as_str()) else {
+                    write_error(&mut send, request_id, ErrorCode::BadRequest, "missing name or input").await?;
+                    continue;
+                };
+                let recorded = match session_manager.macros().record(device_id, name, input) {
+                    Ok(recorded) => recorded,
+                    Err(e) => {
+                        write_failure(&mut send, request_id, &e).await?;
+                        continue;
+                    }
+                };
+                let resp = serde_json::json!({
+                    "type": "macro_recorded",
+                    "request_id": request_id,
+                    "macro": recorded,
+                });
+                write_json(&mut send, &resp).await?;
+                // Continue looping for more requests
+            }
+            "list_macros" => {
+                let request_id = req["request_id"].as_str().unwrap_or("");
+                let resp = serde_json::json!({
+                    "type": "macros",
+                    "request_id": request_id,
+                    "macros": session_manager.macros().list(device_id),
+                });
+                write_json(&mut send, &resp).await?;
+                // Continue looping for more requests
+            }
+            "delete_macro" => {
+                let request_id = req["request_id"].as_str().unwrap_or("");
+                let Some(name) = req["name"].as_str() else {
+                    write_error(&mut send, request_id, ErrorCode::BadRequest, "missing name").await?;
+                    continue;
+                };
+                if let Err(e) = session_manager.macros().delete(device_id, name) {
+                    write_failure(&mut send, request_id, &e).await?;
+                    continue;
+                }
+                let resp = serde_json::json!({
+                    "type": "macro_deleted",
+                    "request_id": request_id,
+                    "name": name,
+                });
+                write_json(&mut send, &resp).await?;
+                // Continue looping for more requests
+            }
+            "run_macro" => {
+                let request_id = req["request_id"].as_str().unwrap_or("");
+                let (Some(name), Some(session_id)) = (req["name"].as_str(), req["session_id"].as_str()) else {
+                    write_error(&mut send, request_id, ErrorCode::BadRequest, "missing name or session_id").await?;
+                    continue;
+                };
+                let result = match visible_session(session_manager, &access, device_id, session_id) {
+                    Some(_) => session_manager.run_macro(session_id, device_id, name),
+                    None => Err(SessionError::NotFound.into()),
+                };
+                let written = match result {
+                    Ok(written) => written,
+                    Err(e) => {
+                        write_failure(&mut send, request_id, &e).await?;
+                        continue;
+                    }
+                };
+                let resp = serde_json::json!({
+                    "type": "macro_run",
+                    "request_id": request_id,
+                    "session_id": session_id,
+                    "bytes": written,
+                });
+                write_json(&mut send, &resp).await?;
+                // Continue looping for more requests
+            }
+            "list_schedules" => {
+                let request_id = req["request_id"].as_str().unwrap_or("");
+                // Scheduled commands run as the daemon's user
+                if access.user().is_some() || access.restrictions().is_some() {
+                    write_error(&mut send, request_id, ErrorCode::PermissionDenied, "schedules are not available to this device").await?;
+                    continue;
+                }
+                let scheduler = session_manager.scheduler();
+                let resp = serde_json::json!({
+                    "type": "schedules",
+                    "request_id": request_id,
+                    "schedules": scheduler.jobs(),
+                    "runs": scheduler.runs(),
+                });
+                write_json(&mut send, &resp).await?;
+                // Continue looping for more requests
+            }
+            "scheduled_run" => {
+                let request_id = req["request_id"].as_str().unwrap_or("");
+                if access.user().is_some() || access.restrictions().is_some() {
+                    write_error(&mut send, request_id, ErrorCode::PermissionDenied, "schedules are not available to this device").await?;
+                    continue;
+                }
+                let Some(run_id) = req["run_id"].as_u64() else {
+                    write_error(&mut send, request_id, ErrorCode::BadRequest, "missing run_id").await?;
+                    continue;
+                };
+                let Some((run, output)) = session_manager.scheduler().run(run_id) else {
+                    write_error(&mut send, request_id, ErrorCode::NotFound, "run not found").await?;
+                    continue;
+                };
+                let resp = serde_json::json!({
+                    "type": "scheduled_run",
+                    "request_id": request_id,
+                    "run": run,
+                    "output": output,
+                });
+                write_json(&mut send, &resp).await?;
+                // Continue looping for more requests
+            }
+            "handoff_session" => {
+                let request_id = req["request_id"].as_str().unwrap_or("");
+                let (Some(session_id), Some(to)) = (req["session_id"].as_str(), req["device_id"].as_str()) else {
+                    write_error(&mut send, request_id, ErrorCode::BadRequest, "missing session_id or device_id").await?;
+                    continue;
+                };
+                let Some(session) = visible_session(session_manager, &access, device_id, session_id) else {
+                    write_failure(&mut send, request_id, &SessionError::NotFound.into()).await?;
+                    continue;
+                };
+                let (attached, attached_here) = {
+                    let s = session.lock().expect("session lock");
+                    (s.attached, s.last_attached_by.as_deref() == Some(device_id))
+                };
+                if !attached {
+                    write_failure(&mut send, request_id, &SessionError::NotAttached.into()).await?;
+                    continue;
+                }
+                if !attached_here || to == device_id {
+                    let error = match attached_here {
+                        true => "session is already attached from this device",
+                        false => "session is attached from another device",
+                    };
+                    write_error(&mut send, request_id, ErrorCode::PermissionDenied, error).await?;
+                    continue;
+                }
+                if !session_manager.is_connected(to) {
+                    let error = format!("device {to} is not connected");
+                    write_error(&mut send, request_id, ErrorCode::Unavailable, &error).await?;
+                    continue;
+                }
+
+                let (offset, name) = {
+                    let s = session.lock().expect("session lock");
+                    let offset = s.scrollback.lock().expect("scrollback lock").end_offset();
+                    (offset, s.name.clone())
+                };
+                let mut ticket = session_manager.handoffs().open(session_id, to, offset);
+                info!("device {device_id} handing session {session_id} to {to}");
+                session_manager.notifier().notify(Some(to), &serde_json::json!({
+                    "type": "handoff_offered",
+                    "handoff_id": ticket.id,
+                    "session_id": session_id,
+                    "name": name,
+                    "from_device_id": device_id,
+                    "expires_in_secs": handoff::TIMEOUT.as_secs(),
+                }));
+                if !ticket.completed(handoff::TIMEOUT).await {
+                    let error = format!("device {to} did not take the session in time");
+                    write_error(&mut send, request_id, ErrorCode::Timeout, &error).await?;
+                    continue;
+                }
+                let resp = serde_json::json!({
+                    "type": "session_handed_off",
+                    "request_id": request_id,
+                    "session_id": session_id,
+                    "device_id": to,
+                    // Output the new device replayed from
+                    "offset": offset,
+                });
+                write_json(&mut send, &resp).await?;
+                // Continue looping for more requests
+            }
+            "destroy_session" => {
+                let request_id = req["request_id"].as_str().unwrap_or("");
+                let Some(session_id) = req["session_id"].as_str() else {
+                    write_error(&mut send, request_id, ErrorCode::BadRequest, "missing session_id").await?;
+                    continue;
+                };
+
+                let result = match visible_session(session_manager, &access, device_id, session_id) {
+                    Some(_) => session_manager.destroy_session_by(session_id, Some(device_id)),
+                    None => Err(SessionError::NotFound.into()),
+                };
+                let resp = serde_json::json!({
+                    "type": "session_destroyed",
+                    "request_id": request_id,
+                    "success": result.is_ok(),
+                    "code": result.as_ref().err().map(ErrorCode::of),
+                    "error": result.as_ref().err().map(|e| e.to_string()),
+                    "message": result.as_ref().err().map(|e| messages::localized(ErrorCode::of(e))),
+                });
+                if let (Some(pending), Ok(())) = (pending, &result) {
+                    pending.complete(&resp);
+                }
+                write_json(&mut send, &resp).await?;
+                // Continue looping for more requests
+            }
+            "approve_pairing" | "deny_pairing" => {
+                let request_id = req["request_id"].as_str().unwrap_or("");
+                let Some(pairing_id) = req["pairing_id"].as_str() else {
+                    write_error(&mut send, request_id, ErrorCode::BadRequest, "missing pairing_id").await?;
+                    continue;
+                };
+                let approved = msg_type == "approve_pairing";
+                let verdict = if approved {
+                    Verdict::Approved { by: device_id.to_string() }
+                } else {
+                    Verdict::Denied { by: device_id.to_string() }
+                };
+                if let Err(e) = session_manager.pairing_requests().settle(pairing_id, access.user(), verdict) {
+                    write_failure(&mut send, request_id, &e).await?;
+                    continue;
+                }
+                info!("device {device_id} {} pairing request {pairing_id}", if approved { "approved" } else { "denied" });
+                let resp = serde_json::json!({
+                    "type": "pairing_settled",
+                    "request_id": request_id,
+                    "pairing_id": pairing_id,
+                    "approved": approved,
+                });
+                write_json(&mut send, &resp).await?;
+                // Continue looping for more requests
+            }
+            "remove_device" => {
+                let request_id = req["request_id"].as_str().unwrap_or("");
+                info!("device requested self-removal");
+                let resp = serde_json::json!({
+                    "type": "device_removed",
+                    "request_id": request_id,
+                    "success": true,
+                });
+                write_json(&mut send, &resp).await?;
+                return Ok(());
+            }
+            other => {
+                warn!("unknown session request type: {other}");
+                let request_id = req["request_id"].as_str().unwrap_or("");
+                let error = format!("unknown request type: {other}");
+                write_error(&mut send, request_id, ErrorCode::Unsupported, &error).await?;
             }
         }
     }
 }
 
+/// How the client ended a bridge.
+enum ClientEnd {
+    Close,
+    Detach { recv: RecvStream, last_received: u64 },
+}
+
+/// A bridge the client detached from with a `detach_session` Close: the
+/// stream is back to length-prefixed control requests.
+struct Detached {
+    send: SendStream,
+    recv: RecvStream,
+    /// Sequence of our last frame, the Close answering the request
+    last_sent: u64,
+    /// Sequence of the client's detach request
+    last_received: u64,
+}
+
 /// Run the frame-based bridge for an attached session.
+/// A non-empty `replay` is sent as Scrollback frames ahead of live output.
+/// `_memory` holds the bridge's buffer budget until it ends. `claimed` means
+/// the session was already marked attached for this bridge by [`take_over`].
+/// Returns the stream when the client detached without closing it.
+#[allow(clippy::too_many_arguments)]
 async fn run_bridge(
     send: SendStream,
     recv: RecvStream,
     session_manager: &SessionManager,
     session_id: &str,
-) -> Result<()> {
-    let session = session_manager
-        .get_session(session_id)
-        .context("session not found for bridge")?;
+    opts: BridgeOptions,
+    replay: Vec<u8>,
+    _memory: Reservation,
+    claimed: bool,
+) -> Result<Option<Detached>> {
+    let session = session_manager.get_session(session_id).ok_or(SessionError::NotFound)?;
 
     let cancel = CancellationToken::new();
+    let (mirror_feed, _) = broadcast::channel(MIRROR_FEED_CHUNKS);
 
     // Take the PTY reader (only one bridge at a time)
     let pty_reader = {
         let mut s = session.lock().expect("session lock");
-        if s.attached {
-            anyhow::bail!("session {session_id} already attached");
+        if s.attached && !claimed {
+            return Err(SessionError::AlreadyAttached("session is already attached").into());
         }
         s.attached = true;
         s.bridge_cancel = Some(cancel.clone());
+        s.mirror_feed = Some(mirror_feed.clone());
+        if !replay.is_empty() {
+            s.resyncs += 1;
+        }
+        // Both callers record the attaching device first
+        let by = s.last_attached_by.clone();
+        s.history.record(EventKind::Attached { by });
         s.reader
             .take()
             .context("PTY reader already taken")?
@@ -205,7 +1825,11 @@ async fn run_bridge(
         session.clone()
     };
 
-    let result = run_bridge_inner(
+    // Detach however the bridge ends, even if this future is dropped
+    let _attachment = Attachment { session_manager, session: session.clone(), session_id };
+    let panics = session_manager.bridge_panics().clone();
+    let mut tasks = BridgeTasks::new(session_id, panics, cancel.clone(), session_manager.tasks().clone());
+    run_bridge_inner(
         send,
         recv,
         pty_reader,
@@ -213,31 +1837,133 @@ async fn run_bridge(
         scrollback,
         master_for_resize,
         cancel.clone(),
+        opts,
+        replay,
+        mirror_feed.downgrade(),
+        session_manager.notifier(),
+        &mut tasks,
     )
-    .await;
+    .await
+}
 
-    // On disconnect: clone a fresh reader from master, mark detached
-    {
-        let mut s = session.lock().expect("session lock");
+/// A bridge's hold on its session: dropping it marks the session detached
+/// and gives it a fresh PTY reader for the next attach.
+struct Attachment<'a> {
+    session_manager: &'a SessionManager,
+    session: Arc<Mutex<PtySession>>,
+    session_id: &'a str,
+}
+
+impl Drop for Attachment<'_> {
+    fn drop(&mut self) {
+        let session_id = self.session_id;
+        let mut s = self.session.lock().expect("session lock");
         s.attached = false;
         s.bridge_cancel = None;
+        let probe = s.bridge_probe.take();
+        if let (Some(probe), Some(device)) = (probe, &s.last_attached_by) {
+            self.session_manager.bandwidth().record(device, session_id, probe.take_traffic());
+        }
+        let by = s.last_attached_by.clone();
+        s.history.record(EventKind::Detached { by });
+        // Closes the feed, ending any mirrors
+        s.mirror_feed = None;
         // Clone a new reader for future reattach
         if s.reader.is_none() {
-            match s.master.try_clone_reader() {
+            match s.backend.try_clone_reader() {
                 Ok(reader) => {
                     s.reader = Some(reader);
                 }
                 Err(e) => {
                     warn!("failed to clone PTY reader on detach, marking session damaged: {e}");
                     s.damaged = true;
+                    s.damaged_cause = Some(format!("PTY reader could not be cloned on detach: {e:#}"));
                 }
             }
         }
     }
+}
+
+/// The tasks of one bridge. A task that panics is logged with its session
+/// and counted in [`SessionManager::bridge_panics`], and ends as if it had
+/// returned nothing, so the bridge tears down as usual; dropping this stops
+/// the tasks still running.
+struct BridgeTasks {
+    session_id: Arc<str>,
+    panics: Arc<AtomicU64>,
+    /// Cancelled on drop, stopping the PTY reader (which can't be aborted)
+    cancel: CancellationToken,
+    running: Vec<tokio::task::AbortHandle>,
+    /// The daemon's tasks, which shutdown waits for
+    tracker: TaskTracker,
+}
+
+impl BridgeTasks {
+    fn new(session_id: &str, panics: Arc<AtomicU64>, cancel: CancellationToken, tracker: TaskTracker) -> Self {
+        Self { session_id: session_id.into(), panics, cancel, running: Vec::new(), tracker }
+    }
+
+    /// Spawn `future` in the current span, so its logs name the connection.
+    fn spawn<T, F>(&mut self, task: &'static str, future: F) -> tokio::task::JoinHandle<T>
+    where
+        T: Default + Send + 'static,
+        F: std::future::Future<Output = T> + Send + 'static,
+    {
+        let handle = self.tracker.spawn(future.in_current_span());
+        self.watch(task, handle)
+    }
+
+    fn spawn_blocking<T, F>(&mut self, task: &'static str, f: F) -> tokio::task::JoinHandle<T>
+    where
+        T: Default + Send + 'static,
+        F: FnOnce() -> T + Send + 'static,
+    {
+        let span = tracing::Span::current();
+        let handle = self.tracker.spawn_blocking(move || span.in_scope(f));
+        self.watch(task, handle)
+    }
+
+    /// Join `handle` from a task of its own, which reports a panic.
+    fn watch<T: Default + Send + 'static>(
+        &mut self,
+        task: &'static str,
+        handle: tokio::task::JoinHandle<T>,
+    ) -> tokio::task::JoinHandle<T> {
+        self.running.push(handle.abort_handle());
+        let session_id = self.session_id.clone();
+        let panics = self.panics.clone();
+        let joined = async move {
+            match handle.await {
+                Ok(output) => output,
+                Err(e) if e.is_panic() => {
+                    let panic = e.into_panic();
+                    let message = panic
+                        .downcast_ref::<&str>()
+                        .copied()
+                        .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+                        .unwrap_or("(no message)");
+                    error!("session {session_id}: bridge {task} task panicked: {message}");
+                    panics.fetch_add(1, Ordering::Relaxed);
+                    T::default()
+                }
+                // Aborted as the bridge ended
+                Err(_) => T::default(),
+            }
+        };
+        self.tracker.spawn(joined.in_current_span())
+    }
+}
 
-    result
+impl Drop for BridgeTasks {
+    fn drop(&mut self) {
+        self.cancel.cancel();
+        for task in &self.running {
+            task.abort();
+        }
+    }
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn run_bridge_inner(
     mut send: SendStream,
     recv: RecvStream,
@@ -246,99 +1972,345 @@ async fn run_bridge_inner(
     scrollback: Arc<Mutex<ScrollbackBuffer>>,
     session_ref: Arc<Mutex<PtySession>>,
     cancel: CancellationToken,
-) -> Result<()> {
+    opts: BridgeOptions,
+    replay: Vec<u8>,
+    mirror_feed: broadcast::WeakSender<Bytes>,
+    notifier: DeviceNotifier,
+    tasks: &mut BridgeTasks,
+) -> Result<Option<Detached>> {
     let mut seq_out: u64 = 1;
+    // Tasks spawned below don't see the connection's language, so take it now
+    let language = messages::current();
+    let activity = session_ref.lock().expect("session lock").activity.clone();
     let client_window = Arc::new(std::sync::atomic::AtomicU64::new(DEFAULT_WINDOW));
     let window_notify = Arc::new(Notify::new());
+    let probe = Arc::new(BridgeProbe::new(client_window.clone()));
+    session_ref.lock().expect("session lock").bridge_probe = Some(probe.clone());
+
+    // Fired when the client sends Close; the send task then drains and closes
+    let drain = CancellationToken::new();
+    // Fired along with `drain` when the client asked to detach
+    let detaching = CancellationToken::new();
+    // Fired once scrollback replay has been written; input is held until then
+    let replayed = CancellationToken::new();
+    let replaying = !replay.is_empty();
+    // Stops PTY reads (bridge cancel, or the end of a close handshake)
+    let stop_read = cancel.child_token();
 
     // PTY → channel (blocking thread)
-    let (tx, mut rx) = mpsc::channel::<Vec<u8>>(128);
-    let cancel_read = cancel.clone();
-
-    let pty_read_handle = tokio::task::spawn_blocking(move || {
-        let mut reader = pty_reader;
-        let mut buf = [0u8; 16384];
-        loop {
-            if cancel_read.is_cancelled() {
-                break;
-            }
-            match reader.read(&mut buf) {
-                Ok(0) => {
-                    info!("PTY reader EOF");
-                    break;
-                }
-                Ok(n) => {
-                    if tx.blocking_send(buf[..n].to_vec()).is_err() {
-                        break;
-                    }
-                }
-                Err(e) => {
-                    if e.raw_os_error() == Some(5) { // EIO
-                        info!("PTY reader got EIO (child exited)");
-                    } else {
-                        error!("PTY read error: {e}");
-                    }
-                    break;
-                }
-            }
-        }
-    });
+    let (tx, mut rx) = mpsc::channel::<Bytes>(OUTPUT_QUEUE_CHUNKS);
+    let read_watch = Arc::new(ReadWatch::new(tokio::time::Instant::now()));
+    let reads = PtyReads {
+        output: tx,
+        cancel: stop_read.clone(),
+        watch: read_watch.clone(),
+        running: probe.pty_reader.clone(),
+        ended: CancellationToken::new(),
+    };
+    let read_ended = reads.ended.clone();
+    reads.running.store(true, Ordering::Relaxed);
+    if let Some(limit) = opts.read_stall {
+        let watchdog = watch_reads(reads.clone(), limit, session_ref.clone(), tasks.tracker.clone());
+        tasks.spawn("read watchdog", watchdog);
+    }
+    tasks.spawn_blocking("PTY read", move || reads.run(pty_reader, 0));
 
     // Channel → QUIC send (with framing and flow control)
     let window_for_send = client_window.clone();
     let notify_for_send = window_notify.clone();
     let scrollback_for_send = scrollback.clone();
+    let output_log = session_ref.lock().expect("session lock").output_log.clone();
+    let bracketed_paste = session_ref.lock().expect("session lock").bracketed_paste.clone();
+    let bracketed_for_send = bracketed_paste.clone();
+    let reported_env = session_ref.lock().expect("session lock").reported_env.clone();
+    // The attaching device's, as its callers record it before run_bridge
+    let input_mark = {
+        let s = session_ref.lock().expect("session lock");
+        s.input_marks.device(s.last_attached_by.as_deref().unwrap_or_default())
+    };
+    let compression = session_ref.lock().expect("session lock").compression.clone();
+    // Bytes of the current paste written, acknowledged by the send task
+    let (paste_acks, mut paste_acks_rx) = tokio::sync::watch::channel(0u64);
+    // Errors about the stream (e.g. input dropped), sent as Error frames
+    let (stream_errors, mut stream_errors_rx) = mpsc::channel::<serde_json::Value>(4);
+    let session_for_send = session_ref.clone();
     let cancel_send = cancel.clone();
+    let drain_send = drain.clone();
+    let replayed_send = replayed.clone();
+    let detaching_send = detaching.clone();
+    let probe_send = probe.clone();
+    let send_running = Running::new(&probe.sender);
 
-    let send_handle = tokio::spawn(async move {
-        while let Some(first) = rx.recv().await {
-            if cancel_send.is_cancelled() {
+    // Channel → PTY (blocking thread), so a slow PTY holds up no async task
+    let (pty_input, mut input_rx) = mpsc::channel::<PtyInput>(INPUT_QUEUE_CHUNKS);
+    let write_running = Running::new(&probe.pty_writer);
+    let written_mark = input_mark.clone();
+    let pty_write_handle = tasks.spawn_blocking("PTY write", move || {
+        let _running = write_running;
+        let mut next = None;
+        while let Some(input) = next.take().or_else(|| input_rx.blocking_recv()) {
+            let (data, seq, paste_written) = match input {
+                PtyInput::Paste { data, seq, written } => (data, seq, written),
+                PtyInput::Data { mut data, mut seq } => {
+                    // Typed input queued up behind it goes in the same write
+                    while data.len() < INPUT_COALESCE_BYTES {
+                        match input_rx.try_recv() {
+                            Ok(PtyInput::Data { data: more, seq: more_seq }) => {
+                                data.extend_from_slice(&more);
+                                seq = more_seq.max(seq);
+                            }
+                            Ok(paste) => {
+                                next = Some(paste);
+                                break;
+                            }
+                            Err(_) => break,
+                        }
+                    }
+                    (data, seq, None)
+                }
+            };
+            if let Err(e) = pty_writer.lock().expect("pty writer lock").write_all(&data) {
+                warn!("PTY write error: {e}");
                 break;
             }
+            if let Some(seq) = seq {
+                written_mark.fetch_max(seq, Ordering::Relaxed);
+            }
+            if let Some(written) = paste_written {
+                paste_acks.send_replace(written);
+            }
+        }
+    });
 
-            // Coalesce: drain any additional queued data into a single buffer
-            let mut data = first;
-            while let Ok(more) = rx.try_recv() {
-                if data.len() + more.len() > frame::MAX_PAYLOAD {
-                    break;
-                }
-                data.extend_from_slice(&more);
+    let mut send_handle = tasks.spawn("send", async move {
+        let _running = send_running;
+        let mut bufs = match FrameBuffers::new() {
+            Ok(bufs) => {
+                let bufs = bufs.with_compression(opts.compression_bypass, compression);
+                FrameBuffers { compress: opts.wire.compress, ..bufs }
+            }
+            Err(e) => {
+                error!("frame buffer init error: {e}");
+                return None;
             }
+        };
 
-            // Append to scrollback
-            {
-                let mut sb = scrollback_for_send.lock().expect("scrollback lock");
-                sb.append(&data);
+        if opts.wire.decodes(FrameType::Hello) {
+            let hello = frame::encode(&Frame::hello(0, opts.wire.describe().to_string().into_bytes()), false)
+                .expect("hello fits a frame");
+            if send.write_all(&hello).await.is_err() {
+                return None;
             }
+            probe_send.traffic_sent.fetch_add(hello.len() as u64, Ordering::Relaxed);
+        }
 
-            // Wait for flow control window to have space
-            loop {
-                let window = window_for_send.load(std::sync::atomic::Ordering::Relaxed);
-                if window > 0 || cancel_send.is_cancelled() {
+        // Replay scrollback before live data, paced by the client's window so
+        // it doesn't arrive as one burst with live output right behind it.
+        // An empty Scrollback frame tells the client live output starts.
+        let mut pacer = opts.max_bytes_per_sec.map(|cap| OutputPacer::new(cap, tokio::time::Instant::now()));
+        for chunk in replay.chunks(REPLAY_CHUNK_BYTES.min(opts.wire.max_payload)) {
+            if let Some(stalled) = wait_for_window(&window_for_send, &notify_for_send, &cancel_send).await {
+                probe_send.stalled_us.fetch_add(stalled.as_micros() as u64, Ordering::Relaxed);
+            }
+            wait_for_pacer(pacer.as_mut(), &cancel_send).await;
+            if cancel_send.is_cancelled() {
+                return None;
+            }
+            let replayed = Frame::scrollback(0, chunk.to_vec());
+            let (header, payload) = match frame::encode_parts(replayed, opts.wire.compress) {
+                Ok(parts) => parts,
+                Err(e) => {
+                    error!("scrollback encode error: {e}");
+                    return None;
+                }
+            };
+            if send.write_all(&header).await.is_err() || send.write_all(&payload).await.is_err() {
+                return None;
+            }
+            let wire_payload = payload.len() as u64;
+            probe_send.traffic_sent.fetch_add(header.len() as u64 + wire_payload, Ordering::Relaxed);
+            if let Some(pacer) = pacer.as_mut() {
+                pacer.sent(header.len() as u64 + wire_payload, tokio::time::Instant::now());
+            }
+            window_for_send
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |w| Some(w.saturating_sub(wire_payload)))
+                .ok();
+        }
+        if !replay.is_empty() {
+            let end = frame::encode_small(FrameType::Scrollback, 0, &[]).expect("replay end fits a small frame");
+            if send.write_all(&end).await.is_err() {
+                return None;
+            }
+            probe_send.traffic_sent.fetch_add(end.len() as u64, Ordering::Relaxed);
+        }
+        replayed_send.cancel();
+
+        // Chunk that didn't fit into the previous frame
+        let mut carry: Option<Bytes> = None;
+        // Output read but over the frame size (the bandwidth cap's, or the
+        // client's payload limit)
+        let mut paced_rest: Option<BytesMut> = None;
+        // Set once the client has sent Close
+        let mut drain_deadline: Option<tokio::time::Instant> = None;
+        let mut throttle = opts.low_bandwidth.then(OutputThrottle::new);
+        let ping_every = opts.liveness.map_or(HEARTBEAT_INTERVAL, |l| HEARTBEAT_INTERVAL.min(l / PINGS_PER_LIVENESS_TIMEOUT));
+        let start = tokio::time::Instant::now() + ping_every;
+        let mut heartbeat = tokio::time::interval_at(start, ping_every);
+        heartbeat.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        // Append to scrollback and the output log, feed mirrors, and follow
+        // the app's bracketed paste mode and prompt hooks' variable reports
+        let mut mode_watch = ModeWatch::new();
+        let mut env_watch = EnvWatch::new();
+        let mut record = |data: &[u8]| {
+            let mut sb = scrollback_for_send.lock().expect("scrollback lock");
+            sb.append(data);
+            if let Some(on) = mode_watch.observe(data) {
+                bracketed_for_send.store(on, Ordering::Relaxed);
+            }
+            let reported = env_watch.observe(data);
+            if !reported.is_empty() {
+                reported_env.lock().expect("reported env lock").extend(reported);
+            }
+            if let Some(log) = output_log.lock().expect("output log lock").as_mut() {
+                log.write(data);
+            }
+            if let Some(feed) = mirror_feed.upgrade().filter(|f| f.receiver_count() > 0) {
+                let _ = feed.send(Bytes::copy_from_slice(data));
+            }
+        };
+        loop {
+            if let Some(rest) = paced_rest.take() {
+                bufs.payload.unsplit(rest);
+            } else if let Some(throttle) = throttle.as_mut().filter(|t| t.has_unsent()) {
+                // Rest of a flush that didn't fit into the previous frame
+                let room = frame::MAX_PAYLOAD - bufs.payload.len();
+                throttle.take(&mut bufs.payload, room);
+            } else {
+                let first = match carry.take() {
+                    Some(data) => data,
+                    None => tokio::select! {
+                        output = next_output(&mut rx, &drain_send, &mut drain_deadline) => match output {
+                            Some(data) => data,
+                            None => break,
+                        },
+                        _ = heartbeat.tick(), if drain_deadline.is_none() => {
+                            let silent = probe_send.silent_for();
+                            if opts.liveness.is_some_and(|l| silent >= l) && probe_send.answers_pings() {
+                                // Gone without a word (out of coverage, killed): detach now,
+                                // not at the QUIC idle timeout, and keep the session
+                                warn!("client silent for {}s, detaching", silent.as_secs());
+                                let _ = send.reset(quinn::VarInt::from_u32(0));
+                                return None;
+                            }
+                            let ping = probe_send.ping().to_be_bytes();
+                            let ping = frame::encode_small(FrameType::Heartbeat, seq_out, &ping)
+                                .expect("ping fits a small frame");
+                            if send.write_all(&ping).await.is_err() {
+                                break;
+                            }
+                            probe_send.traffic_sent.fetch_add(ping.len() as u64, Ordering::Relaxed);
+                            continue;
+                        }
+                        Ok(()) = paste_acks_rx.changed() => {
+                            let written = *paste_acks_rx.borrow_and_update();
+                            if !opts.wire.decodes(FrameType::PasteAck) {
+                                continue;
+                            }
+                            let ack = frame::encode_small(FrameType::PasteAck, seq_out, &written.to_be_bytes())
+                                .expect("paste ack fits a small frame");
+                            if send.write_all(&ack).await.is_err() {
+                                break;
+                            }
+                            probe_send.traffic_sent.fetch_add(ack.len() as u64, Ordering::Relaxed);
+                            continue;
+                        }
+                        Some(error) = stream_errors_rx.recv() => {
+                            if !opts.wire.decodes(FrameType::Error) {
+                                debug!("client doesn't decode error frames, not sending {error}");
+                                continue;
+                            }
+                            let encoded = frame::encode(&Frame::error(seq_out, error.to_string().into_bytes()), false)
+                                .expect("error report fits a frame");
+                            if send.write_all(&encoded).await.is_err() {
+                                break;
+                            }
+                            probe_send.traffic_sent.fetch_add(encoded.len() as u64, Ordering::Relaxed);
+                            continue;
+                        }
+                    },
+                };
+                if cancel_send.is_cancelled() {
                     break;
                 }
-                // Wait for window update notification (with timeout to avoid deadlock)
-                tokio::select! {
-                    _ = notify_for_send.notified() => {}
-                    _ = tokio::time::sleep(std::time::Duration::from_secs(5)) => {
-                        warn!("flow control: window still 0 after 5s, resuming");
-                        break;
+
+                match throttle.as_mut() {
+                    Some(throttle) => {
+                        // Hold output until the next flush, then send only what's left on screen
+                        record(&first);
+                        throttle.push(&first);
+                        while let Ok(Some(more)) = tokio::time::timeout_at(throttle.flush_at(), rx.recv()).await {
+                            record(&more);
+                            throttle.push(&more);
+                        }
+                        let rows = session_for_send.lock().expect("session lock").size().map_or(24, |(rows, _)| rows);
+                        throttle.flush(rows as usize);
+                        let room = frame::MAX_PAYLOAD - bufs.payload.len();
+                        throttle.take(&mut bufs.payload, room);
+                    }
+                    None => {
+                        // Coalesce queued (and, if enabled, imminent) output into a single buffer
+                        let fresh = bufs.payload.len();
+                        bufs.payload.extend_from_slice(&first);
+                        carry = coalesce(&mut rx, &mut bufs.payload, opts.coalesce).await;
+                        record(&bufs.payload[fresh..]);
                     }
-                    _ = cancel_send.cancelled() => break,
                 }
             }
+            probe_send.queued.store(rx.len(), Ordering::Relaxed);
+            let frame_max = pacer.as_ref().map_or(opts.wire.max_payload, |p| p.frame_max().min(opts.wire.max_payload));
+            if bufs.payload.len() > frame_max {
+                paced_rest = Some(bufs.payload.split_off(frame_max));
+            }
+
+            let (flags, utf8_tail) = match opts.utf8_frames {
+                true => align_utf8(&mut bufs.payload),
+                false => (0, None),
+            };
+            if bufs.payload.is_empty() {
+                // Nothing but the start of a codepoint yet
+                if let Some(tail) = utf8_tail {
+                    bufs.payload.unsplit(tail);
+                }
+                continue;
+            }
+
+            // Wait for flow control window to have space
+            if let Some(stalled) = wait_for_window(&window_for_send, &notify_for_send, &cancel_send).await {
+                probe_send.stalled_us.fetch_add(stalled.as_micros() as u64, Ordering::Relaxed);
+            }
+            // Then for the bandwidth cap; output read meanwhile joins the next frame
+            wait_for_pacer(pacer.as_mut(), &cancel_send).await;
 
             // Encode frame with compression for larger payloads
-            let compress = data.len() > 256;
-            let frame = Frame::data(seq_out, data);
+            let encoded = bufs.encode_data(seq_out, flags);
+            probe_send.frames_sent.store(seq_out, Ordering::Relaxed);
             seq_out += 1;
+            // The held-back codepoint starts the next frame
+            if let Some(tail) = utf8_tail {
+                bufs.payload.unsplit(tail);
+            }
 
-            match frame::encode(&frame, compress) {
-                Ok(encoded) => {
-                    let wire_payload = encoded.len().saturating_sub(15) as u64; // 15 = frame header
-                    if send.write_all(&encoded).await.is_err() {
+            match encoded {
+                Ok(mut chunks) => {
+                    let wire_payload = chunks[1].len() as u64;
+                    let wire_len = chunks[0].len() as u64 + wire_payload;
+                    if send.write_all_chunks(&mut chunks).await.is_err() {
                         break;
                     }
+                    probe_send.traffic_sent.fetch_add(wire_len, Ordering::Relaxed);
+                    if let Some(pacer) = pacer.as_mut() {
+                        pacer.sent(wire_len, tokio::time::Instant::now());
+                    }
                     // Saturating subtraction to prevent underflow wrapping
                     window_for_send.fetch_update(
                         std::sync::atomic::Ordering::Relaxed,
@@ -352,53 +2324,188 @@ async fn run_bridge_inner(
                 }
             }
         }
+
+        if drain_deadline.is_some() && !cancel_send.is_cancelled() {
+            // Close handshake: output is drained, answer with our own Close
+            stop_read.cancel();
+            if detaching_send.is_cancelled() {
+                // The stream goes back to control requests instead of finishing
+                let close = frame::encode_small(FrameType::Close, seq_out, CLOSE_REASON_DETACHED.as_bytes())
+                    .expect("close reason fits a small frame");
+                return send.write_all(&close).await.is_ok().then_some((send, seq_out));
+            }
+            let close = frame::encode_small(FrameType::Close, seq_out, CLOSE_REASON_CLIENT.as_bytes())
+                .expect("close reason fits a small frame");
+            let _ = send.write_all(&close).await;
+            let _ = send.finish();
+            // Don't return (and let the caller drop the stream) before the client has it all
+            let _ = tokio::time::timeout(CLOSE_ACK_TIMEOUT, send.stopped()).await;
+            return None;
+        }
         let _ = send.finish();
+        None
     });
 
     // QUIC recv → frame decode → PTY write / handle control frames
     let window_for_recv = client_window;
     let notify_for_recv = window_notify;
     let cancel_recv = cancel.clone();
+    let probe_recv = probe.clone();
+    let recv_running = Running::new(&probe.receiver);
 
-    let recv_handle = tokio::spawn(async move {
-        let mut decoder = FrameDecoder::new();
+    let recv_handle = tasks.spawn("recv", async move {
+        let _running = recv_running;
+        let mut decoder = if opts.strict_frames { FrameDecoder::new() } else { FrameDecoder::new().lenient() };
         let mut recv = recv;
         let mut buf = [0u8; 16384];
+        let mut held = replaying.then(HeldInput::new);
+        // Unnamed sessions are named after the first command typed
+        let mut capture = session_ref.lock().expect("session lock").name.is_none().then(CommandCapture::new);
+        // Paste frames received until the empty one ending the paste
+        let mut pasting: Option<Paste> = None;
+        let mut limiter = InputLimiter::new(opts.input_limit, tokio::time::Instant::now());
 
         loop {
             if cancel_recv.is_cancelled() {
                 break;
             }
 
-            match recv.read(&mut buf).await {
+            let release_at = limiter.release_at();
+            let read = match &held {
+                None => tokio::select! {
+                    read = recv.read(&mut buf) => read,
+                    _ = sleep_until_deadline(release_at), if release_at.is_some() => {
+                        // Input queued over the rate limit
+                        let (input, seq) = limiter.release(tokio::time::Instant::now());
+                        if write_input(&pty_input, input, seq).await.is_err() {
+                            return None;
+                        }
+                        continue;
+                    }
+                },
+                Some(h) => tokio::select! {
+                    read = recv.read(&mut buf) => read,
+                    _ = replayed.cancelled() => {
+                        let (input, seq) = held.take().expect("held input").into_inner();
+                        if write_input(&pty_input, input, seq).await.is_err() {
+                            return None;
+                        }
+                        continue;
+                    }
+                    _ = tokio::time::sleep_until(h.deadline) => {
+                        warn!("scrollback replay still in flight, releasing held input");
+                        let (input, seq) = held.take().expect("held input").into_inner();
+                        if write_input(&pty_input, input, seq).await.is_err() {
+                            return None;
+                        }
+                        continue;
+                    }
+                },
+            };
+
+            match read {
                 Ok(Some(n)) => {
+                    probe_recv.traffic_received.fetch_add(n as u64, Ordering::Relaxed);
+                    probe_recv.heard();
                     decoder.feed(&buf[..n]);
 
                     loop {
                         match decoder.decode_next() {
                             Ok(Some(frame)) => {
+                                probe_recv.frames_received.fetch_add(1, Ordering::Relaxed);
                                 match frame.frame_type {
+                                    FrameType::Data | FrameType::Resize | FrameType::Paste if opts.read_only => {
+                                        // Read-only viewers don't type, paste or resize
+                                    }
+                                    FrameType::Data | FrameType::Paste
+                                        if opts.dedup_input
+                                            && frame.sequence <= input_mark.load(Ordering::Relaxed) =>
+                                    {
+                                        // Resent after a reconnect, and already written
+                                        debug!("skipping input frame {} the session already has", frame.sequence);
+                                    }
                                     FrameType::Data => {
-                                        let data = frame.payload;
-                                        let mut w = pty_writer.lock()
-                                            .expect("pty writer lock");
-                                        if w.write_all(&data).is_err() {
-                                            return;
+                                        let mut data = frame.payload;
+                                        let mut seq = frame.sequence;
+                                        activity.touch();
+                                        if let Some(name) = capture.as_mut().and_then(|c| c.push(&data)) {
+                                            capture = None;
+                                            name_session(&session_ref, name, &notifier);
+                                        }
+                                        if let Some(h) = held.as_mut() {
+                                            if h.push(&data, seq) {
+                                                continue;
+                                            }
+                                            // Over the cap: stop holding, write everything now
+                                            let (all, last) = held.take().expect("held input").into_inner();
+                                            (data, seq) = (all, last.unwrap_or(seq));
+                                        }
+                                        match limiter.admit(data, seq, tokio::time::Instant::now()) {
+                                            Admission::Write(data) => {
+                                                if write_input(&pty_input, data, Some(seq)).await.is_err() {
+                                                    return None;
+                                                }
+                                            }
+                                            Admission::Queued => {}
+                                            Admission::Dropped { first } => {
+                                                if first {
+                                                    warn!("client input over its rate limit, dropping it");
+                                                    let _ = stream_errors.try_send(serde_json::json!({
+                                                        "code": ErrorCode::RateLimited,
+                                                        "error": "input over this device's rate limit was dropped",
+                                                        "message": messages::message(ErrorCode::RateLimited, language),
+                                                    }));
+                                                }
+                                            }
                                         }
-                                        drop(w);
-                                        // Track last input activity
-                                        if let Ok(mut s) = session_ref.try_lock() {
-                                            s.last_activity_at = chrono::Utc::now();
+                                    }
+                                    FrameType::Paste => {
+                                        activity.touch();
+                                        if let Some(h) = held.take() {
+                                            // Keystrokes held during replay came first
+                                            let (input, seq) = h.into_inner();
+                                            if write_input(&pty_input, input, seq).await.is_err() {
+                                                return None;
+                                            }
+                                        }
+                                        // Queued keystrokes go first, and the paste at the rate limit
+                                        while let Some(at) = limiter.release_at() {
+                                            tokio::time::sleep_until(at).await;
+                                            let (input, seq) = limiter.release(tokio::time::Instant::now());
+                                            if write_input(&pty_input, input, seq).await.is_err() {
+                                                return None;
+                                            }
+                                        }
+                                        let wait = limiter.charge_paste(frame.payload.len(), tokio::time::Instant::now());
+                                        tokio::time::sleep(wait).await;
+                                        let (data, received) = if frame.payload.is_empty() {
+                                            let Some(paste) = pasting.take() else {
+                                                continue;
+                                            };
+                                            let received = paste.received;
+                                            (paste.finish(), received)
+                                        } else {
+                                            let paste = pasting.get_or_insert_with(|| {
+                                                Paste::new(bracketed_paste.load(Ordering::Relaxed))
+                                            });
+                                            (paste.push(&frame.payload), paste.received)
+                                        };
+                                        if write_paced(&pty_input, &data, frame.sequence, received).await.is_err() {
+                                            return None;
                                         }
                                     }
                                     FrameType::Resize => {
                                         if let Some((cols, rows)) = frame.parse_resize() {
                                             let cols = cols.clamp(1, 500);
                                             let rows = rows.clamp(1, 500);
-                                            let s = session_ref.lock()
+                                            let mut s = session_ref.lock()
                                                 .expect("session lock");
-                                            if let Err(e) = s.resize(rows, cols) {
-                                                warn!("resize error: {e}");
+                                            match s.resize(rows, cols) {
+                                                Ok(()) => {
+                                                    let by = s.last_attached_by.clone();
+                                                    s.history.record(EventKind::Resized { by, rows, cols });
+                                                }
+                                                Err(e) => warn!("resize error: {e}"),
                                             }
                                         }
                                     }
@@ -411,23 +2518,41 @@ async fn run_bridge_inner(
                                             notify_for_recv.notify_one();
                                         }
                                     }
+                                    FrameType::Close if frame.parse_close_reason() == Some(CLOSE_REASON_DETACH_REQUEST) => {
+                                        info!("received detach request");
+                                        return Some(ClientEnd::Detach { recv, last_received: frame.sequence });
+                                    }
                                     FrameType::Close => {
                                         info!("received Close frame");
-                                        return;
+                                        return Some(ClientEnd::Close);
                                     }
                                     FrameType::Heartbeat => {
-                                        // No-op, connection keepalive is handled by QUIC
+                                        // Keepalive is handled by QUIC; echoed pings measure round trips
+                                        probe_recv.pong(&frame.payload);
+                                    }
+                                    FrameType::Scrollback | FrameType::PasteAck => {
+                                        // Client shouldn't send replays or acks
+                                        warn!("unexpected {:?} frame from client", frame.frame_type);
+                                    }
+                                    FrameType::Error | FrameType::Hello => {
+                                        // Errors and hellos only flow from the daemon to clients
+                                        let (name, len) = (frame.frame_type.name(), frame.payload.len());
+                                        debug!("ignoring {name} frame from client ({len} bytes)");
                                     }
-                                    FrameType::Scrollback => {
-                                        // Client shouldn't send scrollback frames
-                                        warn!("unexpected Scrollback frame from client");
+                                    FrameType::Extension(ty) => {
+                                        // No extension handlers here yet; skip it, keep the stream
+                                        debug!("ignoring extension frame 0x{ty:02x}");
+                                    }
+                                    FrameType::Unknown(ty) => {
+                                        // From a newer or experimental client
+                                        info!("skipping unknown frame type 0x{ty:02x} ({} bytes)", frame.payload.len());
                                     }
                                 }
                             }
                             Ok(None) => break, // need more data
                             Err(e) => {
                                 error!("frame decode error: {e}");
-                                return;
+                                return None;
                             }
                         }
                     }
@@ -442,19 +2567,514 @@ async fn run_bridge_inner(
                 }
             }
         }
+        None
     });
 
     // Wait for any task to end
+    let client_end = tokio::select! {
+        _ = read_ended.cancelled() => {
+            info!("PTY read task ended");
+            None
+        }
+        _ = &mut send_handle => {
+            info!("QUIC send task ended");
+            None
+        }
+        end = recv_handle => {
+            info!("QUIC recv task ended");
+            end.ok().flatten()
+        }
+        _ = cancel.cancelled() => {
+            info!("bridge cancelled");
+            None
+        }
+        _ = sleep_until_deadline(opts.deadline) => {
+            info!("bridge access expired");
+            None
+        }
+    };
+
+    // Input the client sent before leaving is written before the session
+    // is handed on (the receive task is gone, so the writer's queue ends)
+    if client_end.is_some() {
+        let _ = tokio::time::timeout(CLOSE_DRAIN_MAX, pty_write_handle).await;
+    }
+
+    match client_end {
+        Some(ClientEnd::Close) => {
+            // Orderly teardown: flush pending output, send Close, finish the stream
+            drain.cancel();
+            let _ = send_handle.await;
+            Ok(None)
+        }
+        Some(ClientEnd::Detach { recv, last_received }) => {
+            // Same, but the send task hands the stream back
+            detaching.cancel();
+            drain.cancel();
+            Ok(send_handle.await.ok().flatten().map(|(send, last_sent)| Detached {
+                send,
+                recv,
+                last_sent,
+                last_received,
+            }))
+        }
+        None => Ok(None),
+    }
+}
+
+/// Wait for the client's flow control window to have space, giving up after
+/// 5s (to avoid a deadlock) or when the bridge is cancelled. Returns how long
+/// output was held back, if at all.
+async fn wait_for_window(window: &AtomicU64, notify: &Notify, cancel: &CancellationToken) -> Option<Duration> {
+    let mut stalled_since = None;
+    loop {
+        if window.load(Ordering::Relaxed) > 0 || cancel.is_cancelled() {
+            break;
+        }
+        stalled_since.get_or_insert_with(Instant::now);
+        tokio::select! {
+            _ = notify.notified() => {}
+            _ = tokio::time::sleep(Duration::from_secs(5)) => {
+                warn!("flow control: window still 0 after 5s, resuming");
+                break;
+            }
+            _ = cancel.cancelled() => break,
+        }
+    }
+    stalled_since.map(|since| since.elapsed())
+}
+
+/// Wait until the bandwidth cap, if any, lets the next frame go.
+async fn wait_for_pacer(pacer: Option<&mut OutputPacer>, cancel: &CancellationToken) {
+    let Some(pacer) = pacer else {
+        return;
+    };
+    let ready_at = pacer.ready_at(tokio::time::Instant::now());
     tokio::select! {
-        _ = pty_read_handle => info!("PTY read task ended"),
-        _ = send_handle => info!("QUIC send task ended"),
-        _ = recv_handle => info!("QUIC recv task ended"),
-        _ = cancel.cancelled() => info!("bridge cancelled"),
+        _ = tokio::time::sleep_until(ready_at) => {}
+        _ = cancel.cancelled() => {}
     }
+}
+
+/// Stream a session's output to a mirroring device: a scrollback replay, then
+/// live output from the attached bridge as Data frames. Mirrors are read-only
+/// and outside flow control; client frames other than Close are ignored.
+/// Ends with a Close frame: `detached` once the attached client leaves,
+/// `lagged` if the mirror fell behind (mirror again to resync), or
+/// `client_close` in answer to the client's Close.
+async fn run_mirror(
+    mut send: SendStream,
+    mut recv: RecvStream,
+    mut output: broadcast::Receiver<Bytes>,
+    replay: Vec<u8>,
+    deadline: Option<tokio::time::Instant>,
+    _memory: Reservation,
+) -> Result<()> {
+    let wire = hello::current();
+    if wire.decodes(FrameType::Hello) {
+        send.write_all(&frame::encode(&Frame::hello(0, wire.describe().to_string().into_bytes()), false)?).await?;
+    }
+    for chunk in replay.chunks(REPLAY_CHUNK_BYTES.min(wire.max_payload)) {
+        let encoded = frame::encode(&Frame::scrollback(0, chunk.to_vec()), wire.compress)?;
+        send.write_all(&encoded).await?;
+    }
+    if !replay.is_empty() {
+        send.write_all(&frame::encode(&Frame::replay_end(0), false)?).await?;
+    }
+
+    let mut bufs = FrameBuffers { compress: wire.compress, ..FrameBuffers::new()? };
+    let mut seq_out: u64 = 1;
+    let mut decoder = FrameDecoder::new().lenient();
+    let mut buf = [0u8; 4096];
+    let reason = loop {
+        tokio::select! {
+            chunk = output.recv() => match chunk {
+                Ok(chunk) => {
+                    for piece in chunk.chunks(wire.max_payload) {
+                        bufs.payload.extend_from_slice(piece);
+                        let mut chunks = bufs.encode_data(seq_out, 0)?;
+                        seq_out += 1;
+                        if send.write_all_chunks(&mut chunks).await.is_err() {
+                            return Ok(());
+                        }
+                    }
+                }
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    warn!("mirror fell {skipped} chunks behind, closing");
+                    break CLOSE_REASON_LAGGED;
+                }
+                Err(broadcast::error::RecvError::Closed) => break CLOSE_REASON_DETACHED,
+            },
+            read = recv.read(&mut buf) => match read {
+                Ok(Some(n)) => {
+                    decoder.feed(&buf[..n]);
+                    let mut closed = false;
+                    while let Some(frame) = decoder.decode_next()? {
+                        closed |= frame.frame_type == FrameType::Close;
+                    }
+                    if closed {
+                        break CLOSE_REASON_CLIENT;
+                    }
+                }
+                Ok(None) | Err(_) => return Ok(()),
+            },
+            _ = sleep_until_deadline(deadline) => {
+                info!("mirror access expired");
+                let _ = send.finish();
+                return Ok(());
+            }
+        }
+    };
+
+    let close = frame::encode_small(FrameType::Close, seq_out, reason.as_bytes())
+        .expect("close reason fits a small frame");
+    let _ = send.write_all(&close).await;
+    let _ = send.finish();
+    let _ = tokio::time::timeout(CLOSE_ACK_TIMEOUT, send.stopped()).await;
+    Ok(())
+}
+
+/// Sleep until `deadline`, or forever if there is none.
+async fn sleep_until_deadline(deadline: Option<tokio::time::Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Input for the PTY writer thread. `seq` is the sequence of the last input
+/// frame in it, marked as the device's once it is written.
+enum PtyInput {
+    /// Typed input, merged with typed input queued behind it
+    Data { data: Vec<u8>, seq: Option<u64> },
+    /// A piece of a paste, written on its own; the last piece of a Paste
+    /// frame carries the frame's `seq` and the paste's bytes `written`, to
+    /// acknowledge once it is
+    Paste { data: Vec<u8>, seq: Option<u64>, written: Option<u64> },
+}
+
+/// Queue typed input for the PTY writer thread, waiting while its queue is
+/// full. Fails once the thread has stopped (on a failed write).
+async fn write_input(input: &mpsc::Sender<PtyInput>, data: Vec<u8>, seq: Option<u64>) -> Result<(), SendError<PtyInput>> {
+    input.send(PtyInput::Data { data, seq }).await
+}
 
+/// Queue pasted input from Paste frame `seq` in [`paste::CHUNK`]-byte
+/// pieces, pausing between them so the line discipline's input queue
+/// doesn't overflow; `received` is acknowledged once the last is written.
+async fn write_paced(
+    input: &mpsc::Sender<PtyInput>,
+    data: &[u8],
+    seq: u64,
+    received: u64,
+) -> Result<(), SendError<PtyInput>> {
+    // An empty paste end still has its bytes acknowledged
+    let pieces: Vec<&[u8]> = if data.is_empty() { vec![data] } else { data.chunks(paste::CHUNK).collect() };
+    for (i, piece) in pieces.iter().enumerate() {
+        if i > 0 {
+            tokio::time::sleep(paste::CHUNK_INTERVAL).await;
+        }
+        let last = i + 1 == pieces.len();
+        let (seq, written) = (last.then_some(seq), last.then_some(received));
+        input.send(PtyInput::Paste { data: piece.to_vec(), seq, written }).await?;
+    }
     Ok(())
 }
 
+/// Client input received while scrollback is still being replayed. Holding
+/// it keeps keystrokes (and the PTY output they cause) from interleaving
+/// with the replay, which garbles full-screen apps.
+struct HeldInput {
+    buf: Vec<u8>,
+    /// Sequence of the last frame held
+    seq: Option<u64>,
+    /// Release held input at this point even if replay hasn't finished
+    deadline: tokio::time::Instant,
+}
+
+impl HeldInput {
+    fn new() -> Self {
+        Self {
+            buf: Vec::new(),
+            seq: None,
+            deadline: tokio::time::Instant::now() + HELD_INPUT_TIMEOUT,
+        }
+    }
+
+    /// Hold frame `seq`'s `data`. Returns false once the cap is exceeded;
+    /// the caller should then stop holding and release everything.
+    fn push(&mut self, data: &[u8], seq: u64) -> bool {
+        self.buf.extend_from_slice(data);
+        self.seq = Some(seq);
+        self.buf.len() <= HELD_INPUT_CAP
+    }
+
+    /// The held input, and the sequence of its last frame.
+    fn into_inner(self) -> (Vec<u8>, Option<u64>) {
+        (self.buf, self.seq)
+    }
+}
+
+/// Receive the next chunk of PTY output. Once `drain` fires (client Close),
+/// gives up after `CLOSE_QUIET` without output or at the drain deadline.
+async fn next_output(
+    rx: &mut mpsc::Receiver<Bytes>,
+    drain: &CancellationToken,
+    deadline: &mut Option<tokio::time::Instant>,
+) -> Option<Bytes> {
+    if deadline.is_none() {
+        tokio::select! {
+            biased;
+            _ = drain.cancelled() => {
+                *deadline = Some(tokio::time::Instant::now() + CLOSE_DRAIN_MAX);
+            }
+            data = rx.recv() => return data,
+        }
+    }
+    let deadline = (*deadline)?;
+    let until = (tokio::time::Instant::now() + CLOSE_QUIET).min(deadline);
+    tokio::time::timeout_at(until, rx.recv()).await.ok().flatten()
+}
+
+/// The PTY reads of one bridge: a blocking thread reading into `output`,
+/// replaced by [`watch_reads`] if it hangs.
+#[derive(Clone)]
+struct PtyReads {
+    output: mpsc::Sender<Bytes>,
+    cancel: CancellationToken,
+    watch: Arc<ReadWatch>,
+    running: Arc<AtomicBool>,
+    /// Fired when the current reader stops, ending the bridge
+    ended: CancellationToken,
+}
+
+impl PtyReads {
+    /// Read into the output channel as reader `generation` until EOF, an
+    /// error or cancellation, or (once replaced) until its read returns.
+    fn run(self, mut reader: Box<dyn Read + Send>, generation: u64) {
+        let _exit = ReaderExit { reads: &self, generation };
+        let mut slab = BytesMut::with_capacity(READ_SLAB_BYTES);
+        loop {
+            if self.cancel.is_cancelled() {
+                break;
+            }
+            self.watch.reading(tokio::time::Instant::now());
+            let read = read_chunk(&mut reader, &mut slab);
+            let current = self.watch.is_current(generation);
+            // A replaced reader's EOF says nothing about its successor
+            if current || read.as_ref().is_ok_and(|chunk| !chunk.is_empty()) {
+                self.watch.read_returned();
+            }
+            match read {
+                Ok(chunk) if chunk.is_empty() => {
+                    if current {
+                        info!("PTY reader EOF");
+                    }
+                    break;
+                }
+                Ok(chunk) => {
+                    if self.output.blocking_send(chunk).is_err() || !current {
+                        break;
+                    }
+                }
+                Err(e) => {
+                    if !current {
+                        debug!("replaced PTY reader failed: {e}");
+                    } else if e.raw_os_error() == Some(5) { // EIO
+                        info!("PTY reader got EIO (child exited)");
+                    } else {
+                        error!("PTY read error: {e}");
+                    }
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// Ends the bridge when the current reader stops, even by panicking.
+struct ReaderExit<'a> {
+    reads: &'a PtyReads,
+    generation: u64,
+}
+
+impl Drop for ReaderExit<'_> {
+    fn drop(&mut self) {
+        if self.reads.watch.is_current(self.generation) {
+            self.reads.running.store(false, Ordering::Relaxed);
+            self.reads.ended.cancel();
+        }
+    }
+}
+
+/// Replace the PTY reader when it stalls with output waiting. If it can't
+/// be cloned, or the new reader stalls too before reading anything, warn
+/// and stop watching: the session is left as it is.
+async fn watch_reads(reads: PtyReads, limit: Duration, session: Arc<Mutex<PtySession>>, tracker: TaskTracker) {
+    let mut tick = tokio::time::interval((limit / 4).max(Duration::from_millis(100)));
+    // Reads done when the reader was replaced, until the new one reads
+    let mut replaced_at = None;
+    loop {
+        // Done once reads are (which drops this sender of their output)
+        tokio::select! {
+            _ = tick.tick() => {}
+            _ = reads.cancel.cancelled() => return,
+            _ = reads.ended.cancelled() => return,
+        }
+        let watch = &reads.watch;
+        if replaced_at.is_some_and(|done| done != watch.reads()) {
+            replaced_at = None;
+        }
+        let Some(readable) = session.lock().expect("session lock").backend.output_waiting() else {
+            debug!("PTY can't be polled for waiting output, not watching its reads");
+            return;
+        };
+        let Some(stalled) = watch.stalled(tokio::time::Instant::now(), readable, limit) else {
+            continue;
+        };
+        let stalled = stalled.as_secs();
+        if replaced_at.is_some() {
+            warn!("PTY reads stalled for {stalled}s with output waiting, with a re-cloned reader too");
+            return;
+        }
+        match reclone_reader(watch, || session.lock().expect("session lock").backend.try_clone_reader()) {
+            Ok((reader, generation)) => {
+                warn!("PTY read stalled for {stalled}s with output waiting, reading with a re-cloned reader");
+                reads.running.store(true, Ordering::Relaxed);
+                replaced_at = Some(watch.reads());
+                let reads = reads.clone();
+                let span = tracing::Span::current();
+                tracker.spawn_blocking(move || span.in_scope(|| reads.run(reader, generation)));
+            }
+            Err(e) => {
+                warn!("PTY read stalled for {stalled}s with output waiting, and re-cloning the reader failed: {e:#}");
+                return;
+            }
+        }
+    }
+}
+
+/// Retire the stuck reader, then make its replacement with `clone`. The
+/// order matters: a backend may end its old readers' reads on cloning (the
+/// scripted one does), and a read ending in EOF while its reader is still the
+/// current one ends the bridge. Returns the new reader and its generation.
+fn reclone_reader(
+    watch: &ReadWatch,
+    clone: impl FnOnce() -> Result<Box<dyn Read + Send>>,
+) -> Result<(Box<dyn Read + Send>, u64)> {
+    let generation = watch.replace_reader();
+    Ok((clone()?, generation))
+}
+
+/// Read one chunk of PTY output into the slab and split it off as `Bytes`.
+/// An empty chunk means EOF. The slab's allocation is reused once earlier
+/// chunks have been dropped, so steady-state reads don't allocate.
+fn read_chunk(reader: &mut impl Read, slab: &mut BytesMut) -> std::io::Result<Bytes> {
+    slab.resize(READ_CHUNK_BYTES, 0);
+    let n = reader.read(slab)?;
+    slab.truncate(n);
+    Ok(slab.split().freeze())
+}
+
+/// Reusable buffers for the send path. Outgoing payloads are gathered in a
+/// slab and split off as `Bytes` together with a header from a second slab,
+/// so frames reach `write_all_chunks` without a per-frame allocation.
+struct FrameBuffers {
+    /// Raw output for the next frame
+    payload: BytesMut,
+    headers: BytesMut,
+    compressor: FrameCompressor,
+    /// Off when the client has no compression
+    compress: bool,
+    /// Where what the compressor did is counted
+    counters: Option<Arc<CompressionCounters>>,
+}
+
+impl FrameBuffers {
+    fn new() -> Result<Self, FrameError> {
+        Ok(Self {
+            payload: BytesMut::with_capacity(PAYLOAD_SLAB_BYTES),
+            headers: BytesMut::with_capacity(HEADER_SLAB_BYTES),
+            compressor: FrameCompressor::new()?,
+            compress: true,
+            counters: None,
+        })
+    }
+
+    /// Bypass compression at `bypass_entropy` (see
+    /// [`FrameCompressor::with_bypass_entropy`]), counting into `counters`.
+    fn with_compression(mut self, bypass_entropy: Option<f64>, counters: Arc<CompressionCounters>) -> Self {
+        self.compressor = self.compressor.with_bypass_entropy(bypass_entropy);
+        self.counters = Some(counters);
+        self
+    }
+
+    /// Frame the gathered payload as a Data frame, compressing when worthwhile.
+    /// Returns the header and wire payload chunks and leaves `payload` empty.
+    fn encode_data(&mut self, seq: u64, mut flags: u16) -> Result<[Bytes; 2], FrameError> {
+        if self.compress {
+            if let Some(compressed) = self.compressor.compress(&self.payload)? {
+                self.payload.clear();
+                self.payload.extend_from_slice(compressed);
+                flags |= frame::FLAG_COMPRESSED;
+            }
+            if let Some(counters) = &self.counters {
+                counters.add(self.compressor.take_stats());
+            }
+        }
+        let header = frame::encode_header(FrameType::Data, seq, flags, self.payload.len())?;
+        self.headers.reserve(frame::HEADER_SIZE);
+        self.headers.extend_from_slice(&header);
+        Ok([self.headers.split().freeze(), self.payload.split().freeze()])
+    }
+}
+
+/// For codepoint-aligned Data frames: split a codepoint cut off at the end
+/// of `payload` off, to start the next frame, and flag the rest if it isn't
+/// UTF-8.
+fn align_utf8(payload: &mut BytesMut) -> (u16, Option<BytesMut>) {
+    let cut = frame::incomplete_utf8_tail(payload);
+    let tail = (cut > 0).then(|| payload.split_off(payload.len() - cut));
+    let flags = match std::str::from_utf8(payload) {
+        Ok(_) => 0,
+        Err(_) => frame::FLAG_BINARY,
+    };
+    (flags, tail)
+}
+
+/// Append queued PTY output to `data`, up to one frame's payload.
+///
+/// With a coalescing window, small output (typically keystroke echo) waits
+/// briefly for more data so interactive typing produces fewer, larger frames.
+/// Returns a chunk that would have overflowed the frame, to be sent next.
+async fn coalesce(
+    rx: &mut mpsc::Receiver<Bytes>,
+    data: &mut BytesMut,
+    window: Option<Duration>,
+) -> Option<Bytes> {
+    while let Ok(more) = rx.try_recv() {
+        if data.len() + more.len() > frame::MAX_PAYLOAD {
+            return Some(more);
+        }
+        data.extend_from_slice(&more);
+    }
+
+    let window = window?;
+    let deadline = tokio::time::Instant::now() + window;
+    while data.len() < COALESCE_BYPASS_BYTES {
+        match tokio::time::timeout_at(deadline, rx.recv()).await {
+            Ok(Some(more)) => {
+                if data.len() + more.len() > frame::MAX_PAYLOAD {
+                    return Some(more);
+                }
+                data.extend_from_slice(&more);
+            }
+            Ok(None) | Err(_) => break,
+        }
+    }
+    None
+}
+
 async fn write_json(send: &mut SendStream, value: &serde_json::Value) -> Result<()> {
     let json = serde_json::to_vec(value).context("serialize JSON")?;
     let len = (json.len() as u32).to_be_bytes();
@@ -462,3 +3082,1650 @@ async fn write_json(send: &mut SendStream, value: &serde_json::Value) -> Result<
     send.write_all(&json).await.context("write JSON body")?;
     Ok(())
 }
+
+/// Name an unnamed session after a command typed into it, and tell the
+/// devices that list it.
+fn name_session(session: &Mutex<PtySession>, name: String, notifier: &DeviceNotifier) {
+    let mut s = session.lock().expect("session lock");
+    if s.name.is_some() {
+        return;
+    }
+    info!("session {} named {name:?}", s.id);
+    s.name = Some(name.clone());
+    let event = serde_json::json!({
+        "type": "session_renamed",
+        "session_id": s.id,
+        "name": name,
+    });
+    notifier.notify_session(&s, &event);
+}
+
+/// Answer a detach request on the stream it came back on, with where both
+/// sides' sequences ended and the session's terminal size.
+async fn ack_detach(
+    mut detached: Detached,
+    session_manager: &SessionManager,
+    session_id: &str,
+) -> Result<(SendStream, RecvStream)> {
+    let size = session_manager
+        .get_session(session_id)
+        .and_then(|session| session.lock().expect("session lock").size().ok());
+    info!("client detached from session {session_id}, keeping its stream");
+    let resp = serde_json::json!({
+        "type": "session_detached",
+        "session_id": session_id,
+        "last_sequence_sent": detached.last_sent,
+        "last_sequence_received": detached.last_received,
+        "rows": size.map(|(rows, _)| rows),
+        "cols": size.map(|(_, cols)| cols),
+    });
+    write_json(&mut detached.send, &resp).await?;
+    Ok((detached.send, detached.recv))
+}
+
+/// Cancel the bridge on `session`, wait for it to detach, and claim the
+/// session for the caller's bridge in the same step, so no other attach can
+/// slip in between. False if it didn't detach within [`TAKEOVER_TIMEOUT`].
+async fn take_over(session: &Arc<Mutex<PtySession>>) -> bool {
+    let cancel = session.lock().expect("session lock").bridge_cancel.clone();
+    if let Some(cancel) = cancel {
+        cancel.cancel();
+    }
+    let deadline = tokio::time::Instant::now() + TAKEOVER_TIMEOUT;
+    loop {
+        {
+            let mut s = session.lock().expect("session lock");
+            if !s.attached {
+                s.attached = true;
+                return true;
+            }
+        }
+        if tokio::time::Instant::now() >= deadline {
+            return false;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+}
+
+/// Reply to a control request with an error the client can show, and a
+/// code it can branch on.
+pub(crate) async fn write_error(send: &mut SendStream, request_id: &str, code: ErrorCode, error: &str) -> Result<()> {
+    let resp = serde_json::json!({
+        "type": "error",
+        "request_id": request_id,
+        "code": code,
+        "error": error,
+        "message": messages::localized(code),
+    });
+    write_json(send, &resp).await
+}
+
+/// The next session request (length-prefixed JSON like control messages),
+/// or `None` once the client has closed the stream.
+async fn read_request(recv: &mut RecvStream) -> Result<Option<Vec<u8>>> {
+    let mut len_buf = [0u8; 4];
+    if let Err(e) = recv.read_exact(&mut len_buf).await {
+        // Stream closed or reset — normal disconnect
+        info!("session stream read ended: {e}");
+        return Ok(None);
+    }
+    let len = u32::from_be_bytes(len_buf) as usize;
+
+    let mut msg_buf = vec![0u8; len];
+    recv.read_exact(&mut msg_buf)
+        .await
+        .context("read session request body")?;
+    Ok(Some(msg_buf))
+}
+
+/// Reply with `err`, coded where it was raised.
+async fn write_failure(send: &mut SendStream, request_id: &str, err: &anyhow::Error) -> Result<()> {
+    write_error(send, request_id, ErrorCode::of(err), &format!("{err:#}")).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::terminal::{ScriptHandle, ScriptedTerminal};
+
+    /// A daemon-side session manager on scripted terminals behind a loopback
+    /// QUIC connection. Each client stream is served by `handle_session_stream`.
+    struct ScriptedDaemon {
+        sm: Arc<SessionManager>,
+        handles: Arc<Mutex<Vec<ScriptHandle>>>,
+        /// When set, new streams are served with guest access
+        guest: Arc<Mutex<Option<Guest>>>,
+        /// Policy of the device new streams are served for
+        policy: Arc<Mutex<DevicePolicy>>,
+        /// Deadlines new streams are served with
+        deadlines: Arc<Mutex<StreamDeadlines>>,
+        conn: quinn::Connection,
+        _endpoints: (quinn::Endpoint, quinn::Endpoint),
+    }
+
+    impl ScriptedDaemon {
+        async fn start() -> Self {
+            Self::with_manager(SessionManager::new()).await
+        }
+
+        /// A daemon in multi-user mode.
+        async fn multi_user() -> Self {
+            let mut config = crate::config::DaemonConfig::default();
+            config.system.multi_user = true;
+            Self::with_manager(SessionManager::with_config(&config)).await
+        }
+
+        async fn with_manager(sm: SessionManager) -> Self {
+            let _ = rustls::crypto::ring::default_provider().install_default();
+            let cert = rcgen::generate_simple_self_signed(vec!["phantom.local".into()]).unwrap();
+            let key = cert.key_pair.serialize_der();
+            let server_config = crate::tls::build_server_config(cert.cert.der(), &key, &Default::default()).unwrap();
+            let server = quinn::Endpoint::server(server_config, "127.0.0.1:0".parse().unwrap()).unwrap();
+
+            let mut roots = rustls::RootCertStore::empty();
+            roots.add(cert.cert.der().clone()).unwrap();
+            let mut crypto = rustls::ClientConfig::builder()
+                .with_root_certificates(roots)
+                .with_no_client_auth();
+            crypto.alpn_protocols = vec![b"phantom/1".to_vec()];
+            let mut client = quinn::Endpoint::client("127.0.0.1:0".parse().unwrap()).unwrap();
+            client.set_default_client_config(quinn::ClientConfig::new(Arc::new(
+                quinn::crypto::rustls::QuicClientConfig::try_from(crypto).unwrap(),
+            )));
+
+            let handles = Arc::new(Mutex::new(Vec::new()));
+            let spawned = handles.clone();
+            let sm = Arc::new(sm.with_spawner(ScriptedTerminal::spawner(
+                false,
+                move |h| spawned.lock().unwrap().push(h),
+            )));
+
+            let connecting = client.connect(server.local_addr().unwrap(), "phantom.local").unwrap();
+            let (conn, server_conn) =
+                tokio::join!(connecting, async { server.accept().await.unwrap().await });
+            let server_conn = server_conn.unwrap();
+            sm.register_connection("test-device", &server_conn);
+            let sm_server = sm.clone();
+            let guest: Arc<Mutex<Option<Guest>>> = Arc::default();
+            let guest_server = guest.clone();
+            let policy: Arc<Mutex<DevicePolicy>> = Arc::default();
+            let policy_server = policy.clone();
+            let deadlines: Arc<Mutex<StreamDeadlines>> = Arc::default();
+            let deadlines_server = deadlines.clone();
+            tokio::spawn(async move {
+                while let Ok((send, recv)) = server_conn.accept_bi().await {
+                    let sm = sm_server.clone();
+                    let guest = guest_server.lock().unwrap().clone();
+                    let policy = policy_server.lock().unwrap().clone();
+                    let deadlines = *deadlines_server.lock().unwrap();
+                    // The client is this build: it decodes every frame type
+                    tokio::spawn(hello::scope(Wire::full(), async move {
+                        let _ = match guest {
+                            Some(guest) => handle_guest_stream(send, recv, &sm, &guest, deadlines).await,
+                            None => handle_session_stream(send, recv, &sm, "test-device", &policy, deadlines).await,
+                        };
+                    }));
+                }
+            });
+
+            Self {
+                sm,
+                handles,
+                guest,
+                policy,
+                deadlines,
+                conn: conn.unwrap(),
+                _endpoints: (server, client),
+            }
+        }
+
+        fn serve_as_guest(&self, guest: Guest) {
+            *self.guest.lock().unwrap() = Some(guest);
+        }
+
+        fn serve_with(&self, policy: DevicePolicy) {
+            *self.policy.lock().unwrap() = policy;
+        }
+
+        fn serve_with_deadlines(&self, deadlines: StreamDeadlines) {
+            *self.deadlines.lock().unwrap() = deadlines;
+        }
+
+        fn handle(&self, index: usize) -> ScriptHandle {
+            self.handles.lock().unwrap()[index].clone()
+        }
+
+        async fn request(&self, req: serde_json::Value) -> (ClientStream, serde_json::Value) {
+            let (mut send, mut recv) = self.conn.open_bi().await.unwrap();
+            write_json(&mut send, &req).await.unwrap();
+            let mut len = [0u8; 4];
+            recv.read_exact(&mut len).await.unwrap();
+            let mut body = vec![0u8; u32::from_be_bytes(len) as usize];
+            recv.read_exact(&mut body).await.unwrap();
+            let resp = serde_json::from_slice(&body).unwrap();
+            let stream = ClientStream {
+                send,
+                recv,
+                decoder: FrameDecoder::new(),
+                seq: 1,
+            };
+            (stream, resp)
+        }
+
+        /// Next event the daemon pushed on a stream of its own.
+        async fn next_event(&self) -> serde_json::Value {
+            let mut recv = tokio::time::timeout(Duration::from_secs(5), self.conn.accept_uni())
+                .await
+                .expect("no event")
+                .unwrap();
+            let mut len = [0u8; 4];
+            recv.read_exact(&mut len).await.unwrap();
+            let mut body = vec![0u8; u32::from_be_bytes(len) as usize];
+            recv.read_exact(&mut body).await.unwrap();
+            serde_json::from_slice(&body).unwrap()
+        }
+
+        /// Wait until no session is attached (the bridge has fully detached).
+        async fn wait_detached(&self) {
+            for _ in 0..200 {
+                if self.sm.list_sessions().iter().all(|s| !s.attached) {
+                    return;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            panic!("session still attached");
+        }
+    }
+
+    impl Drop for ScriptedDaemon {
+        /// Exit the terminals so blocked PTY read threads let the runtime shut down.
+        fn drop(&mut self) {
+            for handle in self.handles.lock().unwrap().iter() {
+                handle.exit(0);
+            }
+        }
+    }
+
+    struct ClientStream {
+        send: SendStream,
+        recv: RecvStream,
+        decoder: FrameDecoder,
+        seq: u64,
+    }
+
+    impl ClientStream {
+        async fn send_frame(&mut self, frame_type: FrameType, payload: &[u8]) {
+            let frame = Frame { frame_type, sequence: self.seq, payload: payload.to_vec() };
+            let encoded = frame::encode(&frame, false).unwrap();
+            self.seq += 1;
+            self.send.write_all(&encoded).await.unwrap();
+        }
+
+        /// Next frame past the Hello a bridge starts with, or None once the
+        /// daemon has finished the stream.
+        async fn next_frame(&mut self, timeout: Duration) -> Option<Frame> {
+            tokio::time::timeout(timeout, async {
+                loop {
+                    match self.decoder.decode_next().unwrap() {
+                        Some(frame) if frame.frame_type == FrameType::Hello => continue,
+                        Some(frame) => return Some(frame),
+                        None => {}
+                    }
+                    let mut buf = [0u8; 4096];
+                    let n = self.recv.read(&mut buf).await.unwrap()?;
+                    self.decoder.feed(&buf[..n]);
+                }
+            })
+            .await
+            .expect("timed out waiting for a frame")
+        }
+
+        /// Next length-prefixed control message, once the stream has left
+        /// frame mode.
+        async fn next_message(&mut self) -> serde_json::Value {
+            let mut pending = self.decoder.remaining().to_vec();
+            self.decoder = FrameDecoder::new();
+            tokio::time::timeout(Duration::from_secs(5), async {
+                loop {
+                    if let Some((json, consumed)) = frame::control::decode_message(&pending) {
+                        let msg = serde_json::from_slice(json).unwrap();
+                        self.decoder.feed(&pending[consumed..]);
+                        return msg;
+                    }
+                    let mut buf = [0u8; 4096];
+                    let n = self.recv.read(&mut buf).await.unwrap().expect("stream finished");
+                    pending.extend_from_slice(&buf[..n]);
+                }
+            })
+            .await
+            .expect("timed out waiting for a message")
+        }
+    }
+
+    async fn wait_until(mut condition: impl FnMut() -> bool) {
+        for _ in 0..200 {
+            if condition() {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("condition not reached");
+    }
+
+    #[tokio::test]
+    async fn scripted_bridge_relays_output_input_and_resize() {
+        let daemon = ScriptedDaemon::start().await;
+        let (mut client, resp) = daemon
+            .request(serde_json::json!({"type": "create_session", "rows": 24, "cols": 80}))
+            .await;
+        assert_eq!(resp["type"], "session_created");
+        let term = daemon.handle(0);
+
+        term.emit(b"$ ");
+        let frame = client.next_frame(Duration::from_secs(5)).await.unwrap();
+        assert_eq!((frame.frame_type, frame.sequence), (FrameType::Data, 1));
+        assert_eq!(frame.payload, b"$ ");
+
+        client.send_frame(FrameType::Data, b"ls\n").await;
+        client.send_frame(FrameType::Resize, &frame::resize_payload(100, 30)).await;
+        wait_until(|| term.input() == b"ls\n" && term.size() == (30, 100)).await;
+    }
+
+    #[tokio::test]
+    async fn pastes_are_bracketed_once_the_app_asks_and_acknowledged() {
+        let daemon = ScriptedDaemon::start().await;
+        let (mut client, _) = daemon.request(serde_json::json!({"type": "create_session"})).await;
+        let term = daemon.handle(0);
+
+        client.send_frame(FrameType::Paste, b"ls\n").await;
+        client.send_frame(FrameType::Paste, b"").await;
+        // Acks for the same count may arrive once
+        assert_eq!(client.next_frame(Duration::from_secs(2)).await.unwrap().parse_paste_ack(), Some(3));
+        assert_eq!(term.input(), b"ls\n");
+
+        term.emit(b"\x1b[?2004h$ ");
+        while client.next_frame(Duration::from_secs(2)).await.unwrap().frame_type != FrameType::Data {}
+        let pasted = "x".repeat(paste::CHUNK * 3);
+        client.send_frame(FrameType::Paste, pasted.as_bytes()).await;
+        client.send_frame(FrameType::Paste, b"\x1b[201~pwd\n").await;
+        client.send_frame(FrameType::Paste, b"").await;
+        let mut acks = Vec::new();
+        while acks.last() != Some(&(pasted.len() as u64 + 10)) {
+            acks.extend(client.next_frame(Duration::from_secs(2)).await.unwrap().parse_paste_ack());
+        }
+        let expected = format!("ls\n\x1b[200~{pasted}pwd\n\x1b[201~");
+        wait_until(|| term.input() == expected.as_bytes()).await;
+    }
+
+    #[tokio::test]
+    async fn input_over_the_rate_limit_is_queued_then_dropped() {
+        let mut config = crate::config::DaemonConfig::default();
+        config.bridge.input_limit = InputLimit { bytes_per_sec: 4, frames_per_sec: 0, queue_bytes: 4 };
+        let daemon = ScriptedDaemon::with_manager(SessionManager::with_config(&config)).await;
+        let (mut client, _) = daemon.request(serde_json::json!({"type": "create_session"})).await;
+        let term = daemon.handle(0);
+
+        client.send_frame(FrameType::Data, b"abcdef").await;
+        client.send_frame(FrameType::Data, b"gh").await;
+        client.send_frame(FrameType::Data, b"ijklm").await;
+        let error = client.next_frame(Duration::from_secs(2)).await.unwrap();
+        assert_eq!(error.frame_type, FrameType::Error);
+        let error: serde_json::Value = serde_json::from_slice(&error.payload).unwrap();
+        assert_eq!(error["code"], "RATE_LIMITED");
+        wait_until(|| term.input() == b"abcdef").await;
+        wait_until(|| term.input() == b"abcdefgh").await;
+    }
+
+    #[tokio::test]
+    async fn typed_input_keeps_its_order_around_pastes() {
+        let daemon = ScriptedDaemon::start().await;
+        let (mut client, _) = daemon.request(serde_json::json!({"type": "create_session"})).await;
+        let term = daemon.handle(0);
+
+        let typed: Vec<u8> = (0..200).map(|i| b'a' + (i % 26) as u8).collect();
+        for key in &typed[..100] {
+            client.send_frame(FrameType::Data, &[*key]).await;
+        }
+        client.send_frame(FrameType::Paste, b"PASTE").await;
+        client.send_frame(FrameType::Paste, b"").await;
+        for key in &typed[100..] {
+            client.send_frame(FrameType::Data, &[*key]).await;
+        }
+        let expected = [&typed[..100], b"PASTE", &typed[100..]].concat();
+        wait_until(|| term.input() == expected).await;
+    }
+
+    #[tokio::test]
+    async fn bridge_tasks_report_panics_and_stop_when_dropped() {
+        let panics = Arc::new(AtomicU64::new(0));
+        let cancel = CancellationToken::new();
+        let tracker = TaskTracker::new();
+        let mut tasks = BridgeTasks::new("s1", panics.clone(), cancel.clone(), tracker.clone());
+        fn encode() -> Option<u8> {
+            panic!("encode failed")
+        }
+        let panicked = tasks.spawn("send", async { encode() });
+        assert_eq!(panicked.await.unwrap(), None);
+        assert_eq!(panics.load(Ordering::Relaxed), 1);
+
+        let pending = tasks.spawn("recv", std::future::pending::<()>());
+        drop(tasks);
+        tokio::time::timeout(Duration::from_secs(1), pending).await.unwrap().unwrap();
+        assert!(cancel.is_cancelled());
+        assert_eq!(panics.load(Ordering::Relaxed), 1);
+        // Shutdown has nothing left to wait for
+        tracker.close();
+        tokio::time::timeout(Duration::from_secs(1), tracker.wait()).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn scripted_bridge_skips_extension_frames() {
+        let daemon = ScriptedDaemon::start().await;
+        let (mut client, _) = daemon.request(serde_json::json!({"type": "create_session"})).await;
+        let term = daemon.handle(0);
+
+        client.send_frame(FrameType::Unknown(0xC4), b"{\"chunk\":0}").await;
+        // A core type from a newer protocol revision
+        client.send_frame(FrameType::Unknown(0x0a), b"??").await;
+        client.send_frame(FrameType::Data, b"pwd\n").await;
+        wait_until(|| term.input() == b"pwd\n").await;
+        assert!(daemon.sm.list_sessions()[0].attached);
+    }
+
+    #[tokio::test]
+    async fn scripted_bridge_waits_for_client_window() {
+        let daemon = ScriptedDaemon::start().await;
+        let (mut client, _) = daemon.request(serde_json::json!({"type": "create_session"})).await;
+        let term = daemon.handle(0);
+
+        // Frames are handled in order, so the resize landing means the window has too
+        client.send_frame(FrameType::WindowUpdate, &0u64.to_be_bytes()).await;
+        client.send_frame(FrameType::Resize, &frame::resize_payload(81, 25)).await;
+        wait_until(|| term.size() == (25, 81)).await;
+
+        term.emit(b"held back");
+        let stalled =
+            tokio::time::timeout(Duration::from_millis(300), client.next_frame(Duration::from_secs(5))).await;
+        assert!(stalled.is_err(), "output sent with a zero window");
+
+        client.send_frame(FrameType::WindowUpdate, &65536u64.to_be_bytes()).await;
+        let frame = client.next_frame(Duration::from_secs(5)).await.unwrap();
+        assert_eq!(frame.payload, b"held back");
+    }
+
+    #[tokio::test]
+    async fn clipboard_push_is_refused_unless_enabled() {
+        let daemon = ScriptedDaemon::start().await;
+        let (_, resp) = daemon
+            .request(serde_json::json!({"type": "set_clipboard", "request_id": "c1", "text": "token"}))
+            .await;
+        assert_eq!(resp["type"], "error");
+        assert_eq!(resp["request_id"], "c1");
+        assert!(resp["error"].as_str().unwrap().contains("disabled"));
+        assert_eq!(resp["code"], "PERMISSION_DENIED");
+        assert_eq!(resp["message"], "This device isn't allowed to do that.");
+    }
+
+    #[tokio::test]
+    async fn devices_settle_pairing_requests() {
+        let daemon = ScriptedDaemon::start().await;
+        let mut ticket = daemon.sm.pairing_requests().open("new-phone", None).unwrap();
+        let (_, resp) = daemon
+            .request(serde_json::json!({"type": "approve_pairing", "request_id": "p1", "pairing_id": ticket.id}))
+            .await;
+        assert_eq!(resp["type"], "pairing_settled");
+        assert_eq!((&resp["request_id"], &resp["approved"]), (&"p1".into(), &true.into()));
+        let verdict = ticket.verdict(Duration::from_secs(1)).await;
+        assert_eq!(verdict, Some(Verdict::Approved { by: "test-device".to_string() }));
+
+        // Settled requests can't be settled again
+        let (_, resp) = daemon
+            .request(serde_json::json!({"type": "deny_pairing", "pairing_id": ticket.id}))
+            .await;
+        assert_eq!(resp["code"], "NOT_FOUND");
+    }
+
+    #[tokio::test]
+    async fn sessions_are_created_with_budgets() {
+        let daemon = ScriptedDaemon::start().await;
+        let budget = serde_json::json!({"cpu_secs": 600, "lifetime_secs": 3600});
+        let (_client, resp) = daemon.request(serde_json::json!({"type": "create_session", "budget": budget})).await;
+        assert_eq!(resp["budget"], budget);
+        let session_id = resp["session_id"].as_str().unwrap();
+        let report = daemon.sm.inspect_session(session_id).unwrap().budget.unwrap();
+        assert_eq!(report.budget, Budget { cpu_secs: Some(600), lifetime_secs: Some(3600) });
+
+        let (_, resp) = daemon
+            .request(serde_json::json!({"type": "create_session", "budget": {"cpu_secs": 0}}))
+            .await;
+        assert_eq!(resp["code"], "BAD_REQUEST");
+        assert_eq!(daemon.sm.list_sessions().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn errors_carry_codes() {
+        let daemon = ScriptedDaemon::start().await;
+        let (_, resp) = daemon.request(serde_json::json!({"type": "send_file", "request_id": "f1"})).await;
+        assert_eq!((&resp["code"], &resp["request_id"]), (&"UNSUPPORTED".into(), &"f1".into()));
+        let (_, resp) = daemon.request(serde_json::json!({"type": "attach_session"})).await;
+        assert_eq!(resp["code"], "BAD_REQUEST");
+        let (_, resp) = daemon.request(serde_json::json!({"type": "attach_session", "session_id": "missing"})).await;
+        assert_eq!(resp["code"], "NOT_FOUND");
+        let (_, resp) = daemon.request(serde_json::json!({"type": "destroy_session", "session_id": "missing"})).await;
+        assert_eq!((&resp["success"], &resp["code"]), (&false.into(), &"NOT_FOUND".into()));
+
+        let (_phone, resp) = daemon.request(serde_json::json!({"type": "create_session"})).await;
+        let attached = resp["session_id"].as_str().unwrap().to_string();
+        let (_, resp) = daemon.request(serde_json::json!({"type": "attach_session", "session_id": attached})).await;
+        assert_eq!(resp["code"], "ALREADY_ATTACHED");
+
+        let damaged = daemon.sm.create_session(24, 80, None, Launch::default()).unwrap();
+        daemon.sm.get_session(&damaged).unwrap().lock().unwrap().damaged = true;
+        let (_, resp) = daemon.request(serde_json::json!({"type": "attach_session", "session_id": damaged})).await;
+        assert_eq!(resp["code"], "DAMAGED_SESSION");
+    }
+
+    #[tokio::test]
+    async fn described_requests_are_handled() {
+        let daemon = ScriptedDaemon::start().await;
+        let requests = crate::protocol::MESSAGES
+            .iter()
+            .filter(|m| m.sender == crate::protocol::Sender::Client && !m.name.starts_with("auth_"));
+        for message in requests {
+            // A request ID of its own each, or a resent create would be replayed
+            let request_id = format!("p-{}", message.name);
+            let (_, resp) = daemon.request(serde_json::json!({"type": message.name, "request_id": request_id})).await;
+            let reply = resp["type"].as_str().unwrap();
+            assert!(reply == "error" || message.replies.contains(&reply), "{}: {resp}", message.name);
+            assert_ne!(resp["code"], "UNSUPPORTED", "{}", message.name);
+        }
+    }
+
+    #[tokio::test]
+    async fn guest_is_read_only_and_expires() {
+        let daemon = ScriptedDaemon::start().await;
+        let session_id = daemon.sm.create_session(24, 80, None, Launch::default()).unwrap();
+        let term = daemon.handle(0);
+        daemon.serve_as_guest(Guest {
+            id: "guest-viewer".to_string(),
+            session_id: session_id.clone(),
+            expires_at: chrono::Utc::now() + chrono::Duration::seconds(2),
+        });
+
+        let (_, resp) = daemon.request(serde_json::json!({"type": "list_sessions"})).await;
+        assert_eq!(resp["type"], "error");
+        let (_, resp) = daemon
+            .request(serde_json::json!({"type": "attach_session", "session_id": "other"}))
+            .await;
+        assert_eq!(resp["type"], "error");
+
+        let (mut client, resp) = daemon
+            .request(serde_json::json!({"type": "attach_session", "session_id": session_id}))
+            .await;
+        assert_eq!(resp["type"], "session_attached");
+        term.emit(b"watch this");
+        let frame = client.next_frame(Duration::from_secs(5)).await.unwrap();
+        assert_eq!(frame.payload, b"watch this");
+
+        client.send_frame(FrameType::Data, b"rm -rf ~\n").await;
+        client.send_frame(FrameType::Resize, &frame::resize_payload(10, 5)).await;
+
+        // Access ends at expiry: the daemon drops the stream
+        assert!(client.next_frame(Duration::from_secs(5)).await.is_none());
+        daemon.wait_detached().await;
+        assert!(term.input().is_empty());
+        assert_eq!(term.size(), (24, 80));
+    }
+
+    #[tokio::test]
+    async fn server_info_reports_current_addresses() {
+        let daemon = ScriptedDaemon::start().await;
+        let (_, resp) = daemon
+            .request(serde_json::json!({"type": "server_info", "request_id": "r1"}))
+            .await;
+        assert_eq!(resp["type"], "server_info");
+        assert_eq!(resp["request_id"], "r1");
+        assert_eq!(resp["version"], crate::VERSION);
+        assert_eq!(resp["protocol_version"], crate::PROTOCOL_VERSION);
+        assert_eq!(resp["features"]["exec"], true);
+        assert_eq!(resp["features"]["file_transfer"], false);
+        assert_eq!(resp["limits"]["max_payload"], frame::MAX_PAYLOAD);
+        assert!(resp["limits"]["max_sessions"].is_null());
+        assert_eq!(resp["limits"]["input"]["bytes_per_sec"], 1024 * 1024);
+        assert!(resp["hosts"].is_array());
+        assert!(resp["successor"].is_null());
+        assert_eq!(resp["close_codes"][2]["name"], "replaced");
+    }
+
+    #[tokio::test]
+    async fn server_info_reflects_device_restrictions() {
+        let daemon = ScriptedDaemon::start().await;
+        daemon.serve_with(DevicePolicy { restrictions: Some(Restrictions::default()), ..Default::default() });
+        let (_, resp) = daemon.request(serde_json::json!({"type": "server_info"})).await;
+        let features = &resp["features"];
+        assert_eq!(features["multi_attach"], true);
+        for denied in ["exec", "tmux", "agent_forwarding", "clipboard"] {
+            assert_eq!(features[denied], false, "{denied}");
+        }
+    }
+
+    #[tokio::test]
+    async fn devices_query_the_effective_daemon_config() {
+        let mut config = crate::config::DaemonConfig::default();
+        config.session.scrollback_bytes = 1 << 20;
+        config.bridge.stream_idle_secs = 0;
+        let daemon = ScriptedDaemon::with_manager(SessionManager::with_config(&config)).await;
+        let query = serde_json::json!({"type": "get_daemon_config", "request_id": "d1"});
+        let (_, resp) = daemon.request(query.clone()).await;
+        assert_eq!(resp["type"], "daemon_config");
+        assert_eq!(resp["request_id"], "d1");
+        assert_eq!(resp["session"]["scrollback_bytes"], 1 << 20);
+        assert_eq!(resp["session"]["damaged"], "destroy");
+        assert!(resp["bridge"]["stream_idle_secs"].is_null());
+        assert_eq!(resp["bridge"]["liveness_timeout_secs"], 25);
+        assert_eq!(resp["bridge"]["input"]["bytes_per_sec"], 1024 * 1024);
+        assert_eq!(resp["features"]["exec"], true);
+        assert_eq!(resp["features"]["file_transfer"], false);
+
+        daemon.serve_with(DevicePolicy { restrictions: Some(Restrictions::default()), ..Default::default() });
+        let (_, resp) = daemon.request(query).await;
+        assert_eq!(resp["code"], "PERMISSION_DENIED");
+    }
+
+    #[tokio::test]
+    async fn multi_user_devices_only_see_their_users_sessions() {
+        let daemon = ScriptedDaemon::multi_user().await;
+        let bobs = daemon.sm.create_session(24, 80, Some("bob-laptop"), Launch { user: Some("bob"), ..Default::default() }).unwrap();
+
+        // Devices paired before multi-user mode have no user: refused outright
+        let (_, resp) = daemon.request(serde_json::json!({"type": "list_sessions"})).await;
+        assert_eq!(resp["type"], "error");
+        assert!(resp["error"].as_str().unwrap().contains("--user"));
+
+        daemon.serve_with(DevicePolicy { user: Some("alice".to_string()), ..Default::default() });
+        let (_alice, resp) = daemon.request(serde_json::json!({"type": "create_session"})).await;
+        assert_eq!(resp["type"], "session_created");
+        let alices = resp["session_id"].as_str().unwrap().to_string();
+        let owner = |id: &str| daemon.sm.get_session(id).unwrap().lock().unwrap().user.clone();
+        assert_eq!(owner(&alices).as_deref(), Some("alice"));
+
+        let (_, resp) = daemon.request(serde_json::json!({"type": "list_sessions"})).await;
+        let listed: Vec<_> = resp["sessions"].as_array().unwrap().iter().map(|s| s["id"].clone()).collect();
+        assert_eq!(listed, [serde_json::json!(alices)]);
+
+        for req in [
+            serde_json::json!({"type": "attach_session", "session_id": bobs}),
+            serde_json::json!({"type": "mirror_session", "session_id": bobs}),
+            serde_json::json!({"type": "create_session", "tmux_session": "work"}),
+            serde_json::json!({"type": "list_tmux_sessions"}),
+        ] {
+            let (_, resp) = daemon.request(req.clone()).await;
+            assert_eq!(resp["type"], "error", "{req}");
+        }
+        let (_, resp) = daemon
+            .request(serde_json::json!({"type": "destroy_session", "session_id": bobs}))
+            .await;
+        assert_eq!(resp["success"], false);
+        assert!(daemon.sm.get_session(&bobs).is_some());
+    }
+
+    #[tokio::test]
+    async fn restricted_device_runs_only_allowlisted_commands() {
+        let daemon = ScriptedDaemon::start().await;
+        let others = daemon.sm.create_session(24, 80, Some("laptop"), Launch::default()).unwrap();
+        daemon.serve_with(DevicePolicy {
+            restrictions: Some(Restrictions {
+                allowed_commands: vec!["htop".to_string(), "tail -f build.log".to_string()],
+                ..Default::default()
+            }),
+            ..Default::default()
+        });
+
+        let (_, resp) = daemon.request(serde_json::json!({"type": "create_session", "command": "sh"})).await;
+        assert_eq!(resp["type"], "error");
+        let (_top, resp) = daemon.request(serde_json::json!({"type": "create_session"})).await;
+        assert_eq!(resp["type"], "session_created");
+        let top = resp["session_id"].as_str().unwrap().to_string();
+        assert_eq!(daemon.sm.get_session(&top).unwrap().lock().unwrap().shell, "htop");
+
+        for req in [
+            serde_json::json!({"type": "exec", "command": "id"}),
+            serde_json::json!({"type": "set_clipboard", "text": "x"}),
+            serde_json::json!({"type": "list_tmux_sessions"}),
+            serde_json::json!({"type": "approve_pairing", "pairing_id": "p"}),
+            serde_json::json!({"type": "deny_pairing", "pairing_id": "p"}),
+            serde_json::json!({"type": "create_session", "agent_forwarding": true}),
+            serde_json::json!({"type": "attach_session", "session_id": others}),
+            serde_json::json!({"type": "mirror_session", "session_id": others}),
+        ] {
+            let (_, resp) = daemon.request(req.clone()).await;
+            assert_eq!(resp["type"], "error", "{req}");
+        }
+
+        // Only the device's own sessions are visible
+        let (_, resp) = daemon.request(serde_json::json!({"type": "list_sessions"})).await;
+        let listed: Vec<_> = resp["sessions"].as_array().unwrap().iter().map(|s| s["id"].clone()).collect();
+        assert_eq!(listed, [serde_json::json!(top)]);
+    }
+
+    #[tokio::test]
+    async fn resent_requests_are_not_run_twice() {
+        let daemon = ScriptedDaemon::start().await;
+        let create = serde_json::json!({"type": "create_session", "request_id": "c1"});
+        let (mut lost, first) = daemon.request(create.clone()).await;
+        assert_eq!(first["type"], "session_created");
+        let session_id = first["session_id"].as_str().unwrap().to_string();
+        daemon.handle(0).emit(b"prompt$ ");
+        lost.next_frame(Duration::from_secs(5)).await.unwrap();
+
+        // The client gave up on the first stream: the resend gets the same
+        // session, taken over from the abandoned bridge
+        let (mut phone, resent) = daemon.request(create).await;
+        assert_eq!(resent, first);
+        assert_eq!(daemon.sm.list_sessions().len(), 1);
+        let replay = phone.next_frame(Duration::from_secs(5)).await.unwrap();
+        assert_eq!((replay.frame_type, &replay.payload[..]), (FrameType::Scrollback, &b"prompt$ "[..]));
+        assert!(lost.next_frame(Duration::from_secs(5)).await.is_none());
+
+        // A new request isn't a resend, so it can't take the session over
+        let (_, resp) = daemon
+            .request(serde_json::json!({"type": "attach_session", "request_id": "a1", "session_id": session_id}))
+            .await;
+        assert_eq!(resp["code"], "ALREADY_ATTACHED");
+
+        let destroy = serde_json::json!({"type": "destroy_session", "request_id": "d1", "session_id": session_id});
+        let (_, resp) = daemon.request(destroy.clone()).await;
+        assert_eq!(resp["success"], true);
+        let (_, resp) = daemon.request(destroy).await;
+        assert_eq!(resp["success"], true);
+    }
+
+    #[tokio::test]
+    async fn takeover_hands_the_session_to_a_relaunched_app() {
+        let daemon = ScriptedDaemon::start().await;
+        let (mut stale, resp) = daemon.request(serde_json::json!({"type": "create_session"})).await;
+        let session_id = resp["session_id"].as_str().unwrap().to_string();
+        daemon.handle(0).emit(b"prompt$ ");
+        stale.next_frame(Duration::from_secs(5)).await.unwrap();
+
+        let attach = serde_json::json!({"type": "attach_session", "session_id": session_id, "takeover": true});
+        let (mut relaunched, resp) = daemon.request(attach.clone()).await;
+        assert_eq!(resp["type"], "session_attached");
+        let replay = relaunched.next_frame(Duration::from_secs(5)).await.unwrap();
+        assert_eq!((replay.frame_type, &replay.payload[..]), (FrameType::Scrollback, &b"prompt$ "[..]));
+        assert!(relaunched.next_frame(Duration::from_secs(5)).await.unwrap().is_replay_end());
+        assert!(stale.next_frame(Duration::from_secs(5)).await.is_none());
+        daemon.handle(0).emit(b"live");
+        let live = relaunched.next_frame(Duration::from_secs(5)).await.unwrap();
+        assert_eq!(&live.payload[..], b"live");
+
+        // Another device's bridge can't be taken over
+        let session = daemon.sm.get_session(&session_id).unwrap();
+        session.lock().unwrap().last_attached_by = Some("other-device".to_string());
+        let (_, resp) = daemon.request(attach).await;
+        assert_eq!(resp["code"], "ALREADY_ATTACHED");
+    }
+
+    #[tokio::test]
+    async fn session_history_records_who_did_what() {
+        let daemon = ScriptedDaemon::start().await;
+        let (mut client, resp) = daemon.request(serde_json::json!({"type": "create_session"})).await;
+        let session_id = resp["session_id"].as_str().unwrap().to_string();
+        let term = daemon.handle(0);
+        client.send_frame(FrameType::Resize, &frame::resize_payload(100, 30)).await;
+        client.send_frame(FrameType::Resize, &frame::resize_payload(120, 40)).await;
+        wait_until(|| term.size() == (40, 120)).await;
+        client.send_frame(FrameType::Close, &[]).await;
+        daemon.wait_detached().await;
+
+        let destroy = serde_json::json!({"type": "destroy_session", "session_id": session_id});
+        let (_, resp) = daemon.request(destroy).await;
+        assert_eq!(resp["success"], true);
+
+        // Still answerable once the session is gone
+        let (_, resp) = daemon
+            .request(serde_json::json!({"type": "session_history", "request_id": "h1", "session_id": session_id}))
+            .await;
+        assert_eq!(resp["type"], "session_history");
+        assert_eq!(resp["ended"], true);
+        let events: Vec<(String, serde_json::Value)> = resp["events"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|e| (e["event"].as_str().unwrap().to_string(), e["by"].clone()))
+            .collect();
+        let by = serde_json::Value::from("test-device");
+        assert_eq!(events, [
+            ("created".to_string(), by.clone()),
+            ("attached".to_string(), by.clone()),
+            ("resized".to_string(), by.clone()),
+            ("detached".to_string(), by.clone()),
+            ("destroyed".to_string(), by),
+        ]);
+        assert_eq!(resp["events"][2]["cols"], 120);
+
+        let (_, resp) = daemon
+            .request(serde_json::json!({"type": "session_history", "session_id": "missing"}))
+            .await;
+        assert_eq!(resp["code"], "NOT_FOUND");
+    }
+
+    #[tokio::test]
+    async fn list_sessions_groups_and_filters_by_project() {
+        let daemon = ScriptedDaemon::start().await;
+        let dir = tempfile::tempdir().unwrap();
+        for repo in ["alpha", "beta"] {
+            std::fs::create_dir_all(dir.path().join(repo).join(".git")).unwrap();
+            std::fs::create_dir_all(dir.path().join(repo).join("src")).unwrap();
+        }
+        let mut ids = Vec::new();
+        for (i, cwd) in ["alpha/src", "beta", "alpha"].iter().enumerate() {
+            ids.push(daemon.sm.create_session(24, 80, Some("test-device"), Launch::default()).unwrap());
+            daemon.handle(i).cd(dir.path().join(cwd));
+        }
+        ids.push(daemon.sm.create_session(24, 80, Some("test-device"), Launch::default()).unwrap());
+
+        let (_, resp) = daemon.request(serde_json::json!({"type": "list_sessions"})).await;
+        assert_eq!(resp["sessions"].as_array().unwrap().len(), 4);
+        let projects = resp["projects"].as_array().unwrap();
+        assert_eq!(projects.len(), 2);
+        assert_eq!(projects[0]["name"], "alpha");
+        assert_eq!(projects[0]["root"], dir.path().join("alpha").to_str().unwrap());
+        let mut alpha: Vec<&str> = projects[0]["sessions"].as_array().unwrap().iter().map(|s| s.as_str().unwrap()).collect();
+        alpha.sort();
+        let mut expected = vec![ids[0].as_str(), ids[2].as_str()];
+        expected.sort();
+        assert_eq!(alpha, expected);
+
+        let (_, resp) = daemon.request(serde_json::json!({"type": "list_sessions", "project": "beta"})).await;
+        let sessions = resp["sessions"].as_array().unwrap();
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(sessions[0]["id"], ids[1].as_str());
+        assert_eq!(sessions[0]["project"]["name"], "beta");
+    }
+
+    #[tokio::test]
+    async fn resize_session_presizes_detached_sessions_only() {
+        let daemon = ScriptedDaemon::start().await;
+        let (mut client, resp) = daemon.request(serde_json::json!({"type": "create_session"})).await;
+        let session_id = resp["session_id"].as_str().unwrap().to_string();
+        let term = daemon.handle(0);
+
+        let resize = serde_json::json!({"type": "resize_session", "request_id": "r1", "session_id": session_id, "rows": 50, "cols": 132});
+        let (_, resp) = daemon.request(resize.clone()).await;
+        assert_eq!(resp["code"], "ALREADY_ATTACHED");
+
+        client.send_frame(FrameType::Close, &[]).await;
+        daemon.wait_detached().await;
+        let (_, resp) = daemon.request(resize).await;
+        assert_eq!(resp["type"], "session_resized");
+        assert_eq!(resp["request_id"], "r1");
+        assert_eq!(term.size(), (50, 132));
+
+        let (_, resp) = daemon
+            .request(serde_json::json!({"type": "resize_session", "session_id": session_id}))
+            .await;
+        assert_eq!(resp["code"], "BAD_REQUEST");
+    }
+
+    #[tokio::test]
+    async fn detach_request_keeps_the_stream_for_control_requests() {
+        let daemon = ScriptedDaemon::start().await;
+        let (mut client, resp) = daemon.request(serde_json::json!({"type": "create_session"})).await;
+        let session_id = resp["session_id"].as_str().unwrap().to_string();
+        let term = daemon.handle(0);
+        client.send_frame(FrameType::Resize, &frame::resize_payload(100, 30)).await;
+        wait_until(|| term.size() == (30, 100)).await;
+        term.emit(b"last words");
+
+        client.send_frame(FrameType::Close, CLOSE_REASON_DETACH_REQUEST.as_bytes()).await;
+        let data = client.next_frame(Duration::from_secs(5)).await.unwrap();
+        assert_eq!(&data.payload[..], b"last words");
+        let close = client.next_frame(Duration::from_secs(5)).await.unwrap();
+        assert_eq!(close.parse_close_reason(), Some(CLOSE_REASON_DETACHED));
+
+        let ack = client.next_message().await;
+        assert_eq!(ack["type"], "session_detached");
+        assert_eq!(ack["session_id"], session_id.as_str());
+        assert_eq!(ack["last_sequence_sent"], close.sequence);
+        assert_eq!(ack["last_sequence_received"], 2);
+        assert_eq!((&ack["rows"], &ack["cols"]), (&30.into(), &100.into()));
+        daemon.wait_detached().await;
+        assert!(!term.terminated());
+
+        // The same stream takes control requests, and can attach again
+        write_json(&mut client.send, &serde_json::json!({"type": "list_sessions"})).await.unwrap();
+        let list = client.next_message().await;
+        assert_eq!(list["sessions"][0]["attached"], false);
+        let attach = serde_json::json!({"type": "attach_session", "session_id": session_id});
+        write_json(&mut client.send, &attach).await.unwrap();
+        assert_eq!(client.next_message().await["type"], "session_attached");
+        let replay = client.next_frame(Duration::from_secs(5)).await.unwrap();
+        assert_eq!((replay.frame_type, &replay.payload[..]), (FrameType::Scrollback, &b"last words"[..]));
+    }
+
+    #[tokio::test]
+    async fn inspect_session_reports_bridge_and_reader_state() {
+        let daemon = ScriptedDaemon::start().await;
+        let (mut client, resp) = daemon.request(serde_json::json!({"type": "create_session"})).await;
+        let session_id = resp["session_id"].as_str().unwrap().to_string();
+        daemon.handle(0).emit(b"hello");
+        client.next_frame(Duration::from_secs(2)).await.unwrap();
+
+        let inspect = serde_json::json!({"type": "inspect_session", "request_id": "i1", "session_id": session_id});
+        let (_, resp) = daemon.request(inspect.clone()).await;
+        assert_eq!(resp["type"], "session_inspected");
+        let session = &resp["session"];
+        assert_eq!(session["attached"], true);
+        assert_eq!(session["reader_available"], false);
+        assert_eq!(session["bridge"]["sender_running"], true);
+        assert_eq!(session["bridge"]["pty_writer_running"], true);
+        assert_eq!(session["bridge"]["frames_sent"], 1);
+        assert_eq!(session["bridge"]["output_queue_capacity"], OUTPUT_QUEUE_CHUNKS);
+        assert_eq!(session["scrollback"]["bytes"], 5);
+
+        client.send_frame(FrameType::Close, &[]).await;
+        daemon.wait_detached().await;
+        let (_, resp) = daemon.request(inspect).await;
+        let session = &resp["session"];
+        assert_eq!(session["reader_available"], true);
+        assert!(session["bridge"].is_null());
+        assert!(session["damaged_cause"].is_null());
+    }
+
+    #[tokio::test]
+    async fn compressed_output_bypasses_compression() {
+        let daemon = ScriptedDaemon::start().await;
+        let (mut client, resp) = daemon.request(serde_json::json!({"type": "create_session"})).await;
+        let session_id = resp["session_id"].as_str().unwrap().to_string();
+        let term = daemon.handle(0);
+
+        // A PNG catted to the terminal, then a directory listing
+        let mut state = 0x9e37_79b9_7f4a_7c15u64;
+        let png: Vec<u8> = (0..4096)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect();
+        term.emit(&png);
+        assert_eq!(client.next_frame(Duration::from_secs(2)).await.unwrap().payload, png);
+        let listing = b"-rw-r--r--  1 user staff  2048 Oct 16 09:12 notes.txt\r\n".repeat(20);
+        term.emit(&listing);
+        assert_eq!(client.next_frame(Duration::from_secs(2)).await.unwrap().payload, listing);
+
+        let compression = daemon.sm.inspect_session(&session_id).unwrap().compression;
+        assert_eq!((compression.compressed, compression.bypassed, compression.incompressible), (1, 1, 0));
+        assert_eq!(compression.bypass_ratio, 0.5);
+        assert!(compression.compression_ratio.unwrap() < 0.5);
+    }
+
+    #[tokio::test]
+    async fn output_logging_tees_session_output_into_a_file() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let daemon = ScriptedDaemon::with_manager(SessionManager::new().with_output_log_dir(dir.path().to_path_buf())).await;
+        let create = serde_json::json!({"type": "create_session", "log_output": true});
+        let (mut client, resp) = daemon.request(create).await;
+        let session_id = resp["session_id"].as_str().unwrap().to_string();
+        let path = dir.path().join(format!("{session_id}.log"));
+        assert_eq!(resp["output_log"], path.to_str().unwrap());
+        daemon.handle(0).emit(b"logged");
+        client.next_frame(Duration::from_secs(2)).await.unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "logged");
+
+        let off = serde_json::json!({"type": "set_output_log", "session_id": session_id, "enabled": false});
+        let (_, resp) = daemon.request(off).await;
+        assert_eq!(resp["type"], "output_log_set");
+        assert!(resp["path"].is_null());
+        daemon.handle(0).emit(b"not logged");
+        client.next_frame(Duration::from_secs(2)).await.unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "logged");
+    }
+
+    #[tokio::test]
+    async fn connection_stats_count_the_current_attachment() {
+        let daemon = ScriptedDaemon::start().await;
+        let (mut client, resp) = daemon.request(serde_json::json!({"type": "create_session"})).await;
+        let session_id = resp["session_id"].as_str().unwrap().to_string();
+        let term = daemon.handle(0);
+        term.emit(b"hello");
+        client.next_frame(Duration::from_secs(2)).await.unwrap();
+        // Counted as decoded, so both are in once the input is written
+        client.send_frame(FrameType::Heartbeat, &[]).await;
+        client.send_frame(FrameType::Data, b"ls\n").await;
+        wait_until(|| term.input() == b"ls\n").await;
+
+        let stats = serde_json::json!({"type": "connection_stats", "request_id": "c1", "session_id": session_id});
+        let (_, resp) = daemon.request(stats.clone()).await;
+        assert_eq!(resp["type"], "connection_stats");
+        assert_eq!(resp["request_id"], "c1");
+        let counters = &resp["stats"];
+        assert_eq!(counters["frames_sent"], 1);
+        assert_eq!(counters["frames_received"], 2);
+        assert_eq!(counters["resyncs"], 0);
+        assert_eq!(counters["heartbeat_rtt"]["samples"], 0);
+        assert!(counters["heartbeat_rtt"]["last_ms"].is_null());
+
+        client.send_frame(FrameType::Close, &[]).await;
+        daemon.wait_detached().await;
+        let (_, resp) = daemon.request(stats).await;
+        assert_eq!(resp["code"], "NOT_ATTACHED");
+    }
+
+    #[tokio::test]
+    async fn silent_clients_are_detached() {
+        let mut config = crate::config::DaemonConfig::default();
+        config.bridge.liveness_timeout_secs = 1;
+        let daemon = ScriptedDaemon::with_manager(SessionManager::with_config(&config)).await;
+        let (mut client, _) = daemon.request(serde_json::json!({"type": "create_session"})).await;
+
+        // Answer a ping, proving this client echoes them, then vanish
+        let ping = client.next_frame(Duration::from_secs(2)).await.unwrap();
+        assert_eq!(ping.frame_type, FrameType::Heartbeat);
+        client.send_frame(FrameType::Heartbeat, &ping.payload).await;
+        tokio::time::sleep(Duration::from_millis(800)).await;
+        daemon.wait_detached().await;
+
+        let sessions = daemon.sm.list_sessions();
+        assert!(sessions[0].alive, "detached, not destroyed");
+    }
+
+    #[tokio::test]
+    async fn hung_pty_reads_are_replaced_without_damaging_the_session() {
+        let mut config = crate::config::DaemonConfig::default();
+        config.bridge.read_stall_secs = 1;
+        let daemon = ScriptedDaemon::with_manager(SessionManager::with_config(&config)).await;
+        let (mut client, resp) = daemon.request(serde_json::json!({"type": "create_session"})).await;
+        let session_id = resp["session_id"].as_str().unwrap().to_string();
+        let term = daemon.handle(0);
+        async fn next_output(client: &mut ClientStream) -> Vec<u8> {
+            loop {
+                let frame = client.next_frame(Duration::from_secs(5)).await.unwrap();
+                if frame.frame_type == FrameType::Data {
+                    return frame.payload;
+                }
+            }
+        }
+
+        // Silence after input is no stall: the same reader reads on
+        client.send_frame(FrameType::Data, b"sleep 2\n").await;
+        tokio::time::sleep(Duration::from_millis(1600)).await;
+        term.emit(b"late");
+        assert_eq!(next_output(&mut client).await, b"late");
+
+        // Output waiting that the reader doesn't take: a new reader takes it
+        term.jam_reader();
+        term.emit(b"stuck");
+        assert_eq!(next_output(&mut client).await, b"stuck");
+
+        // The replacement hangs too: warned about, and the session left be
+        term.jam_reader();
+        term.emit(b"again");
+        tokio::time::sleep(Duration::from_millis(2500)).await;
+        let session = daemon.sm.inspect_session(&session_id).unwrap();
+        assert_eq!(session.damaged_cause, None);
+        assert!(daemon.sm.list_sessions()[0].alive);
+    }
+
+    #[test]
+    fn stuck_readers_are_retired_before_cloning() {
+        use crate::terminal::TerminalBackend;
+
+        let watch = ReadWatch::new(tokio::time::Instant::now());
+        let (term, _handle) = ScriptedTerminal::new(24, 80);
+        let (_reader, generation) = reclone_reader(&watch, || {
+            // Cloning ends the scripted terminal's old reads: by then the
+            // stuck reader's EOF must not count as the current reader's
+            assert!(!watch.is_current(0));
+            term.try_clone_reader()
+        })
+        .unwrap();
+        assert!(watch.is_current(generation));
+    }
+
+    #[test]
+    fn echoed_pings_are_round_trip_samples() {
+        let probe = BridgeProbe::new(Arc::default());
+        let first = probe.ping();
+        let second = probe.ping();
+        // Only the outstanding ping counts
+        probe.pong(&first.to_be_bytes());
+        probe.pong(b"junk");
+        assert_eq!(probe.stats(0).heartbeat_rtt.samples, 0);
+        probe.pong(&second.to_be_bytes());
+        probe.pong(&second.to_be_bytes());
+        let stats = probe.stats(3);
+        assert_eq!((stats.heartbeats_sent, stats.resyncs), (2, 3));
+        let rtt = stats.heartbeat_rtt;
+        assert_eq!(rtt.samples, 1);
+        assert_eq!(rtt.last_ms, rtt.smoothed_ms);
+        assert_eq!(rtt.min_ms, rtt.max_ms);
+    }
+
+    #[tokio::test]
+    async fn macros_replay_recorded_input_into_a_session() {
+        let daemon = ScriptedDaemon::start().await;
+        let (_client, resp) = daemon.request(serde_json::json!({"type": "create_session"})).await;
+        let session_id = resp["session_id"].as_str().unwrap().to_string();
+        let term = daemon.handle(0);
+
+        let record = serde_json::json!({"type": "record_macro", "request_id": "m1", "name": "build", "input": "make -j8\r"});
+        let (_, resp) = daemon.request(record).await;
+        assert_eq!(resp["type"], "macro_recorded");
+        assert_eq!(resp["macro"]["name"], "build");
+        let (_, resp) = daemon.request(serde_json::json!({"type": "list_macros"})).await;
+        assert_eq!(resp["macros"][0]["input"], "make -j8\r");
+
+        // Works while another stream is attached
+        let run = serde_json::json!({"type": "run_macro", "request_id": "r1", "name": "build", "session_id": session_id});
+        let (_, resp) = daemon.request(run.clone()).await;
+        assert_eq!(resp["type"], "macro_run");
+        assert_eq!(resp["bytes"], 9);
+        assert_eq!(term.input(), b"make -j8\r");
+
+        let (_, resp) = daemon.request(serde_json::json!({"type": "delete_macro", "name": "build"})).await;
+        assert_eq!(resp["type"], "macro_deleted");
+        let (_, resp) = daemon.request(run).await;
+        assert_eq!(resp["code"], "NOT_FOUND");
+    }
+
+    #[tokio::test]
+    async fn unnamed_sessions_are_named_after_their_first_command() {
+        let daemon = ScriptedDaemon::start().await;
+        let (mut client, resp) = daemon.request(serde_json::json!({"type": "create_session"})).await;
+        let session_id = resp["session_id"].as_str().unwrap().to_string();
+        let term = daemon.handle(0);
+
+        client.send_frame(FrameType::Data, b"ls\r").await;
+        client.send_frame(FrameType::Data, b"cargo build --release\r").await;
+        let event = daemon.next_event().await;
+        assert_eq!(event["type"], "session_renamed");
+        assert_eq!(event["session_id"], session_id.as_str());
+        assert_eq!(event["name"], "cargo build");
+
+        // Named once: later commands don't rename it
+        client.send_frame(FrameType::Data, b"vim notes.md\r").await;
+        wait_until(|| term.input().ends_with(b"vim notes.md\r")).await;
+        let sessions = daemon.sm.list_sessions();
+        assert_eq!(sessions[0].name.as_deref(), Some("cargo build"));
+
+        // A given name is kept
+        let create = serde_json::json!({"type": "create_session", "name": " deploy "});
+        let (mut named, resp) = daemon.request(create).await;
+        assert_eq!(resp["name"], "deploy");
+        let named_id = resp["session_id"].as_str().unwrap().to_string();
+        named.send_frame(FrameType::Data, b"make\r").await;
+        wait_until(|| daemon.handle(1).input() == b"make\r").await;
+        let info = daemon.sm.list_sessions().into_iter().find(|s| s.id == named_id).unwrap();
+        assert_eq!(info.name.as_deref(), Some("deploy"));
+
+        let (_, resp) = daemon.request(serde_json::json!({"type": "create_session", "name": ""})).await;
+        assert_eq!(resp["code"], "BAD_REQUEST");
+    }
+
+    #[tokio::test]
+    async fn reaped_sessions_are_announced() {
+        let daemon = ScriptedDaemon::start().await;
+        let (_client, resp) = daemon.request(serde_json::json!({"type": "create_session"})).await;
+        let session_id = resp["session_id"].as_str().unwrap().to_string();
+
+        daemon.handle(0).exit(0);
+        daemon.sm.reap();
+        let event = daemon.next_event().await;
+        assert_eq!(event["type"], "session_removed");
+        assert_eq!(event["session_id"], session_id.as_str());
+        assert_eq!(event["reason"], "exited");
+        assert_eq!(event["exit_code"], 0);
+    }
+
+    #[tokio::test]
+    async fn kill_foreground_signals_the_command_not_the_session() {
+        let daemon = ScriptedDaemon::start().await;
+        let (_client, resp) = daemon.request(serde_json::json!({"type": "create_session"})).await;
+        let session_id = resp["session_id"].as_str().unwrap().to_string();
+        let term = daemon.handle(0);
+
+        let kill = serde_json::json!({"type": "kill_foreground", "request_id": "k1", "session_id": session_id});
+        let (_, resp) = daemon.request(kill.clone()).await;
+        assert_eq!(resp["code"], "NOT_FOUND");
+
+        term.run_foreground(4242);
+        let (_, resp) = daemon.request(kill).await;
+        assert_eq!(resp["type"], "foreground_killed");
+        assert_eq!(resp["process_group"], 4242);
+        assert_eq!(term.signals(), [libc::SIGTERM]);
+        assert!(!term.terminated());
+        assert!(daemon.sm.get_session(&session_id).is_some());
+
+        let (_, resp) = daemon
+            .request(serde_json::json!({"type": "kill_foreground", "session_id": session_id, "signal": "STOP"}))
+            .await;
+        assert_eq!(resp["code"], "BAD_REQUEST");
+    }
+
+    #[tokio::test]
+    async fn scripted_reattach_replays_scrollback_then_detached_output() {
+        let daemon = ScriptedDaemon::start().await;
+        let (mut client, resp) = daemon.request(serde_json::json!({"type": "create_session"})).await;
+        let session_id = resp["session_id"].as_str().unwrap().to_string();
+        let term = daemon.handle(0);
+
+        term.emit(b"before detach\r\n");
+        let frame = client.next_frame(Duration::from_secs(5)).await.unwrap();
+        assert_eq!(frame.payload, b"before detach\r\n");
+
+        // Close handshake: the daemon answers Close, then finishes the stream
+        client.send_frame(FrameType::Close, &[]).await;
+        let close = client.next_frame(Duration::from_secs(5)).await.unwrap();
+        assert_eq!(close.frame_type, FrameType::Close);
+        assert_eq!(close.parse_close_reason(), Some(CLOSE_REASON_CLIENT));
+        assert!(client.next_frame(Duration::from_secs(5)).await.is_none());
+        daemon.wait_detached().await;
+
+        // Output while detached waits in the terminal for the next reader
+        term.emit(b"while detached");
+
+        let (mut client, resp) = daemon
+            .request(serde_json::json!({"type": "attach_session", "session_id": session_id}))
+            .await;
+        assert_eq!(resp["type"], "session_attached");
+        let replay = client.next_frame(Duration::from_secs(5)).await.unwrap();
+        assert_eq!(replay.frame_type, FrameType::Scrollback);
+        assert_eq!(replay.payload, b"before detach\r\n");
+        assert!(client.next_frame(Duration::from_secs(5)).await.unwrap().is_replay_end());
+        let live = client.next_frame(Duration::from_secs(5)).await.unwrap();
+        assert_eq!((live.frame_type, live.sequence), (FrameType::Data, 1));
+        assert_eq!(live.payload, b"while detached");
+    }
+
+    #[tokio::test]
+    async fn input_resent_after_a_reconnect_is_skipped() {
+        let daemon = ScriptedDaemon::start().await;
+        let create = serde_json::json!({"type": "create_session", "dedup_input": true});
+        let (mut client, resp) = daemon.request(create).await;
+        let session_id = resp["session_id"].as_str().unwrap().to_string();
+        let term = daemon.handle(0);
+        client.send_frame(FrameType::Data, b"rm ").await;
+        client.send_frame(FrameType::Data, b"old\r").await;
+        wait_until(|| term.input() == b"rm old\r").await;
+
+        // The connection drops before the client hears its input was written
+        drop(client);
+        daemon.wait_detached().await;
+
+        let attach = serde_json::json!({"type": "attach_session", "session_id": session_id, "dedup_input": true});
+        let (mut client, resp) = daemon.request(attach).await;
+        assert_eq!(resp["last_input_sequence"], 2);
+        // Resent from the client's buffer, then new input
+        client.send_frame(FrameType::Data, b"rm ").await;
+        client.send_frame(FrameType::Data, b"old\r").await;
+        client.send_frame(FrameType::Data, b"ls\r").await;
+        wait_until(|| term.input() == b"rm old\rls\r").await;
+    }
+
+    #[tokio::test]
+    async fn large_replay_is_paced_in_chunks_and_marked_done() {
+        let daemon = ScriptedDaemon::start().await;
+        let (mut client, resp) = daemon.request(serde_json::json!({"type": "create_session"})).await;
+        let session_id = resp["session_id"].as_str().unwrap().to_string();
+        client.send_frame(FrameType::Close, &[]).await;
+        daemon.wait_detached().await;
+        let output: Vec<u8> = (0..40_000u32).map(|i| b'a' + (i % 26) as u8).collect();
+        let session = daemon.sm.get_session(&session_id).unwrap();
+        session.lock().unwrap().scrollback.lock().unwrap().append(&output);
+
+        let (mut client, _) = daemon
+            .request(serde_json::json!({"type": "attach_session", "session_id": session_id}))
+            .await;
+        let mut replayed = Vec::new();
+        let mut frames = 0;
+        loop {
+            let frame = client.next_frame(Duration::from_secs(5)).await.unwrap();
+            assert_eq!(frame.frame_type, FrameType::Scrollback);
+            if frame.is_replay_end() {
+                break;
+            }
+            assert!(frame.payload.len() <= REPLAY_CHUNK_BYTES);
+            replayed.extend_from_slice(&frame.payload);
+            frames += 1;
+        }
+        assert_eq!((replayed, frames), (output, 3));
+        daemon.handle(0).emit(b"live");
+        assert_eq!(client.next_frame(Duration::from_secs(5)).await.unwrap().payload, b"live");
+    }
+
+    #[tokio::test]
+    async fn reattach_replays_the_latest_output_and_pages_the_rest() {
+        let daemon = ScriptedDaemon::start().await;
+        let (mut client, resp) = daemon.request(serde_json::json!({"type": "create_session"})).await;
+        let session_id = resp["session_id"].as_str().unwrap().to_string();
+        daemon.handle(0).emit(b"line 1\r\nline 2\r\nline 3\r\n");
+        client.next_frame(Duration::from_secs(5)).await.unwrap();
+        client.send_frame(FrameType::Close, &[]).await;
+        daemon.wait_detached().await;
+
+        let attach = serde_json::json!({"type": "attach_session", "session_id": session_id, "replay_bytes": 8});
+        let (mut client, resp) = daemon.request(attach).await;
+        assert_eq!(resp["scrollback_offset"], 16);
+        let replay = client.next_frame(Duration::from_secs(5)).await.unwrap();
+        assert_eq!(replay.payload, b"line 3\r\n");
+
+        let fetch = |before: u64| {
+            serde_json::json!({"type": "fetch_scrollback", "session_id": session_id, "before_offset": before, "max_bytes": 10})
+        };
+        let (_, page) = daemon.request(fetch(16)).await;
+        assert_eq!(page["type"], "scrollback_page");
+        assert_eq!(page["offset"], 6);
+        let data = base64::engine::general_purpose::STANDARD.decode(page["data"].as_str().unwrap()).unwrap();
+        assert_eq!(data, b"\r\nline 2\r\n");
+        assert_eq!(page["more"], true);
+        let (_, page) = daemon.request(fetch(6)).await;
+        assert_eq!((page["offset"].as_u64(), page["more"].as_bool()), (Some(0), Some(false)));
+
+        let (_, resp) = daemon.request(serde_json::json!({"type": "fetch_scrollback", "session_id": session_id})).await;
+        assert_eq!(resp["code"], "BAD_REQUEST");
+    }
+
+    #[tokio::test]
+    async fn replay_says_which_sizes_the_output_was_laid_out_for() {
+        let daemon = ScriptedDaemon::start().await;
+        let (mut client, resp) = daemon.request(serde_json::json!({"type": "create_session"})).await;
+        let session_id = resp["session_id"].as_str().unwrap().to_string();
+        let term = daemon.handle(0);
+        let (rows, cols) = term.size();
+        term.emit(b"narrow\r\n");
+        client.next_frame(Duration::from_secs(5)).await.unwrap();
+        client.send_frame(FrameType::Resize, &frame::resize_payload(132, 50)).await;
+        wait_until(|| term.size() == (50, 132)).await;
+        term.emit(b"wide\r\n");
+        client.next_frame(Duration::from_secs(5)).await.unwrap();
+        client.send_frame(FrameType::Close, &[]).await;
+        daemon.wait_detached().await;
+
+        let sizes = serde_json::json!([
+            {"offset": 0, "rows": rows, "cols": cols},
+            {"offset": 8, "rows": 50, "cols": 132},
+        ]);
+        let (_, resp) = daemon.request(serde_json::json!({"type": "attach_session", "session_id": session_id})).await;
+        assert_eq!(resp["scrollback_sizes"], sizes);
+        let fetch = serde_json::json!({"type": "fetch_scrollback", "session_id": session_id, "before_offset": 14});
+        let (_, page) = daemon.request(fetch).await;
+        assert_eq!(page["sizes"], sizes);
+    }
+
+    #[tokio::test]
+    async fn mirror_watches_output_until_detach() {
+        let daemon = ScriptedDaemon::start().await;
+        let (_, resp) = daemon
+            .request(serde_json::json!({"type": "mirror_session", "session_id": "missing"}))
+            .await;
+        assert_eq!(resp["type"], "error");
+
+        let (mut phone, resp) = daemon.request(serde_json::json!({"type": "create_session"})).await;
+        let session_id = resp["session_id"].as_str().unwrap().to_string();
+        let term = daemon.handle(0);
+        term.emit(b"build started\r\n");
+        phone.next_frame(Duration::from_secs(5)).await.unwrap();
+
+        let (mut tablet, resp) = daemon
+            .request(serde_json::json!({"type": "mirror_session", "session_id": session_id}))
+            .await;
+        assert_eq!(resp["type"], "session_mirrored");
+        let replay = tablet.next_frame(Duration::from_secs(5)).await.unwrap();
+        assert_eq!(replay.frame_type, FrameType::Scrollback);
+        assert_eq!(replay.payload, b"build started\r\n");
+        assert!(tablet.next_frame(Duration::from_secs(5)).await.unwrap().is_replay_end());
+        assert_eq!(daemon.sm.list_sessions()[0].mirrors, 1);
+
+        // Both devices see live output; the mirror can't type or resize
+        term.emit(b"step 1 of 3");
+        assert_eq!(phone.next_frame(Duration::from_secs(5)).await.unwrap().payload, b"step 1 of 3");
+        let frame = tablet.next_frame(Duration::from_secs(5)).await.unwrap();
+        assert_eq!((frame.frame_type, frame.sequence), (FrameType::Data, 1));
+        assert_eq!(frame.payload, b"step 1 of 3");
+        tablet.send_frame(FrameType::Data, b"q").await;
+        tablet.send_frame(FrameType::Resize, &frame::resize_payload(10, 5)).await;
+        phone.send_frame(FrameType::Data, b"y").await;
+        wait_until(|| term.input() == b"y").await;
+        assert_eq!(term.size(), (24, 80));
+
+        // The mirror ends when the attached device detaches
+        phone.send_frame(FrameType::Close, &[]).await;
+        let close = tablet.next_frame(Duration::from_secs(5)).await.unwrap();
+        assert_eq!(close.frame_type, FrameType::Close);
+        assert_eq!(close.parse_close_reason(), Some(CLOSE_REASON_DETACHED));
+        assert!(tablet.next_frame(Duration::from_secs(5)).await.is_none());
+        daemon.wait_detached().await;
+
+        let (_, resp) = daemon
+            .request(serde_json::json!({"type": "mirror_session", "session_id": session_id}))
+            .await;
+        assert_eq!(resp["error"], "session is not attached");
+        assert_eq!(resp["code"], "NOT_ATTACHED");
+    }
+
+    #[tokio::test]
+    async fn coalesce_drains_queued_without_window() {
+        let (tx, mut rx) = mpsc::channel(8);
+        tx.send(Bytes::from_static(b"b")).await.unwrap();
+        tx.send(Bytes::from_static(b"c")).await.unwrap();
+        let mut data = BytesMut::from(&b"a"[..]);
+        assert!(coalesce(&mut rx, &mut data, None).await.is_none());
+        assert_eq!(&data[..], b"abc");
+    }
+
+    #[tokio::test]
+    async fn coalesce_waits_for_small_output() {
+        let (tx, mut rx) = mpsc::channel(8);
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(2)).await;
+            tx.send(Bytes::from_static(b"b")).await.unwrap();
+        });
+        let mut data = BytesMut::from(&b"a"[..]);
+        coalesce(&mut rx, &mut data, Some(Duration::from_millis(200))).await;
+        assert_eq!(&data[..], b"ab");
+    }
+
+    #[tokio::test]
+    async fn coalesce_bypasses_large_output() {
+        let (_tx, mut rx) = mpsc::channel::<Bytes>(8);
+        let mut data = BytesMut::from(&[b'x'; COALESCE_BYPASS_BYTES][..]);
+        let start = std::time::Instant::now();
+        coalesce(&mut rx, &mut data, Some(Duration::from_secs(5))).await;
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn coalesce_carries_overflow() {
+        let (tx, mut rx) = mpsc::channel(8);
+        tx.send(Bytes::from(vec![b'y'; 16])).await.unwrap();
+        let mut data = BytesMut::from(&vec![b'x'; frame::MAX_PAYLOAD - 8][..]);
+        let carry = coalesce(&mut rx, &mut data, None).await;
+        assert_eq!(data.len(), frame::MAX_PAYLOAD - 8);
+        assert_eq!(carry, Some(Bytes::from(vec![b'y'; 16])));
+    }
+
+    #[tokio::test]
+    async fn next_output_drains_until_quiet() {
+        let (tx, mut rx) = mpsc::channel(8);
+        let drain = CancellationToken::new();
+        let mut deadline = None;
+
+        tx.send(Bytes::from_static(b"live")).await.unwrap();
+        assert_eq!(next_output(&mut rx, &drain, &mut deadline).await.unwrap(), "live");
+        assert!(deadline.is_none());
+
+        drain.cancel();
+        tx.send(Bytes::from_static(b"tail")).await.unwrap();
+        // Queued output is still delivered after Close...
+        assert_eq!(next_output(&mut rx, &drain, &mut deadline).await.unwrap(), "tail");
+        assert!(deadline.is_some());
+        // ...and a quiet PTY ends the drain even though the sender is alive
+        let start = std::time::Instant::now();
+        assert!(next_output(&mut rx, &drain, &mut deadline).await.is_none());
+        assert!(start.elapsed() < CLOSE_DRAIN_MAX);
+        drop(tx);
+    }
+
+    #[test]
+    fn held_input_releases_over_cap() {
+        let mut held = HeldInput::new();
+        assert!(held.push(b"ls\r", 1));
+        assert!(held.push(&vec![b'x'; HELD_INPUT_CAP - 3], 2));
+        assert!(!held.push(b"!", 3));
+        // Nothing is dropped when the cap is hit
+        let (input, seq) = held.into_inner();
+        assert_eq!(input.len(), HELD_INPUT_CAP + 1);
+        assert_eq!(seq, Some(3));
+        assert!(input.starts_with(b"ls\r"));
+    }
+
+    #[test]
+    fn read_chunks_reuse_slab() {
+        let mut reader = std::io::repeat(b'r');
+        let mut slab = BytesMut::with_capacity(READ_SLAB_BYTES);
+        // First split promotes the slab to a shared allocation
+        drop(read_chunk(&mut reader, &mut slab).unwrap());
+        let ((), allocs) = crate::alloc_counter::count(|| {
+            for _ in 0..1000 {
+                let chunk = read_chunk(&mut reader, &mut slab).unwrap();
+                assert_eq!(chunk.len(), READ_CHUNK_BYTES);
+            }
+        });
+        assert_eq!(allocs, 0);
+    }
+
+    #[test]
+    fn encode_data_reuses_buffers() {
+        let mut bufs = FrameBuffers::new().unwrap();
+        bufs.payload.extend_from_slice(b"warm-up");
+        drop(bufs.encode_data(0, 0).unwrap());
+        let ((), allocs) = crate::alloc_counter::count(|| {
+            for seq in 1..1000 {
+                bufs.payload.extend_from_slice(&[b'k'; 64]);
+                let chunks = bufs.encode_data(seq, 0).unwrap();
+                assert_eq!(chunks[0].len(), frame::HEADER_SIZE);
+            }
+        });
+        assert_eq!(allocs, 0);
+    }
+
+    #[test]
+    fn encode_data_roundtrips() {
+        let mut bufs = FrameBuffers::new().unwrap();
+        for (seq, payload) in [b"short".to_vec(), vec![b'c'; 8192]].into_iter().enumerate() {
+            bufs.payload.extend_from_slice(&payload);
+            let chunks = bufs.encode_data(seq as u64, 0).unwrap();
+            let wire: Vec<u8> = chunks.concat();
+            let (decoded, _) = frame::decode(&wire).unwrap().unwrap();
+            assert_eq!(decoded.payload, payload);
+            assert_eq!(decoded.sequence, seq as u64);
+        }
+    }
+
+    #[test]
+    fn utf8_frames_hold_back_cut_codepoints_and_flag_binary() {
+        let mut payload = BytesMut::from("ok €".as_bytes());
+        payload.truncate(payload.len() - 1);
+        let (flags, tail) = align_utf8(&mut payload);
+        assert_eq!((&payload[..], flags), (&b"ok "[..], 0));
+        assert_eq!(&tail.unwrap()[..], &"€".as_bytes()[..2]);
+
+        let mut payload = BytesMut::from(&b"\xff\xfe bytes"[..]);
+        let (flags, tail) = align_utf8(&mut payload);
+        assert_eq!((flags, tail), (frame::FLAG_BINARY, None));
+
+        // The flag survives compression
+        let mut bufs = FrameBuffers::new().unwrap();
+        bufs.payload.extend_from_slice(&[0xff; 4096]);
+        let chunks = bufs.encode_data(1, frame::FLAG_BINARY).unwrap();
+        let flags = u16::from_be_bytes([chunks[0][13], chunks[0][14]]);
+        assert_eq!(flags, frame::FLAG_BINARY | frame::FLAG_COMPRESSED);
+    }
+
+    #[tokio::test]
+    async fn utf8_frames_end_on_codepoint_boundaries() {
+        let daemon = ScriptedDaemon::start().await;
+        let create = serde_json::json!({"type": "create_session", "utf8_frames": true});
+        let (mut client, _) = daemon.request(create).await;
+        let term = daemon.handle(0);
+        let euro = "€".as_bytes();
+        term.emit(&[b'a', euro[0]]);
+        let first = client.next_frame(Duration::from_secs(2)).await.unwrap();
+        assert_eq!(&first.payload[..], b"a");
+        term.emit(&euro[1..]);
+        let second = client.next_frame(Duration::from_secs(2)).await.unwrap();
+        assert_eq!(&second.payload[..], euro);
+    }
+
+    #[tokio::test]
+    async fn bandwidth_cap_paces_output_frames() {
+        let daemon = ScriptedDaemon::start().await;
+        let create = serde_json::json!({"type": "create_session", "max_bytes_per_sec": 1024});
+        let (mut client, _) = daemon.request(create).await;
+        let term = daemon.handle(0);
+        // Noise, so compression doesn't shrink it under the cap
+        let mut state = 7u32;
+        let output: Vec<u8> = (0..3000)
+            .map(|_| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                (state >> 16) as u8
+            })
+            .collect();
+        let start = Instant::now();
+        term.emit(&output);
+        let mut received = Vec::new();
+        while received.len() < output.len() {
+            let frame = client.next_frame(Duration::from_secs(5)).await.unwrap();
+            assert!(frame.payload.len() <= 1024);
+            received.extend_from_slice(&frame.payload);
+        }
+        assert_eq!(received, output);
+        // A second's worth goes at once, the rest at 1 KB/s
+        assert!(start.elapsed() >= Duration::from_millis(900));
+    }
+
+    #[tokio::test]
+    async fn quiet_streams_time_out() {
+        let daemon = ScriptedDaemon::start().await;
+        daemon.serve_with_deadlines(StreamDeadlines {
+            first: Some(Duration::from_millis(100)),
+            idle: Some(Duration::from_millis(300)),
+        });
+
+        // Half a length prefix, then nothing
+        let (mut send, recv) = daemon.conn.open_bi().await.unwrap();
+        send.write_all(&[0, 0]).await.unwrap();
+        let mut stalled = ClientStream { send, recv, decoder: FrameDecoder::new(), seq: 1 };
+        let closed = stalled.next_message().await;
+        assert_eq!(closed["code"], "TIMEOUT");
+        assert_eq!(closed["error"], "no request within 0.1s of opening the stream");
+
+        // Once a request is answered the idle deadline applies
+        let (mut client, _) = daemon.request(serde_json::json!({"type": "list_sessions"})).await;
+        let closed = client.next_message().await;
+        assert_eq!(closed["code"], "TIMEOUT");
+        assert_eq!(closed["error"], "no request for 0.3s");
+    }
+
+    #[test]
+    fn options_clamp_client_window() {
+        let defaults = BridgeConfig { coalesce_ms: 3, ..BridgeConfig::default() };
+        let opts = BridgeOptions::from_request(&serde_json::json!({}), &defaults);
+        assert_eq!(opts.coalesce, Some(Duration::from_millis(3)));
+        assert!(!opts.low_bandwidth);
+        assert_eq!(opts.max_bytes_per_sec, None);
+        let opts = BridgeOptions::from_request(&serde_json::json!({"low_bandwidth": true}), &defaults);
+        assert!(opts.low_bandwidth);
+        let opts = BridgeOptions::from_request(&serde_json::json!({"max_bytes_per_sec": 8192}), &defaults);
+        assert_eq!(opts.max_bytes_per_sec, Some(8192));
+        let opts = BridgeOptions::from_request(&serde_json::json!({"max_bytes_per_sec": 0}), &defaults);
+        assert_eq!(opts.max_bytes_per_sec, None);
+        let opts = BridgeOptions::from_request(&serde_json::json!({"coalesce_ms": 0}), &defaults);
+        assert_eq!(opts.coalesce, None);
+        let opts = BridgeOptions::from_request(&serde_json::json!({"coalesce_ms": 1000}), &defaults);
+        assert_eq!(opts.coalesce, Some(Duration::from_millis(MAX_COALESCE_MS)));
+    }
+}
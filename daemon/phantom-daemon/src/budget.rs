@@ -0,0 +1,247 @@
+//! CPU-time and lifetime budgets for sessions, for shared build hosts that
+//! several devices run work on.
+//!
+//! A client gives a session its budget at creation (`"budget"` in
+//! `create_session`). The reaper checks it on each pass: once a session has
+//! used [`WARN_AT`] of either, its devices get a `session_budget_warning`;
+//! once it has used all of one, its processes are hung up and the session
+//! removed, with `cpu_budget` or `lifetime_budget` as the reason.
+//!
+//! CPU time is the session's processes' user and system time, including the
+//! commands they have waited for: on Linux every process in the shell's
+//! session, on macOS the shell and what it has waited for (a build still
+//! running counts once it ends).
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
+
+use crate::clock::SharedClock;
+use crate::errors::ErrorCode;
+
+/// Share of a budget used when devices are warned.
+pub const WARN_AT: f64 = 0.9;
+
+/// Limits a client set for a session.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Budget {
+    /// CPU seconds the session's processes may use
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cpu_secs: Option<u64>,
+    /// Seconds the session may live
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lifetime_secs: Option<u64>,
+}
+
+impl Budget {
+    /// The `budget` of a create request, if it has one.
+    pub fn from_request(req: &serde_json::Value) -> Result<Option<Self>> {
+        let budget = &req["budget"];
+        if budget.is_null() {
+            return Ok(None);
+        }
+        let budget: Budget = serde_json::from_value(budget.clone())
+            .map_err(|e| ErrorCode::BadRequest.err(format!("invalid budget: {e}")))?;
+        if budget == Budget::default() {
+            return Err(ErrorCode::BadRequest.err("budget needs cpu_secs or lifetime_secs"));
+        }
+        if budget.cpu_secs == Some(0) || budget.lifetime_secs == Some(0) {
+            return Err(ErrorCode::BadRequest.err("budget limits must be positive"));
+        }
+        Ok(Some(budget))
+    }
+}
+
+/// What a budget limits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Resource {
+    Cpu,
+    Lifetime,
+}
+
+impl Resource {
+    /// Why a session over this budget was removed, for `session_removed`.
+    pub fn reason(self) -> &'static str {
+        match self {
+            Resource::Cpu => "cpu_budget",
+            Resource::Lifetime => "lifetime_budget",
+        }
+    }
+}
+
+/// Where a session stands against its budget.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    Within,
+    /// First pass over [`WARN_AT`] of `resource`: warn, once
+    Warn { resource: Resource, used: Duration, limit: Duration },
+    Exceeded(Resource),
+}
+
+/// A session's budget and what it has used.
+#[derive(Clone)]
+pub struct BudgetTracker {
+    pub budget: Budget,
+    clock: SharedClock,
+    started: Instant,
+    warned: bool,
+}
+
+/// Budget and use of a session, for `inspect_session`.
+#[derive(Debug, Serialize)]
+pub struct BudgetReport {
+    #[serde(flatten)]
+    pub budget: Budget,
+    /// None where the host can't measure it
+    pub cpu_used_secs: Option<f64>,
+    pub lifetime_used_secs: u64,
+    pub warned: bool,
+}
+
+impl BudgetTracker {
+    /// Track `budget` from now on `clock`.
+    pub fn new(budget: Budget, clock: SharedClock) -> Self {
+        Self { budget, started: clock.now(), clock, warned: false }
+    }
+
+    /// Check use so far: `cpu` used (None if unknown) and the time since
+    /// the tracker started.
+    pub fn check(&mut self, cpu: Option<Duration>) -> Verdict {
+        let lifetime = self.clock.now().saturating_duration_since(self.started);
+        let uses = [
+            (Resource::Cpu, cpu.zip(self.budget.cpu_secs)),
+            (Resource::Lifetime, self.budget.lifetime_secs.map(|limit| (lifetime, limit))),
+        ];
+        let uses = uses.into_iter().filter_map(|(resource, use_)| {
+            use_.map(|(used, limit)| (resource, used, Duration::from_secs(limit)))
+        });
+        let mut verdict = Verdict::Within;
+        for (resource, used, limit) in uses {
+            if used >= limit {
+                return Verdict::Exceeded(resource);
+            }
+            if !self.warned && verdict == Verdict::Within && used.as_secs_f64() >= limit.as_secs_f64() * WARN_AT {
+                verdict = Verdict::Warn { resource, used, limit };
+            }
+        }
+        if verdict != Verdict::Within {
+            self.warned = true;
+        }
+        verdict
+    }
+
+    pub fn report(&self, cpu: Option<Duration>) -> BudgetReport {
+        BudgetReport {
+            budget: self.budget,
+            cpu_used_secs: cpu.map(|cpu| cpu.as_secs_f64()),
+            lifetime_used_secs: self.clock.now().saturating_duration_since(self.started).as_secs(),
+            warned: self.warned,
+        }
+    }
+}
+
+/// CPU time used by the session process `pid` leads: its own, its
+/// session's live processes' and what they have waited for.
+#[cfg(target_os = "linux")]
+pub fn cpu_time(pid: u32) -> Option<Duration> {
+    let ticks_per_sec = unsafe { libc::sysconf(libc::_SC_CLK_TCK) };
+    if ticks_per_sec <= 0 {
+        return None;
+    }
+    let own = session_ticks(&std::fs::read_to_string(format!("/proc/{pid}/stat")).ok()?, None)?;
+    let others: u64 = std::fs::read_dir("/proc")
+        .ok()?
+        .flatten()
+        .filter(|entry| entry.file_name().to_str().and_then(|name| name.parse::<u32>().ok()).is_some_and(|p| p != pid))
+        .filter_map(|entry| std::fs::read_to_string(entry.path().join("stat")).ok())
+        .filter_map(|stat| session_ticks(&stat, Some(pid)))
+        .sum();
+    Some(Duration::from_secs_f64((own + others) as f64 / ticks_per_sec as f64))
+}
+
+/// utime + stime + cutime + cstime of a `/proc/<pid>/stat`, if the process
+/// is in `session` (or whatever its session, for None).
+#[cfg(target_os = "linux")]
+fn session_ticks(stat: &str, session: Option<u32>) -> Option<u64> {
+    // Fields after the command name, which may itself hold spaces and ')',
+    // counting the state as 3
+    let fields: Vec<&str> = stat.rsplit_once(')')?.1.split_whitespace().collect();
+    if session.is_some_and(|session| fields.get(3).and_then(|s| s.parse().ok()) != Some(session)) {
+        return None;
+    }
+    fields.get(11..15)?.iter().map(|t| t.parse::<u64>().ok()).sum()
+}
+
+/// CPU time used by process `pid` and the children it has waited for.
+#[cfg(target_os = "macos")]
+pub fn cpu_time(pid: u32) -> Option<Duration> {
+    let mut info: libc::rusage_info_v2 = unsafe { std::mem::zeroed() };
+    let buffer = &mut info as *mut libc::rusage_info_v2 as *mut libc::rusage_info_t;
+    if unsafe { libc::proc_pid_rusage(pid as libc::c_int, libc::RUSAGE_INFO_V2, buffer) } != 0 {
+        return None;
+    }
+    // In Mach absolute time units, nanoseconds only on Intel
+    #[allow(deprecated)]
+    let mut timebase: libc::mach_timebase_info = unsafe { std::mem::zeroed() };
+    #[allow(deprecated)]
+    if unsafe { libc::mach_timebase_info(&mut timebase) } != 0 || timebase.denom == 0 {
+        return None;
+    }
+    let units = info.ri_user_time + info.ri_system_time + info.ri_child_user_time + info.ri_child_system_time;
+    let nanos = units as u128 * timebase.numer as u128 / timebase.denom as u128;
+    Some(Duration::from_nanos(nanos as u64))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+pub fn cpu_time(_pid: u32) -> Option<Duration> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn budgets_are_validated() {
+        assert_eq!(Budget::from_request(&json!({})).unwrap(), None);
+        let budget = Budget::from_request(&json!({"budget": {"cpu_secs": 600}})).unwrap().unwrap();
+        assert_eq!(budget, Budget { cpu_secs: Some(600), lifetime_secs: None });
+        for bad in [json!({}), json!({"cpu_secs": 0}), json!({"cpu_secs": -1}), json!({"wall_secs": 5})] {
+            let err = Budget::from_request(&json!({ "budget": bad })).unwrap_err();
+            assert_eq!(ErrorCode::of(&err), ErrorCode::BadRequest, "{bad}");
+        }
+    }
+
+    #[test]
+    fn warns_once_then_reports_the_budget_exceeded() {
+        let clock = crate::clock::ManualClock::new();
+        let cpu = |secs| Some(Duration::from_secs(secs));
+        let budget = Budget { cpu_secs: Some(100), lifetime_secs: Some(3600) };
+        let mut tracker = BudgetTracker::new(budget, clock.clone());
+
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(tracker.check(cpu(50)), Verdict::Within);
+        // CPU time unknown: only the lifetime counts
+        assert_eq!(tracker.check(None), Verdict::Within);
+        clock.advance(Duration::from_secs(10));
+        let (used, limit) = (Duration::from_secs(95), Duration::from_secs(100));
+        assert_eq!(tracker.check(cpu(95)), Verdict::Warn { resource: Resource::Cpu, used, limit });
+        clock.advance(Duration::from_secs(3280));
+        assert_eq!(tracker.check(cpu(96)), Verdict::Within);
+        assert!(tracker.report(cpu(96)).warned);
+        clock.advance(Duration::from_secs(300));
+        assert_eq!(tracker.check(cpu(96)), Verdict::Exceeded(Resource::Lifetime));
+
+        let mut fresh = BudgetTracker::new(budget, clock.clone());
+        assert_eq!(fresh.check(cpu(100)), Verdict::Exceeded(Resource::Cpu));
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    #[test]
+    fn measures_this_process() {
+        assert!(cpu_time(std::process::id()).is_some());
+    }
+}
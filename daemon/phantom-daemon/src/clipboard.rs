@@ -0,0 +1,130 @@
+//! Host clipboard, set by clients with a `set_clipboard` control message
+//! (`[clipboard]` in config.toml). Off unless `allow_push` is set: anything
+//! a paired device copies then becomes pasteable on the host.
+
+use anyhow::{bail, Context, Result};
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+
+use crate::config::ClipboardConfig;
+use crate::errors::ErrorCode;
+use crate::hooks::shell;
+
+/// Clipboard commands still running after this long are abandoned.
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Default)]
+pub struct Clipboard {
+    config: ClipboardConfig,
+}
+
+impl Clipboard {
+    pub fn new(config: ClipboardConfig) -> Self {
+        Self { config }
+    }
+
+    /// Whether clients may set the clipboard at all.
+    pub fn allows_push(&self) -> bool {
+        self.config.allow_push
+    }
+
+    /// Replace the host clipboard contents with `text`.
+    pub async fn set(&self, text: &str) -> Result<()> {
+        if !self.config.allow_push {
+            return Err(ErrorCode::PermissionDenied.err("clipboard push is disabled on this host"));
+        }
+        if text.len() > self.config.max_bytes {
+            let too_large = format!("clipboard text too large ({} bytes, max {})", text.len(), self.config.max_bytes);
+            return Err(ErrorCode::LimitExceeded.err(too_large));
+        }
+        let command = match &self.config.command {
+            Some(command) => command.clone(),
+            None => default_command()
+                .ok_or_else(|| ErrorCode::Unavailable.err("no clipboard command found (set [clipboard] command)"))?,
+        };
+
+        let mut child = shell(&command)
+            .stdin(std::process::Stdio::piped())
+            // xclip and friends stay around to serve the selection; don't
+            // tie our wait to their output
+            .stdout(std::process::Stdio::null())
+            .stderr(std::process::Stdio::null())
+            .kill_on_drop(true)
+            .spawn()
+            .context("spawn clipboard command")?;
+        let mut stdin = child.stdin.take().context("clipboard command stdin")?;
+        let status = tokio::time::timeout(COMMAND_TIMEOUT, async {
+            stdin.write_all(text.as_bytes()).await?;
+            drop(stdin);
+            child.wait().await
+        })
+        .await
+        .context("clipboard command timed out")?
+        .context("run clipboard command")?;
+        if !status.success() {
+            bail!("clipboard command exited with {status}");
+        }
+        Ok(())
+    }
+}
+
+/// The platform's clipboard writer, if one is installed.
+fn default_command() -> Option<String> {
+    if cfg!(target_os = "macos") {
+        return Some("pbcopy".to_string());
+    }
+    if cfg!(windows) {
+        return Some("clip".to_string());
+    }
+    let wayland = std::env::var_os("WAYLAND_DISPLAY").is_some();
+    let candidates: &[(&str, &str)] = if wayland {
+        &[("wl-copy", "wl-copy"), ("xclip", "xclip -selection clipboard"), ("xsel", "xsel --clipboard --input")]
+    } else {
+        &[("xclip", "xclip -selection clipboard"), ("xsel", "xsel --clipboard --input"), ("wl-copy", "wl-copy")]
+    };
+    candidates
+        .iter()
+        .find(|(program, _)| on_path(program))
+        .map(|(_, command)| command.to_string())
+}
+
+pub(crate) fn on_path(program: &str) -> bool {
+    std::env::var_os("PATH").is_some_and(|path| {
+        std::env::split_paths(&path).any(|dir| dir.join(program).is_file())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn clipboard(dir: &std::path::Path, allow_push: bool) -> (Clipboard, std::path::PathBuf) {
+        let out = dir.join("clipboard");
+        let clipboard = Clipboard::new(ClipboardConfig {
+            allow_push,
+            max_bytes: 16,
+            command: Some(format!("cat > {}", out.display())),
+        });
+        (clipboard, out)
+    }
+
+    #[tokio::test]
+    async fn sets_clipboard_through_command() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let (clipboard, out) = clipboard(dir.path(), true);
+        clipboard.set("ghp_token123").await.unwrap();
+        assert_eq!(std::fs::read_to_string(&out).unwrap(), "ghp_token123");
+
+        let err = clipboard.set(&"x".repeat(17)).await.unwrap_err();
+        assert!(err.to_string().contains("too large"), "{err}");
+    }
+
+    #[tokio::test]
+    async fn refuses_unless_allowed() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let (clipboard, out) = clipboard(dir.path(), false);
+        let err = clipboard.set("secret").await.unwrap_err();
+        assert!(err.to_string().contains("disabled"), "{err}");
+        assert!(!out.exists());
+    }
+}
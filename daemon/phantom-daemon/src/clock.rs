@@ -0,0 +1,74 @@
+//! Time as the reaper, rate limiters and token stores see it.
+//!
+//! They read a [`Clock`] instead of `Instant::now` or `SystemTime::now`, so
+//! tests can hand them a `ManualClock` and move time forward instead of
+//! sleeping through retention periods, windows and expiries. The
+//! [`SystemClock`] takes monotonic time from tokio, so it follows a paused
+//! test runtime as well.
+
+use std::sync::Arc;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+pub trait Clock: Send + Sync {
+    /// Monotonic time, for intervals and retention
+    fn now(&self) -> Instant;
+    /// Wall-clock time in Unix seconds, for expiries stored on disk
+    fn unix_secs(&self) -> u64;
+}
+
+/// A clock shared by everything that reads it.
+pub type SharedClock = Arc<dyn Clock>;
+
+/// The real clocks.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        tokio::time::Instant::now().into_std()
+    }
+
+    fn unix_secs(&self) -> u64 {
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+    }
+}
+
+/// The real clocks, shared.
+pub fn system() -> SharedClock {
+    Arc::new(SystemClock)
+}
+
+/// A clock that stands still until advanced, starting at the real time.
+#[cfg(any(test, feature = "testing"))]
+#[derive(Debug)]
+pub struct ManualClock {
+    start: Instant,
+    unix_start: u64,
+    elapsed: std::sync::Mutex<std::time::Duration>,
+}
+
+#[cfg(any(test, feature = "testing"))]
+impl ManualClock {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self {
+            start: Instant::now(),
+            unix_start: SystemClock.unix_secs(),
+            elapsed: Default::default(),
+        })
+    }
+
+    pub fn advance(&self, by: std::time::Duration) {
+        *self.elapsed.lock().unwrap() += by;
+    }
+}
+
+#[cfg(any(test, feature = "testing"))]
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        self.start + *self.elapsed.lock().unwrap()
+    }
+
+    fn unix_secs(&self) -> u64 {
+        self.unix_start + self.elapsed.lock().unwrap().as_secs()
+    }
+}
@@ -1,15 +1,65 @@
 use clap::{Parser, Subcommand};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use std::net::SocketAddr;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+
+use crate::scheduler::ScheduledJob;
 
 #[derive(Parser, Debug)]
 #[command(name = "phantom", about = "Phantom terminal daemon")]
 pub struct Cli {
+    /// Keep all state here instead of ~/.phantom
+    #[arg(long, global = true, env = "PHANTOM_DATA_DIR")]
+    pub data_dir: Option<PathBuf>,
+    /// Read the configuration from this file instead of config.toml in the
+    /// data directory. It is only ever read, so it can be mounted read-only
+    #[arg(long, global = true, env = "PHANTOM_CONFIG")]
+    pub config: Option<PathBuf>,
+    /// Run in a container: state only under --data-dir, which is required,
+    /// and no keeping the host awake
+    #[arg(long, global = true, env = "PHANTOM_CONTAINER")]
+    pub container: bool,
     #[command(subcommand)]
     pub command: Option<Command>,
 }
 
+impl Cli {
+    /// Where the daemon keeps its state (`--data-dir`, else ~/.phantom).
+    pub fn data_dir(&self) -> anyhow::Result<PathBuf> {
+        use anyhow::Context;
+
+        match &self.data_dir {
+            Some(dir) => Ok(dir.clone()),
+            None if self.container => anyhow::bail!("--container needs --data-dir (or PHANTOM_DATA_DIR)"),
+            None => Ok(dirs::home_dir().context("home dir")?.join(".phantom")),
+        }
+    }
+
+    /// These global options as arguments, resolved against the current
+    /// directory, for a daemon taking over from this one.
+    pub fn global_args(&self) -> Vec<std::ffi::OsString> {
+        let mut args = Vec::new();
+        for (flag, path) in [("--data-dir", &self.data_dir), ("--config", &self.config)] {
+            if let Some(path) = path {
+                args.push(flag.into());
+                args.push(std::path::absolute(path).unwrap_or_else(|_| path.clone()).into());
+            }
+        }
+        if self.container {
+            args.push("--container".into());
+        }
+        args
+    }
+
+    /// The `--config` file, else config.toml in `data_dir`.
+    pub fn load_config(&self, data_dir: &Path) -> anyhow::Result<DaemonConfig> {
+        match &self.config {
+            Some(path) => DaemonConfig::load_file(path),
+            None => Ok(DaemonConfig::load(data_dir)),
+        }
+    }
+}
+
 #[derive(Subcommand, Debug)]
 pub enum Command {
     /// Start the daemon (default if no subcommand)
@@ -17,6 +67,9 @@ pub enum Command {
         /// Address to bind
         #[arg(long)]
         bind: Option<SocketAddr>,
+        /// Take over sessions from a running daemon (used by `phantom upgrade`)
+        #[arg(long, hide = true)]
+        takeover: Option<PathBuf>,
     },
     /// Rotate the TLS certificate
     RotateCert,
@@ -25,12 +78,131 @@ pub enum Command {
         /// Print token string instead of QR code (for remote machines)
         #[arg(long)]
         token: bool,
+        /// User the device's sessions run as (multi-user mode)
+        #[arg(long)]
+        user: Option<String>,
+    },
+    /// Create a one-time, read-only guest link to a session
+    Share {
+        /// Session to share
+        session_id: String,
+        /// Link lifetime in seconds (default 30 minutes, max 24 hours)
+        #[arg(long)]
+        ttl: Option<u64>,
+        /// Print token string instead of QR code
+        #[arg(long)]
+        token: bool,
+    },
+    /// Run a single command through the daemon and print its output
+    Exec {
+        /// Kill the command after this many seconds (default 60, max 600)
+        #[arg(long)]
+        timeout: Option<u64>,
+        /// Command line, run with the user's shell
+        #[arg(required = true, trailing_var_arg = true, allow_hyphen_values = true)]
+        command: Vec<String>,
     },
     /// Manage paired devices
     Device {
         #[command(subcommand)]
         action: DeviceAction,
     },
+    /// Replace the running daemon with this binary, keeping its sessions
+    Upgrade {
+        /// New daemon binary (default: this one)
+        #[arg(long)]
+        binary: Option<PathBuf>,
+    },
+    /// Move sessions between machines
+    Session {
+        #[command(subcommand)]
+        action: SessionAction,
+    },
+    /// Revoke every device, delete keys, certificates and tokens, and
+    /// regenerate the host's identity (for a Mac changing hands or a stolen
+    /// phone)
+    Reset {
+        /// Really wipe; without it, only say what would be wiped
+        #[arg(long)]
+        confirm: bool,
+    },
+    /// See and lift the daemon's per-IP rate limits
+    RateLimit {
+        #[command(subcommand)]
+        action: RateLimitAction,
+    },
+    /// Describe the client protocol
+    Protocol {
+        #[command(subcommand)]
+        action: ProtocolAction,
+    },
+    /// Speak the client protocol on stdin/stdout through the running daemon,
+    /// each stream in chunks, for programs that don't do QUIC (editors, test
+    /// drivers, SSH wrappers)
+    Stdio,
+    /// Forward Wake-on-LAN packets from clients off the LAN (run on an
+    /// always-on machine next to the host)
+    WakeRelay {
+        /// Address to listen on
+        #[arg(long, default_value = "0.0.0.0:4434")]
+        bind: SocketAddr,
+        /// Only wake these MAC addresses (repeatable; default: any)
+        #[arg(long = "mac")]
+        macs: Vec<String>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum RateLimitAction {
+    /// List IPs with recent connections or failed auths, and which are
+    /// limited
+    Status,
+    /// Forget an IP's recent connections and failed auths, letting it
+    /// straight back in
+    Clear {
+        /// IP address
+        ip: std::net::IpAddr,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum ProtocolAction {
+    /// Print a JSON Schema of the control messages, frame types, flags and
+    /// error codes, for clients to generate their models from
+    Dump,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum SessionAction {
+    /// Save a session's metadata and scrollback to a portable archive
+    Export {
+        /// Session to export
+        id: String,
+        /// Archive path (default: <id>.phantom-session)
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+        /// Don't encrypt the archive, so another host can import it
+        #[arg(long)]
+        plaintext: bool,
+    },
+    /// Start a new session holding an exported session's history
+    Import {
+        /// Archive written by `phantom session export`
+        path: PathBuf,
+        /// Run the new shell as this user (required in multi-user mode)
+        #[arg(long)]
+        user: Option<String>,
+    },
+    /// Show who attached, detached, resized or destroyed a session
+    History {
+        /// Session ID (live or recently ended)
+        id: String,
+    },
+    /// Print diagnostics for a session that won't reattach
+    Inspect {
+        /// Session ID
+        id: String,
+    },
 }
 
 #[derive(Subcommand, Debug)]
@@ -42,15 +214,77 @@ pub enum DeviceAction {
         /// Device ID to revoke
         id: String,
     },
+    /// Allow a device to forward its SSH agent into sessions it creates
+    AllowAgent {
+        /// Device ID
+        id: String,
+    },
+    /// Stop a device from forwarding its SSH agent
+    DenyAgent {
+        /// Device ID
+        id: String,
+    },
+    /// Limit a low-trust device to allowlisted commands, optionally jailed
+    Restrict {
+        /// Device ID
+        id: String,
+        /// Command line its sessions may run (repeatable; the first is the
+        /// default). Without any, it can't start sessions.
+        #[arg(long = "allow")]
+        allowed_commands: Vec<String>,
+        /// Directory to chroot its sessions into (needs root)
+        #[arg(long)]
+        chroot: Option<PathBuf>,
+        /// sandbox-exec profile to run its sessions under (macOS)
+        #[arg(long)]
+        sandbox_profile: Option<PathBuf>,
+    },
+    /// Lift a device's restrictions
+    Unrestrict {
+        /// Device ID
+        id: String,
+    },
+    /// Write paired devices to a passphrase-encrypted bundle, for moving to
+    /// another host without re-pairing them
+    Export {
+        /// Bundle path
+        output: PathBuf,
+        /// Give the new host a new TLS identity instead of this one's, which
+        /// devices learn from this host before the move (clients that don't
+        /// follow `successor` must re-pair)
+        #[arg(long)]
+        new_identity: bool,
+    },
+    /// Pair the devices in a bundle written by `phantom device export` and
+    /// take its TLS identity
+    Import {
+        /// Bundle path
+        path: PathBuf,
+        /// Import even though devices are paired here; they stop recognising
+        /// this host
+        #[arg(long)]
+        force: bool,
+    },
 }
 
-/// Configuration file (~/.phantom/config.toml)
+/// Configuration file (~/.phantom/config.toml, or `--config`)
 #[derive(Debug, Deserialize, Default)]
 #[serde(default)]
 pub struct DaemonConfig {
     pub bind: Option<String>,
     pub rate_limit: RateLimitConfig,
     pub session: SessionConfig,
+    pub bridge: BridgeConfig,
+    pub memory: MemoryConfig,
+    pub hooks: HooksConfig,
+    pub clipboard: ClipboardConfig,
+    pub system: SystemConfig,
+    pub power: PowerConfig,
+    pub wake: WakeConfig,
+    pub protocol: ProtocolConfig,
+    pub clients: ClientsConfig,
+    /// Commands run on a schedule (`[[schedules]]`, see [`crate::scheduler`])
+    pub schedules: Vec<ScheduledJob>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -60,10 +294,14 @@ pub struct RateLimitConfig {
     pub connection_limit: usize,
     /// Connection rate limit window (seconds)
     pub connection_window_secs: u64,
-    /// Max auth failures per IP per window
+    /// Max auth failures per IP, and per device from one IP, per window
     pub auth_failure_limit: usize,
     /// Auth failure rate limit window (seconds)
     pub auth_failure_window_secs: u64,
+    /// Most connections open at once from one IP (0 = unlimited)
+    pub max_connections_per_ip: usize,
+    /// Most connections open at once in all (0 = unlimited)
+    pub max_connections: usize,
 }
 
 impl Default for RateLimitConfig {
@@ -73,6 +311,8 @@ impl Default for RateLimitConfig {
             connection_window_secs: 60,
             auth_failure_limit: 3,
             auth_failure_window_secs: 300,
+            max_connections_per_ip: 16,
+            max_connections: 256,
         }
     }
 }
@@ -84,6 +324,51 @@ pub struct SessionConfig {
     pub scrollback_bytes: usize,
     /// Session reaper interval (seconds)
     pub reaper_interval_secs: u64,
+    /// Idle shells kept pre-spawned so create_session returns instantly (0 = disabled)
+    pub prewarm: usize,
+    /// Most sessions open at once (0 = unlimited)
+    pub max_sessions: usize,
+    /// Encrypt scrollback written to disk (session archives) with a
+    /// per-host key (see [`crate::vault`])
+    pub encrypt_scrollback: bool,
+    /// Keep exited sessions (final output, exit code, history) this long
+    /// before the reaper removes them (seconds, 0 = remove at once)
+    pub exited_retention_secs: u64,
+    /// What the reaper does with damaged sessions
+    pub damaged: DamagedPolicy,
+    /// Where sessions with output logging on write their logs (default
+    /// `~/.phantom/logs/sessions`, see [`crate::output_log`]). The logs are
+    /// plain text, so logging is off unless `encrypt_scrollback` is too.
+    pub output_log_dir: Option<PathBuf>,
+    /// Rotate a session's output log at this size (bytes)
+    pub output_log_max_bytes: u64,
+    /// Rotated output logs kept per session
+    pub output_log_keep: usize,
+    /// What startup does with shells a crashed daemon left running (see
+    /// [`crate::orphans`])
+    pub orphans: OrphanPolicy,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DamagedPolicy {
+    /// Destroy them once detached
+    #[default]
+    Destroy,
+    /// Keep them, refused for attach, until destroyed by hand, so their
+    /// scrollback and history can be inspected or exported
+    Quarantine,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OrphanPolicy {
+    /// Hang up their process groups, then kill them
+    #[default]
+    Kill,
+    /// Leave them running; their terminals are gone, so they can't be
+    /// reattached
+    Keep,
 }
 
 impl Default for SessionConfig {
@@ -91,10 +376,304 @@ impl Default for SessionConfig {
         Self {
             scrollback_bytes: 65536,
             reaper_interval_secs: 5,
+            prewarm: 0,
+            max_sessions: 0,
+            encrypt_scrollback: true,
+            exited_retention_secs: 0,
+            damaged: DamagedPolicy::Destroy,
+            output_log_dir: None,
+            output_log_max_bytes: 10 * 1024 * 1024,
+            output_log_keep: 3,
+            orphans: OrphanPolicy::Kill,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct BridgeConfig {
+    /// Default output coalescing window (milliseconds, 0 = disabled).
+    /// Clients may override per attach with `coalesce_ms`.
+    pub coalesce_ms: u64,
+    /// What to do with frame types this daemon doesn't know
+    pub unknown_frames: UnknownFramePolicy,
+    /// Detach a client that answers heartbeats but has gone silent for this
+    /// long (seconds, 0 = leave it to the QUIC idle timeout)
+    pub liveness_timeout_secs: u64,
+    /// Input a client may type into a session
+    pub input_limit: InputLimit,
+    /// Input a restricted device may type into a session
+    pub restricted_input_limit: InputLimit,
+    /// Close a stream opened after auth that sends no request this long
+    /// (seconds, 0 = never)
+    pub stream_first_message_secs: u64,
+    /// Close such a stream once it has sent no request for this long
+    /// (seconds, 0 = never). Attached streams aren't idle
+    pub stream_idle_secs: u64,
+    /// Streams a connection may have served at once besides its control
+    /// stream; more are refused with `LIMIT_EXCEEDED` (0 = unlimited)
+    pub max_streams: usize,
+    /// Take the PTY reader for hung when it has stayed blocked this long
+    /// while the PTY has output waiting (seconds, 0 = never): read with a
+    /// new one. Off by default
+    pub read_stall_secs: u64,
+    /// Send output uncompressed without trying when a sample of it has
+    /// this much entropy (bits per byte, 0 = always try): it's compressed
+    /// already, like a PNG or tarball catted to the terminal
+    pub compression_bypass_entropy: f64,
+}
+
+impl Default for BridgeConfig {
+    fn default() -> Self {
+        Self {
+            coalesce_ms: 0,
+            unknown_frames: UnknownFramePolicy::Skip,
+            liveness_timeout_secs: 25,
+            input_limit: InputLimit::default(),
+            restricted_input_limit: InputLimit {
+                bytes_per_sec: 64 * 1024,
+                frames_per_sec: 200,
+                queue_bytes: 64 * 1024,
+            },
+            stream_first_message_secs: 10,
+            stream_idle_secs: 300,
+            max_streams: 16,
+            read_stall_secs: 0,
+            compression_bypass_entropy: phantom_frame::DEFAULT_BYPASS_ENTROPY,
+        }
+    }
+}
+
+/// Rate limit on a client's input (see [`crate::input_limit`]).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct InputLimit {
+    /// Bytes a second (0 = unlimited)
+    pub bytes_per_sec: u64,
+    /// Data and Paste frames a second (0 = unlimited)
+    pub frames_per_sec: u64,
+    /// Input held back over the limit before more is dropped (bytes)
+    pub queue_bytes: usize,
+}
+
+impl Default for InputLimit {
+    fn default() -> Self {
+        Self {
+            bytes_per_sec: 1024 * 1024,
+            frames_per_sec: 2000,
+            queue_bytes: 256 * 1024,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UnknownFramePolicy {
+    /// Log and skip them by their declared length, keeping the session
+    /// attached across client/daemon protocol skew
+    #[default]
+    Skip,
+    /// End the bridge, as before extension frames existed
+    Close,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct MemoryConfig {
+    /// Cap on session scrollback plus bridge buffers, in bytes (0 = unlimited).
+    /// New sessions and attaches are rejected once it is reached.
+    pub max_bytes: usize,
+    /// Usage (percent of max_bytes) above which detached sessions'
+    /// scrollback is compressed
+    pub pressure_percent: u8,
+}
+
+impl Default for MemoryConfig {
+    fn default() -> Self {
+        Self {
+            max_bytes: 256 * 1024 * 1024,
+            pressure_percent: 75,
+        }
+    }
+}
+
+/// Commands or webhook URLs run on daemon events (see [`crate::hooks`]).
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct HooksConfig {
+    pub session_created: Vec<String>,
+    pub session_exited: Vec<String>,
+    pub device_paired: Vec<String>,
+    pub auth_failed: Vec<String>,
+    pub session_reaped: Vec<String>,
+    pub session_quarantined: Vec<String>,
+    pub pairing_probed: Vec<String>,
+    /// Hooks still running after this long are abandoned
+    pub timeout_secs: u64,
+}
+
+impl Default for HooksConfig {
+    fn default() -> Self {
+        Self {
+            session_created: Vec::new(),
+            session_exited: Vec::new(),
+            device_paired: Vec::new(),
+            auth_failed: Vec::new(),
+            session_reaped: Vec::new(),
+            session_quarantined: Vec::new(),
+            pairing_probed: Vec::new(),
+            timeout_secs: 10,
+        }
+    }
+}
+
+/// Clipboard pushed from clients to the host (see [`crate::clipboard`]).
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ClipboardConfig {
+    /// Let paired devices set the host clipboard
+    pub allow_push: bool,
+    /// Largest accepted clipboard text in bytes
+    pub max_bytes: usize,
+    /// Command that reads the new clipboard contents on stdin (default:
+    /// pbcopy on macOS; wl-copy, xclip or xsel elsewhere)
+    pub command: Option<String>,
+}
+
+impl Default for ClipboardConfig {
+    fn default() -> Self {
+        Self {
+            allow_push: false,
+            max_bytes: 1024 * 1024,
+            command: None,
+        }
+    }
+}
+
+/// Shared system daemon serving several users (see [`crate::users`]).
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct SystemConfig {
+    /// Run as root, spawn each device's sessions as the user it was paired
+    /// for, and show devices only their own user's sessions
+    pub multi_user: bool,
+}
+
+/// Keeping the host awake while the daemon serves (see [`crate::power`]).
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct PowerConfig {
+    /// Prevent idle system sleep
+    pub prevent_system_sleep: bool,
+    /// Also keep the display on
+    pub prevent_display_sleep: bool,
+    /// Also prevent idle disk spin-down
+    pub prevent_disk_sleep: bool,
+    /// When to hold the assertions: always, while any session is alive, or
+    /// only while a client is attached
+    pub when: PowerWhen,
+}
+
+impl Default for PowerConfig {
+    fn default() -> Self {
+        Self {
+            prevent_system_sleep: true,
+            prevent_display_sleep: false,
+            prevent_disk_sleep: false,
+            when: PowerWhen::Always,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PowerWhen {
+    /// For as long as the daemon runs
+    #[default]
+    Always,
+    /// While at least one session is alive
+    Sessions,
+    /// While a client is attached to a session
+    Attached,
+}
+
+/// Waking the host from sleep on demand (see [`crate::wake`]).
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct WakeConfig {
+    /// Register the daemon over Bonjour, so with "Wake for network access"
+    /// the network's sleep proxy answers for the sleeping host and wakes it
+    pub advertise: bool,
+    /// `host:port` of a `phantom wake-relay` on the host's LAN, for clients
+    /// to send Wake-on-LAN packets to from elsewhere
+    pub relay: Option<String>,
+}
+
+impl Default for WakeConfig {
+    fn default() -> Self {
+        Self { advertise: true, relay: None }
+    }
+}
+
+/// What apps are told about versions after auth, in `version_advisory`.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct ClientsConfig {
+    /// Oldest app version this daemon supports (e.g. `2.3.0`), so older
+    /// apps can ask to be updated instead of failing mid-session
+    pub min_version: Option<String>,
+    /// What changed in this daemon, for apps to show
+    pub release_notes: Option<String>,
+}
+
+/// Protocol versions offered to clients over ALPN (see [`crate::tls`]).
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ProtocolConfig {
+    /// Advertised versions, most preferred first (`[[protocol.alpn]]`).
+    /// A client offering several gets the first one listed here
+    pub alpn: Vec<AlpnVersion>,
+}
+
+impl Default for ProtocolConfig {
+    fn default() -> Self {
+        Self {
+            alpn: vec![AlpnVersion {
+                name: format!("phantom/{}", crate::PROTOCOL_VERSION),
+                policy: AlpnPolicy::Accept,
+            }],
         }
     }
 }
 
+impl ProtocolConfig {
+    /// How connections that negotiated `alpn` are handled; `None` for a
+    /// protocol that isn't listed.
+    pub fn policy(&self, alpn: &str) -> Option<AlpnPolicy> {
+        self.alpn.iter().find(|v| v.name == alpn).map(|v| v.policy)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct AlpnVersion {
+    /// ALPN protocol ID, e.g. `phantom/1`
+    pub name: String,
+    #[serde(default)]
+    pub policy: AlpnPolicy,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AlpnPolicy {
+    #[default]
+    Accept,
+    /// Accept, with a warning in the log for each connection
+    Deprecated,
+    /// Complete the handshake, then close the connection with a reason
+    /// the client can show, instead of failing it without one
+    Reject,
+}
+
 impl DaemonConfig {
     pub fn load(phantom_dir: &Path) -> Self {
         let path = phantom_dir.join("config.toml");
@@ -113,4 +692,13 @@ impl DaemonConfig {
         }
         Self::default()
     }
+
+    /// Read the config at `path`, failing if it's missing or invalid: a
+    /// file named on the command line isn't quietly replaced by defaults.
+    pub fn load_file(path: &Path) -> anyhow::Result<Self> {
+        use anyhow::Context;
+
+        let contents = std::fs::read_to_string(path).with_context(|| format!("read {}", path.display()))?;
+        toml::from_str(&contents).with_context(|| format!("parse {}", path.display()))
+    }
 }
@@ -0,0 +1,199 @@
+//! Live QUIC connections, each under a short ID assigned at accept. The ID
+//! tags the connection's log lines (a `conn` span) and audit records, and
+//! `list_connections` reports it with the device, remote address, age, open
+//! streams and bytes moved, so a log line can be traced to a device.
+//!
+//! The daemon closes connections with a [`CloseCode`] (see [`close`]), which
+//! `server_info` lists for clients to explain disconnects with.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use phantom_frame::CloseCode;
+
+tokio::task_local! {
+    static CURRENT: String;
+}
+
+/// The ID of the connection the calling task serves, if any.
+pub fn current_id() -> Option<String> {
+    CURRENT.try_with(Clone::clone).ok()
+}
+
+/// Run `future` as serving connection `id`, for [`current_id`].
+pub async fn scope<F: Future>(id: String, future: F) -> F::Output {
+    CURRENT.scope(id, future).await
+}
+
+/// Close `connection` with `code`, and `reason` for the peer's logs.
+pub fn close(connection: &quinn::Connection, code: CloseCode, reason: &[u8]) {
+    connection.close(quinn::VarInt::from_u32(code.code()), reason);
+}
+
+/// The close codes, for `server_info`.
+pub fn close_codes() -> Vec<serde_json::Value> {
+    CloseCode::ALL
+        .iter()
+        .map(|c| serde_json::json!({"code": c.code(), "name": c.name(), "description": c.description()}))
+        .collect()
+}
+
+#[derive(Default)]
+pub struct Connections {
+    live: Mutex<HashMap<String, Live>>,
+}
+
+struct Live {
+    remote: SocketAddr,
+    opened: Instant,
+    /// Set once the QUIC handshake completes
+    connection: Option<quinn::Connection>,
+    /// Set once the peer authenticates
+    peer_id: Option<String>,
+    streams: Arc<AtomicUsize>,
+}
+
+/// Listed until dropped.
+pub struct ConnectionHandle {
+    connections: Arc<Connections>,
+    id: String,
+    streams: Arc<AtomicUsize>,
+}
+
+/// Counts an open stream until dropped.
+pub struct StreamGuard(Arc<AtomicUsize>);
+
+/// One entry of `list_connections`.
+#[derive(Debug, serde::Serialize)]
+pub struct ConnectionSummary {
+    pub id: String,
+    /// Device or guest, once authenticated
+    pub peer_id: Option<String>,
+    pub remote: SocketAddr,
+    pub age_secs: u64,
+    pub streams: usize,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+}
+
+impl Connections {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// List a connection accepted from `remote` under a new ID.
+    pub fn open(self: &Arc<Self>, remote: SocketAddr) -> ConnectionHandle {
+        let mut live = self.live.lock().expect("connections lock");
+        let id = loop {
+            let id_bytes: [u8; 3] = rand::Rng::gen(&mut rand::thread_rng());
+            let id = id_bytes.iter().map(|b| format!("{b:02x}")).collect::<String>();
+            if !live.contains_key(&id) {
+                break id;
+            }
+        };
+        let streams = Arc::new(AtomicUsize::new(0));
+        let entry = Live { remote, opened: Instant::now(), connection: None, peer_id: None, streams: streams.clone() };
+        live.insert(id.clone(), entry);
+        ConnectionHandle { connections: self.clone(), id, streams }
+    }
+
+    /// Live connections, oldest first.
+    pub fn list(&self) -> Vec<ConnectionSummary> {
+        let live = self.live.lock().expect("connections lock");
+        let mut list: Vec<_> = live
+            .iter()
+            .map(|(id, conn)| {
+                let stats = conn.connection.as_ref().map(quinn::Connection::stats);
+                ConnectionSummary {
+                    id: id.clone(),
+                    peer_id: conn.peer_id.clone(),
+                    remote: conn.remote,
+                    age_secs: conn.opened.elapsed().as_secs(),
+                    streams: conn.streams.load(Ordering::Relaxed),
+                    bytes_sent: stats.as_ref().map_or(0, |s| s.udp_tx.bytes),
+                    bytes_received: stats.as_ref().map_or(0, |s| s.udp_rx.bytes),
+                }
+            })
+            .collect();
+        list.sort_by(|a, b| b.age_secs.cmp(&a.age_secs).then_with(|| a.id.cmp(&b.id)));
+        list
+    }
+
+    fn update(&self, id: &str, f: impl FnOnce(&mut Live)) {
+        if let Some(conn) = self.live.lock().expect("connections lock").get_mut(id) {
+            f(conn);
+        }
+    }
+}
+
+impl ConnectionHandle {
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// The handshake completed: count its traffic from now on.
+    pub fn established(&self, connection: &quinn::Connection) {
+        self.connections.update(&self.id, |conn| conn.connection = Some(connection.clone()));
+    }
+
+    /// The peer authenticated as `peer_id`.
+    pub fn authenticated(&self, peer_id: &str) {
+        self.connections.update(&self.id, |conn| conn.peer_id = Some(peer_id.to_string()));
+    }
+
+    /// Count a stream open on this connection until the guard is dropped.
+    pub fn stream(&self) -> StreamGuard {
+        self.streams.fetch_add(1, Ordering::Relaxed);
+        StreamGuard(self.streams.clone())
+    }
+}
+
+impl Drop for ConnectionHandle {
+    fn drop(&mut self) {
+        self.connections.live.lock().expect("connections lock").remove(&self.id);
+    }
+}
+
+impl Drop for StreamGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lists_connections_until_dropped() {
+        let connections = Arc::new(Connections::new());
+        let first = connections.open("10.0.0.1:5000".parse().unwrap());
+        let second = connections.open("10.0.0.2:5000".parse().unwrap());
+        assert_ne!(first.id(), second.id());
+        assert_eq!(first.id().len(), 6);
+
+        second.authenticated("phone");
+        let stream = second.stream();
+        let _control = second.stream();
+        drop(stream);
+        let list = connections.list();
+        let listed = list.iter().find(|c| c.id == second.id()).unwrap();
+        assert_eq!(listed.peer_id.as_deref(), Some("phone"));
+        assert_eq!(listed.streams, 1);
+        assert_eq!(listed.bytes_sent, 0);
+
+        drop(first);
+        assert_eq!(connections.list().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn tags_tasks_with_their_connection() {
+        assert_eq!(current_id(), None);
+        let id = scope("abc123".to_string(), async { current_id() }).await;
+        assert_eq!(id.as_deref(), Some("abc123"));
+    }
+}
@@ -0,0 +1,141 @@
+//! Idempotent control requests.
+//!
+//! On a flaky link a client may resend `create_session` after a timeout and
+//! end up with two shells. The daemon remembers the responses to recent
+//! requests per device, message type and `request_id`, and answers a resend within
+//! [`WINDOW`] with the original response instead of running it again. A
+//! resend that arrives while the original is still running waits for it.
+//! Failed requests aren't remembered, so resending one retries it.
+
+use anyhow::Result;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+
+use crate::errors::ErrorCode;
+
+/// How long a response is kept for resends.
+pub const WINDOW: Duration = Duration::from_secs(120);
+
+/// Longest a resend waits for the original request to finish.
+const IN_FLIGHT_WAIT: Duration = Duration::from_secs(30);
+
+/// (device id, message type, request id): an id reused for another kind of
+/// request is a new request, not a resend
+type Key = (String, String, String);
+
+#[derive(Default)]
+pub struct RecentRequests {
+    entries: Mutex<HashMap<Key, Entry>>,
+}
+
+struct Entry {
+    at: Instant,
+    /// None while the original request is running
+    response: watch::Receiver<Option<serde_json::Value>>,
+}
+
+pub enum Claim<'a> {
+    /// First time this request is seen: run it, then [`Pending::complete`]
+    New(Pending<'a>),
+    /// A resend: the original response
+    Replay(serde_json::Value),
+}
+
+impl RecentRequests {
+    /// Claim `device_id`'s `msg_type` request `request_id`, or get the
+    /// response it already had.
+    pub async fn claim<'a>(&'a self, device_id: &str, msg_type: &str, request_id: &str) -> Result<Claim<'a>> {
+        let key = (device_id.to_string(), msg_type.to_string(), request_id.to_string());
+        loop {
+            let mut response = {
+                let mut entries = self.entries.lock().expect("recent requests lock");
+                let now = Instant::now();
+                entries.retain(|_, e| e.response.borrow().is_none() || now.duration_since(e.at) < WINDOW);
+                match entries.get(&key) {
+                    Some(entry) => entry.response.clone(),
+                    None => {
+                        let (tx, response) = watch::channel(None);
+                        entries.insert(key.clone(), Entry { at: now, response });
+                        return Ok(Claim::New(Pending { requests: self, key, tx, done: false }));
+                    }
+                }
+            };
+            let done = tokio::time::timeout(IN_FLIGHT_WAIT, response.wait_for(Option::is_some))
+                .await
+                .map(|done| done.map(|done| done.clone()));
+            match done {
+                Ok(Ok(done)) => return Ok(Claim::Replay(done.expect("completed response"))),
+                // The original failed and was forgotten: run this one instead
+                Ok(Err(_)) => continue,
+                Err(_) => return Err(ErrorCode::Unavailable.err("original request is still running; retry later")),
+            }
+        }
+    }
+
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.entries.lock().expect("recent requests lock").len()
+    }
+}
+
+/// A claimed request. Dropped without completing (it failed), it is
+/// forgotten so a resend runs again.
+pub struct Pending<'a> {
+    requests: &'a RecentRequests,
+    key: Key,
+    tx: watch::Sender<Option<serde_json::Value>>,
+    done: bool,
+}
+
+impl Pending<'_> {
+    /// Remember `response` for resends.
+    pub fn complete(mut self, response: &serde_json::Value) {
+        self.tx.send_replace(Some(response.clone()));
+        self.done = true;
+    }
+}
+
+impl Drop for Pending<'_> {
+    fn drop(&mut self) {
+        if !self.done {
+            self.requests.entries.lock().expect("recent requests lock").remove(&self.key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn resends_get_the_original_response() {
+        let requests = RecentRequests::default();
+        let Claim::New(pending) = requests.claim("phone", "create_session", "r1").await.unwrap() else {
+            panic!("first claim is new");
+        };
+        // Another device may reuse the id
+        assert!(matches!(requests.claim("tablet", "create_session", "r1").await.unwrap(), Claim::New(_)));
+
+        let resend = async { requests.claim("phone", "create_session", "r1").await.unwrap() };
+        let complete = async { pending.complete(&serde_json::json!({"session_id": "abc"})) };
+        let (claim, ()) = tokio::join!(resend, complete);
+        let Claim::Replay(response) = claim else { panic!("resend replays") };
+        assert_eq!(response["session_id"], "abc");
+
+        // The same id on another kind of request isn't a resend of it
+        assert!(matches!(requests.claim("phone", "destroy_session", "r1").await.unwrap(), Claim::New(_)));
+    }
+
+    #[tokio::test]
+    async fn failed_requests_are_forgotten() {
+        let requests = RecentRequests::default();
+        let Claim::New(pending) = requests.claim("phone", "create_session", "r1").await.unwrap() else {
+            panic!("first claim is new");
+        };
+        drop(pending);
+        assert_eq!(requests.len(), 0);
+        assert!(matches!(requests.claim("phone", "create_session", "r1").await.unwrap(), Claim::New(_)));
+    }
+}
@@ -1,12 +1,21 @@
-use anyhow::{bail, Context, Result};
+use anyhow::{Context, Result};
 use chrono::{DateTime, Utc};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::fs;
-use std::path::PathBuf;
+use std::io::Write;
+use std::path::{Path, PathBuf};
 use std::sync::Mutex;
 use tracing::{info, warn};
 
+use crate::addresses::{self, Candidate};
+use crate::clock::{self, SharedClock};
+use crate::errors::ErrorCode;
+use crate::restrictions::Restrictions;
+use crate::wake::WakeInfo;
+use crate::webauthn::WebAuthnCredential;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PairedDevice {
     pub device_id: String,
@@ -14,19 +23,82 @@ pub struct PairedDevice {
     pub device_name: String,
     pub paired_at: DateTime<Utc>,
     pub last_seen: Option<DateTime<Utc>>,
+    /// May forward its SSH agent into sessions it creates
+    #[serde(default)]
+    pub agent_forwarding: bool,
+    /// User its sessions run as, in multi-user mode
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    /// Limits for a low-trust device
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub restrictions: Option<Restrictions>,
+    /// Device that approved pairing this one, when it wasn't paired with
+    /// a token
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub vouched_by: Option<String>,
+    /// Hardware key `public_key` belongs to, when paired with one: it is
+    /// challenged for WebAuthn assertions instead of plain signatures
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub webauthn: Option<WebAuthnCredential>,
+}
+
+/// A pairing token: single-use, valid for 5 minutes.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PairingToken {
+    /// Unix seconds
+    pub expires_at: u64,
+    /// User the device being paired is for (multi-user mode)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    /// Wrong tokens presented since this one was issued
+    #[serde(default)]
+    pub wrong_guesses: u32,
+}
+
+/// Wrong pairing tokens an outstanding token survives; the next withdraws it.
+pub const PAIRING_GUESSES_MAX: u32 = 5;
+
+/// What a wrong pairing token did to the tokens outstanding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PairingMiss {
+    /// Tokens still valid afterwards
+    pub outstanding: usize,
+    /// Tokens withdrawn for having seen too many wrong guesses
+    pub withdrawn: usize,
+}
+
+/// A guest share link: single-use, read-only access to one session.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GuestToken {
+    pub session_id: String,
+    /// Unix seconds; the token must be redeemed, and guest access ends, by then
+    pub expires_at: u64,
 }
 
+/// Guest link lifetime when none is requested.
+pub const GUEST_TTL_DEFAULT: std::time::Duration = std::time::Duration::from_secs(30 * 60);
+/// Longest guest link lifetime.
+pub const GUEST_TTL_MAX: std::time::Duration = std::time::Duration::from_secs(24 * 60 * 60);
+
 #[derive(Debug, Serialize, Deserialize, Default)]
 struct DeviceStoreData {
     devices: HashMap<String, PairedDevice>,
 }
 
 /// Manages paired devices, pairing tokens, and the audit log.
+///
+/// The daemon and CLI commands (`phantom pair`, `phantom device`) each hold
+/// their own store over the same files, so every read-modify-write runs under
+/// an advisory lock on `store.lock` and starts from what is on disk.
 pub struct DeviceStore {
     data: Mutex<DeviceStoreData>,
     store_path: PathBuf,
     audit_path: PathBuf,
     token_path: PathBuf,
+    guest_token_path: PathBuf,
+    lock_path: PathBuf,
+    /// What token expiry is stamped and checked by
+    clock: SharedClock,
 }
 
 impl DeviceStore {
@@ -34,103 +106,194 @@ impl DeviceStore {
         let store_path = phantom_dir.join("devices.json");
         let audit_path = phantom_dir.join("auth.log");
 
-        let data = if store_path.exists() {
-            let contents = fs::read_to_string(&store_path)
-                .context("read devices.json")?;
-            serde_json::from_str(&contents).context("parse devices.json")?
-        } else {
-            DeviceStoreData::default()
-        };
+        let data = load_devices(&store_path)?;
 
         info!("loaded {} paired device(s)", data.devices.len());
 
         let token_path = phantom_dir.join("pairing_tokens.json");
+        let guest_token_path = phantom_dir.join("guest_tokens.json");
+        let lock_path = phantom_dir.join("store.lock");
 
         Ok(Self {
             data: Mutex::new(data),
             store_path,
             audit_path,
             token_path,
+            guest_token_path,
+            lock_path,
+            clock: clock::system(),
         })
     }
 
-    fn persist(&self) -> Result<()> {
-        let data = self.data.lock().expect("device store lock");
+    /// Read time from `clock` instead of the system's.
+    pub fn with_clock(self, clock: SharedClock) -> Self {
+        Self { clock, ..self }
+    }
+
+    /// Take the cross-process store lock. Released when the file is dropped.
+    fn lock_files(&self) -> Result<fs::File> {
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&self.lock_path)
+            .context("open store.lock")?;
+        file.lock().context("lock store.lock")?;
+        Ok(file)
+    }
+
+    /// Apply `f` to the device set under the store lock: reload devices.json
+    /// (another process may have changed it), mutate, and persist.
+    fn update_devices<R>(&self, f: impl FnOnce(&mut DeviceStoreData) -> Result<R>) -> Result<R> {
+        let _lock = self.lock_files()?;
+        let mut data = self.data.lock().expect("device store lock");
+        *data = load_devices(&self.store_path)?;
+        let result = f(&mut data)?;
         let json = serde_json::to_string_pretty(&*data)
             .context("serialize devices")?;
-        fs::write(&self.store_path, json).context("write devices.json")?;
-        Ok(())
+        write_atomic(&self.store_path, json.as_bytes()).context("write devices.json")?;
+        Ok(result)
     }
 
-    /// Generate a single-use pairing token valid for 5 minutes.
+    /// Generate a single-use pairing token valid for 5 minutes, for a device
+    /// whose sessions run as `user` (multi-user mode).
     /// Tokens are stored on disk so `phantom pair` and `phantom daemon` share them.
-    pub fn create_pairing_token(&self) -> String {
+    pub fn create_pairing_token(&self, user: Option<&str>) -> String {
         use base64::Engine;
         let token_bytes: [u8; 32] = rand::Rng::gen(&mut rand::thread_rng());
         let token = base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(token_bytes);
-        let expiry_epoch = (std::time::SystemTime::now()
-            + std::time::Duration::from_secs(300))
-            .duration_since(std::time::UNIX_EPOCH)
-            .unwrap()
-            .as_secs();
+        let grant = PairingToken {
+            expires_at: self.clock.unix_secs() + 300,
+            user: user.map(str::to_string),
+            wrong_guesses: 0,
+        };
 
-        let mut tokens = self.load_tokens();
-        tokens.insert(token.clone(), expiry_epoch);
-        self.save_tokens(&tokens);
+        self.update_tokens(|tokens| {
+            tokens.insert(token.clone(), grant);
+        });
 
         token
     }
 
     /// Validate and consume a pairing token (single-use).
-    pub fn validate_pairing_token(&self, token: &str) -> Result<bool> {
-        let mut tokens = self.load_tokens();
-        let now = std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .unwrap()
-            .as_secs();
-
-        // Prune expired tokens
-        tokens.retain(|_, &mut exp| exp > now);
-
-        if let Some(expiry) = tokens.remove(token) {
-            self.save_tokens(&tokens);
-            Ok(expiry > now)
-        } else {
-            self.save_tokens(&tokens);
-            Ok(false)
-        }
+    pub fn redeem_pairing_token(&self, token: &str) -> Option<PairingToken> {
+        // Expired tokens are pruned on load, so any remaining match is valid
+        self.update_tokens(|tokens| tokens.remove(token))
     }
 
-    fn load_tokens(&self) -> HashMap<String, u64> {
-        let mut tokens: HashMap<String, u64> = fs::read_to_string(&self.token_path)
-            .ok()
-            .and_then(|s| serde_json::from_str(&s).ok())
-            .unwrap_or_default();
-        // Prune expired tokens on every load
-        let now = std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .unwrap()
-            .as_secs();
-        let before = tokens.len();
-        tokens.retain(|_, &mut exp| exp > now);
-        if tokens.len() < before {
-            self.save_tokens(&tokens);
-        }
-        tokens
+    /// Record a wrong pairing token from `device_id` at `remote` in the audit
+    /// log, and count it against every outstanding token (a guess can't be
+    /// told from a typo in any one of them): those it takes past
+    /// [`PAIRING_GUESSES_MAX`] are withdrawn, so guessing is bounded however
+    /// many addresses and device IDs it comes from.
+    pub fn record_pairing_miss(&self, device_id: &str, remote: &str) -> PairingMiss {
+        self.append_audit(device_id, &format!("pair_fail\t{remote}"));
+        self.update_tokens(|tokens| {
+            let before = tokens.len();
+            tokens.retain(|_, t| {
+                t.wrong_guesses += 1;
+                t.wrong_guesses <= PAIRING_GUESSES_MAX
+            });
+            PairingMiss { outstanding: tokens.len(), withdrawn: before - tokens.len() }
+        })
+    }
+
+    /// Expiry of the longest-lived unused pairing token, i.e. when the
+    /// pairing currently in progress (if any) runs out. Doesn't take the
+    /// store lock or rewrite the token file, so it is cheap to poll.
+    pub fn pairing_expires_at(&self) -> Option<chrono::DateTime<chrono::Utc>> {
+        load_token_file(&self.token_path, self.clock.unix_secs(), |t: &PairingToken| t.expires_at)
+            .into_values()
+            .map(|t| t.expires_at)
+            .max()
+            .and_then(|secs| chrono::DateTime::from_timestamp(secs as i64, 0))
     }
 
-    fn save_tokens(&self, tokens: &HashMap<String, u64>) {
-        if let Ok(json) = serde_json::to_string(tokens) {
-            let _ = fs::write(&self.token_path, json);
+    /// Apply `f` to the pairing tokens under the store lock: load (pruning
+    /// expired tokens), mutate, and save.
+    fn update_tokens<R>(&self, f: impl FnOnce(&mut HashMap<String, PairingToken>) -> R) -> R {
+        self.update_token_file(&self.token_path, |t: &PairingToken| t.expires_at, f)
+    }
+
+    /// Apply `f` to the token map in `path` under the store lock, pruning
+    /// entries whose `expiry` has passed.
+    fn update_token_file<V, R>(
+        &self,
+        path: &Path,
+        expiry: impl Fn(&V) -> u64,
+        f: impl FnOnce(&mut HashMap<String, V>) -> R,
+    ) -> R
+    where
+        V: Serialize + DeserializeOwned,
+    {
+        let _lock = self
+            .lock_files()
+            .inspect_err(|e| warn!("{} updated without lock: {e:#}", path.display()));
+        let mut tokens = load_token_file(path, self.clock.unix_secs(), expiry);
+        let result = f(&mut tokens);
+        if let Ok(json) = serde_json::to_string(&tokens) {
+            if let Err(e) = write_atomic(path, json.as_bytes()) {
+                warn!("failed to write {}: {e}", path.display());
+            }
         }
+        result
+    }
+
+    /// Mint a single-use guest token for read-only access to `session_id`,
+    /// valid for `ttl` (capped at [`GUEST_TTL_MAX`]).
+    pub fn create_guest_token(&self, session_id: &str, ttl: std::time::Duration) -> String {
+        use base64::Engine;
+        let token_bytes: [u8; 32] = rand::Rng::gen(&mut rand::thread_rng());
+        let token = base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(token_bytes);
+        let grant = GuestToken {
+            session_id: session_id.to_string(),
+            expires_at: self.clock.unix_secs() + ttl.min(GUEST_TTL_MAX).as_secs(),
+        };
+
+        self.update_token_file(&self.guest_token_path, |g: &GuestToken| g.expires_at, |tokens| {
+            tokens.insert(token.clone(), grant);
+        });
+        self.append_audit(&format!("guest:{session_id}"), "guest_link");
+
+        token
+    }
+
+    /// Validate and consume a guest token (single-use).
+    pub fn redeem_guest_token(&self, token: &str) -> Option<GuestToken> {
+        self.update_token_file(&self.guest_token_path, |g: &GuestToken| g.expires_at, |tokens| {
+            tokens.remove(token)
+        })
+    }
+
+    /// Record a guest authentication attempt in the audit log.
+    pub fn record_guest_auth(&self, guest_id: &str, success: bool) {
+        let action = if success { "guest_ok" } else { "guest_fail" };
+        self.append_audit(guest_id, action);
     }
 
-    /// Add a newly paired device.
+    /// Add a newly paired device, for `user` in multi-user mode, approved by
+    /// the paired device `vouched_by` if it didn't present a pairing token.
     pub fn add_device(
         &self,
         device_id: &str,
         public_key: &str,
         device_name: &str,
+        user: Option<&str>,
+        vouched_by: Option<&str>,
+    ) -> Result<()> {
+        self.add_device_with(device_id, public_key, device_name, user, vouched_by, None)
+    }
+
+    /// [`add_device`](Self::add_device) for a device whose key is the
+    /// WebAuthn credential `webauthn`, if given.
+    pub fn add_device_with(
+        &self,
+        device_id: &str,
+        public_key: &str,
+        device_name: &str,
+        user: Option<&str>,
+        vouched_by: Option<&str>,
+        webauthn: Option<WebAuthnCredential>,
     ) -> Result<()> {
         let device = PairedDevice {
             device_id: device_id.to_string(),
@@ -138,16 +301,18 @@ impl DeviceStore {
             device_name: device_name.to_string(),
             paired_at: Utc::now(),
             last_seen: None,
+            agent_forwarding: false,
+            user: user.map(str::to_string),
+            restrictions: None,
+            vouched_by: vouched_by.map(str::to_string),
+            webauthn,
         };
 
-        self.data
-            .lock()
-            .expect("device store lock")
-            .devices
-            .insert(device_id.to_string(), device);
-
-        self.persist()?;
-        self.append_audit(device_id, "pair");
+        self.update_devices(|data| {
+            data.devices.insert(device_id.to_string(), device);
+            Ok(())
+        })?;
+        self.append_audit(device_id, if vouched_by.is_some() { "pair_vouched" } else { "pair" });
         Ok(())
     }
 
@@ -157,7 +322,77 @@ impl DeviceStore {
         data.devices
             .get(device_id)
             .map(|d| d.public_key.clone())
-            .context("device not paired")
+            .ok_or_else(|| ErrorCode::NotPaired.err("device not paired"))
+    }
+
+    /// The WebAuthn credential a device was paired with, if any.
+    pub fn webauthn_credential(&self, device_id: &str) -> Option<WebAuthnCredential> {
+        let data = self.data.lock().expect("device store lock");
+        data.devices.get(device_id).and_then(|d| d.webauthn.clone())
+    }
+
+    /// Keep the signature counter of a WebAuthn device's latest assertion.
+    pub fn record_sign_count(&self, device_id: &str, sign_count: u32) -> Result<()> {
+        self.update_devices(|data| {
+            if let Some(credential) = data.devices.get_mut(device_id).and_then(|d| d.webauthn.as_mut()) {
+                credential.sign_count = sign_count;
+            }
+            Ok(())
+        })
+    }
+
+    /// Whether a device may forward its SSH agent.
+    pub fn agent_forwarding_allowed(&self, device_id: &str) -> bool {
+        let data = self.data.lock().expect("device store lock");
+        data.devices.get(device_id).is_some_and(|d| d.agent_forwarding)
+    }
+
+    /// The user a device was paired for.
+    pub fn device_user(&self, device_id: &str) -> Option<String> {
+        let data = self.data.lock().expect("device store lock");
+        data.devices.get(device_id).and_then(|d| d.user.clone())
+    }
+
+    /// Grant or withdraw a device's SSH agent forwarding permission. Takes
+    /// effect the next time the device connects.
+    pub fn set_agent_forwarding(&self, device_id: &str, allowed: bool) -> Result<()> {
+        self.update_devices(|data| {
+            let device = data
+                .devices
+                .get_mut(device_id)
+                .ok_or_else(|| ErrorCode::NotFound.err(format!("device {device_id} not found")))?;
+            device.agent_forwarding = allowed;
+            Ok(())
+        })?;
+        self.append_audit(device_id, if allowed { "agent_allow" } else { "agent_deny" });
+        info!("agent forwarding {} for device {device_id}", if allowed { "allowed" } else { "denied" });
+        Ok(())
+    }
+
+    /// A device's restrictions, if it is restricted.
+    pub fn device_restrictions(&self, device_id: &str) -> Option<Restrictions> {
+        let data = self.data.lock().expect("device store lock");
+        data.devices.get(device_id).and_then(|d| d.restrictions.clone())
+    }
+
+    /// Restrict a device, or lift its restrictions with None. Takes effect
+    /// the next time the device connects.
+    pub fn set_restrictions(&self, device_id: &str, restrictions: Option<Restrictions>) -> Result<()> {
+        if let Some(restrictions) = &restrictions {
+            restrictions.validate().map_err(|e| ErrorCode::BadRequest.err(format!("{e:#}")))?;
+        }
+        let restricted = restrictions.is_some();
+        self.update_devices(|data| {
+            let device = data
+                .devices
+                .get_mut(device_id)
+                .ok_or_else(|| ErrorCode::NotFound.err(format!("device {device_id} not found")))?;
+            device.restrictions = restrictions;
+            Ok(())
+        })?;
+        self.append_audit(device_id, if restricted { "restrict" } else { "unrestrict" });
+        info!("device {device_id} {}", if restricted { "restricted" } else { "unrestricted" });
+        Ok(())
     }
 
     /// Record an authentication attempt in the audit log.
@@ -166,12 +401,12 @@ impl DeviceStore {
         self.append_audit(device_id, action);
 
         if success {
-            if let Ok(mut data) = self.data.lock() {
+            let _ = self.update_devices(|data| {
                 if let Some(device) = data.devices.get_mut(device_id) {
                     device.last_seen = Some(Utc::now());
                 }
-            }
-            let _ = self.persist();
+                Ok(())
+            });
         }
     }
 
@@ -188,81 +423,489 @@ impl DeviceStore {
 
     /// Revoke (remove) a paired device.
     pub fn revoke_device(&self, device_id: &str) -> Result<()> {
-        let mut data = self.data.lock().expect("device store lock");
-        if data.devices.remove(device_id).is_none() {
-            bail!("device {device_id} not found");
-        }
-        drop(data);
-        self.persist()?;
+        self.update_devices(|data| {
+            if data.devices.remove(device_id).is_none() {
+                return Err(ErrorCode::NotFound.err(format!("device {device_id} not found")));
+            }
+            Ok(())
+        })?;
         self.append_audit(device_id, "revoke");
         info!("revoked device {device_id}");
         Ok(())
     }
 
+    /// All paired devices, for moving them to another host. Audited.
+    pub fn export_devices(&self) -> Vec<PairedDevice> {
+        let devices = self.list_devices();
+        for device in &devices {
+            self.append_audit(&device.device_id, "export");
+        }
+        devices
+    }
+
+    /// Add devices exported from another host, skipping any already paired
+    /// here. Returns the ids added.
+    pub fn import_devices(&self, devices: Vec<PairedDevice>) -> Result<Vec<String>> {
+        let added = self.update_devices(|data| {
+            let mut added = Vec::new();
+            for device in devices {
+                if data.devices.contains_key(&device.device_id) {
+                    continue;
+                }
+                added.push(device.device_id.clone());
+                data.devices.insert(device.device_id.clone(), device);
+            }
+            Ok(added)
+        })?;
+        for id in &added {
+            self.append_audit(id, "import");
+        }
+        Ok(added)
+    }
+
+    /// Record in the audit log that an operator lifted `ip`'s rate limits.
+    pub fn record_rate_limit_clear(&self, ip: &str) {
+        self.append_audit("*", &format!("rate_limit_clear\t{ip}"));
+    }
+
+    /// Revoke every device and drop all pairing and guest tokens, auditing
+    /// the reset and each revocation before anything is deleted. Returns the
+    /// revoked device ids.
+    pub fn revoke_all(&self) -> Result<Vec<String>> {
+        self.append_audit("*", "reset");
+        let revoked = self.update_devices(|data| {
+            let ids: Vec<String> = data.devices.keys().cloned().collect();
+            for id in &ids {
+                self.append_audit(id, "revoke");
+            }
+            data.devices.clear();
+            Ok(ids)
+        })?;
+        self.update_tokens(|tokens| tokens.clear());
+        self.update_token_file(&self.guest_token_path, |g: &GuestToken| g.expires_at, |tokens| tokens.clear());
+        info!("revoked all {} device(s)", revoked.len());
+        Ok(revoked)
+    }
+
     /// Generate all data needed for a pairing QR code / manual entry.
     /// Creates a new pairing token and returns the payload.
-    pub fn generate_pairing_data(&self, fingerprint: &str, port: u16) -> PairingData {
-        let token = self.create_pairing_token();
-        let host = local_ip().unwrap_or_else(|| "127.0.0.1".to_string());
+    pub fn generate_pairing_data(
+        &self,
+        fingerprint: &str,
+        port: u16,
+        user: Option<&str>,
+        wake: Option<WakeInfo>,
+    ) -> PairingData {
+        let token = self.create_pairing_token(user);
+        let hosts = addresses::candidates();
+        let host = hosts
+            .first()
+            .map(|c| c.host.clone())
+            .unwrap_or_else(|| "127.0.0.1".to_string());
         let name = hostname();
-        let qr_payload = serde_json::json!({
+        // v2 adds `hosts`, the candidates to race; `host` stays for v1 clients
+        let mut qr_payload = serde_json::json!({
             "host": host,
+            "hosts": hosts,
             "port": port,
             "fp": fingerprint,
             "tok": token,
             "name": name,
-            "v": 1,
+            "v": 2,
         });
+        if let Some(wake) = &wake {
+            qr_payload["wake"] = serde_json::json!(wake);
+        }
         PairingData {
             qr_payload_json: serde_json::to_string(&qr_payload).unwrap(),
             token,
             host,
+            hosts,
             port,
             fingerprint: fingerprint.to_string(),
             expires_in_secs: 300,
+            wake,
+        }
+    }
+
+    /// Mint a guest token for `session_id` and return everything a guest
+    /// client needs to connect.
+    pub fn generate_guest_link(
+        &self,
+        session_id: &str,
+        fingerprint: &str,
+        port: u16,
+        ttl: std::time::Duration,
+    ) -> GuestLinkData {
+        let ttl = ttl.min(GUEST_TTL_MAX);
+        let token = self.create_guest_token(session_id, ttl);
+        let hosts = addresses::candidates();
+        let host = hosts
+            .first()
+            .map(|c| c.host.clone())
+            .unwrap_or_else(|| "127.0.0.1".to_string());
+        // v2 adds `hosts`, as in the pairing payload
+        let payload = serde_json::json!({
+            "host": host,
+            "hosts": hosts,
+            "port": port,
+            "fp": fingerprint,
+            "guest": token,
+            "sid": session_id,
+            "name": hostname(),
+            "v": 2,
+        });
+        GuestLinkData {
+            payload_json: serde_json::to_string(&payload).unwrap(),
+            token,
+            session_id: session_id.to_string(),
+            host,
+            hosts,
+            port,
+            fingerprint: fingerprint.to_string(),
+            expires_in_secs: ttl.as_secs(),
         }
     }
 
+    /// Write an audit line, ending `conn=<id>` when written while serving a
+    /// connection.
     fn append_audit(&self, device_id: &str, action: &str) {
-        let line = format!(
-            "{}\t{}\t{}\n",
+        let mut line = format!(
+            "{}\t{}\t{}",
             Utc::now().to_rfc3339(),
             device_id,
             action,
         );
+        if let Some(id) = crate::connections::current_id() {
+            line.push_str(&format!("\tconn={id}"));
+        }
+        line.push('\n');
         if let Err(e) = fs::OpenOptions::new()
             .create(true)
             .append(true)
             .open(&self.audit_path)
-            .and_then(|mut f| {
-                use std::io::Write;
-                f.write_all(line.as_bytes())
-            })
+            .and_then(|mut f| f.write_all(line.as_bytes()))
         {
             warn!("failed to write audit log: {e}");
         }
     }
 }
 
+/// Load a token map, dropping entries whose `expiry` isn't after `now`.
+fn load_token_file<V: DeserializeOwned>(path: &Path, now: u64, expiry: impl Fn(&V) -> u64) -> HashMap<String, V> {
+    let mut tokens: HashMap<String, V> = read_with_backup(path, |s| {
+        serde_json::from_str(s).with_context(|| format!("parse {}", path.display()))
+    })
+    .ok()
+    .flatten()
+    .unwrap_or_default();
+    tokens.retain(|_, v| expiry(v) > now);
+    tokens
+}
+
+fn load_devices(store_path: &Path) -> Result<DeviceStoreData> {
+    let data = read_with_backup(store_path, |contents| {
+        serde_json::from_str(contents).context("parse devices.json")
+    })
+    .context("read devices.json")?;
+    Ok(data.unwrap_or_default())
+}
+
+/// `<path>.<suffix>`, e.g. `devices.json.bak`.
+pub(crate) fn sibling(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".");
+    name.push(suffix);
+    path.with_file_name(name)
+}
+
+/// Replace `path` with `contents` so that a crash leaves either the old or
+/// the new file, never a torn one: write a temp file, fsync it, rename it
+/// over the original, then fsync the directory. The previous version is kept
+/// at `<path>.bak` for [`read_with_backup`].
+pub(crate) fn write_atomic(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    let tmp = sibling(path, "tmp");
+    {
+        let mut f = fs::File::create(&tmp)?;
+        f.write_all(contents)?;
+        f.sync_all()?;
+    }
+    if path.exists() {
+        fs::copy(path, sibling(path, "bak"))?;
+    }
+    fs::rename(&tmp, path)?;
+    if let Some(dir) = path.parent() {
+        fs::File::open(dir)?.sync_all()?;
+    }
+    Ok(())
+}
+
+/// Options for creating a file only the user can read. On Windows a new
+/// file takes its directory's permissions, private to the user in their
+/// profile.
+pub(crate) fn private_file() -> fs::OpenOptions {
+    let mut options = fs::OpenOptions::new();
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options
+}
+
+/// Read and parse `path`, falling back to `<path>.bak` when the primary is
+/// missing or unparseable. Returns `None` if neither file exists; if only a
+/// broken primary exists, its error is returned rather than starting empty.
+pub(crate) fn read_with_backup<T>(path: &Path, parse: impl Fn(&str) -> Result<T>) -> Result<Option<T>> {
+    let primary = match fs::read_to_string(path) {
+        Ok(contents) => match parse(&contents) {
+            Ok(value) => return Ok(Some(value)),
+            Err(e) => Some(e),
+        },
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+        Err(e) => Some(e.into()),
+    };
+
+    let backup = sibling(path, "bak");
+    if let Ok(contents) = fs::read_to_string(&backup) {
+        if let Ok(value) = parse(&contents) {
+            warn!("{} unreadable, recovered from {}", path.display(), backup.display());
+            return Ok(Some(value));
+        }
+    }
+
+    match primary {
+        Some(e) => Err(e),
+        None => Ok(None),
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct GuestLinkData {
+    pub payload_json: String,
+    pub token: String,
+    pub session_id: String,
+    pub host: String,
+    pub hosts: Vec<Candidate>,
+    pub port: u16,
+    pub fingerprint: String,
+    pub expires_in_secs: u64,
+}
+
 #[derive(Debug, Clone, Serialize)]
 pub struct PairingData {
     pub qr_payload_json: String,
     pub token: String,
     pub host: String,
+    /// Every address to try, best first
+    pub hosts: Vec<Candidate>,
     pub port: u16,
     pub fingerprint: String,
     pub expires_in_secs: u64,
+    /// How to wake the host from sleep
+    pub wake: Option<WakeInfo>,
 }
 
+/// The address clients should try first: the first in `PHANTOM_HOSTS`, else
+/// the default-route one, IPv6 on hosts without IPv4.
 pub fn local_ip() -> Option<String> {
-    use std::net::UdpSocket;
-    let socket = UdpSocket::bind("0.0.0.0:0").ok()?;
-    socket.connect("8.8.8.8:80").ok()?;
-    Some(socket.local_addr().ok()?.ip().to_string())
+    match addresses::configured() {
+        Some(hosts) => hosts.into_iter().next().map(|c| c.host),
+        None => addresses::primary_ip().or_else(addresses::primary_ipv6).map(|ip| ip.to_string()),
+    }
 }
 
 pub fn hostname() -> String {
-    std::env::var("HOSTNAME")
+    std::env::var("PHANTOM_HOSTNAME")
+        .or_else(|_| std::env::var("HOSTNAME"))
         .or_else(|_| std::env::var("HOST"))
+        .or_else(|_| std::env::var("COMPUTERNAME"))
         .unwrap_or_else(|_| "phantom-host".to_string())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::{Clock, ManualClock};
+    use std::sync::Arc;
+
+    #[test]
+    fn write_atomic_keeps_previous_as_backup() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("devices.json");
+
+        write_atomic(&path, b"one").unwrap();
+        assert!(!sibling(&path, "bak").exists());
+        write_atomic(&path, b"two").unwrap();
+
+        assert_eq!(fs::read(&path).unwrap(), b"two");
+        assert_eq!(fs::read(sibling(&path, "bak")).unwrap(), b"one");
+        assert!(!sibling(&path, "tmp").exists());
+    }
+
+    #[test]
+    fn corrupt_store_recovers_from_backup() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let store = DeviceStore::new(dir.path()).unwrap();
+        store.add_device("dev-1", "key-1", "Phone", None, None).unwrap();
+        store.add_device("dev-2", "key-2", "Tablet", None, None).unwrap();
+
+        // Simulate a torn write of the live file
+        fs::write(dir.path().join("devices.json"), b"{\"devices\": {").unwrap();
+
+        let store = DeviceStore::new(dir.path()).unwrap();
+        assert_eq!(store.get_public_key("dev-1").unwrap(), "key-1");
+    }
+
+    #[test]
+    fn concurrent_stores_keep_all_tokens() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let stores: Vec<_> = (0..4)
+            .map(|_| Arc::new(DeviceStore::new(dir.path()).unwrap()))
+            .collect();
+
+        let handles: Vec<_> = stores
+            .iter()
+            .cloned()
+            .map(|store| {
+                std::thread::spawn(move || {
+                    (0..25).map(|_| store.create_pairing_token(None)).collect::<Vec<_>>()
+                })
+            })
+            .collect();
+        let tokens: Vec<String> = handles.into_iter().flat_map(|h| h.join().unwrap()).collect();
+
+        let now = stores[0].clock.unix_secs();
+        assert_eq!(load_token_file(&stores[0].token_path, now, |t: &PairingToken| t.expires_at).len(), 100);
+        for token in &tokens {
+            assert!(stores[1].redeem_pairing_token(token).is_some());
+        }
+    }
+
+    #[test]
+    fn stale_store_does_not_drop_devices() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let daemon = DeviceStore::new(dir.path()).unwrap();
+        let cli = DeviceStore::new(dir.path()).unwrap();
+
+        daemon.add_device("dev-1", "key-1", "Phone", None, None).unwrap();
+        cli.add_device("dev-2", "key-2", "Tablet", None, None).unwrap();
+        daemon.record_auth("dev-1", true);
+
+        let reloaded = DeviceStore::new(dir.path()).unwrap();
+        assert_eq!(reloaded.list_devices().len(), 2);
+    }
+
+    #[test]
+    fn guest_tokens_are_single_use_and_expire() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let clock = ManualClock::new();
+        let store = DeviceStore::new(dir.path()).unwrap().with_clock(clock.clone());
+
+        let token = store.create_guest_token("sess-1", std::time::Duration::from_secs(60));
+        // Redeemable from another process's store, exactly once
+        let other = DeviceStore::new(dir.path()).unwrap();
+        let grant = other.redeem_guest_token(&token).unwrap();
+        assert_eq!(grant.session_id, "sess-1");
+        assert!(grant.expires_at > clock.unix_secs());
+        assert!(store.redeem_guest_token(&token).is_none());
+
+        let expired = store.create_guest_token("sess-1", std::time::Duration::from_secs(60));
+        clock.advance(std::time::Duration::from_secs(60));
+        assert!(store.redeem_guest_token(&expired).is_none());
+        // Pairing tokens are a separate namespace
+        assert!(store.redeem_pairing_token(&token).is_none());
+    }
+
+    #[test]
+    fn wrong_pairing_tokens_withdraw_outstanding_ones() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let store = DeviceStore::new(dir.path()).unwrap();
+        assert_eq!(store.record_pairing_miss("dev-1", "10.0.0.9:4433"), PairingMiss { outstanding: 0, withdrawn: 0 });
+
+        let token = store.create_pairing_token(None);
+        for _ in 0..PAIRING_GUESSES_MAX {
+            assert_eq!(store.record_pairing_miss("dev-1", "10.0.0.9:4433").outstanding, 1);
+        }
+        let later = store.create_pairing_token(None);
+        // Guesses from another device ID count all the same
+        assert_eq!(store.record_pairing_miss("dev-2", "10.0.0.9:4433"), PairingMiss { outstanding: 1, withdrawn: 1 });
+        assert!(store.redeem_pairing_token(&token).is_none());
+        assert!(store.redeem_pairing_token(&later).is_some());
+
+        let log = std::fs::read_to_string(dir.path().join("auth.log")).unwrap();
+        assert_eq!(log.lines().filter(|l| l.ends_with("pair_fail\t10.0.0.9:4433")).count(), 7);
+    }
+
+    #[test]
+    fn pairing_token_carries_user_to_device() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let store = DeviceStore::new(dir.path()).unwrap();
+
+        let token = store.create_pairing_token(Some("alice"));
+        let grant = store.redeem_pairing_token(&token).unwrap();
+        assert_eq!(grant.user.as_deref(), Some("alice"));
+        assert!(store.redeem_pairing_token(&token).is_none());
+
+        store.add_device("dev-1", "key-1", "Phone", grant.user.as_deref(), None).unwrap();
+        store.add_device("dev-2", "key-2", "Tablet", None, None).unwrap();
+        let reloaded = DeviceStore::new(dir.path()).unwrap();
+        assert_eq!(reloaded.device_user("dev-1").as_deref(), Some("alice"));
+        assert_eq!(reloaded.device_user("dev-2"), None);
+    }
+
+    #[test]
+    fn restrictions_are_validated_and_shared() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let daemon = DeviceStore::new(dir.path()).unwrap();
+        let cli = DeviceStore::new(dir.path()).unwrap();
+        daemon.add_device("dev-1", "key-1", "Phone", None, None).unwrap();
+        assert!(daemon.device_restrictions("dev-1").is_none());
+
+        let jail = Restrictions { chroot: Some("relative".into()), ..Default::default() };
+        assert!(cli.set_restrictions("dev-1", Some(jail)).is_err());
+        let restrictions = Restrictions { allowed_commands: vec!["htop".to_string()], ..Default::default() };
+        cli.set_restrictions("dev-1", Some(restrictions.clone())).unwrap();
+        assert!(cli.set_restrictions("dev-9", None).is_err());
+
+        let reloaded = DeviceStore::new(dir.path()).unwrap();
+        assert_eq!(reloaded.device_restrictions("dev-1"), Some(restrictions));
+        cli.set_restrictions("dev-1", None).unwrap();
+        assert!(DeviceStore::new(dir.path()).unwrap().device_restrictions("dev-1").is_none());
+    }
+
+    #[test]
+    fn webauthn_credentials_keep_their_sign_count() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let store = DeviceStore::new(dir.path()).unwrap();
+        let credential = WebAuthnCredential::new("cred-1", "phantom.local").unwrap();
+        store.add_device_with("dev-1", "key-1", "YubiKey", None, None, Some(credential)).unwrap();
+        store.add_device("dev-2", "key-2", "Tablet", None, None).unwrap();
+
+        store.record_sign_count("dev-1", 42).unwrap();
+        let reopened = DeviceStore::new(dir.path()).unwrap();
+        let credential = reopened.webauthn_credential("dev-1").unwrap();
+        assert_eq!((credential.rp_id.as_str(), credential.sign_count), ("phantom.local", 42));
+        assert!(reopened.webauthn_credential("dev-2").is_none());
+    }
+
+    #[test]
+    fn agent_permission_is_off_by_default_and_shared() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let daemon = DeviceStore::new(dir.path()).unwrap();
+        let cli = DeviceStore::new(dir.path()).unwrap();
+        daemon.add_device("dev-1", "key-1", "Phone", None, None).unwrap();
+        assert!(!daemon.agent_forwarding_allowed("dev-1"));
+
+        cli.set_agent_forwarding("dev-1", true).unwrap();
+        assert!(cli.set_agent_forwarding("dev-2", true).is_err());
+        // The daemon picks it up when the device next authenticates
+        daemon.record_auth("dev-1", true);
+        assert!(daemon.agent_forwarding_allowed("dev-1"));
+        assert!(!daemon.agent_forwarding_allowed("dev-2"));
+    }
+
+    #[test]
+    fn corrupt_store_without_backup_is_an_error() {
+        let dir = tempfile::TempDir::new().unwrap();
+        fs::write(dir.path().join("devices.json"), b"not json").unwrap();
+        assert!(DeviceStore::new(dir.path()).is_err());
+    }
+}
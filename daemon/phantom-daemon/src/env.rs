@@ -0,0 +1,226 @@
+//! Which context a session's shell is pointed at (virtualenv, AWS profile,
+//! kube config, ...), so the session list can show it before something
+//! destructive is typed into the wrong one.
+//!
+//! The [`WATCHED`] variables come from two places, the second winning:
+//! - the shell's process environment (`/proc` on Linux, `KERN_PROCARGS2` on
+//!   macOS), read on every list. That is what the shell started with; what
+//!   it exports later doesn't show there.
+//! - a prompt hook reporting them with iTerm2's user variable sequence,
+//!   `OSC 1337 ; SetUserVar=NAME=<base64 value> BEL`, picked out of the
+//!   session's output by the bridge. In bash, for example:
+//!   `PROMPT_COMMAND='printf "\e]1337;SetUserVar=AWS_PROFILE=%s\a" "$(printf %s "$AWS_PROFILE" | base64)"'`.
+//!   An empty value reports the variable unset.
+
+use base64::Engine;
+use std::collections::BTreeMap;
+
+/// Variables that say which context a shell is pointed at.
+pub const WATCHED: [&str; 7] = [
+    "VIRTUAL_ENV",
+    "CONDA_DEFAULT_ENV",
+    "AWS_PROFILE",
+    "AWS_REGION",
+    "KUBECONFIG",
+    "GOOGLE_CLOUD_PROJECT",
+    "AZURE_CONFIG_DIR",
+];
+
+/// Variable name → value.
+pub type EnvVars = BTreeMap<String, String>;
+
+const ESC: u8 = 0x1b;
+const BEL: u8 = 0x07;
+/// Start of the OSC body a prompt hook reports a variable with.
+const SET_USER_VAR: &[u8] = b"1337;SetUserVar=";
+/// Longest report kept; longer ones are dropped.
+const REPORT_MAX: usize = 4096;
+
+/// The watched variables of the shell `pid`, overridden by what a prompt
+/// hook `reported`.
+pub fn snapshot(pid: Option<u32>, reported: &EnvVars) -> EnvVars {
+    let mut env: EnvVars = pid
+        .and_then(process_env)
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|(name, _)| WATCHED.contains(&name.as_str()))
+        .collect();
+    for (name, value) in reported {
+        if value.is_empty() {
+            env.remove(name);
+        } else {
+            env.insert(name.clone(), value.clone());
+        }
+    }
+    env
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+enum State {
+    #[default]
+    Ground,
+    Escape,
+    /// In an OSC that may be a report
+    Report,
+    /// In any other OSC, or one too long to keep
+    OtherOsc,
+    /// ESC inside an OSC, maybe the start of its ST terminator
+    OscEscape,
+}
+
+/// Follows output for prompt hooks reporting watched variables, including
+/// reports split across reads.
+#[derive(Default)]
+pub struct EnvWatch {
+    state: State,
+    report: Vec<u8>,
+}
+
+impl EnvWatch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The variables `output` reports, in order.
+    pub fn observe(&mut self, output: &[u8]) -> Vec<(String, String)> {
+        let mut reported = Vec::new();
+        let mut rest = output;
+        while let Some((&byte, after)) = rest.split_first() {
+            if let State::Ground = self.state {
+                // Skip to the next escape
+                let Some(at) = rest.iter().position(|&b| b == ESC) else { break };
+                self.state = State::Escape;
+                rest = &rest[at + 1..];
+                continue;
+            }
+            rest = after;
+            self.state = match (self.state, byte) {
+                (State::Escape, b']') => {
+                    self.report.clear();
+                    State::Report
+                }
+                (State::Report | State::OtherOsc, BEL) => {
+                    reported.extend(self.finish());
+                    State::Ground
+                }
+                (State::Report | State::OtherOsc, ESC) => State::OscEscape,
+                (State::OscEscape, b'\\') => {
+                    reported.extend(self.finish());
+                    State::Ground
+                }
+                (State::Report, byte) => {
+                    let prefix = self.report.len() >= SET_USER_VAR.len() || SET_USER_VAR[self.report.len()] == byte;
+                    if !prefix || self.report.len() == REPORT_MAX {
+                        self.report.clear();
+                        State::OtherOsc
+                    } else {
+                        self.report.push(byte);
+                        State::Report
+                    }
+                }
+                (State::OtherOsc, _) => State::OtherOsc,
+                _ => State::Ground,
+            };
+        }
+        reported
+    }
+
+    /// The variable a completed OSC reported, if it was a report of one.
+    fn finish(&mut self) -> Option<(String, String)> {
+        let report = std::mem::take(&mut self.report);
+        let (name, value) = std::str::from_utf8(report.strip_prefix(SET_USER_VAR)?).ok()?.split_once('=')?;
+        if !WATCHED.contains(&name) {
+            return None;
+        }
+        // `base64` wraps long values
+        let value: String = value.chars().filter(|c| !c.is_ascii_whitespace()).collect();
+        let value = base64::engine::general_purpose::STANDARD.decode(value).ok()?;
+        Some((name.to_string(), String::from_utf8(value).ok()?))
+    }
+}
+
+/// Environment process `pid` was started with.
+#[cfg(target_os = "linux")]
+pub fn process_env(pid: u32) -> Option<Vec<(String, String)>> {
+    let environ = std::fs::read(format!("/proc/{pid}/environ")).ok()?;
+    Some(parse_entries(environ.split(|&b| b == 0)))
+}
+
+/// Environment process `pid` was started with.
+#[cfg(target_os = "macos")]
+pub fn process_env(pid: u32) -> Option<Vec<(String, String)>> {
+    let mut mib = [libc::CTL_KERN, libc::KERN_PROCARGS2, pid as libc::c_int];
+    let mut size: libc::size_t = 0;
+    let null = std::ptr::null_mut();
+    if unsafe { libc::sysctl(mib.as_mut_ptr(), 3, null, &mut size, null, 0) } != 0 {
+        return None;
+    }
+    let mut buf = vec![0u8; size];
+    let buffer = buf.as_mut_ptr() as *mut libc::c_void;
+    if unsafe { libc::sysctl(mib.as_mut_ptr(), 3, buffer, &mut size, null, 0) } != 0 {
+        return None;
+    }
+    buf.truncate(size);
+    // argc, the executable path (NUL padded), the arguments, then the environment
+    let argc = i32::from_ne_bytes(buf.get(..4)?.try_into().ok()?) as usize;
+    let fields = buf[4..].split(|&b| b == 0).filter(|f| !f.is_empty());
+    Some(parse_entries(fields.skip(1 + argc)))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+pub fn process_env(_pid: u32) -> Option<Vec<(String, String)>> {
+    None
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn parse_entries<'a>(entries: impl Iterator<Item = &'a [u8]>) -> Vec<(String, String)> {
+    entries
+        .filter_map(|entry| std::str::from_utf8(entry).ok()?.split_once('='))
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn report(name: &str, value: &str) -> Vec<u8> {
+        let value = base64::engine::general_purpose::STANDARD.encode(value);
+        format!("\x1b]1337;SetUserVar={name}={value}\x07").into_bytes()
+    }
+
+    #[test]
+    fn picks_reports_out_of_output() {
+        let mut watch = EnvWatch::new();
+        let mut output = b"\x1b]0;title\x07$ ".to_vec();
+        output.extend(report("AWS_PROFILE", "prod"));
+        output.extend(report("HOME", "/tmp"));
+        output.extend(b"\x1b[1m$ \x1b[0m");
+        assert_eq!(watch.observe(&output), [("AWS_PROFILE".to_string(), "prod".to_string())]);
+
+        // Split across reads, ended by ST
+        let mut split = report("KUBECONFIG", "/home/me/.kube/staging");
+        split.pop();
+        split.extend(b"\x1b\\");
+        let (first, second) = split.split_at(9);
+        assert!(watch.observe(first).is_empty());
+        assert_eq!(watch.observe(second), [("KUBECONFIG".to_string(), "/home/me/.kube/staging".to_string())]);
+    }
+
+    #[test]
+    fn reports_override_the_process_environment() {
+        let reported = EnvVars::from([
+            ("VIRTUAL_ENV".to_string(), "/srv/app/.venv".to_string()),
+            ("AWS_PROFILE".to_string(), String::new()),
+        ]);
+        let env = snapshot(None, &reported);
+        assert_eq!(env, EnvVars::from([("VIRTUAL_ENV".to_string(), "/srv/app/.venv".to_string())]));
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    #[test]
+    fn reads_this_process_environment() {
+        let env = process_env(std::process::id()).unwrap();
+        assert!(env.iter().any(|(name, _)| name == "PATH"));
+    }
+}
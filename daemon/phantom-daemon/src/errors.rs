@@ -0,0 +1,220 @@
+//! Error codes carried by every control-channel error (`"code"` next to the
+//! human-readable `"error"`), by failed auth responses and by IPC errors,
+//! so clients branch on the code instead of parsing the message.
+//!
+//! Codes are attached where an error is raised, with [`ErrorCode::err`] or
+//! as a [`SessionError`] or [`StreamTimeout`]; [`ErrorCode::of`] finds one anywhere in an
+//! error's context chain. Errors that never got one report `INTERNAL`.
+
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ErrorCode {
+    /// Malformed request or a missing or invalid parameter
+    BadRequest,
+    /// Unknown request type or IPC method
+    Unsupported,
+    /// Bad signature, or an invalid or expired pairing token or guest link
+    Unauthenticated,
+    /// The device isn't paired (for a user, in multi-user mode); re-pair it
+    NotPaired,
+    /// Not permitted for this device or guest, or disabled on this host
+    PermissionDenied,
+    /// No such session, device or tmux session
+    NotFound,
+    /// Another client is driving the session; mirror it instead
+    AlreadyAttached,
+    /// Nobody is driving the session, so there is nothing to mirror
+    NotAttached,
+    /// Session lost its PTY reader; destroy it and start a new one
+    DamagedSession,
+    /// The session's program exited; it is only kept for inspection
+    SessionExited,
+    /// A memory, size or session limit was reached
+    LimitExceeded,
+    /// Needs something this host doesn't have (tmux, a clipboard command, ...)
+    Unavailable,
+    /// The peer took too long, e.g. to answer an auth challenge
+    Timeout,
+    /// Too many failed attempts; retry after `retry_after_secs`
+    RateLimited,
+    /// Anything else
+    Internal,
+}
+
+impl ErrorCode {
+    /// Every code, for `phantom protocol dump`; new codes go here too.
+    pub const ALL: [ErrorCode; 15] = [
+        ErrorCode::BadRequest,
+        ErrorCode::Unsupported,
+        ErrorCode::Unauthenticated,
+        ErrorCode::NotPaired,
+        ErrorCode::PermissionDenied,
+        ErrorCode::NotFound,
+        ErrorCode::AlreadyAttached,
+        ErrorCode::NotAttached,
+        ErrorCode::DamagedSession,
+        ErrorCode::SessionExited,
+        ErrorCode::LimitExceeded,
+        ErrorCode::Unavailable,
+        ErrorCode::Timeout,
+        ErrorCode::RateLimited,
+        ErrorCode::Internal,
+    ];
+
+    /// An error with this code and `message`.
+    pub fn err(self, message: impl Into<String>) -> anyhow::Error {
+        anyhow::Error::new(CodedError { code: self, message: message.into() })
+    }
+
+    /// The code attached anywhere in `err`'s chain, or `Internal`.
+    pub fn of(err: &anyhow::Error) -> Self {
+        err.chain()
+            .find_map(|e| {
+                if let Some(e) = e.downcast_ref::<SessionError>() {
+                    Some(e.code())
+                } else if e.is::<StreamTimeout>() {
+                    Some(ErrorCode::Timeout)
+                } else {
+                    e.downcast_ref::<CodedError>().map(|e| e.code)
+                }
+            })
+            .unwrap_or(ErrorCode::Internal)
+    }
+}
+
+#[derive(Debug)]
+pub struct CodedError {
+    pub code: ErrorCode,
+    message: String,
+}
+
+impl fmt::Display for CodedError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for CodedError {}
+
+/// Why an operation on a session failed, raised by
+/// [`crate::session::SessionManager`] and reported alike by the bridge and
+/// IPC. Callers can downcast to branch on it.
+#[derive(Debug)]
+pub enum SessionError {
+    /// No such session, or not one the caller may see
+    NotFound,
+    /// Another client is driving the session; says what to do instead
+    AlreadyAttached(&'static str),
+    /// Nobody is driving the session
+    NotAttached,
+    /// The session lost its PTY reader
+    Damaged,
+    /// The session's program exited
+    Exited,
+    /// `max` sessions are already open
+    LimitReached { max: usize },
+    /// The session's terminal couldn't be started
+    SpawnFailed { source: anyhow::Error },
+}
+
+impl SessionError {
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            SessionError::NotFound => ErrorCode::NotFound,
+            SessionError::AlreadyAttached(_) => ErrorCode::AlreadyAttached,
+            SessionError::NotAttached => ErrorCode::NotAttached,
+            SessionError::Damaged => ErrorCode::DamagedSession,
+            SessionError::Exited => ErrorCode::SessionExited,
+            SessionError::LimitReached { .. } => ErrorCode::LimitExceeded,
+            // E.g. a restricted device's command refused, or a missing user
+            SessionError::SpawnFailed { source } => ErrorCode::of(source),
+        }
+    }
+}
+
+impl fmt::Display for SessionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SessionError::NotFound => f.write_str("session not found"),
+            SessionError::AlreadyAttached(reason) => f.write_str(reason),
+            SessionError::NotAttached => f.write_str("session is not attached"),
+            SessionError::Damaged => f.write_str("session is damaged; destroy it and start a new one"),
+            SessionError::Exited => f.write_str("session has exited"),
+            SessionError::LimitReached { max } => write!(f, "session limit reached ({max})"),
+            SessionError::SpawnFailed { .. } => f.write_str("start session terminal"),
+        }
+    }
+}
+
+impl std::error::Error for SessionError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SessionError::SpawnFailed { source } => Some(&**source),
+            _ => None,
+        }
+    }
+}
+
+/// A session stream closed for want of requests (see
+/// [`crate::bridge::StreamDeadlines`]).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StreamTimeout {
+    /// No request arrived this long after the stream was opened
+    FirstMessage(Duration),
+    /// No request arrived this long after the previous one
+    Idle(Duration),
+}
+
+impl StreamTimeout {
+    pub fn limit(self) -> Duration {
+        match self {
+            StreamTimeout::FirstMessage(limit) | StreamTimeout::Idle(limit) => limit,
+        }
+    }
+}
+
+impl fmt::Display for StreamTimeout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StreamTimeout::FirstMessage(limit) => write!(f, "no request within {}s of opening the stream", limit.as_secs_f64()),
+            StreamTimeout::Idle(limit) => write!(f, "no request for {}s", limit.as_secs_f64()),
+        }
+    }
+}
+
+impl std::error::Error for StreamTimeout {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Context;
+
+    #[test]
+    fn code_survives_context() {
+        let err = Err::<(), _>(ErrorCode::LimitExceeded.err("session limit reached (4)"))
+            .context("create session")
+            .unwrap_err();
+        assert_eq!(ErrorCode::of(&err), ErrorCode::LimitExceeded);
+        assert_eq!(format!("{err:#}"), "create session: session limit reached (4)");
+        assert_eq!(ErrorCode::of(&anyhow::anyhow!("boom")), ErrorCode::Internal);
+        assert_eq!(serde_json::to_value(ErrorCode::DamagedSession).unwrap(), "DAMAGED_SESSION");
+    }
+
+    #[test]
+    fn session_errors_carry_their_code() {
+        let err = anyhow::Error::new(SessionError::LimitReached { max: 4 }).context("create session");
+        assert_eq!(ErrorCode::of(&err), ErrorCode::LimitExceeded);
+        assert_eq!(format!("{err:#}"), "create session: session limit reached (4)");
+
+        // A failed spawn keeps the cause's code and message
+        let source = ErrorCode::PermissionDenied.err("command not allowed");
+        let err = anyhow::Error::new(SessionError::SpawnFailed { source });
+        assert_eq!(ErrorCode::of(&err), ErrorCode::PermissionDenied);
+        assert_eq!(format!("{err:#}"), "start session terminal: command not allowed");
+        assert!(matches!(err.downcast_ref::<SessionError>(), Some(SessionError::SpawnFailed { .. })));
+    }
+}
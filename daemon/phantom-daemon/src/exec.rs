@@ -0,0 +1,292 @@
+//! One-shot command execution (`exec`): run a single command in a fresh PTY,
+//! stream its output, and report how it ended, without creating a session.
+
+use anyhow::Result;
+use phantom_frame::{self as frame, Frame, FrameDecoder, FrameType};
+use quinn::{RecvStream, SendStream};
+use std::io::{Read, Write};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+use tokio_util::task::TaskTracker;
+use tracing::{error, info, warn};
+
+use crate::terminal::TerminalBackend;
+
+/// Timeout when the request doesn't set one.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(60);
+/// Upper bound for a requested timeout.
+pub const MAX_TIMEOUT: Duration = Duration::from_secs(600);
+/// Size of a single PTY read.
+const READ_CHUNK_BYTES: usize = 16384;
+/// Queued output chunks between the PTY reader and the consumer.
+const OUTPUT_QUEUE: usize = 32;
+/// Buffer memory of one exec, reserved against the daemon memory budget.
+pub const EXEC_MEMORY_BYTES: usize = OUTPUT_QUEUE * READ_CHUNK_BYTES + 2 * frame::MAX_FRAME;
+/// How long to wait for an exit status once output has ended.
+const EXIT_GRACE: Duration = Duration::from_secs(2);
+/// Output returned to IPC callers beyond this is dropped.
+pub const IPC_OUTPUT_CAP: usize = 1024 * 1024;
+
+/// Resolve a requested `timeout_secs` into a timeout.
+pub fn timeout_from_request(timeout_secs: Option<u64>) -> Duration {
+    timeout_secs
+        .filter(|&secs| secs > 0)
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_TIMEOUT)
+        .min(MAX_TIMEOUT)
+}
+
+/// How a command ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExecStatus {
+    Exited(u32),
+    /// Killed after running past its timeout
+    TimedOut,
+    /// Killed at the client's request
+    Cancelled,
+    /// Output ended but the process never reported an exit status
+    Unknown,
+}
+
+impl ExecStatus {
+    /// Close-frame reason: `exit:<code>`, `timeout`, `cancelled`, or `unknown`.
+    pub fn reason(&self) -> String {
+        match self {
+            Self::Exited(code) => format!("exit:{code}"),
+            Self::TimedOut => "timeout".to_string(),
+            Self::Cancelled => "cancelled".to_string(),
+            Self::Unknown => "unknown".to_string(),
+        }
+    }
+
+    pub fn exit_code(&self) -> Option<u32> {
+        match self {
+            Self::Exited(code) => Some(*code),
+            _ => None,
+        }
+    }
+}
+
+/// A running one-shot command.
+pub struct Exec {
+    backend: Box<dyn TerminalBackend>,
+    writer: Box<dyn Write + Send>,
+    output: mpsc::Receiver<Vec<u8>>,
+    deadline: Instant,
+    timed_out: bool,
+    /// Where the output reader and the kill timer run
+    tasks: TaskTracker,
+}
+
+impl Exec {
+    /// Start streaming output from a freshly spawned `backend`, reading it
+    /// on a task tracked by `tasks`.
+    pub fn start(mut backend: Box<dyn TerminalBackend>, timeout: Duration, tasks: TaskTracker) -> Result<Self> {
+        let mut reader = backend.try_clone_reader()?;
+        let writer = backend.take_writer()?;
+
+        let (tx, output) = mpsc::channel(OUTPUT_QUEUE);
+        tasks.spawn_blocking(move || {
+            let mut buf = vec![0u8; READ_CHUNK_BYTES];
+            loop {
+                match reader.read(&mut buf) {
+                    Ok(0) => break,
+                    Ok(n) => {
+                        if tx.blocking_send(buf[..n].to_vec()).is_err() {
+                            break;
+                        }
+                    }
+                    // EIO once the command has exited and the PTY is closed
+                    Err(_) => break,
+                }
+            }
+        });
+
+        Ok(Self {
+            backend,
+            writer,
+            output,
+            deadline: Instant::now() + timeout,
+            timed_out: false,
+            tasks,
+        })
+    }
+
+    /// Next chunk of output, or None once output has ended or the command
+    /// has run past its timeout.
+    pub async fn next_output(&mut self) -> Option<Vec<u8>> {
+        if self.timed_out {
+            return None;
+        }
+        tokio::select! {
+            chunk = self.output.recv() => chunk,
+            _ = tokio::time::sleep_until(self.deadline) => {
+                self.timed_out = true;
+                None
+            }
+        }
+    }
+
+    pub fn write_input(&mut self, data: &[u8]) -> std::io::Result<()> {
+        self.writer.write_all(data)
+    }
+
+    /// Wait for the exit status once output has ended, killing the command if
+    /// it timed out or lingers.
+    pub async fn finish(mut self) -> ExecStatus {
+        if self.timed_out {
+            self.backend.terminate(&self.tasks);
+            return ExecStatus::TimedOut;
+        }
+        let deadline = Instant::now() + EXIT_GRACE;
+        loop {
+            match self.backend.try_wait() {
+                Ok(Some(Some(code))) => return ExecStatus::Exited(code),
+                Ok(Some(None)) => return ExecStatus::Unknown,
+                Ok(None) if Instant::now() < deadline => {
+                    tokio::time::sleep(Duration::from_millis(20)).await;
+                }
+                Ok(None) => break,
+                Err(e) => {
+                    warn!("exec try_wait error: {e}");
+                    break;
+                }
+            }
+        }
+        self.backend.terminate(&self.tasks);
+        ExecStatus::Unknown
+    }
+
+    /// Kill the command.
+    pub fn cancel(mut self) -> ExecStatus {
+        self.backend.terminate(&self.tasks);
+        ExecStatus::Cancelled
+    }
+
+    /// Run to completion, collecting up to `cap` bytes of output.
+    /// Returns the output, whether any was dropped, and the status.
+    pub async fn collect(mut self, cap: usize) -> (Vec<u8>, bool, ExecStatus) {
+        let mut output = Vec::new();
+        let mut truncated = false;
+        while let Some(chunk) = self.next_output().await {
+            let room = cap - output.len();
+            truncated |= chunk.len() > room;
+            output.extend_from_slice(&chunk[..chunk.len().min(room)]);
+        }
+        (output, truncated, self.finish().await)
+    }
+}
+
+/// Stream an exec over a QUIC stream: output as Data frames, client Data
+/// frames as input, and a final Close frame whose reason carries the status
+/// (see [`ExecStatus::reason`]). A client Close kills the command.
+pub async fn stream_exec(mut send: SendStream, mut recv: RecvStream, mut exec: Exec) -> Result<()> {
+    let mut seq: u64 = 1;
+    let mut decoder = FrameDecoder::new();
+    let mut buf = [0u8; 4096];
+    let mut client_open = true;
+    let wire = crate::hello::current();
+
+    let status = 'exec: loop {
+        tokio::select! {
+            chunk = exec.next_output() => {
+                let Some(chunk) = chunk else {
+                    break exec.finish().await;
+                };
+                // In frames the client takes (see crate::hello)
+                for piece in chunk.chunks(wire.max_payload) {
+                    let encoded = frame::encode(&Frame::data(seq, piece.to_vec()), wire.compress)?;
+                    seq += 1;
+                    if send.write_all(&encoded).await.is_err() {
+                        break 'exec exec.cancel();
+                    }
+                }
+            }
+            read = recv.read(&mut buf), if client_open => match read {
+                Ok(Some(n)) => {
+                    decoder.feed(&buf[..n]);
+                    let mut cancelled = false;
+                    loop {
+                        match decoder.decode_next() {
+                            Ok(Some(frame)) => match frame.frame_type {
+                                FrameType::Data => {
+                                    if let Err(e) = exec.write_input(&frame.payload) {
+                                        warn!("exec input error: {e}");
+                                    }
+                                }
+                                FrameType::Close => {
+                                    cancelled = true;
+                                    break;
+                                }
+                                _ => {}
+                            },
+                            Ok(None) => break,
+                            Err(e) => {
+                                error!("exec frame decode error: {e}");
+                                cancelled = true;
+                                break;
+                            }
+                        }
+                    }
+                    if cancelled {
+                        break exec.cancel();
+                    }
+                }
+                // Client is done sending; keep streaming output
+                Ok(None) => client_open = false,
+                Err(_) => break exec.cancel(),
+            },
+        }
+    };
+
+    info!("exec finished: {}", status.reason());
+    let close = Frame::close_with_reason(seq, &status.reason());
+    if let Ok(encoded) = frame::encode(&close, false) {
+        let _ = send.write_all(&encoded).await;
+    }
+    let _ = send.finish();
+    let _ = tokio::time::timeout(EXIT_GRACE, send.stopped()).await;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::terminal::ScriptedTerminal;
+
+    #[tokio::test]
+    async fn collect_returns_output_and_exit_code() {
+        let (term, handle) = ScriptedTerminal::new(24, 80);
+        let exec = Exec::start(Box::new(term), Duration::from_secs(5), TaskTracker::new()).unwrap();
+        handle.emit(b"hello ");
+        handle.emit(b"world");
+        handle.exit(3);
+
+        let (output, truncated, status) = exec.collect(8).await;
+        assert_eq!(output, b"hello wo");
+        assert!(truncated);
+        assert_eq!(status, ExecStatus::Exited(3));
+        assert_eq!(status.reason(), "exit:3");
+    }
+
+    #[tokio::test]
+    async fn timeout_kills_the_command() {
+        let (term, handle) = ScriptedTerminal::new(24, 80);
+        let exec = Exec::start(Box::new(term), Duration::from_millis(50), TaskTracker::new()).unwrap();
+        handle.emit(b"still running");
+
+        let (output, _, status) = exec.collect(IPC_OUTPUT_CAP).await;
+        assert_eq!(output, b"still running");
+        assert_eq!(status, ExecStatus::TimedOut);
+        assert!(handle.terminated());
+    }
+
+    #[test]
+    fn timeout_is_clamped() {
+        assert_eq!(timeout_from_request(None), DEFAULT_TIMEOUT);
+        assert_eq!(timeout_from_request(Some(0)), DEFAULT_TIMEOUT);
+        assert_eq!(timeout_from_request(Some(5)), Duration::from_secs(5));
+        assert_eq!(timeout_from_request(Some(100_000)), MAX_TIMEOUT);
+    }
+}
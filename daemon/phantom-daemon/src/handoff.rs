@@ -0,0 +1,131 @@
+//! Handing an attached session to another device without a gap in its
+//! output, e.g. from a phone to a tablet.
+//!
+//! The device driving the session sends `handoff_session` naming the session
+//! and the device to hand it to, on a stream other than the bridged one. The
+//! daemon notes where the session's output stands (the handoff point),
+//! pushes `handoff_offered` to the other device and holds the request open
+//! for up to [`TIMEOUT`]. That device attaches with the offer's `handoff_id`:
+//! the old bridge is ended and the new one started in one step, replaying
+//! the output since the handoff point, so nothing the old device may have
+//! missed is lost and nothing is read from the shell in between. The request
+//! is then answered with `session_handed_off`.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::oneshot;
+
+use crate::errors::ErrorCode;
+
+/// How long a handoff waits for the other device to attach.
+pub const TIMEOUT: Duration = Duration::from_secs(60);
+
+struct Pending {
+    session_id: String,
+    to: String,
+    offset: u64,
+    done: oneshot::Sender<()>,
+}
+
+/// Handoffs waiting for their device to attach, by ID.
+#[derive(Default)]
+pub struct Handoffs {
+    pending: Mutex<HashMap<String, Pending>>,
+}
+
+/// An open handoff. Dropping it withdraws the offer.
+pub struct Ticket<'a> {
+    pub id: String,
+    handoffs: &'a Handoffs,
+    done: oneshot::Receiver<()>,
+}
+
+/// A handoff claimed by the attaching device.
+#[derive(Debug)]
+pub struct Claim {
+    /// Output offset of the handoff point
+    pub offset: u64,
+    done: oneshot::Sender<()>,
+}
+
+impl Handoffs {
+    /// Offer `session_id` to device `to`, from output offset `offset` on.
+    /// Replaces an earlier offer of the same session.
+    pub fn open(&self, session_id: &str, to: &str, offset: u64) -> Ticket<'_> {
+        let mut pending = self.pending.lock().expect("handoffs lock");
+        pending.retain(|_, p| p.session_id != session_id && !p.done.is_closed());
+        let id_bytes: [u8; 8] = rand::Rng::gen(&mut rand::thread_rng());
+        let id = id_bytes.iter().map(|b| format!("{b:02x}")).collect::<String>();
+        let (done, waiting) = oneshot::channel();
+        pending.insert(id.clone(), Pending { session_id: session_id.to_string(), to: to.to_string(), offset, done });
+        Ticket { id, handoffs: self, done: waiting }
+    }
+
+    /// Take handoff `id` for `device_id` attaching `session_id`.
+    pub fn claim(&self, id: &str, device_id: &str, session_id: &str) -> anyhow::Result<Claim> {
+        let mut pending = self.pending.lock().expect("handoffs lock");
+        let offered = pending
+            .get(id)
+            .is_some_and(|p| p.to == device_id && p.session_id == session_id && !p.done.is_closed());
+        match offered.then(|| pending.remove(id)).flatten() {
+            Some(p) => Ok(Claim { offset: p.offset, done: p.done }),
+            None => Err(ErrorCode::NotFound.err(format!("no handoff {id} of session {session_id} to this device"))),
+        }
+    }
+}
+
+impl Ticket<'_> {
+    /// Whether the other device attached in time.
+    pub async fn completed(&mut self, timeout: Duration) -> bool {
+        matches!(tokio::time::timeout(timeout, &mut self.done).await, Ok(Ok(())))
+    }
+}
+
+impl Drop for Ticket<'_> {
+    fn drop(&mut self) {
+        self.handoffs.pending.lock().expect("handoffs lock").remove(&self.id);
+    }
+}
+
+impl Claim {
+    /// The new bridge is taking over: answer the handoff request.
+    pub fn complete(self) {
+        let _ = self.done.send(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn only_the_offered_device_claims_a_handoff_once() {
+        let handoffs = Handoffs::default();
+        let mut ticket = handoffs.open("s1", "tablet", 42);
+        let wrong = handoffs.claim(&ticket.id, "laptop", "s1").unwrap_err();
+        assert_eq!(ErrorCode::of(&wrong), ErrorCode::NotFound);
+        assert!(handoffs.claim(&ticket.id, "tablet", "s2").is_err());
+
+        let claim = handoffs.claim(&ticket.id, "tablet", "s1").unwrap();
+        assert_eq!(claim.offset, 42);
+        assert!(handoffs.claim(&ticket.id, "tablet", "s1").is_err());
+        claim.complete();
+        assert!(ticket.completed(Duration::from_secs(1)).await);
+    }
+
+    #[tokio::test]
+    async fn withdrawn_and_replaced_offers_cant_be_claimed() {
+        let handoffs = Handoffs::default();
+        let mut ticket = handoffs.open("s1", "tablet", 0);
+        assert!(!ticket.completed(Duration::from_millis(10)).await);
+        let id = ticket.id.clone();
+        drop(ticket);
+        assert!(handoffs.claim(&id, "tablet", "s1").is_err());
+
+        let first = handoffs.open("s1", "tablet", 0);
+        let second = handoffs.open("s1", "laptop", 5);
+        assert!(handoffs.claim(&first.id, "tablet", "s1").is_err());
+        assert_eq!(handoffs.claim(&second.id, "laptop", "s1").unwrap().offset, 5);
+    }
+}
@@ -0,0 +1,210 @@
+//! Protocol negotiation at auth.
+//!
+//! A client that puts `"hello"` in its auth request says which protocol
+//! version it speaks and what it decodes: frame types (by name), compression
+//! algorithms, and the largest frame payload it takes. The successful auth
+//! response is followed by a `hello` message of what the connection uses:
+//! the lower protocol version, the frame types both know, compression if the
+//! client has the daemon's, and the smaller payload limit. Bridges on the
+//! connection stick to that, and start with a Hello frame of it when the
+//! client decodes those.
+//!
+//! Clients that don't say hello get [`Wire::legacy`], the wire of protocol 1
+//! before negotiation, so frame types added since can't break them with an
+//! `UnknownType` error. Like the language (see [`crate::messages`]), the
+//! wire applies to the task serving a connection and the streams it spawns.
+
+use anyhow::Result;
+use phantom_frame::{self as frame, FrameType};
+use serde::Deserialize;
+use std::cell::Cell;
+use std::future::Future;
+
+use crate::errors::ErrorCode;
+
+tokio::task_local! {
+    static CURRENT: Cell<Wire>;
+}
+
+/// Smallest payload limit a client may ask for.
+pub const MIN_PAYLOAD: usize = 1024;
+
+/// The compression algorithm of compressed frames.
+const COMPRESSION: &str = "zstd";
+
+/// Frame types a bridge can't do without: output, replay, pings and closes.
+const REQUIRED: [FrameType; 4] = [FrameType::Data, FrameType::Scrollback, FrameType::Heartbeat, FrameType::Close];
+
+/// What bridges send on a connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Wire {
+    pub protocol_version: u32,
+    /// Core frame types the client decodes, a bit per wire byte
+    frame_types: u32,
+    /// Compress output frames (with zstd) when worthwhile
+    pub compress: bool,
+    pub max_payload: usize,
+}
+
+impl Default for Wire {
+    fn default() -> Self {
+        Self::legacy()
+    }
+}
+
+impl Wire {
+    /// For clients that don't say hello: protocol 1's frame types before
+    /// negotiation, compressed, at the full payload size.
+    pub fn legacy() -> Self {
+        use FrameType::*;
+        Self {
+            protocol_version: 1,
+            frame_types: bits([Data, Resize, Heartbeat, Close, Scrollback, WindowUpdate]),
+            compress: true,
+            max_payload: frame::MAX_PAYLOAD,
+        }
+    }
+
+    /// Everything this build has.
+    pub fn full() -> Self {
+        Self {
+            protocol_version: crate::PROTOCOL_VERSION,
+            frame_types: bits(FrameType::CORE),
+            compress: true,
+            max_payload: frame::MAX_PAYLOAD,
+        }
+    }
+
+    /// Whether the client decodes `frame_type`. Extension types always
+    /// count: peers pass on the ones they don't know.
+    pub fn decodes(&self, frame_type: FrameType) -> bool {
+        frame_type.is_extension() || self.frame_types & bit(frame_type) != 0
+    }
+
+    /// What the connection uses, for the `hello` message and Hello frames.
+    pub fn describe(&self) -> serde_json::Value {
+        let frame_types: Vec<_> =
+            FrameType::CORE.into_iter().filter(|&t| self.decodes(t)).map(FrameType::name).collect();
+        serde_json::json!({
+            "protocol_version": self.protocol_version,
+            "frame_types": frame_types,
+            "compression": self.compress.then_some(COMPRESSION),
+            "max_payload": self.max_payload,
+        })
+    }
+}
+
+fn bit(frame_type: FrameType) -> u32 {
+    1 << frame_type.to_u8()
+}
+
+fn bits(types: impl IntoIterator<Item = FrameType>) -> u32 {
+    types.into_iter().fold(0, |bits, t| bits | bit(t))
+}
+
+/// A client's `hello`. Fields newer clients add are ignored.
+#[derive(Debug, Deserialize)]
+struct ClientHello {
+    protocol_version: u32,
+    frame_types: Vec<String>,
+    #[serde(default)]
+    compression: Vec<String>,
+    #[serde(default)]
+    max_payload: Option<usize>,
+}
+
+/// Settle what the connection uses from the client's `hello`.
+pub fn negotiate(hello: &serde_json::Value) -> Result<Wire> {
+    let hello: ClientHello = serde_json::from_value(hello.clone())
+        .map_err(|e| ErrorCode::BadRequest.err(format!("invalid hello: {e}")))?;
+    if hello.protocol_version == 0 {
+        return Err(ErrorCode::BadRequest.err("hello protocol_version starts at 1"));
+    }
+    // Names this build doesn't know are types newer than it: not sent
+    let known = FrameType::CORE.into_iter().filter(|t| hello.frame_types.iter().any(|name| name == t.name()));
+    let frame_types = bits(known);
+    if let Some(missing) = REQUIRED.into_iter().find(|&t| frame_types & bit(t) == 0) {
+        return Err(ErrorCode::BadRequest.err(format!("hello frame_types must include {}", missing.name())));
+    }
+    let max_payload = hello.max_payload.unwrap_or(frame::MAX_PAYLOAD);
+    if max_payload < MIN_PAYLOAD {
+        return Err(ErrorCode::BadRequest.err(format!("hello max_payload must be at least {MIN_PAYLOAD}")));
+    }
+    Ok(Wire {
+        protocol_version: hello.protocol_version.min(crate::PROTOCOL_VERSION),
+        frame_types,
+        compress: hello.compression.iter().any(|c| c == COMPRESSION),
+        max_payload: max_payload.min(frame::MAX_PAYLOAD),
+    })
+}
+
+/// The wire of the connection the calling task serves.
+pub fn current() -> Wire {
+    CURRENT.try_with(Cell::get).unwrap_or_default()
+}
+
+/// Run `future` on `wire`, for [`current`].
+pub async fn scope<F: Future>(wire: Wire, future: F) -> F::Output {
+    CURRENT.scope(Cell::new(wire), future).await
+}
+
+/// Use `wire` from now on in the calling task's scope, once the client has
+/// said hello.
+pub fn set_current(wire: Wire) {
+    let _ = CURRENT.try_with(|current| current.set(wire));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn negotiates_what_both_sides_have() {
+        let hello = json!({
+            "protocol_version": 7,
+            "frame_types": ["data", "scrollback", "heartbeat", "close", "hello", "teleport"],
+            "compression": ["brotli"],
+            "max_payload": 1 << 20,
+            "ui": "tablet",
+        });
+        let wire = negotiate(&hello).unwrap();
+        assert_eq!(wire.protocol_version, crate::PROTOCOL_VERSION);
+        assert!(wire.decodes(FrameType::Hello) && !wire.decodes(FrameType::Error));
+        assert!(!wire.compress);
+        assert_eq!(wire.max_payload, frame::MAX_PAYLOAD);
+        let described = wire.describe();
+        assert_eq!(described["frame_types"], json!(["data", "heartbeat", "close", "scrollback", "hello"]));
+        assert_eq!(described["compression"], serde_json::Value::Null);
+
+        let wire = negotiate(&json!({
+            "protocol_version": 1,
+            "frame_types": ["data", "scrollback", "heartbeat", "close"],
+            "compression": ["brotli", "zstd"],
+            "max_payload": 4096,
+        }))
+        .unwrap();
+        assert!(wire.compress);
+        assert_eq!(wire.max_payload, 4096);
+
+        for bad in [
+            json!({"frame_types": ["data"]}),
+            json!({"protocol_version": 0, "frame_types": ["data", "scrollback", "heartbeat", "close"]}),
+            json!({"protocol_version": 1, "frame_types": ["data", "heartbeat", "close"]}),
+            json!({"protocol_version": 1, "frame_types": ["data", "scrollback", "heartbeat", "close"], "max_payload": 64}),
+        ] {
+            let err = negotiate(&bad).unwrap_err();
+            assert_eq!(ErrorCode::of(&err), ErrorCode::BadRequest, "{bad}");
+        }
+    }
+
+    #[test]
+    fn clients_without_hello_get_the_old_wire() {
+        let legacy = Wire::legacy();
+        assert!(legacy.decodes(FrameType::WindowUpdate) && legacy.decodes(FrameType::Extension(0x90)));
+        assert!(!legacy.decodes(FrameType::Paste) && !legacy.decodes(FrameType::Error));
+        assert!(!legacy.decodes(FrameType::PasteAck) && !legacy.decodes(FrameType::Hello));
+        assert!(FrameType::CORE.into_iter().all(|t| Wire::full().decodes(t)));
+        assert_eq!(current(), legacy);
+    }
+}
@@ -0,0 +1,126 @@
+//! Per-session timeline of who did what: creation, attaches and detaches,
+//! mirrors, resizes, and how the session ended. Queried with the
+//! `session_history` control request and IPC method, so users of a shared
+//! session can see which device did what.
+//!
+//! Each session keeps its last [`MAX_EVENTS`] events. Timelines of ended
+//! sessions are kept a while longer (see [`SessionManager`]) so "destroyed
+//! by" stays answerable.
+//!
+//! [`SessionManager`]: crate::session::SessionManager
+
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fmt;
+
+use crate::budget::Resource;
+
+/// Most events kept per session; the oldest are dropped first.
+pub const MAX_EVENTS: usize = 256;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SessionEvent {
+    pub at: chrono::DateTime<chrono::Utc>,
+    #[serde(flatten)]
+    pub kind: EventKind,
+}
+
+/// `by` is the device (or guest) responsible; None for the host itself
+/// (the CLI, or the daemon).
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum EventKind {
+    Created { by: Option<String> },
+    Attached { by: Option<String> },
+    Detached { by: Option<String> },
+    Mirrored { by: Option<String> },
+    Resized { by: Option<String>, rows: u16, cols: u16 },
+    MacroRun { by: Option<String>, name: String },
+    Destroyed { by: Option<String> },
+    Exited {
+        /// None if unknown (an adopted shell)
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        exit_code: Option<u32>,
+    },
+    /// Terminated for using up its budget
+    OverBudget { resource: Resource },
+}
+
+impl fmt::Display for EventKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let who = |by: &Option<String>| by.clone().unwrap_or_else(|| "host".to_string());
+        match self {
+            EventKind::Created { by } => write!(f, "created by {}", who(by)),
+            EventKind::Attached { by } => write!(f, "attached by {}", who(by)),
+            EventKind::Detached { by } => write!(f, "detached by {}", who(by)),
+            EventKind::Mirrored { by } => write!(f, "mirrored by {}", who(by)),
+            EventKind::Resized { by, rows, cols } => write!(f, "resized to {cols}x{rows} by {}", who(by)),
+            EventKind::MacroRun { by, name } => write!(f, "macro {name} run by {}", who(by)),
+            EventKind::Destroyed { by } => write!(f, "destroyed by {}", who(by)),
+            EventKind::Exited { exit_code: Some(code) } => write!(f, "exited with code {code}"),
+            EventKind::Exited { exit_code: None } => write!(f, "exited"),
+            EventKind::OverBudget { resource: Resource::Cpu } => write!(f, "terminated over its CPU-time budget"),
+            EventKind::OverBudget { resource: Resource::Lifetime } => write!(f, "terminated over its lifetime budget"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct SessionHistory {
+    events: VecDeque<SessionEvent>,
+}
+
+impl SessionHistory {
+    pub fn record(&mut self, kind: EventKind) {
+        // A window being dragged resizes many times: keep only the last size
+        if let (EventKind::Resized { by, .. }, Some(last)) = (&kind, self.events.back_mut()) {
+            if matches!(&last.kind, EventKind::Resized { by: last_by, .. } if last_by == by) {
+                *last = SessionEvent { at: chrono::Utc::now(), kind };
+                return;
+            }
+        }
+        if self.events.len() == MAX_EVENTS {
+            self.events.pop_front();
+        }
+        self.events.push_back(SessionEvent { at: chrono::Utc::now(), kind });
+    }
+
+    /// Oldest first.
+    pub fn events(&self) -> Vec<SessionEvent> {
+        self.events.iter().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bounded_and_coalesces_resizes() {
+        let phone = Some("phone".to_string());
+        let mut history = SessionHistory::default();
+        history.record(EventKind::Attached { by: phone.clone() });
+        history.record(EventKind::Resized { by: phone.clone(), rows: 24, cols: 80 });
+        history.record(EventKind::Resized { by: phone.clone(), rows: 40, cols: 120 });
+        history.record(EventKind::Resized { by: None, rows: 30, cols: 100 });
+        let kinds: Vec<_> = history.events().into_iter().map(|e| e.kind).collect();
+        assert_eq!(kinds, [
+            EventKind::Attached { by: phone.clone() },
+            EventKind::Resized { by: phone.clone(), rows: 40, cols: 120 },
+            EventKind::Resized { by: None, rows: 30, cols: 100 },
+        ]);
+
+        for _ in 0..MAX_EVENTS {
+            history.record(EventKind::Detached { by: phone.clone() });
+        }
+        let events = history.events();
+        assert_eq!(events.len(), MAX_EVENTS);
+        assert!(events.iter().all(|e| matches!(e.kind, EventKind::Detached { .. })));
+
+        let json = serde_json::to_value(&events[0]).unwrap();
+        assert_eq!(json["event"], "detached");
+        assert_eq!(json["by"], "phone");
+        assert!(json["at"].is_string());
+    }
+}
@@ -0,0 +1,280 @@
+//! User hooks on daemon events (`[hooks]` in config.toml).
+//!
+//! Each event maps to a list of targets. A target starting with `http://` or
+//! `https://` receives the event as a JSON POST; anything else is run with
+//! `sh -c` (`cmd /C` on Windows), the JSON on stdin and the event name in `$PHANTOM_EVENT`. Hooks
+//! run in the background: a slow or failing hook is logged and never holds
+//! up the daemon.
+
+use anyhow::{bail, Context, Result};
+use serde::Serialize;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tracing::{debug, warn};
+
+use crate::config::HooksConfig;
+
+/// Something a hook can fire on.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum HookEvent {
+    SessionCreated {
+        session_id: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        device_id: Option<String>,
+    },
+    SessionExited {
+        session_id: String,
+        /// None if unknown (an adopted shell)
+        #[serde(skip_serializing_if = "Option::is_none")]
+        exit_code: Option<u32>,
+    },
+    DevicePaired {
+        device_id: String,
+        device_name: String,
+    },
+    AuthFailed {
+        remote: String,
+        error: String,
+    },
+    /// The reaper removed a session: `exited` or `damaged`
+    SessionReaped {
+        session_id: String,
+        reason: &'static str,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        exit_code: Option<u32>,
+    },
+    /// The reaper quarantined a damaged session instead of destroying it
+    SessionQuarantined {
+        session_id: String,
+    },
+    /// Wrong pairing tokens were presented while no pairing was in
+    /// progress, or often enough that the pairing was withdrawn
+    PairingProbed {
+        remote: String,
+        device_id: String,
+        /// Pairing tokens withdrawn by this guess
+        withdrawn: usize,
+    },
+}
+
+impl HookEvent {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::SessionCreated { .. } => "session_created",
+            Self::SessionExited { .. } => "session_exited",
+            Self::DevicePaired { .. } => "device_paired",
+            Self::AuthFailed { .. } => "auth_failed",
+            Self::SessionReaped { .. } => "session_reaped",
+            Self::SessionQuarantined { .. } => "session_quarantined",
+            Self::PairingProbed { .. } => "pairing_probed",
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Payload<'a> {
+    #[serde(flatten)]
+    event: &'a HookEvent,
+    timestamp: chrono::DateTime<chrono::Utc>,
+    host: String,
+}
+
+/// Configured hooks, shared by everything that fires events.
+#[derive(Debug, Default)]
+pub struct Hooks {
+    config: HooksConfig,
+}
+
+impl Hooks {
+    pub fn new(config: HooksConfig) -> Arc<Self> {
+        Arc::new(Self { config })
+    }
+
+    /// No hooks configured.
+    pub fn disabled() -> Arc<Self> {
+        Arc::default()
+    }
+
+    fn targets(&self, event: &HookEvent) -> &[String] {
+        match event {
+            HookEvent::SessionCreated { .. } => &self.config.session_created,
+            HookEvent::SessionExited { .. } => &self.config.session_exited,
+            HookEvent::DevicePaired { .. } => &self.config.device_paired,
+            HookEvent::AuthFailed { .. } => &self.config.auth_failed,
+            HookEvent::SessionReaped { .. } => &self.config.session_reaped,
+            HookEvent::SessionQuarantined { .. } => &self.config.session_quarantined,
+            HookEvent::PairingProbed { .. } => &self.config.pairing_probed,
+        }
+    }
+
+    /// Run every hook for `event` in the background.
+    pub fn fire(&self, event: HookEvent) {
+        let targets = self.targets(&event);
+        if targets.is_empty() {
+            return;
+        }
+        let payload = Payload {
+            event: &event,
+            timestamp: chrono::Utc::now(),
+            host: crate::device_store::hostname(),
+        };
+        let body = match serde_json::to_vec(&payload) {
+            Ok(body) => Arc::new(body),
+            Err(e) => {
+                warn!("hook payload for {} failed to serialize: {e}", event.name());
+                return;
+            }
+        };
+        let timeout = Duration::from_secs(self.config.timeout_secs.max(1));
+        for target in targets {
+            let target = target.clone();
+            let body = body.clone();
+            let name = event.name();
+            tokio::spawn(async move {
+                match run_hook(&target, name, &body, timeout).await {
+                    Ok(()) => debug!("{name} hook ran: {target}"),
+                    Err(e) => warn!("{name} hook failed ({target}): {e:#}"),
+                }
+            });
+        }
+    }
+}
+
+/// `command` run by the system shell: `sh -c`, or `cmd /C` on Windows.
+pub(crate) fn shell(command: &str) -> tokio::process::Command {
+    let (program, flag) = if cfg!(windows) { ("cmd", "/C") } else { ("sh", "-c") };
+    let mut cmd = tokio::process::Command::new(program);
+    cmd.args([flag, command]);
+    cmd
+}
+
+async fn run_hook(target: &str, event: &str, body: &Arc<Vec<u8>>, timeout: Duration) -> Result<()> {
+    if target.starts_with("http://") || target.starts_with("https://") {
+        let url = target.to_string();
+        let body = body.clone();
+        return tokio::task::spawn_blocking(move || post_json(&url, &body, timeout)).await?;
+    }
+
+    let mut child = shell(target)
+        .env("PHANTOM_EVENT", event)
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::null())
+        .kill_on_drop(true)
+        .spawn()
+        .context("spawn hook command")?;
+    let mut stdin = child.stdin.take().context("hook stdin")?;
+    let status = tokio::time::timeout(timeout, async {
+        // The command may not read its input; that's fine
+        let _ = stdin.write_all(body).await;
+        drop(stdin);
+        child.wait().await
+    })
+    .await
+    .context("timed out")??;
+    if !status.success() {
+        bail!("exited with {status}");
+    }
+    Ok(())
+}
+
+/// POST `body` as JSON and require a 2xx response.
+fn post_json(url: &str, body: &[u8], timeout: Duration) -> Result<()> {
+    let tls = ureq::tls::TlsConfig::builder()
+        .root_certs(ureq::tls::RootCerts::PlatformVerifier)
+        .build();
+    let agent: ureq::Agent = ureq::Agent::config_builder()
+        .timeout_global(Some(timeout))
+        .http_status_as_error(false)
+        .user_agent(format!("phantom/{}", crate::VERSION))
+        .tls_config(tls)
+        .build()
+        .into();
+    let response = agent
+        .post(url)
+        .header("Content-Type", "application/json")
+        .send(body)
+        .context("send")?;
+    let status = response.status();
+    if !status.is_success() {
+        bail!("HTTP {}", status.as_u16());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hooks(config: HooksConfig) -> Arc<Hooks> {
+        Hooks::new(HooksConfig { timeout_secs: 5, ..config })
+    }
+
+    async fn wait_for_file(path: &std::path::Path) -> String {
+        for _ in 0..200 {
+            if let Ok(contents) = std::fs::read_to_string(path) {
+                if contents.ends_with('\n') {
+                    return contents;
+                }
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("hook never wrote {}", path.display());
+    }
+
+    #[tokio::test]
+    async fn command_hook_gets_payload_on_stdin() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let out = dir.path().join("event.json");
+        let hooks = hooks(HooksConfig {
+            session_exited: vec![format!("(echo $PHANTOM_EVENT; cat; echo) > {}", out.display())],
+            ..HooksConfig::default()
+        });
+
+        // Events without hooks do nothing
+        hooks.fire(HookEvent::DevicePaired { device_id: "d".into(), device_name: "Phone".into() });
+        hooks.fire(HookEvent::SessionExited { session_id: "abc".into(), exit_code: Some(3) });
+
+        let contents = wait_for_file(&out).await;
+        let (event, json) = contents.split_once('\n').unwrap();
+        assert_eq!(event, "session_exited");
+        let payload: serde_json::Value = serde_json::from_str(json.trim()).unwrap();
+        assert_eq!(payload["event"], "session_exited");
+        assert_eq!(payload["session_id"], "abc");
+        assert_eq!(payload["exit_code"], 3);
+        assert!(payload["timestamp"].is_string());
+    }
+
+    #[tokio::test]
+    async fn webhook_posts_json() {
+        use std::io::{Read, Write};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = std::thread::spawn(move || {
+            let (mut conn, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0u8; 4096];
+            // Headers and body arrive together or in pieces; read until the body is in
+            while !String::from_utf8_lossy(&request).contains("\"auth_failed\"") {
+                let n = conn.read(&mut buf).unwrap();
+                request.extend_from_slice(&buf[..n]);
+            }
+            conn.write_all(b"HTTP/1.1 204 No Content\r\n\r\n").unwrap();
+            String::from_utf8(request).unwrap()
+        });
+
+        let body = serde_json::to_vec(&serde_json::json!({"event": "auth_failed"})).unwrap();
+        let url = format!("http://127.0.0.1:{port}/phantom");
+        tokio::task::spawn_blocking(move || post_json(&url, &body, Duration::from_secs(5)))
+            .await
+            .unwrap()
+            .unwrap();
+
+        let request = server.join().unwrap().to_ascii_lowercase();
+        assert!(request.starts_with("post /phantom http/1.1\r\n"), "{request}");
+        assert!(request.contains(&format!("host: 127.0.0.1:{port}\r\n")), "{request}");
+        assert!(request.contains("content-type: application/json\r\n"), "{request}");
+    }
+}
@@ -0,0 +1,201 @@
+//! Rate limits on what one client types into a session.
+//!
+//! A bridge admits Data frames while the client is within its
+//! [`InputLimit`]: so many bytes and frames a second, with up to a second's
+//! worth in a burst. Input over the limit is queued and written as the
+//! limit allows; once the queue is full, further input is dropped and the
+//! client gets an Error frame (`RATE_LIMITED`). Paste frames are charged
+//! against the same limit but wait instead of being dropped, as the client
+//! is already told how far a paste has got. Restricted devices get
+//! `[bridge] restricted_input_limit`, others `input_limit`.
+
+use std::collections::VecDeque;
+use std::time::Duration;
+use tokio::time::Instant;
+
+use crate::config::InputLimit;
+
+/// A token bucket refilled at `rate` a second, holding at most a second's
+/// worth. A rate of 0 is unlimited.
+struct Bucket {
+    rate: f64,
+    tokens: f64,
+}
+
+impl Bucket {
+    fn new(rate: u64) -> Self {
+        Self { rate: rate as f64, tokens: rate as f64 }
+    }
+
+    fn refill(&mut self, elapsed: Duration) {
+        self.tokens = (self.tokens + elapsed.as_secs_f64() * self.rate).min(self.rate);
+    }
+
+    /// A whole token's left; taking `n` may then overdraw the bucket, so a
+    /// frame bigger than the rate still gets through.
+    fn ready(&self) -> bool {
+        self.rate == 0.0 || self.tokens >= 1.0
+    }
+
+    fn take(&mut self, n: f64) {
+        if self.rate > 0.0 {
+            self.tokens -= n;
+        }
+    }
+
+    /// Time until the bucket is [`ready`](Self::ready).
+    fn wait(&self) -> Duration {
+        if self.ready() {
+            return Duration::ZERO;
+        }
+        Duration::from_secs_f64((1.0 - self.tokens) / self.rate)
+    }
+}
+
+/// What became of a Data frame.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Admission {
+    /// Write it now
+    Write(Vec<u8>),
+    /// Held until the limit allows
+    Queued,
+    /// The queue is full; `first` is set for the first frame dropped since
+    /// the queue last emptied, so the client is told once
+    Dropped { first: bool },
+}
+
+/// Input one bridge has taken from its client.
+pub struct InputLimiter {
+    bytes: Bucket,
+    frames: Bucket,
+    refilled: Instant,
+    /// Queued frames' data and sequence numbers
+    queue: VecDeque<(Vec<u8>, u64)>,
+    queued_bytes: usize,
+    max_queued_bytes: usize,
+    dropping: bool,
+}
+
+impl InputLimiter {
+    pub fn new(limit: InputLimit, now: Instant) -> Self {
+        Self {
+            bytes: Bucket::new(limit.bytes_per_sec),
+            frames: Bucket::new(limit.frames_per_sec),
+            refilled: now,
+            queue: VecDeque::new(),
+            queued_bytes: 0,
+            max_queued_bytes: limit.queue_bytes,
+            dropping: false,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.refilled);
+        self.bytes.refill(elapsed);
+        self.frames.refill(elapsed);
+        self.refilled = now;
+    }
+
+    fn ready(&self) -> bool {
+        self.bytes.ready() && self.frames.ready()
+    }
+
+    fn take(&mut self, len: usize) {
+        self.bytes.take(len as f64);
+        self.frames.take(1.0);
+    }
+
+    /// Admit Data frame `seq`'s `data`, received at `now`.
+    pub fn admit(&mut self, data: Vec<u8>, seq: u64, now: Instant) -> Admission {
+        self.refill(now);
+        if self.queue.is_empty() && self.ready() {
+            self.take(data.len());
+            return Admission::Write(data);
+        }
+        if self.queued_bytes + data.len() > self.max_queued_bytes {
+            let first = !self.dropping;
+            self.dropping = true;
+            return Admission::Dropped { first };
+        }
+        self.queued_bytes += data.len();
+        self.queue.push_back((data, seq));
+        Admission::Queued
+    }
+
+    /// When queued input can next be written, if any is queued.
+    pub fn release_at(&self) -> Option<Instant> {
+        (!self.queue.is_empty()).then(|| self.refilled + self.bytes.wait().max(self.frames.wait()))
+    }
+
+    /// Queued input the limit allows writing at `now`, in order, and the
+    /// sequence of the last frame in it.
+    pub fn release(&mut self, now: Instant) -> (Vec<u8>, Option<u64>) {
+        self.refill(now);
+        let mut out = Vec::new();
+        let mut last = None;
+        while self.ready() {
+            let Some((data, seq)) = self.queue.pop_front() else {
+                break;
+            };
+            self.take(data.len());
+            self.queued_bytes -= data.len();
+            out.extend_from_slice(&data);
+            last = Some(seq);
+        }
+        if self.queue.is_empty() {
+            self.dropping = false;
+        }
+        (out, last)
+    }
+
+    /// Charge `len` bytes of paste received at `now`, returning how long to
+    /// wait before writing them.
+    pub fn charge_paste(&mut self, len: usize, now: Instant) -> Duration {
+        self.refill(now);
+        let wait = self.bytes.wait().max(self.frames.wait());
+        self.take(len);
+        wait
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limit(bytes_per_sec: u64, frames_per_sec: u64, queue_bytes: usize) -> InputLimit {
+        InputLimit { bytes_per_sec, frames_per_sec, queue_bytes }
+    }
+
+    #[test]
+    fn queues_input_over_the_limit_then_drops_it() {
+        let start = Instant::now();
+        let mut limiter = InputLimiter::new(limit(8, 0, 8), start);
+        assert_eq!(limiter.admit(b"0123456789".to_vec(), 1, start), Admission::Write(b"0123456789".to_vec()));
+        assert_eq!(limiter.admit(b"abcd".to_vec(), 2, start), Admission::Queued);
+        assert_eq!(limiter.admit(b"efgh".to_vec(), 3, start), Admission::Queued);
+        assert_eq!(limiter.admit(b"i".to_vec(), 4, start), Admission::Dropped { first: true });
+        assert_eq!(limiter.admit(b"j".to_vec(), 5, start), Admission::Dropped { first: false });
+
+        // 2 bytes overdrawn: a whole byte's credit is back in 3/8s
+        assert_eq!(limiter.release_at(), Some(start + Duration::from_millis(375)));
+        assert_eq!(limiter.release(start + Duration::from_millis(200)), (Vec::new(), None));
+        assert_eq!(limiter.release(start + Duration::from_millis(400)), (b"abcd".to_vec(), Some(2)));
+        assert_eq!(limiter.release(start + Duration::from_secs(2)), (b"efgh".to_vec(), Some(3)));
+        assert_eq!(limiter.release_at(), None);
+        assert_eq!(limiter.admit(b"k".to_vec(), 6, start + Duration::from_secs(2)), Admission::Write(b"k".to_vec()));
+    }
+
+    #[test]
+    fn limits_frames_and_holds_pastes_back() {
+        let start = Instant::now();
+        let mut limiter = InputLimiter::new(limit(0, 2, 64), start);
+        assert!(matches!(limiter.admit(b"a".to_vec(), 1, start), Admission::Write(_)));
+        assert!(matches!(limiter.admit(b"b".to_vec(), 2, start), Admission::Write(_)));
+        assert_eq!(limiter.admit(b"c".to_vec(), 3, start), Admission::Queued);
+        assert_eq!(limiter.release_at(), Some(start + Duration::from_millis(500)));
+
+        let mut limiter = InputLimiter::new(limit(64, 0, 64), start);
+        assert_eq!(limiter.charge_paste(96, start), Duration::ZERO);
+        assert_eq!(limiter.charge_paste(96, start), Duration::from_micros(515_625));
+    }
+}
@@ -1,14 +1,28 @@
 use anyhow::{bail, Context, Result};
 use serde::{Deserialize, Serialize};
 use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
-use tokio::net::UnixListener;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines, ReadHalf, WriteHalf};
 use tokio_util::sync::CancellationToken;
 use tracing::{info, warn};
 
-use crate::device_store::DeviceStore;
+use crate::archive::SessionArchive;
+use crate::connections::Connections;
+use crate::device_store::{DeviceStore, GUEST_TTL_DEFAULT};
+use crate::errors::{ErrorCode, SessionError};
+use crate::exec::{self, Exec};
+use crate::local_socket::{self, Stream};
+use crate::reset;
+use crate::scheduler::ScheduledJob;
+use crate::server::{ConnectionGauges, RateLimits};
 use crate::session::SessionManager;
+use crate::terminal::NativePty;
+use crate::ui_state::{RecentErrors, UiState};
+#[cfg(unix)]
+use crate::upgrade::Upgrader;
+use crate::vault::Vault;
+use phantom_frame::CloseCode;
 
 /// Maximum concurrent IPC connections (defense in depth).
 const MAX_CONNECTIONS: usize = 5;
@@ -18,6 +32,8 @@ const MAX_LINE_LENGTH: usize = 65536;
 const MAX_ID_LENGTH: usize = 128;
 /// Maximum requests per second per IPC connection.
 const MAX_REQUESTS_PER_SEC: u32 = 20;
+/// How often a `subscribe_ui` connection checks for state changes.
+const UI_POLL_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
 
 #[derive(Debug, Deserialize)]
 struct Request {
@@ -33,15 +49,21 @@ struct Response {
     #[serde(skip_serializing_if = "Option::is_none")]
     result: Option<serde_json::Value>,
     #[serde(skip_serializing_if = "Option::is_none")]
+    code: Option<ErrorCode>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     error: Option<String>,
 }
 
 impl Response {
     fn ok(id: u64, result: serde_json::Value) -> Self {
-        Self { id, result: Some(result), error: None }
+        Self { id, result: Some(result), code: None, error: None }
+    }
+    fn err(id: u64, code: ErrorCode, error: impl Into<String>) -> Self {
+        Self { id, result: None, code: Some(code), error: Some(error.into()) }
     }
-    fn err(id: u64, error: impl Into<String>) -> Self {
-        Self { id, result: None, error: Some(error.into()) }
+    /// `err`, coded where it was raised.
+    fn failure(id: u64, err: &anyhow::Error) -> Self {
+        Self::err(id, ErrorCode::of(err), format!("{err:#}"))
     }
 }
 
@@ -57,12 +79,23 @@ fn validate_id(id: &str) -> Result<()> {
 }
 
 pub struct IpcServer {
+    phantom_dir: PathBuf,
     socket_path: PathBuf,
     session_manager: Arc<SessionManager>,
     device_store: Arc<DeviceStore>,
     fingerprint: String,
     bind_address: String,
     start_time: std::time::Instant,
+    recent_errors: Arc<RecentErrors>,
+    #[cfg(unix)]
+    upgrader: Option<Arc<Upgrader>>,
+    /// A socket bound elsewhere, listened on instead of binding one
+    #[cfg(unix)]
+    inherited: std::sync::Mutex<Option<std::os::unix::net::UnixListener>>,
+    vault: Option<Arc<Vault>>,
+    connection_gauges: Option<Arc<ConnectionGauges>>,
+    rate_limits: Option<Arc<RateLimits>>,
+    connections: Option<Arc<Connections>>,
 }
 
 impl IpcServer {
@@ -74,43 +107,85 @@ impl IpcServer {
         bind_address: String,
     ) -> Self {
         Self {
+            phantom_dir: phantom_dir.to_path_buf(),
             socket_path: phantom_dir.join("daemon.sock"),
             session_manager,
             device_store,
             fingerprint,
             bind_address,
             start_time: std::time::Instant::now(),
+            recent_errors: RecentErrors::new(),
+            #[cfg(unix)]
+            upgrader: None,
+            #[cfg(unix)]
+            inherited: std::sync::Mutex::new(None),
+            vault: None,
+            connection_gauges: None,
+            rate_limits: None,
+            connections: None,
         }
     }
 
-    pub async fn run(self: Arc<Self>, cancel: CancellationToken) -> Result<()> {
-        // Clean up stale socket
-        if self.socket_path.exists() {
-            std::fs::remove_file(&self.socket_path)
-                .context("remove stale IPC socket")?;
-        }
+    /// Report the QUIC server's open connection counts in `status`.
+    pub fn with_connection_gauges(mut self, gauges: Arc<ConnectionGauges>) -> Self {
+        self.connection_gauges = Some(gauges);
+        self
+    }
 
-        let listener = UnixListener::bind(&self.socket_path)
-            .context("bind IPC socket")?;
+    /// Let `rate_limit_status` and `rate_limit_clear` see and lift the QUIC
+    /// server's per-IP rate limits.
+    pub fn with_rate_limits(mut self, limits: Arc<RateLimits>) -> Self {
+        self.rate_limits = Some(limits);
+        self
+    }
 
-        // Set socket permissions to 0o600
-        #[cfg(unix)]
-        {
-            use std::os::unix::fs::PermissionsExt;
-            std::fs::set_permissions(
-                &self.socket_path,
-                std::fs::Permissions::from_mode(0o600),
-            ).context("set IPC socket permissions")?;
-        }
+    /// Let `list_connections` see the QUIC server's live connections.
+    pub fn with_connections(mut self, connections: Arc<Connections>) -> Self {
+        self.connections = Some(connections);
+        self
+    }
+
+    /// Allow `upgrade` to hand the daemon over to a new binary.
+    #[cfg(unix)]
+    pub fn with_upgrader(mut self, upgrader: Arc<Upgrader>) -> Self {
+        self.upgrader = Some(upgrader);
+        self
+    }
+
+    /// Listen on `listener`, such as one systemd bound for the daemon,
+    /// instead of binding the socket. It is left in place on shutdown.
+    #[cfg(unix)]
+    pub fn with_listener(mut self, listener: std::os::unix::net::UnixListener) -> Self {
+        self.inherited = std::sync::Mutex::new(Some(listener));
+        self
+    }
+
+    /// Encrypt exported session archives (and read encrypted ones).
+    pub fn with_vault(mut self, vault: Arc<Vault>) -> Self {
+        self.vault = Some(vault);
+        self
+    }
 
-        info!("IPC server listening on {}", self.socket_path.display());
+    /// Report these warnings and errors in `ui_state`.
+    pub fn with_recent_errors(mut self, recent_errors: Arc<RecentErrors>) -> Self {
+        self.recent_errors = recent_errors;
+        self
+    }
+
+    pub async fn run(self: Arc<Self>, cancel: CancellationToken) -> Result<()> {
+        let (mut listener, inherited) = match self.take_inherited()? {
+            Some(listener) => (listener, true),
+            None => (local_socket::Listener::bind(&self.socket_path)?, false),
+        };
+
+        info!("IPC server listening on {}", listener.address().display());
 
         let semaphore = Arc::new(tokio::sync::Semaphore::new(MAX_CONNECTIONS));
 
         loop {
             tokio::select! {
                 accept = listener.accept() => {
-                    let (stream, _) = accept.context("accept IPC connection")?;
+                    let stream = accept.context("accept IPC connection")?;
 
                     let permit = match semaphore.clone().try_acquire_owned() {
                         Ok(permit) => permit,
@@ -121,7 +196,7 @@ impl IpcServer {
                     };
 
                     let server = self.clone();
-                    tokio::spawn(async move {
+                    self.session_manager.tasks().spawn(async move {
                         if let Err(e) = server.handle_client(stream).await {
                             warn!("IPC client error: {e:#}");
                         }
@@ -134,14 +209,27 @@ impl IpcServer {
             }
         }
 
-        // Clean up socket on shutdown
-        let _ = std::fs::remove_file(&self.socket_path);
+        // Clean up socket on shutdown, unless it's systemd's to keep
+        if !inherited {
+            let _ = std::fs::remove_file(&self.socket_path);
+        }
         info!("IPC server shut down");
         Ok(())
     }
 
-    async fn handle_client(&self, stream: tokio::net::UnixStream) -> Result<()> {
-        let (reader, mut writer) = stream.into_split();
+    #[cfg(unix)]
+    fn take_inherited(&self) -> Result<Option<local_socket::Listener>> {
+        let inherited = self.inherited.lock().expect("IPC listener lock").take();
+        inherited.map(local_socket::Listener::from_std).transpose()
+    }
+
+    #[cfg(not(unix))]
+    fn take_inherited(&self) -> Result<Option<local_socket::Listener>> {
+        Ok(None)
+    }
+
+    async fn handle_client<S: Stream>(&self, stream: S) -> Result<()> {
+        let (reader, mut writer) = tokio::io::split(stream);
         let mut lines = BufReader::new(reader).lines();
         let mut window_start = tokio::time::Instant::now();
         let mut request_count: u32 = 0;
@@ -155,7 +243,7 @@ impl IpcServer {
             }
             request_count += 1;
             if request_count > MAX_REQUESTS_PER_SEC {
-                let resp = Response::err(0, "rate limit exceeded");
+                let resp = Response::err(0, ErrorCode::LimitExceeded, "rate limit exceeded");
                 let mut out = serde_json::to_vec(&resp)?;
                 out.push(b'\n');
                 writer.write_all(&out).await?;
@@ -163,7 +251,7 @@ impl IpcServer {
             }
 
             if line.len() > MAX_LINE_LENGTH {
-                let resp = Response::err(0, "request too large");
+                let resp = Response::err(0, ErrorCode::LimitExceeded, "request too large");
                 let mut out = serde_json::to_vec(&resp)?;
                 out.push(b'\n');
                 writer.write_all(&out).await?;
@@ -173,7 +261,7 @@ impl IpcServer {
             let req: Request = match serde_json::from_str(&line) {
                 Ok(r) => r,
                 Err(e) => {
-                    let resp = Response::err(0, format!("invalid JSON: {e}"));
+                    let resp = Response::err(0, ErrorCode::BadRequest, format!("invalid JSON: {e}"));
                     let mut out = serde_json::to_vec(&resp)?;
                     out.push(b'\n');
                     writer.write_all(&out).await?;
@@ -181,6 +269,10 @@ impl IpcServer {
                 }
             };
 
+            if req.method == "subscribe_ui" {
+                return self.stream_ui_state(req.id, lines, writer).await;
+            }
+
             let resp = self.dispatch(req).await;
             let mut out = serde_json::to_vec(&resp)?;
             out.push(b'\n');
@@ -190,15 +282,81 @@ impl IpcServer {
         Ok(())
     }
 
+    /// Answer `subscribe_ui` with the current state, then push
+    /// `{"event": "ui_state", "state": ...}` whenever it changes, until the
+    /// client disconnects. The connection takes no further requests.
+    async fn stream_ui_state<S: Stream>(
+        &self,
+        id: u64,
+        mut lines: Lines<BufReader<ReadHalf<S>>>,
+        mut writer: WriteHalf<S>,
+    ) -> Result<()> {
+        let mut state = self.ui_state();
+        let mut out = serde_json::to_vec(&Response::ok(id, serde_json::to_value(&state)?))?;
+        out.push(b'\n');
+        writer.write_all(&out).await?;
+
+        let mut poll = tokio::time::interval(UI_POLL_INTERVAL);
+        poll.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            tokio::select! {
+                _ = poll.tick() => {
+                    let current = self.ui_state();
+                    if current == state {
+                        continue;
+                    }
+                    state = current;
+                    let mut out = serde_json::to_vec(&serde_json::json!({
+                        "event": "ui_state",
+                        "state": state,
+                    }))?;
+                    out.push(b'\n');
+                    writer.write_all(&out).await?;
+                }
+                line = lines.next_line() => {
+                    if line?.is_none() {
+                        return Ok(());
+                    }
+                }
+            }
+        }
+    }
+
+    fn ui_state(&self) -> UiState {
+        UiState::collect(&self.session_manager, &self.device_store, &self.recent_errors, &self.bind_address)
+    }
+
     async fn dispatch(&self, req: Request) -> Response {
         match req.method.as_str() {
             "status" => self.handle_status(req.id),
-            "list_sessions" => self.handle_list_sessions(req.id),
+            "list_sessions" => self.handle_list_sessions(req.id, &req.params),
             "list_devices" => self.handle_list_devices(req.id),
-            "create_pairing" => self.handle_create_pairing(req.id),
+            "list_connections" => self.handle_list_connections(req.id),
+            "ui_state" => match serde_json::to_value(self.ui_state()) {
+                Ok(state) => Response::ok(req.id, state),
+                Err(e) => Response::err(req.id, ErrorCode::Internal, format!("{e}")),
+            },
+            "create_pairing" => self.handle_create_pairing(req.id, &req.params),
             "revoke_device" => self.handle_revoke_device(req.id, &req.params),
+            "set_agent_forwarding" => self.handle_set_agent_forwarding(req.id, &req.params),
+            "set_restrictions" => self.handle_set_restrictions(req.id, &req.params),
             "destroy_session" => self.handle_destroy_session(req.id, &req.params),
-            _ => Response::err(req.id, format!("unknown method: {}", req.method)),
+            "session_history" => self.handle_session_history(req.id, &req.params),
+            "resize_session" => self.handle_resize_session(req.id, &req.params),
+            "inspect_session" => self.handle_inspect_session(req.id, &req.params),
+            "export_session" => self.handle_export_session(req.id, &req.params),
+            "import_session" => self.handle_import_session(req.id, &req.params),
+            "exec" => self.handle_exec(req.id, &req.params).await,
+            "list_schedules" => self.handle_list_schedules(req.id),
+            "add_schedule" => self.handle_add_schedule(req.id, &req.params),
+            "remove_schedule" => self.handle_remove_schedule(req.id, &req.params),
+            "scheduled_run" => self.handle_scheduled_run(req.id, &req.params),
+            "create_guest_link" => self.handle_create_guest_link(req.id, &req.params),
+            "upgrade" => self.handle_upgrade(req.id, &req.params).await,
+            "reset" => self.handle_reset(req.id, &req.params),
+            "rate_limit_status" => self.handle_rate_limit_status(req.id),
+            "rate_limit_clear" => self.handle_rate_limit_clear(req.id, &req.params),
+            _ => Response::err(req.id, ErrorCode::Unsupported, format!("unknown method: {}", req.method)),
         }
     }
 
@@ -212,6 +370,7 @@ impl IpcServer {
                     serde_json::json!({
                         "device_id": d.device_id,
                         "device_name": d.device_name,
+                        "protocol": self.session_manager.connected_protocol(cid),
                     })
                 })
             }).collect()
@@ -224,22 +383,34 @@ impl IpcServer {
             "bind_address": self.bind_address,
             "cert_fingerprint": self.fingerprint,
             "connected_devices": connected_devices,
+            "connections": self.connection_gauges.as_ref().map(|g| g.counts()),
+            "bridge_task_panics": self.session_manager.bridge_panics().load(Ordering::Relaxed),
         }))
     }
 
-    fn handle_list_sessions(&self, id: u64) -> Response {
+    /// All sessions, or those in `project` (a project name or root).
+    fn handle_list_sessions(&self, id: u64, params: &serde_json::Value) -> Response {
+        let project = params.get("project").and_then(|v| v.as_str());
         let sessions = self.session_manager.list_sessions();
-        let list: Vec<serde_json::Value> = sessions.into_iter().map(|s| {
+        let sessions = sessions.into_iter().filter(|s| project.is_none_or(|p| s.in_project(p)));
+        let list: Vec<serde_json::Value> = sessions.map(|s| {
             serde_json::json!({
                 "id": s.id,
                 "alive": s.alive,
                 "created_at": s.created_at.to_rfc3339(),
                 "shell": s.shell,
+                "tmux_session": s.tmux_session,
+                "agent_forwarding": s.agent_forwarding,
                 "attached": s.attached,
+                "mirrors": s.mirrors,
                 "created_by_device_id": s.created_by_device_id,
+                "user": s.user,
+                "terminal": s.terminal,
                 "last_attached_at": s.last_attached_at.map(|t| t.to_rfc3339()),
                 "last_attached_by": s.last_attached_by,
                 "last_activity_at": s.last_activity_at.to_rfc3339(),
+                "project": s.project,
+                "env": s.env,
             })
         }).collect();
         Response::ok(id, serde_json::json!(list))
@@ -247,6 +418,8 @@ impl IpcServer {
 
     fn handle_list_devices(&self, id: u64) -> Response {
         let devices = self.device_store.list_devices();
+        // Include attached bridges' traffic since the last accounting
+        self.session_manager.account_traffic();
         let connected = self.session_manager.connected_device_ids();
         let list: Vec<serde_json::Value> = devices.into_iter().map(|d| {
             serde_json::json!({
@@ -255,23 +428,76 @@ impl IpcServer {
                 "paired_at": d.paired_at.to_rfc3339(),
                 "last_seen": d.last_seen.map(|t| t.to_rfc3339()),
                 "is_connected": connected.contains(&d.device_id),
+                "agent_forwarding": d.agent_forwarding,
+                "user": d.user,
+                "restrictions": d.restrictions,
+                "bandwidth": self.session_manager.bandwidth().device_usage(&d.device_id),
             })
         }).collect();
         Response::ok(id, serde_json::json!(list))
     }
 
-    fn handle_create_pairing(&self, id: u64) -> Response {
-        let port = self.bind_address
+    fn bind_port(&self) -> u16 {
+        self.bind_address
             .rsplit(':')
             .next()
             .and_then(|p| p.parse::<u16>().ok())
-            .unwrap_or(4433);
+            .unwrap_or(4433)
+    }
+
+    fn handle_create_pairing(&self, id: u64, params: &serde_json::Value) -> Response {
+        let port = self.bind_port();
+        let user = params.get("user").and_then(|v| v.as_str());
+        match user {
+            Some(user) => {
+                if let Err(e) = crate::users::validate_user(user) {
+                    return Response::err(id, ErrorCode::BadRequest, format!("{e}"));
+                }
+            }
+            None if self.session_manager.multi_user() => {
+                return Response::err(id, ErrorCode::BadRequest, "missing user parameter (required in multi-user mode)");
+            }
+            None => {}
+        }
 
-        let data = self.device_store.generate_pairing_data(&self.fingerprint, port);
+        let data = self.device_store.generate_pairing_data(&self.fingerprint, port, user, self.session_manager.wake_info());
         Response::ok(id, serde_json::json!({
             "qr_payload_json": data.qr_payload_json,
             "token": data.token,
             "host": data.host,
+            "hosts": data.hosts,
+            "port": data.port,
+            "fingerprint": data.fingerprint,
+            "expires_in_secs": data.expires_in_secs,
+            "wake": data.wake,
+        }))
+    }
+
+    fn handle_create_guest_link(&self, id: u64, params: &serde_json::Value) -> Response {
+        let session_id = match params.get("session_id").and_then(|v| v.as_str()) {
+            Some(id) => id,
+            None => return Response::err(id, ErrorCode::BadRequest, "missing session_id parameter"),
+        };
+        if let Err(e) = validate_id(session_id) {
+            return Response::err(id, ErrorCode::BadRequest, format!("invalid session_id: {e}"));
+        }
+        if self.session_manager.get_session(session_id).is_none() {
+            return Response::failure(id, &SessionError::NotFound.into());
+        }
+        let ttl = params
+            .get("ttl_secs")
+            .and_then(|v| v.as_u64())
+            .filter(|&secs| secs > 0)
+            .map(std::time::Duration::from_secs)
+            .unwrap_or(GUEST_TTL_DEFAULT);
+
+        let data = self.device_store.generate_guest_link(session_id, &self.fingerprint, self.bind_port(), ttl);
+        Response::ok(id, serde_json::json!({
+            "payload_json": data.payload_json,
+            "token": data.token,
+            "session_id": data.session_id,
+            "host": data.host,
+            "hosts": data.hosts,
             "port": data.port,
             "fingerprint": data.fingerprint,
             "expires_in_secs": data.expires_in_secs,
@@ -281,28 +507,396 @@ impl IpcServer {
     fn handle_revoke_device(&self, id: u64, params: &serde_json::Value) -> Response {
         let device_id = match params.get("device_id").and_then(|v| v.as_str()) {
             Some(id) => id,
-            None => return Response::err(id, "missing device_id parameter"),
+            None => return Response::err(id, ErrorCode::BadRequest, "missing device_id parameter"),
         };
         if let Err(e) = validate_id(device_id) {
-            return Response::err(id, format!("invalid device_id: {e}"));
+            return Response::err(id, ErrorCode::BadRequest, format!("invalid device_id: {e}"));
         }
         match self.device_store.revoke_device(device_id) {
+            Ok(()) => {
+                // It can't reconnect; don't leave it on the connection it has
+                self.session_manager.close_connection(device_id, CloseCode::Revoked);
+                Response::ok(id, serde_json::json!({"success": true}))
+            }
+            Err(e) => Response::failure(id, &e),
+        }
+    }
+
+    fn handle_set_agent_forwarding(&self, id: u64, params: &serde_json::Value) -> Response {
+        let device_id = match params.get("device_id").and_then(|v| v.as_str()) {
+            Some(id) => id,
+            None => return Response::err(id, ErrorCode::BadRequest, "missing device_id parameter"),
+        };
+        if let Err(e) = validate_id(device_id) {
+            return Response::err(id, ErrorCode::BadRequest, format!("invalid device_id: {e}"));
+        }
+        let Some(allowed) = params.get("allowed").and_then(|v| v.as_bool()) else {
+            return Response::err(id, ErrorCode::BadRequest, "missing allowed parameter");
+        };
+        match self.device_store.set_agent_forwarding(device_id, allowed) {
             Ok(()) => Response::ok(id, serde_json::json!({"success": true})),
-            Err(e) => Response::err(id, format!("{e}")),
+            Err(e) => Response::failure(id, &e),
+        }
+    }
+
+    /// `restrictions` is a [`Restrictions`](crate::restrictions::Restrictions)
+    /// object, or null to lift them.
+    fn handle_set_restrictions(&self, id: u64, params: &serde_json::Value) -> Response {
+        let device_id = match params.get("device_id").and_then(|v| v.as_str()) {
+            Some(id) => id,
+            None => return Response::err(id, ErrorCode::BadRequest, "missing device_id parameter"),
+        };
+        if let Err(e) = validate_id(device_id) {
+            return Response::err(id, ErrorCode::BadRequest, format!("invalid device_id: {e}"));
+        }
+        let Some(restrictions) = params.get("restrictions") else {
+            return Response::err(id, ErrorCode::BadRequest, "missing restrictions parameter");
+        };
+        let restrictions = match serde_json::from_value(restrictions.clone()) {
+            Ok(restrictions) => restrictions,
+            Err(e) => return Response::err(id, ErrorCode::BadRequest, format!("invalid restrictions: {e}")),
+        };
+        match self.device_store.set_restrictions(device_id, restrictions) {
+            Ok(()) => Response::ok(id, serde_json::json!({"success": true})),
+            Err(e) => Response::failure(id, &e),
         }
     }
 
     fn handle_destroy_session(&self, id: u64, params: &serde_json::Value) -> Response {
         let session_id = match params.get("session_id").and_then(|v| v.as_str()) {
             Some(id) => id,
-            None => return Response::err(id, "missing session_id parameter"),
+            None => return Response::err(id, ErrorCode::BadRequest, "missing session_id parameter"),
         };
         if let Err(e) = validate_id(session_id) {
-            return Response::err(id, format!("invalid session_id: {e}"));
+            return Response::err(id, ErrorCode::BadRequest, format!("invalid session_id: {e}"));
         }
         match self.session_manager.destroy_session(session_id) {
             Ok(()) => Response::ok(id, serde_json::json!({"success": true})),
-            Err(e) => Response::err(id, format!("{e}")),
+            Err(e) => Response::failure(id, &e),
+        }
+    }
+
+    /// Diagnostics for a session that won't reattach.
+    fn handle_inspect_session(&self, id: u64, params: &serde_json::Value) -> Response {
+        let session_id = match params.get("session_id").and_then(|v| v.as_str()) {
+            Some(id) => id,
+            None => return Response::err(id, ErrorCode::BadRequest, "missing session_id parameter"),
+        };
+        if let Err(e) = validate_id(session_id) {
+            return Response::err(id, ErrorCode::BadRequest, format!("invalid session_id: {e}"));
         }
+        match self.session_manager.inspect_session(session_id) {
+            Some(diagnostics) => Response::ok(id, serde_json::json!(diagnostics)),
+            None => Response::failure(id, &SessionError::NotFound.into()),
+        }
+    }
+
+    /// Scheduled commands and their recent runs.
+    fn handle_list_schedules(&self, id: u64) -> Response {
+        let scheduler = self.session_manager.scheduler();
+        Response::ok(id, serde_json::json!({
+            "schedules": scheduler.jobs(),
+            "runs": scheduler.runs(),
+        }))
+    }
+
+    fn handle_add_schedule(&self, id: u64, params: &serde_json::Value) -> Response {
+        let job: ScheduledJob = match serde_json::from_value(params.clone()) {
+            Ok(job) => job,
+            Err(e) => return Response::err(id, ErrorCode::BadRequest, format!("invalid schedule: {e}")),
+        };
+        match self.session_manager.scheduler().add(job) {
+            Ok(info) => Response::ok(id, serde_json::json!(info)),
+            Err(e) => Response::failure(id, &e),
+        }
+    }
+
+    fn handle_remove_schedule(&self, id: u64, params: &serde_json::Value) -> Response {
+        let Some(name) = params.get("name").and_then(|v| v.as_str()) else {
+            return Response::err(id, ErrorCode::BadRequest, "missing name parameter");
+        };
+        match self.session_manager.scheduler().remove(name) {
+            Ok(()) => Response::ok(id, serde_json::json!({"removed": name})),
+            Err(e) => Response::failure(id, &e),
+        }
+    }
+
+    /// A scheduled run with its output.
+    fn handle_scheduled_run(&self, id: u64, params: &serde_json::Value) -> Response {
+        let Some(run_id) = params.get("run_id").and_then(|v| v.as_u64()) else {
+            return Response::err(id, ErrorCode::BadRequest, "missing run_id parameter");
+        };
+        match self.session_manager.scheduler().run(run_id) {
+            Some((run, output)) => Response::ok(id, serde_json::json!({"run": run, "output": output})),
+            None => Response::err(id, ErrorCode::NotFound, "run not found"),
+        }
+    }
+
+    /// Resize a detached session ahead of an attach.
+    fn handle_resize_session(&self, id: u64, params: &serde_json::Value) -> Response {
+        let session_id = match params.get("session_id").and_then(|v| v.as_str()) {
+            Some(id) => id,
+            None => return Response::err(id, ErrorCode::BadRequest, "missing session_id parameter"),
+        };
+        if let Err(e) = validate_id(session_id) {
+            return Response::err(id, ErrorCode::BadRequest, format!("invalid session_id: {e}"));
+        }
+        let rows = params.get("rows").and_then(|v| v.as_u64());
+        let cols = params.get("cols").and_then(|v| v.as_u64());
+        let (Some(rows), Some(cols)) = (rows, cols) else {
+            return Response::err(id, ErrorCode::BadRequest, "missing rows or cols parameter");
+        };
+        let (rows, cols) = (rows.clamp(1, 500) as u16, cols.clamp(1, 500) as u16);
+        match self.session_manager.resize_session(session_id, rows, cols, None) {
+            Ok(()) => Response::ok(id, serde_json::json!({"rows": rows, "cols": cols})),
+            Err(e) => Response::failure(id, &e),
+        }
+    }
+
+    /// Timeline of a live or recently ended session.
+    fn handle_session_history(&self, id: u64, params: &serde_json::Value) -> Response {
+        let session_id = match params.get("session_id").and_then(|v| v.as_str()) {
+            Some(id) => id,
+            None => return Response::err(id, ErrorCode::BadRequest, "missing session_id parameter"),
+        };
+        if let Err(e) = validate_id(session_id) {
+            return Response::err(id, ErrorCode::BadRequest, format!("invalid session_id: {e}"));
+        }
+        match self.session_manager.session_history(session_id) {
+            Some(history) => Response::ok(id, serde_json::json!({
+                "session_id": session_id,
+                "ended": history.ended,
+                "events": history.events,
+            })),
+            None => Response::failure(id, &SessionError::NotFound.into()),
+        }
+    }
+
+    /// Write a session archive to `path` (absolute, on this host), encrypted
+    /// unless `plaintext`.
+    fn handle_export_session(&self, id: u64, params: &serde_json::Value) -> Response {
+        let session_id = match params.get("session_id").and_then(|v| v.as_str()) {
+            Some(id) => id,
+            None => return Response::err(id, ErrorCode::BadRequest, "missing session_id parameter"),
+        };
+        if let Err(e) = validate_id(session_id) {
+            return Response::err(id, ErrorCode::BadRequest, format!("invalid session_id: {e}"));
+        }
+        let path = match archive_path(params) {
+            Ok(path) => path,
+            Err(e) => return Response::err(id, ErrorCode::BadRequest, format!("{e}")),
+        };
+        let plaintext = params.get("plaintext").and_then(|v| v.as_bool()).unwrap_or(false);
+        let vault = self.vault.as_deref().filter(|_| !plaintext);
+        let written = self
+            .session_manager
+            .export_session(session_id)
+            .and_then(|archive| archive.write(path, vault).map(|()| archive));
+        match written {
+            Ok(archive) => Response::ok(id, serde_json::json!({
+                "path": path,
+                "scrollback_bytes": archive.scrollback.len(),
+                "encrypted": vault.is_some(),
+            })),
+            Err(e) => Response::failure(id, &e),
+        }
+    }
+
+    /// Start a new session from the archive at `path`, as `user` if given
+    /// (required in multi-user mode).
+    fn handle_import_session(&self, id: u64, params: &serde_json::Value) -> Response {
+        let path = match archive_path(params) {
+            Ok(path) => path,
+            Err(e) => return Response::err(id, ErrorCode::BadRequest, format!("{e}")),
+        };
+        let user = params.get("user").and_then(|v| v.as_str());
+        if let Some(Err(e)) = user.map(crate::users::validate_user) {
+            return Response::err(id, ErrorCode::BadRequest, format!("{e}"));
+        }
+        let rows = (params.get("rows").and_then(|v| v.as_u64()).unwrap_or(24) as u16).clamp(1, 500);
+        let cols = (params.get("cols").and_then(|v| v.as_u64()).unwrap_or(80) as u16).clamp(1, 500);
+        let imported = SessionArchive::read(path, self.vault.as_deref()).and_then(|archive| {
+            let session_id = self.session_manager.import_session(&archive, rows, cols, user)?;
+            Ok((session_id, archive))
+        });
+        match imported {
+            Ok((session_id, archive)) => Response::ok(id, serde_json::json!({
+                "session_id": session_id,
+                "original_session_id": archive.session.id,
+                "host": archive.host,
+                "exported_at": archive.exported_at.to_rfc3339(),
+            })),
+            Err(e) => Response::failure(id, &e),
+        }
+    }
+
+    /// Hand the daemon over to a new binary, then exit.
+    #[cfg(unix)]
+    async fn handle_upgrade(&self, id: u64, params: &serde_json::Value) -> Response {
+        let Some(upgrader) = &self.upgrader else {
+            return Response::err(id, ErrorCode::Unavailable, "upgrade is not available");
+        };
+        let binary = match params.get("binary").and_then(|v| v.as_str()).map(Path::new) {
+            Some(binary) if binary.is_absolute() => binary,
+            Some(_) => return Response::err(id, ErrorCode::BadRequest, "binary path must be absolute"),
+            None => return Response::err(id, ErrorCode::BadRequest, "missing binary parameter"),
+        };
+        match upgrader.hand_off(binary).await {
+            Ok(handed_off) => {
+                upgrader.finish();
+                Response::ok(id, serde_json::json!({
+                    "pid": handed_off.pid,
+                    "sessions": handed_off.sessions,
+                }))
+            }
+            Err(e) => {
+                warn!("upgrade failed: {e:#}");
+                Response::failure(id, &e)
+            }
+        }
+    }
+
+    /// Upgrades hand over PTY masters, which only Unix can pass between
+    /// processes.
+    #[cfg(not(unix))]
+    async fn handle_upgrade(&self, id: u64, _params: &serde_json::Value) -> Response {
+        Response::err(id, ErrorCode::Unsupported, "upgrade is not supported on this platform: restart the daemon instead")
+    }
+
+    /// Wipe devices, tokens and keys (see [`crate::reset`]), destroy every
+    /// session and close every connection, then exit for launchd to restart
+    /// the daemon with a new identity. Needs `confirm: true`.
+    fn handle_reset(&self, id: u64, params: &serde_json::Value) -> Response {
+        if params.get("confirm").and_then(|v| v.as_bool()) != Some(true) {
+            return Response::err(id, ErrorCode::BadRequest, "reset wipes all devices and keys; pass confirm: true");
+        }
+        warn!("resetting daemon state");
+        let wiped = match reset::reset(&self.phantom_dir, &self.device_store) {
+            Ok(wiped) => wiped,
+            Err(e) => return Response::failure(id, &e),
+        };
+        self.session_manager.close_connections(CloseCode::Reset);
+        self.session_manager.destroy_all();
+        // Let the response go out first
+        tokio::spawn(async {
+            tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+            info!("reset complete, exiting");
+            std::process::exit(0);
+        });
+        match serde_json::to_value(&wiped) {
+            Ok(wiped) => Response::ok(id, wiped),
+            Err(e) => Response::err(id, ErrorCode::Internal, e.to_string()),
+        }
+    }
+
+    /// Live QUIC connections by ID, the one their log lines and audit records
+    /// carry.
+    fn handle_list_connections(&self, id: u64) -> Response {
+        let Some(connections) = &self.connections else {
+            return Response::err(id, ErrorCode::Unavailable, "connections aren't tracked here");
+        };
+        Response::ok(id, serde_json::json!({"connections": connections.list()}))
+    }
+
+    /// IPs with recent connections or failed auths, and how long any that are
+    /// limited must wait.
+    fn handle_rate_limit_status(&self, id: u64) -> Response {
+        let Some(limits) = &self.rate_limits else {
+            return Response::err(id, ErrorCode::Unavailable, "rate limits aren't tracked here");
+        };
+        match serde_json::to_value(limits.status()) {
+            Ok(status) => Response::ok(id, status),
+            Err(e) => Response::err(id, ErrorCode::Internal, e.to_string()),
+        }
+    }
+
+    /// Forget an IP's recent connections and failed auths, e.g. for a user
+    /// who locked themselves out mistyping a token. Audited.
+    fn handle_rate_limit_clear(&self, id: u64, params: &serde_json::Value) -> Response {
+        let Some(limits) = &self.rate_limits else {
+            return Response::err(id, ErrorCode::Unavailable, "rate limits aren't tracked here");
+        };
+        let Some(ip) = params.get("ip").and_then(|v| v.as_str()) else {
+            return Response::err(id, ErrorCode::BadRequest, "missing ip parameter");
+        };
+        let ip: std::net::IpAddr = match ip.parse() {
+            Ok(ip) => ip,
+            Err(e) => return Response::err(id, ErrorCode::BadRequest, format!("invalid ip: {e}")),
+        };
+        let cleared = limits.clear(ip);
+        self.device_store.record_rate_limit_clear(&ip.to_string());
+        info!("rate limits cleared for {ip}");
+        Response::ok(id, serde_json::json!({"ip": ip, "cleared": cleared}))
+    }
+
+    /// Run a command to completion and return its (capped) output and status.
+    async fn handle_exec(&self, id: u64, params: &serde_json::Value) -> Response {
+        let command = match params.get("command").and_then(|v| v.as_str()) {
+            Some(command) if !command.trim().is_empty() => command,
+            _ => return Response::err(id, ErrorCode::BadRequest, "missing command parameter"),
+        };
+        let timeout = exec::timeout_from_request(params.get("timeout_secs").and_then(|v| v.as_u64()));
+
+        let started = self.session_manager.reserve(exec::EXEC_MEMORY_BYTES).and_then(|memory| {
+            let pty = NativePty::exec(24, 80, command)?;
+            Ok((Exec::start(Box::new(pty), timeout, self.session_manager.tasks().clone())?, memory))
+        });
+        let (exec, _memory) = match started {
+            Ok(started) => started,
+            Err(e) => return Response::failure(id, &e),
+        };
+
+        let (output, truncated, status) = exec.collect(exec::IPC_OUTPUT_CAP).await;
+        Response::ok(id, serde_json::json!({
+            "output": String::from_utf8_lossy(&output),
+            "truncated": truncated,
+            "status": status.reason(),
+            "exit_code": status.exit_code(),
+        }))
+    }
+}
+
+/// The `path` parameter of an archive request. Must be absolute: the daemon's
+/// working directory is not the caller's. Archives are never written or read
+/// through symlinks (see [`SessionArchive::write`]).
+fn archive_path(params: &serde_json::Value) -> Result<&Path> {
+    let path = params
+        .get("path")
+        .and_then(|v| v.as_str())
+        .map(Path::new)
+        .context("missing path parameter")?;
+    if !path.is_absolute() {
+        bail!("path must be absolute");
+    }
+    Ok(path)
+}
+
+/// Send a single request to a running daemon's IPC socket and return its result.
+pub async fn call(phantom_dir: &Path, method: &str, params: serde_json::Value) -> Result<serde_json::Value> {
+    let socket_path = phantom_dir.join("daemon.sock");
+    let stream = local_socket::connect(&socket_path)
+        .await
+        .with_context(|| format!("connect to daemon at {} (is it running?)", socket_path.display()))?;
+    let (reader, mut writer) = tokio::io::split(stream);
+
+    let mut line = serde_json::to_vec(&serde_json::json!({
+        "id": 1,
+        "method": method,
+        "params": params,
+    }))?;
+    line.push(b'\n');
+    writer.write_all(&line).await?;
+
+    let resp = BufReader::new(reader)
+        .lines()
+        .next_line()
+        .await?
+        .context("daemon closed the IPC connection")?;
+    let mut resp: serde_json::Value = serde_json::from_str(&resp).context("parse IPC response")?;
+    if let Some(error) = resp["error"].as_str() {
+        // Keep the code, so callers can branch on it too
+        let code = ErrorCode::deserialize(&resp["code"]).unwrap_or(ErrorCode::Internal);
+        return Err(code.err(error));
     }
+    Ok(resp["result"].take())
 }
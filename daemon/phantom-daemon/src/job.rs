@@ -0,0 +1,71 @@
+//! Windows job objects, standing in for Unix process groups.
+//!
+//! Each session's shell runs in a [`Job`] of its own, which the processes it
+//! starts join too, so [`Job::terminate`] ends the whole session like
+//! `killpg` does. Jobs kill their processes when their last handle closes:
+//! a daemon that dies takes its shells with it, so Windows never has the
+//! orphans [`crate::orphans`] cleans up after.
+
+use std::io;
+use windows_sys::Win32::Foundation::{CloseHandle, HANDLE};
+use windows_sys::Win32::System::JobObjects::{
+    AssignProcessToJobObject, CreateJobObjectW, JobObjectExtendedLimitInformation, SetInformationJobObject,
+    TerminateJobObject, JOBOBJECT_EXTENDED_LIMIT_INFORMATION, JOB_OBJECT_LIMIT_KILL_ON_JOB_CLOSE,
+};
+use windows_sys::Win32::System::Threading::{OpenProcess, PROCESS_SET_QUOTA, PROCESS_TERMINATE};
+
+/// A job object, closed (killing what's left in it) when dropped.
+pub struct Job(HANDLE);
+
+// A job handle may be used from any thread
+unsafe impl Send for Job {}
+unsafe impl Sync for Job {}
+
+impl Job {
+    /// A new job holding process `pid`. Processes it starts from now on
+    /// are in the job too.
+    pub fn for_process(pid: u32) -> io::Result<Self> {
+        let handle = unsafe { CreateJobObjectW(std::ptr::null(), std::ptr::null()) };
+        if handle.is_null() {
+            return Err(io::Error::last_os_error());
+        }
+        let job = Self(handle);
+
+        let mut limits: JOBOBJECT_EXTENDED_LIMIT_INFORMATION = unsafe { std::mem::zeroed() };
+        limits.BasicLimitInformation.LimitFlags = JOB_OBJECT_LIMIT_KILL_ON_JOB_CLOSE;
+        let set = unsafe {
+            SetInformationJobObject(
+                job.0,
+                JobObjectExtendedLimitInformation,
+                &limits as *const JOBOBJECT_EXTENDED_LIMIT_INFORMATION as *const std::ffi::c_void,
+                std::mem::size_of::<JOBOBJECT_EXTENDED_LIMIT_INFORMATION>() as u32,
+            )
+        };
+        if set == 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let process = unsafe { OpenProcess(PROCESS_SET_QUOTA | PROCESS_TERMINATE, 0, pid) };
+        if process.is_null() {
+            return Err(io::Error::last_os_error());
+        }
+        let assigned = unsafe { AssignProcessToJobObject(job.0, process) };
+        let error = io::Error::last_os_error();
+        unsafe { CloseHandle(process) };
+        if assigned == 0 {
+            return Err(error);
+        }
+        Ok(job)
+    }
+
+    /// Kill every process in the job.
+    pub fn terminate(&self) {
+        unsafe { TerminateJobObject(self.0, 1) };
+    }
+}
+
+impl Drop for Job {
+    fn drop(&mut self) {
+        unsafe { CloseHandle(self.0) };
+    }
+}
@@ -1,10 +1,70 @@
+#[cfg(test)]
+mod alloc_counter;
+pub mod addresses;
+pub mod agent;
+pub mod archive;
 pub mod auth;
+pub mod bandwidth;
 pub mod bridge;
+pub mod budget;
+pub mod clipboard;
+pub mod clock;
 pub mod config;
+pub mod connections;
+pub mod dedup;
 pub mod device_store;
+pub mod env;
+pub mod errors;
+pub mod exec;
+pub mod handoff;
+pub mod hello;
+pub mod history;
+pub mod hooks;
+pub mod input_limit;
 pub mod ipc;
+#[cfg(windows)]
+pub mod job;
+pub mod local_socket;
+pub mod macros;
+pub mod memory;
+pub mod messages;
+pub mod migrate;
+pub mod naming;
+pub mod orphans;
+pub mod output_log;
+pub mod paste;
+pub mod power;
+pub mod project;
+pub mod protocol;
+pub mod ratelimit;
+pub mod reset;
+pub mod restrictions;
+pub mod resume;
+#[cfg(feature = "testing")]
+pub mod scenario;
+pub mod scheduler;
 pub mod server;
 pub mod session;
+pub mod stdio;
+#[cfg(unix)]
+pub mod systemd;
+pub mod terminal;
+#[cfg(feature = "testing")]
+pub mod testing;
+pub mod throttle;
 pub mod tls;
+pub mod tmux;
+pub mod ui_state;
+#[cfg(unix)]
+pub mod upgrade;
+pub mod users;
+pub mod vault;
+pub mod vouch;
+pub mod wake;
+pub mod watchdog;
+pub mod webauthn;
 
 pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Control protocol revision, as in the `phantom/1` ALPN.
+pub const PROTOCOL_VERSION: u32 = 1;
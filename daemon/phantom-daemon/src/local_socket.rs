@@ -0,0 +1,126 @@
+//! Local sockets: the one commands reach the daemon on (see [`crate::ipc`])
+//! and sessions' `SSH_AUTH_SOCK` (see [`crate::agent`]).
+//!
+//! On Unix they're Unix sockets, such as `~/.phantom/daemon.sock`, readable
+//! only by the user. Windows has named pipes instead, so there the daemon
+//! listens on `\\.\pipe\` followed by the socket's path with separators
+//! replaced: daemons with different data dirs still don't meet. Pipes refuse
+//! remote clients, and their default security lets other users open them
+//! only for reading, which is no use for sending requests.
+
+use anyhow::{Context, Result};
+use std::path::{Path, PathBuf};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+/// A connection between the daemon and a local client.
+pub trait Stream: AsyncRead + AsyncWrite + Send + Unpin + 'static {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin + 'static> Stream for T {}
+
+/// The daemon's end: accepts local connections.
+#[cfg(unix)]
+pub struct Listener {
+    listener: tokio::net::UnixListener,
+    address: PathBuf,
+}
+
+#[cfg(unix)]
+impl Listener {
+    /// Listen at `path`, replacing a stale socket left there.
+    pub fn bind(path: &Path) -> Result<Self> {
+        use std::os::unix::fs::PermissionsExt;
+
+        if path.exists() {
+            std::fs::remove_file(path).with_context(|| format!("remove stale socket {}", path.display()))?;
+        }
+        let listener = tokio::net::UnixListener::bind(path).with_context(|| format!("bind {}", path.display()))?;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))
+            .with_context(|| format!("restrict {}", path.display()))?;
+        Ok(Self { listener, address: path.to_path_buf() })
+    }
+
+    /// Listen on a socket bound by someone else: systemd, or the daemon
+    /// this one took over from.
+    pub fn from_std(listener: std::os::unix::net::UnixListener) -> Result<Self> {
+        listener.set_nonblocking(true)?;
+        let address = listener.local_addr()?.as_pathname().map(Path::to_path_buf).unwrap_or_default();
+        let listener = tokio::net::UnixListener::from_std(listener).context("register inherited socket")?;
+        Ok(Self { listener, address })
+    }
+
+    /// Where clients connect: the socket's path.
+    pub fn address(&self) -> &Path {
+        &self.address
+    }
+
+    pub async fn accept(&mut self) -> std::io::Result<impl Stream> {
+        Ok(self.listener.accept().await?.0)
+    }
+}
+
+/// The daemon's end: accepts local connections.
+#[cfg(windows)]
+pub struct Listener {
+    address: PathBuf,
+    /// The pipe instance the next client connects to
+    next: tokio::net::windows::named_pipe::NamedPipeServer,
+}
+
+#[cfg(windows)]
+impl Listener {
+    /// Listen on the pipe named after `path`, failing if another daemon
+    /// already does.
+    pub fn bind(path: &Path) -> Result<Self> {
+        use tokio::net::windows::named_pipe::ServerOptions;
+
+        let name = pipe_name(path);
+        let next = ServerOptions::new()
+            .first_pipe_instance(true)
+            .create(&name)
+            .with_context(|| format!("create pipe {name}"))?;
+        Ok(Self { address: PathBuf::from(name), next })
+    }
+
+    /// Where clients connect: the pipe's name.
+    pub fn address(&self) -> &Path {
+        &self.address
+    }
+
+    pub async fn accept(&mut self) -> std::io::Result<impl Stream> {
+        use tokio::net::windows::named_pipe::ServerOptions;
+
+        self.next.connect().await?;
+        // A fresh instance for the next client before this one is handed out
+        let next = ServerOptions::new().create(&self.address)?;
+        Ok(std::mem::replace(&mut self.next, next))
+    }
+}
+
+/// Connect to the daemon listening at `path`.
+#[cfg(unix)]
+pub async fn connect(path: &Path) -> std::io::Result<impl Stream> {
+    tokio::net::UnixStream::connect(path).await
+}
+
+/// Connect to the daemon listening at `path`, waiting briefly while every
+/// pipe instance is busy.
+#[cfg(windows)]
+pub async fn connect(path: &Path) -> std::io::Result<impl Stream> {
+    use tokio::net::windows::named_pipe::ClientOptions;
+    use windows_sys::Win32::Foundation::ERROR_PIPE_BUSY;
+
+    let name = pipe_name(path);
+    let mut attempts = 0;
+    loop {
+        match ClientOptions::new().open(&name) {
+            Err(e) if e.raw_os_error() == Some(ERROR_PIPE_BUSY as i32) && attempts < 20 => attempts += 1,
+            opened => return opened,
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    }
+}
+
+#[cfg(windows)]
+fn pipe_name(path: &Path) -> String {
+    format!(r"\\.\pipe\{}", path.display().to_string().replace(['\\', '/', ':'], "-"))
+}
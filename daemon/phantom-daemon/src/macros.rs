@@ -0,0 +1,153 @@
+//! Input macros: named sequences of input a device records once and replays
+//! into any of its sessions with `run_macro`, so a VPN login or a long build
+//! invocation is one tap.
+//!
+//! Each device has its own macros, kept in `macros.json` in the data dir.
+//! Input is JSON text, so control keys are written as escapes (`"\r"` for
+//! Return, `"\u0003"` for Ctrl-C).
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use tracing::warn;
+
+use crate::device_store::{read_with_backup, write_atomic};
+use crate::errors::ErrorCode;
+
+/// Longest macro input, in bytes.
+pub const MAX_INPUT_BYTES: usize = 4096;
+/// Most macros one device may keep.
+pub const MAX_MACROS_PER_DEVICE: usize = 64;
+/// Longest macro name, in characters.
+const MAX_NAME_CHARS: usize = 64;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Macro {
+    pub name: String,
+    pub input: String,
+    pub recorded_at: chrono::DateTime<chrono::Utc>,
+}
+
+/// Device id → its macros by name.
+type DeviceMacros = HashMap<String, BTreeMap<String, Macro>>;
+
+#[derive(Default)]
+pub struct MacroStore {
+    /// None keeps macros in memory only
+    path: Option<PathBuf>,
+    macros: Mutex<DeviceMacros>,
+}
+
+impl MacroStore {
+    /// Macros persisted at `path`, starting empty if it can't be read.
+    pub fn load(path: PathBuf) -> Self {
+        let macros = read_with_backup(&path, |s| serde_json::from_str(s).context("parse macros.json"))
+            .unwrap_or_else(|e| {
+                warn!("ignoring unreadable {}: {e:#}", path.display());
+                None
+            })
+            .unwrap_or_default();
+        Self { path: Some(path), macros: Mutex::new(macros) }
+    }
+
+    /// Record (or replace) `device_id`'s macro `name`.
+    pub fn record(&self, device_id: &str, name: &str, input: &str) -> Result<Macro> {
+        let name = name.trim();
+        if name.is_empty() || name.chars().count() > MAX_NAME_CHARS {
+            return Err(ErrorCode::BadRequest.err(format!("macro name must be 1-{MAX_NAME_CHARS} characters")));
+        }
+        if input.is_empty() || input.len() > MAX_INPUT_BYTES {
+            return Err(ErrorCode::BadRequest.err(format!("macro input must be 1-{MAX_INPUT_BYTES} bytes")));
+        }
+        let mut macros = self.macros.lock().expect("macros lock");
+        let device = macros.entry(device_id.to_string()).or_default();
+        if !device.contains_key(name) && device.len() >= MAX_MACROS_PER_DEVICE {
+            return Err(ErrorCode::LimitExceeded.err(format!("macro limit reached ({MAX_MACROS_PER_DEVICE})")));
+        }
+        let recorded = Macro {
+            name: name.to_string(),
+            input: input.to_string(),
+            recorded_at: chrono::Utc::now(),
+        };
+        device.insert(recorded.name.clone(), recorded.clone());
+        self.save(&macros)?;
+        Ok(recorded)
+    }
+
+    /// `device_id`'s macros, by name.
+    pub fn list(&self, device_id: &str) -> Vec<Macro> {
+        let macros = self.macros.lock().expect("macros lock");
+        macros.get(device_id).map(|device| device.values().cloned().collect()).unwrap_or_default()
+    }
+
+    pub fn get(&self, device_id: &str, name: &str) -> Result<Macro> {
+        let macros = self.macros.lock().expect("macros lock");
+        macros
+            .get(device_id)
+            .and_then(|device| device.get(name))
+            .cloned()
+            .ok_or_else(|| ErrorCode::NotFound.err(format!("no macro named {name}")))
+    }
+
+    pub fn delete(&self, device_id: &str, name: &str) -> Result<()> {
+        let mut macros = self.macros.lock().expect("macros lock");
+        let removed = macros.get_mut(device_id).and_then(|device| device.remove(name));
+        if removed.is_none() {
+            return Err(ErrorCode::NotFound.err(format!("no macro named {name}")));
+        }
+        macros.retain(|_, device| !device.is_empty());
+        self.save(&macros)
+    }
+
+    fn save(&self, macros: &DeviceMacros) -> Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let json = serde_json::to_string_pretty(macros)?;
+        write_atomic(path, json.as_bytes()).context("write macros.json")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn macros_are_per_device_and_persisted() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("macros.json");
+        let store = MacroStore::load(path.clone());
+        store.record("phone", "vpn", "sudo openconnect vpn.example.com\r").unwrap();
+        store.record("phone", "build", "cargo build\r").unwrap();
+        store.record("phone", "build", "cargo build --release\r").unwrap();
+
+        let store = MacroStore::load(path);
+        let names: Vec<_> = store.list("phone").into_iter().map(|m| m.name).collect();
+        assert_eq!(names, ["build", "vpn"]);
+        assert_eq!(store.get("phone", "build").unwrap().input, "cargo build --release\r");
+        assert!(store.list("tablet").is_empty());
+        assert_eq!(ErrorCode::of(&store.get("tablet", "vpn").unwrap_err()), ErrorCode::NotFound);
+
+        store.delete("phone", "vpn").unwrap();
+        assert_eq!(ErrorCode::of(&store.delete("phone", "vpn").unwrap_err()), ErrorCode::NotFound);
+        assert_eq!(store.list("phone").len(), 1);
+    }
+
+    #[test]
+    fn rejects_bad_and_excess_macros() {
+        let store = MacroStore::default();
+        let code = |r: Result<Macro>| ErrorCode::of(&r.unwrap_err());
+        assert_eq!(code(store.record("phone", " ", "ls\r")), ErrorCode::BadRequest);
+        assert_eq!(code(store.record("phone", "empty", "")), ErrorCode::BadRequest);
+        assert_eq!(code(store.record("phone", "huge", &"x".repeat(MAX_INPUT_BYTES + 1))), ErrorCode::BadRequest);
+
+        for i in 0..MAX_MACROS_PER_DEVICE {
+            store.record("phone", &format!("m{i}"), "ls\r").unwrap();
+        }
+        assert_eq!(code(store.record("phone", "one-more", "ls\r")), ErrorCode::LimitExceeded);
+        // Replacing one is fine
+        store.record("phone", "m0", "pwd\r").unwrap();
+    }
+}
@@ -1,71 +1,191 @@
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 use clap::Parser;
-use phantom_daemon::config::{Cli, Command, DaemonConfig, DeviceAction};
-use phantom_daemon::{auth, device_store, ipc, server, session, tls};
+use phantom_daemon::config::{
+    Cli, Command, DaemonConfig, DeviceAction, ProtocolAction, RateLimitAction, SessionAction,
+};
+use phantom_daemon::restrictions::Restrictions;
+use phantom_daemon::ui_state::RecentErrors;
+use phantom_daemon::{addresses, auth, bandwidth, connections, device_store, ipc, local_socket, macros, migrate, orphans, power, scheduler, server, session, stdio, tls, users, vault, wake};
+#[cfg(unix)]
+use phantom_daemon::{systemd, upgrade};
 use std::sync::Arc;
 use tokio_util::sync::CancellationToken;
 use tracing::{error, info, warn};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
 
-#[tokio::main]
-async fn main() -> Result<()> {
+fn main() -> Result<()> {
     rustls::crypto::ring::default_provider()
         .install_default()
         .expect("install crypto provider");
 
-    tracing_subscriber::fmt()
-        .with_env_filter(
+    let cli = Cli::parse();
+    // `phantom stdio`'s stdout is the protocol: log to stderr
+    let stdio = matches!(cli.command, Some(Command::Stdio));
+
+    let recent_errors = RecentErrors::new();
+    tracing_subscriber::registry()
+        .with(
             tracing_subscriber::EnvFilter::try_from_default_env()
                 .unwrap_or_else(|_| "info".into()),
         )
+        .with((!stdio).then(tracing_subscriber::fmt::layer))
+        .with(stdio.then(|| tracing_subscriber::fmt::layer().with_writer(std::io::stderr)))
+        .with(recent_errors.layer())
         .init();
 
-    let cli = Cli::parse();
+    // Before the runtime starts its threads: taking systemd's variables
+    // changes the environment
+    #[cfg(unix)]
+    let service = match cli.command {
+        None | Some(Command::Daemon { .. }) => systemd::Service::from_env(),
+        _ => systemd::Service::default(),
+    };
+
+    tokio::runtime::Builder::new_multi_thread().enable_all().build()?.block_on(run(
+        cli,
+        recent_errors,
+        #[cfg(unix)]
+        service,
+    ))
+}
 
-    match cli.command {
-        None | Some(Command::Daemon { .. }) => {
-            let phantom_dir = dirs::home_dir()
-                .context("home dir")?
-                .join(".phantom");
+async fn run(mut cli: Cli, recent_errors: Arc<RecentErrors>, #[cfg(unix)] service: systemd::Service) -> Result<()> {
+    let phantom_dir = cli.data_dir()?;
+
+    match cli.command.take() {
+        command @ (None | Some(Command::Daemon { .. })) => {
             std::fs::create_dir_all(&phantom_dir)?;
 
-            let config = DaemonConfig::load(&phantom_dir);
+            let config = cli.load_config(&phantom_dir)?;
 
-            let cli_bind = match &cli.command {
-                Some(Command::Daemon { bind }) => *bind,
-                _ => None,
+            let (cli_bind, takeover) = match command {
+                Some(Command::Daemon { bind, takeover }) => (bind, takeover),
+                _ => (None, None),
             };
             let bind = cli_bind
                 .or_else(|| config.bind.as_ref().and_then(|b| b.parse().ok()))
                 .unwrap_or_else(|| "[::]:4433".parse().unwrap());
 
-            run_daemon(bind, &phantom_dir, &config).await
+            let stopped = run_daemon(
+                bind,
+                takeover,
+                &cli,
+                &phantom_dir,
+                &config,
+                recent_errors,
+                #[cfg(unix)]
+                service,
+            )
+            .await?;
+            match stopped {
+                Stopped::Shutdown => Ok(()),
+                // The shells now belong to the new daemon, so reads of their
+                // PTYs never end: don't wait for them
+                Stopped::HandedOff => std::process::exit(0),
+            }
         }
         Some(Command::RotateCert) => {
-            tls::rotate_cert()?;
+            tls::rotate_cert(&phantom_dir)?;
             println!("Certificate rotated successfully.");
             Ok(())
         }
-        Some(Command::Pair { token }) => {
-            run_pair(token)
+        Some(Command::Pair { token, user }) => {
+            let config = cli.load_config(&phantom_dir)?;
+            run_pair(&phantom_dir, &config, token, user.as_deref())
+        }
+        Some(Command::Share { session_id, ttl, token }) => {
+            run_share(&phantom_dir, &session_id, ttl, token).await
+        }
+        Some(Command::Exec { timeout, command }) => {
+            run_exec(&phantom_dir, timeout, &command.join(" ")).await
         }
         Some(Command::Device { action }) => {
-            run_device_command(action)
+            run_device_command(&phantom_dir, action)
+        }
+        Some(Command::Upgrade { binary }) => {
+            run_upgrade(&phantom_dir, binary).await
+        }
+        Some(Command::Session { action }) => {
+            run_session_command(&phantom_dir, action).await
+        }
+        Some(Command::Reset { confirm }) => {
+            run_reset(&phantom_dir, confirm).await
+        }
+        Some(Command::RateLimit { action }) => {
+            run_rate_limit_command(&phantom_dir, action).await
+        }
+        Some(Command::Protocol { action: ProtocolAction::Dump }) => {
+            println!("{}", serde_json::to_string_pretty(&phantom_daemon::protocol::schema())?);
+            Ok(())
+        }
+        Some(Command::Stdio) => {
+            run_stdio(&phantom_dir).await
+        }
+        Some(Command::WakeRelay { bind, macs }) => {
+            let allowed = macs.iter().map(|mac| wake::parse_mac(mac)).collect::<Result<Vec<_>>>()?;
+            wake::run_relay(bind, &allowed).await
         }
     }
 }
 
-async fn run_daemon(bind: std::net::SocketAddr, phantom_dir: &std::path::Path, config: &DaemonConfig) -> Result<()> {
-    let (cert_der, key_der) = tls::load_or_generate()
+/// How the daemon stopped serving.
+enum Stopped {
+    Shutdown,
+    /// Everything was handed to a new daemon by `phantom upgrade`
+    HandedOff,
+}
+
+async fn run_daemon(
+    bind: std::net::SocketAddr,
+    takeover: Option<std::path::PathBuf>,
+    cli: &Cli,
+    phantom_dir: &std::path::Path,
+    config: &DaemonConfig,
+    recent_errors: Arc<RecentErrors>,
+    #[cfg(unix)] service: systemd::Service,
+) -> Result<Stopped> {
+    let (cert_der, key_der) = tls::load_or_generate(phantom_dir)
         .context("load or generate TLS certificate")?;
 
     let fp = tls::fingerprint_base64(&cert_der);
     info!("certificate fingerprint: {fp}");
 
-    let server_config = tls::build_server_config(&cert_der, &key_der)
+    let server_config = tls::build_server_config(&cert_der, &key_der, &config.protocol)
         .context("build server config")?;
 
-    let endpoint = quinn::Endpoint::server(server_config, bind)
-        .context("bind QUIC endpoint")?;
+    // Under systemd: report status, and listen on the sockets it bound
+    #[cfg(unix)]
+    let systemd::Service { notifier, activation } = service;
+    #[cfg(unix)]
+    let notifier = notifier.map(Arc::new);
+
+    // On upgrade the previous daemon hands over its sockets and sessions
+    #[cfg(unix)]
+    let (socket, ipc_listener, takeover) = match takeover {
+        Some(path) => {
+            let takeover = upgrade::Takeover::receive(&path)
+                .context("take over from the running daemon")?;
+            (takeover.socket, takeover.ipc, Some((takeover.sessions, takeover.ack)))
+        }
+        None => match activation.quic {
+            Some(socket) => (socket, activation.ipc, None),
+            None => (addresses::bind_udp(bind).context("bind QUIC endpoint")?, activation.ipc, None),
+        },
+    };
+    #[cfg(not(unix))]
+    let socket = match takeover {
+        Some(_) => bail!("--takeover is not supported on this platform"),
+        None => addresses::bind_udp(bind).context("bind QUIC endpoint")?,
+    };
+    let bind = socket.local_addr()?;
+    let endpoint = quinn::Endpoint::new(
+        quinn::EndpointConfig::default(),
+        Some(server_config),
+        socket.try_clone()?,
+        Arc::new(quinn::TokioRuntime),
+    )
+    .context("create QUIC endpoint")?;
 
     if bind.ip().is_unspecified() {
         warn!("listening on all interfaces ({bind}) — ensure firewall is configured");
@@ -76,76 +196,227 @@ async fn run_daemon(bind: std::net::SocketAddr, phantom_dir: &std::path::Path, c
             .context("initialize device store")?,
     );
 
-    let authenticator = Arc::new(auth::Authenticator::new(device_store.clone()));
-
     if device_store.list_devices().is_empty() {
         warn!("no paired devices — run `phantom pair` to pair a device");
     }
 
-    let session_manager = Arc::new(session::SessionManager::with_scrollback(config.session.scrollback_bytes));
+    if config.system.multi_user && !users::is_root() {
+        warn!("multi-user mode needs root: sessions for other users will fail to start");
+    }
+
+    let children = orphans::ChildRegistry::new(phantom_dir.join("children"));
+    let mut session_manager = session::SessionManager::with_config(config)
+        .with_agent_dir(phantom_dir.join("agent"))
+        .with_agent_permission({
+            let device_store = device_store.clone();
+            move |device_id| device_store.agent_forwarding_allowed(device_id)
+        })
+        .with_macros(macros::MacroStore::load(phantom_dir.join("macros.json")))
+        .with_schedule_store(phantom_dir.join("schedules.json"))
+        .with_successor_file(phantom_dir.join(migrate::SUCCESSOR_FILE))
+        .with_child_registry(children.clone())
+        .with_bandwidth(bandwidth::BandwidthLedger::load(phantom_dir.join("bandwidth.json")));
+    // Output logs are plain text, so they'd leak what encrypted scrollback keeps sealed
+    if config.session.encrypt_scrollback {
+        info!("session output logging is off while scrollback is encrypted");
+    } else {
+        let dir = config.session.output_log_dir.clone().unwrap_or_else(|| phantom_dir.join("logs").join("sessions"));
+        session_manager = session_manager.with_output_log_dir(dir);
+    }
+    let session_manager = Arc::new(session_manager);
+    // Acknowledged once serving, below
+    #[cfg(unix)]
+    let takeover_ack = match takeover {
+        Some((sessions, ack)) => {
+            let count = sessions.len();
+            for (handoff, master) in sessions {
+                let id = handoff.session.id.clone();
+                if let Err(e) = session_manager.adopt_session(handoff, master) {
+                    warn!("failed to adopt session {id}: {e:#}");
+                }
+            }
+            info!("took over {count} session(s) from the previous daemon");
+            Some(ack)
+        }
+        None => None,
+    };
+    // Clean up after a daemon that crashed (a previous one handing off is
+    // still running, so its entries are left alone)
+    let orphan_policy = config.session.orphans;
+    let tasks = session_manager.tasks().clone();
+    tasks.spawn(async move { children.sweep(orphan_policy).await });
+    #[cfg(unix)]
+    let upgrader = {
+        let mut upgrader = upgrade::Upgrader::new(phantom_dir, socket, session_manager.clone())
+            .with_args(cli.global_args());
+        if let Some(listener) = &ipc_listener {
+            upgrader = upgrader.with_ipc_listener(listener.try_clone().context("dup IPC socket")?);
+        }
+        if let Some(notifier) = &notifier {
+            upgrader = upgrader.with_notifier(notifier.clone());
+        }
+        Arc::new(upgrader)
+    };
+    #[cfg(unix)]
+    let handed_off = upgrader.handed_off();
+    #[cfg(not(unix))]
+    let handed_off = CancellationToken::new();
+    let authenticator = Arc::new(
+        auth::Authenticator::new(device_store.clone())
+            .with_clock(session_manager.clock().clone())
+            .with_hooks(session_manager.hooks().clone())
+            .with_failure_limit(
+                config.rate_limit.auth_failure_limit,
+                std::time::Duration::from_secs(config.rate_limit.auth_failure_window_secs),
+            )
+            .with_vouching(session_manager.pairing_requests().clone(), session_manager.notifier()),
+    );
 
     // Start the session reaper
     let cancel = CancellationToken::new();
     let sm_for_reaper = session_manager.clone();
     let cancel_for_reaper = cancel.clone();
     let reaper_interval = config.session.reaper_interval_secs;
-    tokio::spawn(async move {
+    tasks.spawn(async move {
         sm_for_reaper.run_reaper(cancel_for_reaper, reaper_interval).await;
     });
 
+    // Keep pre-warmed shells ready for create_session
+    let sm_for_prewarm = session_manager.clone();
+    let cancel_for_prewarm = cancel.clone();
+    tasks.spawn(async move {
+        sm_for_prewarm.run_prewarm(cancel_for_prewarm).await;
+    });
+
+    // Run scheduled commands
+    tasks.spawn(scheduler::run(session_manager.clone(), cancel.clone()));
+
+    // Count devices' traffic (saved once more on shutdown)
+    let bandwidth = tasks.spawn(bandwidth::run(session_manager.clone(), cancel.clone()));
+
+    let rate_config = &config.rate_limit;
+    let gauges = Arc::new(server::ConnectionGauges::new(
+        rate_config.max_connections_per_ip,
+        rate_config.max_connections,
+    ));
+    let rate_limits = Arc::new(server::RateLimits::new(rate_config, session_manager.clock().clone()));
+    let connections = Arc::new(connections::Connections::new());
+
     // Start the IPC server
-    let ipc_server = Arc::new(ipc::IpcServer::new(
+    let mut ipc_server = ipc::IpcServer::new(
         phantom_dir,
         session_manager.clone(),
         device_store.clone(),
         fp.clone(),
         bind.to_string(),
-    ));
+    )
+    .with_recent_errors(recent_errors)
+    .with_connection_gauges(gauges.clone())
+    .with_rate_limits(rate_limits.clone())
+    .with_connections(connections.clone());
+    #[cfg(unix)]
+    {
+        ipc_server = ipc_server.with_upgrader(upgrader);
+        if let Some(listener) = ipc_listener {
+            ipc_server = ipc_server.with_listener(listener);
+        }
+    }
+    if config.session.encrypt_scrollback {
+        match vault::Vault::load_or_create(phantom_dir) {
+            Ok(vault) => ipc_server = ipc_server.with_vault(Arc::new(vault)),
+            Err(e) => warn!("scrollback encryption unavailable, archives will be plaintext: {e:#}"),
+        }
+    }
+    let ipc_server = Arc::new(ipc_server);
     let ipc_cancel = cancel.clone();
-    tokio::spawn(async move {
+    tasks.spawn(async move {
         if let Err(e) = ipc_server.run(ipc_cancel).await {
             error!("IPC server error: {e:#}");
         }
     });
 
-    server::prevent_sleep();
+    // Keep the host awake while serving (a container's host isn't ours to)
+    let power = (!cli.container)
+        .then(|| tasks.spawn(power::run(config.power.clone(), session_manager.clone(), cancel.clone())));
 
     info!("Phantom daemon listening on {}", endpoint.local_addr()?);
+    #[cfg(unix)]
+    if let Some(notifier) = &notifier {
+        notifier.notify(&format!("READY=1\nSTATUS=Listening on {}", endpoint.local_addr()?));
+        let notifier = notifier.clone();
+        let cancel = cancel.clone();
+        tasks.spawn(async move { notifier.run_watchdog(cancel).await });
+    }
 
-    let rate_config = &config.rate_limit;
-    let result = server::run(
-        endpoint,
-        session_manager,
-        authenticator,
-        rate_config.connection_limit,
-        rate_config.connection_window_secs,
-        rate_config.auth_failure_limit,
-        rate_config.auth_failure_window_secs,
-    ).await;
-
-    server::allow_sleep();
+    // Let the sleep proxy wake the host for clients
+    if config.wake.advertise {
+        tasks.spawn(wake::advertise(endpoint.local_addr()?.port(), cancel.clone()));
+    }
+
+    // Everything is up: the previous daemon can go
+    #[cfg(unix)]
+    if let Some(ack) = takeover_ack {
+        // The service's main process from now on, before the old one exits
+        if let Some(notifier) = &notifier {
+            notifier.notify(&format!("MAINPID={}", std::process::id()));
+        }
+        ack.ready()?;
+    }
+
+    let result = tokio::select! {
+        result = server::run(endpoint, session_manager, authenticator, server::Intake { gauges, limits: rate_limits, connections }) => result,
+        // Without the usual shutdown: closing the endpoint would destroy
+        // every session, and cleanup would remove sockets the new daemon has
+        // since bound at the same paths
+        _ = handed_off.cancelled() => {
+            info!("upgrade complete, exiting");
+            return Ok(Stopped::HandedOff);
+        }
+    };
+
+    #[cfg(unix)]
+    if let Some(notifier) = &notifier {
+        notifier.notify("STOPPING=1");
+    }
     cancel.cancel();
+    if let Some(power) = power {
+        let _ = power.await;
+    }
+    let _ = bandwidth.await;
+    // Let connections, bridges and kill timers finish
+    server::join_tasks(&tasks, server::SHUTDOWN_GRACE).await;
 
-    result
+    result.map(|()| Stopped::Shutdown)
 }
 
-fn run_pair(token_only: bool) -> Result<()> {
-    let phantom_dir = dirs::home_dir()
-        .context("home dir")?
-        .join(".phantom");
+fn run_pair(phantom_dir: &std::path::Path, config: &DaemonConfig, token_only: bool, user: Option<&str>) -> Result<()> {
+    match user {
+        Some(user) => users::validate_user(user)?,
+        None if config.system.multi_user => bail!("multi-user mode: pass --user for the device's sessions"),
+        None => {}
+    }
 
-    let device_store = device_store::DeviceStore::new(&phantom_dir)
+    let device_store = device_store::DeviceStore::new(phantom_dir)
         .context("initialize device store")?;
 
-    let (cert_der, _) = tls::load_or_generate()
+    let (cert_der, _) = tls::load_or_generate(phantom_dir)
         .context("load TLS certificate")?;
     let fp = tls::fingerprint_base64(&cert_der);
 
-    let pairing = device_store.generate_pairing_data(&fp, 4433);
+    let pairing = device_store.generate_pairing_data(&fp, 4433, user, wake::info(&config.wake));
+    let others: Vec<&str> = pairing
+        .hosts
+        .iter()
+        .map(|c| c.host.as_str())
+        .filter(|&h| h != pairing.host)
+        .collect();
 
     if token_only {
         println!("Pairing token: {}", pairing.token);
-        println!("Host: {}:{}", pairing.host, pairing.port);
+        println!("Host: {}", addresses::host_port(&pairing.host, pairing.port));
+        if !others.is_empty() {
+            println!("Also reachable at: {}", others.join(", "));
+        }
         println!("Fingerprint: {}", pairing.fingerprint);
         println!("\nEnter these in the Phantom iOS app to pair.");
     } else {
@@ -154,42 +425,319 @@ fn run_pair(token_only: bool) -> Result<()> {
             .context("print QR code")?;
         println!("\nOr use manual pairing:");
         println!("  Token: {}", pairing.token);
-        println!("  Host: {}:{}", pairing.host, pairing.port);
+        println!("  Host: {}", addresses::host_port(&pairing.host, pairing.port));
+        if !others.is_empty() {
+            println!("  Also reachable at: {}", others.join(", "));
+        }
         println!("  Fingerprint: {}", pairing.fingerprint);
     }
 
+    if let Some(user) = user {
+        println!("\nSessions from this device will run as {user}.");
+    }
     println!("\nToken expires in 5 minutes.");
     Ok(())
 }
 
-fn run_device_command(action: DeviceAction) -> Result<()> {
-    let phantom_dir = dirs::home_dir()
-        .context("home dir")?
-        .join(".phantom");
+async fn run_share(phantom_dir: &std::path::Path, session_id: &str, ttl_secs: Option<u64>, token_only: bool) -> Result<()> {
+    let link = ipc::call(phantom_dir, "create_guest_link", serde_json::json!({
+        "session_id": session_id,
+        "ttl_secs": ttl_secs,
+    })).await?;
+    let field = |name: &str| link[name].as_str().unwrap_or_default().to_string();
+    let host = addresses::host_port(&field("host"), link["port"].as_u64().unwrap_or_default() as u16);
 
-    let device_store = device_store::DeviceStore::new(&phantom_dir)
+    if token_only {
+        println!("Guest token: {}", field("token"));
+        println!("Session: {}", field("session_id"));
+        println!("Host: {host}");
+        println!("Fingerprint: {}", field("fingerprint"));
+    } else {
+        println!("Scan this QR code with the Phantom iOS app to watch session {session_id}:\n");
+        qr2term::print_qr(field("payload_json"))
+            .context("print QR code")?;
+        println!("\nOr enter manually:");
+        println!("  Guest token: {}", field("token"));
+        println!("  Host: {host}");
+        println!("  Fingerprint: {}", field("fingerprint"));
+    }
+
+    let minutes = link["expires_in_secs"].as_u64().unwrap_or(0) / 60;
+    println!("\nRead-only. Single use; expires in {minutes} minutes.");
+    Ok(())
+}
+
+async fn run_upgrade(phantom_dir: &std::path::Path, binary: Option<std::path::PathBuf>) -> Result<()> {
+    let binary = match binary {
+        Some(binary) => std::path::absolute(binary).context("resolve binary path")?,
+        None => std::env::current_exe().context("locate this binary")?,
+    };
+
+    let result = ipc::call(phantom_dir, "upgrade", serde_json::json!({
+        "binary": binary,
+    })).await?;
+    println!(
+        "Daemon upgraded: pid {} took over {} session(s). Devices reconnect automatically.",
+        result["pid"],
+        result["sessions"],
+    );
+    Ok(())
+}
+
+async fn run_session_command(phantom_dir: &std::path::Path, action: SessionAction) -> Result<()> {
+    match action {
+        SessionAction::Export { id, output, plaintext } => {
+            let output = output.unwrap_or_else(|| format!("{id}.phantom-session").into());
+            let path = std::path::absolute(&output).context("resolve output path")?;
+            let result = ipc::call(phantom_dir, "export_session", serde_json::json!({
+                "session_id": id,
+                "path": path,
+                "plaintext": plaintext,
+            })).await?;
+            println!(
+                "Exported session {id} ({} bytes of scrollback) to {}",
+                result["scrollback_bytes"],
+                path.display(),
+            );
+            if result["encrypted"].as_bool().unwrap_or(false) {
+                println!("Encrypted with this host's key; use --plaintext to import it elsewhere.");
+            }
+        }
+        SessionAction::Import { path, user } => {
+            let path = std::path::absolute(&path).context("resolve archive path")?;
+            let result = ipc::call(phantom_dir, "import_session", serde_json::json!({
+                "path": path,
+                "user": user,
+            })).await?;
+            println!(
+                "Imported session {} from {} as {}",
+                result["original_session_id"].as_str().unwrap_or_default(),
+                result["host"].as_str().unwrap_or_default(),
+                result["session_id"].as_str().unwrap_or_default(),
+            );
+        }
+        SessionAction::History { id } => {
+            let result = ipc::call(phantom_dir, "session_history", serde_json::json!({
+                "session_id": id,
+            })).await?;
+            let events: Vec<phantom_daemon::history::SessionEvent> =
+                serde_json::from_value(result["events"].clone()).context("parse session history")?;
+            for event in events {
+                println!("{}  {}", event.at.format("%Y-%m-%d %H:%M:%S UTC"), event.kind);
+            }
+            if result["ended"].as_bool().unwrap_or(false) {
+                println!("(session has ended)");
+            }
+        }
+        SessionAction::Inspect { id } => {
+            let result = ipc::call(phantom_dir, "inspect_session", serde_json::json!({
+                "session_id": id,
+            })).await?;
+            println!("{}", serde_json::to_string_pretty(&result)?);
+        }
+    }
+    Ok(())
+}
+
+async fn run_rate_limit_command(phantom_dir: &std::path::Path, action: RateLimitAction) -> Result<()> {
+    match action {
+        RateLimitAction::Status => {
+            let status = ipc::call(phantom_dir, "rate_limit_status", serde_json::json!({})).await?;
+            for (name, key) in [("Connections", "connections"), ("Auth failures", "auth_failures")] {
+                let limiter = &status[key];
+                println!("{name} (limit {} per {}s):", limiter["limit"], limiter["window_secs"]);
+                let by_ip = limiter["by_ip"].as_array().map(Vec::as_slice).unwrap_or_default();
+                if by_ip.is_empty() {
+                    println!("  none");
+                }
+                for entry in by_ip {
+                    let ip = entry["ip"].as_str().unwrap_or_default();
+                    match entry["retry_after_secs"].as_u64() {
+                        Some(secs) => println!("  {ip}  {}  limited for {secs}s", entry["events"]),
+                        None => println!("  {ip}  {}", entry["events"]),
+                    }
+                }
+            }
+        }
+        RateLimitAction::Clear { ip } => {
+            let result = ipc::call(phantom_dir, "rate_limit_clear", serde_json::json!({"ip": ip})).await?;
+            if result["cleared"].as_bool().unwrap_or(false) {
+                println!("Cleared rate limits for {ip}.");
+            } else {
+                println!("No recent connections or failed auths from {ip}.");
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn run_reset(phantom_dir: &std::path::Path, confirm: bool) -> Result<()> {
+    if !confirm {
+        println!("This revokes every paired device, deletes the TLS certificate, scrollback");
+        println!("key and all pairing and guest links, and ends every session. Devices will");
+        println!("have to be paired again. Run `phantom reset --confirm` to go ahead.");
+        return Ok(());
+    }
+
+    // Through the daemon if it's running, so sessions and connections go too
+    let daemon_running = local_socket::connect(&phantom_dir.join("daemon.sock")).await.is_ok();
+    let wiped = if daemon_running {
+        let wiped = ipc::call(phantom_dir, "reset", serde_json::json!({"confirm": true})).await?;
+        println!("The daemon is restarting with a new certificate.");
+        wiped
+    } else {
+        let device_store = device_store::DeviceStore::new(phantom_dir)
+            .context("initialize device store")?;
+        let wiped = phantom_daemon::reset::reset(phantom_dir, &device_store)?;
+        let (cert_der, _) = tls::load_or_generate(phantom_dir).context("generate TLS certificate")?;
+        println!("New certificate fingerprint: {}", tls::fingerprint_base64(&cert_der));
+        serde_json::to_value(wiped)?
+    };
+    let revoked = wiped["devices_revoked"].as_array().map_or(0, Vec::len);
+    let removed = wiped["files_removed"].as_array().map_or(0, Vec::len);
+    println!("Reset: revoked {revoked} device(s) and removed {removed} file(s).");
+    println!("Run `phantom pair` to pair a device again.");
+    Ok(())
+}
+
+/// A bundle passphrase from `PHANTOM_BUNDLE_PASSPHRASE`, or else stdin.
+fn read_passphrase(prompt: &str) -> Result<String> {
+    if let Ok(passphrase) = std::env::var("PHANTOM_BUNDLE_PASSPHRASE") {
+        return Ok(passphrase);
+    }
+    eprint!("{prompt}");
+    let mut passphrase = String::new();
+    std::io::stdin().read_line(&mut passphrase).context("read passphrase")?;
+    Ok(passphrase.trim_end_matches(['\r', '\n']).to_string())
+}
+
+async fn run_exec(phantom_dir: &std::path::Path, timeout_secs: Option<u64>, command: &str) -> Result<()> {
+    let result = ipc::call(phantom_dir, "exec", serde_json::json!({
+        "command": command,
+        "timeout_secs": timeout_secs,
+    })).await?;
+
+    print!("{}", result["output"].as_str().unwrap_or(""));
+    if result["truncated"].as_bool().unwrap_or(false) {
+        eprintln!("\n[output truncated]");
+    }
+    let code = match result["exit_code"].as_u64() {
+        Some(code) => code as i32,
+        None => {
+            eprintln!("command did not exit normally: {}", result["status"].as_str().unwrap_or("unknown"));
+            // Same as timeout(1)
+            124
+        }
+    };
+    std::io::Write::flush(&mut std::io::stdout())?;
+    std::process::exit(code);
+}
+
+async fn run_stdio(phantom_dir: &std::path::Path) -> Result<()> {
+    let connection = stdio::connect_local(phantom_dir).await?;
+    stdio::relay(connection, tokio::io::stdin(), tokio::io::stdout()).await?;
+    // Don't wait for the runtime's blocking read of stdin to return
+    std::process::exit(0);
+}
+
+fn run_device_command(phantom_dir: &std::path::Path, action: DeviceAction) -> Result<()> {
+    let device_store = device_store::DeviceStore::new(phantom_dir)
         .context("initialize device store")?;
 
     match action {
         DeviceAction::List => {
             let devices = device_store.list_devices();
+            // As of the daemon's last save, at most a minute ago
+            let bandwidth = bandwidth::BandwidthLedger::load(phantom_dir.join("bandwidth.json"));
             if devices.is_empty() {
                 println!("No paired devices.");
             } else {
-                println!("{:<20} {:<20} {:<30}", "DEVICE ID", "NAME", "LAST SEEN");
+                println!(
+                    "{:<20} {:<20} {:<22} {:<6} {:<12} {:<10} {:<10} {:<10} {:<20}",
+                    "DEVICE ID", "NAME", "LAST SEEN", "AGENT", "USER", "RESTRICTED", "TODAY", "30 DAYS", "VOUCHED BY"
+                );
                 for d in devices {
                     let last_seen = d
                         .last_seen
                         .map(|t| t.format("%Y-%m-%d %H:%M:%S").to_string())
                         .unwrap_or_else(|| "never".to_string());
-                    println!("{:<20} {:<20} {:<30}", d.device_id, d.device_name, last_seen);
+                    let agent = if d.agent_forwarding { "yes" } else { "no" };
+                    let user = d.user.as_deref().unwrap_or("-");
+                    let restricted = if d.restrictions.is_some() { "yes" } else { "no" };
+                    let usage = bandwidth.device_usage(&d.device_id);
+                    println!(
+                        "{:<20} {:<20} {:<22} {:<6} {:<12} {:<10} {:<10} {:<10} {:<20}",
+                        d.device_id,
+                        d.device_name,
+                        last_seen,
+                        agent,
+                        user,
+                        restricted,
+                        bandwidth::format_bytes(usage.today.usage.total()),
+                        bandwidth::format_bytes(usage.last_30_days.total()),
+                        d.vouched_by.as_deref().unwrap_or("-"),
+                    );
                 }
             }
         }
+        DeviceAction::Export { output, new_identity } => {
+            let passphrase = read_passphrase("Passphrase to encrypt the bundle with: ")?;
+            let bundle = migrate::export(phantom_dir, &device_store, !new_identity)?;
+            let sealed = migrate::seal(&bundle, &passphrase)?;
+            std::fs::write(&output, sealed).with_context(|| format!("write {}", output.display()))?;
+            migrate::announce(phantom_dir, &bundle)?;
+            println!("Exported {} device(s) to {}.", bundle.devices.len(), output.display());
+            if bundle.previous_fingerprint.is_some() {
+                println!("The new host's fingerprint is {}.", bundle.identity.fingerprint()?);
+                println!("Devices learn it from this host: let each connect here once before the move.");
+                println!("Clients that don't follow the announced successor must be paired again.");
+            } else {
+                println!("The bundle holds this host's TLS key; keep it safe and stop this daemon after the move.");
+            }
+        }
+        DeviceAction::Import { path, force } => {
+            let bytes = std::fs::read(&path).with_context(|| format!("read {}", path.display()))?;
+            let passphrase = read_passphrase("Bundle passphrase: ")?;
+            let bundle = migrate::open(&bytes, &passphrase)?;
+            let imported = migrate::import(phantom_dir, &device_store, bundle, force)?;
+            println!("Imported {} device(s).", imported.devices_added.len());
+            if !imported.devices_skipped.is_empty() {
+                println!("Already paired here, left as they were: {}", imported.devices_skipped.join(", "));
+            }
+            match &imported.previous_fingerprint {
+                Some(previous) => println!("Fingerprint is now {} (devices knew {previous}).", imported.fingerprint),
+                None => println!("Fingerprint is now {}, as before the move.", imported.fingerprint),
+            }
+            if imported.identity_backed_up {
+                println!("The identity it replaced is in server.crt.bak and server.key.bak.");
+            }
+            println!("Restart the daemon to use it.");
+        }
         DeviceAction::Revoke { id } => {
             device_store.revoke_device(&id)?;
             println!("Device {id} revoked.");
         }
+        DeviceAction::AllowAgent { id } => {
+            device_store.set_agent_forwarding(&id, true)?;
+            println!("Device {id} may forward its SSH agent (from its next connection).");
+        }
+        DeviceAction::DenyAgent { id } => {
+            device_store.set_agent_forwarding(&id, false)?;
+            println!("Device {id} may no longer forward its SSH agent (from its next connection).");
+        }
+        DeviceAction::Restrict { id, allowed_commands, chroot, sandbox_profile } => {
+            let restrictions = Restrictions { allowed_commands, chroot, sandbox_profile };
+            let no_commands = restrictions.allowed_commands.is_empty();
+            device_store.set_restrictions(&id, Some(restrictions))?;
+            println!("Device {id} is restricted (from its next connection).");
+            if no_commands {
+                println!("No commands are allowed, so it can't start sessions; pass --allow to permit some.");
+            }
+        }
+        DeviceAction::Unrestrict { id } => {
+            device_store.set_restrictions(&id, None)?;
+            println!("Device {id} is no longer restricted (from its next connection).");
+        }
     }
     Ok(())
 }
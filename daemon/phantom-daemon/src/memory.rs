@@ -0,0 +1,138 @@
+//! Daemon-wide memory accounting.
+//!
+//! Session scrollback and per-bridge buffers reserve their size against a
+//! shared [`MemoryBudget`] before allocating, so N sessions × scrollback plus
+//! attached bridges can't grow without bound.
+
+use anyhow::Result;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use crate::errors::ErrorCode;
+
+/// Shared byte budget. A limit of 0 means unlimited (usage is still tracked).
+#[derive(Debug)]
+pub struct MemoryBudget {
+    limit: usize,
+    /// Usage above this marks the budget as under pressure
+    pressure: usize,
+    used: AtomicUsize,
+}
+
+impl MemoryBudget {
+    pub fn new(limit: usize, pressure_percent: u8) -> Arc<Self> {
+        let pressure = limit / 100 * usize::from(pressure_percent.min(100));
+        Arc::new(Self {
+            limit,
+            pressure,
+            used: AtomicUsize::new(0),
+        })
+    }
+
+    pub fn unlimited() -> Arc<Self> {
+        Self::new(0, 100)
+    }
+
+    /// Reserve `bytes`, failing if that would exceed the limit.
+    pub fn try_reserve(self: &Arc<Self>, bytes: usize) -> Result<Reservation> {
+        let limit = self.limit;
+        let reserved = self.used.fetch_update(Ordering::AcqRel, Ordering::Acquire, |used| {
+            let next = used.checked_add(bytes)?;
+            (limit == 0 || next <= limit).then_some(next)
+        });
+        if let Err(used) = reserved {
+            let exhausted = format!("memory budget exhausted: need {bytes} bytes, {used} of {limit} in use");
+            return Err(ErrorCode::LimitExceeded.err(exhausted));
+        }
+        Ok(Reservation {
+            budget: self.clone(),
+            bytes,
+        })
+    }
+
+    /// Bytes currently reserved.
+    pub fn used(&self) -> usize {
+        self.used.load(Ordering::Acquire)
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    /// Whether usage is above the pressure threshold (never, when unlimited).
+    pub fn under_pressure(&self) -> bool {
+        self.limit > 0 && self.used() > self.pressure
+    }
+}
+
+/// Bytes held against a [`MemoryBudget`], released on drop.
+#[derive(Debug)]
+pub struct Reservation {
+    budget: Arc<MemoryBudget>,
+    bytes: usize,
+}
+
+impl Reservation {
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    /// Change the reserved amount. Growing is not checked against the limit:
+    /// it is for memory an already-admitted owner needs back (e.g. expanding
+    /// compacted scrollback on reattach).
+    pub fn resize(&mut self, bytes: usize) {
+        if bytes > self.bytes {
+            self.budget.used.fetch_add(bytes - self.bytes, Ordering::AcqRel);
+        } else {
+            self.budget.used.fetch_sub(self.bytes - bytes, Ordering::AcqRel);
+        }
+        self.bytes = bytes;
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        self.budget.used.fetch_sub(self.bytes, Ordering::AcqRel);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reservations_are_released_on_drop() {
+        let budget = MemoryBudget::new(1000, 80);
+        let a = budget.try_reserve(600).unwrap();
+        let b = budget.try_reserve(400).unwrap();
+        assert_eq!(budget.used(), 1000);
+
+        let err = budget.try_reserve(1).unwrap_err();
+        assert!(err.to_string().contains("memory budget exhausted"));
+
+        drop(a);
+        assert_eq!(budget.used(), 400);
+        drop(b);
+        assert_eq!(budget.used(), 0);
+    }
+
+    #[test]
+    fn resize_tracks_pressure() {
+        let budget = MemoryBudget::new(1000, 80);
+        let mut r = budget.try_reserve(900).unwrap();
+        assert!(budget.under_pressure());
+        r.resize(100);
+        assert!(!budget.under_pressure());
+        assert_eq!(budget.used(), 100);
+        // Growing back may exceed the limit
+        r.resize(1200);
+        assert_eq!(budget.used(), 1200);
+    }
+
+    #[test]
+    fn unlimited_never_rejects() {
+        let budget = MemoryBudget::unlimited();
+        let _r = budget.try_reserve(usize::MAX / 2).unwrap();
+        assert!(!budget.under_pressure());
+    }
+}
@@ -0,0 +1,207 @@
+//! Client-facing error messages, by [`ErrorCode`], in the languages the
+//! daemon speaks.
+//!
+//! A client picks a language with `"language"` (a BCP 47 tag such as
+//! `de-AT`) in its auth request; the connection's error responses then carry
+//! `"message"`, the code's message in that language, next to the English
+//! `"error"` detail meant for logs. Untranslated languages get English.
+//!
+//! The language applies to the task serving a connection and the streams it
+//! spawns, like the connection ID (see [`crate::connections`]).
+
+use std::cell::Cell;
+use std::future::Future;
+
+use crate::errors::ErrorCode;
+
+tokio::task_local! {
+    static CURRENT: Cell<Language>;
+}
+
+/// A language messages are translated to.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Language {
+    #[default]
+    English,
+    Spanish,
+    French,
+    German,
+}
+
+impl Language {
+    pub const ALL: [Language; 4] = [Language::English, Language::Spanish, Language::French, Language::German];
+
+    /// The language of a BCP 47 `tag`, by its primary subtag, if translated.
+    pub fn from_tag(tag: &str) -> Option<Self> {
+        let primary = tag.split(['-', '_']).next().unwrap_or_default().to_ascii_lowercase();
+        Self::ALL.into_iter().find(|l| l.tag() == primary)
+    }
+
+    pub fn tag(self) -> &'static str {
+        match self {
+            Language::English => "en",
+            Language::Spanish => "es",
+            Language::French => "fr",
+            Language::German => "de",
+        }
+    }
+}
+
+/// The language of the calling task's connection, English outside one.
+pub fn current() -> Language {
+    CURRENT.try_with(Cell::get).unwrap_or_default()
+}
+
+/// Run `future` speaking `language`, for [`current`].
+pub async fn scope<F: Future>(language: Language, future: F) -> F::Output {
+    CURRENT.scope(Cell::new(language), future).await
+}
+
+/// Speak `language` from now on in the calling task's scope, once the client
+/// has said which it wants.
+pub fn set_current(language: Language) {
+    let _ = CURRENT.try_with(|current| current.set(language));
+}
+
+/// What `code` means, for a person, in `language`.
+pub fn message(code: ErrorCode, language: Language) -> &'static str {
+    let [en, es, fr, de] = match code {
+        ErrorCode::BadRequest => [
+            "The request was invalid.",
+            "La solicitud no es válida.",
+            "La requête n'est pas valide.",
+            "Die Anfrage ist ungültig.",
+        ],
+        ErrorCode::Unsupported => [
+            "This host doesn't support that request.",
+            "Este equipo no admite esa solicitud.",
+            "Cet hôte ne prend pas en charge cette requête.",
+            "Dieser Host unterstützt diese Anfrage nicht.",
+        ],
+        ErrorCode::Unauthenticated => [
+            "Authentication failed.",
+            "La autenticación ha fallado.",
+            "L'authentification a échoué.",
+            "Die Authentifizierung ist fehlgeschlagen.",
+        ],
+        ErrorCode::NotPaired => [
+            "This device isn't paired. Pair it again.",
+            "Este dispositivo no está vinculado. Vincúlalo de nuevo.",
+            "Cet appareil n'est pas associé. Associez-le à nouveau.",
+            "Dieses Gerät ist nicht gekoppelt. Koppeln Sie es erneut.",
+        ],
+        ErrorCode::PermissionDenied => [
+            "This device isn't allowed to do that.",
+            "Este dispositivo no tiene permiso para hacer eso.",
+            "Cet appareil n'est pas autorisé à faire cela.",
+            "Dieses Gerät darf das nicht.",
+        ],
+        ErrorCode::NotFound => [
+            "The session or device was not found.",
+            "No se encontró la sesión o el dispositivo.",
+            "Session ou appareil introuvable.",
+            "Sitzung oder Gerät nicht gefunden.",
+        ],
+        ErrorCode::AlreadyAttached => [
+            "The session is in use on another device.",
+            "La sesión se está usando en otro dispositivo.",
+            "La session est utilisée sur un autre appareil.",
+            "Die Sitzung wird auf einem anderen Gerät verwendet.",
+        ],
+        ErrorCode::NotAttached => [
+            "Nobody is using the session.",
+            "Nadie está usando la sesión.",
+            "Personne n'utilise la session.",
+            "Niemand verwendet die Sitzung.",
+        ],
+        ErrorCode::DamagedSession => [
+            "The session is damaged. Close it and start a new one.",
+            "La sesión está dañada. Ciérrala y abre una nueva.",
+            "La session est endommagée. Fermez-la et ouvrez-en une nouvelle.",
+            "Die Sitzung ist beschädigt. Beenden Sie sie und starten Sie eine neue.",
+        ],
+        ErrorCode::SessionExited => [
+            "The session has ended.",
+            "La sesión ha terminado.",
+            "La session est terminée.",
+            "Die Sitzung wurde beendet.",
+        ],
+        ErrorCode::LimitExceeded => [
+            "A limit on this host was reached.",
+            "Se alcanzó un límite en este equipo.",
+            "Une limite a été atteinte sur cet hôte.",
+            "Ein Limit auf diesem Host wurde erreicht.",
+        ],
+        ErrorCode::Unavailable => [
+            "This isn't available on this host.",
+            "Esto no está disponible en este equipo.",
+            "Ceci n'est pas disponible sur cet hôte.",
+            "Das ist auf diesem Host nicht verfügbar.",
+        ],
+        ErrorCode::Timeout => [
+            "The request timed out.",
+            "Se agotó el tiempo de espera.",
+            "Le délai d'attente a expiré.",
+            "Die Zeit für die Anfrage ist abgelaufen.",
+        ],
+        ErrorCode::RateLimited => [
+            "Too many attempts. Try again later.",
+            "Demasiados intentos. Inténtalo más tarde.",
+            "Trop de tentatives. Réessayez plus tard.",
+            "Zu viele Versuche. Versuchen Sie es später erneut.",
+        ],
+        ErrorCode::Internal => [
+            "Something went wrong on the host.",
+            "Algo salió mal en el equipo.",
+            "Une erreur s'est produite sur l'hôte.",
+            "Auf dem Host ist ein Fehler aufgetreten.",
+        ],
+    };
+    match language {
+        Language::English => en,
+        Language::Spanish => es,
+        Language::French => fr,
+        Language::German => de,
+    }
+}
+
+/// [`message`] in the calling task's language.
+pub fn localized(code: ErrorCode) -> &'static str {
+    message(code, current())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_code_is_translated() {
+        for code in ErrorCode::ALL {
+            let english = message(code, Language::English);
+            for language in &Language::ALL[1..] {
+                let translated = message(code, *language);
+                assert!(!translated.is_empty());
+                assert_ne!(translated, english, "{code:?} in {language:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn picks_languages_by_primary_subtag() {
+        assert_eq!(Language::from_tag("de-AT"), Some(Language::German));
+        assert_eq!(Language::from_tag("ES"), Some(Language::Spanish));
+        assert_eq!(Language::from_tag("fr_CA"), Some(Language::French));
+        assert_eq!(Language::from_tag("pt-BR"), None);
+    }
+
+    #[tokio::test]
+    async fn connections_speak_the_language_they_pick() {
+        assert_eq!(current(), Language::English);
+        let spoken = scope(Language::English, async {
+            set_current(Language::French);
+            localized(ErrorCode::SessionExited)
+        })
+        .await;
+        assert_eq!(spoken, "La session est terminée.");
+    }
+}
@@ -0,0 +1,254 @@
+//! Moving paired devices to a new host: `phantom device export` seals the
+//! device records and a TLS identity into a passphrase-encrypted bundle, and
+//! `phantom device import` installs them on the new machine.
+//!
+//! By default the old host's own identity moves with the devices, so they
+//! see no change. With `--new-identity` the bundle carries a new identity
+//! instead, and the old host advertises its fingerprint as `successor` in
+//! `server_info`; only clients that learn the new pin from it over a
+//! connection they already trust keep working, and the rest must re-pair.
+//!
+//! Importing onto a host that already has paired devices needs `--force`,
+//! and the identity it replaces is kept as `server.crt.bak` and
+//! `server.key.bak`.
+//!
+//! Bundles start with [`MAGIC`], then the PBKDF2 iteration count and salt,
+//! then the bundle JSON sealed by a [`Vault`] under the derived key.
+
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Utc};
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+use std::num::NonZeroU32;
+use std::path::Path;
+use tracing::info;
+
+use crate::device_store::{write_atomic, DeviceStore, PairedDevice};
+use crate::errors::ErrorCode;
+use crate::tls::Identity;
+use crate::vault::Vault;
+
+/// Prefix of every bundle.
+pub const MAGIC: &[u8] = b"PHDEVICES1";
+/// Where a host that's moving keeps its successor's fingerprint.
+pub const SUCCESSOR_FILE: &str = "successor.json";
+const SALT_LEN: usize = 16;
+const PBKDF2_ITERATIONS: u32 = 600_000;
+/// Most PBKDF2 iterations a bundle may ask for, so a crafted one can't tie
+/// up the importing host.
+const MAX_PBKDF2_ITERATIONS: u32 = 10_000_000;
+/// Shortest passphrase a bundle is sealed with.
+pub const MIN_PASSPHRASE_LEN: usize = 8;
+
+/// Paired devices and the identity the new host takes.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Bundle {
+    /// Host exported from
+    pub host: String,
+    pub exported_at: DateTime<Utc>,
+    pub devices: Vec<PairedDevice>,
+    pub identity: Identity,
+    /// Fingerprint devices have pinned, when `identity` is a new one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub previous_fingerprint: Option<String>,
+}
+
+/// The host devices should trust next, announced by one that's moving.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Successor {
+    pub fingerprint: String,
+    pub announced_at: DateTime<Utc>,
+}
+
+/// What an import changed.
+#[derive(Debug, Serialize)]
+pub struct Imported {
+    pub devices_added: Vec<String>,
+    /// Already paired here, left as they were
+    pub devices_skipped: Vec<String>,
+    pub fingerprint: String,
+    pub previous_fingerprint: Option<String>,
+    /// The identity replaced was kept as `server.crt.bak` and `server.key.bak`
+    pub identity_backed_up: bool,
+}
+
+/// Bundle this host's devices with its identity (`keep_identity`) or a
+/// new one for the host they move to.
+pub fn export(phantom_dir: &Path, device_store: &DeviceStore, keep_identity: bool) -> Result<Bundle> {
+    let current = Identity::load(phantom_dir)?;
+    let (identity, previous_fingerprint) = if keep_identity {
+        (current.context("this host has no TLS identity to move")?, None)
+    } else {
+        let previous = current.map(|c| c.fingerprint()).transpose()?;
+        (Identity::generate()?, previous)
+    };
+    Ok(Bundle {
+        host: crate::device_store::hostname(),
+        exported_at: Utc::now(),
+        devices: device_store.export_devices(),
+        identity,
+        previous_fingerprint,
+    })
+}
+
+/// Advertise `bundle`'s identity as this host's successor, once the bundle
+/// is safely written. Nothing to do if it's this host's own.
+pub fn announce(phantom_dir: &Path, bundle: &Bundle) -> Result<()> {
+    if bundle.previous_fingerprint.is_none() {
+        return Ok(());
+    }
+    let successor = Successor { fingerprint: bundle.identity.fingerprint()?, announced_at: Utc::now() };
+    let json = serde_json::to_string_pretty(&successor)?;
+    write_atomic(&phantom_dir.join(SUCCESSOR_FILE), json.as_bytes()).context("write successor.json")?;
+    info!("announcing successor host {}", successor.fingerprint);
+    Ok(())
+}
+
+/// The successor announced in `path`, if any.
+pub fn successor(path: &Path) -> Option<Successor> {
+    let json = std::fs::read_to_string(path).ok()?;
+    serde_json::from_str(&json).ok()
+}
+
+/// Add `bundle`'s devices to this host and take its identity (the daemon
+/// uses it from its next start), backing up the one it replaces. Refused
+/// if this host already has paired devices, unless `force`: they would lose
+/// the identity they pinned.
+pub fn import(phantom_dir: &Path, device_store: &DeviceStore, bundle: Bundle, force: bool) -> Result<Imported> {
+    let paired = device_store.list_devices().len();
+    if paired > 0 && !force {
+        return Err(ErrorCode::BadRequest.err(format!(
+            "this host already has {paired} paired device(s), which would stop recognising it; pass --force to import anyway"
+        )));
+    }
+    let identity_backed_up = Identity::back_up(phantom_dir)?;
+    let ids: Vec<String> = bundle.devices.iter().map(|d| d.device_id.clone()).collect();
+    let devices_added = device_store.import_devices(bundle.devices)?;
+    let devices_skipped = ids.into_iter().filter(|id| !devices_added.contains(id)).collect();
+    bundle.identity.save(phantom_dir)?;
+    // A host that takes over its own identity isn't moving any more
+    let _ = std::fs::remove_file(phantom_dir.join(SUCCESSOR_FILE));
+    info!("imported {} device(s) from {}", devices_added.len(), bundle.host);
+    Ok(Imported {
+        devices_added,
+        devices_skipped,
+        fingerprint: bundle.identity.fingerprint()?,
+        previous_fingerprint: bundle.previous_fingerprint,
+        identity_backed_up,
+    })
+}
+
+/// Encrypt `bundle` under `passphrase`.
+pub fn seal(bundle: &Bundle, passphrase: &str) -> Result<Vec<u8>> {
+    seal_with(bundle, passphrase, PBKDF2_ITERATIONS)
+}
+
+fn seal_with(bundle: &Bundle, passphrase: &str, iterations: u32) -> Result<Vec<u8>> {
+    if passphrase.chars().count() < MIN_PASSPHRASE_LEN {
+        return Err(ErrorCode::BadRequest.err(format!("passphrase must be at least {MIN_PASSPHRASE_LEN} characters")));
+    }
+    let mut salt = [0u8; SALT_LEN];
+    SystemRandom::new().fill(&mut salt).map_err(|_| anyhow::anyhow!("generate salt"))?;
+    let json = serde_json::to_vec(bundle).context("serialize bundle")?;
+    let sealed = Vault::new(&derive_key(passphrase, &salt, iterations)?).seal(&json)?;
+
+    let mut out = Vec::with_capacity(MAGIC.len() + 4 + SALT_LEN + sealed.len());
+    out.extend_from_slice(MAGIC);
+    out.extend_from_slice(&iterations.to_be_bytes());
+    out.extend_from_slice(&salt);
+    out.extend_from_slice(&sealed);
+    Ok(out)
+}
+
+/// Decrypt a bundle written by [`seal`].
+pub fn open(bytes: &[u8], passphrase: &str) -> Result<Bundle> {
+    let Some(body) = bytes.strip_prefix(MAGIC).filter(|b| b.len() > 4 + SALT_LEN) else {
+        bail!("not a device bundle");
+    };
+    let (iterations, body) = body.split_at(4);
+    let iterations = u32::from_be_bytes(iterations.try_into().expect("4 bytes"));
+    if iterations > MAX_PBKDF2_ITERATIONS {
+        return Err(ErrorCode::BadRequest.err(format!("bundle asks for {iterations} PBKDF2 iterations, more than {MAX_PBKDF2_ITERATIONS}")));
+    }
+    let (salt, sealed) = body.split_at(SALT_LEN);
+    let json = Vault::new(&derive_key(passphrase, salt, iterations)?)
+        .open(sealed)
+        .map_err(|_| ErrorCode::Unauthenticated.err("wrong passphrase, or the bundle is corrupt"))?;
+    serde_json::from_slice(&json).context("parse bundle")
+}
+
+fn derive_key(passphrase: &str, salt: &[u8], iterations: u32) -> Result<[u8; 32]> {
+    let iterations = NonZeroU32::new(iterations).context("bundle has no PBKDF2 iterations")?;
+    let mut key = [0u8; 32];
+    ring::pbkdf2::derive(ring::pbkdf2::PBKDF2_HMAC_SHA256, iterations, salt, passphrase.as_bytes(), &mut key);
+    Ok(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn store_with_devices(dir: &Path) -> DeviceStore {
+        let store = DeviceStore::new(dir).unwrap();
+        store.add_device("dev-1", "key-1", "Phone", None, None).unwrap();
+        store.add_device("dev-2", "key-2", "Tablet", None, Some("dev-1")).unwrap();
+        Identity::generate().unwrap().save(dir).unwrap();
+        store
+    }
+
+    #[test]
+    fn bundles_open_only_with_their_passphrase() {
+        let old = tempfile::TempDir::new().unwrap();
+        let store = store_with_devices(old.path());
+        let bundle = export(old.path(), &store, true).unwrap();
+
+        assert!(seal_with(&bundle, "short", 1000).is_err());
+        let sealed = seal_with(&bundle, "correct horse", 1000).unwrap();
+        assert!(sealed.starts_with(MAGIC));
+        assert!(!sealed.windows(5).any(|w| w == b"key-1"));
+        let err = open(&sealed, "wrong horse").unwrap_err();
+        assert_eq!(ErrorCode::of(&err), ErrorCode::Unauthenticated);
+        let opened = open(&sealed, "correct horse").unwrap();
+        assert_eq!(opened.devices.len(), 2);
+        assert!(opened.previous_fingerprint.is_none());
+        assert!(open(b"PHVAULT1...", "correct horse").is_err());
+
+        let mut greedy = sealed.clone();
+        greedy[MAGIC.len()..MAGIC.len() + 4].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert_eq!(ErrorCode::of(&open(&greedy, "correct horse").unwrap_err()), ErrorCode::BadRequest);
+    }
+
+    #[test]
+    fn moving_to_a_new_identity_announces_it_and_keeps_devices_paired() {
+        let (old, new) = (tempfile::TempDir::new().unwrap(), tempfile::TempDir::new().unwrap());
+        let old_store = store_with_devices(old.path());
+        let old_fingerprint = Identity::load(old.path()).unwrap().unwrap().fingerprint().unwrap();
+
+        let bundle = export(old.path(), &old_store, false).unwrap();
+        assert_eq!(bundle.previous_fingerprint.as_deref(), Some(old_fingerprint.as_str()));
+        announce(old.path(), &bundle).unwrap();
+        let announced = successor(&old.path().join(SUCCESSOR_FILE)).unwrap();
+        assert_eq!(announced.fingerprint, bundle.identity.fingerprint().unwrap());
+
+        let new_store = DeviceStore::new(new.path()).unwrap();
+        new_store.add_device("dev-2", "other-key", "Tablet", None, None).unwrap();
+        let replaced = Identity::generate().unwrap();
+        replaced.save(new.path()).unwrap();
+        // This host's devices would lose the identity they pinned
+        let err = import(new.path(), &new_store, export(old.path(), &old_store, true).unwrap(), false).unwrap_err();
+        assert_eq!(ErrorCode::of(&err), ErrorCode::BadRequest);
+        assert_eq!(Identity::load(new.path()).unwrap().unwrap().cert_pem, replaced.cert_pem);
+
+        let imported = import(new.path(), &new_store, bundle, true).unwrap();
+        assert!(imported.identity_backed_up);
+        let backup = std::fs::read_to_string(new.path().join("server.crt.bak")).unwrap();
+        assert_eq!(backup, replaced.cert_pem);
+        assert_eq!(imported.devices_added, ["dev-1"]);
+        assert_eq!(imported.devices_skipped, ["dev-2"]);
+        assert_eq!(imported.fingerprint, announced.fingerprint);
+        assert_eq!(new_store.get_public_key("dev-1").unwrap(), "key-1");
+        assert_eq!(new_store.get_public_key("dev-2").unwrap(), "other-key");
+        let installed = Identity::load(new.path()).unwrap().unwrap();
+        assert_eq!(installed.fingerprint().unwrap(), announced.fingerprint);
+    }
+}
@@ -0,0 +1,163 @@
+//! Session names derived from the first command typed into an unnamed
+//! session, so session lists read `cargo build` or `ssh prod` instead of a
+//! hex id.
+//!
+//! The bridge feeds client input to a [`CommandCapture`], which rebuilds
+//! each line as the shell would see it. Lines edited with anything the
+//! capture can't follow (arrow keys, history, tab completion) are skipped,
+//! as are trivial commands like `ls` or `cd`.
+
+/// Longest session name, in characters.
+pub const MAX_NAME_CHARS: usize = 64;
+
+/// Longest line worth keeping; a pasted blob isn't a command.
+const MAX_LINE_BYTES: usize = 1024;
+
+/// Commands too common to say what a session is for.
+const TRIVIAL: &[&str] = &[
+    "cd", "ls", "ll", "la", "l", "pwd", "clear", "reset", "exit", "logout", "history", "whoami", "true", "echo",
+];
+
+/// Wrappers whose command is the one after them.
+const WRAPPERS: &[&str] = &["sudo", "exec", "time", "nohup", "nice", "env"];
+
+/// Rebuilds command lines from terminal input.
+#[derive(Default)]
+pub struct CommandCapture {
+    line: Vec<u8>,
+    /// Edited in a way we can't follow, so not what the shell will run
+    garbled: bool,
+    /// Inside an escape sequence (arrow keys and the like)
+    in_escape: bool,
+}
+
+impl CommandCapture {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Feed client input; returns a name once a line worth naming the
+    /// session after is entered.
+    pub fn push(&mut self, input: &[u8]) -> Option<String> {
+        let mut name = None;
+        for &b in input {
+            if self.in_escape {
+                // Final byte of a CSI/SS3 sequence ends it
+                if (0x40..=0x7e).contains(&b) && b != b'[' && b != b'O' {
+                    self.in_escape = false;
+                }
+                continue;
+            }
+            match b {
+                b'\r' | b'\n' => {
+                    let line = std::mem::take(&mut self.line);
+                    if !std::mem::take(&mut self.garbled) && name.is_none() {
+                        name = std::str::from_utf8(&line).ok().and_then(name_for);
+                    }
+                }
+                // Backspace, Delete
+                0x08 | 0x7f => {
+                    // Drop a whole UTF-8 character
+                    while let Some(b) = self.line.pop() {
+                        if b & 0xc0 != 0x80 {
+                            break;
+                        }
+                    }
+                }
+                // Ctrl-C, Ctrl-U: the line is gone
+                0x03 | 0x15 => {
+                    self.line.clear();
+                    self.garbled = false;
+                }
+                0x1b => {
+                    self.in_escape = true;
+                    self.garbled = true;
+                }
+                // Tab completion and other control keys
+                0x00..=0x1f => self.garbled = true,
+                _ if self.line.len() >= MAX_LINE_BYTES => self.garbled = true,
+                _ => self.line.push(b),
+            }
+        }
+        name
+    }
+}
+
+/// The session name for command `line`, unless it's trivial: the command
+/// and its first argument, e.g. `cargo build` or `vim main.rs`.
+pub fn name_for(line: &str) -> Option<String> {
+    let mut words = line.split_whitespace().skip_while(|w| {
+        // Leading VAR=value assignments and wrappers
+        WRAPPERS.contains(w) || w.split_once('=').is_some_and(|(var, _)| !var.is_empty() && !var.contains('/'))
+    });
+    let command = basename(words.next()?);
+    if command.is_empty() || TRIVIAL.contains(&command) || command.starts_with('#') {
+        return None;
+    }
+    let mut name = command.to_string();
+    if let Some(arg) = words.find(|w| !w.starts_with('-')).map(basename).filter(|a| !a.is_empty()) {
+        name.push(' ');
+        name.push_str(arg);
+    }
+    Some(name.chars().take(MAX_NAME_CHARS).collect())
+}
+
+fn basename(word: &str) -> &str {
+    word.trim_end_matches('/').rsplit('/').next().unwrap_or(word)
+}
+
+/// Validate a client-given session name.
+pub fn validate(name: &str) -> Result<&str, String> {
+    let name = name.trim();
+    if name.is_empty() || name.chars().count() > MAX_NAME_CHARS {
+        return Err(format!("session name must be 1-{MAX_NAME_CHARS} characters"));
+    }
+    if name.chars().any(char::is_control) {
+        return Err("session name must not contain control characters".to_string());
+    }
+    Ok(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_after_the_command_and_its_first_argument() {
+        assert_eq!(name_for("cargo build --release").as_deref(), Some("cargo build"));
+        assert_eq!(name_for("vim src/main.rs").as_deref(), Some("vim main.rs"));
+        assert_eq!(name_for("RUST_LOG=debug sudo /usr/bin/htop").as_deref(), Some("htop"));
+        assert_eq!(name_for("ssh -A prod-db").as_deref(), Some("ssh prod-db"));
+        for trivial in ["", "   ", "ls -la", "cd ~/src", "clear", "# note"] {
+            assert_eq!(name_for(trivial), None, "{trivial:?}");
+        }
+        assert_eq!(name_for(&"x".repeat(100)).unwrap().len(), MAX_NAME_CHARS);
+    }
+
+    #[test]
+    fn capture_follows_line_editing() {
+        let mut capture = CommandCapture::new();
+        assert_eq!(capture.push(b"ls\r"), None);
+        assert_eq!(capture.push(b"cargo"), None);
+        assert_eq!(capture.push(b" tesx\x7ft\r").as_deref(), Some("cargo test"));
+
+        // Recalled from history: the shell runs something we never saw
+        let mut capture = CommandCapture::new();
+        assert_eq!(capture.push(b"\x1b[A\r"), None);
+        assert_eq!(capture.push(b"make\r").as_deref(), Some("make"));
+
+        // Tab completion
+        let mut capture = CommandCapture::new();
+        assert_eq!(capture.push(b"vim ma\t\r"), None);
+        // Ctrl-U starts over
+        assert_eq!(capture.push(b"junk\x15top\r").as_deref(), Some("top"));
+    }
+
+    #[test]
+    fn validates_given_names() {
+        assert_eq!(validate("  deploy  "), Ok("deploy"));
+        assert!(validate("").is_err());
+        assert!(validate("a\nb").is_err());
+        assert!(validate(&"x".repeat(MAX_NAME_CHARS + 1)).is_err());
+    }
+}
@@ -0,0 +1,247 @@
+//! Shells left running by a daemon that crashed.
+//!
+//! While a session exists its shell is recorded in the registry directory
+//! (`~/.phantom/children`, a file per PID) along with the daemon that owns
+//! it; the entry is removed with the session. A daemon that dies without
+//! cleaning up leaves its entries behind, and on startup [`ChildRegistry::sweep`]
+//! finds those whose shell is still running but whose daemon isn't, then
+//! applies `[session] orphans`: `kill` (the default) hangs up the shell's
+//! process group and kills what's left of it [`GRACE`] later, `keep` leaves
+//! it running. Orphans can't be adopted back as sessions: their PTY master
+//! closed with the daemon, so nothing can read or write their terminal.
+//!
+//! Start times are recorded with the PIDs, so a PID since reused by an
+//! unrelated process is never taken for a shell or for its daemon. Windows
+//! needs no registry: a dead daemon's job objects end its shells.
+
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tracing::{info, warn};
+
+use crate::config::OrphanPolicy;
+use crate::terminal::signal;
+
+/// How long a hung-up orphan has to exit before it is killed.
+pub const GRACE: Duration = Duration::from_secs(2);
+
+/// A registered shell and the daemon that spawned or adopted it.
+#[derive(Debug, Serialize, Deserialize)]
+struct Child {
+    pid: u32,
+    /// Start time, in the units of [`start_time`]
+    started: u64,
+    daemon_pid: u32,
+    daemon_started: u64,
+}
+
+/// Registry of the shells this daemon's sessions run.
+#[derive(Debug, Clone)]
+pub struct ChildRegistry {
+    dir: PathBuf,
+}
+
+/// A shell's registry entry, removed when dropped.
+#[derive(Debug)]
+pub struct Registered {
+    path: PathBuf,
+}
+
+impl Drop for Registered {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+impl ChildRegistry {
+    /// Keep the registry in `dir`, created on first use.
+    pub fn new(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+
+    /// Record `pid` as this process's shell until the returned entry is
+    /// dropped. Failures are logged: they cost only the orphan cleanup.
+    pub fn register(&self, pid: u32) -> Option<Registered> {
+        if cfg!(windows) {
+            return None;
+        }
+        let daemon_pid = std::process::id();
+        let (Some(started), Some(daemon_started)) = (start_time(pid), start_time(daemon_pid)) else {
+            warn!("shell {pid} not registered: can't read its start time");
+            return None;
+        };
+        let child = Child { pid, started, daemon_pid, daemon_started };
+        let path = self.dir.join(format!("{pid}.json"));
+        // Renamed into place, so a sweep never reads half an entry
+        let tmp = self.dir.join(format!("{pid}.tmp"));
+        let written = fs::create_dir_all(&self.dir)
+            .and_then(|()| fs::write(&tmp, serde_json::to_vec(&child).expect("serialize child")))
+            .and_then(|()| fs::rename(&tmp, &path));
+        match written {
+            Ok(()) => Some(Registered { path }),
+            Err(e) => {
+                warn!("shell {pid} not registered: write {}: {e}", path.display());
+                None
+            }
+        }
+    }
+
+    /// Forget entries whose daemon is gone, applying `policy` to their
+    /// shells that are still running. Returns those shells' PIDs.
+    pub async fn sweep(&self, policy: OrphanPolicy) -> Vec<u32> {
+        self.sweep_with(policy, GRACE).await
+    }
+
+    async fn sweep_with(&self, policy: OrphanPolicy, grace: Duration) -> Vec<u32> {
+        let Ok(entries) = fs::read_dir(&self.dir) else {
+            return Vec::new();
+        };
+        let mut orphans = Vec::new();
+        for path in entries.flatten().map(|e| e.path()) {
+            if path.extension().is_none_or(|ext| ext != "json") {
+                continue;
+            }
+            match read_child(&path) {
+                Some(child) if running(child.daemon_pid, child.daemon_started) => continue,
+                Some(child) if running(child.pid, child.started) => orphans.push(child.pid),
+                _ => {}
+            }
+            let _ = fs::remove_file(&path);
+        }
+        if orphans.is_empty() {
+            return orphans;
+        }
+
+        match policy {
+            OrphanPolicy::Keep => {
+                info!("leaving {} shell(s) a previous daemon left running: {orphans:?}", orphans.len());
+            }
+            OrphanPolicy::Kill => {
+                signal_groups(&orphans, signal::SIGHUP);
+                tokio::time::sleep(grace).await;
+                signal_groups(&orphans, signal::SIGKILL);
+                info!("killed {} shell(s) a previous daemon left running: {orphans:?}", orphans.len());
+            }
+        }
+        orphans
+    }
+}
+
+#[cfg(unix)]
+fn signal_groups(pids: &[u32], signal: i32) {
+    for &pid in pids {
+        unsafe {
+            libc::killpg(pid as i32, signal);
+        }
+    }
+}
+
+/// Nothing is registered on Windows, so nothing is left to signal.
+#[cfg(not(unix))]
+fn signal_groups(_pids: &[u32], _signal: i32) {}
+
+fn read_child(path: &Path) -> Option<Child> {
+    serde_json::from_slice(&fs::read(path).ok()?).ok()
+}
+
+/// Process `pid` is running and started at `started`.
+fn running(pid: u32, started: u64) -> bool {
+    start_time(pid) == Some(started)
+}
+
+/// When process `pid` started, in clock ticks since boot; `None` if it
+/// doesn't exist or is a zombie.
+#[cfg(target_os = "linux")]
+fn start_time(pid: u32) -> Option<u64> {
+    let stat = fs::read_to_string(format!("/proc/{pid}/stat")).ok()?;
+    // Fields after the command name, which may itself hold spaces and ')'
+    let mut fields = stat.rsplit_once(')')?.1.split_whitespace();
+    if fields.next()? == "Z" {
+        return None;
+    }
+    // Field 22, counting the state as 3
+    fields.nth(18)?.parse().ok()
+}
+
+/// When process `pid` started, in microseconds since the epoch; `None` if it
+/// doesn't exist or is a zombie.
+#[cfg(target_os = "macos")]
+fn start_time(pid: u32) -> Option<u64> {
+    // `SZOMB` in <sys/proc.h>
+    const ZOMBIE: u32 = 5;
+    let mut info: libc::proc_bsdinfo = unsafe { std::mem::zeroed() };
+    let size = std::mem::size_of::<libc::proc_bsdinfo>() as libc::c_int;
+    let buffer = &mut info as *mut libc::proc_bsdinfo as *mut libc::c_void;
+    let n = unsafe { libc::proc_pidinfo(pid as libc::c_int, libc::PROC_PIDTBSDINFO, 0, buffer, size) };
+    if n != size || info.pbi_status == ZOMBIE {
+        return None;
+    }
+    Some(info.pbi_start_tvsec * 1_000_000 + info.pbi_start_tvusec)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn start_time(_pid: u32) -> Option<u64> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::process::{CommandExt, ExitStatusExt};
+    use std::process::{Child as Process, Command};
+
+    fn shell() -> Process {
+        Command::new("sleep").arg("30").process_group(0).spawn().unwrap()
+    }
+
+    /// An entry for `pid` left by a daemon that has exited.
+    fn orphan(dir: &Path, pid: u32, started: u64) {
+        let mut gone = Command::new("true").spawn().unwrap();
+        let daemon_pid = gone.id();
+        gone.wait().unwrap();
+        let child = Child { pid, started, daemon_pid, daemon_started: 1 };
+        fs::write(dir.join(format!("{pid}.json")), serde_json::to_vec(&child).unwrap()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn entries_last_as_long_as_their_session() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let registry = ChildRegistry::new(dir.path().join("children"));
+        let mut shell = shell();
+        let entry = registry.register(shell.id()).unwrap();
+        let path = dir.path().join("children").join(format!("{}.json", shell.id()));
+        assert!(path.exists());
+
+        // Ours, so a sweep leaves it be
+        assert!(registry.sweep_with(OrphanPolicy::Kill, Duration::ZERO).await.is_empty());
+        assert!(path.exists());
+
+        drop(entry);
+        assert!(!path.exists());
+        shell.kill().unwrap();
+        shell.wait().unwrap();
+    }
+
+    #[tokio::test]
+    async fn sweeps_kill_or_keep_shells_whose_daemon_is_gone() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let registry = ChildRegistry::new(dir.path().to_path_buf());
+        let mut shell = shell();
+        let started = start_time(shell.id()).unwrap();
+        orphan(dir.path(), shell.id(), started);
+        let mut exited = Command::new("true").spawn().unwrap();
+        exited.wait().unwrap();
+        orphan(dir.path(), exited.id(), started);
+        fs::write(dir.path().join("torn.json"), "{\"pid\":").unwrap();
+
+        assert_eq!(registry.sweep_with(OrphanPolicy::Keep, Duration::ZERO).await, [shell.id()]);
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 0);
+        assert!(shell.try_wait().unwrap().is_none());
+
+        orphan(dir.path(), shell.id(), started);
+        let swept = registry.sweep_with(OrphanPolicy::Kill, Duration::from_millis(100)).await;
+        assert_eq!(swept, [shell.id()]);
+        assert_eq!(shell.wait().unwrap().signal(), Some(libc::SIGHUP));
+    }
+}
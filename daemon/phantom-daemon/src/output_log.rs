@@ -0,0 +1,123 @@
+//! Plain logs of everything a session printed, for grepping later.
+//!
+//! A session created with `log_output`, or switched on with
+//! `set_output_log`, has its raw PTY output appended to `<id>.log` in the
+//! log directory (`[session] output_log_dir`, default
+//! `~/.phantom/logs/sessions`) as the bridge reads it. When a log reaches
+//! [`Rotation::max_bytes`] it moves to `<id>.log.1`, older ones shift up,
+//! and those past [`Rotation::keep`] are deleted.
+//!
+//! The logs are plain text, so the daemon only keeps them with
+//! `encrypt_scrollback` off: sealing them would defeat grepping, and keeping
+//! them unsealed would leak what the encrypted scrollback protects.
+
+use anyhow::{Context, Result};
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use tracing::warn;
+
+use crate::device_store::private_file;
+
+/// When logs rotate and how many old ones are kept.
+#[derive(Debug, Clone, Copy)]
+pub struct Rotation {
+    pub max_bytes: u64,
+    pub keep: usize,
+}
+
+impl Default for Rotation {
+    fn default() -> Self {
+        Self { max_bytes: 10 * 1024 * 1024, keep: 3 }
+    }
+}
+
+/// An open session log.
+pub struct OutputLog {
+    path: PathBuf,
+    file: File,
+    /// Bytes in the current file
+    written: u64,
+    rotation: Rotation,
+}
+
+impl OutputLog {
+    /// Append to `<session_id>.log` in `dir`, creating both as needed.
+    pub fn open(dir: &Path, session_id: &str, rotation: Rotation) -> Result<Self> {
+        fs::create_dir_all(dir).with_context(|| format!("create {}", dir.display()))?;
+        let path = dir.join(format!("{session_id}.log"));
+        let file = open_append(&path)?;
+        let written = file.metadata().map_or(0, |m| m.len());
+        Ok(Self { path, file, written, rotation })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Append `data`, rotating first if it would overflow the file. Errors
+    /// are logged, not returned: a full disk mustn't end the session.
+    pub fn write(&mut self, data: &[u8]) {
+        if self.written > 0 && self.written + data.len() as u64 > self.rotation.max_bytes {
+            if let Err(e) = self.rotate() {
+                warn!("can't rotate {}: {e:#}", self.path.display());
+            }
+        }
+        match self.file.write_all(data) {
+            Ok(()) => self.written += data.len() as u64,
+            Err(e) => warn!("can't write {}: {e}", self.path.display()),
+        }
+    }
+
+    /// `<id>.log.N` → `.N+1` (dropping the oldest), `<id>.log` → `.1`, and a
+    /// fresh `<id>.log`.
+    fn rotate(&mut self) -> Result<()> {
+        let numbered = |n: usize| PathBuf::from(format!("{}.{n}", self.path.display()));
+        if self.rotation.keep == 0 {
+            fs::remove_file(&self.path).context("remove log")?;
+        } else {
+            let _ = fs::remove_file(numbered(self.rotation.keep));
+            for n in (1..self.rotation.keep).rev() {
+                let _ = fs::rename(numbered(n), numbered(n + 1));
+            }
+            fs::rename(&self.path, numbered(1)).context("rename log")?;
+        }
+        self.file = open_append(&self.path)?;
+        self.written = 0;
+        Ok(())
+    }
+}
+
+fn open_append(path: &Path) -> Result<File> {
+    private_file()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("open {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rotates_and_keeps_the_newest_logs() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let rotation = Rotation { max_bytes: 10, keep: 2 };
+        let mut log = OutputLog::open(dir.path(), "s1", rotation).unwrap();
+        for chunk in ["aaaaaaaa", "bbbbbbbb", "cccccccc", "dddd"] {
+            log.write(chunk.as_bytes());
+        }
+        let read = |name: &str| fs::read_to_string(dir.path().join(name)).unwrap();
+        assert_eq!(read("s1.log"), "dddd");
+        assert_eq!(read("s1.log.1"), "cccccccc");
+        assert_eq!(read("s1.log.2"), "bbbbbbbb");
+        assert!(!dir.path().join("s1.log.3").exists());
+
+        // Reopening appends
+        drop(log);
+        let mut log = OutputLog::open(dir.path(), "s1", rotation).unwrap();
+        log.write(b"e");
+        assert_eq!(read("s1.log"), "dddde");
+    }
+}
@@ -0,0 +1,182 @@
+//! Pastes sent as Paste frames instead of Data.
+//!
+//! A large paste typed into the PTY in one write can overflow the line
+//! discipline's input queue (bytes past `MAX_INPUT` are dropped) and
+//! interleave with its own echo. The bridge instead writes a paste in
+//! [`CHUNK`]-byte pieces [`CHUNK_INTERVAL`] apart, and acknowledges each
+//! Paste frame once written so the client can show progress. When the app
+//! in the terminal has turned on bracketed paste (`ESC [?2004h`, tracked by
+//! [`ModeWatch`]), the paste is wrapped in `ESC [200~` … `ESC [201~`, with
+//! any markers inside the text removed so it can't end its own bracket. The
+//! removal repeats until none are left, since the bytes either side of a
+//! removed marker can join into a new one.
+
+use std::time::Duration;
+
+/// Bytes written to the PTY at a time.
+pub const CHUNK: usize = 512;
+/// Pause between chunks, for the app to drain its input.
+pub const CHUNK_INTERVAL: Duration = Duration::from_millis(4);
+
+const START: &[u8] = b"\x1b[200~";
+const END: &[u8] = b"\x1b[201~";
+const MODE_ON: &[u8] = b"\x1b[?2004h";
+const MODE_OFF: &[u8] = b"\x1b[?2004l";
+
+/// Follows output for the app turning bracketed paste on or off, including
+/// sequences split across reads.
+#[derive(Default)]
+pub struct ModeWatch {
+    tail: [u8; MODE_ON.len() - 1],
+    tail_len: usize,
+}
+
+impl ModeWatch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The mode `output` leaves bracketed paste in, if it changes it.
+    pub fn observe(&mut self, output: &[u8]) -> Option<bool> {
+        // Sequences straddling the previous read come before any in `output`
+        let mut seam = [0u8; 2 * (MODE_ON.len() - 1)];
+        let head = &output[..output.len().min(self.tail.len())];
+        seam[..self.tail_len].copy_from_slice(&self.tail[..self.tail_len]);
+        seam[self.tail_len..self.tail_len + head.len()].copy_from_slice(head);
+        let seam = &seam[..self.tail_len + head.len()];
+        let mode = last_mode(output).or_else(|| last_mode(seam));
+
+        if output.len() >= self.tail.len() {
+            let n = self.tail.len();
+            self.tail.copy_from_slice(&output[output.len() - n..]);
+            self.tail_len = n;
+        } else {
+            let keep = seam.len().min(self.tail.len());
+            self.tail[..keep].copy_from_slice(&seam[seam.len() - keep..]);
+            self.tail_len = keep;
+        }
+        mode
+    }
+}
+
+fn last_mode(buf: &[u8]) -> Option<bool> {
+    buf.windows(MODE_ON.len()).rev().find_map(|w| match w {
+        MODE_ON => Some(true),
+        MODE_OFF => Some(false),
+        _ => None,
+    })
+}
+
+/// A paste being written: what goes to the PTY for each Paste frame.
+pub struct Paste {
+    bracketed: bool,
+    started: bool,
+    /// End of the previous frame that may be the start of a marker
+    carry: Vec<u8>,
+    /// Pasted bytes received so far
+    pub received: u64,
+}
+
+impl Paste {
+    pub fn new(bracketed: bool) -> Self {
+        Self { bracketed, started: false, carry: Vec::new(), received: 0 }
+    }
+
+    /// What to write for the next slice of pasted text.
+    pub fn push(&mut self, text: &[u8]) -> Vec<u8> {
+        self.received += text.len() as u64;
+        if !self.bracketed {
+            return text.to_vec();
+        }
+        let mut out = Vec::with_capacity(START.len() + text.len());
+        if !self.started {
+            self.started = true;
+            out.extend_from_slice(START);
+        }
+        let mut joined = std::mem::take(&mut self.carry);
+        joined.extend_from_slice(text);
+        strip_markers(&mut joined);
+        let held = partial_marker(&joined);
+        self.carry = joined.split_off(joined.len() - held);
+        out.extend_from_slice(&joined);
+        out
+    }
+
+    /// What to write to end the paste.
+    pub fn finish(self) -> Vec<u8> {
+        if !self.bracketed {
+            return Vec::new();
+        }
+        let mut out = Vec::with_capacity(START.len() + self.carry.len() + END.len());
+        if !self.started {
+            out.extend_from_slice(START);
+        }
+        out.extend_from_slice(&self.carry);
+        out.extend_from_slice(END);
+        out
+    }
+}
+
+/// Length of the longest suffix of `buf` that a marker starts with.
+fn partial_marker(buf: &[u8]) -> usize {
+    (1..START.len())
+        .rev()
+        .find(|&n| n <= buf.len() && [START, END].iter().any(|m| buf.ends_with(&m[..n])))
+        .unwrap_or(0)
+}
+
+/// Remove bracketed paste markers from `text`, including ones that only
+/// form once another is removed.
+fn strip_markers(text: &mut Vec<u8>) {
+    let mut from = 0;
+    while let Some(at) = text[from..].windows(START.len()).position(|w| w == START || w == END) {
+        let at = from + at;
+        text.drain(at..at + START.len());
+        // The bytes either side may now make up a marker ending past `at`
+        from = at.saturating_sub(START.len() - 1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn follows_the_mode_across_reads() {
+        let mut watch = ModeWatch::new();
+        assert_eq!(watch.observe(b"prompt\x1b[?2004h$ "), Some(true));
+        assert_eq!(watch.observe(b"ls\r\n"), None);
+        assert_eq!(watch.observe(b"\x1b[?20"), None);
+        assert_eq!(watch.observe(b"04l"), Some(false));
+        assert_eq!(watch.observe(b"\x1b[?2004h\x1b[?2004l"), Some(false));
+    }
+
+    #[test]
+    fn brackets_pastes_and_strips_markers_split_across_frames() {
+        let mut paste = Paste::new(true);
+        let mut written = paste.push(b"echo a\x1b[20");
+        written.extend(paste.push(b"1~; rm -rf ~\n"));
+        written.extend(paste.finish());
+        assert_eq!(written, b"\x1b[200~echo a; rm -rf ~\n\x1b[201~");
+        assert_eq!(Paste::new(true).finish(), b"\x1b[200~\x1b[201~");
+
+        let mut plain = Paste::new(false);
+        assert_eq!(plain.push(b"ls\n"), b"ls\n");
+        assert!(plain.finish().is_empty());
+    }
+
+    #[test]
+    fn markers_nested_in_markers_are_stripped_too() {
+        let mut paste = Paste::new(true);
+        let mut written = paste.push(b"\x1b[20\x1b[200~1~; touch /tmp/pwned\n");
+        written.extend(paste.finish());
+        assert_eq!(written, b"\x1b[200~; touch /tmp/pwned\n\x1b[201~");
+
+        // What's left of a frame once stripped can start a marker the next ends
+        let mut paste = Paste::new(true);
+        let mut written = paste.push(b"ab\x1b[20\x1b[200~");
+        written.extend(paste.push(b"1~x"));
+        written.extend(paste.finish());
+        assert_eq!(written, b"\x1b[200~abx\x1b[201~");
+    }
+}
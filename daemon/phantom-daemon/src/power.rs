@@ -0,0 +1,222 @@
+//! Keeping the host awake (macOS IOPMAssertions).
+//!
+//! By default the daemon prevents idle system sleep for as long as it runs.
+//! `[power]` can add display and disk assertions, and can hold them only
+//! while sessions are alive or attached so an idle daemon lets a laptop
+//! sleep. Elsewhere this is a no-op.
+
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+use tracing::info;
+
+use crate::config::{PowerConfig, PowerWhen};
+use crate::session::SessionManager;
+
+/// How often session activity is checked when `when` isn't `always`.
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+// kIOPMAssertionTypePreventUserIdleSystemSleep
+const SYSTEM_SLEEP: &[u8] = b"PreventUserIdleSystemSleep\0";
+// kIOPMAssertionTypePreventUserIdleDisplaySleep
+const DISPLAY_SLEEP: &[u8] = b"PreventUserIdleDisplaySleep\0";
+// kIOPMAssertPreventDiskIdle
+const DISK_SLEEP: &[u8] = b"PreventDiskIdle\0";
+
+impl PowerWhen {
+    /// Whether the assertions should be held given the current sessions.
+    pub fn wanted(self, any_alive: bool, any_attached: bool) -> bool {
+        match self {
+            PowerWhen::Always => true,
+            PowerWhen::Sessions => any_alive,
+            PowerWhen::Attached => any_attached,
+        }
+    }
+}
+
+/// The configured assertions, taken and released together.
+pub struct PowerAssertions {
+    types: Vec<&'static [u8]>,
+    held: Option<Vec<u32>>,
+}
+
+impl PowerAssertions {
+    pub fn new(config: &PowerConfig) -> Self {
+        let types = [
+            (config.prevent_system_sleep, SYSTEM_SLEEP),
+            (config.prevent_display_sleep, DISPLAY_SLEEP),
+            (config.prevent_disk_sleep, DISK_SLEEP),
+        ]
+        .into_iter()
+        .filter_map(|(on, assertion_type)| on.then_some(assertion_type))
+        .collect();
+        Self { types, held: None }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.types.is_empty()
+    }
+
+    pub fn is_held(&self) -> bool {
+        self.held.is_some()
+    }
+
+    pub fn acquire(&mut self) {
+        if self.held.is_some() {
+            return;
+        }
+        let ids = self.types.iter().filter_map(|t| platform::create(t)).collect();
+        self.held = Some(ids);
+        info!("sleep prevention enabled");
+    }
+
+    pub fn release(&mut self) {
+        if let Some(ids) = self.held.take() {
+            for id in ids {
+                platform::release(id);
+            }
+            info!("sleep prevention disabled");
+        }
+    }
+}
+
+impl Drop for PowerAssertions {
+    fn drop(&mut self) {
+        self.release();
+    }
+}
+
+/// Hold the configured assertions while `config.when` asks for them, until
+/// `cancel`.
+pub async fn run(config: PowerConfig, sm: Arc<SessionManager>, cancel: CancellationToken) {
+    let mut assertions = PowerAssertions::new(&config);
+    if assertions.is_empty() {
+        return;
+    }
+    if config.when == PowerWhen::Always {
+        assertions.acquire();
+        cancel.cancelled().await;
+        return;
+    }
+
+    let mut interval = tokio::time::interval(POLL_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = cancel.cancelled() => break,
+        }
+        let sessions = sm.list_sessions();
+        let any_alive = sessions.iter().any(|s| s.alive);
+        let any_attached = sessions.iter().any(|s| s.attached);
+        if config.when.wanted(any_alive, any_attached) {
+            assertions.acquire();
+        } else {
+            assertions.release();
+        }
+    }
+}
+
+#[cfg(target_os = "macos")]
+mod platform {
+    // CoreFoundation and IOKit FFI
+    type CFStringRef = *const std::ffi::c_void;
+    type IOPMAssertionID = u32;
+
+    extern "C" {
+        fn CFStringCreateWithCString(
+            alloc: *const std::ffi::c_void,
+            c_str: *const std::ffi::c_char,
+            encoding: u32,
+        ) -> CFStringRef;
+        fn CFRelease(cf: *const std::ffi::c_void);
+        fn IOPMAssertionCreateWithName(
+            assertion_type: CFStringRef,
+            level: u32,
+            reason: CFStringRef,
+            assertion_id: *mut IOPMAssertionID,
+        ) -> i32;
+        fn IOPMAssertionRelease(assertion_id: IOPMAssertionID) -> i32;
+    }
+
+    const K_CF_STRING_ENCODING_UTF8: u32 = 0x08000100;
+    const REASON: &[u8] = b"Phantom daemon active\0";
+    // kIOPMAssertionLevelOn
+    const K_IOPM_ASSERTION_LEVEL_ON: u32 = 255;
+
+    /// Take an assertion of `assertion_type` (NUL-terminated).
+    pub fn create(assertion_type: &[u8]) -> Option<u32> {
+        unsafe {
+            let type_ref = CFStringCreateWithCString(
+                std::ptr::null(),
+                assertion_type.as_ptr() as *const std::ffi::c_char,
+                K_CF_STRING_ENCODING_UTF8,
+            );
+            let reason = CFStringCreateWithCString(
+                std::ptr::null(),
+                REASON.as_ptr() as *const std::ffi::c_char,
+                K_CF_STRING_ENCODING_UTF8,
+            );
+
+            let mut assertion_id: IOPMAssertionID = 0;
+            let result = IOPMAssertionCreateWithName(
+                type_ref,
+                K_IOPM_ASSERTION_LEVEL_ON,
+                reason,
+                &mut assertion_id,
+            );
+
+            CFRelease(reason);
+            CFRelease(type_ref);
+
+            let name = String::from_utf8_lossy(&assertion_type[..assertion_type.len() - 1]);
+            if result == 0 {
+                tracing::debug!("{name} asserted (IOPMAssertion {assertion_id})");
+                Some(assertion_id)
+            } else {
+                tracing::warn!("failed to create {name} IOPMAssertion: error {result}");
+                None
+            }
+        }
+    }
+
+    pub fn release(id: u32) {
+        let result = unsafe { IOPMAssertionRelease(id) };
+        if result != 0 {
+            tracing::warn!("failed to release IOPMAssertion {id}: error {result}");
+        }
+    }
+}
+
+#[cfg(not(target_os = "macos"))]
+mod platform {
+    pub fn create(_assertion_type: &[u8]) -> Option<u32> {
+        None
+    }
+
+    pub fn release(_id: u32) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn assertions_follow_config() {
+        assert!(PowerWhen::Always.wanted(false, false));
+        assert!(!PowerWhen::Sessions.wanted(false, false));
+        assert!(PowerWhen::Sessions.wanted(true, false));
+        assert!(!PowerWhen::Attached.wanted(true, false));
+        assert!(PowerWhen::Attached.wanted(true, true));
+
+        let config = PowerConfig { prevent_disk_sleep: true, ..PowerConfig::default() };
+        let mut assertions = PowerAssertions::new(&config);
+        assert_eq!(assertions.types, [SYSTEM_SLEEP, DISK_SLEEP]);
+        assertions.acquire();
+        assert!(assertions.is_held());
+        assertions.release();
+        assert!(!assertions.is_held());
+
+        let off = PowerConfig { prevent_system_sleep: false, ..PowerConfig::default() };
+        assert!(PowerAssertions::new(&off).is_empty());
+    }
+}
@@ -0,0 +1,115 @@
+//! Which project a session is working in, for grouping the session list.
+//!
+//! A session's project is the git toplevel of its shell's working
+//! directory, found by walking up to the nearest directory holding a `.git`
+//! entry (a directory, or a file for worktrees and submodules) rather than
+//! running git for every session on every list.
+
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Project {
+    /// Name of the root directory
+    pub name: String,
+    pub root: PathBuf,
+}
+
+impl Project {
+    /// The project `dir` is in, if any.
+    pub fn detect(dir: &Path) -> Option<Self> {
+        let root = dir.ancestors().find(|d| d.join(".git").exists())?;
+        Some(Self {
+            name: root.file_name()?.to_string_lossy().into_owned(),
+            root: root.to_path_buf(),
+        })
+    }
+
+    /// Whether a `project` filter names this project: its name, or its root.
+    pub fn matches(&self, filter: &str) -> bool {
+        self.name == filter || self.root == Path::new(filter)
+    }
+}
+
+/// The sessions of one project in a session list.
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub struct ProjectGroup {
+    #[serde(flatten)]
+    pub project: Project,
+    pub sessions: Vec<String>,
+}
+
+/// Group `(session id, project)` pairs by project, sorted by project name.
+/// Sessions outside any project are left out.
+pub fn group<'a>(sessions: impl IntoIterator<Item = (&'a str, Option<&'a Project>)>) -> Vec<ProjectGroup> {
+    let mut groups: Vec<ProjectGroup> = Vec::new();
+    for (id, project) in sessions {
+        let Some(project) = project else { continue };
+        match groups.iter_mut().find(|g| g.project == *project) {
+            Some(group) => group.sessions.push(id.to_string()),
+            None => groups.push(ProjectGroup { project: project.clone(), sessions: vec![id.to_string()] }),
+        }
+    }
+    groups.sort_by(|a, b| (&a.project.name, &a.project.root).cmp(&(&b.project.name, &b.project.root)));
+    groups
+}
+
+/// Current working directory of process `pid`.
+#[cfg(target_os = "linux")]
+pub fn process_cwd(pid: u32) -> Option<PathBuf> {
+    std::fs::read_link(format!("/proc/{pid}/cwd")).ok()
+}
+
+/// Current working directory of process `pid`.
+#[cfg(target_os = "macos")]
+pub fn process_cwd(pid: u32) -> Option<PathBuf> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let mut info: libc::proc_vnodepathinfo = unsafe { std::mem::zeroed() };
+    let size = std::mem::size_of::<libc::proc_vnodepathinfo>() as libc::c_int;
+    let buffer = &mut info as *mut libc::proc_vnodepathinfo as *mut libc::c_void;
+    let n = unsafe { libc::proc_pidinfo(pid as libc::c_int, libc::PROC_PIDVNODEPATHINFO, 0, buffer, size) };
+    if n != size {
+        return None;
+    }
+    // MAXPATHLEN bytes, declared by libc as 32 rows of 32
+    let path = info.pvi_cdir.vip_path.iter().flatten();
+    let bytes: Vec<u8> = path.take_while(|&&c| c != 0).map(|&c| c as u8).collect();
+    Some(PathBuf::from(std::ffi::OsStr::from_bytes(&bytes)))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+pub fn process_cwd(_pid: u32) -> Option<PathBuf> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_the_nearest_git_root() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = dir.path().join("phantom");
+        std::fs::create_dir_all(repo.join(".git")).unwrap();
+        std::fs::create_dir_all(repo.join("daemon/src")).unwrap();
+
+        let project = Project::detect(&repo.join("daemon/src")).unwrap();
+        assert_eq!(project, Project { name: "phantom".to_string(), root: repo.clone() });
+        assert!(project.matches("phantom"));
+        assert!(project.matches(repo.to_str().unwrap()));
+        assert!(!project.matches("daemon"));
+
+        // A worktree's `.git` is a file
+        let worktree = dir.path().join("phantom-wt");
+        std::fs::create_dir_all(&worktree).unwrap();
+        std::fs::write(worktree.join(".git"), "gitdir: ../phantom/.git/worktrees/wt\n").unwrap();
+        assert_eq!(Project::detect(&worktree).unwrap().name, "phantom-wt");
+    }
+
+    #[test]
+    fn finds_this_process_cwd() {
+        let pid = std::process::id();
+        assert_eq!(process_cwd(pid), std::env::current_dir().ok());
+    }
+}
@@ -0,0 +1,853 @@
+//! A machine-readable description of the client protocol, printed by
+//! `phantom protocol dump`, for clients to generate their models from.
+//!
+//! Control messages are length-prefixed JSON objects told apart by `type`.
+//! Their handlers read fields straight off the JSON, so the fields are listed
+//! here by hand, next to the frame types, flags and error codes taken from
+//! their definitions. The dump is a JSON Schema (2020-12) with a definition
+//! per message, named `client.<type>` or `daemon.<type>`; frames are under
+//! `x-frames` and connection close codes under `x-close-codes`.
+
+use serde_json::{json, Map, Value};
+
+use crate::errors::ErrorCode;
+use phantom_frame::{self as frame, FrameType};
+
+/// The JSON type of a field.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    String,
+    Integer,
+    Boolean,
+    Object,
+    Array,
+    /// An [`ErrorCode`] name
+    Code,
+}
+
+/// A field of a message, besides `type`.
+#[derive(Debug, Clone, Copy)]
+pub struct Field {
+    pub name: &'static str,
+    pub kind: Kind,
+    pub required: bool,
+    pub doc: &'static str,
+}
+
+const fn required(name: &'static str, kind: Kind, doc: &'static str) -> Field {
+    Field { name, kind, required: true, doc }
+}
+
+const fn optional(name: &'static str, kind: Kind, doc: &'static str) -> Field {
+    Field { name, kind, required: false, doc }
+}
+
+/// Who sends a message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Sender {
+    Client,
+    Daemon,
+}
+
+/// A control message type.
+#[derive(Debug)]
+pub struct Message {
+    pub name: &'static str,
+    pub sender: Sender,
+    pub doc: &'static str,
+    /// What the daemon answers with, besides `error`
+    pub replies: &'static [&'static str],
+    pub fields: &'static [Field],
+}
+
+const REQUEST_ID: Field = optional("request_id", Kind::String, "Echoed in the reply");
+const SESSION_ID: Field = required("session_id", Kind::String, "");
+const REPLY_ID: Field = required("request_id", Kind::String, "The request's, or empty");
+/// Describes the size marks replayed output comes with.
+const SCROLLBACK_SIZES: &str =
+    "{offset, rows, cols}: the terminal size output from `offset` on was laid out for, the first in force at the start";
+
+/// Options a bridged session stream is opened with.
+const BRIDGE_OPTIONS: [Field; 6] = [
+    optional("coalesce_ms", Kind::Integer, "Gather output this long before sending it (0 = as read)"),
+    optional("low_bandwidth", Kind::Boolean, "Throttle output to what's left on screen"),
+    optional("max_bytes_per_sec", Kind::Integer, "Cap output at this many bytes a second (at least 1024)"),
+    optional("utf8_frames", Kind::Boolean, "End Data frames on codepoint boundaries; flag binary ones"),
+    optional("dedup_input", Kind::Boolean, "Skip input frames numbered at or below this device's last_input_sequence"),
+    optional("terminal", Kind::Object, "`term`, `truecolor` and `unicode_version` the client emulates"),
+];
+
+/// Every control message, requests before their replies.
+pub const MESSAGES: &[Message] = &[
+    // ── Authentication, on the first stream of a connection ──────────────
+    Message {
+        name: "auth_request",
+        sender: Sender::Client,
+        doc: "Opens a connection: pairs, resumes, or asks for a challenge to sign",
+        replies: &["auth_challenge", "auth_response"],
+        fields: &[
+            required("request_id", Kind::String, ""),
+            required("device_id", Kind::String, ""),
+            optional("public_key", Kind::String, "Base64 SEC1 P-256 key, when pairing"),
+            optional("device_name", Kind::String, "When pairing"),
+            optional("pairing_token", Kind::String, "From `phantom pair`"),
+            optional("guest_token", Kind::String, "From `phantom share`"),
+            optional("resume_token", Kind::String, "From the previous connection's auth_response"),
+            optional("vouch", Kind::Boolean, "Pair by having a paired device approve"),
+            optional("user", Kind::String, "With `vouch` in multi-user mode: whose devices approve"),
+            optional("webauthn", Kind::Object, "Pairing a hardware key: its `credential_id` and `rp_id`"),
+            optional("server_info", Kind::Boolean, "Send server_info after a successful auth_response"),
+            optional("version_advisory", Kind::Boolean, "Send version_advisory after a successful auth_response"),
+            optional("language", Kind::String, "BCP 47 tag; errors carry `message` in it"),
+            optional("hello", Kind::Object, "protocol_version, frame_types, compression, max_payload it takes"),
+        ],
+    },
+    Message {
+        name: "auth_response",
+        sender: Sender::Client,
+        doc: "The signed challenge",
+        replies: &["auth_response"],
+        fields: &[
+            required("request_id", Kind::String, ""),
+            required("device_id", Kind::String, ""),
+            required("signature", Kind::String, "Base64 DER ECDSA signature over the challenge or assertion"),
+            optional("authenticator_data", Kind::String, "Base64, answering a WebAuthn challenge"),
+            optional("client_data_json", Kind::String, "Base64, answering a WebAuthn challenge"),
+        ],
+    },
+    Message {
+        name: "auth_challenge",
+        sender: Sender::Daemon,
+        doc: "Bytes for a paired device to sign",
+        replies: &[],
+        fields: &[
+            REPLY_ID,
+            required("challenge", Kind::String, "Base64"),
+            optional("webauthn", Kind::Object, "Hardware keys: `credential_id` and `rp_id` to assert with"),
+        ],
+    },
+    Message {
+        name: "auth_response",
+        sender: Sender::Daemon,
+        doc: "How authentication ended; the connection closes after a failure",
+        replies: &[],
+        fields: &[
+            REPLY_ID,
+            required("success", Kind::Boolean, ""),
+            optional("code", Kind::Code, "On failure"),
+            optional("error", Kind::String, "On failure"),
+            optional("message", Kind::String, "On failure: `code` explained in the requested language"),
+            optional("session_id", Kind::String, "For guests: the one session they may view"),
+            optional("resume_token", Kind::String, "Present it on the next reconnect to skip the challenge (not for hardware keys)"),
+            optional("resumed", Kind::Boolean, "Authenticated by resume token"),
+            optional("retry_after_secs", Kind::Integer, "With RATE_LIMITED"),
+        ],
+    },
+    Message {
+        name: "server_info",
+        sender: Sender::Daemon,
+        doc: "The daemon's version, the features this device may use, limits and addresses",
+        replies: &[],
+        fields: &[
+            REPLY_ID,
+            required("name", Kind::String, "Host name"),
+            required("version", Kind::String, ""),
+            required("protocol_version", Kind::Integer, ""),
+            required("features", Kind::Object, "Feature name to whether this device may use it"),
+            required("limits", Kind::Object, "max_payload, max_control_message, max_sessions, ..."),
+            required("hosts", Kind::Array, "Addresses the host may be reached at"),
+            optional("wake", Kind::Object, "How to wake the host"),
+            optional("successor", Kind::Object, "The host this one is moving to"),
+            required("close_codes", Kind::Array, "Codes connections are closed with: code, name, description"),
+        ],
+    },
+    Message {
+        name: "hello",
+        sender: Sender::Daemon,
+        doc: "After auth with `hello`: what the connection's streams use",
+        replies: &[],
+        fields: &[
+            REPLY_ID,
+            required("version", Kind::String, ""),
+            required("protocol_version", Kind::Integer, "The lower of the client's and the daemon's"),
+            required("frame_types", Kind::Array, "Frame types sent to this client, by name"),
+            optional("compression", Kind::String, "`zstd`, or null for none"),
+            required("max_payload", Kind::Integer, "Largest frame payload sent"),
+        ],
+    },
+    Message {
+        name: "version_advisory",
+        sender: Sender::Daemon,
+        doc: "After auth: the daemon's version and the oldest app version it supports",
+        replies: &[],
+        fields: &[
+            REPLY_ID,
+            required("version", Kind::String, ""),
+            required("protocol_version", Kind::Integer, ""),
+            optional("min_client_version", Kind::String, "Older apps should ask to be updated"),
+            optional("release_notes", Kind::String, "What changed in this daemon"),
+        ],
+    },
+    Message {
+        name: "resumable_sessions",
+        sender: Sender::Daemon,
+        doc: "After a resumed auth: sessions still attached, to reattach with `takeover`",
+        replies: &[],
+        fields: &[REPLY_ID, required("session_ids", Kind::Array, "")],
+    },
+    // ── Requests, on any stream after authentication ─────────────────────
+    Message {
+        name: "create_session",
+        sender: Sender::Client,
+        doc: "Start a session and bridge it on this stream",
+        replies: &["session_created"],
+        fields: &[
+            REQUEST_ID,
+            optional("rows", Kind::Integer, "1-500, default 24"),
+            optional("cols", Kind::Integer, "1-500, default 80"),
+            optional("name", Kind::String, ""),
+            optional("command", Kind::String, "Instead of the login shell"),
+            optional("tmux_session", Kind::String, "Attach this tmux session instead"),
+            optional("agent_forwarding", Kind::Boolean, ""),
+            optional("log_output", Kind::Boolean, "Tee output into a log on the host"),
+            optional("budget", Kind::Object, "cpu_secs and/or lifetime_secs, terminated once used up"),
+            BRIDGE_OPTIONS[0],
+            BRIDGE_OPTIONS[1],
+            BRIDGE_OPTIONS[2],
+            BRIDGE_OPTIONS[3],
+            BRIDGE_OPTIONS[4],
+            BRIDGE_OPTIONS[5],
+        ],
+    },
+    Message {
+        name: "attach_session",
+        sender: Sender::Client,
+        doc: "Drive a session, bridged on this stream",
+        replies: &["session_attached"],
+        fields: &[
+            REQUEST_ID,
+            SESSION_ID,
+            optional("takeover", Kind::Boolean, "Replace this device's own stale bridge"),
+            optional("replay_bytes", Kind::Integer, "Replay at most this much scrollback"),
+            optional("handoff_id", Kind::String, "From handoff_offered: take the session over from its device"),
+            BRIDGE_OPTIONS[0],
+            BRIDGE_OPTIONS[1],
+            BRIDGE_OPTIONS[2],
+            BRIDGE_OPTIONS[3],
+            BRIDGE_OPTIONS[4],
+            BRIDGE_OPTIONS[5],
+        ],
+    },
+    Message {
+        name: "mirror_session",
+        sender: Sender::Client,
+        doc: "Watch a session read-only, bridged on this stream",
+        replies: &["session_mirrored"],
+        fields: &[REQUEST_ID, SESSION_ID],
+    },
+    Message {
+        name: "fetch_scrollback",
+        sender: Sender::Client,
+        doc: "A page of scrollback older than what was replayed",
+        replies: &["scrollback_page"],
+        fields: &[
+            REQUEST_ID,
+            SESSION_ID,
+            required("before_offset", Kind::Integer, ""),
+            optional("max_bytes", Kind::Integer, ""),
+        ],
+    },
+    Message {
+        name: "exec",
+        sender: Sender::Client,
+        doc: "Run one command; its output follows as frames on this stream",
+        replies: &["exec_started"],
+        fields: &[
+            REQUEST_ID,
+            required("command", Kind::String, "Run with the user's shell"),
+            optional("rows", Kind::Integer, ""),
+            optional("cols", Kind::Integer, ""),
+            optional("timeout_secs", Kind::Integer, "Default 60, at most max_exec_timeout_secs"),
+        ],
+    },
+    Message {
+        name: "server_info",
+        sender: Sender::Client,
+        doc: "Ask for server_info again",
+        replies: &["server_info"],
+        fields: &[REQUEST_ID],
+    },
+    Message {
+        name: "list_sessions",
+        sender: Sender::Client,
+        doc: "",
+        replies: &["session_list"],
+        fields: &[REQUEST_ID, optional("project", Kind::String, "Only sessions in this project")],
+    },
+    Message {
+        name: "session_history",
+        sender: Sender::Client,
+        doc: "Who attached, detached, resized or destroyed a live or recently ended session",
+        replies: &["session_history"],
+        fields: &[REQUEST_ID, SESSION_ID],
+    },
+    Message {
+        name: "inspect_session",
+        sender: Sender::Client,
+        doc: "Diagnostics for a session that won't reattach",
+        replies: &["session_inspected"],
+        fields: &[REQUEST_ID, SESSION_ID],
+    },
+    Message {
+        name: "get_daemon_config",
+        sender: Sender::Client,
+        doc: "The daemon's effective limits, timeouts and feature toggles; not for restricted devices",
+        replies: &["daemon_config"],
+        fields: &[REQUEST_ID],
+    },
+    Message {
+        name: "connection_stats",
+        sender: Sender::Client,
+        doc: "Counters of a session's attachment",
+        replies: &["connection_stats"],
+        fields: &[REQUEST_ID, SESSION_ID],
+    },
+    Message {
+        name: "resize_session",
+        sender: Sender::Client,
+        doc: "",
+        replies: &["session_resized"],
+        fields: &[REQUEST_ID, SESSION_ID, required("rows", Kind::Integer, ""), required("cols", Kind::Integer, "")],
+    },
+    Message {
+        name: "kill_foreground",
+        sender: Sender::Client,
+        doc: "Signal the session's foreground process group",
+        replies: &["foreground_killed"],
+        fields: &[REQUEST_ID, SESSION_ID, optional("signal", Kind::String, "INT, TERM (default), HUP or KILL")],
+    },
+    Message {
+        name: "set_output_log",
+        sender: Sender::Client,
+        doc: "",
+        replies: &["output_log_set"],
+        fields: &[REQUEST_ID, SESSION_ID, required("enabled", Kind::Boolean, "")],
+    },
+    Message {
+        name: "list_tmux_sessions",
+        sender: Sender::Client,
+        doc: "",
+        replies: &["tmux_session_list"],
+        fields: &[REQUEST_ID],
+    },
+    Message {
+        name: "set_clipboard",
+        sender: Sender::Client,
+        doc: "Set the host's clipboard",
+        replies: &["clipboard_set"],
+        fields: &[REQUEST_ID, required("text", Kind::String, "")],
+    },
+    Message {
+        name: "record_macro",
+        sender: Sender::Client,
+        doc: "",
+        replies: &["macro_recorded"],
+        fields: &[REQUEST_ID, required("name", Kind::String, ""), required("input", Kind::String, "")],
+    },
+    Message {
+        name: "list_macros",
+        sender: Sender::Client,
+        doc: "",
+        replies: &["macros"],
+        fields: &[REQUEST_ID],
+    },
+    Message {
+        name: "delete_macro",
+        sender: Sender::Client,
+        doc: "",
+        replies: &["macro_deleted"],
+        fields: &[REQUEST_ID, required("name", Kind::String, "")],
+    },
+    Message {
+        name: "run_macro",
+        sender: Sender::Client,
+        doc: "Type a macro into a session",
+        replies: &["macro_run"],
+        fields: &[REQUEST_ID, required("name", Kind::String, ""), SESSION_ID],
+    },
+    Message {
+        name: "list_schedules",
+        sender: Sender::Client,
+        doc: "",
+        replies: &["schedules"],
+        fields: &[REQUEST_ID],
+    },
+    Message {
+        name: "scheduled_run",
+        sender: Sender::Client,
+        doc: "A scheduled command's run, with its output",
+        replies: &["scheduled_run"],
+        fields: &[REQUEST_ID, required("run_id", Kind::Integer, "")],
+    },
+    Message {
+        name: "handoff_session",
+        sender: Sender::Client,
+        doc: "Hand a session attached from this device to another; answered once that one attached",
+        replies: &["session_handed_off"],
+        fields: &[REQUEST_ID, SESSION_ID, required("device_id", Kind::String, "A connected device")],
+    },
+    Message {
+        name: "destroy_session",
+        sender: Sender::Client,
+        doc: "",
+        replies: &["session_destroyed"],
+        fields: &[REQUEST_ID, SESSION_ID],
+    },
+    Message {
+        name: "approve_pairing",
+        sender: Sender::Client,
+        doc: "Answer a pairing_request",
+        replies: &["pairing_settled"],
+        fields: &[REQUEST_ID, required("pairing_id", Kind::String, "")],
+    },
+    Message {
+        name: "deny_pairing",
+        sender: Sender::Client,
+        doc: "Answer a pairing_request",
+        replies: &["pairing_settled"],
+        fields: &[REQUEST_ID, required("pairing_id", Kind::String, "")],
+    },
+    Message {
+        name: "remove_device",
+        sender: Sender::Client,
+        doc: "Unpair this device; the stream ends after the reply",
+        replies: &["device_removed"],
+        fields: &[REQUEST_ID],
+    },
+    // ── Replies ──────────────────────────────────────────────────────────
+    Message {
+        name: "session_created",
+        sender: Sender::Daemon,
+        doc: "Frames follow on the stream",
+        replies: &[],
+        fields: &[
+            REPLY_ID,
+            SESSION_ID,
+            required("terminal", Kind::Object, "The caps the session runs with"),
+            optional("budget", Kind::Object, "The budget the session runs with"),
+        ],
+    },
+    Message {
+        name: "session_attached",
+        sender: Sender::Daemon,
+        doc: "Frames follow on the stream: replayed scrollback, an empty Scrollback frame, then live output",
+        replies: &[],
+        fields: &[
+            REPLY_ID,
+            SESSION_ID,
+            optional("scrollback_offset", Kind::Integer, "Where the replay starts, for fetch_scrollback"),
+            optional("scrollback_sizes", Kind::Array, SCROLLBACK_SIZES),
+            optional("last_input_sequence", Kind::Integer, "Sequence of the last input frame this device wrote to the session"),
+            required("terminal", Kind::Object, ""),
+        ],
+    },
+    Message {
+        name: "session_mirrored",
+        sender: Sender::Daemon,
+        doc: "Frames follow on the stream",
+        replies: &[],
+        fields: &[
+            REPLY_ID,
+            SESSION_ID,
+            optional("scrollback_offset", Kind::Integer, "Where the replay starts"),
+            optional("scrollback_sizes", Kind::Array, SCROLLBACK_SIZES),
+        ],
+    },
+    Message {
+        name: "scrollback_page",
+        sender: Sender::Daemon,
+        doc: "",
+        replies: &[],
+        fields: &[
+            REPLY_ID,
+            SESSION_ID,
+            required("offset", Kind::Integer, "Where `data` starts; page on from here"),
+            required("data", Kind::String, "Base64"),
+            required("more", Kind::Boolean, "Older output is still held"),
+            optional("sizes", Kind::Array, SCROLLBACK_SIZES),
+        ],
+    },
+    Message {
+        name: "exec_started",
+        sender: Sender::Daemon,
+        doc: "Output follows as Data frames, then a Close frame with the exit status",
+        replies: &[],
+        fields: &[REPLY_ID],
+    },
+    Message {
+        name: "session_list",
+        sender: Sender::Daemon,
+        doc: "",
+        replies: &[],
+        fields: &[REPLY_ID, required("sessions", Kind::Array, ""), required("projects", Kind::Array, "")],
+    },
+    Message {
+        name: "session_history",
+        sender: Sender::Daemon,
+        doc: "",
+        replies: &[],
+        fields: &[REPLY_ID, SESSION_ID, required("ended", Kind::Boolean, ""), required("events", Kind::Array, "")],
+    },
+    Message {
+        name: "session_inspected",
+        sender: Sender::Daemon,
+        doc: "",
+        replies: &[],
+        fields: &[REPLY_ID, required("session", Kind::Object, "")],
+    },
+    Message {
+        name: "daemon_config",
+        sender: Sender::Daemon,
+        doc: "Settings without paths, commands or addresses",
+        replies: &[],
+        fields: &[
+            REPLY_ID,
+            required("session", Kind::Object, "Scrollback size, session limit, retention, damaged policy"),
+            required("bridge", Kind::Object, "Timeouts (null = none), stream limit, this device's input limit"),
+            required("features", Kind::Object, "As in server_info"),
+        ],
+    },
+    Message {
+        name: "connection_stats",
+        sender: Sender::Daemon,
+        doc: "",
+        replies: &[],
+        fields: &[REPLY_ID, SESSION_ID, required("stats", Kind::Object, "")],
+    },
+    Message {
+        name: "session_resized",
+        sender: Sender::Daemon,
+        doc: "",
+        replies: &[],
+        fields: &[REPLY_ID, SESSION_ID, required("rows", Kind::Integer, ""), required("cols", Kind::Integer, "")],
+    },
+    Message {
+        name: "foreground_killed",
+        sender: Sender::Daemon,
+        doc: "",
+        replies: &[],
+        fields: &[
+            REPLY_ID,
+            SESSION_ID,
+            required("signal", Kind::String, ""),
+            required("process_group", Kind::Integer, ""),
+        ],
+    },
+    Message {
+        name: "output_log_set",
+        sender: Sender::Daemon,
+        doc: "",
+        replies: &[],
+        fields: &[REPLY_ID, SESSION_ID, required("enabled", Kind::Boolean, ""), optional("path", Kind::String, "")],
+    },
+    Message {
+        name: "tmux_session_list",
+        sender: Sender::Daemon,
+        doc: "",
+        replies: &[],
+        fields: &[REPLY_ID, required("sessions", Kind::Array, "")],
+    },
+    Message {
+        name: "clipboard_set",
+        sender: Sender::Daemon,
+        doc: "",
+        replies: &[],
+        fields: &[REPLY_ID],
+    },
+    Message {
+        name: "macro_recorded",
+        sender: Sender::Daemon,
+        doc: "",
+        replies: &[],
+        fields: &[REPLY_ID, required("macro", Kind::Object, "")],
+    },
+    Message {
+        name: "macros",
+        sender: Sender::Daemon,
+        doc: "",
+        replies: &[],
+        fields: &[REPLY_ID, required("macros", Kind::Array, "")],
+    },
+    Message {
+        name: "macro_deleted",
+        sender: Sender::Daemon,
+        doc: "",
+        replies: &[],
+        fields: &[REPLY_ID, required("name", Kind::String, "")],
+    },
+    Message {
+        name: "macro_run",
+        sender: Sender::Daemon,
+        doc: "",
+        replies: &[],
+        fields: &[REPLY_ID, SESSION_ID, required("bytes", Kind::Integer, "Bytes typed")],
+    },
+    Message {
+        name: "schedules",
+        sender: Sender::Daemon,
+        doc: "",
+        replies: &[],
+        fields: &[REPLY_ID, required("schedules", Kind::Array, ""), required("runs", Kind::Array, "Recent runs")],
+    },
+    Message {
+        name: "scheduled_run",
+        sender: Sender::Daemon,
+        doc: "",
+        replies: &[],
+        fields: &[REPLY_ID, required("run", Kind::Object, ""), required("output", Kind::String, "")],
+    },
+    Message {
+        name: "session_handed_off",
+        sender: Sender::Daemon,
+        doc: "The other device attached; this device's bridge has ended",
+        replies: &[],
+        fields: &[
+            REPLY_ID,
+            SESSION_ID,
+            required("device_id", Kind::String, ""),
+            required("offset", Kind::Integer, "Output offset the other device replayed from"),
+        ],
+    },
+    Message {
+        name: "session_destroyed",
+        sender: Sender::Daemon,
+        doc: "",
+        replies: &[],
+        fields: &[
+            REPLY_ID,
+            required("success", Kind::Boolean, ""),
+            optional("code", Kind::Code, "On failure"),
+            optional("error", Kind::String, "On failure"),
+            optional("message", Kind::String, "On failure, in the language picked at auth"),
+        ],
+    },
+    Message {
+        name: "pairing_settled",
+        sender: Sender::Daemon,
+        doc: "",
+        replies: &[],
+        fields: &[REPLY_ID, required("pairing_id", Kind::String, ""), required("approved", Kind::Boolean, "")],
+    },
+    Message {
+        name: "device_removed",
+        sender: Sender::Daemon,
+        doc: "",
+        replies: &[],
+        fields: &[REPLY_ID, required("success", Kind::Boolean, "")],
+    },
+    Message {
+        name: "session_detached",
+        sender: Sender::Daemon,
+        doc: "Answers a detach on a bridged stream, which then takes requests again",
+        replies: &[],
+        fields: &[
+            SESSION_ID,
+            required("last_sequence_sent", Kind::Integer, ""),
+            required("last_sequence_received", Kind::Integer, ""),
+            optional("rows", Kind::Integer, ""),
+            optional("cols", Kind::Integer, ""),
+        ],
+    },
+    Message {
+        name: "error",
+        sender: Sender::Daemon,
+        doc: "A request failed; the stream takes further requests",
+        replies: &[],
+        fields: &[
+            REPLY_ID,
+            required("code", Kind::Code, ""),
+            required("error", Kind::String, "For people, not for parsing"),
+            required("message", Kind::String, "`code` explained in the language picked at auth"),
+        ],
+    },
+    // ── Events, each on a unidirectional stream of its own ───────────────
+    Message {
+        name: "session_renamed",
+        sender: Sender::Daemon,
+        doc: "An unnamed session was named after its first command",
+        replies: &[],
+        fields: &[SESSION_ID, required("name", Kind::String, "")],
+    },
+    Message {
+        name: "session_removed",
+        sender: Sender::Daemon,
+        doc: "The reaper removed a session",
+        replies: &[],
+        fields: &[
+            SESSION_ID,
+            required("reason", Kind::String, "exited, damaged, cpu_budget, lifetime_budget, ..."),
+            optional("exit_code", Kind::Integer, ""),
+        ],
+    },
+    Message {
+        name: "session_budget_warning",
+        sender: Sender::Daemon,
+        doc: "A session has used most of its budget, and is terminated when it's used up",
+        replies: &[],
+        fields: &[
+            SESSION_ID,
+            required("resource", Kind::String, "cpu or lifetime"),
+            required("used_secs", Kind::Integer, ""),
+            required("limit_secs", Kind::Integer, ""),
+        ],
+    },
+    Message {
+        name: "handoff_offered",
+        sender: Sender::Daemon,
+        doc: "Another device hands this one a session; attach_session with `handoff_id` to take it",
+        replies: &[],
+        fields: &[
+            required("handoff_id", Kind::String, ""),
+            SESSION_ID,
+            optional("name", Kind::String, ""),
+            required("from_device_id", Kind::String, ""),
+            required("expires_in_secs", Kind::Integer, ""),
+        ],
+    },
+    Message {
+        name: "pairing_request",
+        sender: Sender::Daemon,
+        doc: "A new device asks to be paired; answer with approve_pairing or deny_pairing",
+        replies: &[],
+        fields: &[
+            required("pairing_id", Kind::String, ""),
+            required("device_id", Kind::String, ""),
+            required("device_name", Kind::String, ""),
+            required("verification_code", Kind::String, "Shown on both devices"),
+            required("expires_in_secs", Kind::Integer, ""),
+        ],
+    },
+    Message {
+        name: crate::agent::STREAM_TYPE,
+        sender: Sender::Daemon,
+        doc: "Header of a bidirectional stream relaying an SSH agent connection",
+        replies: &[],
+        fields: &[SESSION_ID],
+    },
+];
+
+/// The protocol as a JSON Schema document.
+pub fn schema() -> Value {
+    let mut defs = Map::new();
+    defs.insert("ErrorCode".into(), json!({ "type": "string", "enum": ErrorCode::ALL }));
+    for message in MESSAGES {
+        defs.insert(def_name(message), message_schema(message));
+    }
+    let refs = |sender| {
+        MESSAGES
+            .iter()
+            .filter(|m| m.sender == sender)
+            .map(|m| json!({ "$ref": format!("#/$defs/{}", def_name(m)) }))
+            .collect::<Vec<_>>()
+    };
+    defs.insert("ClientMessage".into(), json!({ "oneOf": refs(Sender::Client) }));
+    defs.insert("DaemonMessage".into(), json!({ "oneOf": refs(Sender::Daemon) }));
+
+    json!({
+        "$schema": "https://json-schema.org/draft/2020-12/schema",
+        "title": "Phantom control protocol",
+        "version": crate::VERSION,
+        "protocol_version": crate::PROTOCOL_VERSION,
+        "oneOf": [{ "$ref": "#/$defs/ClientMessage" }, { "$ref": "#/$defs/DaemonMessage" }],
+        "$defs": defs,
+        "x-frames": frames(),
+        "x-close-codes": crate::connections::close_codes(),
+    })
+}
+
+fn def_name(message: &Message) -> String {
+    let sender = match message.sender {
+        Sender::Client => "client",
+        Sender::Daemon => "daemon",
+    };
+    format!("{sender}.{}", message.name)
+}
+
+fn message_schema(message: &Message) -> Value {
+    let mut properties = Map::new();
+    properties.insert("type".into(), json!({ "const": message.name }));
+    let mut required = vec!["type"];
+    for field in message.fields {
+        let mut schema = match field.kind {
+            Kind::String => json!({ "type": "string" }),
+            Kind::Integer => json!({ "type": "integer", "minimum": 0 }),
+            Kind::Boolean => json!({ "type": "boolean" }),
+            Kind::Object => json!({ "type": "object" }),
+            Kind::Array => json!({ "type": "array" }),
+            Kind::Code => json!({ "$ref": "#/$defs/ErrorCode" }),
+        };
+        if !field.doc.is_empty() {
+            schema["description"] = field.doc.into();
+        }
+        properties.insert(field.name.into(), schema);
+        if field.required {
+            required.push(field.name);
+        }
+    }
+    let mut schema = json!({
+        "type": "object",
+        "properties": properties,
+        "required": required,
+    });
+    if !message.doc.is_empty() {
+        schema["description"] = message.doc.into();
+    }
+    if !message.replies.is_empty() {
+        schema["x-replies"] = message.replies.into();
+    }
+    schema
+}
+
+fn frames() -> Value {
+    let types: Vec<Value> = FrameType::CORE
+        .iter()
+        .map(|t| json!({ "name": t.name(), "code": t.to_u8() }))
+        .collect();
+    json!({
+        "header_size": frame::HEADER_SIZE,
+        "max_payload": frame::MAX_PAYLOAD,
+        "types": types,
+        "extension_base": frame::EXTENSION_BASE,
+        "flags": [
+            { "name": "compressed", "bit": frame::FLAG_COMPRESSED },
+            { "name": "binary", "bit": frame::FLAG_BINARY },
+        ],
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_message_is_described_once() {
+        let schema = schema();
+        let defs = schema["$defs"].as_object().unwrap();
+        // ErrorCode, ClientMessage and DaemonMessage besides the messages
+        assert_eq!(defs.len(), MESSAGES.len() + 3);
+
+        let create = &defs["client.create_session"];
+        assert_eq!(create["properties"]["type"]["const"], "create_session");
+        assert_eq!(create["x-replies"], json!(["session_created"]));
+        let error = &defs["daemon.error"];
+        assert_eq!(error["required"], json!(["type", "request_id", "code", "error", "message"]));
+        assert_eq!(defs["ErrorCode"]["enum"][0], "BAD_REQUEST");
+
+        // Replies name messages the daemon sends
+        for reply in MESSAGES.iter().flat_map(|m| m.replies) {
+            assert!(defs.contains_key(&format!("daemon.{reply}")), "{reply}");
+        }
+        assert_eq!(schema["x-frames"]["types"][8], json!({ "name": "error", "code": 9 }));
+        assert_eq!(schema["x-close-codes"][3]["name"], "revoked");
+    }
+}
@@ -0,0 +1,172 @@
+//! Sliding-window rate limiting of connections and auth failures, by IP or
+//! device.
+
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::clock::{self, SharedClock};
+
+/// At most `max_per_window` events per key in any `window`.
+pub struct RateLimiter<K> {
+    /// Map of key → event timestamps, oldest first
+    events: Mutex<HashMap<K, Vec<Instant>>>,
+    max_per_window: usize,
+    window: Duration,
+    clock: SharedClock,
+}
+
+impl<K: Hash + Eq + Clone> RateLimiter<K> {
+    pub fn new(max_per_window: usize, window: Duration) -> Self {
+        Self {
+            events: Mutex::new(HashMap::new()),
+            max_per_window,
+            window,
+            clock: clock::system(),
+        }
+    }
+
+    /// Read time from `clock` instead of the system's.
+    pub fn with_clock(self, clock: SharedClock) -> Self {
+        Self { clock, ..self }
+    }
+
+    /// Returns true if the event should be allowed, and records it.
+    pub fn check(&self, key: &K) -> bool {
+        self.with_events(key, |timestamps, now, max| {
+            if timestamps.len() >= max {
+                false
+            } else {
+                timestamps.push(now);
+                true
+            }
+        })
+    }
+
+    /// Returns true if under the limit, without recording a new event.
+    pub fn is_allowed(&self, key: &K) -> bool {
+        self.retry_after(key).is_none()
+    }
+
+    /// How long until `key` is under the limit again, or None if it is.
+    pub fn retry_after(&self, key: &K) -> Option<Duration> {
+        self.with_events(key, |timestamps, now, _| self.wait(timestamps, now))
+    }
+
+    /// Record an event without checking limits (for tracking failures).
+    pub fn record(&self, key: &K) {
+        self.with_events(key, |timestamps, now, _| timestamps.push(now));
+    }
+
+    /// Forget `key`'s events. Whether it had any unexpired.
+    pub fn clear(&self, key: &K) -> bool {
+        let mut map = self.events.lock().expect("rate limiter lock");
+        self.prune(&mut map);
+        map.remove(key).is_some()
+    }
+
+    /// Every key with unexpired events: how many, and how long until it is
+    /// under the limit again (None if it is).
+    pub fn entries(&self) -> Vec<(K, usize, Option<Duration>)> {
+        let mut map = self.events.lock().expect("rate limiter lock");
+        let now = self.prune(&mut map);
+        map.iter()
+            .map(|(key, timestamps)| (key.clone(), timestamps.len(), self.wait(timestamps, now)))
+            .collect()
+    }
+
+    pub fn max_per_window(&self) -> usize {
+        self.max_per_window
+    }
+
+    pub fn window(&self) -> Duration {
+        self.window
+    }
+
+    /// `f` on `key`'s unexpired events.
+    fn with_events<R>(&self, key: &K, f: impl FnOnce(&mut Vec<Instant>, Instant, usize) -> R) -> R {
+        let mut map = self.events.lock().expect("rate limiter lock");
+        let now = self.prune(&mut map);
+        let timestamps = map.entry(key.clone()).or_default();
+        let result = f(timestamps, now, self.max_per_window);
+        if timestamps.is_empty() {
+            map.remove(key);
+        }
+        result
+    }
+
+    /// How long until `timestamps` are under the limit again.
+    fn wait(&self, timestamps: &[Instant], now: Instant) -> Option<Duration> {
+        // The event whose expiry brings the count back under the limit
+        let blocking = timestamps.len().checked_sub(self.max_per_window)?;
+        Some(self.window.saturating_sub(now.duration_since(timestamps[blocking])))
+    }
+
+    /// Drop expired events, and keys with none left. Returns the time now.
+    fn prune(&self, map: &mut HashMap<K, Vec<Instant>>) -> Instant {
+        let now = self.clock.now();
+        map.retain(|_, timestamps| {
+            timestamps.retain(|t| now.duration_since(*t) < self.window);
+            !timestamps.is_empty()
+        });
+        now
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+
+    #[test]
+    fn limits_events_per_key() {
+        let limiter = RateLimiter::new(2, Duration::from_secs(60));
+        assert!(limiter.check(&"a"));
+        assert!(limiter.check(&"a"));
+        assert!(!limiter.check(&"a"));
+        assert!(limiter.check(&"b"));
+    }
+
+    #[test]
+    fn says_when_a_limited_key_may_retry() {
+        let limiter = RateLimiter::new(2, Duration::from_secs(300));
+        limiter.record(&"phone");
+        assert_eq!(limiter.retry_after(&"phone"), None);
+        limiter.record(&"phone");
+        limiter.record(&"phone");
+        let wait = limiter.retry_after(&"phone").unwrap();
+        assert!(wait > Duration::from_secs(290) && wait <= Duration::from_secs(300), "{wait:?}");
+        assert!(!limiter.is_allowed(&"phone"));
+    }
+
+    #[test]
+    fn events_expire_with_their_window() {
+        let clock = ManualClock::new();
+        let limiter = RateLimiter::new(1, Duration::from_secs(300)).with_clock(clock.clone());
+        assert!(limiter.check(&"phone"));
+        clock.advance(Duration::from_secs(200));
+        assert!(!limiter.check(&"phone"));
+        assert_eq!(limiter.retry_after(&"phone"), Some(Duration::from_secs(100)));
+        clock.advance(Duration::from_secs(100));
+        assert!(limiter.check(&"phone"));
+    }
+
+    #[test]
+    fn lists_and_clears_keys() {
+        let clock = ManualClock::new();
+        let limiter = RateLimiter::new(2, Duration::from_secs(300)).with_clock(clock.clone());
+        limiter.record(&"phone");
+        limiter.record(&"phone");
+        limiter.record(&"laptop");
+        clock.advance(Duration::from_secs(60));
+        let mut entries = limiter.entries();
+        entries.sort();
+        assert_eq!(entries, [("laptop", 1, None), ("phone", 2, Some(Duration::from_secs(240)))]);
+
+        assert!(limiter.clear(&"phone"));
+        assert!(limiter.is_allowed(&"phone"));
+        assert!(!limiter.clear(&"phone"));
+        assert_eq!(limiter.entries().len(), 1);
+    }
+}
@@ -0,0 +1,113 @@
+//! `phantom reset`: wipe the daemon's state, for a Mac changing hands or a
+//! stolen phone.
+//!
+//! Every device is revoked (each one audited in `auth.log`, which is kept,
+//! before anything is deleted), and pairing and guest tokens, the TLS
+//! certificate and key, the scrollback key, per-device data and session
+//! output logs are removed.
+//! A new certificate is generated the next time the daemon starts, so no
+//! previously paired device can connect or even recognise the host. Run
+//! through the daemon (IPC `reset`), live sessions are destroyed and
+//! connections closed as well, and the daemon exits for launchd to restart
+//! it with its new identity.
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use tracing::info;
+
+use crate::device_store::{sibling, DeviceStore};
+use crate::vault;
+
+/// Files in the data dir a reset removes, with their backups.
+const WIPED_FILES: &[&str] = &[
+    "devices.json",
+    "pairing_tokens.json",
+    "guest_tokens.json",
+    "server.crt",
+    "server.key",
+    "scrollback.key",
+    "successor.json",
+    "macros.json",
+    "bandwidth.json",
+];
+
+/// Directories in the data dir a reset removes with everything in them.
+const WIPED_DIRS: &[&str] = &["logs/sessions"];
+
+/// What a reset removed.
+#[derive(Debug, Serialize)]
+pub struct Wiped {
+    pub devices_revoked: Vec<String>,
+    pub files_removed: Vec<PathBuf>,
+}
+
+/// Wipe `phantom_dir`, and on macOS the scrollback key in the Keychain.
+pub fn reset(phantom_dir: &Path, device_store: &DeviceStore) -> Result<Wiped> {
+    let wiped = wipe(phantom_dir, device_store)?;
+    vault::forget_keychain_key().context("remove scrollback key from the Keychain")?;
+    Ok(wiped)
+}
+
+/// Revoke every device, then remove [`WIPED_FILES`] and [`WIPED_DIRS`] from
+/// `phantom_dir`.
+fn wipe(phantom_dir: &Path, device_store: &DeviceStore) -> Result<Wiped> {
+    let devices_revoked = device_store.revoke_all()?;
+    let mut files_removed = Vec::new();
+    for name in WIPED_FILES {
+        let path = phantom_dir.join(name);
+        for path in [sibling(&path, "bak"), path] {
+            match fs::remove_file(&path) {
+                Ok(()) => files_removed.push(path),
+                Err(e) if e.kind() == ErrorKind::NotFound => {}
+                Err(e) => return Err(e).with_context(|| format!("remove {}", path.display())),
+            }
+        }
+    }
+    for name in WIPED_DIRS {
+        let path = phantom_dir.join(name);
+        match fs::remove_dir_all(&path) {
+            Ok(()) => files_removed.push(path),
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(e).with_context(|| format!("remove {}", path.display())),
+        }
+    }
+    info!("reset: revoked {} device(s), removed {} file(s)", devices_revoked.len(), files_removed.len());
+    Ok(Wiped { devices_revoked, files_removed })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wipe_revokes_devices_and_removes_secrets_but_keeps_the_audit_log() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let store = DeviceStore::new(dir.path()).unwrap();
+        store.add_device("dev-1", "key-1", "Phone", None, None).unwrap();
+        store.add_device("dev-2", "key-2", "Tablet", None, None).unwrap();
+        let token = store.create_pairing_token(None);
+        for name in ["server.crt", "server.key", "scrollback.key", "schedules.json"] {
+            fs::write(dir.path().join(name), "secret").unwrap();
+        }
+        fs::create_dir_all(dir.path().join("logs/sessions")).unwrap();
+        fs::write(dir.path().join("logs/sessions/s1.log"), "secret").unwrap();
+
+        let mut wiped = wipe(dir.path(), &store).unwrap();
+        wiped.devices_revoked.sort();
+        assert_eq!(wiped.devices_revoked, ["dev-1", "dev-2"]);
+        for name in ["devices.json", "devices.json.bak", "server.crt", "server.key", "scrollback.key", "logs/sessions"] {
+            assert!(!dir.path().join(name).exists(), "{name}");
+        }
+        assert!(dir.path().join("schedules.json").exists());
+
+        let store = DeviceStore::new(dir.path()).unwrap();
+        assert!(store.list_devices().is_empty());
+        assert!(store.redeem_pairing_token(&token).is_none());
+        let audit = fs::read_to_string(dir.path().join("auth.log")).unwrap();
+        assert!(audit.contains("*\treset"));
+        assert!(audit.contains("dev-1\trevoke"));
+    }
+}
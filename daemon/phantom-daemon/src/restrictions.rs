@@ -0,0 +1,171 @@
+//! Restrictions for low-trust devices (`phantom device restrict`).
+//!
+//! A restricted device may only start sessions running allowlisted
+//! commands, optionally jailed in a chroot (Linux) or a `sandbox-exec`
+//! profile (macOS). It can't run `exec` commands, use tmux passthrough,
+//! forward its SSH agent or set the host clipboard, and only sees the
+//! sessions it created itself.
+
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+
+use crate::errors::ErrorCode;
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Restrictions {
+    /// Command lines sessions may run, matched exactly; the first is used
+    /// when a client doesn't ask for one. Empty = no sessions at all.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub allowed_commands: Vec<String>,
+    /// Directory sessions are chrooted into (needs root)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub chroot: Option<PathBuf>,
+    /// `sandbox-exec` profile sessions run under
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sandbox_profile: Option<PathBuf>,
+}
+
+impl Restrictions {
+    /// Reject settings that can't be enforced.
+    pub fn validate(&self) -> Result<()> {
+        if self.chroot.is_some() && self.sandbox_profile.is_some() {
+            bail!("use either a chroot or a sandbox profile, not both");
+        }
+        for path in self.chroot.iter().chain(&self.sandbox_profile) {
+            if !path.is_absolute() {
+                bail!("{} must be an absolute path", path.display());
+            }
+        }
+        if let Some(dir) = &self.chroot {
+            if !dir.is_dir() {
+                bail!("chroot directory {} does not exist", dir.display());
+            }
+        }
+        if let Some(profile) = &self.sandbox_profile {
+            if !profile.is_file() {
+                bail!("sandbox profile {} does not exist", profile.display());
+            }
+        }
+        if self.allowed_commands.iter().any(|c| c.trim().is_empty()) {
+            bail!("allowed commands must not be empty");
+        }
+        Ok(())
+    }
+
+    /// The command a session requested as `requested` may run.
+    pub fn session_command(&self, requested: Option<&str>) -> Result<String> {
+        match (requested, self.allowed_commands.first()) {
+            (_, None) => Err(ErrorCode::PermissionDenied.err("no commands are permitted for this device")),
+            (None, Some(default)) => Ok(default.clone()),
+            (Some(command), _) if self.allowed_commands.iter().any(|c| c == command) => Ok(command.to_string()),
+            (Some(command), _) => Err(ErrorCode::PermissionDenied.err(format!("command not permitted for this device: {command}"))),
+        }
+    }
+
+    pub fn is_jailed(&self) -> bool {
+        self.chroot.is_some() || self.sandbox_profile.is_some()
+    }
+
+    /// Program and arguments running `command` (or a login shell) in the
+    /// jail, as `user` when given.
+    pub fn jail_argv(&self, command: Option<&str>, user: Option<&str>) -> Vec<String> {
+        let shell = |sh: &str| -> Vec<String> {
+            match command {
+                Some(command) => vec![sh.to_string(), "-c".to_string(), command.to_string()],
+                None => vec![sh.to_string(), "-l".to_string()],
+            }
+        };
+        if let Some(dir) = &self.chroot {
+            // The host's $SHELL may not exist inside the jail
+            let mut argv = vec!["chroot".to_string()];
+            if let Some(user) = user {
+                argv.push(format!("--userspec={user}"));
+            }
+            argv.push(dir.display().to_string());
+            argv.extend(shell("/bin/sh"));
+            return argv;
+        }
+        let Some(profile) = &self.sandbox_profile else {
+            return shell(&default_shell());
+        };
+        match user {
+            // sandbox-exec doesn't need root, so it runs inside su, under
+            // the user's own shell
+            Some(user) => {
+                let mut inner = format!("exec sandbox-exec -f {} \"$SHELL\"", shell_quote(&profile.display().to_string()));
+                match command {
+                    Some(command) => inner.push_str(&format!(" -c {}", shell_quote(command))),
+                    None => inner.push_str(" -l"),
+                }
+                ["su", "-", user, "-c", &inner].map(str::to_string).to_vec()
+            }
+            None => {
+                let mut argv = vec!["sandbox-exec".to_string(), "-f".to_string(), profile.display().to_string()];
+                argv.extend(shell(&default_shell()));
+                argv
+            }
+        }
+    }
+}
+
+fn default_shell() -> String {
+    std::env::var("SHELL").unwrap_or_else(|_| "/bin/sh".to_string())
+}
+
+/// Quote `s` as a single POSIX shell word.
+fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allowlist_picks_and_checks_commands() {
+        // Nothing allowed means no sessions, not the default shell
+        let none = Restrictions::default();
+        assert_eq!(ErrorCode::of(&none.session_command(None).unwrap_err()), ErrorCode::PermissionDenied);
+        assert!(none.session_command(Some("bash")).is_err());
+
+        let r = Restrictions {
+            allowed_commands: vec!["htop".to_string(), "tail -f /var/log/build.log".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(r.session_command(None).unwrap(), "htop");
+        assert_eq!(r.session_command(Some("tail -f /var/log/build.log")).unwrap(), "tail -f /var/log/build.log");
+        assert!(r.session_command(Some("htop; sh")).is_err());
+    }
+
+    #[test]
+    fn jails_wrap_the_session_command() {
+        let chroot = Restrictions { chroot: Some("/srv/jail".into()), ..Default::default() };
+        assert_eq!(
+            chroot.jail_argv(Some("htop"), Some("alice")),
+            ["chroot", "--userspec=alice", "/srv/jail", "/bin/sh", "-c", "htop"]
+        );
+
+        let sandbox = Restrictions { sandbox_profile: Some("/etc/phantom/low.sb".into()), ..Default::default() };
+        assert_eq!(&sandbox.jail_argv(None, None)[..3], ["sandbox-exec", "-f", "/etc/phantom/low.sb"]);
+        assert_eq!(
+            sandbox.jail_argv(Some("echo 'hi'"), Some("bob")),
+            ["su", "-", "bob", "-c", r#"exec sandbox-exec -f '/etc/phantom/low.sb' "$SHELL" -c 'echo '\''hi'\'''"#]
+        );
+    }
+
+    #[test]
+    fn rejects_unenforceable_settings() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let both = Restrictions {
+            chroot: Some(dir.path().into()),
+            sandbox_profile: Some(dir.path().join("p.sb")),
+            ..Default::default()
+        };
+        assert!(both.validate().is_err());
+        assert!(Restrictions { chroot: Some("jail".into()), ..Default::default() }.validate().is_err());
+        assert!(Restrictions { chroot: Some(dir.path().join("missing")), ..Default::default() }.validate().is_err());
+        Restrictions { chroot: Some(dir.path().into()), ..Default::default() }.validate().unwrap();
+    }
+}
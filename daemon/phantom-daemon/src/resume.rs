@@ -0,0 +1,108 @@
+//! Resume tokens: a reconnect shortcut for a device that just lost its
+//! connection.
+//!
+//! When a phone moves from Wi-Fi to cellular and quinn can't migrate the
+//! connection, the client reconnects from scratch. Every successful device
+//! auth hands out a single-use token valid for [`TTL`]; presenting it in the
+//! next `auth_request` skips the challenge round trip, and the daemon then
+//! lists the sessions the device had attached so it can reattach them with
+//! `takeover`. A token that's expired, spent or for another device falls
+//! back to the challenge. Devices paired with a hardware key get none: the
+//! user touches the key on every connection.
+//!
+//! Reattaching stays with the client, one `takeover` per session, rather
+//! than the daemon restoring every attachment at once: each attachment lives
+//! on a stream the client opens, so the daemon has nothing to restore them
+//! onto. A session whose takeover never comes keeps its stale bridge until
+//! the old connection times out, as without a token.
+
+use base64::Engine;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::clock::{self, SharedClock};
+
+/// How long a resume token stays valid.
+pub const TTL: Duration = Duration::from_secs(300);
+
+pub struct ResumeTokens {
+    /// Token → (device id, issued at)
+    tokens: Mutex<HashMap<String, (String, Instant)>>,
+    clock: SharedClock,
+}
+
+impl Default for ResumeTokens {
+    fn default() -> Self {
+        Self { tokens: Mutex::default(), clock: clock::system() }
+    }
+}
+
+impl ResumeTokens {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Read time from `clock` instead of the system's.
+    pub fn with_clock(self, clock: SharedClock) -> Self {
+        Self { clock, ..self }
+    }
+
+    /// A fresh token for `device_id`, replacing any it still holds.
+    pub fn issue(&self, device_id: &str) -> String {
+        let bytes: [u8; 32] = rand::Rng::gen(&mut rand::thread_rng());
+        let token = base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(bytes);
+        let mut tokens = self.tokens.lock().expect("resume tokens lock");
+        let now = self.clock.now();
+        tokens.retain(|_, (device, at)| device != device_id && now.duration_since(*at) < TTL);
+        tokens.insert(token.clone(), (device_id.to_string(), now));
+        token
+    }
+
+    /// Spend `token` for `device_id`. False if it's unknown, expired or
+    /// another device's (which also spends it).
+    pub fn redeem(&self, token: &str, device_id: &str) -> bool {
+        let Some((device, at)) = self.tokens.lock().expect("resume tokens lock").remove(token) else {
+            return false;
+        };
+        device == device_id && self.clock.now().duration_since(at) < TTL
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tokens_are_single_use_and_bound_to_their_device() {
+        let tokens = ResumeTokens::new();
+        let token = tokens.issue("phone");
+        assert!(!tokens.redeem(&token, "tablet"));
+        // Tried by the wrong device: spent
+        assert!(!tokens.redeem(&token, "phone"));
+
+        let token = tokens.issue("phone");
+        assert!(tokens.redeem(&token, "phone"));
+        assert!(!tokens.redeem(&token, "phone"));
+    }
+
+    #[test]
+    fn reissuing_replaces_the_old_token() {
+        let tokens = ResumeTokens::new();
+        let old = tokens.issue("phone");
+        let new = tokens.issue("phone");
+        let other = tokens.issue("tablet");
+        assert!(!tokens.redeem(&old, "phone"));
+        assert!(tokens.redeem(&new, "phone"));
+        assert!(tokens.redeem(&other, "tablet"));
+    }
+
+    #[test]
+    fn tokens_expire_after_the_ttl() {
+        let clock = crate::clock::ManualClock::new();
+        let tokens = ResumeTokens::new().with_clock(clock.clone());
+        let token = tokens.issue("phone");
+        clock.advance(TTL);
+        assert!(!tokens.redeem(&token, "phone"));
+    }
+}
@@ -0,0 +1,454 @@
+//! Protocol scenarios: scripted exchanges run against a [`TestHarness`]
+//! (feature `testing`).
+//!
+//! A scenario is a JSON file of steps, run in order on one authenticated
+//! connection. Steps send control messages and frames, and expect replies,
+//! frames and pushed events within a time limit:
+//!
+//! ```json
+//! {
+//!   "name": "echo round trip",
+//!   "steps": [
+//!     {"send": {"type": "create_session", "rows": 24, "cols": 80}},
+//!     {"expect": {"type": "session_created"}, "capture": {"sid": "session_id"}},
+//!     {"send_frame": {"type": "data", "text": "echo PING\n"}},
+//!     {"expect_frame": {"type": "data", "contains": "PING"}, "within_ms": 5000},
+//!     {"send": {"type": "list_sessions"}, "stream": "lister"},
+//!     {"expect": {"type": "session_list", "sessions": "*"}, "stream": "lister"}
+//!   ]
+//! }
+//! ```
+//!
+//! - Each step does one of `send`, `expect`, `send_frame`, `expect_frame`,
+//!   `expect_no_frame`, `expect_event` or `sleep_ms`.
+//! - `stream` names the control stream (opened on first use, `main` if not
+//!   given). A stream carries frames once it has received `session_created`
+//!   or `session_attached`, and control messages again after it sent a
+//!   Close frame.
+//! - Expected messages match if every field they give matches: objects
+//!   field by field, arrays element by element, and `"*"` anything present.
+//!   `expect` takes the stream's next message; `expect_event` skips pushed
+//!   events until one matches.
+//! - `capture` names fields (dot-separated paths) of the message just
+//!   received; `${name}` in later strings is replaced with their values.
+//! - Frames are given by type name (`data`, `resize`, `paste`, ...) with
+//!   `text` (payload), or `cols` and `rows` for resizes. Expected Data
+//!   frames match once the output received since the last match contains
+//!   `contains`; other types match on `text`, `contains` or `json`.
+//! - `within_ms` bounds a step's wait (default [`DEFAULT_WITHIN`]); for
+//!   `expect_no_frame`, it's how long no matching frame may arrive.
+//!
+//! Scenarios are plain serde types, so they can be built in code too.
+
+use anyhow::{bail, ensure, Context, Result};
+use phantom_frame::{self as frame, Frame, FrameDecoder, FrameType};
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::path::Path;
+use std::time::Duration;
+use tokio::time::Instant;
+
+use crate::testing::{recv_json, send_json, TestHarness};
+
+/// How long a step waits when it doesn't say.
+pub const DEFAULT_WITHIN: Duration = Duration::from_secs(5);
+
+/// Stream steps use when they don't name one.
+const MAIN_STREAM: &str = "main";
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Scenario {
+    pub name: String,
+    pub steps: Vec<Step>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Step {
+    pub send: Option<Value>,
+    pub expect: Option<Value>,
+    pub send_frame: Option<FrameSpec>,
+    pub expect_frame: Option<FrameSpec>,
+    pub expect_no_frame: Option<FrameSpec>,
+    pub expect_event: Option<Value>,
+    pub sleep_ms: Option<u64>,
+    pub stream: Option<String>,
+    #[serde(default)]
+    pub capture: BTreeMap<String, String>,
+    pub within_ms: Option<u64>,
+}
+
+/// A frame to send, or what an expected one must hold.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FrameSpec {
+    #[serde(rename = "type")]
+    pub frame_type: String,
+    pub text: Option<String>,
+    pub contains: Option<String>,
+    pub json: Option<Value>,
+    pub cols: Option<u16>,
+    pub rows: Option<u16>,
+}
+
+impl Scenario {
+    pub fn from_json(json: &str) -> Result<Self> {
+        serde_json::from_str(json).context("parse scenario")
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let json = std::fs::read_to_string(path).with_context(|| format!("read {}", path.display()))?;
+        Self::from_json(&json).with_context(|| format!("in {}", path.display()))
+    }
+
+    /// Run the steps on a new connection to `harness`. Fails with the first
+    /// step that doesn't go as scripted.
+    pub async fn run(&self, harness: &TestHarness) -> Result<()> {
+        let connection = harness.connect_and_auth().await?;
+        let mut run = Run { connection, streams: HashMap::new(), vars: HashMap::new(), events: VecDeque::new() };
+        for (i, step) in self.steps.iter().enumerate() {
+            run.step(step).await.with_context(|| format!("scenario {:?}, step {}: {}", self.name, i + 1, step))?;
+        }
+        Ok(())
+    }
+}
+
+impl std::fmt::Display for Step {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let stream = self.stream.as_deref().unwrap_or(MAIN_STREAM);
+        match self {
+            Step { send: Some(msg), .. } => write!(f, "send {} on {stream}", msg["type"]),
+            Step { expect: Some(msg), .. } => write!(f, "expect {} on {stream}", msg["type"]),
+            Step { send_frame: Some(spec), .. } => write!(f, "send {} frame on {stream}", spec.frame_type),
+            Step { expect_frame: Some(spec), .. } => write!(f, "expect {} frame on {stream}", spec.frame_type),
+            Step { expect_no_frame: Some(spec), .. } => write!(f, "expect no {} frame on {stream}", spec.frame_type),
+            Step { expect_event: Some(event), .. } => write!(f, "expect event {}", event["type"]),
+            Step { sleep_ms: Some(ms), .. } => write!(f, "sleep {ms}ms"),
+            _ => write!(f, "empty step"),
+        }
+    }
+}
+
+/// A control stream and what has been read off it.
+struct Stream {
+    send: quinn::SendStream,
+    recv: quinn::RecvStream,
+    /// Carrying frames (bridged) rather than control messages
+    framed: bool,
+    decoder: FrameDecoder,
+    frames: VecDeque<Frame>,
+    /// Data received since the last expected Data frame matched
+    output: Vec<u8>,
+    sequence: u64,
+}
+
+struct Run {
+    connection: quinn::Connection,
+    streams: HashMap<String, Stream>,
+    vars: HashMap<String, Value>,
+    /// Events received that no step has matched yet
+    events: VecDeque<Value>,
+}
+
+impl Run {
+    async fn step(&mut self, step: &Step) -> Result<()> {
+        let actions = [
+            step.send.is_some(),
+            step.expect.is_some(),
+            step.send_frame.is_some(),
+            step.expect_frame.is_some(),
+            step.expect_no_frame.is_some(),
+            step.expect_event.is_some(),
+            step.sleep_ms.is_some(),
+        ];
+        ensure!(actions.iter().filter(|a| **a).count() == 1, "a step does exactly one thing");
+        let within = step.within_ms.map_or(DEFAULT_WITHIN, Duration::from_millis);
+        let name = step.stream.as_deref().unwrap_or(MAIN_STREAM);
+
+        if let Some(ms) = step.sleep_ms {
+            tokio::time::sleep(Duration::from_millis(ms)).await;
+            return Ok(());
+        }
+        if let Some(expected) = &step.expect_event {
+            let expected = self.substitute(expected);
+            let event = tokio::time::timeout(within, self.next_event(&expected))
+                .await
+                .context("timed out")??;
+            return self.capture(&step.capture, &event);
+        }
+
+        let stream = open_stream(&self.connection, &mut self.streams, name).await?;
+        if let Some(msg) = &step.send {
+            let msg = substitute(msg, &self.vars);
+            ensure!(!stream.framed, "stream {name} is bridged: send frames");
+            return send_json(&mut stream.send, &msg).await;
+        }
+        if let Some(expected) = &step.expect {
+            let expected = substitute(expected, &self.vars);
+            ensure!(!stream.framed, "stream {name} is bridged: expect frames");
+            let msg = tokio::time::timeout(within, recv_json(&mut stream.recv)).await.context("timed out")??;
+            ensure!(matches(&expected, &msg), "got {msg}");
+            if matches!(msg["type"].as_str(), Some("session_created" | "session_attached")) {
+                stream.framed = true;
+            }
+            return self.capture(&step.capture, &msg);
+        }
+        if let Some(spec) = &step.send_frame {
+            let frame = spec.to_frame(&self.vars, stream.sequence + 1)?;
+            ensure!(stream.framed, "stream {name} isn't bridged yet");
+            stream.sequence += 1;
+            stream.send.write_all(&frame::encode(&frame, false)?).await?;
+            if frame.frame_type == FrameType::Close {
+                stream.framed = false;
+            }
+            return Ok(());
+        }
+        if let Some(spec) = &step.expect_frame {
+            let spec = spec.substituted(&self.vars);
+            let deadline = Instant::now() + within;
+            loop {
+                let frame = stream.next_frame(deadline).await?;
+                let Some(frame) = frame else {
+                    bail!("timed out; output so far: {:?}", String::from_utf8_lossy(&stream.output));
+                };
+                if spec.matches(&frame, &mut stream.output)? {
+                    return Ok(());
+                }
+            }
+        }
+        if let Some(spec) = &step.expect_no_frame {
+            let spec = spec.substituted(&self.vars);
+            let deadline = Instant::now() + within;
+            while let Some(frame) = stream.next_frame(deadline).await? {
+                ensure!(!spec.matches(&frame, &mut stream.output)?, "got {:?}", frame);
+            }
+            return Ok(());
+        }
+        unreachable!("checked above")
+    }
+
+    /// The first event (pushed on a unidirectional stream) matching `expected`.
+    async fn next_event(&mut self, expected: &Value) -> Result<Value> {
+        if let Some(i) = self.events.iter().position(|event| matches(expected, event)) {
+            return Ok(self.events.remove(i).expect("found"));
+        }
+        loop {
+            let mut recv = self.connection.accept_uni().await.context("accept event stream")?;
+            let mut len = [0u8; 4];
+            recv.read_exact(&mut len).await?;
+            let mut json = vec![0u8; u32::from_be_bytes(len) as usize];
+            recv.read_exact(&mut json).await?;
+            let event: Value = serde_json::from_slice(&json)?;
+            if matches(expected, &event) {
+                return Ok(event);
+            }
+            self.events.push_back(event);
+        }
+    }
+
+    fn capture(&mut self, captures: &BTreeMap<String, String>, msg: &Value) -> Result<()> {
+        for (var, path) in captures {
+            let value = path.split('.').try_fold(msg, |value, key| match key.parse::<usize>() {
+                Ok(i) => value.get(i),
+                Err(_) => value.get(key),
+            });
+            let value = value.filter(|v| !v.is_null()).with_context(|| format!("no {path} to capture in {msg}"))?;
+            self.vars.insert(var.clone(), value.clone());
+        }
+        Ok(())
+    }
+
+    fn substitute(&self, value: &Value) -> Value {
+        substitute(value, &self.vars)
+    }
+}
+
+/// Stream `name`, opened on first use.
+async fn open_stream<'a>(
+    connection: &quinn::Connection,
+    streams: &'a mut HashMap<String, Stream>,
+    name: &str,
+) -> Result<&'a mut Stream> {
+    if !streams.contains_key(name) {
+        let (send, recv) = connection.open_bi().await.context("open stream")?;
+        let stream = Stream {
+            send,
+            recv,
+            framed: false,
+            decoder: FrameDecoder::new(),
+            frames: VecDeque::new(),
+            output: Vec::new(),
+            sequence: 0,
+        };
+        streams.insert(name.to_string(), stream);
+    }
+    Ok(streams.get_mut(name).expect("opened"))
+}
+
+impl Stream {
+    /// The next frame, or None once `deadline` passes.
+    async fn next_frame(&mut self, deadline: Instant) -> Result<Option<Frame>> {
+        ensure!(self.framed, "stream isn't bridged");
+        let mut buf = [0u8; 16 * 1024];
+        loop {
+            if let Some(frame) = self.frames.pop_front() {
+                return Ok(Some(frame));
+            }
+            let read = match tokio::time::timeout_at(deadline, self.recv.read(&mut buf)).await {
+                Ok(read) => read?,
+                Err(_) => return Ok(None),
+            };
+            let Some(n) = read else {
+                bail!("stream finished");
+            };
+            self.decoder.feed(&buf[..n]);
+            while let Some(frame) = self.decoder.decode_next()? {
+                self.frames.push_back(frame);
+            }
+        }
+    }
+}
+
+impl FrameSpec {
+    fn frame_type(&self) -> Result<FrameType> {
+        FrameType::CORE
+            .into_iter()
+            .find(|t| t.name() == self.frame_type)
+            .with_context(|| format!("unknown frame type {:?}", self.frame_type))
+    }
+
+    fn to_frame(&self, vars: &HashMap<String, Value>, sequence: u64) -> Result<Frame> {
+        let spec = self.substituted(vars);
+        let frame_type = spec.frame_type()?;
+        let payload = match (frame_type, spec.cols, spec.rows) {
+            (FrameType::Resize, Some(cols), Some(rows)) => frame::resize_payload(cols, rows).to_vec(),
+            (FrameType::Resize, ..) => bail!("resize frames need cols and rows"),
+            _ => spec.text.unwrap_or_default().into_bytes(),
+        };
+        Ok(Frame { frame_type, sequence, payload })
+    }
+
+    fn substituted(&self, vars: &HashMap<String, Value>) -> Self {
+        let text = |s: &Option<String>| s.as_deref().map(|s| substitute_str(s, vars));
+        Self {
+            frame_type: self.frame_type.clone(),
+            text: text(&self.text),
+            contains: text(&self.contains),
+            json: self.json.as_ref().map(|json| substitute(json, vars)),
+            cols: self.cols,
+            rows: self.rows,
+        }
+    }
+
+    /// Whether `frame` is the one expected; Data output accumulates in
+    /// `output` until it contains what's expected.
+    fn matches(&self, frame: &Frame, output: &mut Vec<u8>) -> Result<bool> {
+        if frame.frame_type != self.frame_type()? {
+            return Ok(false);
+        }
+        if frame.frame_type == FrameType::Data && self.contains.is_some() {
+            output.extend_from_slice(&frame.payload);
+        }
+        let payload = match frame.frame_type {
+            FrameType::Data => String::from_utf8_lossy(output).into_owned(),
+            _ => String::from_utf8_lossy(&frame.payload).into_owned(),
+        };
+        let matched = self.text.as_ref().is_none_or(|text| String::from_utf8_lossy(&frame.payload) == *text)
+            && self.contains.as_ref().is_none_or(|part| payload.contains(part.as_str()))
+            && self.json.as_ref().is_none_or(|json| {
+                serde_json::from_slice(&frame.payload).is_ok_and(|actual: Value| matches(json, &actual))
+            })
+            && (self.cols.is_none() && self.rows.is_none()
+                || frame.parse_resize() == Some((self.cols.unwrap_or_default(), self.rows.unwrap_or_default())));
+        if matched && frame.frame_type == FrameType::Data {
+            output.clear();
+        }
+        Ok(matched)
+    }
+}
+
+/// Whether `actual` has everything `expected` gives.
+pub fn matches(expected: &Value, actual: &Value) -> bool {
+    match (expected, actual) {
+        (Value::String(s), actual) if s == "*" => !actual.is_null(),
+        (Value::Object(expected), Value::Object(actual)) => expected
+            .iter()
+            .all(|(key, value)| matches(value, actual.get(key).unwrap_or(&Value::Null))),
+        (Value::Array(expected), Value::Array(actual)) => {
+            expected.len() == actual.len() && expected.iter().zip(actual).all(|(e, a)| matches(e, a))
+        }
+        (expected, actual) => expected == actual,
+    }
+}
+
+/// `value` with `${name}` in its strings replaced by captured values. A
+/// string that is only a reference takes the value as is (a number stays a
+/// number).
+fn substitute(value: &Value, vars: &HashMap<String, Value>) -> Value {
+    match value {
+        Value::String(s) => {
+            let whole = s.strip_prefix("${").and_then(|rest| rest.strip_suffix('}'));
+            match whole.and_then(|name| vars.get(name)) {
+                Some(value) => value.clone(),
+                None => Value::String(substitute_str(s, vars)),
+            }
+        }
+        Value::Array(items) => Value::Array(items.iter().map(|item| substitute(item, vars)).collect()),
+        Value::Object(fields) => {
+            Value::Object(fields.iter().map(|(key, value)| (key.clone(), substitute(value, vars))).collect())
+        }
+        other => other.clone(),
+    }
+}
+
+fn substitute_str(s: &str, vars: &HashMap<String, Value>) -> String {
+    let mut out = s.to_string();
+    for (name, value) in vars {
+        let text = match value {
+            Value::String(s) => s.clone(),
+            other => other.to_string(),
+        };
+        out = out.replace(&format!("${{{name}}}"), &text);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn expected_fields_match_as_a_subset() {
+        let msg = json!({"type": "session_created", "session_id": "ab12", "terminal": {"term": "xterm"}, "n": [1, 2]});
+        assert!(matches(&json!({"type": "session_created"}), &msg));
+        assert!(matches(&json!({"session_id": "*", "terminal": {"term": "xterm"}, "n": [1, 2]}), &msg));
+        assert!(!matches(&json!({"name": "*"}), &msg));
+        assert!(!matches(&json!({"n": [1]}), &msg));
+        assert!(!matches(&json!({"type": "session_attached"}), &msg));
+    }
+
+    #[test]
+    fn captured_values_are_substituted() {
+        let vars = HashMap::from([("sid".to_string(), json!("ab12")), ("rows".to_string(), json!(30))]);
+        let msg = json!({"session_id": "${sid}", "rows": "${rows}", "note": "session ${sid}, ${rows} rows"});
+        assert_eq!(substitute(&msg, &vars), json!({"session_id": "ab12", "rows": 30, "note": "session ab12, 30 rows"}));
+    }
+
+    #[test]
+    fn scenarios_parse_and_describe_their_steps() {
+        let scenario = Scenario::from_json(
+            r#"{"name": "resize", "steps": [
+                {"send_frame": {"type": "resize", "cols": 100, "rows": 30}, "stream": "tab"},
+                {"sleep_ms": 10}
+            ]}"#,
+        )
+        .unwrap();
+        assert_eq!(scenario.steps[0].to_string(), "send resize frame on tab");
+        let frame = scenario.steps[0].send_frame.as_ref().unwrap().to_frame(&HashMap::new(), 1).unwrap();
+        assert_eq!(frame.parse_resize(), Some((100, 30)));
+        assert!(Scenario::from_json(r#"{"name": "typo", "steps": [{"sned": {}}]}"#).is_err());
+    }
+}
@@ -0,0 +1,555 @@
+//! Scheduled commands: run a command at crontab times and keep what it
+//! printed for the phone to read later ("run the nightly build and let me
+//! check it from bed").
+//!
+//! Jobs come from `[[schedules]]` in config.toml or are added over IPC
+//! (`add_schedule`, kept in `schedules.json`). A job runs like `phantom
+//! exec`, its output kept with the run, or with `session = true` in a new
+//! session a client can attach to. The last [`MAX_RUNS`] runs are kept in
+//! memory.
+
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Datelike, Duration as ChronoDuration, Local, NaiveDateTime, TimeZone, Timelike};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::Notify;
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+use crate::device_store::{read_with_backup, write_atomic};
+use crate::errors::ErrorCode;
+use crate::exec::{self, Exec};
+use crate::session::SessionManager;
+use crate::terminal::Launch;
+
+/// Runs kept, oldest dropped first.
+pub const MAX_RUNS: usize = 20;
+/// Output kept per run; the rest is dropped.
+pub const RUN_OUTPUT_CAP: usize = 256 * 1024;
+/// Timeout of a job that doesn't set one.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(60 * 60);
+/// Upper bound for a job's timeout.
+pub const MAX_TIMEOUT: Duration = Duration::from_secs(24 * 60 * 60);
+/// Longest the scheduler sleeps before checking the clock again, so jobs
+/// still run (late) after the host slept through their time.
+const MAX_WAIT: Duration = Duration::from_secs(60);
+/// Longest job name, in characters.
+const MAX_NAME_CHARS: usize = 64;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ScheduledJob {
+    pub name: String,
+    /// Five-field crontab expression (or `@daily` etc.), in local time
+    pub cron: String,
+    /// Command line, run with the user's shell
+    pub command: String,
+    /// Run in a new session instead of as an exec
+    #[serde(default)]
+    pub session: bool,
+    /// Kill an exec run after this many seconds (default 1 hour, max 24)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout_secs: Option<u64>,
+}
+
+impl ScheduledJob {
+    /// Check the job can run, returning its schedule.
+    pub fn validate(&self) -> Result<CronSchedule> {
+        let name = self.name.trim();
+        if name.is_empty() || name.chars().count() > MAX_NAME_CHARS {
+            return Err(ErrorCode::BadRequest.err(format!("schedule name must be 1-{MAX_NAME_CHARS} characters")));
+        }
+        if self.command.trim().is_empty() {
+            return Err(ErrorCode::BadRequest.err("schedule command must not be empty"));
+        }
+        CronSchedule::parse(&self.cron).map_err(|e| ErrorCode::BadRequest.err(format!("bad cron expression: {e:#}")))
+    }
+
+    fn timeout(&self) -> Duration {
+        self.timeout_secs
+            .filter(|&secs| secs > 0)
+            .map(Duration::from_secs)
+            .unwrap_or(DEFAULT_TIMEOUT)
+            .min(MAX_TIMEOUT)
+    }
+}
+
+/// A job as listed: where it's defined and when it runs next.
+#[derive(Debug, Clone, Serialize)]
+pub struct JobInfo {
+    #[serde(flatten)]
+    pub job: ScheduledJob,
+    /// Defined in config.toml (and so not removable over IPC)
+    pub configured: bool,
+    pub next_run_at: Option<DateTime<Local>>,
+}
+
+/// One run of a job. Its output is served separately ([`Scheduler::run`]).
+#[derive(Debug, Clone, Serialize)]
+pub struct ScheduledRun {
+    pub id: u64,
+    pub job: String,
+    pub command: String,
+    pub started_at: DateTime<Local>,
+    pub finished_at: Option<DateTime<Local>>,
+    /// `running`, `failed`, `session`, or how the exec ended (`exit:<code>`,
+    /// `timeout`, `unknown`)
+    pub status: String,
+    pub exit_code: Option<u32>,
+    /// Session a `session` job runs in
+    pub session_id: Option<String>,
+    /// Why the run couldn't start
+    pub error: Option<String>,
+    /// Output beyond [`RUN_OUTPUT_CAP`] was dropped
+    pub truncated: bool,
+}
+
+struct RunRecord {
+    run: ScheduledRun,
+    output: Vec<u8>,
+}
+
+#[derive(Default)]
+pub struct Scheduler {
+    /// From config.toml
+    configured: Vec<ScheduledJob>,
+    /// Added over IPC, kept in `path`
+    added: Mutex<Vec<ScheduledJob>>,
+    path: Option<PathBuf>,
+    runs: Mutex<VecDeque<RunRecord>>,
+    next_run_id: AtomicU64,
+    /// Wakes the scheduler when jobs change
+    changed: Notify,
+}
+
+impl Scheduler {
+    /// Scheduler for the jobs in config.toml, skipping invalid ones.
+    pub fn new(configured: Vec<ScheduledJob>) -> Self {
+        let configured = configured
+            .into_iter()
+            .filter(|job| match job.validate() {
+                Ok(_) => true,
+                Err(e) => {
+                    warn!("ignoring schedule {:?} in config.toml: {e:#}", job.name);
+                    false
+                }
+            })
+            .collect();
+        Self { configured, ..Self::default() }
+    }
+
+    /// Keep jobs added over IPC at `path`, loading those saved there.
+    pub fn with_store(self, path: PathBuf) -> Self {
+        let added: Vec<ScheduledJob> = read_with_backup(&path, |s| serde_json::from_str(s).context("parse schedules.json"))
+            .unwrap_or_else(|e| {
+                warn!("ignoring unreadable {}: {e:#}", path.display());
+                None
+            })
+            .unwrap_or_default();
+        Self { added: Mutex::new(added), path: Some(path), ..self }
+    }
+
+    pub fn jobs(&self) -> Vec<JobInfo> {
+        let now = Local::now();
+        let added = self.added.lock().expect("schedules lock");
+        let configured = self.configured.iter().map(|job| (job, true));
+        configured
+            .chain(added.iter().map(|job| (job, false)))
+            .map(|(job, configured)| JobInfo {
+                job: job.clone(),
+                configured,
+                next_run_at: job.validate().ok().and_then(|cron| cron.next_after(now)),
+            })
+            .collect()
+    }
+
+    pub fn add(&self, job: ScheduledJob) -> Result<JobInfo> {
+        let job = ScheduledJob { name: job.name.trim().to_string(), ..job };
+        let cron = job.validate()?;
+        let mut added = self.added.lock().expect("schedules lock");
+        if self.configured.iter().chain(added.iter()).any(|j| j.name == job.name) {
+            return Err(ErrorCode::BadRequest.err(format!("a schedule named {} exists", job.name)));
+        }
+        added.push(job.clone());
+        self.save(&added)?;
+        self.changed.notify_one();
+        info!("added schedule {} ({})", job.name, job.cron);
+        Ok(JobInfo { next_run_at: cron.next_after(Local::now()), job, configured: false })
+    }
+
+    pub fn remove(&self, name: &str) -> Result<()> {
+        if self.configured.iter().any(|j| j.name == name) {
+            return Err(ErrorCode::PermissionDenied.err(format!("schedule {name} is defined in config.toml")));
+        }
+        let mut added = self.added.lock().expect("schedules lock");
+        let before = added.len();
+        added.retain(|j| j.name != name);
+        if added.len() == before {
+            return Err(ErrorCode::NotFound.err(format!("no schedule named {name}")));
+        }
+        self.save(&added)?;
+        self.changed.notify_one();
+        info!("removed schedule {name}");
+        Ok(())
+    }
+
+    /// Recent runs, newest first.
+    pub fn runs(&self) -> Vec<ScheduledRun> {
+        let runs = self.runs.lock().expect("runs lock");
+        runs.iter().rev().map(|r| r.run.clone()).collect()
+    }
+
+    /// Run `id` and its output (lossily decoded).
+    pub fn run(&self, id: u64) -> Option<(ScheduledRun, String)> {
+        let runs = self.runs.lock().expect("runs lock");
+        let record = runs.iter().find(|r| r.run.id == id)?;
+        Some((record.run.clone(), String::from_utf8_lossy(&record.output).into_owned()))
+    }
+
+    /// Jobs due after `since`, up to `now`.
+    fn due(&self, since: DateTime<Local>, now: DateTime<Local>) -> Vec<ScheduledJob> {
+        let added = self.added.lock().expect("schedules lock");
+        self.configured
+            .iter()
+            .chain(added.iter())
+            .filter(|job| job.validate().ok().and_then(|cron| cron.next_after(since)).is_some_and(|at| at <= now))
+            .cloned()
+            .collect()
+    }
+
+    /// When the next job is due.
+    fn next_due(&self, now: DateTime<Local>) -> Option<DateTime<Local>> {
+        let added = self.added.lock().expect("schedules lock");
+        self.configured
+            .iter()
+            .chain(added.iter())
+            .filter_map(|job| job.validate().ok()?.next_after(now))
+            .min()
+    }
+
+    fn start_run(&self, job: &ScheduledJob) -> u64 {
+        let id = self.next_run_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let run = ScheduledRun {
+            id,
+            job: job.name.clone(),
+            command: job.command.clone(),
+            started_at: Local::now(),
+            finished_at: None,
+            status: "running".to_string(),
+            exit_code: None,
+            session_id: None,
+            error: None,
+            truncated: false,
+        };
+        let mut runs = self.runs.lock().expect("runs lock");
+        if runs.len() == MAX_RUNS {
+            runs.pop_front();
+        }
+        runs.push_back(RunRecord { run, output: Vec::new() });
+        id
+    }
+
+    fn finish_run(&self, id: u64, finish: impl FnOnce(&mut RunRecord)) {
+        let mut runs = self.runs.lock().expect("runs lock");
+        if let Some(record) = runs.iter_mut().find(|r| r.run.id == id) {
+            finish(record);
+            record.run.finished_at = Some(Local::now());
+        }
+    }
+
+    fn save(&self, added: &[ScheduledJob]) -> Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let json = serde_json::to_string_pretty(added)?;
+        write_atomic(path, json.as_bytes()).context("write schedules.json")
+    }
+}
+
+/// Run jobs as they come due, until cancelled.
+pub async fn run(session_manager: Arc<SessionManager>, cancel: CancellationToken) {
+    let mut checked = Local::now();
+    loop {
+        let now = Local::now();
+        for job in session_manager.scheduler().due(checked, now) {
+            let sm = session_manager.clone();
+            session_manager.tasks().spawn(async move {
+                run_job(&sm, &job).await;
+            });
+        }
+        checked = now;
+
+        let scheduler = session_manager.scheduler();
+        let wait = scheduler
+            .next_due(now)
+            .map_or(MAX_WAIT, |at| (at - now).to_std().unwrap_or_default().min(MAX_WAIT));
+        tokio::select! {
+            _ = cancel.cancelled() => return,
+            _ = scheduler.changed.notified() => {}
+            _ = tokio::time::sleep(wait) => {}
+        }
+    }
+}
+
+/// Run `job` now, returning the run's id once it has finished (or, for a
+/// session job, started).
+pub async fn run_job(session_manager: &SessionManager, job: &ScheduledJob) -> u64 {
+    let scheduler = session_manager.scheduler();
+    let id = scheduler.start_run(job);
+    info!("running schedule {} (run {id})", job.name);
+
+    if job.session {
+        let launch = Launch { command: Some(&job.command), ..Default::default() };
+        let created = session_manager.create_session(24, 80, None, launch);
+        scheduler.finish_run(id, |record| match created {
+            Ok(session_id) => {
+                record.run.status = "session".to_string();
+                record.run.session_id = Some(session_id);
+            }
+            Err(e) => fail(record, e),
+        });
+        return id;
+    }
+
+    let started = session_manager.reserve(exec::EXEC_MEMORY_BYTES).and_then(|memory| {
+        let backend = session_manager.spawn_command(&job.command)?;
+        Ok((Exec::start(backend, job.timeout(), session_manager.tasks().clone())?, memory))
+    });
+    let (exec, _memory) = match started {
+        Ok(started) => started,
+        Err(e) => {
+            scheduler.finish_run(id, |record| fail(record, e));
+            return id;
+        }
+    };
+    let (output, truncated, status) = exec.collect(RUN_OUTPUT_CAP).await;
+    info!("schedule {} (run {id}) finished: {}", job.name, status.reason());
+    scheduler.finish_run(id, |record| {
+        record.output = output;
+        record.run.truncated = truncated;
+        record.run.status = status.reason();
+        record.run.exit_code = status.exit_code();
+    });
+    id
+}
+
+fn fail(record: &mut RunRecord, e: anyhow::Error) {
+    warn!("schedule {} (run {}) failed to start: {e:#}", record.run.job, record.run.id);
+    record.run.status = "failed".to_string();
+    record.run.error = Some(format!("{e:#}"));
+}
+
+/// A crontab schedule: minute, hour, day of month, month and day of week,
+/// each `*`, a value, a range (`1-5`), a step (`*/15`, `0-30/10`) or a
+/// comma-separated list of those. Day of week 0 and 7 are Sunday; as in
+/// cron, when both days are restricted a time matching either runs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CronSchedule {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    any_day: bool,
+    any_weekday: bool,
+}
+
+impl CronSchedule {
+    pub fn parse(expr: &str) -> Result<Self> {
+        let expr = match expr.trim() {
+            "@hourly" => "0 * * * *",
+            "@daily" | "@midnight" => "0 0 * * *",
+            "@weekly" => "0 0 * * 0",
+            "@monthly" => "0 0 1 * *",
+            "@yearly" | "@annually" => "0 0 1 1 *",
+            expr => expr,
+        };
+        let fields: Vec<&str> = expr.split_whitespace().collect();
+        let [minute, hour, day, month, weekday] = fields[..] else {
+            bail!("expected 5 fields (minute hour day month weekday), got {}", fields.len());
+        };
+        let mut weekdays = parse_field(weekday, 0, 7).context("weekday")?;
+        // 7 is Sunday too
+        if weekdays & (1 << 7) != 0 {
+            weekdays = (weekdays & !(1 << 7)) | 1;
+        }
+        Ok(Self {
+            minutes: parse_field(minute, 0, 59).context("minute")?,
+            hours: parse_field(hour, 0, 23).context("hour")?,
+            days: parse_field(day, 1, 31).context("day")?,
+            months: parse_field(month, 1, 12).context("month")?,
+            weekdays,
+            any_day: day == "*",
+            any_weekday: weekday == "*",
+        })
+    }
+
+    /// First time after `after` (at a whole minute) this schedule runs.
+    /// None if it never does (e.g. February 30th).
+    pub fn next_after(&self, after: DateTime<Local>) -> Option<DateTime<Local>> {
+        let start = after.naive_local().with_second(0)?.with_nanosecond(0)? + ChronoDuration::minutes(1);
+        let mut t = start;
+        // Every day of five years, plus hours and minutes of the last one
+        for _ in 0..(5 * 366 + 24 + 60) * 2 {
+            if !bit(self.months, t.month()) {
+                let (year, month) = if t.month() == 12 { (t.year() + 1, 1) } else { (t.year(), t.month() + 1) };
+                t = chrono::NaiveDate::from_ymd_opt(year, month, 1)?.and_hms_opt(0, 0, 0)?;
+            } else if !self.day_matches(&t) {
+                t = t.date().succ_opt()?.and_hms_opt(0, 0, 0)?;
+            } else if !bit(self.hours, t.hour()) {
+                t = t.with_minute(0)? + ChronoDuration::hours(1);
+            } else if !bit(self.minutes, t.minute()) {
+                t += ChronoDuration::minutes(1);
+            } else {
+                // Times skipped by a DST change don't exist; repeated ones run once
+                match Local.from_local_datetime(&t).earliest() {
+                    Some(at) => return Some(at),
+                    None => t += ChronoDuration::minutes(1),
+                }
+            }
+        }
+        None
+    }
+
+    fn day_matches(&self, t: &NaiveDateTime) -> bool {
+        let day = bit(self.days, t.day());
+        let weekday = bit(self.weekdays, t.weekday().num_days_from_sunday());
+        match (self.any_day, self.any_weekday) {
+            (true, true) => true,
+            (true, false) => weekday,
+            (false, true) => day,
+            (false, false) => day || weekday,
+        }
+    }
+}
+
+fn bit(set: u64, n: u32) -> bool {
+    set & (1 << n) != 0
+}
+
+/// Values one crontab field allows, as a bit set.
+fn parse_field(field: &str, min: u32, max: u32) -> Result<u64> {
+    let number = |s: &str| -> Result<u32> {
+        let n: u32 = s.parse().with_context(|| format!("{s:?} is not a number"))?;
+        if !(min..=max).contains(&n) {
+            bail!("{n} is out of range {min}-{max}");
+        }
+        Ok(n)
+    };
+    let mut set = 0u64;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse::<u32>().ok().filter(|&s| s > 0).context("bad step")?),
+            None => (part, 1),
+        };
+        let (first, last) = match range {
+            "*" => (min, max),
+            range => match range.split_once('-') {
+                Some((first, last)) => (number(first)?, number(last)?),
+                // `5/15` means from 5 on
+                None if step > 1 => (number(range)?, max),
+                None => (number(range)?, number(range)?),
+            },
+        };
+        if first > last {
+            bail!("range {first}-{last} is backwards");
+        }
+        for n in (first..=last).step_by(step as usize) {
+            set |= 1 << n;
+        }
+    }
+    Ok(set)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::terminal::ScriptedTerminal;
+
+    fn local(s: &str) -> DateTime<Local> {
+        let naive = NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M").unwrap();
+        Local.from_local_datetime(&naive).earliest().unwrap()
+    }
+
+    fn next(expr: &str, after: &str) -> String {
+        let cron = CronSchedule::parse(expr).unwrap();
+        cron.next_after(local(after)).unwrap().format("%Y-%m-%d %H:%M").to_string()
+    }
+
+    #[test]
+    fn finds_the_next_run_time() {
+        assert_eq!(next("30 2 * * *", "2026-10-16 14:00"), "2026-10-17 02:30");
+        assert_eq!(next("*/15 * * * *", "2026-10-16 14:00"), "2026-10-16 14:15");
+        assert_eq!(next("@hourly", "2026-10-16 14:59"), "2026-10-16 15:00");
+        // Weekdays only: Friday evening → Monday
+        assert_eq!(next("0 9 * * 1-5", "2026-10-16 18:00"), "2026-10-19 09:00");
+        // Sunday as 7
+        assert_eq!(next("0 9 * * 7", "2026-10-16 18:00"), "2026-10-18 09:00");
+        // Either day field may match when both are set: the 1st, or a Friday
+        assert_eq!(next("0 0 1 * 5", "2026-10-16 18:00"), "2026-10-23 00:00");
+        assert_eq!(next("0 0 1 1 *", "2026-10-16 18:00"), "2027-01-01 00:00");
+        assert!(CronSchedule::parse("0 0 30 2 *").unwrap().next_after(local("2026-10-16 18:00")).is_none());
+    }
+
+    #[test]
+    fn rejects_bad_expressions() {
+        for expr in ["", "* * * *", "60 * * * *", "* 24 * * *", "5-1 * * * *", "*/0 * * * *", "x * * * *", "* * 0 * *"] {
+            assert!(CronSchedule::parse(expr).is_err(), "{expr:?}");
+        }
+    }
+
+    fn job(name: &str, session: bool) -> ScheduledJob {
+        ScheduledJob {
+            name: name.to_string(),
+            cron: "0 3 * * *".to_string(),
+            command: "make nightly".to_string(),
+            session,
+            timeout_secs: None,
+        }
+    }
+
+    #[test]
+    fn jobs_added_over_ipc_are_kept() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("schedules.json");
+        let scheduler = Scheduler::new(vec![job("backup", false)]).with_store(path.clone());
+        scheduler.add(job("nightly", false)).unwrap();
+        assert_eq!(ErrorCode::of(&scheduler.add(job("backup", false)).unwrap_err()), ErrorCode::BadRequest);
+        let bad = ScheduledJob { cron: "nope".to_string(), ..job("bad", false) };
+        assert_eq!(ErrorCode::of(&scheduler.add(bad).unwrap_err()), ErrorCode::BadRequest);
+
+        let scheduler = Scheduler::new(vec![job("backup", false)]).with_store(path);
+        let jobs = scheduler.jobs();
+        assert_eq!(jobs.iter().map(|j| (j.job.name.as_str(), j.configured)).collect::<Vec<_>>(), [("backup", true), ("nightly", false)]);
+        assert!(jobs[0].next_run_at.is_some());
+        assert_eq!(ErrorCode::of(&scheduler.remove("backup").unwrap_err()), ErrorCode::PermissionDenied);
+        scheduler.remove("nightly").unwrap();
+        assert_eq!(ErrorCode::of(&scheduler.remove("nightly").unwrap_err()), ErrorCode::NotFound);
+
+        let due = scheduler.due(local("2026-10-16 02:59"), local("2026-10-16 03:00"));
+        assert_eq!(due.len(), 1);
+        assert!(scheduler.due(local("2026-10-16 03:00"), local("2026-10-16 03:01")).is_empty());
+    }
+
+    #[tokio::test]
+    async fn runs_keep_their_output() {
+        let sm = SessionManager::new().with_spawner(ScriptedTerminal::spawner(false, |h| {
+            h.emit(b"build ok\n");
+            h.exit(0);
+        }));
+        let id = run_job(&sm, &job("nightly", false)).await;
+        let (run, output) = sm.scheduler().run(id).unwrap();
+        assert_eq!((run.status.as_str(), run.exit_code), ("exit:0", Some(0)));
+        assert!(run.finished_at.is_some());
+        assert_eq!(output, "build ok\n");
+
+        let id = run_job(&sm, &job("nightly-session", true)).await;
+        let (run, _) = sm.scheduler().run(id).unwrap();
+        assert_eq!(run.status, "session");
+        assert!(sm.get_session(run.session_id.as_deref().unwrap()).is_some());
+        assert_eq!(sm.scheduler().runs().iter().map(|r| r.id).collect::<Vec<_>>(), [2, 1]);
+    }
+}
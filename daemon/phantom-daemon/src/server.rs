@@ -1,80 +1,218 @@
 use anyhow::{Context, Result};
 use std::collections::HashMap;
 use std::net::IpAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
-use std::time::{Duration, Instant};
+use std::time::Duration;
+use tokio::sync::Semaphore;
 use tokio::time::timeout;
-use tracing::{error, info, warn};
-
-use crate::auth::Authenticator;
+use tokio_util::task::TaskTracker;
+use tracing::{error, info, info_span, warn, Instrument};
+
+use crate::auth::{Authenticated, Authenticator, Peer};
+use crate::bridge::StreamDeadlines;
+use crate::clock::SharedClock;
+use crate::config::{AlpnPolicy, RateLimitConfig};
+use crate::connections::{self, ConnectionHandle, Connections};
+use crate::errors::ErrorCode;
+use crate::hello::{self, Wire};
+use crate::hooks::HookEvent;
+use crate::messages::{self, Language};
+use crate::ratelimit::RateLimiter;
 use crate::session::SessionManager;
+use phantom_frame::CloseCode;
+
+/// How long shutdown waits for the daemon's tasks to finish: long enough
+/// for the shells' kill timers, short enough that a task stuck in a read
+/// doesn't hold the daemon up.
+pub const SHUTDOWN_GRACE: Duration = Duration::from_secs(5);
+
+/// Live connection counts, per IP and in all, with caps on both so a peer
+/// can't hold hundreds of idle connections open.
+pub struct ConnectionGauges {
+    per_ip: Mutex<HashMap<IpAddr, usize>>,
+    total: AtomicUsize,
+    /// 0 = unlimited
+    max_per_ip: usize,
+    /// 0 = unlimited
+    max_total: usize,
+}
 
-/// Rate limiter: max N events per IP per window.
-struct RateLimiter {
-    /// Map of IP → list of event timestamps
-    connections: Mutex<HashMap<IpAddr, Vec<Instant>>>,
-    max_per_window: usize,
-    window: Duration,
+/// Counts a connection until dropped.
+pub struct ConnectionGuard {
+    gauges: Arc<ConnectionGauges>,
+    ip: IpAddr,
 }
 
-impl RateLimiter {
-    fn new(max_per_window: usize, window: Duration) -> Self {
+/// Snapshot of [`ConnectionGauges`] for status.
+#[derive(Debug, serde::Serialize)]
+pub struct ConnectionCounts {
+    pub total: usize,
+    pub max_total: usize,
+    pub max_per_ip: usize,
+    /// IPs with open connections, most first
+    pub by_ip: Vec<IpConnections>,
+}
+
+#[derive(Debug, PartialEq, serde::Serialize)]
+pub struct IpConnections {
+    pub ip: IpAddr,
+    pub connections: usize,
+}
+
+impl ConnectionGauges {
+    pub fn new(max_per_ip: usize, max_total: usize) -> Self {
         Self {
-            connections: Mutex::new(HashMap::new()),
-            max_per_window,
-            window,
+            per_ip: Mutex::new(HashMap::new()),
+            total: AtomicUsize::new(0),
+            max_per_ip,
+            max_total,
+        }
+    }
+
+    /// Count a new connection from `ip`, or None if that would exceed a cap.
+    pub fn acquire(self: &Arc<Self>, ip: IpAddr) -> Option<ConnectionGuard> {
+        let mut per_ip = self.per_ip.lock().expect("gauges lock");
+        let from_ip = per_ip.entry(ip).or_default();
+        let over_ip = self.max_per_ip > 0 && *from_ip >= self.max_per_ip;
+        let over_total = self.max_total > 0 && self.total.load(Ordering::Relaxed) >= self.max_total;
+        if over_ip || over_total {
+            if *from_ip == 0 {
+                per_ip.remove(&ip);
+            }
+            return None;
+        }
+        *from_ip += 1;
+        self.total.fetch_add(1, Ordering::Relaxed);
+        Some(ConnectionGuard { gauges: self.clone(), ip })
+    }
+
+    pub fn counts(&self) -> ConnectionCounts {
+        let mut by_ip: Vec<_> = self
+            .per_ip
+            .lock()
+            .expect("gauges lock")
+            .iter()
+            .map(|(&ip, &connections)| IpConnections { ip, connections })
+            .collect();
+        by_ip.sort_by(|a, b| b.connections.cmp(&a.connections).then(a.ip.cmp(&b.ip)));
+        ConnectionCounts {
+            total: self.total.load(Ordering::Relaxed),
+            max_total: self.max_total,
+            max_per_ip: self.max_per_ip,
+            by_ip,
+        }
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        let mut per_ip = self.gauges.per_ip.lock().expect("gauges lock");
+        if let Some(n) = per_ip.get_mut(&self.ip) {
+            *n -= 1;
+            if *n == 0 {
+                per_ip.remove(&self.ip);
+            }
         }
+        self.gauges.total.fetch_sub(1, Ordering::Relaxed);
     }
+}
 
-    /// Returns true if the event should be allowed, and records it.
-    fn check(&self, ip: IpAddr) -> bool {
-        let mut map = self.connections.lock().expect("rate limiter lock");
-        let now = Instant::now();
-        let timestamps = map.entry(ip).or_default();
+/// Sliding-window limits on new connections and failed auths per IP, shared
+/// with IPC so an operator can see who is limited and lift it.
+pub struct RateLimits {
+    pub connections: RateLimiter<IpAddr>,
+    pub auth_failures: RateLimiter<IpAddr>,
+}
 
-        // Prune expired entries
-        timestamps.retain(|t| now.duration_since(*t) < self.window);
+/// Snapshot of [`RateLimits`] for `rate_limit_status`.
+#[derive(Debug, serde::Serialize)]
+pub struct RateLimitStatus {
+    pub connections: LimiterStatus,
+    pub auth_failures: LimiterStatus,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct LimiterStatus {
+    pub limit: usize,
+    pub window_secs: u64,
+    /// IPs with events in the window, most first
+    pub by_ip: Vec<IpRateLimit>,
+}
+
+#[derive(Debug, PartialEq, serde::Serialize)]
+pub struct IpRateLimit {
+    pub ip: IpAddr,
+    pub events: usize,
+    /// Seconds until the IP is under the limit again; None if it is
+    pub retry_after_secs: Option<u64>,
+}
+
+impl RateLimits {
+    pub fn new(config: &RateLimitConfig, clock: SharedClock) -> Self {
+        Self {
+            connections: RateLimiter::new(config.connection_limit, Duration::from_secs(config.connection_window_secs))
+                .with_clock(clock.clone()),
+            auth_failures: RateLimiter::new(
+                config.auth_failure_limit,
+                Duration::from_secs(config.auth_failure_window_secs),
+            )
+            .with_clock(clock),
+        }
+    }
 
-        if timestamps.len() >= self.max_per_window {
-            false
-        } else {
-            timestamps.push(now);
-            true
+    pub fn status(&self) -> RateLimitStatus {
+        RateLimitStatus {
+            connections: limiter_status(&self.connections),
+            auth_failures: limiter_status(&self.auth_failures),
         }
     }
 
-    /// Returns true if under the limit, without recording a new event.
-    fn is_allowed(&self, ip: IpAddr) -> bool {
-        let mut map = self.connections.lock().expect("rate limiter lock");
-        let now = Instant::now();
-        let timestamps = map.entry(ip).or_default();
-        timestamps.retain(|t| now.duration_since(*t) < self.window);
-        timestamps.len() < self.max_per_window
+    /// Forget `ip`'s recent connections and failed auths. Whether it had any.
+    pub fn clear(&self, ip: IpAddr) -> bool {
+        let connections = self.connections.clear(&ip);
+        let auth_failures = self.auth_failures.clear(&ip);
+        connections || auth_failures
     }
+}
 
-    /// Record an event without checking limits (for tracking failures).
-    fn record(&self, ip: IpAddr) {
-        let mut map = self.connections.lock().expect("rate limiter lock");
-        let now = Instant::now();
-        let timestamps = map.entry(ip).or_default();
-        timestamps.retain(|t| now.duration_since(*t) < self.window);
-        timestamps.push(now);
+fn limiter_status(limiter: &RateLimiter<IpAddr>) -> LimiterStatus {
+    let mut by_ip: Vec<_> = limiter
+        .entries()
+        .into_iter()
+        .map(|(ip, events, retry_after)| IpRateLimit {
+            ip,
+            events,
+            // Rounded up, so a limited IP never shows 0
+            retry_after_secs: retry_after.map(|d| d.as_secs() + u64::from(d.subsec_nanos() > 0)),
+        })
+        .collect();
+    by_ip.sort_by(|a, b| b.events.cmp(&a.events).then(a.ip.cmp(&b.ip)));
+    LimiterStatus {
+        limit: limiter.max_per_window(),
+        window_secs: limiter.window().as_secs(),
+        by_ip,
     }
 }
 
+/// What new connections are checked against, and where they are listed.
+/// Shared with IPC, which reports on and lifts these.
+pub struct Intake {
+    pub gauges: Arc<ConnectionGauges>,
+    pub limits: Arc<RateLimits>,
+    pub connections: Arc<Connections>,
+}
+
 /// Run the QUIC server accept loop with graceful shutdown on SIGTERM/SIGINT.
+/// Each accepted connection is listed in `connections`, and served in a
+/// `conn` span carrying its ID.
 pub async fn run(
     endpoint: quinn::Endpoint,
     session_manager: Arc<SessionManager>,
     authenticator: Arc<Authenticator>,
-    conn_limit: usize,
-    conn_window_secs: u64,
-    auth_fail_limit: usize,
-    auth_fail_window_secs: u64,
+    intake: Intake,
 ) -> Result<()> {
-    let rate_limiter = Arc::new(RateLimiter::new(conn_limit, Duration::from_secs(conn_window_secs)));
-    let auth_fail_limiter = Arc::new(RateLimiter::new(auth_fail_limit, Duration::from_secs(auth_fail_window_secs)));
-
+    let Intake { gauges, limits, connections } = intake;
     info!("accepting connections on {}", endpoint.local_addr()?);
 
     loop {
@@ -84,27 +222,35 @@ pub async fn run(
                 let remote = incoming.remote_address();
                 let ip = remote.ip();
 
-                if !rate_limiter.check(ip) {
+                if !limits.connections.check(&ip) {
                     warn!("rate limited connection from {remote}");
                     incoming.refuse();
                     continue;
                 }
 
-                if !auth_fail_limiter.is_allowed(ip) {
-                    warn!("auth-failure rate limited connection from {remote}");
+                let Some(guard) = gauges.acquire(ip) else {
+                    warn!("too many open connections, refusing {remote}");
                     incoming.refuse();
                     continue;
-                }
+                };
 
                 let sm = session_manager.clone();
                 let auth = authenticator.clone();
-                let fail_limiter = auth_fail_limiter.clone();
-
-                tokio::spawn(async move {
-                    if let Err(e) = handle_connection(incoming, sm, auth, fail_limiter).await {
+                let limits = limits.clone();
+                let handle = connections.open(remote);
+                let id = handle.id().to_string();
+                let span = info_span!("conn", id = %id);
+
+                let serve = async move {
+                    // Counted until the connection is done
+                    let _guard = guard;
+                    if let Err(e) = handle_connection(incoming, sm, auth, limits, handle).await {
                         error!("connection from {remote} failed: {e:#}");
                     }
-                });
+                };
+                let serve = messages::scope(Language::default(), serve);
+                let serve = hello::scope(Wire::default(), serve);
+                session_manager.tasks().spawn(connections::scope(id, serve).instrument(span));
             }
             _ = tokio::signal::ctrl_c() => {
                 info!("received shutdown signal, closing endpoint...");
@@ -114,7 +260,7 @@ pub async fn run(
     }
 
     // Graceful shutdown: close endpoint and destroy all sessions
-    endpoint.close(0u32.into(), b"server shutdown");
+    endpoint.close(quinn::VarInt::from_u32(CloseCode::Shutdown.code()), b"server shutdown");
     info!("destroying all sessions...");
     session_manager.destroy_all();
     info!("shutdown complete");
@@ -122,74 +268,174 @@ pub async fn run(
     Ok(())
 }
 
+/// Stop accepting tasks on `tasks` and wait up to `grace` for the running
+/// ones to finish. Whether they all did.
+pub async fn join_tasks(tasks: &TaskTracker, grace: Duration) -> bool {
+    tasks.close();
+    if timeout(grace, tasks.wait()).await.is_err() {
+        warn!("{} task(s) still running after {}s, exiting anyway", tasks.len(), grace.as_secs());
+        return false;
+    }
+    true
+}
+
 async fn handle_connection(
     incoming: quinn::Incoming,
     session_manager: Arc<SessionManager>,
     authenticator: Arc<Authenticator>,
-    auth_fail_limiter: Arc<RateLimiter>,
+    limits: Arc<RateLimits>,
+    handle: ConnectionHandle,
 ) -> Result<()> {
     let connection = incoming
         .accept()
         .context("accept incoming")?
         .await
         .context("QUIC handshake")?;
+    handle.established(&connection);
 
     let remote = connection.remote_address();
-    info!("connection established with {remote}");
+    let protocol = crate::tls::negotiated_protocol(&connection).unwrap_or_else(|| "no ALPN".to_string());
+    info!("connection established with {remote} ({protocol})");
+    match session_manager.protocol_config().policy(&protocol) {
+        Some(AlpnPolicy::Reject) => {
+            warn!("rejecting {remote}: protocol {protocol} is not supported");
+            let reason = format!("protocol {protocol} is no longer supported, update the app");
+            connections::close(&connection, CloseCode::UnsupportedProtocol, reason.as_bytes());
+            return Ok(());
+        }
+        Some(AlpnPolicy::Deprecated) => warn!("{remote} connected with deprecated protocol {protocol}"),
+        Some(AlpnPolicy::Accept) | None => {}
+    }
 
     // First bidirectional stream = control channel.
-    // Must authenticate within 10 seconds.
+    // Must be opened within one auth message timeout; handle_auth then
+    // bounds each message and the whole exchange.
     let (control_send, control_recv) = timeout(
-        Duration::from_secs(10),
+        crate::auth::MESSAGE_TIMEOUT,
         connection.accept_bi(),
     )
     .await
     .context("auth timeout")?
     .context("accept control stream")?;
+    let control_stream = handle.stream();
+
+    // Too many failed auths from this IP: say when to retry, then close.
+    // Checked after the handshake so the client gets a reason, not a refusal.
+    if let Some(retry_after) = limits.auth_failures.retry_after(&remote.ip()) {
+        warn!("auth-failure rate limited connection from {remote}");
+        authenticator.refuse_rate_limited(&connection, control_send, control_recv, retry_after).await;
+        return Ok(());
+    }
 
     // Authenticate the connection (returns streams back for reuse)
-    let (device_id, control_send, control_recv) = match authenticator
+    let authenticated = match authenticator
         .handle_auth(&connection, control_send, control_recv)
         .await
     {
-        Ok(tuple) => tuple,
+        Ok(authenticated) => authenticated,
         Err(e) => {
+            // The client already has a failure response; this is for us
+            let code = ErrorCode::of(&e);
+            warn!("authentication failed for {remote} ({code:?}): {e:#}");
             // Record auth failure for rate limiting
-            auth_fail_limiter.record(remote.ip());
-            return Err(e).context("authentication");
+            if code != ErrorCode::RateLimited {
+                limits.auth_failures.record(&remote.ip());
+            }
+            session_manager.hooks().fire(HookEvent::AuthFailed {
+                remote: remote.to_string(),
+                error: format!("{e:#}"),
+            });
+            return Ok(());
         }
     };
+    let Authenticated {
+        peer,
+        send: mut control_send,
+        recv: control_recv,
+        server_info,
+        version_advisory,
+        hello,
+        resumed,
+    } = authenticated;
+
+    let device_id = peer.id().to_string();
+    info!("authenticated {device_id} from {remote}");
+    handle.authenticated(&device_id);
+
+    // Before the old connection is closed below and its bridges detach
+    let resumable = resumed.as_ref().map(|_| session_manager.attached_by(&device_id));
+
+    // Track this connection for the device (guests aren't devices)
+    if let Peer::Device { .. } = peer {
+        session_manager.register_connection(&device_id, &connection);
+    }
 
-    info!("authenticated device {device_id} from {remote}");
-
-    // Track this connection for the device
-    session_manager.register_connection(&device_id, &connection);
+    if let Some(request_id) = &hello {
+        if let Err(e) = crate::bridge::send_hello(&mut control_send, request_id).await {
+            warn!("hello not sent to {device_id}: {e:#}");
+        }
+    }
+    if let (Some(request_id), Peer::Device { policy, .. }) = (&server_info, &peer) {
+        if let Err(e) = crate::bridge::send_server_info(&mut control_send, &session_manager, policy, request_id).await {
+            warn!("server_info not sent to {device_id}: {e:#}");
+        }
+    }
+    if let Some(request_id) = &version_advisory {
+        if let Err(e) = crate::bridge::send_version_advisory(&mut control_send, &session_manager, request_id).await {
+            warn!("version advisory not sent to {device_id}: {e:#}");
+        }
+    }
+    if let (Some(request_id), Some(session_ids)) = (&resumed, &resumable) {
+        if let Err(e) = crate::bridge::send_resumable_sessions(&mut control_send, session_ids, request_id).await {
+            warn!("resumable sessions not sent to {device_id}: {e:#}");
+        }
+    }
 
     // Continue handling session requests on the same control stream.
-    // The first bidi stream serves as both auth and session management.
-    if let Err(e) = crate::bridge::handle_session_stream(
-        control_send, control_recv, &session_manager, &device_id,
-    )
-    .await
-    {
+    // The first bidi stream serves as both auth and session management, so
+    // it lasts as long as the connection.
+    let control = serve_stream(control_send, control_recv, &session_manager, &peer, StreamDeadlines::default()).await;
+    drop(control_stream);
+    if let Err(e) = control {
         info!("session stream ended for {device_id}: {e:#}");
     }
 
-    // Also accept additional bidi streams (for future multi-stream support)
+    // Also accept additional bidi streams (for future multi-stream support),
+    // each closed if it goes without requests, and only so many at once
+    let deadlines = StreamDeadlines::from_config(session_manager.bridge_config());
+    let max_streams = session_manager.bridge_config().max_streams;
+    let streams = Arc::new(Semaphore::new(if max_streams == 0 { Semaphore::MAX_PERMITS } else { max_streams }));
     loop {
         match connection.accept_bi().await {
-            Ok((send, recv)) => {
+            Ok((mut send, mut recv)) => {
+                let Ok(permit) = streams.clone().try_acquire_owned() else {
+                    warn!("refused a stream from {device_id}: {max_streams} already open");
+                    let refusal = format!("too many streams open on this connection (max {max_streams})");
+                    let refused = crate::bridge::write_error(&mut send, "", ErrorCode::LimitExceeded, &refusal);
+                    let _ = timeout(crate::auth::MESSAGE_TIMEOUT, refused).await;
+                    let _ = send.finish();
+                    let _ = recv.stop(0u32.into());
+                    continue;
+                };
                 let sm = session_manager.clone();
-                let did = device_id.clone();
-                tokio::spawn(async move {
-                    if let Err(e) = crate::bridge::handle_session_stream(
-                        send, recv, &sm, &did,
-                    )
-                    .await
-                    {
-                        error!("session stream error for {did}: {e:#}");
+                let peer = peer.clone();
+                let stream = handle.stream();
+                let serve = async move {
+                    // Held until the stream is done
+                    let (_permit, _stream) = (permit, stream);
+                    match serve_stream(send, recv, &sm, &peer, deadlines).await {
+                        Ok(()) => {}
+                        Err(e) if ErrorCode::of(&e) == ErrorCode::Timeout => {
+                            info!("session stream closed for {}: {e:#}", peer.id());
+                        }
+                        Err(e) => error!("session stream error for {}: {e:#}", peer.id()),
                     }
-                });
+                };
+                // In the language the device picked at auth
+                let serve = messages::scope(messages::current(), serve);
+                // On the wire negotiated at auth
+                let serve = hello::scope(hello::current(), serve);
+                session_manager.tasks().spawn(connections::scope(handle.id().to_string(), serve).in_current_span());
             }
             Err(quinn::ConnectionError::ApplicationClosed { .. }) => {
                 info!("connection closed by {device_id}");
@@ -206,97 +452,101 @@ async fn handle_connection(
         }
     }
 
-    session_manager.unregister_connection(&device_id);
+    if let Peer::Device { .. } = peer {
+        session_manager.unregister_connection(&device_id);
+    }
     Ok(())
 }
 
-// macOS sleep prevention via IOKit IOPMAssertion
-#[cfg(target_os = "macos")]
-mod sleep_prevention {
-    use std::sync::atomic::{AtomicU32, Ordering};
-
-    // IOPMAssertionID
-    static ASSERTION_ID: AtomicU32 = AtomicU32::new(0);
-
-    // CoreFoundation and IOKit FFI
-    type CFStringRef = *const std::ffi::c_void;
-    type IOPMAssertionID = u32;
-
-    extern "C" {
-        fn CFStringCreateWithCString(
-            alloc: *const std::ffi::c_void,
-            c_str: *const std::ffi::c_char,
-            encoding: u32,
-        ) -> CFStringRef;
-        fn CFRelease(cf: *const std::ffi::c_void);
-        fn IOPMAssertionCreateWithName(
-            assertion_type: CFStringRef,
-            level: u32,
-            reason: CFStringRef,
-            assertion_id: *mut IOPMAssertionID,
-        ) -> i32;
-        fn IOPMAssertionRelease(assertion_id: IOPMAssertionID) -> i32;
+/// Serve a session stream with the access the peer authenticated for.
+async fn serve_stream(
+    send: quinn::SendStream,
+    recv: quinn::RecvStream,
+    session_manager: &SessionManager,
+    peer: &Peer,
+    deadlines: StreamDeadlines,
+) -> Result<()> {
+    match peer {
+        Peer::Device { id, policy } => {
+            crate::bridge::handle_session_stream(send, recv, session_manager, id, policy, deadlines).await
+        }
+        Peer::Guest(guest) => {
+            crate::bridge::handle_guest_stream(send, recv, session_manager, guest, deadlines).await
+        }
     }
+}
 
-    const K_CF_STRING_ENCODING_UTF8: u32 = 0x08000100;
-    // kIOPMAssertionTypePreventUserIdleSystemSleep
-    const ASSERTION_TYPE: &[u8] = b"PreventUserIdleSystemSleep\0";
-    const REASON: &[u8] = b"Phantom daemon active\0";
-    // kIOPMAssertionLevelOn
-    const K_IOPM_ASSERTION_LEVEL_ON: u32 = 255;
-
-    pub fn prevent_sleep() {
-        unsafe {
-            let assertion_type = CFStringCreateWithCString(
-                std::ptr::null(),
-                ASSERTION_TYPE.as_ptr() as *const std::ffi::c_char,
-                K_CF_STRING_ENCODING_UTF8,
-            );
-            let reason = CFStringCreateWithCString(
-                std::ptr::null(),
-                REASON.as_ptr() as *const std::ffi::c_char,
-                K_CF_STRING_ENCODING_UTF8,
-            );
-
-            let mut assertion_id: IOPMAssertionID = 0;
-            let result = IOPMAssertionCreateWithName(
-                assertion_type,
-                K_IOPM_ASSERTION_LEVEL_ON,
-                reason,
-                &mut assertion_id,
-            );
-
-            CFRelease(reason);
-            CFRelease(assertion_type);
-
-            if result == 0 {
-                ASSERTION_ID.store(assertion_id, Ordering::Relaxed);
-                tracing::info!("system sleep prevention enabled (IOPMAssertion {assertion_id})");
-            } else {
-                tracing::warn!("failed to create IOPMAssertion: error {result}");
-            }
-        }
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gauges_cap_connections_per_ip_and_in_all() {
+        let gauges = Arc::new(ConnectionGauges::new(2, 3));
+        let (a, b): (IpAddr, IpAddr) = ("10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap());
+        let first = gauges.acquire(a).unwrap();
+        let _second = gauges.acquire(a).unwrap();
+        assert!(gauges.acquire(a).is_none());
+        let _third = gauges.acquire(b).unwrap();
+        // Global cap
+        assert!(gauges.acquire("10.0.0.3".parse().unwrap()).is_none());
+        let counts = gauges.counts();
+        assert_eq!(counts.total, 3);
+        let by_ip: Vec<_> = counts.by_ip.iter().map(|c| (c.ip, c.connections)).collect();
+        assert_eq!(by_ip, [(a, 2), (b, 1)]);
+
+        drop(first);
+        let _again = gauges.acquire(a).unwrap();
+        assert_eq!(gauges.counts().total, 3);
     }
 
-    pub fn allow_sleep() {
-        let id = ASSERTION_ID.swap(0, Ordering::Relaxed);
-        if id != 0 {
-            unsafe {
-                let result = IOPMAssertionRelease(id);
-                if result == 0 {
-                    tracing::info!("system sleep prevention disabled");
-                } else {
-                    tracing::warn!("failed to release IOPMAssertion: error {result}");
-                }
-            }
-        }
+    #[test]
+    fn rate_limits_list_and_clear_limited_ips() {
+        let clock = crate::clock::ManualClock::new();
+        let config = RateLimitConfig { connection_limit: 2, auth_failure_limit: 1, ..Default::default() };
+        let limits = RateLimits::new(&config, clock.clone());
+        let (a, b): (IpAddr, IpAddr) = ("10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap());
+        assert!(limits.connections.check(&a));
+        assert!(limits.connections.check(&a));
+        assert!(limits.connections.check(&b));
+        limits.auth_failures.record(&a);
+        clock.advance(Duration::from_secs(10));
+
+        let status = limits.status();
+        assert_eq!(
+            status.connections.by_ip,
+            [
+                IpRateLimit { ip: a, events: 2, retry_after_secs: Some(50) },
+                IpRateLimit { ip: b, events: 1, retry_after_secs: None },
+            ]
+        );
+        assert_eq!(status.auth_failures.by_ip[0].retry_after_secs, Some(290));
+
+        assert!(limits.clear(a));
+        assert!(limits.connections.check(&a));
+        assert!(limits.auth_failures.is_allowed(&a));
+        assert!(!limits.clear("10.0.0.3".parse().unwrap()));
     }
-}
 
-#[cfg(target_os = "macos")]
-pub use sleep_prevention::{allow_sleep, prevent_sleep};
+    #[test]
+    fn zero_means_unlimited() {
+        let gauges = Arc::new(ConnectionGauges::new(0, 0));
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let guards: Vec<_> = (0..100).map(|_| gauges.acquire(ip).unwrap()).collect();
+        assert_eq!(gauges.counts().total, 100);
+        drop(guards);
+        assert!(gauges.counts().by_ip.is_empty());
+    }
+
+    #[tokio::test]
+    async fn shutdown_waits_for_tasks_within_the_grace() {
+        let tasks = TaskTracker::new();
+        let finished = tasks.spawn(tokio::time::sleep(Duration::from_millis(20)));
+        assert!(join_tasks(&tasks, Duration::from_secs(1)).await);
+        assert!(finished.is_finished());
 
-#[cfg(not(target_os = "macos"))]
-pub fn prevent_sleep() {}
-#[cfg(not(target_os = "macos"))]
-pub fn allow_sleep() {}
+        let tasks = TaskTracker::new();
+        tasks.spawn(std::future::pending::<()>());
+        assert!(!join_tasks(&tasks, Duration::from_millis(50)).await);
+    }
+}
@@ -1,10 +1,65 @@
 use anyhow::{Context, Result};
-use portable_pty::{native_pty_system, CommandBuilder, MasterPty, PtySize};
-use std::collections::HashMap;
+use bytes::Bytes;
+use phantom_frame::CloseCode;
+use std::collections::{HashMap, VecDeque};
 use std::io::{Read, Write};
-use std::sync::{Arc, Mutex};
+#[cfg(unix)]
+use std::os::fd::{BorrowedFd, OwnedFd};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicI64, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use tokio::sync::{broadcast, Notify};
 use tokio_util::sync::CancellationToken;
-use tracing::{info, warn};
+use tokio_util::task::TaskTracker;
+use tracing::{debug, info, warn};
+
+use crate::agent::{AgentPermission, AgentSocket, DeviceLookup};
+use crate::archive::{ArchivedSession, SessionArchive};
+use crate::bandwidth::BandwidthLedger;
+use crate::budget::{Budget, BudgetReport, BudgetTracker, Verdict};
+use crate::bridge::{BridgeProbe, BridgeState, CompressionCounters, CompressionReport, ConnectionStats};
+use crate::clipboard::Clipboard;
+use crate::clock::{self, SharedClock};
+use crate::macros::MacroStore;
+use crate::scheduler::Scheduler;
+use crate::config::{BridgeConfig, ClientsConfig, DaemonConfig, DamagedPolicy, ProtocolConfig, WakeConfig};
+use crate::dedup::RecentRequests;
+use crate::handoff::Handoffs;
+use crate::env::EnvVars;
+use crate::errors::{ErrorCode, SessionError};
+use crate::history::{EventKind, SessionEvent, SessionHistory};
+use crate::hooks::{HookEvent, Hooks};
+use crate::memory::{MemoryBudget, Reservation};
+use crate::migrate::Successor;
+use crate::orphans::{ChildRegistry, Registered};
+use crate::output_log::{OutputLog, Rotation};
+use crate::project::Project;
+#[cfg(unix)]
+use crate::terminal::AdoptedPty;
+use crate::terminal::{native_spawner, ExitCode, Launch, NativePty, Spawner, TerminalBackend, TerminalCaps};
+use crate::tmux::Tmux;
+#[cfg(unix)]
+use crate::upgrade::HandoffSession;
+use crate::vouch::PairingRequests;
+use crate::wake::WakeInfo;
+
+/// zstd level for compacting idle scrollback.
+const COMPACT_LEVEL: i32 = 3;
+/// Most recent output kept by [`ScrollbackBuffer::tail`], also while compacted.
+pub const TAIL_BYTES: usize = 2048;
+/// Most terminal size changes [`ScrollbackBuffer`] remembers.
+const SIZE_MARKS_MAX: usize = 256;
+
+/// The terminal size output was laid out for, from an output offset on, so
+/// a client replaying scrollback into a terminal of another size can reflow
+/// it, or at least mark where the layout changes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+pub struct SizeMark {
+    pub offset: u64,
+    pub rows: u16,
+    pub cols: u16,
+}
 
 /// Ring buffer for PTY scrollback with clean-point tracking.
 pub struct ScrollbackBuffer {
@@ -15,6 +70,16 @@ pub struct ScrollbackBuffer {
     /// Byte offset of the last position where the terminal was in ground state
     /// (no pending escape sequence). Safe to replay from here on reattach.
     clean_point: usize,
+    /// Contents (in order) while compacted; `buf` is freed meanwhile
+    compressed: Option<Vec<u8>>,
+    /// Last [`TAIL_BYTES`] of output while compacted
+    compacted_tail: Vec<u8>,
+    /// Budget share for the ring (or the compressed contents)
+    reservation: Option<Reservation>,
+    /// Bytes ever appended: the output offset just past the newest byte
+    written: u64,
+    /// Terminal size changes, oldest first
+    sizes: VecDeque<SizeMark>,
 }
 
 impl ScrollbackBuffer {
@@ -25,6 +90,58 @@ impl ScrollbackBuffer {
             write_pos: 0,
             len: 0,
             clean_point: 0,
+            compressed: None,
+            compacted_tail: Vec::new(),
+            reservation: None,
+            written: 0,
+            sizes: VecDeque::new(),
+        }
+    }
+
+    /// Account this buffer against a memory budget.
+    pub fn set_reservation(&mut self, reservation: Reservation) {
+        self.reservation = Some(reservation);
+    }
+
+    pub fn is_compacted(&self) -> bool {
+        self.compressed.is_some()
+    }
+
+    /// Compress the contents and free the ring, shrinking the reservation to
+    /// the compressed size. Returns whether anything was compacted.
+    pub fn compact(&mut self) -> bool {
+        if self.compressed.is_some() || self.len == 0 {
+            return false;
+        }
+        let compressed = match zstd::bulk::compress(&self.read_from_clean_point(), COMPACT_LEVEL) {
+            Ok(c) => c,
+            Err(e) => {
+                warn!("scrollback compaction failed: {e}");
+                return false;
+            }
+        };
+        if let Some(r) = self.reservation.as_mut() {
+            r.resize(compressed.len());
+        }
+        self.compacted_tail = self.tail();
+        self.compressed = Some(compressed);
+        self.buf = Vec::new();
+        true
+    }
+
+    /// Restore the ring from compacted contents.
+    fn expand(&mut self) {
+        let Some(compressed) = self.compressed.take() else {
+            return;
+        };
+        let data = decompress_scrollback(&compressed, self.capacity);
+        self.compacted_tail = Vec::new();
+        self.buf = vec![0; self.capacity];
+        self.buf[..data.len()].copy_from_slice(&data);
+        self.len = data.len();
+        self.write_pos = data.len() % self.capacity;
+        if let Some(r) = self.reservation.as_mut() {
+            r.resize(self.capacity);
         }
     }
 
@@ -34,6 +151,8 @@ impl ScrollbackBuffer {
         if data.is_empty() {
             return;
         }
+        self.expand();
+        self.written += data.len() as u64;
 
         let data = if data.len() >= self.capacity {
             // Data larger than buffer — only keep the last `capacity` bytes
@@ -96,6 +215,9 @@ impl ScrollbackBuffer {
     /// is deferred — in practice the full buffer works fine since
     /// SwiftTerm's parser handles partial escape sequences gracefully.
     pub fn read_from_clean_point(&self) -> Vec<u8> {
+        if let Some(compressed) = &self.compressed {
+            return decompress_scrollback(compressed, self.capacity);
+        }
         if self.len == 0 {
             return Vec::new();
         }
@@ -114,52 +236,234 @@ impl ScrollbackBuffer {
     }
 }
 
+impl ScrollbackBuffer {
+    /// How full the buffer is.
+    pub fn usage(&self) -> ScrollbackUsage {
+        ScrollbackUsage {
+            bytes: self.len,
+            capacity: self.capacity,
+            compacted: self.is_compacted(),
+        }
+    }
+
+    /// Output offset of the oldest byte still held. Offsets count every
+    /// byte the session ever output, so they stay valid as the ring wraps.
+    pub fn start_offset(&self) -> u64 {
+        self.written - self.len as u64
+    }
+
+    /// Output offset just past the newest byte.
+    pub fn end_offset(&self) -> u64 {
+        self.written
+    }
+
+    /// Up to `max` bytes of held output ending at offset `before` (clamped
+    /// to what's held), with the offset they start at.
+    pub fn page(&self, before: u64, max: usize) -> (u64, Vec<u8>) {
+        let start = self.start_offset();
+        let end = before.clamp(start, self.written);
+        let from = end.saturating_sub(max as u64).max(start);
+        if from == end {
+            return (end, Vec::new());
+        }
+        let held = self.read_from_clean_point();
+        // Compacted contents that failed to decompress come back empty
+        let page = held.get((from - start) as usize..(end - start) as usize).unwrap_or_default();
+        (from, page.to_vec())
+    }
+
+    /// Note that output from now on is laid out for a `rows`×`cols` terminal.
+    pub fn record_size(&mut self, rows: u16, cols: u16) {
+        // A size nothing was written for doesn't count
+        if self.sizes.back().is_some_and(|m| m.offset == self.written) {
+            self.sizes.pop_back();
+        }
+        if self.sizes.back().is_some_and(|m| (m.rows, m.cols) == (rows, cols)) {
+            return;
+        }
+        self.sizes.push_back(SizeMark { offset: self.written, rows, cols });
+        // Keep the size in force at the oldest held byte
+        let start = self.start_offset();
+        while self.sizes.len() > SIZE_MARKS_MAX || (self.sizes.len() > 1 && self.sizes[1].offset <= start) {
+            self.sizes.pop_front();
+        }
+    }
+
+    /// The sizes output from offset `from` up to `to` was laid out for: the
+    /// one in force at `from` (as starting there), then each change before
+    /// `to`. Empty if no size was ever recorded.
+    pub fn sizes(&self, from: u64, to: u64) -> Vec<SizeMark> {
+        let in_force = self.sizes.iter().rposition(|m| m.offset <= from).unwrap_or(0);
+        let mut sizes: Vec<SizeMark> =
+            self.sizes.iter().skip(in_force).take_while(|m| m.offset < to || m.offset <= from).copied().collect();
+        if let Some(first) = sizes.first_mut() {
+            first.offset = first.offset.max(from);
+        }
+        sizes
+    }
+
+    /// The last (up to) [`TAIL_BYTES`] of output, without expanding a
+    /// compacted buffer.
+    pub fn tail(&self) -> Vec<u8> {
+        if self.compressed.is_some() {
+            return self.compacted_tail.clone();
+        }
+        let n = self.len.min(TAIL_BYTES);
+        let mut result = Vec::with_capacity(n);
+        if n <= self.write_pos {
+            result.extend_from_slice(&self.buf[self.write_pos - n..self.write_pos]);
+        } else {
+            // Wraps: end of the ring, then its start up to the write position
+            let wrapped = n - self.write_pos;
+            result.extend_from_slice(&self.buf[self.capacity - wrapped..]);
+            result.extend_from_slice(&self.buf[..self.write_pos]);
+        }
+        result
+    }
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct ScrollbackUsage {
+    pub bytes: usize,
+    pub capacity: usize,
+    pub compacted: bool,
+}
+
+fn decompress_scrollback(compressed: &[u8], capacity: usize) -> Vec<u8> {
+    zstd::bulk::decompress(compressed, capacity).unwrap_or_else(|e| {
+        warn!("scrollback decompression failed, dropping history: {e}");
+        Vec::new()
+    })
+}
+
+/// Last client input time, stored as epoch milliseconds so the bridge can
+/// record activity on every keystroke without taking the session lock.
+#[derive(Debug)]
+pub struct ActivityClock(AtomicI64);
+
+impl ActivityClock {
+    pub fn new() -> Self {
+        Self(AtomicI64::new(chrono::Utc::now().timestamp_millis()))
+    }
+
+    /// Record activity now.
+    pub fn touch(&self) {
+        self.0.store(chrono::Utc::now().timestamp_millis(), Ordering::Relaxed);
+    }
+
+    /// Record activity at `at` (restoring a handed-off session).
+    pub fn set(&self, at: chrono::DateTime<chrono::Utc>) {
+        self.0.store(at.timestamp_millis(), Ordering::Relaxed);
+    }
+
+    /// Time of the last recorded activity.
+    pub fn get(&self) -> chrono::DateTime<chrono::Utc> {
+        chrono::DateTime::from_timestamp_millis(self.0.load(Ordering::Relaxed))
+            .unwrap_or_default()
+    }
+}
+
+impl Default for ActivityClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Highest sequence of an input frame written to a session, by the device
+/// that sent it: each device numbers its own frames.
+#[derive(Debug, Default)]
+pub struct InputMarks(Mutex<HashMap<String, Arc<AtomicU64>>>);
+
+impl InputMarks {
+    /// `device_id`'s mark, 0 until its input is written.
+    pub fn device(&self, device_id: &str) -> Arc<AtomicU64> {
+        self.0.lock().expect("input marks lock").entry(device_id.to_string()).or_default().clone()
+    }
+}
+
 /// A single PTY session.
 pub struct PtySession {
     pub id: String,
     pub reader: Option<Box<dyn Read + Send>>,
     pub writer: Arc<Mutex<Box<dyn Write + Send>>>,
-    pub child: Box<dyn portable_pty::Child + Send + Sync>,
-    pub master: Box<dyn MasterPty + Send>,
+    pub backend: Box<dyn TerminalBackend>,
     pub scrollback: Arc<Mutex<ScrollbackBuffer>>,
     pub created_at: chrono::DateTime<chrono::Utc>,
     pub shell: String,
+    /// tmux session this session's terminal is attached to (passthrough mode)
+    pub tmux_session: Option<String>,
+    /// Given at creation, or derived from the first command typed
+    pub name: Option<String>,
+    /// `SSH_AUTH_SOCK` forwarded to the creating device, if enabled
+    pub agent: Option<AgentSocket>,
     /// Set when a client is attached
     pub attached: bool,
     /// Set when PTY reader cannot be recovered after detach — session is unusable
     pub damaged: bool,
+    /// What damaged the session
+    pub damaged_cause: Option<String>,
+    /// Damaged, and kept for inspection instead of being reaped
+    pub quarantined: bool,
+    /// Exit code and when the reaper saw it, for exited sessions kept around
+    pub exited: Option<(ExitCode, Instant)>,
     /// Cancellation token for the current bridge tasks
     pub bridge_cancel: Option<CancellationToken>,
+    /// Live state of the current bridge
+    pub bridge_probe: Option<Arc<BridgeProbe>>,
+    /// Scrollback replays sent to reattaching clients
+    pub resyncs: u64,
+    /// Output compressed, and not, over the session's life
+    pub compression: Arc<CompressionCounters>,
+    /// CPU-time and lifetime limits the creating client set
+    pub budget: Option<BudgetTracker>,
+    /// Output feed of the attached bridge, subscribed to by mirrors
+    pub mirror_feed: Option<broadcast::Sender<Bytes>>,
     /// Device that created this session
     pub created_by_device_id: Option<String>,
+    /// User the session runs as, in multi-user mode
+    pub user: Option<String>,
+    /// Terminal the creating client declared
+    pub terminal: TerminalCaps,
     /// Last time a client attached to this session
     pub last_attached_at: Option<chrono::DateTime<chrono::Utc>>,
     /// Device that last attached to this session
     pub last_attached_by: Option<String>,
-    /// Last time the session had client input activity
-    pub last_activity_at: chrono::DateTime<chrono::Utc>,
+    /// Last time the session had client input activity (shared with the bridge)
+    pub activity: Arc<ActivityClock>,
+    /// Who attached, detached, resized, ...
+    pub history: SessionHistory,
+    /// Log the bridge tees output into, when output logging is on
+    pub output_log: Arc<Mutex<Option<OutputLog>>>,
+    /// The app has turned on bracketed paste, as seen in its output
+    pub bracketed_paste: Arc<AtomicBool>,
+    /// Input written so far, kept across attachments so input resent after
+    /// a reconnect can be skipped
+    pub input_marks: InputMarks,
+    /// Watched variables a prompt hook reported in the output (see [`crate::env`])
+    pub reported_env: Arc<Mutex<EnvVars>>,
+    /// The shell's orphan registry entry, while the session exists
+    pub registered: Option<Registered>,
 }
 
 impl PtySession {
     pub fn spawn(id: String, rows: u16, cols: u16, device_id: Option<&str>, scrollback_bytes: usize) -> Result<Self> {
-        let pty_system = native_pty_system();
-        let pair = pty_system
-            .openpty(PtySize {
-                rows,
-                cols,
-                pixel_width: 0,
-                pixel_height: 0,
-            })
-            .context("openpty")?;
-
-        let mut cmd = CommandBuilder::new_default_prog();
-        cmd.env("TERM", "xterm-256color");
-
-        let child = pair.slave.spawn_command(cmd).context("spawn shell")?;
-        drop(pair.slave);
+        (native_spawner())(rows, cols, &Launch::default())
+            .and_then(|backend| Self::with_backend(id, backend, device_id, scrollback_bytes))
+    }
 
-        let reader = pair.master.try_clone_reader().context("clone PTY reader")?;
-        let writer = pair.master.take_writer().context("take PTY writer")?;
+    /// Wrap an already-running terminal backend.
+    pub fn with_backend(
+        id: String,
+        mut backend: Box<dyn TerminalBackend>,
+        device_id: Option<&str>,
+        scrollback_bytes: usize,
+    ) -> Result<Self> {
+        let reader = backend.try_clone_reader()?;
+        let writer = backend.take_writer()?;
+        let mut scrollback = ScrollbackBuffer::new(scrollback_bytes);
+        if let Ok((rows, cols)) = backend.size() {
+            scrollback.record_size(rows, cols);
+        }
 
         let shell = std::env::var("SHELL").unwrap_or_else(|_| "/bin/zsh".to_string());
         let now = chrono::Utc::now();
@@ -168,44 +472,195 @@ impl PtySession {
             id,
             reader: Some(reader),
             writer: Arc::new(Mutex::new(writer)),
-            child,
-            master: pair.master,
-            scrollback: Arc::new(Mutex::new(ScrollbackBuffer::new(scrollback_bytes))),
+            backend,
+            scrollback: Arc::new(Mutex::new(scrollback)),
             created_at: now,
             shell,
+            tmux_session: None,
+            name: None,
+            agent: None,
             attached: false,
             damaged: false,
+            damaged_cause: None,
+            quarantined: false,
+            exited: None,
             bridge_cancel: None,
+            bridge_probe: None,
+            resyncs: 0,
+            compression: Arc::default(),
+            budget: None,
+            mirror_feed: None,
             created_by_device_id: device_id.map(|s| s.to_string()),
+            user: None,
+            terminal: TerminalCaps::default(),
             last_attached_at: None,
             last_attached_by: None,
-            last_activity_at: now,
+            activity: Arc::new(ActivityClock::new()),
+            history: SessionHistory::default(),
+            output_log: Arc::default(),
+            bracketed_paste: Arc::default(),
+            input_marks: InputMarks::default(),
+            reported_env: Arc::default(),
+            registered: None,
         })
     }
 
-    #[allow(dead_code)]
     pub fn is_alive(&mut self) -> bool {
-        matches!(self.child.try_wait(), Ok(None))
+        matches!(self.backend.try_wait(), Ok(None))
     }
 
+    /// Resize the terminal, noting the new size in the scrollback.
     pub fn resize(&self, rows: u16, cols: u16) -> Result<()> {
-        self.master
-            .resize(PtySize {
-                rows,
-                cols,
-                pixel_width: 0,
-                pixel_height: 0,
-            })
-            .context("resize PTY")
+        self.backend.resize(rows, cols)?;
+        self.scrollback.lock().expect("scrollback lock").record_size(rows, cols);
+        Ok(())
+    }
+
+    /// Terminal size as (rows, cols).
+    pub fn size(&self) -> Result<(u16, u16)> {
+        self.backend.size()
+    }
+
+    /// The PTY master and process, as far as they can be checked.
+    fn pty_diagnostics(&mut self) -> PtyDiagnostics {
+        #[cfg(unix)]
+        let fd = self.backend.handoff().map(|(fd, _)| fd);
+        // A ConPTY has no descriptor to check
+        #[cfg(not(unix))]
+        let fd = None;
+        PtyDiagnostics {
+            fd,
+            fd_open: fd.is_some_and(fd_open),
+            pid: self.backend.pid(),
+            process_alive: self.is_alive(),
+        }
+    }
+
+    /// Number of devices currently mirroring the session.
+    pub fn mirror_count(&self) -> usize {
+        self.mirror_feed.as_ref().map_or(0, |feed| feed.receiver_count())
+    }
+
+    fn terminate(&mut self, tasks: &TaskTracker) {
+        // Stop forwarding right away; a bridge may hold the session a while longer
+        self.agent = None;
+        self.backend.terminate(tasks);
     }
 }
 
 /// Manages all PTY sessions.
+///
+/// The session map is behind an `RwLock` and only ever held long enough to
+/// clone out `Arc`s — per-session locks are never taken while it is held, so
+/// list/reap/create/destroy don't serialize behind each other or behind a
+/// busy session.
 pub struct SessionManager {
-    sessions: Mutex<HashMap<String, Arc<Mutex<PtySession>>>>,
+    sessions: RwLock<HashMap<String, Arc<Mutex<PtySession>>>>,
     /// device_id → active quinn::Connection
-    connections: Mutex<HashMap<String, quinn::Connection>>,
+    connections: Arc<RwLock<HashMap<String, quinn::Connection>>>,
     scrollback_bytes: usize,
+    bridge_config: BridgeConfig,
+    /// ALPN versions and how connections that negotiated them are handled
+    protocol_config: ProtocolConfig,
+    /// Version advice for apps
+    clients_config: ClientsConfig,
+    /// Pre-spawned idle shells handed out by create_session
+    pool: Mutex<Vec<PtySession>>,
+    /// Target pool size (0 = no pre-warming)
+    prewarm: usize,
+    /// Wakes the pre-warm task when the pool has been drawn from
+    pool_notify: Notify,
+    /// Shared cap for scrollback and bridge buffers
+    budget: Arc<MemoryBudget>,
+    /// Creates the terminal behind each new session
+    spawner: Spawner,
+    /// Where agent-forwarding sessions' sockets live (None = unavailable)
+    agent_dir: Option<PathBuf>,
+    /// Which devices may forward their agent, re-checked per request
+    agent_permission: AgentPermission,
+    /// User hooks on session events
+    hooks: Arc<Hooks>,
+    /// Host clipboard clients may set
+    clipboard: Clipboard,
+    /// Devices' input macros
+    macros: MacroStore,
+    /// Scheduled commands and their recent runs
+    scheduler: Scheduler,
+    /// Bytes each device transferred, by day and session
+    bandwidth: BandwidthLedger,
+    /// Sessions run as, and are visible only to, their device's user
+    multi_user: bool,
+    /// Most sessions open at once (0 = unlimited)
+    max_sessions: usize,
+    /// Sessions counted against `max_sessions` while they spawn
+    spawning: Mutex<usize>,
+    /// Responses to recent control requests, for resends
+    recent_requests: RecentRequests,
+    /// How clients can wake this host
+    wake: WakeConfig,
+    /// Histories of recently ended sessions, oldest first
+    ended: Mutex<VecDeque<EndedSession>>,
+    /// How long exited sessions are kept before being reaped
+    exited_retention: Duration,
+    /// What the reaper does with damaged sessions
+    damaged_policy: DamagedPolicy,
+    /// New devices waiting for a paired one to approve them
+    pairing_requests: Arc<PairingRequests>,
+    /// Sessions being handed to another device
+    handoffs: Handoffs,
+    /// Where the host this one is moving to is announced (None = not kept)
+    successor_path: Option<PathBuf>,
+    /// Where sessions' output logs are written (None = unavailable)
+    output_log_dir: Option<PathBuf>,
+    /// When output logs rotate
+    output_log_rotation: Rotation,
+    /// Bridge tasks that have panicked since the daemon started
+    bridge_panics: Arc<AtomicU64>,
+    /// Where shells are registered for orphan cleanup (None = not kept)
+    children: Option<ChildRegistry>,
+    /// tmux server passthrough sessions attach to
+    tmux: Tmux,
+    /// What the reaper times retention by
+    clock: SharedClock,
+    /// Tasks shutdown waits for: connections, streams, bridges, kill timers
+    tasks: TaskTracker,
+}
+
+/// A new session's place under `max_sessions`, given back when dropped
+/// unless the session was inserted (see [`SessionManager::reserve_slot`]).
+struct SessionSlot<'a> {
+    manager: &'a SessionManager,
+}
+
+impl Drop for SessionSlot<'_> {
+    fn drop(&mut self) {
+        *self.manager.spawning.lock().expect("spawning lock") -= 1;
+    }
+}
+
+/// Ended sessions whose history is kept.
+const MAX_ENDED_HISTORIES: usize = 32;
+
+struct EndedSession {
+    id: String,
+    user: Option<String>,
+    created_by_device_id: Option<String>,
+    history: SessionHistory,
+}
+
+/// A session's timeline, with what's needed to check who may see it.
+pub struct HistoryView {
+    pub user: Option<String>,
+    pub created_by_device_id: Option<String>,
+    /// The session has been destroyed or exited
+    pub ended: bool,
+    pub events: Vec<SessionEvent>,
+}
+
+impl Default for SessionManager {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl SessionManager {
@@ -215,64 +670,725 @@ impl SessionManager {
 
     pub fn with_scrollback(scrollback_bytes: usize) -> Self {
         Self {
-            sessions: Mutex::new(HashMap::new()),
-            connections: Mutex::new(HashMap::new()),
+            sessions: RwLock::new(HashMap::new()),
+            connections: Arc::default(),
             scrollback_bytes,
+            bridge_config: BridgeConfig::default(),
+            protocol_config: ProtocolConfig::default(),
+            clients_config: ClientsConfig::default(),
+            pool: Mutex::new(Vec::new()),
+            prewarm: 0,
+            pool_notify: Notify::new(),
+            budget: MemoryBudget::unlimited(),
+            spawner: native_spawner(),
+            agent_dir: None,
+            agent_permission: Arc::new(|_| true),
+            hooks: Hooks::disabled(),
+            clipboard: Clipboard::default(),
+            macros: MacroStore::default(),
+            scheduler: Scheduler::default(),
+            bandwidth: BandwidthLedger::default(),
+            multi_user: false,
+            max_sessions: 0,
+            spawning: Mutex::new(0),
+            recent_requests: RecentRequests::default(),
+            wake: WakeConfig::default(),
+            ended: Mutex::new(VecDeque::new()),
+            exited_retention: Duration::ZERO,
+            damaged_policy: DamagedPolicy::Destroy,
+            pairing_requests: Arc::default(),
+            handoffs: Handoffs::default(),
+            successor_path: None,
+            output_log_dir: None,
+            output_log_rotation: Rotation::default(),
+            bridge_panics: Arc::default(),
+            children: None,
+            tmux: Tmux::default(),
+            clock: clock::system(),
+            tasks: TaskTracker::new(),
+        }
+    }
+
+    /// Build a manager from the daemon config file.
+    pub fn with_config(config: &DaemonConfig) -> Self {
+        Self {
+            bridge_config: config.bridge.clone(),
+            protocol_config: config.protocol.clone(),
+            clients_config: config.clients.clone(),
+            // Pooled shells run as the daemon, which no device uses in multi-user mode
+            prewarm: if config.system.multi_user { 0 } else { config.session.prewarm },
+            budget: MemoryBudget::new(config.memory.max_bytes, config.memory.pressure_percent),
+            hooks: Hooks::new(config.hooks.clone()),
+            clipboard: Clipboard::new(config.clipboard.clone()),
+            scheduler: Scheduler::new(config.schedules.clone()),
+            multi_user: config.system.multi_user,
+            max_sessions: config.session.max_sessions,
+            wake: config.wake.clone(),
+            exited_retention: Duration::from_secs(config.session.exited_retention_secs),
+            damaged_policy: config.session.damaged,
+            output_log_rotation: Rotation {
+                max_bytes: config.session.output_log_max_bytes,
+                keep: config.session.output_log_keep,
+            },
+            ..Self::with_scrollback(config.session.scrollback_bytes)
+        }
+    }
+
+    /// Use `spawner` instead of native PTYs for new sessions.
+    pub fn with_spawner(self, spawner: Spawner) -> Self {
+        Self { spawner, ..self }
+    }
+
+    /// Enable SSH agent forwarding, with session sockets under `dir`.
+    pub fn with_agent_dir(self, dir: PathBuf) -> Self {
+        Self { agent_dir: Some(dir), ..self }
+    }
+
+    /// Re-check `permission` on every agent request, so that a device denied
+    /// agent forwarding stops being asked to sign from its open sessions.
+    pub fn with_agent_permission(self, permission: impl Fn(&str) -> bool + Send + Sync + 'static) -> Self {
+        Self { agent_permission: Arc::new(permission), ..self }
+    }
+
+    /// Attach tmux passthrough sessions to the server whose socket is under
+    /// `dir`, rather than the user's own.
+    pub fn with_tmux_tmpdir(self, dir: PathBuf) -> Self {
+        Self { tmux: Tmux::with_tmpdir(dir), ..self }
+    }
+
+    /// Allow output logging, with sessions' logs under `dir`.
+    pub fn with_output_log_dir(self, dir: PathBuf) -> Self {
+        Self { output_log_dir: Some(dir), ..self }
+    }
+
+    /// Register sessions' shells in `registry`, so a restart after a crash
+    /// can clean up those left running.
+    pub fn with_child_registry(self, registry: ChildRegistry) -> Self {
+        Self { children: Some(registry), ..self }
+    }
+
+    /// Keep devices' input macros in `store`.
+    pub fn with_macros(self, store: MacroStore) -> Self {
+        Self { macros: store, ..self }
+    }
+
+    /// Announce the host this one is moving to, as written at `path` by
+    /// `phantom device export`.
+    pub fn with_successor_file(self, path: PathBuf) -> Self {
+        Self { successor_path: Some(path), ..self }
+    }
+
+    /// Keep scheduled commands added over IPC at `path`.
+    pub fn with_schedule_store(self, path: PathBuf) -> Self {
+        Self { scheduler: self.scheduler.with_store(path), ..self }
+    }
+
+    /// Keep devices' bandwidth counters in `ledger`.
+    pub fn with_bandwidth(self, ledger: BandwidthLedger) -> Self {
+        Self { bandwidth: ledger, ..self }
+    }
+
+    /// Read time from `clock` instead of the system's.
+    pub fn with_clock(self, clock: SharedClock) -> Self {
+        Self { clock, ..self }
+    }
+
+    /// Keep exited sessions for `retention` before the reaper removes them.
+    pub fn with_exited_retention(self, retention: Duration) -> Self {
+        Self { exited_retention: retention, ..self }
+    }
+
+    /// The tmux server passthrough sessions attach to.
+    pub fn tmux(&self) -> &Tmux {
+        &self.tmux
+    }
+
+    /// The clock the manager reads, for limiters that should agree with it.
+    pub fn clock(&self) -> &SharedClock {
+        &self.clock
+    }
+
+    /// Where the daemon's tasks are spawned, so shutdown can wait for them.
+    pub fn tasks(&self) -> &TaskTracker {
+        &self.tasks
+    }
+
+    /// Hooks from the daemon config, for events fired outside the manager.
+    pub fn hooks(&self) -> &Arc<Hooks> {
+        &self.hooks
+    }
+
+    pub fn clipboard(&self) -> &Clipboard {
+        &self.clipboard
+    }
+
+    pub fn macros(&self) -> &MacroStore {
+        &self.macros
+    }
+
+    /// Pushes events to connected devices.
+    pub fn notifier(&self) -> DeviceNotifier {
+        DeviceNotifier(self.connections.clone())
+    }
+
+    /// The host devices should trust next, if this one is moving.
+    pub fn successor(&self) -> Option<Successor> {
+        self.successor_path.as_deref().and_then(crate::migrate::successor)
+    }
+
+    /// Vouched pairing requests, settled by devices' `approve_pairing`.
+    pub fn pairing_requests(&self) -> &Arc<PairingRequests> {
+        &self.pairing_requests
+    }
+
+    /// Sessions being handed between devices, claimed by `attach_session`.
+    pub fn handoffs(&self) -> &Handoffs {
+        &self.handoffs
+    }
+
+    /// Whether `device_id` has a connection open.
+    pub fn is_connected(&self, device_id: &str) -> bool {
+        self.connections.read().expect("connections lock").contains_key(device_id)
+    }
+
+    pub fn bandwidth(&self) -> &BandwidthLedger {
+        &self.bandwidth
+    }
+
+    pub fn scheduler(&self) -> &Scheduler {
+        &self.scheduler
+    }
+
+    /// What clients need to wake this host, if it can be woken.
+    pub fn wake_info(&self) -> Option<WakeInfo> {
+        crate::wake::info(&self.wake)
+    }
+
+    /// Whether devices are scoped to the user they were paired for.
+    pub fn multi_user(&self) -> bool {
+        self.multi_user
+    }
+
+    /// Whether sessions' output can be logged.
+    pub fn output_logging(&self) -> bool {
+        self.output_log_dir.is_some()
+    }
+
+    /// Most sessions open at once, if limited.
+    pub fn max_sessions(&self) -> Option<usize> {
+        (self.max_sessions > 0).then_some(self.max_sessions)
+    }
+
+    /// Scrollback kept per session, in bytes.
+    pub fn scrollback_bytes(&self) -> usize {
+        self.scrollback_bytes
+    }
+
+    /// How long exited sessions are kept before being reaped.
+    pub fn exited_retention(&self) -> Duration {
+        self.exited_retention
+    }
+
+    /// What the reaper does with damaged sessions.
+    pub fn damaged_policy(&self) -> DamagedPolicy {
+        self.damaged_policy
+    }
+
+    /// Responses to recent control requests, so resends aren't run twice.
+    pub fn recent_requests(&self) -> &RecentRequests {
+        &self.recent_requests
+    }
+
+    /// Hold a place for a new session under `max_sessions` while it spawns,
+    /// so that concurrent creates can't all pass the check before any of
+    /// them is inserted. Refused once `max_sessions` are open.
+    fn reserve_slot(&self) -> Result<SessionSlot<'_>> {
+        let mut spawning = self.spawning.lock().expect("spawning lock");
+        if let Some(max) = self.max_sessions() {
+            // Exited sessions kept for inspection don't count
+            let open = self
+                .snapshot()
+                .into_iter()
+                .filter(|(_, s)| s.lock().expect("session lock").exited.is_none())
+                .count();
+            if open + *spawning >= max {
+                return Err(SessionError::LimitReached { max }.into());
+            }
+        }
+        *spawning += 1;
+        Ok(SessionSlot { manager: self })
+    }
+
+    /// Bridge tasks that have panicked, counted by their bridges.
+    pub fn bridge_panics(&self) -> &Arc<AtomicU64> {
+        &self.bridge_panics
+    }
+
+    /// Daemon-wide bridge defaults (clients may override some per attach).
+    pub fn bridge_config(&self) -> &BridgeConfig {
+        &self.bridge_config
+    }
+
+    /// Advertised ALPN versions and their policies.
+    pub fn protocol_config(&self) -> &ProtocolConfig {
+        &self.protocol_config
+    }
+
+    /// Oldest supported app version and release notes.
+    pub fn clients_config(&self) -> &ClientsConfig {
+        &self.clients_config
+    }
+
+    pub fn budget(&self) -> &Arc<MemoryBudget> {
+        &self.budget
+    }
+
+    /// Reserve memory, compacting detached sessions' scrollback first if the
+    /// budget is short.
+    pub fn reserve(&self, bytes: usize) -> Result<Reservation> {
+        self.budget.try_reserve(bytes).or_else(|_| {
+            self.compact_detached(true);
+            self.budget.try_reserve(bytes)
+        })
+    }
+
+    /// Spawn what `launch` describes, with scrollback accounted against the
+    /// budget.
+    fn spawn_session(
+        &self,
+        id: String,
+        rows: u16,
+        cols: u16,
+        device_id: Option<&str>,
+        launch: &Launch<'_>,
+    ) -> Result<PtySession> {
+        let mut session = self.spawn_session_with(id, device_id, || (self.spawner)(rows, cols, launch))?;
+        session.user = launch.user.map(str::to_string);
+        session.terminal = launch.terminal.cloned().unwrap_or_default();
+        if let Some(command) = launch.command {
+            session.shell = command.to_string();
+        }
+        Ok(session)
+    }
+
+    fn spawn_session_with(
+        &self,
+        id: String,
+        device_id: Option<&str>,
+        spawn: impl FnOnce() -> Result<Box<dyn TerminalBackend>>,
+    ) -> Result<PtySession> {
+        let reservation = self.reserve(self.scrollback_bytes)?;
+        let backend = spawn().map_err(|source| SessionError::SpawnFailed { source })?;
+        let mut session = PtySession::with_backend(id, backend, device_id, self.scrollback_bytes)?;
+        session.scrollback.lock().expect("scrollback lock").set_reservation(reservation);
+        if let (Some(children), Some(pid)) = (&self.children, session.backend.pid()) {
+            session.registered = children.register(pid);
         }
+        Ok(session)
+    }
+
+    /// Spawn `command` in a terminal of its own, for an exec.
+    pub fn spawn_command(&self, command: &str) -> Result<Box<dyn TerminalBackend>> {
+        (self.spawner)(24, 80, &Launch { command: Some(command), ..Default::default() })
     }
 
+    /// Create a session running what `launch` describes. Pooled shells are
+    /// only used for the default.
     pub fn create_session(
         &self,
         rows: u16,
         cols: u16,
         device_id: Option<&str>,
+        launch: Launch<'_>,
+    ) -> Result<String> {
+        let slot = self.reserve_slot()?;
+        let id = uuid_short();
+        let pooled = if launch.is_default() { self.take_pooled() } else { None };
+        let session = match pooled {
+            Some(mut session) => {
+                session.resize(rows, cols)?;
+                session.id = id.clone();
+                session.created_at = chrono::Utc::now();
+                session.created_by_device_id = device_id.map(|s| s.to_string());
+                session.activity.touch();
+                self.pool_notify.notify_one();
+                session
+            }
+            None => self.spawn_session(id.clone(), rows, cols, device_id, &launch)
+                .context("spawn session")?,
+        };
+
+        self.insert_session(slot, &id, session, device_id);
+
+        info!("created session {id}");
+        Ok(id)
+    }
+
+    /// Add a spawned session, in place of the slot reserved for it.
+    fn insert_session(&self, slot: SessionSlot<'_>, id: &str, mut session: PtySession, device_id: Option<&str>) {
+        session.history.record(EventKind::Created { by: device_id.map(str::to_string) });
+        {
+            // Counted as open and no longer as spawning in one step
+            let mut spawning = self.spawning.lock().expect("spawning lock");
+            self.sessions
+                .write()
+                .expect("sessions lock")
+                .insert(id.to_string(), Arc::new(Mutex::new(session)));
+            *spawning -= 1;
+            std::mem::forget(slot);
+        }
+        self.hooks.fire(HookEvent::SessionCreated {
+            session_id: id.to_string(),
+            device_id: device_id.map(str::to_string),
+        });
+    }
+
+    /// Create a session whose `SSH_AUTH_SOCK` forwards to `device_id`'s agent.
+    /// Always a fresh shell: pooled shells were started without the socket.
+    pub fn create_agent_session(
+        &self,
+        rows: u16,
+        cols: u16,
+        device_id: &str,
+        terminal: &TerminalCaps,
+    ) -> Result<String> {
+        let slot = self.reserve_slot()?;
+        let id = uuid_short();
+        let agent = self.bind_agent(&id, device_id)?;
+
+        let mut session = self
+            .spawn_session_with(id.clone(), Some(device_id), || {
+                let env = [("SSH_AUTH_SOCK", agent.path().as_os_str())];
+                Ok(Box::new(NativePty::spawn_with_env(rows, cols, &env, terminal)?))
+            })
+            .context("spawn session")?;
+        session.agent = Some(agent);
+        session.terminal = terminal.clone();
+
+        self.insert_session(slot, &id, session, Some(device_id));
+
+        info!("created session {id} with agent forwarding to {device_id}");
+        Ok(id)
+    }
+
+    /// Listen on session `id`'s `SSH_AUTH_SOCK`, relaying to `device_id`.
+    fn bind_agent(&self, id: &str, device_id: &str) -> Result<AgentSocket> {
+        let dir = self.agent_dir.as_ref().ok_or_else(|| ErrorCode::Unavailable.err("agent forwarding is not enabled"))?;
+        let connections = self.connections.clone();
+        let permission = self.agent_permission.clone();
+        let device = device_id.to_string();
+        let lookup: DeviceLookup = Arc::new(move || {
+            if !permission(&device) {
+                return None;
+            }
+            connections.read().expect("connections lock").get(&device).cloned()
+        });
+        AgentSocket::bind(dir, id, lookup)
+    }
+
+    /// Create a session attached to the user's existing tmux session `name`.
+    /// Never drawn from the pre-warm pool.
+    pub fn create_tmux_session(
+        &self,
+        name: &str,
+        rows: u16,
+        cols: u16,
+        device_id: Option<&str>,
+        terminal: &TerminalCaps,
     ) -> Result<String> {
+        let slot = self.reserve_slot()?;
+        let id = uuid_short();
+        let mut session = self
+            .spawn_session_with(id.clone(), device_id, || Ok(Box::new(self.tmux.attach(rows, cols, name, terminal)?)))
+            .context("attach tmux session")?;
+        session.tmux_session = Some(name.to_string());
+        session.terminal = terminal.clone();
+
+        self.insert_session(slot, &id, session, device_id);
+
+        info!("created session {id} on tmux session {name}");
+        Ok(id)
+    }
+
+    /// Snapshot a session's metadata and scrollback for export.
+    pub fn export_session(&self, id: &str) -> Result<SessionArchive> {
+        let session = self.get_session(id).ok_or(SessionError::NotFound)?;
+        let (metadata, scrollback) = {
+            let s = session.lock().expect("session lock");
+            let metadata = ArchivedSession {
+                id: s.id.clone(),
+                shell: s.shell.clone(),
+                created_at: s.created_at,
+                created_by_device_id: s.created_by_device_id.clone(),
+                tmux_session: s.tmux_session.clone(),
+                last_activity_at: s.activity.get(),
+            };
+            (metadata, s.scrollback.clone())
+        };
+        let scrollback = scrollback.lock().expect("scrollback lock").read_from_clean_point();
+        Ok(SessionArchive::new(metadata, scrollback))
+    }
+
+    /// Start a new shell with an archived session's history in its
+    /// scrollback, so it replays on attach. Never drawn from the pre-warm
+    /// pool, whose shells may already have output of their own. In
+    /// multi-user mode the shell must be given a `user` to run as.
+    pub fn import_session(&self, archive: &SessionArchive, rows: u16, cols: u16, user: Option<&str>) -> Result<String> {
+        if self.multi_user && user.is_none() {
+            return Err(ErrorCode::BadRequest.err("importing a session in multi-user mode needs a user"));
+        }
+        let slot = self.reserve_slot()?;
         let id = uuid_short();
-        let session = PtySession::spawn(id.clone(), rows, cols, device_id, self.scrollback_bytes)
+        let session = self
+            .spawn_session(id.clone(), rows, cols, None, &Launch { user, ..Default::default() })
             .context("spawn session")?;
+        {
+            let mut scrollback = session.scrollback.lock().expect("scrollback lock");
+            scrollback.append(&archive.scrollback);
+            scrollback.append(archive.banner().as_bytes());
+        }
+
+        self.insert_session(slot, &id, session, None);
+
+        info!("imported session {} from {} as {id}", archive.session.id, archive.host);
+        Ok(id)
+    }
+
+    /// Live sessions whose terminal can outlive this process, each with a
+    /// duplicate of its PTY master, for handing to a new daemon on upgrade.
+    #[cfg(unix)]
+    pub fn handoff_sessions(&self) -> Vec<(HandoffSession, OwnedFd)> {
+        self.snapshot()
+            .into_iter()
+            .filter_map(|(_, session)| {
+                let mut s = session.lock().expect("session lock");
+                if s.damaged || !s.is_alive() {
+                    return None;
+                }
+                let (fd, pid) = s.backend.handoff()?;
+                // Duplicated under the session lock so a concurrent destroy
+                // can't close it before it is sent
+                let master = match unsafe { BorrowedFd::borrow_raw(fd) }.try_clone_to_owned() {
+                    Ok(master) => master,
+                    Err(e) => {
+                        warn!("session {} not handed off: dup PTY master: {e}", s.id);
+                        return None;
+                    }
+                };
+                let scrollback = s.scrollback.lock().expect("scrollback lock").read_from_clean_point();
+                let handoff = HandoffSession {
+                    session: ArchivedSession {
+                        id: s.id.clone(),
+                        shell: s.shell.clone(),
+                        created_at: s.created_at,
+                        created_by_device_id: s.created_by_device_id.clone(),
+                        tmux_session: s.tmux_session.clone(),
+                        last_activity_at: s.activity.get(),
+                    },
+                    pid,
+                    name: s.name.clone(),
+                    user: s.user.clone(),
+                    terminal: s.terminal.clone(),
+                    last_attached_at: s.last_attached_at,
+                    last_attached_by: s.last_attached_by.clone(),
+                    history: s.history.clone(),
+                    agent_forwarding: s.agent.is_some(),
+                    output_log: s.output_log.lock().expect("output log lock").is_some(),
+                    env: s.reported_env.lock().expect("reported env lock").clone(),
+                    scrollback,
+                };
+                Some((handoff, master))
+            })
+            .collect()
+    }
+
+    /// Take over a session handed off by the previous daemon process, under
+    /// its original id. It starts detached; clients reattach as usual.
+    #[cfg(unix)]
+    pub fn adopt_session(&self, handoff: HandoffSession, master: OwnedFd) -> Result<()> {
+        let HandoffSession { session: meta, pid, .. } = &handoff;
+        let mut session = self.spawn_session_with(meta.id.clone(), meta.created_by_device_id.as_deref(), || {
+            Ok(Box::new(AdoptedPty::new(master, *pid)))
+        })?;
+        session.created_at = meta.created_at;
+        session.shell = meta.shell.clone();
+        session.tmux_session = meta.tmux_session.clone();
+        session.name = handoff.name.clone();
+        session.user = handoff.user.clone();
+        session.terminal = handoff.terminal.clone();
+        session.last_attached_at = handoff.last_attached_at;
+        session.last_attached_by = handoff.last_attached_by.clone();
+        session.history = handoff.history.clone();
+        *session.reported_env.lock().expect("reported env lock") = handoff.env.clone();
+        session.activity.set(meta.last_activity_at);
+        session.scrollback.lock().expect("scrollback lock").append(&handoff.scrollback);
+        if let (true, Some(device_id)) = (handoff.agent_forwarding, &meta.created_by_device_id) {
+            // Same path as before, so the shell's SSH_AUTH_SOCK still works
+            match self.bind_agent(&meta.id, device_id) {
+                Ok(agent) => session.agent = Some(agent),
+                Err(e) => warn!("session {}: agent forwarding not restored: {e:#}", meta.id),
+            }
+        }
+        if handoff.output_log {
+            if let Some(dir) = &self.output_log_dir {
+                match OutputLog::open(dir, &meta.id, self.output_log_rotation) {
+                    Ok(log) => *session.output_log.lock().expect("output log lock") = Some(log),
+                    Err(e) => warn!("session {}: output logging not restored: {e:#}", meta.id),
+                }
+            }
+        }
 
         self.sessions
-            .lock()
+            .write()
             .expect("sessions lock")
-            .insert(id.clone(), Arc::new(Mutex::new(session)));
+            .insert(meta.id.clone(), Arc::new(Mutex::new(session)));
+        info!("adopted session {} (pid {pid})", meta.id);
+        Ok(())
+    }
 
-        info!("created session {id}");
-        Ok(id)
+    /// Take a live shell from the pre-warm pool, discarding any that have exited.
+    fn take_pooled(&self) -> Option<PtySession> {
+        let mut pool = self.pool.lock().expect("pool lock");
+        while let Some(mut session) = pool.pop() {
+            if session.is_alive() {
+                return Some(session);
+            }
+        }
+        None
+    }
+
+    /// Number of idle pre-warmed shells.
+    pub fn pooled(&self) -> usize {
+        self.pool.lock().expect("pool lock").len()
     }
 
     pub fn get_session(&self, id: &str) -> Option<Arc<Mutex<PtySession>>> {
-        self.sessions.lock().expect("sessions lock").get(id).cloned()
+        self.sessions.read().expect("sessions lock").get(id).cloned()
+    }
+
+    /// Clone out every session handle so callers can lock sessions one at a
+    /// time without holding the map lock.
+    fn snapshot(&self) -> Vec<(String, Arc<Mutex<PtySession>>)> {
+        self.sessions
+            .read()
+            .expect("sessions lock")
+            .iter()
+            .map(|(id, s)| (id.clone(), s.clone()))
+            .collect()
+    }
+
+    /// Sessions `device_id` has attached, in id order, e.g. through bridges
+    /// on a connection it just lost.
+    pub fn attached_by(&self, device_id: &str) -> Vec<String> {
+        let mut ids: Vec<String> = self
+            .snapshot()
+            .into_iter()
+            .filter(|(_, s)| {
+                let s = s.lock().expect("session lock");
+                s.attached && s.exited.is_none() && s.last_attached_by.as_deref() == Some(device_id)
+            })
+            .map(|(id, _)| id)
+            .collect();
+        ids.sort();
+        ids
     }
 
     pub fn list_sessions(&self) -> Vec<SessionInfo> {
-        let sessions = self.sessions.lock().expect("sessions lock");
-        sessions
-            .values()
-            .map(|s| {
+        self.snapshot()
+            .into_iter()
+            .map(|(_, s)| {
                 let mut s = s.lock().expect("session lock");
-                SessionInfo {
+                let cwd = s.backend.cwd();
+                let pid = s.backend.pid();
+                let reported_env = s.reported_env.lock().expect("reported env lock").clone();
+                let mut info = SessionInfo {
                     id: s.id.clone(),
-                    alive: matches!(s.child.try_wait(), Ok(None)) && !s.damaged,
+                    alive: s.is_alive() && !s.damaged,
                     created_at: s.created_at,
                     shell: s.shell.clone(),
+                    tmux_session: s.tmux_session.clone(),
+                    name: s.name.clone(),
+                    agent_forwarding: s.agent.is_some(),
                     attached: s.attached,
+                    mirrors: s.mirror_count(),
                     damaged: s.damaged,
+                    quarantined: s.quarantined,
+                    exit_code: s.exited.and_then(|(code, _)| code),
                     created_by_device_id: s.created_by_device_id.clone(),
+                    user: s.user.clone(),
+                    terminal: s.terminal.clone(),
                     last_attached_at: s.last_attached_at,
                     last_attached_by: s.last_attached_by.clone(),
-                    last_activity_at: s.last_activity_at,
-                }
+                    last_activity_at: s.activity.get(),
+                    project: None,
+                    env: EnvVars::new(),
+                };
+                // Walking the filesystem doesn't need the session
+                drop(s);
+                info.project = cwd.as_deref().and_then(Project::detect);
+                info.env = crate::env::snapshot(pid, &reported_env);
+                info
             })
             .collect()
     }
 
+    /// Deep diagnostics for session `id`, to debug one that won't reattach.
+    pub fn inspect_session(&self, id: &str) -> Option<SessionDiagnostics> {
+        let session = self.get_session(id)?;
+        let mut s = session.lock().expect("session lock");
+        let scrollback = s.scrollback.lock().expect("scrollback lock").usage();
+        Some(SessionDiagnostics {
+            id: s.id.clone(),
+            attached: s.attached,
+            damaged: s.damaged,
+            damaged_cause: s.damaged_cause.clone(),
+            quarantined: s.quarantined,
+            exit_code: s.exited.and_then(|(code, _)| code),
+            reader_available: s.reader.is_some(),
+            pty: s.pty_diagnostics(),
+            bridge_cancelled: s.bridge_cancel.as_ref().map(CancellationToken::is_cancelled),
+            bridge: s.bridge_probe.as_ref().map(|probe| probe.state()),
+            mirrors: s.mirror_count(),
+            scrollback,
+            compression: s.compression.report(),
+            budget: s.budget.as_ref().map(|budget| budget.report(s.backend.cpu_time())),
+            last_attached_at: s.last_attached_at,
+            last_attached_by: s.last_attached_by.clone(),
+        })
+    }
+
+    /// Counters of session `id`'s current attachment, for connection
+    /// quality indicators.
+    pub fn connection_stats(&self, id: &str) -> Result<ConnectionStats> {
+        let session = self.get_session(id).ok_or(SessionError::NotFound)?;
+        let s = session.lock().expect("session lock");
+        let probe = s.bridge_probe.as_ref().ok_or(SessionError::NotAttached)?;
+        Ok(probe.stats(s.resyncs))
+    }
+
+    /// Move attached bridges' traffic so far into the bandwidth ledger,
+    /// under the device driving each.
+    pub fn account_traffic(&self) {
+        for (id, session) in self.snapshot() {
+            let s = session.lock().expect("session lock");
+            if let (Some(probe), Some(device)) = (&s.bridge_probe, &s.last_attached_by) {
+                self.bandwidth.record(device, &id, probe.take_traffic());
+            }
+        }
+    }
+
     pub fn destroy_session(&self, id: &str) -> Result<()> {
+        self.destroy_session_by(id, None)
+    }
+
+    /// Destroy session `id` on behalf of device `by` (None: the host).
+    pub fn destroy_session_by(&self, id: &str, by: Option<&str>) -> Result<()> {
         let session = self
             .sessions
-            .lock()
+            .write()
             .expect("sessions lock")
             .remove(id)
-            .context("session not found")?;
+            .ok_or(SessionError::NotFound)?;
 
         let mut s = session.lock().expect("session lock");
 
@@ -281,31 +1397,147 @@ impl SessionManager {
             cancel.cancel();
         }
 
-        // Send SIGHUP to the process group
-        if let Some(pid) = s.child.process_id() {
-            #[cfg(unix)]
-            unsafe {
-                libc::killpg(pid as i32, libc::SIGHUP);
+        s.terminate(&self.tasks);
+        s.history.record(EventKind::Destroyed { by: by.map(str::to_string) });
+        self.retire(&mut s);
+
+        info!("destroyed session {id}");
+        Ok(())
+    }
+
+    /// Resize detached session `id` on behalf of device `by`, so a client
+    /// can size it to its screen before attaching. An attached session is
+    /// sized by its bridge.
+    pub fn resize_session(&self, id: &str, rows: u16, cols: u16, by: Option<&str>) -> Result<()> {
+        let session = self
+            .get_session(id)
+            .ok_or(SessionError::NotFound)?;
+        let mut s = session.lock().expect("session lock");
+        if s.attached {
+            return Err(SessionError::AlreadyAttached("session is attached; resize it through its bridge").into());
+        }
+        if s.damaged {
+            return Err(SessionError::Damaged.into());
+        }
+        if s.exited.is_some() {
+            return Err(SessionError::Exited.into());
+        }
+        let (rows, cols) = (rows.clamp(1, 500), cols.clamp(1, 500));
+        s.resize(rows, cols)?;
+        s.history.record(EventKind::Resized { by: by.map(str::to_string), rows, cols });
+        debug!("resized detached session {id} to {cols}x{rows}");
+        Ok(())
+    }
+
+    /// Start or stop logging session `id`'s output. Returns the log's path
+    /// while logging.
+    pub fn set_output_log(&self, id: &str, enabled: bool) -> Result<Option<PathBuf>> {
+        let dir = self
+            .output_log_dir
+            .as_ref()
+            .ok_or_else(|| ErrorCode::Unavailable.err("output logging is not enabled (it needs encrypt_scrollback off)"))?;
+        let session = self.get_session(id).ok_or(SessionError::NotFound)?;
+        let s = session.lock().expect("session lock");
+        let mut log = s.output_log.lock().expect("output log lock");
+        if !enabled {
+            if log.take().is_some() {
+                debug!("stopped logging output of session {id}");
             }
+            return Ok(None);
+        }
+        if log.is_none() {
+            *log = Some(OutputLog::open(dir, id, self.output_log_rotation)?);
+            debug!("logging output of session {id}");
+        }
+        Ok(log.as_ref().map(|l| l.path().to_path_buf()))
+    }
+
+    /// Signal the command running in the foreground of session `id`, to stop
+    /// a runaway command without losing the shell. Returns its process group.
+    pub fn kill_foreground(&self, id: &str, signal: i32) -> Result<u32> {
+        let session = self
+            .get_session(id)
+            .ok_or(SessionError::NotFound)?;
+        let s = session.lock().expect("session lock");
+        if s.exited.is_some() {
+            return Err(SessionError::Exited.into());
+        }
+        let pgid = s.backend.kill_foreground(signal)?;
+        debug!("sent signal {signal} to foreground process group {pgid} of session {id}");
+        Ok(pgid)
+    }
+
+    /// Type device `by`'s macro `name` into session `id`, attached or not.
+    /// Returns how many bytes were written.
+    pub fn run_macro(&self, id: &str, by: &str, name: &str) -> Result<usize> {
+        let recorded = self.macros.get(by, name)?;
+        let session = self
+            .get_session(id)
+            .ok_or(SessionError::NotFound)?;
+        let mut s = session.lock().expect("session lock");
+        if s.damaged {
+            return Err(SessionError::Damaged.into());
         }
+        if s.exited.is_some() {
+            return Err(SessionError::Exited.into());
+        }
+        s.writer
+            .lock()
+            .expect("pty writer lock")
+            .write_all(recorded.input.as_bytes())
+            .context("write macro input")?;
+        s.activity.touch();
+        s.history.record(EventKind::MacroRun { by: Some(by.to_string()), name: recorded.name });
+        debug!("ran macro of device {by} in session {id}");
+        Ok(recorded.input.len())
+    }
 
-        // Give it 2 seconds, then SIGKILL
-        let killer = s.child.clone_killer();
-        tokio::spawn(async move {
-            tokio::time::sleep(std::time::Duration::from_secs(2)).await;
-            let mut k = killer;
-            let _ = k.kill();
+    /// Keep a removed session's history around.
+    fn retire(&self, session: &mut PtySession) {
+        let mut ended = self.ended.lock().expect("ended sessions lock");
+        if ended.len() == MAX_ENDED_HISTORIES {
+            ended.pop_front();
+        }
+        ended.push_back(EndedSession {
+            id: session.id.clone(),
+            user: session.user.clone(),
+            created_by_device_id: session.created_by_device_id.clone(),
+            history: std::mem::take(&mut session.history),
         });
+    }
 
-        info!("destroyed session {id}");
+    /// Limit session `id` to `budget`, counting its lifetime from now.
+    pub fn set_budget(&self, id: &str, budget: Budget) -> Result<()> {
+        let session = self.get_session(id).ok_or(SessionError::NotFound)?;
+        session.lock().expect("session lock").budget = Some(BudgetTracker::new(budget, self.clock.clone()));
         Ok(())
     }
 
+    /// Timeline of session `id`, live or recently ended.
+    pub fn session_history(&self, id: &str) -> Option<HistoryView> {
+        if let Some(session) = self.get_session(id) {
+            let s = session.lock().expect("session lock");
+            return Some(HistoryView {
+                user: s.user.clone(),
+                created_by_device_id: s.created_by_device_id.clone(),
+                ended: false,
+                events: s.history.events(),
+            });
+        }
+        let ended = self.ended.lock().expect("ended sessions lock");
+        ended.iter().rev().find(|e| e.id == id).map(|e| HistoryView {
+            user: e.user.clone(),
+            created_by_device_id: e.created_by_device_id.clone(),
+            ended: true,
+            events: e.history.events(),
+        })
+    }
+
     /// Destroy all sessions (for graceful shutdown).
     pub fn destroy_all(&self) {
         let ids: Vec<String> = self
             .sessions
-            .lock()
+            .read()
             .expect("sessions lock")
             .keys()
             .cloned()
@@ -315,34 +1547,118 @@ impl SessionManager {
                 warn!("destroy_all: session {id}: {e}");
             }
         }
+        for mut session in self.pool.lock().expect("pool lock").drain(..) {
+            session.terminate(&self.tasks);
+        }
     }
 
     pub fn register_connection(&self, device_id: &str, conn: &quinn::Connection) {
-        let mut conns = self.connections.lock().expect("connections lock");
+        let mut conns = self.connections.write().expect("connections lock");
         // Tear down old connection from same device (stale)
         if let Some(old) = conns.insert(device_id.to_string(), conn.clone()) {
             warn!("replacing stale connection for device {device_id}");
-            old.close(quinn::VarInt::from_u32(0), b"replaced");
+            crate::connections::close(&old, CloseCode::Replaced, b"replaced");
         }
     }
 
     pub fn unregister_connection(&self, device_id: &str) {
         self.connections
-            .lock()
+            .write()
             .expect("connections lock")
             .remove(device_id);
     }
 
+    /// Close every device connection with `code`, e.g. so clients reconnect
+    /// right away.
+    pub fn close_connections(&self, code: CloseCode) {
+        for conn in self.connections.read().expect("connections lock").values() {
+            crate::connections::close(conn, code, code.name().as_bytes());
+        }
+    }
+
+    /// Close `device_id`'s connection with `code`, if it's connected.
+    pub fn close_connection(&self, device_id: &str, code: CloseCode) -> bool {
+        let conns = self.connections.read().expect("connections lock");
+        let Some(conn) = conns.get(device_id) else { return false };
+        crate::connections::close(conn, code, code.name().as_bytes());
+        true
+    }
+
+    /// The ALPN protocol the device's connection negotiated.
+    pub fn connected_protocol(&self, device_id: &str) -> Option<String> {
+        let conns = self.connections.read().expect("connections lock");
+        conns.get(device_id).and_then(crate::tls::negotiated_protocol)
+    }
+
     /// Return the device IDs of all currently connected devices.
     pub fn connected_device_ids(&self) -> Vec<String> {
         self.connections
-            .lock()
+            .read()
             .expect("connections lock")
             .keys()
             .cloned()
             .collect()
     }
 
+    /// Keep the pre-warm pool at its target size, spawning shells off the
+    /// async runtime. Refills whenever create_session draws from the pool.
+    pub async fn run_prewarm(self: &Arc<Self>, cancel: CancellationToken) {
+        if self.prewarm == 0 {
+            return;
+        }
+        loop {
+            let missing = {
+                let mut pool = self.pool.lock().expect("pool lock");
+                pool.retain_mut(|s| s.is_alive());
+                self.prewarm.saturating_sub(pool.len())
+            };
+            for _ in 0..missing {
+                let sm = self.clone();
+                let spawned = self.tasks.spawn_blocking(move || {
+                    sm.spawn_session(uuid_short(), 24, 80, None, &Launch::default())
+                })
+                .await;
+                match spawned {
+                    Ok(Ok(session)) => self.pool.lock().expect("pool lock").push(session),
+                    Ok(Err(e)) => warn!("pre-warm spawn failed: {e:#}"),
+                    Err(e) => warn!("pre-warm task failed: {e}"),
+                }
+                if cancel.is_cancelled() {
+                    return;
+                }
+            }
+
+            tokio::select! {
+                _ = self.pool_notify.notified() => {}
+                _ = cancel.cancelled() => break,
+            }
+        }
+    }
+
+    /// Compress the scrollback of detached sessions, least recently active
+    /// first, until the budget is out of pressure (or all of them if `force`).
+    pub fn compact_detached(&self, force: bool) {
+        let mut detached: Vec<_> = self
+            .snapshot()
+            .into_iter()
+            .filter_map(|(id, s)| {
+                let session = s.lock().expect("session lock");
+                (!session.attached)
+                    .then(|| (session.activity.get(), id, session.scrollback.clone()))
+            })
+            .collect();
+        detached.sort_by_key(|(activity, _, _)| *activity);
+
+        for (_, id, scrollback) in detached {
+            if !force && !self.budget.under_pressure() {
+                break;
+            }
+            if scrollback.lock().expect("scrollback lock").compact() {
+                debug!("compacted scrollback of detached session {id}");
+            }
+        }
+    }
+
     /// Run the session reaper: check for dead sessions periodically.
     pub async fn run_reaper(self: &Arc<Self>, cancel: CancellationToken, interval_secs: u64) {
         let mut interval = tokio::time::interval(std::time::Duration::from_secs(interval_secs));
@@ -352,50 +1668,213 @@ impl SessionManager {
                 _ = cancel.cancelled() => break,
             }
 
-            let session_ids: Vec<String> = {
-                self.sessions
-                    .lock()
-                    .expect("sessions lock")
-                    .keys()
-                    .cloned()
-                    .collect()
-            };
+            if self.budget.under_pressure() {
+                self.compact_detached(false);
+            }
+            self.reap();
+        }
+    }
 
-            for id in session_ids {
-                let session = {
-                    self.sessions.lock().expect("sessions lock").get(&id).cloned()
-                };
-                if let Some(session) = session {
-                    let mut s = session.lock().expect("session lock");
-                    match s.child.try_wait() {
-                        Ok(Some(status)) => {
-                            info!("session {id} exited: {status}");
-                            // Cancel bridge if active
+    /// One reaper pass: notice exited sessions and remove them once their
+    /// retention is up, destroy or quarantine damaged ones, and enforce
+    /// budgets (see [`crate::budget`]).
+    pub fn reap(&self) {
+        for (id, session) in self.snapshot() {
+            let mut s = session.lock().expect("session lock");
+            if let Some((code, at)) = s.exited {
+                if self.clock.now().saturating_duration_since(at) >= self.exited_retention {
+                    drop(s);
+                    self.remove_reaped(&id, "exited", code);
+                }
+                continue;
+            }
+            match s.backend.try_wait() {
+                Ok(Some(code)) => {
+                    match code {
+                        Some(code) => info!("session {id} exited with code {code}"),
+                        None => info!("session {id} exited"),
+                    }
+                    self.hooks.fire(HookEvent::SessionExited { session_id: id.clone(), exit_code: code });
+                    // Cancel bridge if active
+                    if let Some(cancel) = s.bridge_cancel.take() {
+                        cancel.cancel();
+                    }
+                    s.history.record(EventKind::Exited { exit_code: code });
+                    s.exited = Some((code, self.clock.now()));
+                    if self.exited_retention.is_zero() {
+                        drop(s);
+                        self.remove_reaped(&id, "exited", code);
+                    }
+                }
+                // Damaged sessions: the PTY reader is unrecoverable
+                Ok(None) if s.damaged && !s.attached => match self.damaged_policy {
+                    DamagedPolicy::Destroy => {
+                        if let Some(cancel) = s.bridge_cancel.take() {
+                            cancel.cancel();
+                        }
+                        s.history.record(EventKind::Destroyed { by: None });
+                        drop(s);
+                        self.remove_reaped(&id, "damaged", None);
+                    }
+                    DamagedPolicy::Quarantine if !s.quarantined => {
+                        warn!("quarantined damaged session {id}; destroy it when done inspecting");
+                        s.quarantined = true;
+                        self.hooks.fire(HookEvent::SessionQuarantined { session_id: id.clone() });
+                    }
+                    DamagedPolicy::Quarantine => {}
+                },
+                Ok(None) if s.budget.is_some() => {
+                    let cpu = s.backend.cpu_time();
+                    let verdict = s.budget.as_mut().expect("budgeted").check(cpu);
+                    match verdict {
+                        Verdict::Within => {}
+                        Verdict::Warn { resource, used, limit } => {
+                            info!("session {id} has used {used:?} of its {limit:?} {resource:?} budget");
+                            let event = serde_json::json!({
+                                "type": "session_budget_warning",
+                                "session_id": id,
+                                "resource": resource,
+                                "used_secs": used.as_secs(),
+                                "limit_secs": limit.as_secs(),
+                            });
+                            self.notifier().notify_session(&s, &event);
+                        }
+                        Verdict::Exceeded(resource) => {
+                            warn!("session {id} is over its {resource:?} budget; terminating it");
                             if let Some(cancel) = s.bridge_cancel.take() {
                                 cancel.cancel();
                             }
+                            s.terminate(&self.tasks);
+                            s.history.record(EventKind::OverBudget { resource });
                             drop(s);
-                            self.sessions.lock().expect("sessions lock").remove(&id);
-                        }
-                        Ok(None) => {
-                            // Reap damaged sessions (PTY reader unrecoverable)
-                            if s.damaged && !s.attached {
-                                info!("reaping damaged session {id}");
-                                if let Some(cancel) = s.bridge_cancel.take() {
-                                    cancel.cancel();
-                                }
-                                drop(s);
-                                self.sessions.lock().expect("sessions lock").remove(&id);
-                            }
-                        }
-                        Err(e) => {
-                            warn!("session {id} try_wait error: {e}");
+                            self.remove_reaped(&id, resource.reason(), None);
                         }
                     }
                 }
+                Ok(None) => {}
+                Err(e) => {
+                    warn!("session {id} try_wait error: {e}");
+                }
             }
         }
     }
+
+    fn remove_reaped(&self, id: &str, reason: &'static str, exit_code: Option<u32>) {
+        let Some(session) = self.sessions.write().expect("sessions lock").remove(id) else {
+            return;
+        };
+        info!("reaped {reason} session {id}");
+        let mut s = session.lock().expect("session lock");
+        self.retire(&mut s);
+        let event = serde_json::json!({
+            "type": "session_removed",
+            "session_id": id,
+            "reason": reason,
+            "exit_code": exit_code,
+        });
+        self.notifier().notify_session(&s, &event);
+        drop(s);
+        self.hooks.fire(HookEvent::SessionReaped { session_id: id.to_string(), reason, exit_code });
+    }
+}
+
+/// Answer to `inspect_session`.
+#[derive(Debug, serde::Serialize)]
+pub struct SessionDiagnostics {
+    pub id: String,
+    pub attached: bool,
+    pub damaged: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub damaged_cause: Option<String>,
+    pub quarantined: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exit_code: Option<u32>,
+    /// A PTY reader is parked for the next attach (a bridge holds it while attached)
+    pub reader_available: bool,
+    pub pty: PtyDiagnostics,
+    /// Whether the bridge cancel token has fired; None when there's no bridge
+    pub bridge_cancelled: Option<bool>,
+    /// Tasks, queue and flow control window of the attached bridge
+    pub bridge: Option<BridgeState>,
+    pub mirrors: usize,
+    pub scrollback: ScrollbackUsage,
+    /// Output frames compressed, and skipped, over the session's life
+    pub compression: CompressionReport,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub budget: Option<BudgetReport>,
+    pub last_attached_at: Option<chrono::DateTime<chrono::Utc>>,
+    pub last_attached_by: Option<String>,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct PtyDiagnostics {
+    /// PTY master; None for backends without one (tests, ConPTY)
+    pub fd: Option<i32>,
+    pub fd_open: bool,
+    pub pid: Option<u32>,
+    pub process_alive: bool,
+}
+
+#[cfg(unix)]
+fn fd_open(fd: i32) -> bool {
+    unsafe { libc::fcntl(fd, libc::F_GETFD) != -1 }
+}
+
+#[cfg(not(unix))]
+fn fd_open(_fd: i32) -> bool {
+    false
+}
+
+/// Pushes events to connected devices, each on a unidirectional stream of
+/// its own as length-prefixed JSON (like control responses).
+#[derive(Clone)]
+pub struct DeviceNotifier(Arc<RwLock<HashMap<String, quinn::Connection>>>);
+
+impl DeviceNotifier {
+    /// Send `event` to `device_id` if it's connected, or with `None` to every
+    /// connected device.
+    pub fn notify(&self, device_id: Option<&str>, event: &serde_json::Value) {
+        self.notify_where(|id| device_id.is_none_or(|device_id| id == device_id), event);
+    }
+
+    /// Send `event` to each connected device whose id passes `to`.
+    pub fn notify_where(&self, to: impl Fn(&str) -> bool, event: &serde_json::Value) {
+        // Nothing to deliver on outside a runtime (the reaper in sync tests)
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
+        let json = serde_json::to_vec(event).expect("serialize event");
+        let conns: Vec<_> = {
+            let conns = self.0.read().expect("connections lock");
+            conns.iter().filter(|(id, _)| to(id)).map(|(_, conn)| conn.clone()).collect()
+        };
+        for conn in conns {
+            let json = json.clone();
+            runtime.spawn(async move {
+                let sent = async {
+                    let mut send = conn.open_uni().await?;
+                    send.write_all(&(json.len() as u32).to_be_bytes()).await?;
+                    send.write_all(&json).await?;
+                    send.finish()?;
+                    anyhow::Ok(())
+                };
+                if let Err(e) = sent.await {
+                    debug!("event not delivered: {e:#}");
+                }
+            });
+        }
+    }
+
+    /// Send `event` about `session` to the devices that list it: all of
+    /// them, or in multi-user mode the one that created it.
+    pub fn notify_session(&self, session: &PtySession, event: &serde_json::Value) {
+        let device = match (&session.user, &session.created_by_device_id) {
+            (None, _) => None,
+            (Some(_), Some(device)) => Some(device.as_str()),
+            (Some(_), None) => return,
+        };
+        self.notify(device, event);
+    }
 }
 
 #[derive(Debug, serde::Serialize)]
@@ -404,16 +1883,46 @@ pub struct SessionInfo {
     pub alive: bool,
     pub created_at: chrono::DateTime<chrono::Utc>,
     pub shell: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tmux_session: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub agent_forwarding: bool,
     pub attached: bool,
+    /// Devices watching through `mirror_session`
+    pub mirrors: usize,
     #[serde(skip_serializing_if = "std::ops::Not::not")]
     pub damaged: bool,
+    /// Damaged and kept for inspection; destroy it when done
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub quarantined: bool,
+    /// Set on exited sessions kept for `exited_retention_secs`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exit_code: Option<u32>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub created_by_device_id: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    pub terminal: TerminalCaps,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub last_attached_at: Option<chrono::DateTime<chrono::Utc>>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub last_attached_by: Option<String>,
     pub last_activity_at: chrono::DateTime<chrono::Utc>,
+    /// Git project the shell is working in
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub project: Option<Project>,
+    /// Context the shell is pointed at: virtualenv, AWS profile, ...
+    #[serde(skip_serializing_if = "EnvVars::is_empty")]
+    pub env: EnvVars,
+}
+
+impl SessionInfo {
+    /// Whether the session is in `project`, by name or root.
+    pub fn in_project(&self, project: &str) -> bool {
+        self.project.as_ref().is_some_and(|p| p.matches(project))
+    }
 }
 
 fn uuid_short() -> String {
@@ -426,6 +1935,215 @@ fn uuid_short() -> String {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::budget::Resource;
+    use crate::clock::ManualClock;
+    use crate::terminal::{ScriptHandle, ScriptedTerminal};
+
+    /// Manager whose sessions run scripted terminals; handles are collected
+    /// in spawn order.
+    fn scripted_manager() -> (Arc<SessionManager>, Arc<Mutex<Vec<ScriptHandle>>>) {
+        let handles = Arc::new(Mutex::new(Vec::new()));
+        let spawned = handles.clone();
+        let sm = SessionManager::new().with_spawner(ScriptedTerminal::spawner(false, move |h| {
+            spawned.lock().unwrap().push(h)
+        }));
+        (Arc::new(sm), handles)
+    }
+
+    #[test]
+    fn scripted_session_lifecycle() {
+        let (sm, handles) = scripted_manager();
+        let terminal = TerminalCaps { truecolor: true, ..Default::default() };
+        let launch = Launch { terminal: Some(&terminal), ..Default::default() };
+        let id = sm.create_session(30, 100, Some("dev"), launch).unwrap();
+        let handle = handles.lock().unwrap()[0].clone();
+        assert_eq!(handle.size(), (30, 100));
+        assert_eq!(sm.list_sessions()[0].terminal, terminal);
+
+        let session = sm.get_session(&id).unwrap();
+        session.lock().unwrap().resize(40, 120).unwrap();
+        assert_eq!(handle.size(), (40, 120));
+        assert!(sm.list_sessions()[0].alive);
+
+        handle.exit(0);
+        assert!(!sm.list_sessions()[0].alive);
+
+        sm.destroy_session(&id).unwrap();
+        assert!(handle.terminated());
+        assert!(sm.list_sessions().is_empty());
+    }
+
+    #[test]
+    fn input_marks_are_per_device() {
+        let marks = InputMarks::default();
+        marks.device("phone").fetch_max(7, Ordering::Relaxed);
+        assert_eq!(marks.device("phone").load(Ordering::Relaxed), 7);
+        // Another device's frames are numbered from its own start
+        assert_eq!(marks.device("laptop").load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn exported_history_replays_in_imported_session() {
+        let (sm, _handles) = scripted_manager();
+        let id = sm.create_session(24, 80, Some("dev"), Launch::default()).unwrap();
+        let scrollback = sm.get_session(&id).unwrap().lock().unwrap().scrollback.clone();
+        scrollback.lock().unwrap().append(b"$ cargo build\r\nFinished\r\n");
+
+        let archive = sm.export_session(&id).unwrap();
+        assert_eq!(archive.session.id, id);
+        assert_eq!(archive.session.created_by_device_id.as_deref(), Some("dev"));
+        assert!(sm.export_session("missing").is_err());
+
+        let imported = sm.import_session(&archive, 24, 80, None).unwrap();
+        assert_ne!(imported, id);
+        let scrollback = sm.get_session(&imported).unwrap().lock().unwrap().scrollback.clone();
+        let history = scrollback.lock().unwrap().read_from_clean_point();
+        assert!(history.starts_with(b"$ cargo build\r\nFinished\r\n"));
+        let banner = String::from_utf8_lossy(&history);
+        assert!(banner.contains(&format!("restored session {id}")), "{banner}");
+
+        // Multi-user hosts have no shell to fall back on
+        let mut config = DaemonConfig::default();
+        config.system.multi_user = true;
+        let sm = SessionManager::with_config(&config).with_spawner(ScriptedTerminal::spawner(false, |_| {}));
+        let err = sm.import_session(&archive, 24, 80, None).unwrap_err();
+        assert_eq!(ErrorCode::of(&err), ErrorCode::BadRequest);
+    }
+
+    #[test]
+    fn session_limit_refuses_new_sessions() {
+        let mut config = DaemonConfig::default();
+        config.session.max_sessions = 1;
+        let sm = SessionManager::with_config(&config).with_spawner(ScriptedTerminal::spawner(false, |_| {}));
+        assert_eq!(sm.max_sessions(), Some(1));
+        let id = sm.create_session(24, 80, None, Launch::default()).unwrap();
+        let err = sm.create_session(24, 80, None, Launch::default()).unwrap_err();
+        assert!(err.to_string().contains("session limit reached"), "{err}");
+        assert_eq!(ErrorCode::of(&err), ErrorCode::LimitExceeded);
+
+        sm.destroy_session(&id).unwrap();
+        sm.create_session(24, 80, None, Launch::default()).unwrap();
+    }
+
+    #[test]
+    fn session_limit_holds_for_concurrent_creates() {
+        let mut config = DaemonConfig::default();
+        config.session.max_sessions = 2;
+        // Slow spawns, so every create is checked before any is inserted
+        let sm = SessionManager::with_config(&config)
+            .with_spawner(ScriptedTerminal::spawner(false, |_| std::thread::sleep(Duration::from_millis(50))));
+        let created = std::thread::scope(|scope| {
+            let creates: Vec<_> = (0..6)
+                .map(|_| scope.spawn(|| sm.create_session(24, 80, None, Launch::default()).is_ok()))
+                .collect();
+            creates.into_iter().map(|create| create.join().unwrap()).filter(|&ok| ok).count()
+        });
+        assert_eq!(created, 2);
+        assert_eq!(sm.list_sessions().len(), 2);
+
+        // Failed spawns give their slot back
+        let sm = SessionManager::with_config(&config).with_spawner(Arc::new(|_, _, _| anyhow::bail!("no PTYs")));
+        for _ in 0..3 {
+            assert!(sm.create_session(24, 80, None, Launch::default()).is_err());
+        }
+        assert_eq!(*sm.spawning.lock().unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn reaper_removes_exited_sessions() {
+        let (sm, handles) = scripted_manager();
+        let exited = sm.create_session(24, 80, None, Launch::default()).unwrap();
+        let running = sm.create_session(24, 80, None, Launch::default()).unwrap();
+        handles.lock().unwrap()[0].exit(1);
+
+        let cancel = CancellationToken::new();
+        let reaper = {
+            let sm = sm.clone();
+            let cancel = cancel.clone();
+            tokio::spawn(async move { sm.run_reaper(cancel, 60).await })
+        };
+        // The first interval tick fires immediately
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        cancel.cancel();
+        reaper.await.unwrap();
+
+        assert!(sm.get_session(&exited).is_none());
+        assert!(sm.get_session(&running).is_some());
+    }
+
+    #[test]
+    fn reaper_policies_keep_exited_and_quarantine_damaged() {
+        let mut config = DaemonConfig::default();
+        config.session.exited_retention_secs = 3600;
+        config.session.damaged = DamagedPolicy::Quarantine;
+        config.session.max_sessions = 2;
+        let handles = Arc::new(Mutex::new(Vec::new()));
+        let spawned = handles.clone();
+        let clock = ManualClock::new();
+        let sm = SessionManager::with_config(&config)
+            .with_spawner(ScriptedTerminal::spawner(false, move |h| spawned.lock().unwrap().push(h)))
+            .with_clock(clock.clone());
+        let exited = sm.create_session(24, 80, None, Launch::default()).unwrap();
+        let damaged = sm.create_session(24, 80, None, Launch::default()).unwrap();
+        handles.lock().unwrap()[0].exit(3);
+        sm.get_session(&damaged).unwrap().lock().unwrap().damaged = true;
+
+        sm.reap();
+        sm.reap();
+        let info = |id: &str| sm.list_sessions().into_iter().find(|s| s.id == id).unwrap();
+        assert_eq!(info(&exited).exit_code, Some(3));
+        assert!(info(&damaged).quarantined);
+        // The exited session no longer counts toward the limit
+        let third = sm.create_session(24, 80, None, Launch::default()).unwrap();
+
+        // Retention over: removed, with its history kept
+        clock.advance(Duration::from_secs(3599));
+        sm.reap();
+        assert!(sm.get_session(&exited).is_some());
+        clock.advance(Duration::from_secs(1));
+        sm.reap();
+        assert!(sm.get_session(&exited).is_none());
+        let history = sm.session_history(&exited).unwrap();
+        assert!(history.ended);
+        assert_eq!(history.events.last().unwrap().kind, EventKind::Exited { exit_code: Some(3) });
+        assert!(sm.get_session(&damaged).is_some());
+        assert!(sm.get_session(&third).is_some());
+    }
+
+    #[test]
+    fn reaper_enforces_session_budgets() {
+        let handles = Arc::new(Mutex::new(Vec::new()));
+        let spawned = handles.clone();
+        let clock = ManualClock::new();
+        let sm = SessionManager::new()
+            .with_spawner(ScriptedTerminal::spawner(false, move |h| spawned.lock().unwrap().push(h)))
+            .with_clock(clock.clone());
+        let cpu_bound = sm.create_session(24, 80, None, Launch::default()).unwrap();
+        let short_lived = sm.create_session(24, 80, None, Launch::default()).unwrap();
+        sm.set_budget(&cpu_bound, Budget { cpu_secs: Some(60), lifetime_secs: None }).unwrap();
+        sm.set_budget(&short_lived, Budget { cpu_secs: None, lifetime_secs: Some(600) }).unwrap();
+        let cpu = handles.lock().unwrap()[0].clone();
+
+        // Warned at 90%, once
+        cpu.use_cpu(Duration::from_secs(54));
+        clock.advance(Duration::from_secs(300));
+        sm.reap();
+        let budget = sm.inspect_session(&cpu_bound).unwrap().budget.unwrap();
+        assert!(budget.warned);
+        assert_eq!(budget.cpu_used_secs, Some(54.0));
+        assert!(!sm.inspect_session(&short_lived).unwrap().budget.unwrap().warned);
+
+        // Over budget: terminated and removed, saying why
+        cpu.use_cpu(Duration::from_secs(6));
+        clock.advance(Duration::from_secs(300));
+        sm.reap();
+        assert!(sm.get_session(&cpu_bound).is_none());
+        assert!(cpu.terminated());
+        let history = sm.session_history(&cpu_bound).unwrap();
+        assert_eq!(history.events.last().unwrap().kind, EventKind::OverBudget { resource: Resource::Cpu });
+        assert!(sm.get_session(&short_lived).is_none());
+        assert!(handles.lock().unwrap()[1].terminated());
+    }
 
     #[test]
     fn scrollback_empty() {
@@ -441,6 +2159,90 @@ mod tests {
         assert_eq!(data, b"hello world");
     }
 
+    #[test]
+    fn scrollback_compacts_and_expands() {
+        let budget = MemoryBudget::new(1 << 20, 50);
+        let mut sb = ScrollbackBuffer::new(4096);
+        sb.set_reservation(budget.try_reserve(4096).unwrap());
+        for i in 0..600 {
+            sb.append(format!("line {i}\r\n").as_bytes());
+        }
+        let before = sb.read_from_clean_point();
+
+        assert!(sb.compact());
+        assert!(sb.is_compacted());
+        assert!(budget.used() < 4096);
+        assert_eq!(sb.read_from_clean_point(), before);
+
+        // Appending restores the ring and its full reservation
+        sb.append(b"more");
+        assert!(!sb.is_compacted());
+        assert_eq!(budget.used(), 4096);
+        let after = sb.read_from_clean_point();
+        assert_eq!(after.len(), 4096);
+        assert!(after.ends_with(b"line 599\r\nmore"));
+    }
+
+    #[test]
+    fn scrollback_tail_survives_wrap_and_compaction() {
+        let mut sb = ScrollbackBuffer::new(TAIL_BYTES + 100);
+        sb.append(b"short");
+        assert_eq!(sb.tail(), b"short");
+
+        for i in 0..1000 {
+            sb.append(format!("line {i}\r\n").as_bytes());
+        }
+        let tail = sb.tail();
+        assert_eq!(tail.len(), TAIL_BYTES);
+        assert!(sb.read_from_clean_point().ends_with(&tail));
+        assert!(tail.ends_with(b"line 999\r\n"));
+
+        assert!(sb.compact());
+        assert_eq!(sb.tail(), tail);
+    }
+
+    #[test]
+    fn scrollback_pages_by_output_offset() {
+        let mut sb = ScrollbackBuffer::new(10);
+        sb.append(b"0123456789");
+        sb.append(b"abcdef");
+        // Holds "6789abcdef", output offsets 6..16
+        assert_eq!((sb.start_offset(), sb.end_offset()), (6, 16));
+        assert_eq!(sb.page(16, 4), (12, b"cdef".to_vec()));
+        assert_eq!(sb.page(12, 4), (8, b"89ab".to_vec()));
+        // Clamped to what's held
+        assert_eq!(sb.page(8, 4), (6, b"67".to_vec()));
+        assert_eq!(sb.page(6, 4), (6, Vec::new()));
+        assert_eq!(sb.page(u64::MAX, 3), (13, b"def".to_vec()));
+
+        sb.compact();
+        assert_eq!(sb.page(12, 4), (8, b"89ab".to_vec()));
+    }
+
+    #[test]
+    fn scrollback_marks_size_changes_by_offset() {
+        let mark = |offset, rows, cols| SizeMark { offset, rows, cols };
+        let mut sb = ScrollbackBuffer::new(10);
+        sb.record_size(24, 80);
+        sb.append(b"01234");
+        sb.record_size(50, 132);
+        // Resized back and forth without output in between
+        sb.record_size(40, 100);
+        sb.record_size(50, 132);
+        sb.append(b"56789");
+        sb.record_size(24, 80);
+        sb.append(b"abc");
+        assert_eq!(sb.sizes(3, 13), [mark(3, 24, 80), mark(5, 50, 132), mark(10, 24, 80)]);
+        assert_eq!(sb.sizes(6, 10), [mark(6, 50, 132)]);
+
+        // Sizes older than what's held go, except the one in force at its start
+        sb.append(b"defghij");
+        sb.record_size(30, 90);
+        assert_eq!(sb.start_offset(), 10);
+        assert_eq!(sb.sizes(10, u64::MAX), [mark(10, 24, 80), mark(20, 30, 90)]);
+        assert_eq!(sb.sizes.len(), 2);
+    }
+
     #[test]
     fn scrollback_wraps_at_capacity() {
         let mut sb = ScrollbackBuffer::new(16);
@@ -514,6 +2316,17 @@ mod tests {
         assert_eq!(&data, b"EFGH");
     }
 
+    #[test]
+    fn activity_clock_tracks_touch() {
+        let clock = ActivityClock::new();
+        let before = clock.get();
+        std::thread::sleep(std::time::Duration::from_millis(5));
+        clock.touch();
+        let after = clock.get();
+        assert!(after > before);
+        assert!((chrono::Utc::now() - after).num_seconds() < 1);
+    }
+
     #[test]
     fn throughput_scrollback_append() {
         let capacity = 65536;
@@ -522,10 +2335,13 @@ mod tests {
         let iterations = 10_000;
 
         let start = std::time::Instant::now();
-        for _ in 0..iterations {
-            sb.append(&chunk);
-        }
+        let ((), allocs) = crate::alloc_counter::count(|| {
+            for _ in 0..iterations {
+                sb.append(&chunk);
+            }
+        });
         let elapsed = start.elapsed();
+        assert_eq!(allocs, 0, "scrollback append must not allocate");
         let throughput_mb = (iterations as f64 * 4096.0) / elapsed.as_secs_f64() / 1_048_576.0;
         eprintln!("[bench] scrollback append 4KB x {iterations}: {elapsed:?} ({throughput_mb:.1} MB/s)");
         assert!(throughput_mb > 500.0, "scrollback append throughput too low: {throughput_mb:.1} MB/s");
@@ -540,11 +2356,14 @@ mod tests {
 
         let iterations = 10_000;
         let start = std::time::Instant::now();
-        for _ in 0..iterations {
-            let data = sb.read_from_clean_point();
-            assert_eq!(data.len(), capacity);
-        }
+        let ((), allocs) = crate::alloc_counter::count(|| {
+            for _ in 0..iterations {
+                let data = sb.read_from_clean_point();
+                assert_eq!(data.len(), capacity);
+            }
+        });
         let elapsed = start.elapsed();
+        assert_eq!(allocs, iterations, "scrollback read should allocate exactly once per call");
         let throughput_mb = (iterations as f64 * capacity as f64) / elapsed.as_secs_f64() / 1_048_576.0;
         eprintln!("[bench] scrollback read 64KB x {iterations}: {elapsed:?} ({throughput_mb:.1} MB/s)");
         assert!(throughput_mb > 1000.0, "scrollback read throughput too low: {throughput_mb:.1} MB/s");
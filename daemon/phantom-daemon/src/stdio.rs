@@ -0,0 +1,188 @@
+//! `phantom stdio`: the client protocol over stdin/stdout, for programs that
+//! would rather not speak QUIC (editors, test drivers, an SSH
+//! `ProxyCommand`-style wrapper on another machine).
+//!
+//! The relay connects to the running daemon over loopback and carries each
+//! of the connection's streams as chunks (see [`phantom_frame::stdio`]).
+//! What goes in them is the protocol as is, auth included: the program on
+//! the other end authenticates as a paired device like any other client.
+
+use anyhow::{bail, Context, Result};
+use phantom_frame::stdio::{self, Chunk, DAEMON_STREAMS};
+use phantom_frame::CloseCode;
+use quinn::{RecvStream, SendStream};
+use std::collections::hash_map::{Entry, HashMap};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::Path;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc;
+use tracing::debug;
+
+use crate::{ipc, tls};
+
+/// Chunks queued for the output before stream readers wait.
+const OUTPUT_QUEUE: usize = 64;
+
+/// Connect to the daemon running on `phantom_dir`, trusting only the
+/// certificate there.
+pub async fn connect_local(phantom_dir: &Path) -> Result<quinn::Connection> {
+    let status = ipc::call(phantom_dir, "status", serde_json::json!({})).await?;
+    let bind: SocketAddr = status["bind_address"]
+        .as_str()
+        .context("daemon status has no bind address")?
+        .parse()
+        .context("parse the daemon's bind address")?;
+    let (ip, any) = match bind.ip() {
+        IpAddr::V4(_) => (Ipv4Addr::LOCALHOST.into(), Ipv4Addr::UNSPECIFIED.into()),
+        IpAddr::V6(_) => (Ipv6Addr::LOCALHOST.into(), Ipv6Addr::UNSPECIFIED.into()),
+    };
+    let addr = if bind.ip().is_unspecified() { SocketAddr::new(ip, bind.port()) } else { bind };
+
+    let (cert_der, _) = tls::Identity::load(phantom_dir)?
+        .context("no certificate in the data directory")?
+        .to_der()?;
+    let mut endpoint = quinn::Endpoint::client(SocketAddr::new(any, 0)).context("create QUIC endpoint")?;
+    endpoint.set_default_client_config(tls::build_client_config(&cert_der)?);
+    endpoint
+        .connect(addr, "phantom.local")?
+        .await
+        .with_context(|| format!("connect to the daemon at {addr}"))
+}
+
+/// Carry `conn`'s streams over `input` and `output` until either ends. The
+/// end of `input` closes the connection; the connection closing by
+/// application error ends `output` with a [`stdio::CONNECTION`] chunk.
+pub async fn relay<R, W>(conn: quinn::Connection, mut input: R, output: W) -> Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin + Send + 'static,
+{
+    let (chunks, queued) = mpsc::channel(OUTPUT_QUEUE);
+    let writer = tokio::spawn(write_chunks(queued, output));
+
+    // Send sides by stream ID; None once finished, so an ID isn't reused
+    let mut sends: HashMap<u32, Option<SendStream>> = HashMap::new();
+    let mut next_daemon_stream = DAEMON_STREAMS;
+    let mut pending = Vec::new();
+    let mut input_ended = false;
+    let result = loop {
+        tokio::select! {
+            read = input.read_buf(&mut pending) => {
+                match read {
+                    Ok(0) => {
+                        conn.close(CloseCode::Normal.code().into(), b"input closed");
+                        input_ended = true;
+                        break Ok(());
+                    }
+                    Ok(_) => {}
+                    Err(e) => break Err(anyhow::Error::from(e).context("read input")),
+                }
+                match forward(&conn, &mut sends, &chunks, &pending).await {
+                    Ok(consumed) => {
+                        pending.drain(..consumed);
+                    }
+                    Err(e) => {
+                        conn.close(CloseCode::Normal.code().into(), b"relay error");
+                        break Err(e);
+                    }
+                }
+            }
+            Ok(recv) = conn.accept_uni() => {
+                tokio::spawn(read_stream(next_daemon_stream, recv, chunks.clone()));
+                next_daemon_stream += 1;
+            }
+            Ok((send, recv)) = conn.accept_bi() => {
+                sends.insert(next_daemon_stream, Some(send));
+                tokio::spawn(read_stream(next_daemon_stream, recv, chunks.clone()));
+                next_daemon_stream += 1;
+            }
+            e = conn.closed() => {
+                if let quinn::ConnectionError::ApplicationClosed(close) = &e {
+                    let code = close.error_code.into_inner() as u32;
+                    let _ = chunks.send(stdio::encode_close(code, &String::from_utf8_lossy(&close.reason))).await;
+                }
+                break Ok(());
+            }
+        }
+    };
+
+    drop(chunks);
+    // Whoever closed the input may have closed the output with it
+    if input_ended {
+        writer.abort();
+    } else {
+        writer.await?.context("write output")?;
+    }
+    result
+}
+
+/// Pass the whole chunks at the start of `pending` on to their streams,
+/// opening client streams on their first chunk. Returns the bytes consumed.
+async fn forward(
+    conn: &quinn::Connection,
+    sends: &mut HashMap<u32, Option<SendStream>>,
+    chunks: &mpsc::Sender<Vec<u8>>,
+    pending: &[u8],
+) -> Result<usize> {
+    let mut consumed = 0;
+    while let Some((Chunk { stream, bytes }, len)) = stdio::decode_chunk(&pending[consumed..])? {
+        consumed += len;
+        if stream == stdio::CONNECTION {
+            let (code, reason) = stdio::parse_close(bytes).context("invalid close chunk")?;
+            let code = code.unwrap_or(CloseCode::Normal);
+            conn.close(code.code().into(), reason.as_bytes());
+            continue;
+        }
+        let send = match sends.entry(stream) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) if stream < DAEMON_STREAMS => {
+                let (send, recv) = conn.open_bi().await.context("open stream")?;
+                tokio::spawn(read_stream(stream, recv, chunks.clone()));
+                entry.insert(Some(send))
+            }
+            Entry::Vacant(_) => bail!("chunk for stream {stream}, which the daemon didn't open"),
+        };
+        let Some(open) = send else {
+            bail!("chunk for stream {stream} after it was finished");
+        };
+        if bytes.is_empty() {
+            open.finish()?;
+            *send = None;
+        } else {
+            open.write_all(bytes).await.with_context(|| format!("write to stream {stream}"))?;
+        }
+    }
+    Ok(consumed)
+}
+
+/// Queue what the daemon sends on `recv` as chunks of `stream`, then an
+/// empty one when it finishes (or the stream breaks).
+async fn read_stream(stream: u32, mut recv: RecvStream, chunks: mpsc::Sender<Vec<u8>>) {
+    let mut buf = vec![0u8; stdio::MAX_CHUNK];
+    loop {
+        let n = match recv.read(&mut buf).await {
+            Ok(Some(n)) => n,
+            Ok(None) => 0,
+            Err(e) => {
+                debug!("stream {stream} ended: {e}");
+                0
+            }
+        };
+        let chunk = stdio::encode_chunk(stream, &buf[..n]).expect("reads fit in a chunk");
+        if chunks.send(chunk).await.is_err() || n == 0 {
+            return;
+        }
+    }
+}
+
+async fn write_chunks<W: AsyncWrite + Unpin>(mut queued: mpsc::Receiver<Vec<u8>>, mut output: W) -> Result<()> {
+    while let Some(chunk) = queued.recv().await {
+        output.write_all(&chunk).await?;
+        // Flush once caught up, not per chunk
+        if queued.is_empty() {
+            output.flush().await?;
+        }
+    }
+    output.flush().await?;
+    Ok(())
+}
@@ -0,0 +1,237 @@
+//! Running as a systemd service: socket activation and `sd_notify`.
+//!
+//! With the socket unit in `dist/systemd`, systemd binds the QUIC UDP port
+//! and `~/.phantom/daemon.sock` itself and passes them in (`LISTEN_FDS`).
+//! They stay open while the service restarts, so packets and commands sent
+//! in between wait for the next daemon instead of being refused.
+//!
+//! A `Type=notify` service is told when the daemon is serving (`READY=1`)
+//! and stopping (`STOPPING=1`) and, with `WatchdogSec=`, that it is still
+//! alive (`WATCHDOG=1` every half interval). After `phantom upgrade` the new
+//! daemon names itself the service's main process (`MAINPID=`) before the
+//! old one exits, which needs `NotifyAccess=all`.
+//!
+//! The variables are removed from the environment once read, so sessions'
+//! shells don't inherit them. That happens in [`Service::from_env`] before
+//! the async runtime starts: changing the environment isn't safe while other
+//! threads may read it.
+
+use std::ffi::OsString;
+use std::net::UdpSocket;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::net::{UnixDatagram, UnixListener};
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info, warn};
+
+/// The first descriptor systemd passes (`SD_LISTEN_FDS_START`).
+const LISTEN_FDS_START: RawFd = 3;
+
+/// What systemd passed the daemon.
+#[derive(Debug, Default)]
+pub struct Service {
+    pub notifier: Option<Notifier>,
+    pub activation: Activation,
+}
+
+impl Service {
+    /// Take systemd's variables from the environment. Call this before any
+    /// other thread starts.
+    pub fn from_env() -> Self {
+        Self { notifier: Notifier::from_env(), activation: Activation::from_env() }
+    }
+}
+
+/// Sockets systemd bound for the daemon.
+#[derive(Debug, Default)]
+pub struct Activation {
+    /// The QUIC endpoint's UDP socket (`ListenDatagram=`)
+    pub quic: Option<UdpSocket>,
+    /// The IPC socket (`ListenStream=`)
+    pub ipc: Option<UnixListener>,
+}
+
+impl Activation {
+    /// Take the sockets passed to this process, if any. Sockets that are
+    /// neither kind, or a second of one kind, are closed with a warning.
+    fn from_env() -> Self {
+        let pid = take_var("LISTEN_PID");
+        let count = take_var("LISTEN_FDS");
+        take_var("LISTEN_FDNAMES");
+        let mut activation = Self::default();
+        // Meant for this process, not one that exec'd it
+        let Some(count) = count
+            .filter(|_| pid.is_some_and(|pid| pid.parse() == Ok(std::process::id())))
+            .and_then(|count| count.parse::<RawFd>().ok())
+        else {
+            return activation;
+        };
+
+        for fd in LISTEN_FDS_START..LISTEN_FDS_START.saturating_add(count) {
+            // Sessions' shells mustn't inherit them
+            unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+            let socket = unsafe { OwnedFd::from_raw_fd(fd) };
+            match socket_kind(&socket) {
+                Some((libc::AF_INET | libc::AF_INET6, libc::SOCK_DGRAM)) if activation.quic.is_none() => {
+                    activation.quic = Some(UdpSocket::from(socket));
+                }
+                Some((libc::AF_UNIX, libc::SOCK_STREAM)) if activation.ipc.is_none() => {
+                    activation.ipc = Some(UnixListener::from(socket));
+                }
+                kind => warn!("closing socket {fd} passed by systemd: not expected ({kind:?})"),
+            }
+        }
+        if activation.quic.is_some() || activation.ipc.is_some() {
+            info!(
+                "using sockets from systemd (QUIC: {}, IPC: {})",
+                activation.quic.is_some(),
+                activation.ipc.is_some(),
+            );
+        }
+        activation
+    }
+}
+
+/// A socket's address family and type.
+fn socket_kind(socket: &OwnedFd) -> Option<(i32, i32)> {
+    let mut addr: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    let addr_ptr = (&mut addr as *mut libc::sockaddr_storage).cast();
+    let named = unsafe { libc::getsockname(socket.as_raw_fd(), addr_ptr, &mut len) };
+    let mut kind: libc::c_int = 0;
+    let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    let typed = unsafe {
+        libc::getsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_TYPE,
+            (&mut kind as *mut libc::c_int).cast(),
+            &mut len,
+        )
+    };
+    (named == 0 && typed == 0).then_some((addr.ss_family as i32, kind))
+}
+
+/// Reports the daemon's state to systemd (`NOTIFY_SOCKET`).
+#[derive(Debug)]
+pub struct Notifier {
+    socket: UnixDatagram,
+    address: OsString,
+    watchdog: Option<Duration>,
+}
+
+impl Notifier {
+    /// A notifier if systemd is listening for one; `None` otherwise.
+    fn from_env() -> Option<Self> {
+        let address = std::env::var_os("NOTIFY_SOCKET");
+        std::env::remove_var("NOTIFY_SOCKET");
+        let watchdog = watchdog_interval(take_var("WATCHDOG_USEC"), take_var("WATCHDOG_PID"), std::process::id());
+        let socket = match UnixDatagram::unbound() {
+            Ok(socket) => socket,
+            Err(e) => {
+                warn!("can't notify systemd: {e}");
+                return None;
+            }
+        };
+        Some(Self { socket, address: address?, watchdog })
+    }
+
+    /// Send `state`, such as `READY=1`. Failures are only logged: systemd
+    /// acts on missing notifications itself.
+    pub fn notify(&self, state: &str) {
+        if let Err(e) = self.send(state.as_bytes()) {
+            warn!("sd_notify {:?}: {e}", state.lines().next().unwrap_or_default());
+        }
+    }
+
+    fn send(&self, state: &[u8]) -> std::io::Result<()> {
+        use std::os::unix::ffi::OsStrExt;
+
+        let address = self.address.as_bytes();
+        // Linux abstract sockets are given with '@' for the leading NUL
+        #[cfg(target_os = "linux")]
+        if let Some(name) = address.strip_prefix(b"@") {
+            use std::os::linux::net::SocketAddrExt;
+            let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+            return self.socket.send_to_addr(state, &addr).map(drop);
+        }
+        self.socket.send_to(state, std::ffi::OsStr::from_bytes(address)).map(drop)
+    }
+
+    /// Environment for a daemon taking over from this one, so it can notify
+    /// the same service.
+    pub fn env(&self) -> Vec<(&'static str, OsString)> {
+        let mut env = vec![("NOTIFY_SOCKET", self.address.clone())];
+        if let Some(watchdog) = self.watchdog {
+            env.push(("WATCHDOG_USEC", watchdog.as_micros().to_string().into()));
+        }
+        env
+    }
+
+    /// Ping the watchdog until cancelled, if the service has one.
+    pub async fn run_watchdog(&self, cancel: CancellationToken) {
+        let Some(interval) = self.watchdog else {
+            return;
+        };
+        debug!("systemd watchdog every {:?}", interval / 2);
+        loop {
+            self.notify("WATCHDOG=1");
+            tokio::select! {
+                _ = tokio::time::sleep(interval / 2) => {}
+                _ = cancel.cancelled() => return,
+            }
+        }
+    }
+}
+
+/// The watchdog interval from `WATCHDOG_USEC`, unless it's off or meant for
+/// another process (`WATCHDOG_PID`).
+fn watchdog_interval(usec: Option<String>, pid: Option<String>, own_pid: u32) -> Option<Duration> {
+    if pid.is_some_and(|pid| pid.parse() != Ok(own_pid)) {
+        return None;
+    }
+    let usec: u64 = usec?.parse().ok()?;
+    (usec > 0).then(|| Duration::from_micros(usec))
+}
+
+fn take_var(name: &str) -> Option<String> {
+    let value = std::env::var(name).ok();
+    std::env::remove_var(name);
+    value
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn watchdog_interval_is_for_this_process_only() {
+        let usec = || Some("30000000".to_string());
+        assert_eq!(watchdog_interval(usec(), None, 7), Some(Duration::from_secs(30)));
+        assert_eq!(watchdog_interval(usec(), Some("7".to_string()), 7), Some(Duration::from_secs(30)));
+        assert_eq!(watchdog_interval(usec(), Some("8".to_string()), 7), None);
+        assert_eq!(watchdog_interval(Some("0".to_string()), None, 7), None);
+        assert_eq!(watchdog_interval(None, None, 7), None);
+    }
+
+    #[test]
+    fn notifies_the_socket_and_classifies_passed_sockets() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("notify");
+        let systemd = UnixDatagram::bind(&path).unwrap();
+        let notifier = Notifier {
+            socket: UnixDatagram::unbound().unwrap(),
+            address: path.into_os_string(),
+            watchdog: None,
+        };
+        notifier.notify("READY=1\nSTATUS=Serving");
+        let mut buf = [0u8; 64];
+        let n = systemd.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"READY=1\nSTATUS=Serving");
+
+        let udp = OwnedFd::from(UdpSocket::bind("127.0.0.1:0").unwrap());
+        assert_eq!(socket_kind(&udp), Some((libc::AF_INET, libc::SOCK_DGRAM)));
+        let ipc = OwnedFd::from(UnixListener::bind(dir.path().join("ipc")).unwrap());
+        assert_eq!(socket_kind(&ipc), Some((libc::AF_UNIX, libc::SOCK_STREAM)));
+    }
+}
@@ -0,0 +1,874 @@
+//! Terminal backends behind a [`PtySession`](crate::session::PtySession).
+//!
+//! [`NativePty`] runs the user's shell in a real PTY (a ConPTY on Windows,
+//! with its processes in a job object), and [`AdoptedPty`] is one inherited
+//! from the previous daemon on upgrade (Unix only). [`ScriptedTerminal`] is
+//! an in-memory stand-in driven through a [`ScriptHandle`], so session and
+//! bridge logic can be tested without spawning shells.
+
+use anyhow::{bail, Context, Result};
+use portable_pty::{native_pty_system, CommandBuilder, MasterPty, PtySize};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::io::{Read, Write};
+#[cfg(unix)]
+use std::os::fd::{AsRawFd, OwnedFd, RawFd};
+use std::path::PathBuf;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+use tokio_util::task::TaskTracker;
+use tracing::warn;
+
+use crate::errors::ErrorCode;
+use crate::restrictions::Restrictions;
+
+/// Signals [`TerminalBackend::kill_foreground`] takes, by their POSIX
+/// numbers on every platform.
+pub mod signal {
+    #[cfg(unix)]
+    pub use libc::{SIGHUP, SIGINT, SIGKILL, SIGTERM};
+    #[cfg(not(unix))]
+    pub const SIGHUP: i32 = 1;
+    #[cfg(not(unix))]
+    pub const SIGINT: i32 = 2;
+    #[cfg(not(unix))]
+    pub const SIGKILL: i32 = 9;
+    #[cfg(not(unix))]
+    pub const SIGTERM: i32 = 15;
+}
+
+/// `TERM` for clients that don't declare one.
+pub const DEFAULT_TERM: &str = "xterm-256color";
+
+/// What the client's terminal emulator supports, declared in `create_session`
+/// (`"terminal": {...}`) and applied to the session's environment.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TerminalCaps {
+    /// `TERM` for programs in the session
+    pub term: String,
+    /// 24-bit color, advertised as `COLORTERM=truecolor`
+    pub truecolor: bool,
+    /// Unicode version the client's character widths follow (e.g. "15.1").
+    /// Recorded for other clients; programs have no standard way to learn it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub unicode_version: Option<String>,
+}
+
+impl Default for TerminalCaps {
+    fn default() -> Self {
+        Self {
+            term: DEFAULT_TERM.to_string(),
+            truecolor: false,
+            unicode_version: None,
+        }
+    }
+}
+
+impl TerminalCaps {
+    /// Caps declared in a request's `terminal` field, if any. A `TERM` this
+    /// host has no terminfo entry for falls back to [`DEFAULT_TERM`], so
+    /// programs still start.
+    pub fn from_request(req: &serde_json::Value) -> Result<Self> {
+        let declared = &req["terminal"];
+        if declared.is_null() {
+            return Ok(Self::default());
+        }
+        let mut caps: Self = serde_json::from_value(declared.clone())
+            .map_err(|e| ErrorCode::BadRequest.err(format!("invalid terminal: {e}")))?;
+        caps.validate()?;
+        if !has_terminfo(&caps.term) {
+            warn!("no terminfo entry for TERM={}, using {DEFAULT_TERM}", caps.term);
+            caps.term = DEFAULT_TERM.to_string();
+        }
+        Ok(caps)
+    }
+
+    fn validate(&self) -> Result<()> {
+        let valid_term = !self.term.is_empty()
+            && self.term.len() <= 64
+            && self.term.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | '+'));
+        if !valid_term {
+            return Err(ErrorCode::BadRequest.err(format!("invalid TERM: {}", self.term)));
+        }
+        if let Some(version) = &self.unicode_version {
+            if version.is_empty() || version.len() > 16 || !version.chars().all(|c| c.is_ascii_digit() || c == '.') {
+                return Err(ErrorCode::BadRequest.err(format!("invalid unicode_version: {version}")));
+            }
+        }
+        Ok(())
+    }
+
+    /// Set `TERM` and `COLORTERM` for `cmd`; the daemon's own values never leak through.
+    fn apply(&self, cmd: &mut CommandBuilder) {
+        cmd.env("TERM", &self.term);
+        if self.truecolor {
+            cmd.env("COLORTERM", "truecolor");
+        } else {
+            cmd.env_remove("COLORTERM");
+        }
+    }
+}
+
+/// Whether a terminfo entry for `term` is installed, in any of the places
+/// ncurses looks (`<dir>/t/<term>`, or `<dir>/74/<term>` on macOS).
+fn has_terminfo(term: &str) -> bool {
+    if term == DEFAULT_TERM {
+        return true;
+    }
+    let mut search: Vec<PathBuf> = Vec::new();
+    search.extend(std::env::var_os("TERMINFO").map(PathBuf::from));
+    search.extend(dirs::home_dir().map(|home| home.join(".terminfo")));
+    if let Some(list) = std::env::var_os("TERMINFO_DIRS") {
+        search.extend(std::env::split_paths(&list).filter(|d| !d.as_os_str().is_empty()));
+    }
+    search.extend(["/etc/terminfo", "/lib/terminfo", "/usr/share/terminfo", "/usr/lib/terminfo"].map(PathBuf::from));
+    let first = term.chars().next().unwrap_or('x');
+    search.iter().any(|dir| {
+        dir.join(first.to_string()).join(term).is_file() || dir.join(format!("{:x}", first as u32)).join(term).is_file()
+    })
+}
+
+/// A process attached to a terminal: output is read, input is written.
+pub trait TerminalBackend: Send {
+    /// A reader for terminal output. Called again on detach so the next
+    /// attach has a reader; the previous one may still be blocked in `read`.
+    fn try_clone_reader(&self) -> Result<Box<dyn Read + Send>>;
+    /// The input writer. Only taken once, when the session is created.
+    fn take_writer(&mut self) -> Result<Box<dyn Write + Send>>;
+    fn resize(&self, rows: u16, cols: u16) -> Result<()>;
+    /// Current size as (rows, cols).
+    fn size(&self) -> Result<(u16, u16)>;
+    /// Exit code, once the process has exited.
+    fn try_wait(&mut self) -> Result<Option<ExitCode>>;
+    /// Hang up the process, killing it if it lingers (from a task on
+    /// `tasks`, so shutdown waits for the kill).
+    fn terminate(&mut self, tasks: &TaskTracker);
+    /// Send `signal` to the terminal's foreground process group, leaving the
+    /// session's own process (the shell) alive. Returns the group signalled.
+    fn kill_foreground(&self, _signal: i32) -> Result<u32> {
+        Err(ErrorCode::Unsupported.err("this terminal can't signal its foreground process"))
+    }
+    /// Process id of the session's own process (the shell), if it has one.
+    fn pid(&self) -> Option<u32> {
+        None
+    }
+    /// Working directory of the session's own process (the shell).
+    fn cwd(&self) -> Option<PathBuf> {
+        self.pid().and_then(crate::project::process_cwd)
+    }
+    /// CPU time the session's processes have used (see [`crate::budget`]).
+    fn cpu_time(&self) -> Option<Duration> {
+        self.pid().and_then(crate::budget::cpu_time)
+    }
+    /// Whether output is waiting to be read, as `poll` sees the PTY master
+    /// (see [`crate::watchdog`]). None if it can't tell.
+    fn output_waiting(&self) -> Option<bool> {
+        None
+    }
+    /// PTY master and process id, for handing the terminal to a new daemon
+    /// process. None if it can't outlive this one.
+    #[cfg(unix)]
+    fn handoff(&self) -> Option<(RawFd, u32)> {
+        None
+    }
+}
+
+/// Exit code of a terminal's process: None if it can't be known, as for a
+/// shell adopted from a previous daemon, which isn't ours to wait for.
+pub type ExitCode = Option<u32>;
+
+/// Run `kill` once a terminated process has had 2 seconds to exit. Off the
+/// runtime (a session dropped during shutdown) it waits on a thread instead.
+#[cfg(unix)]
+fn after_grace(tasks: &TaskTracker, kill: impl FnOnce() + Send + 'static) {
+    const GRACE: Duration = Duration::from_secs(2);
+    match tokio::runtime::Handle::try_current() {
+        Ok(runtime) => {
+            tasks.spawn_on(
+                async move {
+                    tokio::time::sleep(GRACE).await;
+                    kill();
+                },
+                &runtime,
+            );
+        }
+        Err(_) => {
+            std::thread::spawn(move || {
+                std::thread::sleep(GRACE);
+                kill();
+            });
+        }
+    }
+}
+
+/// What a new session runs. The default is the daemon user's login shell.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Launch<'a> {
+    /// Run as this user (multi-user mode)
+    pub user: Option<&'a str>,
+    /// Command line to run instead of the shell
+    pub command: Option<&'a str>,
+    /// Jail of a restricted device
+    pub restrictions: Option<&'a Restrictions>,
+    /// Client terminal; None for the default
+    pub terminal: Option<&'a TerminalCaps>,
+}
+
+impl Launch<'_> {
+    pub fn is_default(&self) -> bool {
+        self.user.is_none()
+            && self.command.is_none()
+            && !self.restrictions.is_some_and(Restrictions::is_jailed)
+            && self.terminal.is_none_or(|t| *t == TerminalCaps::default())
+    }
+
+    pub fn command_builder(&self) -> CommandBuilder {
+        if let Some(restrictions) = self.restrictions.filter(|r| r.is_jailed()) {
+            let argv = restrictions.jail_argv(self.command, self.user);
+            return CommandBuilder::from_argv(argv.into_iter().map(Into::into).collect());
+        }
+        match (self.user, self.command) {
+            (Some(user), command) => crate::users::login_command(user, command),
+            (None, Some(command)) => shell_command(command),
+            (None, None) => CommandBuilder::new_default_prog(),
+        }
+    }
+}
+
+/// Creates the backend for a new session at `rows` × `cols`.
+pub type Spawner = Arc<dyn Fn(u16, u16, &Launch<'_>) -> Result<Box<dyn TerminalBackend>> + Send + Sync>;
+
+/// Spawner for sessions in a native PTY.
+pub fn native_spawner() -> Spawner {
+    Arc::new(|rows, cols, launch: &Launch<'_>| {
+        let terminal = launch.terminal.cloned().unwrap_or_default();
+        Ok(Box::new(NativePty::spawn_terminal(rows, cols, launch.command_builder(), &terminal)?))
+    })
+}
+
+/// `command` run through the user's shell (`$SHELL -c`, or `%COMSPEC% /C` on
+/// Windows), from their home.
+fn shell_command(command: &str) -> CommandBuilder {
+    #[cfg(unix)]
+    let (shell, flag) = (std::env::var("SHELL").unwrap_or_else(|_| "/bin/sh".to_string()), "-c");
+    #[cfg(windows)]
+    let (shell, flag) = (std::env::var("COMSPEC").unwrap_or_else(|_| "cmd.exe".to_string()), "/C");
+    let mut cmd = CommandBuilder::new(shell);
+    cmd.args([flag, command]);
+    if let Some(home) = dirs::home_dir() {
+        cmd.cwd(home);
+    }
+    cmd
+}
+
+/// Send `signal` to the foreground process group of PTY `master`, unless
+/// that's the session's own process `pid`: then no command is running.
+#[cfg(unix)]
+fn signal_foreground(master: RawFd, pid: u32, signal: i32) -> Result<u32> {
+    let pgid = unsafe { libc::tcgetpgrp(master) };
+    if pgid < 0 {
+        return Err(std::io::Error::last_os_error()).context("get foreground process group");
+    }
+    if pgid as u32 == pid {
+        return Err(ErrorCode::NotFound.err("no foreground command is running"));
+    }
+    if unsafe { libc::killpg(pgid, signal) } != 0 {
+        return Err(std::io::Error::last_os_error()).context("signal foreground process group");
+    }
+    Ok(pgid as u32)
+}
+
+/// Whether PTY `master` polls readable, without waiting.
+#[cfg(unix)]
+fn poll_readable(master: RawFd) -> Option<bool> {
+    let mut fd = libc::pollfd { fd: master, events: libc::POLLIN, revents: 0 };
+    match unsafe { libc::poll(&mut fd, 1, 0) } {
+        n if n < 0 => None,
+        _ => Some(fd.revents & libc::POLLIN != 0),
+    }
+}
+
+fn pty_size(rows: u16, cols: u16) -> PtySize {
+    PtySize {
+        rows,
+        cols,
+        pixel_width: 0,
+        pixel_height: 0,
+    }
+}
+
+/// The user's default shell in a portable_pty PTY.
+pub struct NativePty {
+    child: Box<dyn portable_pty::Child + Send + Sync>,
+    master: Box<dyn MasterPty + Send>,
+    /// Job the shell and its descendants run in
+    #[cfg(windows)]
+    job: crate::job::Job,
+}
+
+impl NativePty {
+    pub fn spawn(rows: u16, cols: u16) -> Result<Self> {
+        Self::spawn_command(rows, cols, CommandBuilder::new_default_prog())
+    }
+
+    /// The user's default shell with extra environment variables.
+    pub fn spawn_with_env(
+        rows: u16,
+        cols: u16,
+        env: &[(&str, &std::ffi::OsStr)],
+        terminal: &TerminalCaps,
+    ) -> Result<Self> {
+        let mut cmd = CommandBuilder::new_default_prog();
+        for (key, value) in env {
+            cmd.env(key, value);
+        }
+        Self::spawn_terminal(rows, cols, cmd, terminal)
+    }
+
+    /// Run a single `command` through the user's shell (`$SHELL -c`).
+    pub fn exec(rows: u16, cols: u16, command: &str) -> Result<Self> {
+        Self::spawn_command(rows, cols, shell_command(command))
+    }
+
+    /// Run `cmd` in a new PTY.
+    pub fn spawn_command(rows: u16, cols: u16, cmd: CommandBuilder) -> Result<Self> {
+        Self::spawn_terminal(rows, cols, cmd, &TerminalCaps::default())
+    }
+
+    /// Run `cmd` in a new PTY, for a client terminal with `terminal` caps.
+    pub fn spawn_terminal(rows: u16, cols: u16, mut cmd: CommandBuilder, terminal: &TerminalCaps) -> Result<Self> {
+        let pty_system = native_pty_system();
+        let pair = pty_system.openpty(pty_size(rows, cols)).context("openpty")?;
+
+        terminal.apply(&mut cmd);
+
+        let child = pair.slave.spawn_command(cmd).context("spawn shell")?;
+        drop(pair.slave);
+        #[cfg(windows)]
+        let (child, job) = in_job(child)?;
+
+        Ok(Self {
+            child,
+            master: pair.master,
+            #[cfg(windows)]
+            job,
+        })
+    }
+}
+
+/// Put `child` in a job of its own, killing it if that fails.
+#[cfg(windows)]
+fn in_job(
+    mut child: Box<dyn portable_pty::Child + Send + Sync>,
+) -> Result<(Box<dyn portable_pty::Child + Send + Sync>, crate::job::Job)> {
+    let job = child
+        .process_id()
+        .context("shell has no process id")
+        .and_then(|pid| crate::job::Job::for_process(pid).context("put shell in a job object"));
+    match job {
+        Ok(job) => Ok((child, job)),
+        Err(e) => {
+            let _ = child.kill();
+            Err(e)
+        }
+    }
+}
+
+impl TerminalBackend for NativePty {
+    fn try_clone_reader(&self) -> Result<Box<dyn Read + Send>> {
+        self.master.try_clone_reader().context("clone PTY reader")
+    }
+
+    fn take_writer(&mut self) -> Result<Box<dyn Write + Send>> {
+        self.master.take_writer().context("take PTY writer")
+    }
+
+    fn resize(&self, rows: u16, cols: u16) -> Result<()> {
+        self.master.resize(pty_size(rows, cols)).context("resize PTY")
+    }
+
+    fn size(&self) -> Result<(u16, u16)> {
+        let size = self.master.get_size().context("get PTY size")?;
+        Ok((size.rows, size.cols))
+    }
+
+    fn try_wait(&mut self) -> Result<Option<ExitCode>> {
+        Ok(self.child.try_wait()?.map(|status| Some(status.exit_code())))
+    }
+
+    /// SIGHUP the shell's process group, then SIGKILL it after 2 seconds.
+    #[cfg(unix)]
+    fn terminate(&mut self, tasks: &TaskTracker) {
+        if let Some(pid) = self.child.process_id() {
+            unsafe {
+                libc::killpg(pid as i32, libc::SIGHUP);
+            }
+        }
+
+        let mut killer = self.child.clone_killer();
+        after_grace(tasks, move || {
+            let _ = killer.kill();
+        });
+    }
+
+    /// End the shell's job: Windows has no hangup to send first.
+    #[cfg(windows)]
+    fn terminate(&mut self, _tasks: &TaskTracker) {
+        self.job.terminate();
+    }
+
+    #[cfg(unix)]
+    fn kill_foreground(&self, signal: i32) -> Result<u32> {
+        let (master, pid) = self.handoff().context("PTY has no master fd or process id")?;
+        signal_foreground(master, pid, signal)
+    }
+
+    fn pid(&self) -> Option<u32> {
+        self.child.process_id()
+    }
+
+    #[cfg(unix)]
+    fn output_waiting(&self) -> Option<bool> {
+        poll_readable(self.master.as_raw_fd()?)
+    }
+
+    #[cfg(unix)]
+    fn handoff(&self) -> Option<(RawFd, u32)> {
+        Some((self.master.as_raw_fd()?, self.child.process_id()?))
+    }
+}
+
+/// A PTY whose master was handed over by the previous daemon process.
+///
+/// The shell is not our child, so it can't be waited for: its exit is
+/// noticed by polling, and its exit code is unknown.
+#[cfg(unix)]
+pub struct AdoptedPty {
+    master: OwnedFd,
+    pid: u32,
+    exited: bool,
+}
+
+#[cfg(unix)]
+impl AdoptedPty {
+    pub fn new(master: OwnedFd, pid: u32) -> Self {
+        Self { master, pid, exited: false }
+    }
+
+    fn clone_master(&self) -> Result<std::fs::File> {
+        Ok(std::fs::File::from(self.master.try_clone().context("dup PTY master")?))
+    }
+}
+
+#[cfg(unix)]
+impl TerminalBackend for AdoptedPty {
+    fn try_clone_reader(&self) -> Result<Box<dyn Read + Send>> {
+        Ok(Box::new(self.clone_master()?))
+    }
+
+    fn take_writer(&mut self) -> Result<Box<dyn Write + Send>> {
+        Ok(Box::new(self.clone_master()?))
+    }
+
+    fn resize(&self, rows: u16, cols: u16) -> Result<()> {
+        let size = libc::winsize { ws_row: rows, ws_col: cols, ws_xpixel: 0, ws_ypixel: 0 };
+        if unsafe { libc::ioctl(self.master.as_raw_fd(), libc::TIOCSWINSZ, &size) } != 0 {
+            return Err(std::io::Error::last_os_error()).context("resize PTY");
+        }
+        Ok(())
+    }
+
+    fn size(&self) -> Result<(u16, u16)> {
+        let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+        if unsafe { libc::ioctl(self.master.as_raw_fd(), libc::TIOCGWINSZ, &mut size) } != 0 {
+            return Err(std::io::Error::last_os_error()).context("get PTY size");
+        }
+        Ok((size.ws_row, size.ws_col))
+    }
+
+    fn try_wait(&mut self) -> Result<Option<ExitCode>> {
+        if !self.exited {
+            let alive = unsafe { libc::kill(self.pid as i32, 0) } == 0
+                || std::io::Error::last_os_error().raw_os_error() != Some(libc::ESRCH);
+            self.exited = !alive;
+        }
+        Ok(self.exited.then_some(None))
+    }
+
+    /// SIGHUP the shell's process group, then SIGKILL it after 2 seconds.
+    fn terminate(&mut self, tasks: &TaskTracker) {
+        let pgid = self.pid as i32;
+        unsafe {
+            libc::killpg(pgid, libc::SIGHUP);
+        }
+        after_grace(tasks, move || unsafe {
+            if libc::kill(pgid, 0) == 0 {
+                libc::killpg(pgid, libc::SIGKILL);
+            }
+        });
+    }
+
+    fn kill_foreground(&self, signal: i32) -> Result<u32> {
+        signal_foreground(self.master.as_raw_fd(), self.pid, signal)
+    }
+
+    fn pid(&self) -> Option<u32> {
+        Some(self.pid)
+    }
+
+    fn output_waiting(&self) -> Option<bool> {
+        poll_readable(self.master.as_raw_fd())
+    }
+
+    fn handoff(&self) -> Option<(RawFd, u32)> {
+        Some((self.master.as_raw_fd(), self.pid))
+    }
+}
+
+/// State shared between a [`ScriptedTerminal`], its readers/writer, and its
+/// [`ScriptHandle`].
+#[derive(Default)]
+struct Script {
+    state: Mutex<ScriptState>,
+    output_ready: Condvar,
+}
+
+#[derive(Default)]
+struct ScriptState {
+    output: VecDeque<u8>,
+    input: Vec<u8>,
+    size: (u16, u16),
+    exit_code: Option<u32>,
+    terminated: bool,
+    /// Process group of the running command, if any
+    foreground: Option<u32>,
+    /// Signals sent to foreground commands
+    signals: Vec<i32>,
+    cwd: Option<PathBuf>,
+    cpu_time: Duration,
+    echo: bool,
+    /// Generation of the newest reader; older readers see EOF
+    reader: u64,
+    /// Reader whose reads block even with output waiting
+    jammed: Option<u64>,
+    writer_taken: bool,
+}
+
+impl Script {
+    fn state(&self) -> std::sync::MutexGuard<'_, ScriptState> {
+        self.state.lock().expect("script lock")
+    }
+}
+
+/// In-memory terminal for tests. Output is whatever the [`ScriptHandle`]
+/// emits (plus input, when echoing); input is recorded for inspection.
+///
+/// Unlike a real PTY, cloning a reader retires the previous ones — they read
+/// EOF — so a detached bridge's blocked read can't race the next attach for
+/// output and tests stay deterministic.
+pub struct ScriptedTerminal {
+    script: Arc<Script>,
+}
+
+impl ScriptedTerminal {
+    pub fn new(rows: u16, cols: u16) -> (Self, ScriptHandle) {
+        let script = Arc::new(Script::default());
+        script.state().size = (rows, cols);
+        (
+            Self {
+                script: script.clone(),
+            },
+            ScriptHandle { script },
+        )
+    }
+
+    /// Like [`new`](Self::new), but input is echoed back as output the way a
+    /// cooked-mode TTY does.
+    pub fn echoing(rows: u16, cols: u16) -> (Self, ScriptHandle) {
+        let (terminal, handle) = Self::new(rows, cols);
+        terminal.script.state().echo = true;
+        (terminal, handle)
+    }
+
+    /// A spawner that creates scripted terminals, passing each new handle to
+    /// `on_spawn`.
+    pub fn spawner(echo: bool, on_spawn: impl Fn(ScriptHandle) + Send + Sync + 'static) -> Spawner {
+        Arc::new(move |rows, cols, _: &Launch<'_>| {
+            let (terminal, handle) = if echo {
+                Self::echoing(rows, cols)
+            } else {
+                Self::new(rows, cols)
+            };
+            on_spawn(handle);
+            Ok(Box::new(terminal))
+        })
+    }
+}
+
+impl TerminalBackend for ScriptedTerminal {
+    fn try_clone_reader(&self) -> Result<Box<dyn Read + Send>> {
+        let mut state = self.script.state();
+        state.reader += 1;
+        let generation = state.reader;
+        drop(state);
+        // Wake any retired reader so it can return EOF
+        self.script.output_ready.notify_all();
+        Ok(Box::new(ScriptReader {
+            script: self.script.clone(),
+            generation,
+        }))
+    }
+
+    fn take_writer(&mut self) -> Result<Box<dyn Write + Send>> {
+        let mut state = self.script.state();
+        if state.writer_taken {
+            bail!("writer already taken");
+        }
+        state.writer_taken = true;
+        Ok(Box::new(ScriptWriter {
+            script: self.script.clone(),
+        }))
+    }
+
+    fn resize(&self, rows: u16, cols: u16) -> Result<()> {
+        self.script.state().size = (rows, cols);
+        Ok(())
+    }
+
+    fn size(&self) -> Result<(u16, u16)> {
+        Ok(self.script.state().size)
+    }
+
+    fn try_wait(&mut self) -> Result<Option<ExitCode>> {
+        Ok(self.script.state().exit_code.map(Some))
+    }
+
+    fn terminate(&mut self, _tasks: &TaskTracker) {
+        let mut state = self.script.state();
+        state.terminated = true;
+        state.exit_code.get_or_insert(129);
+        drop(state);
+        self.script.output_ready.notify_all();
+    }
+
+    fn kill_foreground(&self, signal: i32) -> Result<u32> {
+        let mut state = self.script.state();
+        let pgid = state.foreground.take().ok_or_else(|| ErrorCode::NotFound.err("no foreground command is running"))?;
+        state.signals.push(signal);
+        Ok(pgid)
+    }
+
+    fn cwd(&self) -> Option<PathBuf> {
+        self.script.state().cwd.clone()
+    }
+
+    fn cpu_time(&self) -> Option<Duration> {
+        Some(self.script.state().cpu_time)
+    }
+
+    fn output_waiting(&self) -> Option<bool> {
+        Some(!self.script.state().output.is_empty())
+    }
+}
+
+struct ScriptReader {
+    script: Arc<Script>,
+    generation: u64,
+}
+
+impl Read for ScriptReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let mut state = self.script.state();
+        loop {
+            if state.reader != self.generation {
+                return Ok(0);
+            }
+            if !state.output.is_empty() && state.jammed != Some(self.generation) {
+                let n = buf.len().min(state.output.len());
+                for (dst, src) in buf.iter_mut().zip(state.output.drain(..n)) {
+                    *dst = src;
+                }
+                return Ok(n);
+            }
+            if state.exit_code.is_some() {
+                return Ok(0);
+            }
+            state = self.script.output_ready.wait(state).expect("script lock");
+        }
+    }
+}
+
+struct ScriptWriter {
+    script: Arc<Script>,
+}
+
+impl Write for ScriptWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let mut state = self.script.state();
+        if state.exit_code.is_some() {
+            return Err(std::io::ErrorKind::BrokenPipe.into());
+        }
+        state.input.extend_from_slice(buf);
+        if state.echo {
+            state.output.extend(buf);
+            drop(state);
+            self.script.output_ready.notify_all();
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Test-side control of a [`ScriptedTerminal`].
+#[derive(Clone)]
+pub struct ScriptHandle {
+    script: Arc<Script>,
+}
+
+impl ScriptHandle {
+    /// Queue output as if the process had written it.
+    pub fn emit(&self, data: &[u8]) {
+        self.script.state().output.extend(data);
+        self.script.output_ready.notify_all();
+    }
+
+    /// Everything written to the terminal so far.
+    pub fn input(&self) -> Vec<u8> {
+        self.script.state().input.clone()
+    }
+
+    /// Current size as (rows, cols).
+    pub fn size(&self) -> (u16, u16) {
+        self.script.state().size
+    }
+
+    /// Exit the process; readers see EOF once queued output is consumed.
+    pub fn exit(&self, code: u32) {
+        self.script.state().exit_code = Some(code);
+        self.script.output_ready.notify_all();
+    }
+
+    /// Start a command in process group `pgid`, until it's signalled.
+    pub fn run_foreground(&self, pgid: u32) {
+        self.script.state().foreground = Some(pgid);
+    }
+
+    /// Move the process to `dir`, as if it had run `cd`.
+    pub fn cd(&self, dir: impl Into<PathBuf>) {
+        self.script.state().cwd = Some(dir.into());
+    }
+
+    /// Use `time` more CPU time.
+    pub fn use_cpu(&self, time: Duration) {
+        self.script.state().cpu_time += time;
+    }
+
+    /// Signals sent to foreground commands, oldest first.
+    pub fn signals(&self) -> Vec<i32> {
+        self.script.state().signals.clone()
+    }
+
+    /// Whether the session asked the process to terminate.
+    pub fn terminated(&self) -> bool {
+        self.script.state().terminated
+    }
+
+    /// Hang the current reader: its reads block even with output waiting,
+    /// as in the driver states [`crate::watchdog`] is for.
+    pub fn jam_reader(&self) {
+        let mut state = self.script.state();
+        state.jammed = Some(state.reader);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scripted_output_and_input() {
+        let (mut term, handle) = ScriptedTerminal::echoing(24, 80);
+        let mut reader = term.try_clone_reader().unwrap();
+        let mut writer = term.take_writer().unwrap();
+        assert!(term.take_writer().is_err());
+
+        handle.emit(b"$ ");
+        writer.write_all(b"ls\n").unwrap();
+        let mut buf = [0u8; 16];
+        let n = reader.read(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"$ ls\n");
+        assert_eq!(handle.input(), b"ls\n");
+
+        term.resize(40, 120).unwrap();
+        assert_eq!(handle.size(), (40, 120));
+    }
+
+    #[test]
+    fn terminal_caps_are_validated_and_applied() {
+        assert_eq!(TerminalCaps::from_request(&serde_json::json!({})).unwrap(), TerminalCaps::default());
+
+        let req = serde_json::json!({"terminal": {"truecolor": true, "unicode_version": "15.1"}});
+        let caps = TerminalCaps::from_request(&req).unwrap();
+        assert_eq!((caps.term.as_str(), caps.truecolor), (DEFAULT_TERM, true));
+        let mut cmd = CommandBuilder::new("sh");
+        caps.apply(&mut cmd);
+        assert_eq!(cmd.get_env("TERM").unwrap(), DEFAULT_TERM);
+        assert_eq!(cmd.get_env("COLORTERM").unwrap(), "truecolor");
+        TerminalCaps::default().apply(&mut cmd);
+        assert!(cmd.get_env("COLORTERM").is_none());
+
+        // Unknown to this host's terminfo: programs get the default instead
+        let req = serde_json::json!({"terminal": {"term": "no-such-terminal-phantom"}});
+        assert_eq!(TerminalCaps::from_request(&req).unwrap().term, DEFAULT_TERM);
+
+        for terminal in [
+            serde_json::json!({"term": "xterm;reset"}),
+            serde_json::json!({"term": ""}),
+            serde_json::json!({"unicode_version": "latest"}),
+            serde_json::json!({"truecolor": "yes"}),
+        ] {
+            let err = TerminalCaps::from_request(&serde_json::json!({"terminal": terminal})).unwrap_err();
+            assert_eq!(ErrorCode::of(&err), ErrorCode::BadRequest, "{terminal}");
+        }
+    }
+
+    #[test]
+    fn scripted_reader_retires_and_exits() {
+        let (mut term, handle) = ScriptedTerminal::new(24, 80);
+        let mut old = term.try_clone_reader().unwrap();
+
+        // A blocked reader returns EOF once a newer reader is cloned
+        let blocked = std::thread::spawn(move || old.read(&mut [0u8; 8]).unwrap());
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        let mut reader = term.try_clone_reader().unwrap();
+        assert_eq!(blocked.join().unwrap(), 0);
+
+        handle.emit(b"bye");
+        handle.exit(3);
+        assert_eq!(term.try_wait().unwrap(), Some(Some(3)));
+        let mut out = Vec::new();
+        reader.read_to_end(&mut out).unwrap();
+        assert_eq!(out, b"bye");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn adopted_shell_ends_off_the_runtime_with_no_exit_code() {
+        use std::os::unix::process::CommandExt;
+
+        let mut shell = std::process::Command::new("sleep").arg("30").process_group(0).spawn().unwrap();
+        let (master, _) = std::io::pipe().unwrap();
+        let mut adopted = AdoptedPty::new(OwnedFd::from(master), shell.id());
+        assert_eq!(adopted.try_wait().unwrap(), None);
+
+        // No tokio runtime here
+        adopted.terminate(&TaskTracker::new());
+        use std::os::unix::process::ExitStatusExt;
+        assert_eq!(shell.wait().unwrap().signal(), Some(libc::SIGHUP));
+        assert_eq!(adopted.try_wait().unwrap(), Some(None));
+    }
+}
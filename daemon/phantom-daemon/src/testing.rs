@@ -0,0 +1,560 @@
+//! An in-process daemon to test clients against (feature `testing`).
+//!
+//! [`TestHarness::new`] starts a daemon on a loopback port with its state in
+//! a temporary directory and a device already paired, and connects as that
+//! device with [`TestHarness::connect_and_auth`]. Everything goes away when
+//! the harness is dropped. [`send_json`] and [`recv_json`] speak the control
+//! protocol's length-prefixed JSON, and [`crate::scenario`] runs scripted
+//! exchanges against a harness.
+//!
+//! The daemon's own integration tests use it too, so it keeps working, but
+//! it's no substitute for the real thing: there's no IPC, upgrade or config
+//! file, and the certificate is whatever the client is told to accept.
+
+use anyhow::{Context, Result};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::auth::Authenticator;
+use crate::config::RateLimitConfig;
+use crate::connections::Connections;
+use crate::device_store::DeviceStore;
+use crate::server::{ConnectionGauges, Intake, RateLimits};
+use crate::session::SessionManager;
+
+/// A self-signed certificate for `localhost` and its PKCS#8 key, DER-encoded.
+pub fn gen_test_cert() -> (Vec<u8>, Vec<u8>) {
+    use rcgen::{CertificateParams, KeyPair};
+    let key_pair = KeyPair::generate_for(&rcgen::PKCS_ECDSA_P256_SHA256).unwrap();
+    let params = CertificateParams::new(vec!["localhost".to_string()]).unwrap();
+    let cert = params.self_signed(&key_pair).unwrap();
+    (cert.der().to_vec(), key_pair.serialize_der())
+}
+
+/// The daemon's QUIC config for `cert_der` and `key_der`.
+pub fn build_server_config(cert_der: &[u8], key_der: &[u8]) -> quinn::ServerConfig {
+    use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+
+    let cert = CertificateDer::from(cert_der.to_vec());
+    let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(key_der.to_vec()));
+
+    let mut tls_config = rustls::ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(vec![cert], key)
+        .unwrap();
+    tls_config.alpn_protocols = vec![b"phantom/1".to_vec()];
+    tls_config.max_early_data_size = 0;
+
+    let quic_config =
+        quinn::crypto::rustls::QuicServerConfig::try_from(Arc::new(tls_config)).unwrap();
+    let mut server_config = quinn::ServerConfig::with_crypto(Arc::new(quic_config));
+    let transport = Arc::get_mut(&mut server_config.transport).unwrap();
+    transport.max_idle_timeout(Some(Duration::from_secs(30).try_into().unwrap()));
+    transport.keep_alive_interval(Some(Duration::from_secs(5)));
+
+    server_config
+}
+
+/// Client QUIC config that accepts any server certificate.
+pub fn build_client_config() -> quinn::ClientConfig {
+    let mut crypto = rustls::ClientConfig::builder()
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(AcceptAnyCert))
+        .with_no_client_auth();
+    crypto.alpn_protocols = vec![b"phantom/1".to_vec()];
+
+    let mut client_config = quinn::ClientConfig::new(Arc::new(
+        quinn::crypto::rustls::QuicClientConfig::try_from(crypto).unwrap(),
+    ));
+    let mut transport = quinn::TransportConfig::default();
+    transport.max_idle_timeout(Some(Duration::from_secs(30).try_into().unwrap()));
+    client_config.transport_config(Arc::new(transport));
+
+    client_config
+}
+
+/// Accepts any server certificate; for tests only.
+#[derive(Debug)]
+pub struct AcceptAnyCert;
+
+impl rustls::client::danger::ServerCertVerifier for AcceptAnyCert {
+    fn verify_server_cert(
+        &self,
+        _: &rustls::pki_types::CertificateDer,
+        _: &[rustls::pki_types::CertificateDer],
+        _: &rustls::pki_types::ServerName,
+        _: &[u8],
+        _: rustls::pki_types::UnixTime,
+    ) -> Result<rustls::client::danger::ServerCertVerified, rustls::Error> {
+        Ok(rustls::client::danger::ServerCertVerified::assertion())
+    }
+    fn verify_tls12_signature(
+        &self,
+        _: &[u8],
+        _: &rustls::pki_types::CertificateDer,
+        _: &rustls::DigitallySignedStruct,
+    ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
+        Ok(rustls::client::danger::HandshakeSignatureValid::assertion())
+    }
+    fn verify_tls13_signature(
+        &self,
+        _: &[u8],
+        _: &rustls::pki_types::CertificateDer,
+        _: &rustls::DigitallySignedStruct,
+    ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
+        Ok(rustls::client::danger::HandshakeSignatureValid::assertion())
+    }
+    fn supported_verify_schemes(&self) -> Vec<rustls::SignatureScheme> {
+        rustls::crypto::ring::default_provider()
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+/// A new P-256 device key pair.
+pub fn gen_p256_key() -> (p256::ecdsa::SigningKey, p256::ecdsa::VerifyingKey) {
+    let sk = p256::ecdsa::SigningKey::random(&mut rand::thread_rng());
+    let vk = *sk.verifying_key();
+    (sk, vk)
+}
+
+/// Send a length-prefixed JSON message on a QUIC stream.
+pub async fn send_json(send: &mut quinn::SendStream, value: &serde_json::Value) -> Result<()> {
+    let json = serde_json::to_vec(value)?;
+    let len = (json.len() as u32).to_be_bytes();
+    send.write_all(&len).await?;
+    send.write_all(&json).await?;
+    Ok(())
+}
+
+/// Receive a length-prefixed JSON message from a QUIC stream.
+pub async fn recv_json(recv: &mut quinn::RecvStream) -> Result<serde_json::Value> {
+    let mut len_buf = [0u8; 4];
+    recv.read_exact(&mut len_buf).await?;
+    let len = u32::from_be_bytes(len_buf) as usize;
+    let mut buf = vec![0u8; len];
+    recv.read_exact(&mut buf).await?;
+    Ok(serde_json::from_slice(&buf)?)
+}
+
+/// A daemon serving on loopback, and a client endpoint for the device
+/// paired with it. The daemon stops when the harness is dropped.
+pub struct TestHarness {
+    server_addr: SocketAddr,
+    client_endpoint: quinn::Endpoint,
+    device_id: String,
+    signing_key: p256::ecdsa::SigningKey,
+    session_manager: Arc<SessionManager>,
+    device_store: Arc<DeviceStore>,
+    server: tokio::task::JoinHandle<()>,
+    reaper_cancel: tokio_util::sync::CancellationToken,
+    temp_dir: tempfile::TempDir,
+}
+
+impl Drop for TestHarness {
+    fn drop(&mut self) {
+        self.server.abort();
+        self.reaper_cancel.cancel();
+    }
+}
+
+impl TestHarness {
+    /// Start a daemon with default settings and device `test-device-001`
+    /// paired. Must be called within a tokio runtime.
+    pub async fn new() -> Result<Self> {
+        Self::with_session_manager(|manager| manager).await
+    }
+
+    /// Like [`TestHarness::new`], with the session manager adjusted by
+    /// `configure` before the daemon starts.
+    pub async fn with_session_manager(configure: impl FnOnce(SessionManager) -> SessionManager) -> Result<Self> {
+        let (cert_der, key_der) = gen_test_cert();
+        let server_config = build_server_config(&cert_der, &key_der);
+
+        let server_endpoint =
+            quinn::Endpoint::server(server_config, "127.0.0.1:0".parse().unwrap())?;
+        let server_addr = server_endpoint.local_addr()?;
+
+        // Create temp dir for device store
+        let temp_dir = tempfile::TempDir::new()?;
+
+        // Create device store and pre-pair a test device
+        let (sk, vk) = gen_p256_key();
+        let device_id = "test-device-001".to_string();
+        let pub_key_b64 = {
+            use base64::Engine;
+            let point = p256::EncodedPoint::from(vk);
+            base64::engine::general_purpose::STANDARD.encode(point.as_bytes())
+        };
+
+        // Write devices.json with pre-paired device
+        let devices_json = serde_json::json!({
+            "devices": {
+                &device_id: {
+                    "device_id": &device_id,
+                    "public_key": &pub_key_b64,
+                    "device_name": "Test Device",
+                    "paired_at": "2024-01-01T00:00:00Z",
+                    "last_seen": null,
+                }
+            }
+        });
+        std::fs::write(
+            temp_dir.path().join("devices.json"),
+            serde_json::to_string_pretty(&devices_json)?,
+        )?;
+
+        // Start server components
+        let device_store = Arc::new(DeviceStore::new(temp_dir.path())?);
+        let store = device_store.clone();
+        let session_manager = Arc::new(configure(
+            SessionManager::new()
+                .with_agent_dir(temp_dir.path().join("agent"))
+                .with_agent_permission(move |device_id| store.agent_forwarding_allowed(device_id)),
+        ));
+        let authenticator = Arc::new(
+            Authenticator::new(device_store.clone())
+                .with_clock(session_manager.clock().clone())
+                .with_vouching(session_manager.pairing_requests().clone(), session_manager.notifier()),
+        );
+
+        // Start session reaper
+        let sm_for_reaper = session_manager.clone();
+        let reaper_cancel = tokio_util::sync::CancellationToken::new();
+        let reaper_cancel_clone = reaper_cancel.clone();
+        tokio::spawn(async move {
+            sm_for_reaper.run_reaper(reaper_cancel_clone, 5).await;
+        });
+
+        let rate_limits = RateLimitConfig {
+            connection_limit: 100,
+            connection_window_secs: 60,
+            auth_failure_limit: 10,
+            auth_failure_window_secs: 300,
+            ..Default::default()
+        };
+        let limits = Arc::new(RateLimits::new(&rate_limits, session_manager.clock().clone()));
+        let sm_for_server = session_manager.clone();
+        let server_handle = tokio::spawn(async move {
+            if let Err(e) = crate::server::run(
+                server_endpoint,
+                sm_for_server,
+                authenticator,
+                Intake {
+                    gauges: Arc::new(ConnectionGauges::new(0, 0)),
+                    limits,
+                    connections: Arc::new(Connections::new()),
+                },
+            )
+            .await
+            {
+                eprintln!("server error: {e:#}");
+            }
+        });
+
+        // Build client endpoint
+        let mut client_endpoint = quinn::Endpoint::client("0.0.0.0:0".parse().unwrap())?;
+        client_endpoint.set_default_client_config(build_client_config());
+
+        Ok(Self {
+            server_addr,
+            client_endpoint,
+            device_id,
+            signing_key: sk,
+            session_manager,
+            device_store,
+            server: server_handle,
+            reaper_cancel,
+            temp_dir,
+        })
+    }
+
+    /// Where the daemon listens.
+    pub fn server_addr(&self) -> SocketAddr {
+        self.server_addr
+    }
+
+    /// The pre-paired test device.
+    pub fn device_id(&self) -> &str {
+        &self.device_id
+    }
+
+    /// The daemon's sessions, to inspect or set up directly.
+    pub fn session_manager(&self) -> &Arc<SessionManager> {
+        &self.session_manager
+    }
+
+    /// The daemon's paired devices and tokens, e.g. to mint a guest token.
+    pub fn device_store(&self) -> &Arc<DeviceStore> {
+        &self.device_store
+    }
+
+    /// The daemon's data directory.
+    pub fn data_dir(&self) -> &Path {
+        self.temp_dir.path()
+    }
+
+    /// Where agent-forwarding sessions' sockets are created.
+    pub fn agent_dir(&self) -> PathBuf {
+        self.temp_dir.path().join("agent")
+    }
+
+    /// Connect to the server and authenticate.
+    pub async fn connect_and_auth(&self) -> Result<quinn::Connection> {
+        self.connect_and_auth_via(self.server_addr).await
+    }
+
+    /// Connect through `addr` (e.g. a proxy in front of the server) and authenticate.
+    pub async fn connect_and_auth_via(&self, addr: SocketAddr) -> Result<quinn::Connection> {
+        Ok(self.challenge_auth(addr).await?.0)
+    }
+
+    /// Connect and authenticate; also returns the auth response.
+    pub async fn challenge_auth(
+        &self,
+        addr: SocketAddr,
+    ) -> Result<(quinn::Connection, serde_json::Value)> {
+        let (connection, _, _, result) = self.auth_with(addr, serde_json::json!({})).await?;
+        Ok((connection, result))
+    }
+
+    /// Connect and authenticate saying `hello`; returns the connection and
+    /// the daemon's `hello` message of the wire it settled on.
+    pub async fn connect_with_hello(
+        &self,
+        hello: serde_json::Value,
+    ) -> Result<(quinn::Connection, serde_json::Value)> {
+        let (connection, _send, mut recv, _) = self
+            .auth_with(self.server_addr, serde_json::json!({ "hello": hello }))
+            .await?;
+        let msg = recv_json(&mut recv).await?;
+        anyhow::ensure!(msg["type"] == "hello", "expected hello: {msg}");
+        Ok((connection, msg))
+    }
+
+    /// Challenge auth with `extra` fields in the auth request; returns the
+    /// connection, the control streams and the auth response.
+    async fn auth_with(
+        &self,
+        addr: SocketAddr,
+        extra: serde_json::Value,
+    ) -> Result<(
+        quinn::Connection,
+        quinn::SendStream,
+        quinn::RecvStream,
+        serde_json::Value,
+    )> {
+        let connection = self
+            .client_endpoint
+            .connect(addr, "localhost")?
+            .await
+            .context("QUIC connect")?;
+
+        // Open control stream and authenticate
+        let (mut send, mut recv) = connection.open_bi().await?;
+
+        // Send auth_request
+        let mut auth_req = serde_json::json!({
+            "type": "auth_request",
+            "request_id": "test-auth-1",
+            "device_id": &self.device_id,
+        });
+        if let (Some(req), Some(extra)) = (auth_req.as_object_mut(), extra.as_object()) {
+            req.extend(extra.clone());
+        }
+        send_json(&mut send, &auth_req).await?;
+
+        // Receive challenge
+        let challenge_msg = recv_json(&mut recv).await?;
+        anyhow::ensure!(
+            challenge_msg["type"] == "auth_challenge",
+            "expected a challenge: {challenge_msg}"
+        );
+        let challenge_b64 = challenge_msg["challenge"]
+            .as_str()
+            .context("challenge missing")?;
+        let challenge_bytes = {
+            use base64::Engine;
+            base64::engine::general_purpose::STANDARD.decode(challenge_b64)?
+        };
+
+        // Sign challenge (without TLS exporter binding — daemon supports fallback)
+        let signature = {
+            use p256::ecdsa::{signature::Signer, Signature};
+            let sig: Signature = self.signing_key.sign(&challenge_bytes);
+            use base64::Engine;
+            base64::engine::general_purpose::STANDARD.encode(sig.to_der().as_bytes())
+        };
+
+        // Send auth response
+        let auth_resp = serde_json::json!({
+            "type": "auth_response",
+            "request_id": "test-auth-1",
+            "device_id": &self.device_id,
+            "signature": signature,
+        });
+        send_json(&mut send, &auth_resp).await?;
+
+        // Receive auth result
+        let result = recv_json(&mut recv).await?;
+        anyhow::ensure!(
+            result["type"] == "auth_response",
+            "expected an auth response: {result}"
+        );
+        anyhow::ensure!(
+            result["success"] == true,
+            "auth failed: {:?}",
+            result["error"]
+        );
+
+        Ok((connection, send, recv, result))
+    }
+
+    /// Connect and answer the challenge with a signature over the wrong
+    /// bytes. Returns the final auth response, which comes without a
+    /// challenge once the device is rate limited.
+    pub async fn auth_with_bad_signature(&self) -> Result<serde_json::Value> {
+        let connection = self
+            .client_endpoint
+            .connect(self.server_addr, "localhost")?
+            .await
+            .context("QUIC connect")?;
+
+        let (mut send, mut recv) = connection.open_bi().await?;
+        send_json(
+            &mut send,
+            &serde_json::json!({
+                "type": "auth_request",
+                "request_id": "bad-auth-1",
+                "device_id": &self.device_id,
+            }),
+        )
+        .await?;
+        let msg = recv_json(&mut recv).await?;
+        if msg["type"] != "auth_challenge" {
+            return Ok(msg);
+        }
+        let signature = {
+            use p256::ecdsa::{signature::Signer, Signature};
+            let sig: Signature = self.signing_key.sign(b"not the challenge");
+            use base64::Engine;
+            base64::engine::general_purpose::STANDARD.encode(sig.to_der().as_bytes())
+        };
+        send_json(
+            &mut send,
+            &serde_json::json!({
+                "type": "auth_response",
+                "request_id": "bad-auth-1",
+                "device_id": &self.device_id,
+                "signature": signature,
+            }),
+        )
+        .await?;
+        recv_json(&mut recv).await
+    }
+
+    /// Reconnect with a resume token. Returns the connection, the control
+    /// streams and the auth response, which is an `auth_challenge` if the
+    /// token wasn't accepted.
+    pub async fn connect_with_resume_token(
+        &self,
+        token: &str,
+    ) -> Result<(
+        quinn::Connection,
+        quinn::SendStream,
+        quinn::RecvStream,
+        serde_json::Value,
+    )> {
+        let connection = self
+            .client_endpoint
+            .connect(self.server_addr, "localhost")?
+            .await
+            .context("QUIC connect")?;
+
+        let (mut send, mut recv) = connection.open_bi().await?;
+        send_json(
+            &mut send,
+            &serde_json::json!({
+                "type": "auth_request",
+                "request_id": "resume-auth-1",
+                "device_id": &self.device_id,
+                "resume_token": token,
+            }),
+        )
+        .await?;
+        let result = recv_json(&mut recv).await?;
+
+        Ok((connection, send, recv, result))
+    }
+
+    /// Connect as unpaired `device_id` and ask to be paired by approval.
+    /// Returns the connection and the control streams; the auth response
+    /// comes once a paired device settles the request.
+    pub async fn request_vouched_pairing(
+        &self,
+        device_id: &str,
+    ) -> Result<(quinn::Connection, quinn::SendStream, quinn::RecvStream)> {
+        let connection = self
+            .client_endpoint
+            .connect(self.server_addr, "localhost")?
+            .await
+            .context("QUIC connect")?;
+
+        let (_, vk) = gen_p256_key();
+        let public_key = {
+            use base64::Engine;
+            let point = p256::EncodedPoint::from(vk);
+            base64::engine::general_purpose::STANDARD.encode(point.as_bytes())
+        };
+        let (mut send, recv) = connection.open_bi().await?;
+        send_json(
+            &mut send,
+            &serde_json::json!({
+                "type": "auth_request",
+                "request_id": "vouch-auth-1",
+                "device_id": device_id,
+                "public_key": public_key,
+                "device_name": "New Phone",
+                "vouch": true,
+            }),
+        )
+        .await?;
+
+        Ok((connection, send, recv))
+    }
+
+    /// Connect with a guest token. Returns the connection and the auth
+    /// response, successful or not.
+    pub async fn connect_as_guest(
+        &self,
+        device_id: &str,
+        token: &str,
+    ) -> Result<(quinn::Connection, serde_json::Value)> {
+        let connection = self
+            .client_endpoint
+            .connect(self.server_addr, "localhost")?
+            .await
+            .context("QUIC connect")?;
+
+        let (mut send, mut recv) = connection.open_bi().await?;
+        send_json(
+            &mut send,
+            &serde_json::json!({
+                "type": "auth_request",
+                "request_id": "guest-auth-1",
+                "device_id": device_id,
+                "guest_token": token,
+            }),
+        )
+        .await?;
+        let result = recv_json(&mut recv).await?;
+        anyhow::ensure!(
+            result["type"] == "auth_response",
+            "expected an auth response: {result}"
+        );
+
+        Ok((connection, result))
+    }
+}
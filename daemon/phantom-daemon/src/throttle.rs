@@ -0,0 +1,282 @@
+//! Output throttling for low-bandwidth attaches (`"low_bandwidth": true` in
+//! create/attach requests), for clients on metered connections.
+//!
+//! The bridge sends output at most every [`FLUSH_INTERVAL`], and of what
+//! piled up in between only what is still on screen: the part after the
+//! last full-screen clear, and at most a screenful of lines. `yes` or a
+//! verbose build then costs a few KB a second instead of megabytes. Escape
+//! sequences in the dropped part are kept, so modes, colors and the
+//! alternate screen stay right. The session's scrollback and mirrors still
+//! get all output.
+//!
+//! Separately, a client can cap an attachment's output at so many bytes a
+//! second (`"max_bytes_per_sec"`), for a session left attached in the
+//! background: the bridge holds each frame until the [`OutputPacer`] lets
+//! it go, and output queued meanwhile is coalesced into the next frame.
+
+use bytes::BytesMut;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// How often a low-bandwidth bridge sends output.
+pub const FLUSH_INTERVAL: Duration = Duration::from_millis(250);
+/// Lowest output cap a client can set; lower caps are raised to it.
+pub const MIN_BYTES_PER_SEC: u64 = 1024;
+
+const ESC: u8 = 0x1b;
+
+/// Output held between flushes, and thinned output not sent yet.
+pub struct OutputThrottle {
+    held: Vec<u8>,
+    unsent: BytesMut,
+    last_flush: Instant,
+}
+
+impl OutputThrottle {
+    pub fn new() -> Self {
+        Self {
+            held: Vec::new(),
+            unsent: BytesMut::new(),
+            last_flush: Instant::now(),
+        }
+    }
+
+    /// Hold output until the next flush.
+    pub fn push(&mut self, data: &[u8]) {
+        self.held.extend_from_slice(data);
+    }
+
+    /// When held output is due.
+    pub fn flush_at(&self) -> Instant {
+        self.last_flush + FLUSH_INTERVAL
+    }
+
+    /// Thin held output for a terminal of `rows` rows, to be sent.
+    pub fn flush(&mut self, rows: usize) {
+        let thinned = thin(&self.held, rows);
+        self.unsent.extend_from_slice(&thinned);
+        self.held.clear();
+        self.last_flush = Instant::now();
+    }
+
+    pub fn has_unsent(&self) -> bool {
+        !self.unsent.is_empty()
+    }
+
+    /// Move up to `max` bytes of flushed output to `payload`.
+    pub fn take(&mut self, payload: &mut BytesMut, max: usize) {
+        let n = self.unsent.len().min(max);
+        payload.extend_from_slice(&self.unsent.split_to(n));
+    }
+}
+
+impl Default for OutputThrottle {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Paces output to a cap of so many bytes a second, with up to a second's
+/// worth in a burst. A frame bigger than the burst still goes, and the
+/// frames after it wait until it is paid off.
+pub struct OutputPacer {
+    rate: f64,
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl OutputPacer {
+    pub fn new(bytes_per_sec: u64, now: Instant) -> Self {
+        let rate = bytes_per_sec.max(MIN_BYTES_PER_SEC) as f64;
+        Self { rate, tokens: rate, refilled: now }
+    }
+
+    /// When the next frame may be sent.
+    pub fn ready_at(&mut self, now: Instant) -> Instant {
+        self.refill(now);
+        if self.tokens >= 0.0 {
+            return now;
+        }
+        now + Duration::from_secs_f64(-self.tokens / self.rate)
+    }
+
+    /// Largest payload to put in one frame, so no frame holds the next
+    /// (or a heartbeat) back for more than about a second.
+    pub fn frame_max(&self) -> usize {
+        self.rate as usize
+    }
+
+    /// Charge `bytes` sent at `now`.
+    pub fn sent(&mut self, bytes: u64, now: Instant) {
+        self.refill(now);
+        self.tokens -= bytes as f64;
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.refilled);
+        self.tokens = (self.tokens + elapsed.as_secs_f64() * self.rate).min(self.rate);
+        self.refilled = now;
+    }
+}
+
+/// What of `output` is left on a terminal of `rows` rows once it has all
+/// been written: everything from the last full-screen clear on, at most the
+/// last `rows` lines, and before that only escape sequences.
+pub fn thin(output: &[u8], rows: usize) -> Vec<u8> {
+    // (start, end) of each escape sequence, in order
+    let mut escapes: Vec<(usize, usize)> = Vec::new();
+    // Offsets just past each newline outside escape sequences
+    let mut newlines = Vec::new();
+    let mut last_clear = None;
+    let mut i = 0;
+    while i < output.len() {
+        match output[i] {
+            ESC => {
+                let end = escape_end(output, i);
+                let seq = &output[i..end];
+                if seq == b"\x1b[2J" || seq == b"\x1bc" {
+                    last_clear = Some(i);
+                } else if matches!(seq, b"\x1b[J" | b"\x1b[0J") {
+                    // Cursor home then erase below clears the screen too
+                    if let Some(&(start, home_end)) = escapes.last() {
+                        if home_end == i && is_home(&output[start..home_end]) {
+                            last_clear = Some(start);
+                        }
+                    }
+                }
+                escapes.push((i, end));
+                i = end;
+            }
+            b'\n' => {
+                newlines.push(i + 1);
+                i += 1;
+            }
+            _ => i += 1,
+        }
+    }
+
+    let line_cut = newlines.len().checked_sub(rows + 1).map_or(0, |n| newlines[n]);
+    let cut = last_clear.unwrap_or(0).max(line_cut);
+    if cut == 0 {
+        return output.to_vec();
+    }
+    let mut thinned: Vec<u8> = escapes
+        .iter()
+        .take_while(|&&(_, end)| end <= cut)
+        .flat_map(|&(start, end)| &output[start..end])
+        .copied()
+        .collect();
+    thinned.extend_from_slice(&output[cut..]);
+    thinned
+}
+
+/// End of the escape sequence starting at `buf[start]` (an ESC), or the end
+/// of `buf` if it's cut short.
+fn escape_end(buf: &[u8], start: usize) -> usize {
+    let Some(&kind) = buf.get(start + 1) else {
+        return buf.len();
+    };
+    let body = start + 2;
+    let final_byte = |range: std::ops::RangeInclusive<u8>| {
+        buf[body..].iter().position(|b| range.contains(b)).map_or(buf.len(), |p| body + p + 1)
+    };
+    match kind {
+        // CSI: parameters and intermediates, then a final byte
+        b'[' => final_byte(0x40..=0x7e),
+        // OSC, DCS, SOS, PM, APC: a string ended by BEL or ST (ESC \)
+        b']' | b'P' | b'X' | b'^' | b'_' => {
+            let mut i = body;
+            while i < buf.len() {
+                match buf[i] {
+                    0x07 => return i + 1,
+                    ESC if buf.get(i + 1) == Some(&b'\\') => return i + 2,
+                    _ => i += 1,
+                }
+            }
+            buf.len()
+        }
+        // Intermediates (e.g. a charset designation, `ESC ( B`), then a final byte
+        0x20..=0x2f => final_byte(0x30..=0x7e),
+        _ => body,
+    }
+}
+
+/// Whether `seq` moves the cursor to the top left.
+fn is_home(seq: &[u8]) -> bool {
+    matches!(seq, b"\x1b[H" | b"\x1b[;H" | b"\x1b[1;1H")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_only_the_last_screenful_of_lines() {
+        let output = b"y\n".repeat(10_000);
+        assert_eq!(thin(&output, 24), b"y\n".repeat(24));
+        // Short output goes through untouched
+        assert_eq!(thin(b"one\ntwo\n", 24), b"one\ntwo\n");
+    }
+
+    #[test]
+    fn drops_redraws_before_the_last_clear_but_keeps_escapes() {
+        let mut output = b"\x1b[?1049h".to_vec();
+        for frame in ["first", "second", "third"] {
+            output.extend_from_slice(b"\x1b[H\x1b[2J\x1b[1m");
+            output.extend_from_slice(frame.as_bytes());
+        }
+        assert_eq!(thin(&output, 24), b"\x1b[?1049h\x1b[H\x1b[2J\x1b[1m\x1b[H\x1b[2J\x1b[1m\x1b[H\x1b[2J\x1b[1mthird");
+
+        // Home then erase below counts as a clear, from the home sequence on
+        let output = b"\x1b]0;title\x07old screen\x1b[H\x1b[Jnew screen";
+        assert_eq!(thin(output, 24), b"\x1b]0;title\x07\x1b[H\x1b[Jnew screen");
+    }
+
+    #[test]
+    fn escape_sequences_are_not_split() {
+        let output = b"a\n\x1b]0;two\nlines\x07b\nc\n";
+        // The OSC's newline isn't a line break
+        assert_eq!(thin(output, 1), b"\x1b]0;two\nlines\x07c\n");
+        assert_eq!(escape_end(b"\x1b[38;5;1", 0), 8);
+        assert_eq!(escape_end(b"\x1b(Bx", 0), 3);
+    }
+
+    #[tokio::test]
+    async fn throttle_sends_flushed_output_in_frames() {
+        let mut throttle = OutputThrottle::new();
+        throttle.push(&b"y\n".repeat(100));
+        assert!(!throttle.has_unsent());
+        throttle.flush(3);
+        let mut payload = BytesMut::new();
+        throttle.take(&mut payload, 4);
+        assert_eq!(&payload[..], b"y\ny\n");
+        throttle.take(&mut payload, 4);
+        assert_eq!(&payload[..], b"y\ny\ny\n");
+        assert!(!throttle.has_unsent());
+        assert!(throttle.flush_at() > Instant::now());
+    }
+
+    #[test]
+    fn paces_output_to_the_cap() {
+        let start = Instant::now();
+        let mut pacer = OutputPacer::new(4096, start);
+        assert_eq!(pacer.frame_max(), 4096);
+        // A second's worth goes at once
+        assert_eq!(pacer.ready_at(start), start);
+        pacer.sent(4096, start);
+        assert_eq!(pacer.ready_at(start), start);
+        // Overdrawn by a frame: wait until it's paid off
+        pacer.sent(2048, start);
+        assert_eq!(pacer.ready_at(start), start + Duration::from_millis(500));
+        let later = start + Duration::from_millis(500);
+        assert_eq!(pacer.ready_at(later), later);
+        // Idle time refills at most a second's worth
+        let idle = later + Duration::from_secs(10);
+        pacer.sent(8192, idle);
+        assert_eq!(pacer.ready_at(idle), idle + Duration::from_secs(1));
+        // Caps below the minimum are raised
+        let mut slow = OutputPacer::new(1, start);
+        slow.sent(2048, start);
+        assert_eq!(slow.ready_at(start), start + Duration::from_secs(1));
+    }
+}
@@ -2,27 +2,86 @@ use anyhow::{Context, Result};
 use quinn::crypto::rustls::QuicServerConfig;
 use rcgen::{CertificateParams, KeyPair};
 use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 use std::fs;
-use std::path::PathBuf;
+use std::path::Path;
 use std::sync::Arc;
 use std::time::Duration;
 use tracing::info;
 
-/// Paths for persistent TLS material under ~/.phantom/
-fn phantom_dir() -> Result<PathBuf> {
-    let home = dirs::home_dir().context("cannot determine home directory")?;
-    let dir = home.join(".phantom");
-    fs::create_dir_all(&dir).context("create ~/.phantom")?;
-    Ok(dir)
-}
+use crate::config::ProtocolConfig;
+
+const CERT_FILE: &str = "server.crt";
+const KEY_FILE: &str = "server.key";
 
-fn cert_path() -> Result<PathBuf> {
-    Ok(phantom_dir()?.join("server.crt"))
+/// A certificate and its private key, as PEM: what devices pin the host by.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Identity {
+    pub cert_pem: String,
+    pub key_pem: String,
 }
 
-fn key_path() -> Result<PathBuf> {
-    Ok(phantom_dir()?.join("server.key"))
+impl Identity {
+    /// A new P256 self-signed certificate.
+    pub fn generate() -> Result<Self> {
+        let key_pair = KeyPair::generate_for(&rcgen::PKCS_ECDSA_P256_SHA256)
+            .context("generate P256 key pair")?;
+
+        let params = CertificateParams::new(vec!["phantom.local".to_string()])
+            .context("create cert params")?;
+
+        let cert = params
+            .self_signed(&key_pair)
+            .context("self-sign certificate")?;
+
+        Ok(Self { cert_pem: cert.pem(), key_pem: key_pair.serialize_pem() })
+    }
+
+    /// The identity saved in `dir`, if there is one.
+    pub fn load(dir: &Path) -> Result<Option<Self>> {
+        let (cp, kp) = (dir.join(CERT_FILE), dir.join(KEY_FILE));
+        if !(cp.exists() && kp.exists()) {
+            return Ok(None);
+        }
+        Ok(Some(Self {
+            cert_pem: fs::read_to_string(&cp).context("read server.crt")?,
+            key_pem: fs::read_to_string(&kp).context("read server.key")?,
+        }))
+    }
+
+    /// Save in `dir`, replacing the identity there.
+    pub fn save(&self, dir: &Path) -> Result<()> {
+        fs::write(dir.join(CERT_FILE), &self.cert_pem).context("write server.crt")?;
+        fs::write(dir.join(KEY_FILE), &self.key_pem).context("write server.key")?;
+        Ok(())
+    }
+
+    /// Copy the identity saved in `dir` to `server.crt.bak` and
+    /// `server.key.bak`, replacing an earlier backup. Whether there was one.
+    pub fn back_up(dir: &Path) -> Result<bool> {
+        let (cp, kp) = (dir.join(CERT_FILE), dir.join(KEY_FILE));
+        if !(cp.exists() && kp.exists()) {
+            return Ok(false);
+        }
+        fs::copy(&cp, crate::device_store::sibling(&cp, "bak")).context("back up server.crt")?;
+        fs::copy(&kp, crate::device_store::sibling(&kp, "bak")).context("back up server.key")?;
+        Ok(true)
+    }
+
+    /// Certificate and key as DER.
+    pub fn to_der(&self) -> Result<(Vec<u8>, Vec<u8>)> {
+        let cert_der = pem_to_der(&self.cert_pem, "CERTIFICATE")
+            .context("parse certificate PEM")?;
+        let key_der = pem_to_der(&self.key_pem, "PRIVATE KEY")
+            .context("parse key PEM")?;
+        Ok((cert_der, key_der))
+    }
+
+    /// [`fingerprint_base64`] of the certificate.
+    pub fn fingerprint(&self) -> Result<String> {
+        Ok(fingerprint_base64(&self.to_der()?.0))
+    }
 }
 
 /// SHA-256 fingerprint of a DER-encoded certificate, returned as raw bytes.
@@ -38,26 +97,12 @@ pub fn fingerprint_base64(cert_der: &[u8]) -> String {
     base64::engine::general_purpose::STANDARD.encode(fingerprint(cert_der))
 }
 
-/// Generate a new P256 self-signed certificate and persist to disk.
-fn generate_and_persist() -> Result<(Vec<u8>, Vec<u8>)> {
-    let key_pair = KeyPair::generate_for(&rcgen::PKCS_ECDSA_P256_SHA256)
-        .context("generate P256 key pair")?;
-
-    let params = CertificateParams::new(vec!["phantom.local".to_string()])
-        .context("create cert params")?;
-
-    let cert = params
-        .self_signed(&key_pair)
-        .context("self-sign certificate")?;
-
-    let cert_der = cert.der().to_vec();
-    let key_der = key_pair.serialize_der();
-
-    let cert_pem = cert.pem();
-    let key_pem = key_pair.serialize_pem();
-
-    fs::write(cert_path()?, &cert_pem).context("write server.crt")?;
-    fs::write(key_path()?, &key_pem).context("write server.key")?;
+/// Generate a new P256 self-signed certificate and persist it in `dir`.
+fn generate_and_persist(dir: &Path) -> Result<(Vec<u8>, Vec<u8>)> {
+    let identity = Identity::generate()?;
+    fs::create_dir_all(dir).with_context(|| format!("create {}", dir.display()))?;
+    identity.save(dir)?;
+    let (cert_der, key_der) = identity.to_der()?;
 
     let fp = fingerprint_base64(&cert_der);
     info!("generated new TLS certificate, fingerprint: {fp}");
@@ -65,37 +110,30 @@ fn generate_and_persist() -> Result<(Vec<u8>, Vec<u8>)> {
     Ok((cert_der, key_der))
 }
 
-/// Load existing cert and key from disk, or generate new ones.
-pub fn load_or_generate() -> Result<(Vec<u8>, Vec<u8>)> {
-    let cp = cert_path()?;
-    let kp = key_path()?;
-
-    if cp.exists() && kp.exists() {
-        let cert_pem = fs::read_to_string(&cp).context("read server.crt")?;
-        let key_pem = fs::read_to_string(&kp).context("read server.key")?;
-
-        let cert_der = pem_to_der(&cert_pem, "CERTIFICATE")
-            .context("parse certificate PEM")?;
-        let key_der = pem_to_der(&key_pem, "PRIVATE KEY")
-            .context("parse key PEM")?;
+/// Load the cert and key in the data directory `dir`, or generate new ones.
+pub fn load_or_generate(dir: &Path) -> Result<(Vec<u8>, Vec<u8>)> {
+    if let Some(identity) = Identity::load(dir)? {
+        let (cert_der, key_der) = identity.to_der()?;
 
         let fp = fingerprint_base64(&cert_der);
         info!("loaded TLS certificate, fingerprint: {fp}");
 
         Ok((cert_der, key_der))
     } else {
-        generate_and_persist()
+        generate_and_persist(dir)
     }
 }
 
 /// Rotate: generate a new cert, replacing the old one on disk.
-pub fn rotate_cert() -> Result<(Vec<u8>, Vec<u8>)> {
+pub fn rotate_cert(dir: &Path) -> Result<(Vec<u8>, Vec<u8>)> {
     info!("rotating TLS certificate");
-    generate_and_persist()
+    generate_and_persist(dir)
 }
 
-/// Build a quinn ServerConfig from cert/key DER bytes.
-pub fn build_server_config(cert_der: &[u8], key_der: &[u8]) -> Result<quinn::ServerConfig> {
+/// Build a quinn ServerConfig from cert/key DER bytes, advertising the
+/// ALPN versions in `protocols` (rejected ones too, so their clients get a
+/// reason when the connection is closed instead of a failed handshake).
+pub fn build_server_config(cert_der: &[u8], key_der: &[u8], protocols: &ProtocolConfig) -> Result<quinn::ServerConfig> {
     let cert = CertificateDer::from(cert_der.to_vec());
     let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(key_der.to_vec()));
 
@@ -104,7 +142,7 @@ pub fn build_server_config(cert_der: &[u8], key_der: &[u8]) -> Result<quinn::Ser
         .with_single_cert(vec![cert], key)
         .context("build rustls ServerConfig")?;
 
-    rustls_config.alpn_protocols = vec![b"phantom/1".to_vec()];
+    rustls_config.alpn_protocols = protocols.alpn.iter().map(|v| v.name.as_bytes().to_vec()).collect();
 
     let quic_crypto = QuicServerConfig::try_from(rustls_config)
         .context("convert rustls config to QUIC config")?;
@@ -124,6 +162,27 @@ pub fn build_server_config(cert_der: &[u8], key_der: &[u8]) -> Result<quinn::Ser
     Ok(server_config)
 }
 
+/// A client config trusting only `cert_der`, the host's own certificate, for
+/// connecting to the daemon from the same host. Offers this build's protocol.
+pub fn build_client_config(cert_der: &[u8]) -> Result<quinn::ClientConfig> {
+    let mut roots = rustls::RootCertStore::empty();
+    roots.add(CertificateDer::from(cert_der.to_vec())).context("trust the host certificate")?;
+    let mut rustls_config = rustls::ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    rustls_config.alpn_protocols = vec![format!("phantom/{}", crate::PROTOCOL_VERSION).into_bytes()];
+
+    let quic_crypto = quinn::crypto::rustls::QuicClientConfig::try_from(rustls_config)
+        .context("convert rustls config to QUIC config")?;
+    Ok(quinn::ClientConfig::new(Arc::new(quic_crypto)))
+}
+
+/// The ALPN protocol `conn` negotiated.
+pub fn negotiated_protocol(conn: &quinn::Connection) -> Option<String> {
+    let data = conn.handshake_data()?.downcast::<quinn::crypto::rustls::HandshakeData>().ok()?;
+    data.protocol.map(|p| String::from_utf8_lossy(&p).into_owned())
+}
+
 /// Extract DER bytes from a PEM string. Simple parser, no external dep.
 fn pem_to_der(pem: &str, expected_label: &str) -> Result<Vec<u8>> {
     use base64::Engine;
@@ -141,3 +200,44 @@ fn pem_to_der(pem: &str, expected_label: &str) -> Result<Vec<u8>> {
         .decode(&b64)
         .context("base64 decode PEM body")
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{AlpnPolicy, AlpnVersion};
+
+    #[tokio::test]
+    async fn clients_get_the_first_advertised_version_they_offer() {
+        let _ = rustls::crypto::ring::default_provider().install_default();
+        let cert = rcgen::generate_simple_self_signed(vec!["phantom.local".into()]).unwrap();
+        let version = |name: &str, policy| AlpnVersion { name: name.to_string(), policy };
+        let protocols = ProtocolConfig {
+            alpn: vec![version("phantom/2", AlpnPolicy::Accept), version("phantom/1", AlpnPolicy::Deprecated)],
+        };
+        let key = cert.key_pair.serialize_der();
+        let server_config = build_server_config(cert.cert.der(), &key, &protocols).unwrap();
+        let server = quinn::Endpoint::server(server_config, "127.0.0.1:0".parse().unwrap()).unwrap();
+
+        let connect = |offered: &[&str]| {
+            let mut roots = rustls::RootCertStore::empty();
+            roots.add(cert.cert.der().clone()).unwrap();
+            let mut crypto = rustls::ClientConfig::builder().with_root_certificates(roots).with_no_client_auth();
+            crypto.alpn_protocols = offered.iter().map(|p| p.as_bytes().to_vec()).collect();
+            let mut client = quinn::Endpoint::client("127.0.0.1:0".parse().unwrap()).unwrap();
+            client.set_default_client_config(quinn::ClientConfig::new(Arc::new(
+                quinn::crypto::rustls::QuicClientConfig::try_from(crypto).unwrap(),
+            )));
+            let connecting = client.connect(server.local_addr().unwrap(), "phantom.local").unwrap();
+            (client, connecting)
+        };
+        for (offered, negotiated) in [(&["phantom/1"][..], "phantom/1"), (&["phantom/1", "phantom/2"], "phantom/2")] {
+            let (_endpoint, connecting) = connect(offered);
+            let (client, accepted) = tokio::join!(connecting, async { server.accept().await.unwrap().await });
+            let accepted = accepted.unwrap();
+            assert_eq!(negotiated_protocol(&accepted).as_deref(), Some(negotiated));
+            assert_eq!(negotiated_protocol(&client.unwrap()).as_deref(), Some(negotiated));
+        }
+        assert_eq!(protocols.policy("phantom/1"), Some(AlpnPolicy::Deprecated));
+        assert_eq!(protocols.policy("phantom/0"), None);
+    }
+}
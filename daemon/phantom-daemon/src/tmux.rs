@@ -0,0 +1,169 @@
+//! tmux passthrough: sessions that attach to the user's existing tmux
+//! sessions (`tmux attach -t`) instead of starting a fresh shell.
+//!
+//! Destroying such a session hangs up the tmux client only; the tmux session
+//! itself keeps running, as it would after closing any other terminal.
+
+use std::path::PathBuf;
+
+use anyhow::{bail, Result};
+use portable_pty::CommandBuilder;
+
+use crate::errors::ErrorCode;
+use crate::terminal::{NativePty, TerminalCaps};
+
+/// Longest accepted tmux session name.
+const MAX_NAME_LEN: usize = 128;
+/// `list-sessions` format: name, windows, attached clients, creation time.
+/// Separated by `:`, which tmux never allows in session names (tabs would be
+/// escaped outside UTF-8 locales).
+const LIST_FORMAT: &str = "#{session_name}:#{session_windows}:#{session_attached}:#{session_created}";
+
+/// A session on the user's tmux server.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct TmuxSession {
+    pub name: String,
+    pub windows: u32,
+    /// tmux clients attached to it, including Phantom's
+    pub attached: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+/// Reject names tmux would interpret as a target path (`session:window.pane`)
+/// or that can't be passed on as a single argument.
+pub fn validate_name(name: &str) -> Result<()> {
+    if name.is_empty() || name.len() > MAX_NAME_LEN {
+        return Err(ErrorCode::BadRequest.err("invalid tmux session name"));
+    }
+    if name.chars().any(|c| c == ':' || c == '.' || c.is_control()) {
+        return Err(ErrorCode::BadRequest.err(format!("invalid tmux session name: {name}")));
+    }
+    Ok(())
+}
+
+/// The tmux server sessions attach to: the user's own, or the one whose
+/// socket is under a given `TMUX_TMPDIR`.
+#[derive(Debug, Clone, Default)]
+pub struct Tmux {
+    tmpdir: Option<PathBuf>,
+}
+
+impl Tmux {
+    /// The server whose socket is under `dir`, rather than the user's own.
+    pub fn with_tmpdir(dir: PathBuf) -> Self {
+        Self { tmpdir: Some(dir) }
+    }
+
+    /// Sessions on the server. Empty when no server is running.
+    pub async fn list_sessions(&self) -> Result<Vec<TmuxSession>> {
+        let output = self
+            .command(&["list-sessions", "-F", LIST_FORMAT])
+            .output()
+            .await
+            .map_err(|e| ErrorCode::Unavailable.err(format!("run tmux (is it installed?): {e}")))?;
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            if is_no_server(&stderr) {
+                return Ok(Vec::new());
+            }
+            bail!("tmux list-sessions failed: {}", stderr.trim());
+        }
+        Ok(parse_sessions(&String::from_utf8_lossy(&output.stdout)))
+    }
+
+    /// Whether a tmux session called `name` exists.
+    pub async fn has_session(&self, name: &str) -> Result<bool> {
+        let status = self
+            .command(&["has-session", "-t", &exact_target(name)])
+            .stderr(std::process::Stdio::null())
+            .status()
+            .await
+            .map_err(|e| ErrorCode::Unavailable.err(format!("run tmux (is it installed?): {e}")))?;
+        Ok(status.success())
+    }
+
+    /// Attach a tmux client to session `name` in a new PTY.
+    pub fn attach(&self, rows: u16, cols: u16, name: &str, terminal: &TerminalCaps) -> Result<NativePty> {
+        validate_name(name)?;
+        let mut cmd = CommandBuilder::new("tmux");
+        cmd.args(["attach-session", "-t", &exact_target(name)]);
+        // tmux refuses to attach from inside another tmux client
+        cmd.env_remove("TMUX");
+        if let Some(dir) = &self.tmpdir {
+            cmd.env("TMUX_TMPDIR", dir);
+        }
+        if let Some(home) = dirs::home_dir() {
+            cmd.cwd(home);
+        }
+        NativePty::spawn_terminal(rows, cols, cmd, terminal)
+    }
+
+    fn command(&self, args: &[&str]) -> tokio::process::Command {
+        let mut cmd = tokio::process::Command::new("tmux");
+        cmd.args(args).env_remove("TMUX").kill_on_drop(true);
+        if let Some(dir) = &self.tmpdir {
+            cmd.env("TMUX_TMPDIR", dir);
+        }
+        cmd
+    }
+}
+
+/// Target that matches `name` exactly rather than as a prefix or pattern.
+fn exact_target(name: &str) -> String {
+    format!("={name}")
+}
+
+fn is_no_server(stderr: &str) -> bool {
+    stderr.contains("no server running") || stderr.contains("error connecting to")
+}
+
+fn parse_sessions(output: &str) -> Vec<TmuxSession> {
+    output
+        .lines()
+        .filter_map(|line| {
+            // Numbers come last, so split from the right
+            let mut fields = line.rsplitn(4, ':');
+            let created_at = fields
+                .next()?
+                .parse()
+                .ok()
+                .and_then(|secs| chrono::DateTime::from_timestamp(secs, 0));
+            let attached = fields.next()?.parse().ok()?;
+            let windows = fields.next()?.parse().ok()?;
+            let name = fields.next().filter(|name| !name.is_empty())?;
+            Some(TmuxSession {
+                name: name.to_string(),
+                windows,
+                attached,
+                created_at,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_list_sessions_output() {
+        let sessions = parse_sessions("work:3:1:1700000000\nscratch pad:1:0:1700000100\n\ngarbage\n");
+        assert_eq!(sessions.len(), 2);
+        assert_eq!(sessions[0].name, "work");
+        assert_eq!((sessions[0].windows, sessions[0].attached), (3, 1));
+        assert_eq!(sessions[0].created_at.unwrap().timestamp(), 1_700_000_000);
+        assert_eq!(sessions[1].name, "scratch pad");
+    }
+
+    #[test]
+    fn rejects_target_syntax_in_names() {
+        assert!(validate_name("work").is_ok());
+        assert!(validate_name("my session").is_ok());
+        assert!(validate_name("").is_err());
+        assert!(validate_name("work:1").is_err());
+        assert!(validate_name("work.0").is_err());
+        assert!(validate_name("work\n").is_err());
+        assert!(validate_name(&"x".repeat(MAX_NAME_LEN + 1)).is_err());
+    }
+}
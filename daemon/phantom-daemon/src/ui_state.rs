@@ -0,0 +1,338 @@
+//! Compact daemon state for GUI companions (the macOS menu-bar app).
+//!
+//! [`UiState`] is a stable schema: fields are only ever added, and a change
+//! that would break existing readers bumps [`SCHEMA_VERSION`]. It is served
+//! by the `ui_state` IPC method and pushed on every change to connections
+//! that call `subscribe_ui`.
+
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use tracing::field::{Field, Visit};
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::layer::{Context, Layer};
+
+use crate::device_store::DeviceStore;
+use crate::session::SessionManager;
+
+/// Version of the [`UiState`] schema.
+pub const SCHEMA_VERSION: u32 = 1;
+/// Warnings and errors kept for `recent_errors`.
+const MAX_RECENT_ERRORS: usize = 20;
+/// Longest session summary, in characters.
+const MAX_SUMMARY_CHARS: usize = 80;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct UiState {
+    pub schema: u32,
+    pub version: &'static str,
+    pub bind_address: String,
+    /// Devices with a live connection
+    pub devices: Vec<UiDevice>,
+    pub sessions: Vec<UiSession>,
+    pub pairing: UiPairing,
+    /// Oldest first
+    pub recent_errors: Vec<RecentError>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct UiDevice {
+    pub device_id: String,
+    pub device_name: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct UiSession {
+    pub id: String,
+    /// One line describing the session: its latest line of output, or what
+    /// it runs when there is none
+    pub summary: String,
+    pub alive: bool,
+    pub attached: bool,
+    /// Name of the attached device, when attached
+    pub attached_by: Option<String>,
+    pub mirrors: usize,
+    pub tmux_session: Option<String>,
+    pub last_activity_at: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct UiPairing {
+    /// A pairing token was issued and hasn't been used or expired yet
+    pub in_progress: bool,
+    pub expires_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RecentError {
+    pub at: chrono::DateTime<chrono::Utc>,
+    /// `warn` or `error`
+    pub level: &'static str,
+    pub message: String,
+}
+
+impl UiState {
+    pub fn collect(
+        session_manager: &SessionManager,
+        device_store: &DeviceStore,
+        recent_errors: &RecentErrors,
+        bind_address: &str,
+    ) -> Self {
+        let paired = device_store.list_devices();
+        let device_name = |id: &str| {
+            paired
+                .iter()
+                .find(|d| d.device_id == id)
+                .map(|d| d.device_name.clone())
+        };
+
+        let mut devices: Vec<UiDevice> = session_manager
+            .connected_device_ids()
+            .into_iter()
+            .filter_map(|id| {
+                let device_name = device_name(&id)?;
+                Some(UiDevice { device_id: id, device_name })
+            })
+            .collect();
+        devices.sort_by(|a, b| a.device_name.cmp(&b.device_name));
+
+        let mut sessions: Vec<UiSession> = session_manager
+            .list_sessions()
+            .into_iter()
+            .map(|s| {
+                let tail = session_manager
+                    .get_session(&s.id)
+                    .map(|session| {
+                        let scrollback = session.lock().expect("session lock").scrollback.clone();
+                        let tail = scrollback.lock().expect("scrollback lock").tail();
+                        tail
+                    })
+                    .unwrap_or_default();
+                let summary = summarize_output(&tail).unwrap_or_else(|| match &s.tmux_session {
+                    Some(name) => format!("tmux: {name}"),
+                    None => s.shell.rsplit('/').next().unwrap_or(&s.shell).to_string(),
+                });
+                let attached_by = s
+                    .attached
+                    .then(|| s.last_attached_by.as_deref().and_then(device_name))
+                    .flatten();
+                UiSession {
+                    id: s.id,
+                    summary,
+                    alive: s.alive,
+                    attached: s.attached,
+                    attached_by,
+                    mirrors: s.mirrors,
+                    tmux_session: s.tmux_session,
+                    last_activity_at: s.last_activity_at,
+                }
+            })
+            .collect();
+        sessions.sort_by(|a, b| a.id.cmp(&b.id));
+
+        let expires_at = device_store.pairing_expires_at();
+        Self {
+            schema: SCHEMA_VERSION,
+            version: crate::VERSION,
+            bind_address: bind_address.to_string(),
+            devices,
+            sessions,
+            pairing: UiPairing { in_progress: expires_at.is_some(), expires_at },
+            recent_errors: recent_errors.list(),
+        }
+    }
+}
+
+/// The last non-blank line of terminal output, with escape sequences
+/// removed and cut to [`MAX_SUMMARY_CHARS`].
+pub fn summarize_output(output: &[u8]) -> Option<String> {
+    let text = strip_escapes(&String::from_utf8_lossy(output));
+    // A carriage return without a newline redraws the line (progress bars),
+    // so the text after the last one is what's on screen
+    let line = text
+        .lines()
+        .filter_map(|line| line.rsplit('\r').map(str::trim).find(|s| !s.is_empty()))
+        .next_back()?;
+    if line.chars().count() <= MAX_SUMMARY_CHARS {
+        return Some(line.to_string());
+    }
+    let mut cut: String = line.chars().take(MAX_SUMMARY_CHARS - 1).collect();
+    cut.push('…');
+    Some(cut)
+}
+
+/// Drop ANSI escape sequences and control characters other than line breaks.
+fn strip_escapes(text: &str) -> String {
+    enum State {
+        Text,
+        Escape,
+        Csi,
+        Osc,
+        OscEscape,
+    }
+    let mut state = State::Text;
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        state = match state {
+            State::Text => match c {
+                '\x1b' => State::Escape,
+                '\n' | '\r' => {
+                    out.push(c);
+                    State::Text
+                }
+                '\t' => {
+                    out.push(' ');
+                    State::Text
+                }
+                c if c.is_control() => State::Text,
+                c => {
+                    out.push(c);
+                    State::Text
+                }
+            },
+            State::Escape => match c {
+                '[' => State::Csi,
+                ']' => State::Osc,
+                // Intermediate bytes, e.g. the `(` of a charset selection
+                ' '..='/' => State::Escape,
+                _ => State::Text,
+            },
+            State::Csi => match c {
+                '@'..='~' => State::Text,
+                _ => State::Csi,
+            },
+            State::Osc => match c {
+                '\x07' => State::Text,
+                '\x1b' => State::OscEscape,
+                _ => State::Osc,
+            },
+            State::OscEscape => match c {
+                '\\' => State::Text,
+                _ => State::Osc,
+            },
+        };
+    }
+    out
+}
+
+/// The daemon's latest warnings and errors, captured from its log through
+/// [`RecentErrors::layer`].
+#[derive(Debug, Default)]
+pub struct RecentErrors {
+    entries: Mutex<VecDeque<RecentError>>,
+}
+
+impl RecentErrors {
+    pub fn new() -> Arc<Self> {
+        Arc::default()
+    }
+
+    pub fn push(&self, level: &'static str, message: String) {
+        let mut entries = self.entries.lock().expect("recent errors lock");
+        if entries.len() == MAX_RECENT_ERRORS {
+            entries.pop_front();
+        }
+        entries.push_back(RecentError { at: chrono::Utc::now(), level, message });
+    }
+
+    pub fn list(&self) -> Vec<RecentError> {
+        self.entries.lock().expect("recent errors lock").iter().cloned().collect()
+    }
+
+    /// A tracing layer that records `WARN` and `ERROR` events here.
+    pub fn layer(self: &Arc<Self>) -> RecentErrorsLayer {
+        RecentErrorsLayer(self.clone())
+    }
+}
+
+pub struct RecentErrorsLayer(Arc<RecentErrors>);
+
+impl<S: Subscriber> Layer<S> for RecentErrorsLayer {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let level = match *event.metadata().level() {
+            Level::ERROR => "error",
+            Level::WARN => "warn",
+            _ => return,
+        };
+        let mut message = MessageVisitor(String::new());
+        event.record(&mut message);
+        self.0.push(level, message.0);
+    }
+}
+
+struct MessageVisitor(String);
+
+impl Visit for MessageVisitor {
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        if field.name() == "message" {
+            self.0 = format!("{value:?}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    #[test]
+    fn summary_is_last_visible_line() {
+        assert_eq!(summarize_output(b""), None);
+        assert_eq!(summarize_output(b"\r\n  \r\n"), None);
+        assert_eq!(
+            summarize_output(b"$ make\r\n\x1b[1;32mBuilding\x1b[0m target\r\n\r\n").as_deref(),
+            Some("Building target")
+        );
+        // Progress redraws and window-title updates
+        assert_eq!(
+            summarize_output(b"\x1b]0;vim notes\x07 10%\r 55%\r").as_deref(),
+            Some("55%")
+        );
+        assert_eq!(summarize_output(b"\x1b(Buser@host ~ % ").as_deref(), Some("user@host ~ %"));
+
+        let long = summarize_output("é".repeat(200).as_bytes()).unwrap();
+        assert_eq!(long.chars().count(), MAX_SUMMARY_CHARS);
+        assert!(long.ends_with('…'));
+    }
+
+    #[test]
+    fn keeps_latest_warnings_and_errors() {
+        let errors = RecentErrors::new();
+        let subscriber = tracing_subscriber::registry().with(errors.layer());
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info!("not recorded");
+            tracing::warn!("auth failed for {}", "1.2.3.4");
+            for i in 0..MAX_RECENT_ERRORS {
+                tracing::error!("failure {i}");
+            }
+        });
+
+        let list = errors.list();
+        assert_eq!(list.len(), MAX_RECENT_ERRORS);
+        assert_eq!(list[0].message, "failure 0");
+        assert_eq!(list[0].level, "error");
+        assert_eq!(list.last().unwrap().message, format!("failure {}", MAX_RECENT_ERRORS - 1));
+    }
+
+    #[test]
+    fn reports_pairing_in_progress() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let store = DeviceStore::new(dir.path()).unwrap();
+        let sm = SessionManager::new();
+        let errors = RecentErrors::new();
+
+        let state = UiState::collect(&sm, &store, &errors, "0.0.0.0:4433");
+        assert_eq!(state.schema, SCHEMA_VERSION);
+        assert!(!state.pairing.in_progress);
+        assert!(state.sessions.is_empty() && state.devices.is_empty());
+
+        let token = store.create_pairing_token(None);
+        let state = UiState::collect(&sm, &store, &errors, "0.0.0.0:4433");
+        assert!(state.pairing.in_progress);
+        assert!(state.pairing.expires_at.unwrap() > chrono::Utc::now());
+
+        assert!(store.redeem_pairing_token(&token).is_some());
+        let state = UiState::collect(&sm, &store, &errors, "0.0.0.0:4433");
+        assert!(!state.pairing.in_progress);
+    }
+}
@@ -0,0 +1,485 @@
+//! In-place daemon upgrade (`phantom upgrade`).
+//!
+//! The running daemon starts the new binary as `phantom daemon --takeover
+//! <socket>` and, once it connects, sends it over that Unix socket:
+//!
+//! - a length-prefixed JSON [`Handoff`] with each session's metadata and
+//!   scrollback, then
+//! - the QUIC UDP socket, every session's PTY master and, when systemd
+//!   bound it, the IPC socket, as `SCM_RIGHTS`.
+//!
+//! The new daemon adopts the sessions, starts serving on the same socket and
+//! answers `ready`; only then does the old daemon exit, leaving its shells
+//! running. If anything fails first the old daemon carries on as before.
+//! Shells keep running across the upgrade, but QUIC connections can't be
+//! moved between processes: clients reconnect and reattach.
+
+use anyhow::{bail, Context, Result};
+use phantom_frame::CloseCode;
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+use crate::archive::{base64_bytes, ArchivedSession};
+use crate::env::EnvVars;
+use crate::history::SessionHistory;
+use crate::session::SessionManager;
+use crate::systemd::Notifier;
+use crate::terminal::TerminalCaps;
+
+/// Version of the [`Handoff`] message; the new daemon refuses others.
+pub const VERSION: u32 = 1;
+/// How long each step of the handoff may take before the upgrade is abandoned.
+const HANDOFF_TIMEOUT: Duration = Duration::from_secs(10);
+/// Largest accepted handoff message.
+const MAX_HANDOFF_BYTES: usize = 256 * 1024 * 1024;
+/// File descriptors sent per `sendmsg` (Linux caps one message at 253).
+const FDS_PER_MESSAGE: usize = 64;
+/// What the new daemon answers once it is serving.
+const READY: &str = "ready";
+
+/// Everything but the file descriptors: those follow in the same order.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Handoff {
+    pub version: u32,
+    pub sessions: Vec<HandoffSession>,
+    /// The IPC socket follows the PTYs (last, so daemons predating it
+    /// ignore it)
+    #[serde(default)]
+    pub ipc: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HandoffSession {
+    #[serde(flatten)]
+    pub session: ArchivedSession,
+    /// The shell's process id
+    pub pid: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    #[serde(default)]
+    pub terminal: TerminalCaps,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_attached_at: Option<chrono::DateTime<chrono::Utc>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_attached_by: Option<String>,
+    #[serde(default)]
+    pub history: SessionHistory,
+    #[serde(default)]
+    pub agent_forwarding: bool,
+    #[serde(default)]
+    pub output_log: bool,
+    /// Watched variables the shell's prompt hook reported
+    #[serde(default, skip_serializing_if = "EnvVars::is_empty")]
+    pub env: EnvVars,
+    #[serde(with = "base64_bytes")]
+    pub scrollback: Vec<u8>,
+}
+
+/// The old daemon's side: hands its socket and sessions to a new binary.
+pub struct Upgrader {
+    socket_path: PathBuf,
+    socket: std::net::UdpSocket,
+    session_manager: Arc<SessionManager>,
+    ipc: Option<std::os::unix::net::UnixListener>,
+    notifier: Option<Arc<Notifier>>,
+    args: Vec<std::ffi::OsString>,
+    /// Cancelled once the new daemon has everything and this one should stop
+    handed_off: CancellationToken,
+}
+
+/// A completed handoff.
+#[derive(Debug)]
+pub struct HandedOff {
+    /// Process id of the new daemon
+    pub pid: u32,
+    pub sessions: usize,
+}
+
+impl Upgrader {
+    /// `socket` is (a clone of) the UDP socket the QUIC endpoint serves on.
+    pub fn new(phantom_dir: &Path, socket: std::net::UdpSocket, session_manager: Arc<SessionManager>) -> Self {
+        Self {
+            socket_path: phantom_dir.join("handoff.sock"),
+            socket,
+            session_manager,
+            ipc: None,
+            notifier: None,
+            args: Vec::new(),
+            handed_off: CancellationToken::new(),
+        }
+    }
+
+    /// Start the new daemon with `args` too, such as the data directory
+    /// this one runs with.
+    pub fn with_args(self, args: Vec<std::ffi::OsString>) -> Self {
+        Self { args, ..self }
+    }
+
+    /// Hand over `listener` (a clone of the IPC socket systemd bound), so
+    /// the new daemon keeps serving on it.
+    pub fn with_ipc_listener(self, listener: std::os::unix::net::UnixListener) -> Self {
+        Self { ipc: Some(listener), ..self }
+    }
+
+    /// Let the new daemon notify systemd in this one's place.
+    pub fn with_notifier(self, notifier: Arc<Notifier>) -> Self {
+        Self { notifier: Some(notifier), ..self }
+    }
+
+    /// Cancelled when [`finish`](Self::finish) is done: the daemon should
+    /// then stop serving and exit.
+    pub fn handed_off(&self) -> CancellationToken {
+        self.handed_off.clone()
+    }
+
+    /// Start `binary` as the new daemon and hand everything over to it.
+    /// Once this returns Ok the new daemon is serving; call [`finish`] to
+    /// stop. On error the new process has been stopped and nothing changed.
+    ///
+    /// [`finish`]: Self::finish
+    pub async fn hand_off(&self, binary: &Path) -> Result<HandedOff> {
+        let _ = std::fs::remove_file(&self.socket_path);
+        let listener = tokio::net::UnixListener::bind(&self.socket_path).context("bind handoff socket")?;
+        {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(&self.socket_path, std::fs::Permissions::from_mode(0o600))
+                .context("restrict handoff socket")?;
+        }
+
+        let mut child = tokio::process::Command::new(binary)
+            .args(&self.args)
+            .arg("daemon")
+            .arg("--takeover")
+            .arg(&self.socket_path)
+            .stdin(std::process::Stdio::null())
+            .envs(self.notifier.iter().flat_map(|notifier| notifier.env()))
+            .spawn()
+            .with_context(|| format!("start {}", binary.display()))?;
+        let pid = child.id().context("new daemon pid")?;
+        info!("upgrade: started {} (pid {pid})", binary.display());
+
+        let result = self.send_to(&listener, &mut child).await;
+        let _ = std::fs::remove_file(&self.socket_path);
+        match result {
+            Ok(sessions) => Ok(HandedOff { pid, sessions }),
+            Err(e) => {
+                let _ = child.kill().await;
+                Err(e)
+            }
+        }
+    }
+
+    async fn send_to(&self, listener: &tokio::net::UnixListener, child: &mut tokio::process::Child) -> Result<usize> {
+        let stream = tokio::select! {
+            accepted = tokio::time::timeout(HANDOFF_TIMEOUT, listener.accept()) => {
+                accepted.context("new daemon did not connect")?.context("accept handoff")?.0
+            }
+            status = child.wait() => bail!("new daemon exited during startup: {}", status?),
+        };
+        let mut stream = stream.into_std()?;
+        stream.set_nonblocking(false)?;
+        stream.set_read_timeout(Some(HANDOFF_TIMEOUT))?;
+
+        let (sessions, masters): (Vec<_>, Vec<_>) = self.session_manager.handoff_sessions().into_iter().unzip();
+        let count = sessions.len();
+        let handoff = Handoff { version: VERSION, sessions, ipc: self.ipc.is_some() };
+        let socket = self.socket.try_clone().context("dup UDP socket")?;
+        let ipc = self.ipc.as_ref().map(|ipc| ipc.try_clone()).transpose().context("dup IPC socket")?;
+
+        tokio::task::spawn_blocking(move || {
+            let mut fds = vec![OwnedFd::from(socket)];
+            fds.extend(masters);
+            fds.extend(ipc.map(OwnedFd::from));
+            send(&mut stream, &handoff, &fds)?;
+            wait_ready(&stream)
+        })
+        .await??;
+        info!("upgrade: handed {count} session(s) to the new daemon");
+        Ok(count)
+    }
+
+    /// Disconnect devices, then signal [`handed_off`](Self::handed_off),
+    /// leaving sessions to the new daemon. The short delay lets the close
+    /// frames and the IPC reply that triggered the upgrade go out first.
+    pub fn finish(&self) {
+        self.session_manager.close_connections(CloseCode::Upgrade);
+        let handed_off = self.handed_off.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            handed_off.cancel();
+        });
+    }
+}
+
+/// The new daemon's side of a handoff.
+pub struct Takeover {
+    pub socket: std::net::UdpSocket,
+    /// Sessions with their PTY masters
+    pub sessions: Vec<(HandoffSession, OwnedFd)>,
+    /// The IPC socket, if the old daemon had it from systemd
+    pub ipc: Option<std::os::unix::net::UnixListener>,
+    pub ack: Ack,
+}
+
+/// Tells the old daemon the new one is serving, so it can exit.
+pub struct Ack(UnixStream);
+
+impl Takeover {
+    pub fn receive(socket_path: &Path) -> Result<Self> {
+        let mut stream = UnixStream::connect(socket_path).context("connect to the running daemon")?;
+        stream.set_read_timeout(Some(HANDOFF_TIMEOUT))?;
+        let (handoff, fds) = receive(&mut stream)?;
+        let mut fds = fds.into_iter();
+        let socket = std::net::UdpSocket::from(fds.next().context("handoff without a UDP socket")?);
+        let sessions = handoff.sessions.into_iter().zip(fds.by_ref()).collect();
+        let ipc = if handoff.ipc { fds.next().map(std::os::unix::net::UnixListener::from) } else { None };
+        Ok(Self { socket, sessions, ipc, ack: Ack(stream) })
+    }
+}
+
+impl Ack {
+    pub fn ready(mut self) -> Result<()> {
+        writeln!(self.0, "{READY}").context("acknowledge handoff")
+    }
+}
+
+fn wait_ready(stream: &UnixStream) -> Result<()> {
+    let mut line = String::new();
+    BufReader::new(stream.take(64))
+        .read_line(&mut line)
+        .context("new daemon did not confirm")?;
+    if line.trim_end() != READY {
+        bail!("new daemon failed to take over");
+    }
+    Ok(())
+}
+
+/// Write `handoff` and pass `fds` (which must match its sessions, after the
+/// UDP socket and before any IPC socket).
+fn send(stream: &mut UnixStream, handoff: &Handoff, fds: &[OwnedFd]) -> Result<()> {
+    if fds.len() != fd_count(handoff) {
+        bail!("handoff needs one PTY per session");
+    }
+    let json = serde_json::to_vec(handoff).context("serialize handoff")?;
+    stream.write_all(&(json.len() as u32).to_be_bytes())?;
+    stream.write_all(&json)?;
+    let raw: Vec<RawFd> = fds.iter().map(|fd| fd.as_raw_fd()).collect();
+    for chunk in raw.chunks(FDS_PER_MESSAGE) {
+        send_fds(stream, chunk).context("pass file descriptors")?;
+    }
+    Ok(())
+}
+
+fn receive(stream: &mut UnixStream) -> Result<(Handoff, Vec<OwnedFd>)> {
+    let mut len = [0u8; 4];
+    stream.read_exact(&mut len).context("read handoff")?;
+    let len = u32::from_be_bytes(len) as usize;
+    if len > MAX_HANDOFF_BYTES {
+        bail!("handoff too large");
+    }
+    let mut json = vec![0u8; len];
+    stream.read_exact(&mut json).context("read handoff")?;
+    let handoff: Handoff = serde_json::from_slice(&json).context("parse handoff")?;
+    if handoff.version != VERSION {
+        bail!("handoff version {} not supported (expected {VERSION})", handoff.version);
+    }
+
+    let expected = fd_count(&handoff);
+    let mut fds = Vec::with_capacity(expected);
+    while fds.len() < expected {
+        let received = recv_fds(stream).context("receive file descriptors")?;
+        if received.is_empty() {
+            bail!("handoff ended after {} of {expected} file descriptors", fds.len());
+        }
+        fds.extend(received);
+    }
+    Ok((handoff, fds))
+}
+
+/// Descriptors passed with `handoff`.
+fn fd_count(handoff: &Handoff) -> usize {
+    1 + handoff.sessions.len() + usize::from(handoff.ipc)
+}
+
+fn cmsg_space(fds: usize) -> usize {
+    unsafe { libc::CMSG_SPACE((fds * std::mem::size_of::<RawFd>()) as u32) as usize }
+}
+
+/// Send `fds` as `SCM_RIGHTS` on a one-byte message.
+fn send_fds(stream: &UnixStream, fds: &[RawFd]) -> std::io::Result<()> {
+    let mut byte = [0u8; 1];
+    let mut iov = libc::iovec { iov_base: byte.as_mut_ptr().cast(), iov_len: 1 };
+    let mut control = vec![0u8; cmsg_space(fds.len())];
+    let data_len = std::mem::size_of_val(fds);
+
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr().cast();
+    msg.msg_controllen = control.len() as _;
+    unsafe {
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        (*cmsg).cmsg_level = libc::SOL_SOCKET;
+        (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(data_len as u32) as _;
+        std::ptr::copy_nonoverlapping(fds.as_ptr().cast::<u8>(), libc::CMSG_DATA(cmsg), data_len);
+        if libc::sendmsg(stream.as_raw_fd(), &msg, 0) < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Receive one message's worth of `SCM_RIGHTS` descriptors (empty at EOF).
+fn recv_fds(stream: &UnixStream) -> std::io::Result<Vec<OwnedFd>> {
+    let mut byte = [0u8; 1];
+    let mut iov = libc::iovec { iov_base: byte.as_mut_ptr().cast(), iov_len: 1 };
+    let mut control = vec![0u8; cmsg_space(FDS_PER_MESSAGE)];
+
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr().cast();
+    msg.msg_controllen = control.len() as _;
+
+    let mut fds = Vec::new();
+    unsafe {
+        if libc::recvmsg(stream.as_raw_fd(), &mut msg, 0) < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_RIGHTS {
+                let data_len = (*cmsg).cmsg_len as usize - libc::CMSG_LEN(0) as usize;
+                let data = libc::CMSG_DATA(cmsg).cast::<RawFd>();
+                for i in 0..data_len / std::mem::size_of::<RawFd>() {
+                    let fd = std::ptr::read_unaligned(data.add(i));
+                    libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC);
+                    fds.push(OwnedFd::from_raw_fd(fd));
+                }
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+    }
+    if msg.msg_flags & libc::MSG_CTRUNC != 0 {
+        warn!("handoff file descriptors were truncated");
+    }
+    Ok(fds)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn session(id: &str, pid: u32) -> HandoffSession {
+        let now = chrono::Utc::now();
+        HandoffSession {
+            session: ArchivedSession {
+                id: id.to_string(),
+                shell: "/bin/zsh".to_string(),
+                created_at: now,
+                created_by_device_id: Some("dev".to_string()),
+                tmux_session: None,
+                last_activity_at: now,
+            },
+            pid,
+            name: None,
+            user: Some("alice".to_string()),
+            terminal: TerminalCaps::default(),
+            last_attached_at: None,
+            last_attached_by: None,
+            history: SessionHistory::default(),
+            agent_forwarding: false,
+            output_log: false,
+            env: EnvVars::new(),
+            scrollback: b"$ ls\r\n".to_vec(),
+        }
+    }
+
+    #[test]
+    fn passes_sessions_and_descriptors() {
+        let (mut old, mut new) = UnixStream::pair().unwrap();
+        let udp = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = udp.local_addr().unwrap();
+
+        // More sessions than fit in one message
+        let count = FDS_PER_MESSAGE + 3;
+        let mut pipes = Vec::new();
+        let mut fds = vec![OwnedFd::from(udp)];
+        for _ in 0..count {
+            let (reader, writer) = std::io::pipe().unwrap();
+            pipes.push(reader);
+            fds.push(OwnedFd::from(writer));
+        }
+        let handoff = Handoff {
+            version: VERSION,
+            sessions: (0..count).map(|i| session(&format!("s{i}"), 100 + i as u32)).collect(),
+            ipc: false,
+        };
+
+        let sent = handoff.clone();
+        let sender = std::thread::spawn(move || send(&mut old, &sent, &fds).unwrap());
+        let (received, fds) = receive(&mut new).unwrap();
+        sender.join().unwrap();
+
+        assert_eq!(received, handoff);
+        assert_eq!(fds.len(), count + 1);
+        let udp = std::net::UdpSocket::from(fds.into_iter().next().unwrap());
+        assert_eq!(udp.local_addr().unwrap(), addr);
+    }
+
+    #[test]
+    fn received_descriptors_stay_usable() {
+        let (mut old, mut new) = UnixStream::pair().unwrap();
+        let udp = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let (mut reader, writer) = std::io::pipe().unwrap();
+        let handoff = Handoff { version: VERSION, sessions: vec![session("abc", 1)], ipc: false };
+
+        send(&mut old, &handoff, &[OwnedFd::from(udp), OwnedFd::from(writer)]).unwrap();
+        let (_, mut fds) = receive(&mut new).unwrap();
+
+        let mut writer = std::fs::File::from(fds.pop().unwrap());
+        writer.write_all(b"still here").unwrap();
+        drop(writer);
+        drop(fds);
+        let mut out = String::new();
+        reader.read_to_string(&mut out).unwrap();
+        assert_eq!(out, "still here");
+    }
+
+    #[test]
+    fn passes_the_ipc_socket_last() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let (mut old, mut new) = UnixStream::pair().unwrap();
+        let udp = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let (_reader, writer) = std::io::pipe().unwrap();
+        let path = dir.path().join("daemon.sock");
+        let ipc = std::os::unix::net::UnixListener::bind(&path).unwrap();
+        let handoff = Handoff { version: VERSION, sessions: vec![session("abc", 1)], ipc: true };
+
+        send(&mut old, &handoff, &[OwnedFd::from(udp), OwnedFd::from(writer), OwnedFd::from(ipc)]).unwrap();
+        let (_, mut fds) = receive(&mut new).unwrap();
+
+        assert_eq!(fds.len(), 3);
+        let ipc = std::os::unix::net::UnixListener::from(fds.pop().unwrap());
+        assert_eq!(ipc.local_addr().unwrap().as_pathname(), Some(path.as_path()));
+    }
+
+    #[test]
+    fn rejects_other_versions() {
+        let (mut old, mut new) = UnixStream::pair().unwrap();
+        let udp = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let handoff = Handoff { version: VERSION + 1, sessions: Vec::new(), ipc: false };
+        send(&mut old, &handoff, &[OwnedFd::from(udp)]).unwrap();
+        assert!(receive(&mut new).is_err());
+    }
+}
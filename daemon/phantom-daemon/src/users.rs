@@ -0,0 +1,96 @@
+//! Multi-user system service mode (`[system] multi_user` in config.toml).
+//!
+//! Run as root — a systemd unit or launchd daemon on a shared build server —
+//! one daemon serves several users. Each device is paired for a user
+//! (`phantom pair --user`), the sessions it creates run as that user, and it
+//! only sees and attaches to that user's sessions. Unix only: on Windows
+//! no user is found, so devices can't be paired for one.
+
+use anyhow::{bail, Result};
+use portable_pty::CommandBuilder;
+#[cfg(unix)]
+use std::ffi::{CStr, CString};
+
+/// Longest accepted user name.
+const MAX_NAME_LEN: usize = 64;
+
+/// Whether the daemon runs as root, which spawning as other users needs.
+#[cfg(unix)]
+pub fn is_root() -> bool {
+    unsafe { libc::geteuid() == 0 }
+}
+
+#[cfg(not(unix))]
+pub fn is_root() -> bool {
+    false
+}
+
+/// Reject names that aren't accounts on this host.
+pub fn validate_user(name: &str) -> Result<()> {
+    if name.is_empty() || name.len() > MAX_NAME_LEN || name.starts_with('-') {
+        bail!("invalid user name");
+    }
+    if !name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.')) {
+        bail!("invalid user name: {name}");
+    }
+    if home_dir(name).is_none() {
+        bail!("no such user: {name}");
+    }
+    Ok(())
+}
+
+/// `name`'s home directory from the password database.
+#[cfg(unix)]
+fn home_dir(name: &str) -> Option<String> {
+    let c_name = CString::new(name).ok()?;
+    let mut passwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut buf = vec![0 as libc::c_char; 16 * 1024];
+    let mut result: *mut libc::passwd = std::ptr::null_mut();
+    let rc = unsafe {
+        libc::getpwnam_r(c_name.as_ptr(), &mut passwd, buf.as_mut_ptr(), buf.len(), &mut result)
+    };
+    if rc != 0 || result.is_null() || passwd.pw_dir.is_null() {
+        return None;
+    }
+    Some(unsafe { CStr::from_ptr(passwd.pw_dir) }.to_string_lossy().into_owned())
+}
+
+#[cfg(not(unix))]
+fn home_dir(_name: &str) -> Option<String> {
+    None
+}
+
+/// `user`'s login shell, or `command` run by it, through `su -`: a full
+/// login environment (home, shell, groups) on both Linux and macOS. Needs root.
+pub fn login_command(user: &str, command: Option<&str>) -> CommandBuilder {
+    let mut cmd = CommandBuilder::new("su");
+    cmd.args(["-", user]);
+    if let Some(command) = command {
+        cmd.args(["-c", command]);
+    }
+    if let Some(home) = home_dir(user) {
+        cmd.cwd(home);
+    }
+    cmd
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validates_user_names() {
+        validate_user("root").unwrap();
+        assert!(validate_user("no-such-user-phantom").unwrap_err().to_string().contains("no such user"));
+        for bad in ["", "-f", "root;id", "a b", &"x".repeat(65)] {
+            assert!(validate_user(bad).is_err(), "{bad:?}");
+        }
+    }
+
+    #[test]
+    fn login_command_runs_through_su() {
+        let cmd = login_command("root", Some("make test"));
+        let argv: Vec<_> = cmd.get_argv().iter().map(|a| a.to_string_lossy().into_owned()).collect();
+        assert_eq!(argv, ["su", "-", "root", "-c", "make test"]);
+    }
+}
@@ -0,0 +1,208 @@
+//! Encryption at rest for files holding scrollback.
+//!
+//! Scrollback can hold anything that was on screen, passwords and tokens
+//! included, so session archives are sealed with ChaCha20-Poly1305 under a
+//! per-host key before they touch the disk. The key lives in the login
+//! Keychain on macOS and in `~/.phantom/scrollback.key` (mode 0600)
+//! elsewhere; it is generated on first use.
+//!
+//! Sealed files start with [`MAGIC`], so readers can tell them from plaintext
+//! ones (`phantom session export --plaintext`, for moving to another host).
+
+use anyhow::{bail, Context, Result};
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305, NONCE_LEN};
+use ring::rand::{SecureRandom, SystemRandom};
+use std::path::Path;
+use tracing::info;
+
+use crate::device_store::private_file;
+use crate::errors::ErrorCode;
+
+/// Prefix of every sealed file.
+pub const MAGIC: &[u8] = b"PHVAULT1";
+
+const KEY_LEN: usize = 32;
+const KEY_FILE: &str = "scrollback.key";
+#[cfg(target_os = "macos")]
+const KEYCHAIN_SERVICE: &str = "phantom";
+#[cfg(target_os = "macos")]
+const KEYCHAIN_ACCOUNT: &str = "scrollback-key";
+
+pub struct Vault {
+    key: LessSafeKey,
+}
+
+impl Vault {
+    pub fn new(key: &[u8; KEY_LEN]) -> Self {
+        let key = UnboundKey::new(&CHACHA20_POLY1305, key).expect("32-byte key");
+        Self { key: LessSafeKey::new(key) }
+    }
+
+    /// This host's vault, creating its key if there isn't one yet.
+    pub fn load_or_create(phantom_dir: &Path) -> Result<Self> {
+        #[cfg(target_os = "macos")]
+        if let Some(key) = keychain::load_or_create()? {
+            return Ok(Self::new(&key));
+        }
+        let key = load_or_create_key_file(&phantom_dir.join(KEY_FILE))?;
+        Ok(Self::new(&key))
+    }
+
+    /// Whether `bytes` were written by [`Vault::seal`].
+    pub fn is_sealed(bytes: &[u8]) -> bool {
+        bytes.starts_with(MAGIC)
+    }
+
+    /// `MAGIC`, a random nonce, then the ciphertext and tag.
+    pub fn seal(&self, plaintext: &[u8]) -> Result<Vec<u8>> {
+        let mut nonce = [0u8; NONCE_LEN];
+        SystemRandom::new().fill(&mut nonce).map_err(|_| anyhow::anyhow!("generate nonce"))?;
+        let mut sealed = plaintext.to_vec();
+        self.key
+            .seal_in_place_append_tag(Nonce::assume_unique_for_key(nonce), Aad::from(MAGIC), &mut sealed)
+            .map_err(|_| anyhow::anyhow!("encrypt"))?;
+        let mut out = Vec::with_capacity(MAGIC.len() + NONCE_LEN + sealed.len());
+        out.extend_from_slice(MAGIC);
+        out.extend_from_slice(&nonce);
+        out.extend_from_slice(&sealed);
+        Ok(out)
+    }
+
+    pub fn open(&self, sealed: &[u8]) -> Result<Vec<u8>> {
+        let body = sealed
+            .strip_prefix(MAGIC)
+            .filter(|body| body.len() >= NONCE_LEN)
+            .ok_or_else(|| ErrorCode::BadRequest.err("not an encrypted file"))?;
+        let (nonce, ciphertext) = body.split_at(NONCE_LEN);
+        let nonce = Nonce::try_assume_unique_for_key(nonce).expect("nonce length");
+        let mut plaintext = ciphertext.to_vec();
+        let len = self
+            .key
+            .open_in_place(nonce, Aad::from(MAGIC), &mut plaintext)
+            .map_err(|_| {
+                ErrorCode::BadRequest.err("can't decrypt: encrypted on another host, or corrupt")
+            })?
+            .len();
+        plaintext.truncate(len);
+        Ok(plaintext)
+    }
+}
+
+/// Delete the scrollback key from the login Keychain (macOS), for a reset;
+/// the key file elsewhere goes with the rest of the data dir.
+pub fn forget_keychain_key() -> Result<()> {
+    #[cfg(target_os = "macos")]
+    keychain::delete()?;
+    Ok(())
+}
+
+fn generate_key() -> Result<[u8; KEY_LEN]> {
+    let mut key = [0u8; KEY_LEN];
+    SystemRandom::new().fill(&mut key).map_err(|_| anyhow::anyhow!("generate scrollback key"))?;
+    Ok(key)
+}
+
+fn decode_key(hex_key: &str) -> Result<[u8; KEY_LEN]> {
+    let bytes = hex::decode(hex_key.trim()).context("decode scrollback key")?;
+    match <[u8; KEY_LEN]>::try_from(bytes) {
+        Ok(key) => Ok(key),
+        Err(_) => bail!("scrollback key must be {KEY_LEN} bytes"),
+    }
+}
+
+fn load_or_create_key_file(path: &Path) -> Result<[u8; KEY_LEN]> {
+    use std::io::Write;
+
+    match std::fs::read_to_string(path) {
+        Ok(contents) => return decode_key(&contents).with_context(|| format!("read {}", path.display())),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e).with_context(|| format!("read {}", path.display())),
+    }
+    let key = generate_key()?;
+    let mut file = private_file()
+        .write(true)
+        .create_new(true)
+        .open(path)
+        .with_context(|| format!("create {}", path.display()))?;
+    file.write_all(hex::encode(key).as_bytes())
+        .with_context(|| format!("write {}", path.display()))?;
+    info!("generated scrollback encryption key at {}", path.display());
+    Ok(key)
+}
+
+#[cfg(target_os = "macos")]
+mod keychain {
+    use super::*;
+    use std::process::Command;
+
+    /// The key from the login Keychain, stored there first if missing. None
+    /// if the Keychain can't be used (e.g. a headless daemon).
+    pub fn load_or_create() -> Result<Option<[u8; KEY_LEN]>> {
+        let found = Command::new("security")
+            .args(["find-generic-password", "-s", KEYCHAIN_SERVICE, "-a", KEYCHAIN_ACCOUNT, "-w"])
+            .output()
+            .context("run security")?;
+        if found.status.success() {
+            return decode_key(&String::from_utf8_lossy(&found.stdout)).map(Some);
+        }
+
+        let key = generate_key()?;
+        let added = Command::new("security")
+            .args(["add-generic-password", "-s", KEYCHAIN_SERVICE, "-a", KEYCHAIN_ACCOUNT, "-w"])
+            .arg(hex::encode(key))
+            .output()
+            .context("run security")?;
+        if !added.status.success() {
+            tracing::warn!(
+                "can't store scrollback key in the Keychain ({}); using a key file",
+                String::from_utf8_lossy(&added.stderr).trim()
+            );
+            return Ok(None);
+        }
+        info!("generated scrollback encryption key in the login Keychain");
+        Ok(Some(key))
+    }
+
+    /// Remove the key, if it's there.
+    pub fn delete() -> Result<()> {
+        let deleted = Command::new("security")
+            .args(["delete-generic-password", "-s", KEYCHAIN_SERVICE, "-a", KEYCHAIN_ACCOUNT])
+            .output()
+            .context("run security")?;
+        // 44: no such item
+        if !deleted.status.success() && deleted.status.code() != Some(44) {
+            bail!("security: {}", String::from_utf8_lossy(&deleted.stderr).trim());
+        }
+        info!("removed scrollback encryption key from the login Keychain");
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn seals_with_a_persistent_key() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join(KEY_FILE);
+        let key = load_or_create_key_file(&path).unwrap();
+        assert_eq!(load_or_create_key_file(&path).unwrap(), key);
+        use std::os::unix::fs::PermissionsExt;
+        assert_eq!(std::fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+
+        let vault = Vault::new(&key);
+        let secret = b"export AWS_SECRET_ACCESS_KEY=hunter2";
+        let sealed = vault.seal(secret).unwrap();
+        assert!(Vault::is_sealed(&sealed));
+        assert!(!sealed.windows(7).any(|w| w == b"hunter2"));
+        assert_eq!(vault.open(&sealed).unwrap(), secret);
+
+        // Another host's key, or a flipped bit, fails authentication
+        let other = Vault::new(&[7; KEY_LEN]);
+        assert_eq!(ErrorCode::of(&other.open(&sealed).unwrap_err()), ErrorCode::BadRequest);
+        let mut corrupt = sealed.clone();
+        *corrupt.last_mut().unwrap() ^= 1;
+        assert!(vault.open(&corrupt).is_err());
+    }
+}
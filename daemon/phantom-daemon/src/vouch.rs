@@ -0,0 +1,163 @@
+//! Vouched pairing: an already-paired device approves a new one, so pairing
+//! doesn't need `phantom pair` run at the Mac.
+//!
+//! The new device sends an `auth_request` with `vouch: true`, its public key
+//! and name, no pairing token, and in multi-user mode the `user` it's for.
+//! The daemon pushes a `pairing_request` event to that user's connected,
+//! unrestricted devices and holds the auth exchange open for up to
+//! [`TIMEOUT`]; the first `approve_pairing` or `deny_pairing` from one of
+//! them settles it. Both devices show the request's [`verification_code`]
+//! so the user can tell it's the phone in their hand being approved. One
+//! request is pending at a time, and each IP may open only a few an hour,
+//! which keeps a stranger from flooding devices with prompts or holding the
+//! slot.
+
+use sha2::{Digest, Sha256};
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::oneshot;
+
+use crate::errors::ErrorCode;
+
+/// How long a vouched pairing waits for an approval.
+pub const TIMEOUT: Duration = Duration::from_secs(120);
+
+/// How a pairing request was settled.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Verdict {
+    /// Approved by device `by`
+    Approved { by: String },
+    Denied { by: String },
+}
+
+struct Pending {
+    id: String,
+    device_id: String,
+    user: Option<String>,
+    reply: oneshot::Sender<Verdict>,
+}
+
+/// The pairing request waiting for a vouch, if any.
+#[derive(Default)]
+pub struct PairingRequests {
+    pending: Mutex<Option<Pending>>,
+}
+
+/// An open pairing request. Dropping it withdraws the request.
+pub struct Ticket<'a> {
+    pub id: String,
+    requests: &'a PairingRequests,
+    verdict: oneshot::Receiver<Verdict>,
+}
+
+impl PairingRequests {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Open a request to pair `device_id` for `user`, or None while another
+    /// device's is pending. A request whose device has gone away doesn't
+    /// count.
+    pub fn open(&self, device_id: &str, user: Option<&str>) -> Option<Ticket<'_>> {
+        let mut pending = self.pending.lock().expect("pairing requests lock");
+        if pending.as_ref().is_some_and(|p| !p.reply.is_closed() && p.device_id != device_id) {
+            return None;
+        }
+        let id_bytes: [u8; 8] = rand::Rng::gen(&mut rand::thread_rng());
+        let id = id_bytes.iter().map(|b| format!("{b:02x}")).collect::<String>();
+        let (reply, verdict) = oneshot::channel();
+        *pending = Some(Pending {
+            id: id.clone(),
+            device_id: device_id.to_string(),
+            user: user.map(str::to_string),
+            reply,
+        });
+        Some(Ticket { id, requests: self, verdict })
+    }
+
+    /// Settle request `id` with `verdict` from a device of `user`. Another
+    /// user's requests aren't theirs to settle, or to know of.
+    pub fn settle(&self, id: &str, user: Option<&str>, verdict: Verdict) -> anyhow::Result<()> {
+        let mut pending = self.pending.lock().expect("pairing requests lock");
+        if pending.as_ref().is_none_or(|p| p.id != id || p.user.as_deref() != user) {
+            return Err(ErrorCode::NotFound.err(format!("no pending pairing request {id}")));
+        }
+        let p = pending.take().expect("checked above");
+        p.reply
+            .send(verdict)
+            .map_err(|_| ErrorCode::NotFound.err(format!("pairing request {id} was withdrawn")))
+    }
+}
+
+impl Ticket<'_> {
+    /// The verdict, or None if nobody settled the request in time.
+    pub async fn verdict(&mut self, timeout: Duration) -> Option<Verdict> {
+        tokio::time::timeout(timeout, &mut self.verdict).await.ok()?.ok()
+    }
+}
+
+impl Drop for Ticket<'_> {
+    fn drop(&mut self) {
+        let mut pending = self.requests.pending.lock().expect("pairing requests lock");
+        if pending.as_ref().is_some_and(|p| p.id == self.id) {
+            *pending = None;
+        }
+    }
+}
+
+/// Six digits both devices show for a request: from the SHA-256 of the new
+/// device's base64 public key, as sent.
+pub fn verification_code(public_key: &str) -> String {
+    let digest = Sha256::digest(public_key.as_bytes());
+    let n = u32::from_be_bytes([digest[0], digest[1], digest[2], digest[3]]);
+    format!("{:06}", n % 1_000_000)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn one_request_at_a_time_until_settled_or_withdrawn() {
+        let requests = PairingRequests::new();
+        let mut ticket = requests.open("phone", None).unwrap();
+        assert!(requests.open("tablet", None).is_none());
+
+        let approved = Verdict::Approved { by: "laptop".to_string() };
+        assert!(requests.settle("other", None, approved.clone()).is_err());
+        requests.settle(&ticket.id, None, approved.clone()).unwrap();
+        assert_eq!(ticket.verdict(Duration::from_secs(1)).await, Some(approved));
+        drop(ticket);
+
+        let ticket = requests.open("tablet", None).unwrap();
+        let id = ticket.id.clone();
+        drop(ticket);
+        let denied = Verdict::Denied { by: "laptop".to_string() };
+        assert_eq!(ErrorCode::of(&requests.settle(&id, None, denied).unwrap_err()), ErrorCode::NotFound);
+        assert!(requests.open("phone", None).is_some());
+    }
+
+    #[test]
+    fn only_the_users_devices_settle_a_request() {
+        let requests = PairingRequests::new();
+        let ticket = requests.open("phone", Some("alice")).unwrap();
+        let denied = Verdict::Denied { by: "bobs-laptop".to_string() };
+        assert!(requests.settle(&ticket.id, Some("bob"), denied.clone()).is_err());
+        assert!(requests.settle(&ticket.id, None, denied.clone()).is_err());
+        requests.settle(&ticket.id, Some("alice"), denied).unwrap();
+    }
+
+    #[tokio::test]
+    async fn unsettled_requests_time_out() {
+        let requests = PairingRequests::new();
+        let mut ticket = requests.open("phone", None).unwrap();
+        assert_eq!(ticket.verdict(Duration::from_millis(10)).await, None);
+    }
+
+    #[test]
+    fn verification_codes_are_six_digits() {
+        let code = verification_code("BPk1...");
+        assert_eq!(code.len(), 6);
+        assert_eq!(code, verification_code("BPk1..."));
+    }
+}
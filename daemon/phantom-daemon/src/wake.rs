@@ -0,0 +1,243 @@
+//! Waking a sleeping host on demand, so it needn't be kept awake (see
+//! [`crate::power`]) just to stay reachable.
+//!
+//! Both routes end in a Wake-on-LAN magic packet for the host's primary
+//! interface:
+//! - With "Wake for network access" on macOS, the daemon's Bonjour
+//!   registration is handed to the network's sleep proxy, which answers for
+//!   the sleeping Mac and wakes it when a client connects.
+//! - Clients learn the host's MAC address (`wake` in the pairing payload and
+//!   `server_info`) and send the packet themselves on the LAN, or from
+//!   elsewhere to a wake relay: `phantom wake-relay` on an always-on machine
+//!   on the host's LAN, which broadcasts it there.
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::process::Stdio;
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info, warn};
+
+use crate::config::WakeConfig;
+use crate::errors::ErrorCode;
+
+/// Bonjour service type the daemon registers as.
+pub const SERVICE_TYPE: &str = "_phantom._udp";
+
+/// Port magic packets are broadcast to (discard).
+pub const WOL_PORT: u16 = 9;
+
+/// A relay forwards at most one packet per MAC address this often.
+const RELAY_THROTTLE: Duration = Duration::from_secs(1);
+
+const MAGIC_PACKET_LEN: usize = 6 + 16 * 6;
+
+/// What a client needs to wake this host.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct WakeInfo {
+    /// MAC address of the primary interface, `aa:bb:cc:dd:ee:ff`
+    pub mac: String,
+    /// Wake relay to send the magic packet to when off the LAN
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub relay: Option<String>,
+}
+
+/// Wake info for this host, if its primary interface has a MAC address.
+pub fn info(config: &WakeConfig) -> Option<WakeInfo> {
+    let mac = crate::addresses::primary_mac()?;
+    Some(WakeInfo { mac: format_mac(&mac), relay: config.relay.clone() })
+}
+
+pub fn format_mac(mac: &[u8; 6]) -> String {
+    mac.iter().map(|b| format!("{b:02x}")).collect::<Vec<_>>().join(":")
+}
+
+/// Parse `aa:bb:cc:dd:ee:ff` (or with `-` separators).
+pub fn parse_mac(s: &str) -> Result<[u8; 6]> {
+    let invalid = || ErrorCode::BadRequest.err(format!("invalid MAC address: {s}"));
+    let bytes = s
+        .split([':', '-'])
+        .map(|b| if b.len() == 2 { u8::from_str_radix(b, 16).ok() } else { None })
+        .collect::<Option<Vec<u8>>>()
+        .ok_or_else(invalid)?;
+    bytes.try_into().map_err(|_| invalid())
+}
+
+/// Six 0xff bytes, then `mac` sixteen times.
+pub fn magic_packet(mac: &[u8; 6]) -> Vec<u8> {
+    let mut packet = vec![0xff; 6];
+    for _ in 0..16 {
+        packet.extend_from_slice(mac);
+    }
+    packet
+}
+
+/// The MAC address a magic packet is for.
+fn parse_magic_packet(packet: &[u8]) -> Option<[u8; 6]> {
+    if packet.len() != MAGIC_PACKET_LEN || packet[..6] != [0xff; 6] {
+        return None;
+    }
+    let mac: [u8; 6] = packet[6..12].try_into().ok()?;
+    (packet == magic_packet(&mac)).then_some(mac)
+}
+
+/// Register the daemon's `port` over Bonjour until `cancel`: with `dns-sd`
+/// on macOS, `avahi-publish` elsewhere. The TXT record lists the host's
+/// addresses (see [`txt_record`]).
+pub async fn advertise(port: u16, cancel: CancellationToken) {
+    let name = crate::device_store::hostname();
+    let port = port.to_string();
+    let txt = txt_record(&crate::addresses::candidates());
+    let mut cmd = if cfg!(target_os = "macos") {
+        let mut cmd = tokio::process::Command::new("dns-sd");
+        cmd.args(["-R", &name, SERVICE_TYPE, "local", &port]).args(&txt);
+        cmd
+    } else if crate::clipboard::on_path("avahi-publish") {
+        let mut cmd = tokio::process::Command::new("avahi-publish");
+        cmd.args(["-s", &name, SERVICE_TYPE, &port]).args(&txt);
+        cmd
+    } else {
+        debug!("no Bonjour publisher installed; not advertising");
+        return;
+    };
+    cmd.stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .kill_on_drop(true);
+
+    let mut child = match cmd.spawn() {
+        Ok(child) => child,
+        Err(e) => {
+            warn!("failed to advertise over Bonjour: {e}");
+            return;
+        }
+    };
+    info!("advertising {SERVICE_TYPE} on port {port} over Bonjour");
+    tokio::select! {
+        _ = cancel.cancelled() => {}
+        status = child.wait() => warn!("Bonjour publisher exited: {status:?}"),
+    }
+}
+
+/// The service's TXT record: `addrs=` the IP candidates, best first and
+/// comma-separated, so clients that browse get IPv6 addresses too without
+/// resolving the host's AAAA records. One key, as DNS-SD clients only read
+/// a key's first occurrence, cut to the 255 bytes a TXT string holds.
+fn txt_record(hosts: &[crate::addresses::Candidate]) -> Option<String> {
+    const TXT_STRING_MAX: usize = 255;
+    let mut txt = String::from("addrs=");
+    for host in hosts.iter().filter(|c| c.host.parse::<std::net::IpAddr>().is_ok()) {
+        let sep = if txt.len() > "addrs=".len() { "," } else { "" };
+        if txt.len() + sep.len() + host.host.len() > TXT_STRING_MAX {
+            break;
+        }
+        txt.push_str(sep);
+        txt.push_str(&host.host);
+    }
+    (txt.len() > "addrs=".len()).then_some(txt)
+}
+
+/// Run a wake relay on `bind`: broadcast the magic packets clients send it
+/// on this LAN, for any of `allowed` (any MAC if empty).
+pub async fn run_relay(bind: SocketAddr, allowed: &[[u8; 6]]) -> Result<()> {
+    let socket = UdpSocket::bind(bind).await.with_context(|| format!("bind {bind}"))?;
+    info!("wake relay listening on {}", socket.local_addr()?);
+    relay(socket, SocketAddr::from((Ipv4Addr::BROADCAST, WOL_PORT)), allowed).await
+}
+
+async fn relay(socket: UdpSocket, target: SocketAddr, allowed: &[[u8; 6]]) -> Result<()> {
+    let out = UdpSocket::bind("0.0.0.0:0").await.context("bind broadcast socket")?;
+    out.set_broadcast(true)?;
+    let mut last_sent: HashMap<[u8; 6], Instant> = HashMap::new();
+    let mut buf = [0u8; 512];
+    loop {
+        let (len, from) = socket.recv_from(&mut buf).await?;
+        let Some(mac) = parse_magic_packet(&buf[..len]) else {
+            debug!("ignoring {len} byte datagram from {from}: not a magic packet");
+            continue;
+        };
+        if !allowed.is_empty() && !allowed.contains(&mac) {
+            warn!("refusing to wake {} for {from}: not allowed", format_mac(&mac));
+            continue;
+        }
+        // Also keeps the relay from echoing its own broadcasts
+        let now = Instant::now();
+        if last_sent.get(&mac).is_some_and(|at| now.duration_since(*at) < RELAY_THROTTLE) {
+            continue;
+        }
+        last_sent.insert(mac, now);
+        last_sent.retain(|_, at| now.duration_since(*at) < RELAY_THROTTLE);
+
+        info!("waking {} for {from}", format_mac(&mac));
+        if let Err(e) = out.send_to(&buf[..len], target).await {
+            warn!("failed to broadcast magic packet: {e}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn magic_packets_round_trip() {
+        let mac = parse_mac("A4:83:e7:01:02:03").unwrap();
+        assert_eq!(format_mac(&mac), "a4:83:e7:01:02:03");
+        assert_eq!(parse_mac("a4-83-e7-01-02-03").unwrap(), mac);
+        for bad in ["a4:83:e7:01:02", "a4:83:e7:01:02:03:04", "a4:83:e7:01:02:zz", "a483e7010203"] {
+            let err = parse_mac(bad).unwrap_err();
+            assert_eq!(ErrorCode::of(&err), ErrorCode::BadRequest);
+        }
+
+        let packet = magic_packet(&mac);
+        assert_eq!(packet.len(), MAGIC_PACKET_LEN);
+        assert_eq!(parse_magic_packet(&packet), Some(mac));
+        let mut corrupt = packet.clone();
+        corrupt[50] ^= 1;
+        assert_eq!(parse_magic_packet(&corrupt), None);
+        assert_eq!(parse_magic_packet(&packet[..60]), None);
+    }
+
+    #[test]
+    fn txt_record_lists_ip_candidates() {
+        use crate::addresses::Candidate;
+        let candidate = |host: &str, kind| Candidate { host: host.to_string(), kind };
+        let hosts = [
+            candidate("192.168.1.20", "lan"),
+            candidate("2a00:1450::1", "ipv6"),
+            candidate("studio.local", "mdns"),
+            candidate("100.101.102.103", "tailscale"),
+        ];
+        assert_eq!(txt_record(&hosts).as_deref(), Some("addrs=192.168.1.20,2a00:1450::1,100.101.102.103"));
+        assert_eq!(txt_record(&hosts[2..3]), None);
+
+        let many: Vec<_> = (0..40).map(|i| candidate(&format!("2001:db8::{i:x}"), "ipv6")).collect();
+        let txt = txt_record(&many).unwrap();
+        assert!(txt.len() <= 255 && txt.ends_with(&many[txt.matches(',').count()].host));
+    }
+
+    #[tokio::test]
+    async fn relay_forwards_allowed_magic_packets() {
+        let allowed = [0x02, 0, 0, 0, 0, 1];
+        let other = [0x02, 0, 0, 0, 0, 2];
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let relay_addr = socket.local_addr().unwrap();
+        let lan = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let target = lan.local_addr().unwrap();
+        tokio::spawn(async move { relay(socket, target, &[allowed]).await });
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        for datagram in [b"hello".to_vec(), magic_packet(&other), magic_packet(&allowed), magic_packet(&allowed)] {
+            client.send_to(&datagram, relay_addr).await.unwrap();
+        }
+
+        let mut buf = [0u8; 512];
+        let len = tokio::time::timeout(Duration::from_secs(5), lan.recv(&mut buf)).await.unwrap().unwrap();
+        assert_eq!(parse_magic_packet(&buf[..len]), Some(allowed));
+        // The repeat within the throttle window isn't forwarded
+        assert!(tokio::time::timeout(Duration::from_millis(300), lan.recv(&mut buf)).await.is_err());
+    }
+}
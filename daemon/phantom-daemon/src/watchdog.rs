@@ -0,0 +1,137 @@
+//! Watchdog for PTY reads that hang.
+//!
+//! In rare driver states a read on the PTY master blocks forever: the
+//! session looks alive but shows nothing, and the bridge's blocking read
+//! thread is stuck. Silence alone says nothing (a shell waits quietly for
+//! its next command), so the bridge's watchdog only counts a read as stalled
+//! when the master has polled readable (output waiting) for all of
+//! `[bridge] read_stall_secs` while the reader stayed blocked in it. The
+//! reader tells a [`ReadWatch`] when it enters and leaves a read.
+//!
+//! The bridge then reads with a freshly cloned reader, leaving the stuck one
+//! to return (and hand on what it read) or not. That is all it does: if the
+//! clone fails or the new reader stalls too, it logs a warning and leaves
+//! the session alone. Off by default.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// What the PTY reader of one bridge is up to. Times are microseconds since
+/// `epoch`, plus one so that 0 means "not".
+#[derive(Debug)]
+pub struct ReadWatch {
+    epoch: Instant,
+    /// When the reader entered the read it's blocked in
+    reading_since: AtomicU64,
+    /// Since when the master has polled readable during that read
+    readable_since: AtomicU64,
+    /// Reads that returned
+    reads: AtomicU64,
+    /// The current reader; readers it replaced leave after their read
+    generation: AtomicU64,
+}
+
+impl ReadWatch {
+    pub fn new(now: Instant) -> Self {
+        Self {
+            epoch: now,
+            reading_since: AtomicU64::new(0),
+            readable_since: AtomicU64::new(0),
+            reads: AtomicU64::new(0),
+            generation: AtomicU64::new(0),
+        }
+    }
+
+    fn stamp(&self, now: Instant) -> u64 {
+        now.saturating_duration_since(self.epoch).as_micros() as u64 + 1
+    }
+
+    /// The reader is about to block in a read.
+    pub fn reading(&self, now: Instant) {
+        self.reading_since.store(self.stamp(now), Ordering::Relaxed);
+    }
+
+    /// A read returned.
+    pub fn read_returned(&self) {
+        self.reading_since.store(0, Ordering::Relaxed);
+        self.readable_since.store(0, Ordering::Relaxed);
+        self.reads.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn reads(&self) -> u64 {
+        self.reads.load(Ordering::Relaxed)
+    }
+
+    /// Whether the master polls `readable` now. Returns how long the reader
+    /// has been blocked with output waiting, once that's `limit` or more;
+    /// the master not polling readable starts the count over.
+    pub fn stalled(&self, now: Instant, readable: bool, limit: Duration) -> Option<Duration> {
+        let reading = self.reading_since.load(Ordering::Relaxed);
+        if reading == 0 || !readable {
+            self.readable_since.store(0, Ordering::Relaxed);
+            return None;
+        }
+        let stamp = self.stamp(now);
+        let since = match self.readable_since.compare_exchange(0, stamp, Ordering::Relaxed, Ordering::Relaxed) {
+            Ok(_) => stamp,
+            Err(since) => since,
+        };
+        let stalled = Duration::from_micros(stamp.saturating_sub(reading.max(since)));
+        (stalled >= limit).then_some(stalled)
+    }
+
+    /// Hand reading to a new reader: returns its generation, and the stall
+    /// count starts over for it.
+    pub fn replace_reader(&self) -> u64 {
+        self.readable_since.store(0, Ordering::Relaxed);
+        self.generation.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    /// Whether the reader of `generation` is still the one reading.
+    pub fn is_current(&self, generation: u64) -> bool {
+        self.generation.load(Ordering::Relaxed) == generation
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_blocked_with_output_waiting_are_stalls() {
+        let limit = Duration::from_secs(30);
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let watch = ReadWatch::new(start);
+
+        // Idle: blocked in a read, but nothing to read
+        watch.reading(at(0));
+        assert_eq!(watch.stalled(at(100), false, limit), None);
+
+        // Output waiting for the limit, counted from when it was first seen
+        assert_eq!(watch.stalled(at(100), true, limit), None);
+        assert_eq!(watch.stalled(at(129), true, limit), None);
+        assert_eq!(watch.stalled(at(131), true, limit), Some(Duration::from_secs(31)));
+
+        // Not readable at one look: the count starts over
+        assert_eq!(watch.stalled(at(132), false, limit), None);
+        assert_eq!(watch.stalled(at(140), true, limit), None);
+        assert!(watch.stalled(at(170), true, limit).is_some());
+
+        // A new reader gets the full limit again
+        assert_eq!(watch.replace_reader(), 1);
+        assert!(!watch.is_current(0));
+        watch.reading(at(170));
+        assert_eq!(watch.stalled(at(171), true, limit), None);
+        assert_eq!(watch.stalled(at(200), true, limit), None);
+        assert!(watch.stalled(at(201), true, limit).is_some());
+
+        // The read returns
+        watch.read_returned();
+        assert_eq!(watch.reads(), 1);
+        watch.reading(at(201));
+        assert_eq!(watch.stalled(at(202), true, limit), None);
+        assert_eq!(watch.stalled(at(300), false, limit), None);
+    }
+}
@@ -0,0 +1,226 @@
+//! Devices paired with a FIDO2 / WebAuthn credential (a hardware key such as
+//! a YubiKey) instead of a key the app holds.
+//!
+//! The pairing request carries the credential's P-256 public key (SEC1, as
+//! for other devices) with its `webauthn` registration: credential id and
+//! relying party id. Challenges to such a device name the credential, and
+//! the device answers with a WebAuthn assertion over the challenge, which
+//! [`verify`] checks as a relying party would: the client data is a
+//! `webauthn.get` for this challenge from an https origin on the relying
+//! party's domain, the authenticator data is for this
+//! relying party with the user present, the signature counter moved on, and
+//! the signature covers both.
+
+use anyhow::{Context, Result};
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::errors::ErrorCode;
+
+/// Authenticator data flag: the user touched the key.
+const FLAG_USER_PRESENT: u8 = 0x01;
+/// rpIdHash, flags and the signature counter.
+const AUTHENTICATOR_DATA_MIN: usize = 37;
+
+/// A paired device's WebAuthn credential.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WebAuthnCredential {
+    /// base64url, as the authenticator returned it
+    pub credential_id: String,
+    /// Relying party the credential is scoped to
+    pub rp_id: String,
+    /// Signature counter of the last assertion (0 = the key keeps none)
+    #[serde(default)]
+    pub sign_count: u32,
+}
+
+/// A credential as named at pairing (the registration) and in challenges
+/// (so the client asks the right key).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CredentialRef {
+    pub credential_id: String,
+    pub rp_id: String,
+}
+
+/// What a challenged WebAuthn device answers with, base64 encoded.
+#[derive(Debug, Deserialize)]
+pub struct Assertion {
+    pub authenticator_data: String,
+    pub client_data_json: String,
+    /// DER ECDSA signature over authenticator data and the client data hash
+    pub signature: String,
+}
+
+#[derive(Deserialize)]
+struct ClientData {
+    #[serde(rename = "type")]
+    type_: String,
+    challenge: String,
+    origin: String,
+}
+
+/// Whether `origin` is an https origin on `rp_id` or one of its subdomains,
+/// the origins a browser lets assert for it.
+fn origin_matches(origin: &str, rp_id: &str) -> bool {
+    let Some(authority) = origin.strip_prefix("https://") else {
+        return false;
+    };
+    let host = match authority.rsplit_once(':') {
+        Some((host, port)) if !port.is_empty() && port.bytes().all(|b| b.is_ascii_digit()) => host,
+        _ => authority,
+    };
+    host.eq_ignore_ascii_case(rp_id)
+        || host.len() > rp_id.len()
+            && host.as_bytes()[host.len() - rp_id.len() - 1] == b'.'
+            && host[host.len() - rp_id.len()..].eq_ignore_ascii_case(rp_id)
+}
+
+impl WebAuthnCredential {
+    /// A credential registered at pairing.
+    pub fn new(credential_id: &str, rp_id: &str) -> Result<Self> {
+        if credential_id.is_empty() || rp_id.is_empty() {
+            return Err(ErrorCode::BadRequest.err("webauthn needs credential_id and rp_id"));
+        }
+        Ok(Self { credential_id: credential_id.to_string(), rp_id: rp_id.to_string(), sign_count: 0 })
+    }
+
+    pub fn to_ref(&self) -> CredentialRef {
+        CredentialRef { credential_id: self.credential_id.clone(), rp_id: self.rp_id.clone() }
+    }
+}
+
+/// The base64url form of `challenge` a client puts in its client data.
+pub fn encode_challenge(challenge: &[u8]) -> String {
+    base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(challenge)
+}
+
+/// Check `assertion` answers `challenge` for `credential`, whose public key
+/// is `public_key_b64`. Returns the new signature counter to store.
+pub fn verify(
+    credential: &WebAuthnCredential,
+    public_key_b64: &str,
+    challenge: &[u8],
+    assertion: &Assertion,
+) -> Result<u32> {
+    use p256::ecdsa::{signature::Verifier, Signature, VerifyingKey};
+    let b64 = base64::engine::general_purpose::STANDARD;
+
+    let authenticator_data = b64.decode(&assertion.authenticator_data).context("decode authenticator data")?;
+    let client_data_json = b64.decode(&assertion.client_data_json).context("decode client data")?;
+    let client_data: ClientData = serde_json::from_slice(&client_data_json).context("parse client data")?;
+    if client_data.type_ != "webauthn.get" {
+        return Err(ErrorCode::Unauthenticated.err(format!("client data is for {}", client_data.type_)));
+    }
+    if client_data.challenge != encode_challenge(challenge) {
+        return Err(ErrorCode::Unauthenticated.err("assertion is for another challenge"));
+    }
+    if !origin_matches(&client_data.origin, &credential.rp_id) {
+        return Err(ErrorCode::Unauthenticated.err(format!("assertion is from origin {}", client_data.origin)));
+    }
+
+    if authenticator_data.len() < AUTHENTICATOR_DATA_MIN {
+        return Err(ErrorCode::Unauthenticated.err("authenticator data too short"));
+    }
+    if authenticator_data[..32] != Sha256::digest(credential.rp_id.as_bytes())[..] {
+        return Err(ErrorCode::Unauthenticated.err("assertion is for another relying party"));
+    }
+    if authenticator_data[32] & FLAG_USER_PRESENT == 0 {
+        return Err(ErrorCode::Unauthenticated.err("user presence not confirmed"));
+    }
+    let sign_count = u32::from_be_bytes(authenticator_data[33..37].try_into().expect("4 bytes"));
+    // A counter that didn't move on means the key may have been cloned
+    if (sign_count != 0 || credential.sign_count != 0) && sign_count <= credential.sign_count {
+        return Err(ErrorCode::Unauthenticated.err("signature counter went backwards"));
+    }
+
+    let key = VerifyingKey::from_sec1_bytes(&b64.decode(public_key_b64).context("decode public key")?)
+        .context("parse P256 public key")?;
+    let signature = Signature::from_der(&b64.decode(&assertion.signature).context("decode signature")?)
+        .context("parse DER signature")?;
+    let mut signed = authenticator_data;
+    signed.extend_from_slice(&Sha256::digest(&client_data_json));
+    key.verify(&signed, &signature)
+        .map_err(|_| ErrorCode::Unauthenticated.err("signature verification failed"))?;
+    Ok(sign_count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use p256::ecdsa::{signature::Signer, Signature, SigningKey};
+
+    /// An assertion as an authenticator for `rp_id` would make.
+    fn assertion(key: &SigningKey, rp_id: &str, challenge: &[u8], sign_count: u32, flags: u8) -> Assertion {
+        assertion_from(key, rp_id, &format!("https://{rp_id}"), challenge, sign_count, flags)
+    }
+
+    /// An assertion for `rp_id` whose client data names `origin`.
+    fn assertion_from(
+        key: &SigningKey,
+        rp_id: &str,
+        origin: &str,
+        challenge: &[u8],
+        sign_count: u32,
+        flags: u8,
+    ) -> Assertion {
+        let b64 = base64::engine::general_purpose::STANDARD;
+        let mut authenticator_data = Sha256::digest(rp_id.as_bytes()).to_vec();
+        authenticator_data.push(flags);
+        authenticator_data.extend_from_slice(&sign_count.to_be_bytes());
+        let client_data = serde_json::json!({
+            "type": "webauthn.get",
+            "challenge": encode_challenge(challenge),
+            "origin": origin,
+        });
+        let client_data_json = serde_json::to_vec(&client_data).unwrap();
+        let mut signed = authenticator_data.clone();
+        signed.extend_from_slice(&Sha256::digest(&client_data_json));
+        let signature: Signature = key.sign(&signed);
+        Assertion {
+            authenticator_data: b64.encode(authenticator_data),
+            client_data_json: b64.encode(client_data_json),
+            signature: b64.encode(signature.to_der()),
+        }
+    }
+
+    #[test]
+    fn verifies_assertions_like_a_relying_party() {
+        let key = SigningKey::random(&mut rand::thread_rng());
+        let public_key = base64::engine::general_purpose::STANDARD
+            .encode(p256::EncodedPoint::from(*key.verifying_key()).as_bytes());
+        let mut credential = WebAuthnCredential::new("cred-1", "phantom.local").unwrap();
+        let challenge = [7u8; 32];
+
+        let good = assertion(&key, "phantom.local", &challenge, 5, FLAG_USER_PRESENT);
+        assert_eq!(verify(&credential, &public_key, &challenge, &good).unwrap(), 5);
+        credential.sign_count = 5;
+
+        let reject = |assertion: &Assertion, challenge: &[u8]| {
+            let err = verify(&credential, &public_key, challenge, assertion).unwrap_err();
+            assert_eq!(ErrorCode::of(&err), ErrorCode::Unauthenticated);
+        };
+        // Replayed, for another challenge or party, without a touch, or by another key
+        reject(&good, &challenge);
+        reject(&assertion(&key, "phantom.local", &[8u8; 32], 6, FLAG_USER_PRESENT), &challenge);
+        reject(&assertion(&key, "evil.example", &challenge, 6, FLAG_USER_PRESENT), &challenge);
+        reject(&assertion(&key, "phantom.local", &challenge, 6, 0), &challenge);
+        let other = SigningKey::random(&mut rand::thread_rng());
+        reject(&assertion(&other, "phantom.local", &challenge, 6, FLAG_USER_PRESENT), &challenge);
+        // From a page on another site, or not over https
+        for origin in ["https://evil.example", "https://notphantom.local", "http://phantom.local", "phantom.local"] {
+            reject(&assertion_from(&key, "phantom.local", origin, &challenge, 6, FLAG_USER_PRESENT), &challenge);
+        }
+        // Subdomains and ports of the relying party may assert for it
+        let subdomain = "https://app.phantom.local:8443";
+        let from_subdomain = assertion_from(&key, "phantom.local", subdomain, &challenge, 6, FLAG_USER_PRESENT);
+        assert_eq!(verify(&credential, &public_key, &challenge, &from_subdomain).unwrap(), 6);
+        credential.sign_count = 6;
+
+        // Keys that keep no counter always send 0
+        credential.sign_count = 0;
+        let uncounted = assertion(&key, "phantom.local", &challenge, 0, FLAG_USER_PRESENT);
+        assert_eq!(verify(&credential, &public_key, &challenge, &uncounted).unwrap(), 0);
+        assert!(WebAuthnCredential::new("", "phantom.local").is_err());
+    }
+}
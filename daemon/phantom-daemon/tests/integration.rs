@@ -1,267 +1,10 @@
-use anyhow::{Context, Result};
+use anyhow::Result;
+use phantom_daemon::clock::ManualClock;
+use phantom_daemon::testing::{recv_json, send_json, TestHarness};
 use phantom_frame::{self as frame, Frame, FrameDecoder, FrameType};
 use std::sync::Arc;
 use std::time::Duration;
 
-// Re-use crate internals — integration tests are in-crate tests via `tests/`.
-// We access the binary's modules by depending on the phantom-daemon lib if exported,
-// but since it's a binary crate, we'll build a mini test harness using the same deps.
-
-/// Helper: generate self-signed cert and key for testing.
-fn gen_test_cert() -> (Vec<u8>, Vec<u8>) {
-    use rcgen::{CertificateParams, KeyPair};
-    let key_pair = KeyPair::generate_for(&rcgen::PKCS_ECDSA_P256_SHA256).unwrap();
-    let params = CertificateParams::new(vec!["localhost".to_string()]).unwrap();
-    let cert = params.self_signed(&key_pair).unwrap();
-    (cert.der().to_vec(), key_pair.serialize_der())
-}
-
-/// Helper: build quinn server config.
-fn build_server_config(cert_der: &[u8], key_der: &[u8]) -> quinn::ServerConfig {
-    use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
-
-    let cert = CertificateDer::from(cert_der.to_vec());
-    let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(key_der.to_vec()));
-
-    let mut tls_config = rustls::ServerConfig::builder()
-        .with_no_client_auth()
-        .with_single_cert(vec![cert], key)
-        .unwrap();
-    tls_config.alpn_protocols = vec![b"phantom/1".to_vec()];
-    tls_config.max_early_data_size = 0;
-
-    let quic_config = quinn::crypto::rustls::QuicServerConfig::try_from(Arc::new(tls_config)).unwrap();
-    let mut server_config = quinn::ServerConfig::with_crypto(Arc::new(quic_config));
-    let transport = Arc::get_mut(&mut server_config.transport).unwrap();
-    transport.max_idle_timeout(Some(Duration::from_secs(30).try_into().unwrap()));
-    transport.keep_alive_interval(Some(Duration::from_secs(5)));
-
-    server_config
-}
-
-/// Helper: build quinn client config (accept any cert).
-fn build_client_config() -> quinn::ClientConfig {
-    let mut crypto = rustls::ClientConfig::builder()
-        .dangerous()
-        .with_custom_certificate_verifier(Arc::new(AcceptAnyCert))
-        .with_no_client_auth();
-    crypto.alpn_protocols = vec![b"phantom/1".to_vec()];
-
-    let mut client_config = quinn::ClientConfig::new(Arc::new(
-        quinn::crypto::rustls::QuicClientConfig::try_from(crypto).unwrap(),
-    ));
-    let mut transport = quinn::TransportConfig::default();
-    transport.max_idle_timeout(Some(Duration::from_secs(30).try_into().unwrap()));
-    client_config.transport_config(Arc::new(transport));
-
-    client_config
-}
-
-#[derive(Debug)]
-struct AcceptAnyCert;
-
-impl rustls::client::danger::ServerCertVerifier for AcceptAnyCert {
-    fn verify_server_cert(
-        &self, _: &rustls::pki_types::CertificateDer, _: &[rustls::pki_types::CertificateDer],
-        _: &rustls::pki_types::ServerName, _: &[u8], _: rustls::pki_types::UnixTime,
-    ) -> Result<rustls::client::danger::ServerCertVerified, rustls::Error> {
-        Ok(rustls::client::danger::ServerCertVerified::assertion())
-    }
-    fn verify_tls12_signature(
-        &self, _: &[u8], _: &rustls::pki_types::CertificateDer,
-        _: &rustls::DigitallySignedStruct,
-    ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
-        Ok(rustls::client::danger::HandshakeSignatureValid::assertion())
-    }
-    fn verify_tls13_signature(
-        &self, _: &[u8], _: &rustls::pki_types::CertificateDer,
-        _: &rustls::DigitallySignedStruct,
-    ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
-        Ok(rustls::client::danger::HandshakeSignatureValid::assertion())
-    }
-    fn supported_verify_schemes(&self) -> Vec<rustls::SignatureScheme> {
-        rustls::crypto::ring::default_provider()
-            .signature_verification_algorithms
-            .supported_schemes()
-    }
-}
-
-/// Helper: generate P256 key pair for test client.
-fn gen_p256_key() -> (p256::ecdsa::SigningKey, p256::ecdsa::VerifyingKey) {
-    let sk = p256::ecdsa::SigningKey::random(&mut rand::thread_rng());
-    let vk = *sk.verifying_key();
-    (sk, vk)
-}
-
-/// Helper: send a length-prefixed JSON message on a QUIC stream.
-async fn send_json(send: &mut quinn::SendStream, value: &serde_json::Value) -> Result<()> {
-    let json = serde_json::to_vec(value)?;
-    let len = (json.len() as u32).to_be_bytes();
-    send.write_all(&len).await?;
-    send.write_all(&json).await?;
-    Ok(())
-}
-
-/// Helper: receive a length-prefixed JSON message from a QUIC stream.
-async fn recv_json(recv: &mut quinn::RecvStream) -> Result<serde_json::Value> {
-    let mut len_buf = [0u8; 4];
-    recv.read_exact(&mut len_buf).await?;
-    let len = u32::from_be_bytes(len_buf) as usize;
-    let mut buf = vec![0u8; len];
-    recv.read_exact(&mut buf).await?;
-    Ok(serde_json::from_slice(&buf)?)
-}
-
-/// Test harness: starts a daemon server in the background, returns the client endpoint
-/// and socket address to connect to. Also returns the device_id and signing key for auth.
-struct TestHarness {
-    server_addr: std::net::SocketAddr,
-    client_endpoint: quinn::Endpoint,
-    device_id: String,
-    signing_key: p256::ecdsa::SigningKey,
-    _server_handle: tokio::task::JoinHandle<()>,
-    _temp_dir: tempfile::TempDir,
-}
-
-impl TestHarness {
-    async fn new() -> Result<Self> {
-        let (cert_der, key_der) = gen_test_cert();
-        let server_config = build_server_config(&cert_der, &key_der);
-
-        let server_endpoint = quinn::Endpoint::server(
-            server_config,
-            "127.0.0.1:0".parse().unwrap(),
-        )?;
-        let server_addr = server_endpoint.local_addr()?;
-
-        // Create temp dir for device store
-        let temp_dir = tempfile::TempDir::new()?;
-
-        // Create device store and pre-pair a test device
-        let (sk, vk) = gen_p256_key();
-        let device_id = "test-device-001".to_string();
-        let pub_key_b64 = {
-            use base64::Engine;
-            let point = p256::EncodedPoint::from(vk);
-            base64::engine::general_purpose::STANDARD.encode(point.as_bytes())
-        };
-
-        // Write devices.json with pre-paired device
-        let devices_json = serde_json::json!({
-            "devices": {
-                &device_id: {
-                    "device_id": &device_id,
-                    "public_key": &pub_key_b64,
-                    "device_name": "Test Device",
-                    "paired_at": "2024-01-01T00:00:00Z",
-                    "last_seen": null,
-                }
-            }
-        });
-        std::fs::write(
-            temp_dir.path().join("devices.json"),
-            serde_json::to_string_pretty(&devices_json)?,
-        )?;
-
-        // Start server components
-        let device_store = Arc::new(
-            phantom_daemon::device_store::DeviceStore::new(temp_dir.path())?,
-        );
-        let authenticator = Arc::new(phantom_daemon::auth::Authenticator::new(device_store));
-        let session_manager = Arc::new(phantom_daemon::session::SessionManager::new());
-
-        // Start session reaper
-        let sm_for_reaper = session_manager.clone();
-        let reaper_cancel = tokio_util::sync::CancellationToken::new();
-        let reaper_cancel_clone = reaper_cancel.clone();
-        tokio::spawn(async move {
-            sm_for_reaper.run_reaper(reaper_cancel_clone, 5).await;
-        });
-
-        let sm_for_server = session_manager.clone();
-        let server_handle = tokio::spawn(async move {
-            if let Err(e) = phantom_daemon::server::run(
-                server_endpoint,
-                sm_for_server,
-                authenticator,
-                100,  // conn_limit
-                60,   // conn_window_secs
-                10,   // auth_fail_limit
-                300,  // auth_fail_window_secs
-            ).await {
-                eprintln!("server error: {e:#}");
-            }
-        });
-
-        // Build client endpoint
-        let mut client_endpoint = quinn::Endpoint::client("0.0.0.0:0".parse().unwrap())?;
-        client_endpoint.set_default_client_config(build_client_config());
-
-        Ok(Self {
-            server_addr,
-            client_endpoint,
-            device_id,
-            signing_key: sk,
-            _server_handle: server_handle,
-            _temp_dir: temp_dir,
-        })
-    }
-
-    /// Connect to the server and authenticate.
-    async fn connect_and_auth(&self) -> Result<quinn::Connection> {
-        let connection = self.client_endpoint
-            .connect(self.server_addr, "localhost")?
-            .await
-            .context("QUIC connect")?;
-
-        // Open control stream and authenticate
-        let (mut send, mut recv) = connection.open_bi().await?;
-
-        // Send auth_request
-        let auth_req = serde_json::json!({
-            "type": "auth_request",
-            "request_id": "test-auth-1",
-            "device_id": &self.device_id,
-        });
-        send_json(&mut send, &auth_req).await?;
-
-        // Receive challenge
-        let challenge_msg = recv_json(&mut recv).await?;
-        assert_eq!(challenge_msg["type"], "auth_challenge");
-        let challenge_b64 = challenge_msg["challenge"].as_str().unwrap();
-        let challenge_bytes = {
-            use base64::Engine;
-            base64::engine::general_purpose::STANDARD.decode(challenge_b64)?
-        };
-
-        // Sign challenge (without TLS exporter binding — daemon supports fallback)
-        let signature = {
-            use p256::ecdsa::{signature::Signer, Signature};
-            let sig: Signature = self.signing_key.sign(&challenge_bytes);
-            use base64::Engine;
-            base64::engine::general_purpose::STANDARD.encode(sig.to_der().as_bytes())
-        };
-
-        // Send auth response
-        let auth_resp = serde_json::json!({
-            "type": "auth_response",
-            "request_id": "test-auth-1",
-            "device_id": &self.device_id,
-            "signature": signature,
-        });
-        send_json(&mut send, &auth_resp).await?;
-
-        // Receive auth result
-        let result = recv_json(&mut recv).await?;
-        assert_eq!(result["type"], "auth_response");
-        assert_eq!(result["success"], true, "auth failed: {:?}", result["error"]);
-
-        Ok(connection)
-    }
-}
-
-// ─── Tests ───────────────────────────────────────────────────────────────
-
 #[tokio::test]
 async fn full_session_lifecycle() -> Result<()> {
     rustls::crypto::ring::default_provider()
@@ -484,8 +227,15 @@ async fn session_reaper_cleans_dead_sessions() -> Result<()> {
         .install_default()
         .ok();
 
-    let harness = TestHarness::new().await?;
+    // Time stands still until the test moves it
+    let clock = ManualClock::new();
+    let retention = Duration::from_secs(60);
+    let harness = {
+        let clock = clock.clone();
+        TestHarness::with_session_manager(move |manager| manager.with_clock(clock).with_exited_retention(retention)).await?
+    };
     let conn = harness.connect_and_auth().await?;
+    let sm = harness.session_manager();
 
     // Create session
     let (mut send, mut recv) = conn.open_bi().await?;
@@ -502,16 +252,23 @@ async fn session_reaper_cleans_dead_sessions() -> Result<()> {
     let exit_frame = Frame::data(1, b"exit\n".to_vec());
     send.write_all(&frame::encode(&exit_frame, false)?).await?;
 
-    // Wait for the shell to exit
-    tokio::time::sleep(Duration::from_secs(2)).await;
-
     // Close data stream
     send.finish()?;
     drop(send);
     drop(recv);
 
-    // Wait for reaper to run (runs every 5s)
-    tokio::time::sleep(Duration::from_secs(7)).await;
+    let alive = || sm.list_sessions().iter().any(|s| s.id == session_id && s.alive);
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(10);
+    while alive() {
+        assert!(tokio::time::Instant::now() < deadline, "shell didn't exit");
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    // A reaper pass notices the exit, and keeps the session for its retention
+    sm.reap();
+    assert!(sm.get_session(&session_id).is_some());
+
+    clock.advance(retention);
+    sm.reap();
 
     // List sessions — dead session should be reaped
     let (mut send2, mut recv2) = conn.open_bi().await?;
@@ -1030,3 +787,959 @@ async fn sustained_throughput() -> Result<()> {
     conn.close(quinn::VarInt::from_u32(0), b"done");
     Ok(())
 }
+
+#[tokio::test]
+async fn command_output_throughput_excludes_shell_startup() -> Result<()> {
+    rustls::crypto::ring::default_provider()
+        .install_default()
+        .ok();
+
+    let harness = TestHarness::new().await?;
+    let conn = harness.connect_and_auth().await?;
+
+    // Create session
+    let (mut send, mut recv) = conn.open_bi().await?;
+    send_json(&mut send, &serde_json::json!({
+        "type": "create_session",
+        "request_id": "throughput-command-create",
+        "rows": 24,
+        "cols": 200,
+    })).await?;
+    let resp = recv_json(&mut recv).await?;
+    assert_eq!(resp["type"], "session_created");
+
+    // Wait for shell
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    // Generate sustained output using yes piped to head (fast, no encoding overhead).
+    // The marker is split in the command so the shell's echo of it doesn't match.
+    let cmd = Frame::data(1, b"yes $(printf '%0200d' 0) | head -c 131072; echo THROUGHPUT_''DONE\n".to_vec());
+    send.write_all(&frame::encode(&cmd, false)?).await?;
+
+    // Measure total bytes received until marker, timed from the first output
+    // line so shell startup (prompt, rc files) isn't counted
+    let mut decoder = FrameDecoder::new();
+    let mut total_bytes: usize = 0;
+    let mut start: Option<tokio::time::Instant> = None;
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(10);
+    let mut found_marker = false;
+
+    loop {
+        if tokio::time::Instant::now() > deadline { break; }
+        let mut buf = [0u8; 16384];
+        match tokio::time::timeout(Duration::from_millis(500), recv.read(&mut buf)).await {
+            Ok(Ok(Some(n))) => {
+                decoder.feed(&buf[..n]);
+                while let Some(frame) = decoder.decode_next()? {
+                    if frame.frame_type == FrameType::Data {
+                        if start.is_none() && frame.payload.windows(32).any(|w| w.iter().all(|&b| b == b'0')) {
+                            start = Some(tokio::time::Instant::now());
+                        }
+                        if start.is_some() {
+                            total_bytes += frame.payload.len();
+                        }
+                        if frame.payload.windows(15).any(|w| w == b"THROUGHPUT_DONE") {
+                            found_marker = true;
+                            break;
+                        }
+                    }
+                }
+                if found_marker { break; }
+            }
+            _ => continue,
+        }
+    }
+
+    let elapsed = start.expect("no command output received").elapsed();
+    let throughput_kb = total_bytes as f64 / elapsed.as_secs_f64() / 1024.0;
+
+    eprintln!("[throughput] command output: {total_bytes} bytes in {elapsed:?} ({throughput_kb:.0} KB/s)");
+
+    assert!(found_marker, "didn't receive throughput marker (got {total_bytes} bytes)");
+    // Localhost QUIC should exceed 100 KB/s easily
+    assert!(throughput_kb > 100.0,
+        "throughput too low: {throughput_kb:.0} KB/s");
+
+    send.finish()?;
+    conn.close(quinn::VarInt::from_u32(0), b"done");
+    Ok(())
+}
+
+#[tokio::test]
+async fn close_handshake_flushes_final_output() -> Result<()> {
+    rustls::crypto::ring::default_provider()
+        .install_default()
+        .ok();
+
+    let harness = TestHarness::new().await?;
+    let conn = harness.connect_and_auth().await?;
+
+    let (mut send, mut recv) = conn.open_bi().await?;
+    send_json(&mut send, &serde_json::json!({
+        "type": "create_session",
+        "request_id": "close-create",
+        "rows": 24,
+        "cols": 80,
+    })).await?;
+    let resp = recv_json(&mut recv).await?;
+    assert_eq!(resp["type"], "session_created");
+
+    // Wait until the shell is reading input. Markers are split with '' in the
+    // commands so only the command output (not the echo) matches.
+    let mut decoder = FrameDecoder::new();
+    let mut output = Vec::new();
+    let ready = Frame::data(1, b"echo CLOSE_''READY\n".to_vec());
+    send.write_all(&frame::encode(&ready, false)?).await?;
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(10);
+    while !String::from_utf8_lossy(&output).contains("CLOSE_READY") {
+        assert!(tokio::time::Instant::now() < deadline, "shell never became ready");
+        let mut buf = [0u8; 4096];
+        if let Ok(Ok(Some(n))) = tokio::time::timeout(Duration::from_millis(500), recv.read(&mut buf)).await {
+            decoder.feed(&buf[..n]);
+            while let Some(frame) = decoder.decode_next()? {
+                output.extend_from_slice(&frame.payload);
+            }
+        }
+    }
+
+    // Final command immediately followed by Close: its output must not be lost
+    let last = Frame::data(2, b"echo CLOSE_''TAIL\n".to_vec());
+    send.write_all(&frame::encode(&last, false)?).await?;
+    send.write_all(&frame::encode(&Frame::close(3), false)?).await?;
+
+    // Read everything until the daemon finishes the stream
+    output.clear();
+    let mut close_reason = None;
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(10);
+    loop {
+        assert!(tokio::time::Instant::now() < deadline, "stream never finished");
+        let mut buf = [0u8; 4096];
+        match tokio::time::timeout(Duration::from_millis(500), recv.read(&mut buf)).await {
+            Ok(Ok(Some(n))) => {
+                decoder.feed(&buf[..n]);
+                while let Some(frame) = decoder.decode_next()? {
+                    match frame.frame_type {
+                        FrameType::Data => {
+                            assert!(close_reason.is_none(), "data after Close frame");
+                            output.extend_from_slice(&frame.payload);
+                        }
+                        FrameType::Close => {
+                            close_reason = frame.parse_close_reason().map(str::to_string);
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            Ok(Ok(None)) => break,
+            Ok(Err(e)) => panic!("read error: {e}"),
+            Err(_) => continue,
+        }
+    }
+
+    assert!(
+        String::from_utf8_lossy(&output).contains("CLOSE_TAIL"),
+        "final output lost: {}",
+        String::from_utf8_lossy(&output),
+    );
+    assert_eq!(close_reason.as_deref(), Some("client_close"));
+
+    conn.close(quinn::VarInt::from_u32(0), b"done");
+    Ok(())
+}
+
+#[tokio::test]
+async fn input_during_replay_follows_scrollback() -> Result<()> {
+    rustls::crypto::ring::default_provider()
+        .install_default()
+        .ok();
+
+    let harness = TestHarness::new().await?;
+    let conn = harness.connect_and_auth().await?;
+
+    // Create a session with some scrollback
+    let (mut send, mut recv) = conn.open_bi().await?;
+    send_json(&mut send, &serde_json::json!({
+        "type": "create_session",
+        "request_id": "replay-create",
+        "rows": 24,
+        "cols": 80,
+    })).await?;
+    let resp = recv_json(&mut recv).await?;
+    let session_id = resp["session_id"].as_str().unwrap().to_string();
+
+    let input = Frame::data(1, b"echo REPLAY_''HISTORY\n".to_vec());
+    send.write_all(&frame::encode(&input, false)?).await?;
+    let mut decoder = FrameDecoder::new();
+    let mut output = Vec::new();
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(10);
+    while !String::from_utf8_lossy(&output).contains("REPLAY_HISTORY") {
+        assert!(tokio::time::Instant::now() < deadline, "no output before detach");
+        let mut buf = [0u8; 4096];
+        if let Ok(Ok(Some(n))) = tokio::time::timeout(Duration::from_millis(200), recv.read(&mut buf)).await {
+            decoder.feed(&buf[..n]);
+            while let Some(frame) = decoder.decode_next()? {
+                output.extend_from_slice(&frame.payload);
+            }
+        }
+    }
+    send.finish()?;
+    drop(send);
+    drop(recv);
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    // Reattach and type before reading anything back
+    let (mut send2, mut recv2) = conn.open_bi().await?;
+    send_json(&mut send2, &serde_json::json!({
+        "type": "attach_session",
+        "request_id": "replay-attach",
+        "session_id": &session_id,
+    })).await?;
+    let early = Frame::data(1, b"echo REPLAY_''TYPED\n".to_vec());
+    send2.write_all(&frame::encode(&early, false)?).await?;
+
+    let attach_resp = recv_json(&mut recv2).await?;
+    assert_eq!(attach_resp["type"], "session_attached");
+
+    // The typed command's output arrives, and only after the replay
+    let mut decoder2 = FrameDecoder::new();
+    let mut scrollback = Vec::new();
+    let mut live = Vec::new();
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(10);
+    while !String::from_utf8_lossy(&live).contains("REPLAY_TYPED") {
+        assert!(tokio::time::Instant::now() < deadline, "typed input lost");
+        let mut buf = [0u8; 4096];
+        if let Ok(Ok(Some(n))) = tokio::time::timeout(Duration::from_millis(200), recv2.read(&mut buf)).await {
+            decoder2.feed(&buf[..n]);
+            while let Some(frame) = decoder2.decode_next()? {
+                match frame.frame_type {
+                    FrameType::Scrollback => {
+                        // Possibly over several frames, and an empty one ending it
+                        assert!(live.is_empty(), "scrollback after live output");
+                        scrollback.extend_from_slice(&frame.payload);
+                    }
+                    FrameType::Data => live.extend_from_slice(&frame.payload),
+                    _ => {}
+                }
+            }
+        }
+    }
+    assert!(String::from_utf8_lossy(&scrollback).contains("REPLAY_HISTORY"), "expected the history replayed");
+
+    send2.finish()?;
+    conn.close(quinn::VarInt::from_u32(0), b"done");
+    Ok(())
+}
+
+#[tokio::test]
+async fn prewarm_pool_serves_and_refills() -> Result<()> {
+    let mut config = phantom_daemon::config::DaemonConfig::default();
+    config.session.prewarm = 2;
+    let sm = Arc::new(phantom_daemon::session::SessionManager::with_config(&config));
+
+    let cancel = tokio_util::sync::CancellationToken::new();
+    let sm_for_prewarm = sm.clone();
+    let cancel_for_prewarm = cancel.clone();
+    tokio::spawn(async move {
+        sm_for_prewarm.run_prewarm(cancel_for_prewarm).await;
+    });
+
+    let wait_for_pool = |target: usize| {
+        let sm = sm.clone();
+        async move {
+            let deadline = tokio::time::Instant::now() + Duration::from_secs(10);
+            while sm.pooled() < target {
+                assert!(tokio::time::Instant::now() < deadline, "pool never reached {target}");
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        }
+    };
+    wait_for_pool(2).await;
+
+    // Pooled shells aren't visible as sessions until handed out
+    assert!(sm.list_sessions().is_empty());
+
+    let id = sm.create_session(30, 100, Some("test-device"), phantom_daemon::terminal::Launch::default())?;
+    assert_eq!(sm.pooled(), 1);
+    let sessions = sm.list_sessions();
+    assert_eq!(sessions.len(), 1);
+    assert_eq!(sessions[0].id, id);
+    assert!(sessions[0].alive);
+    assert_eq!(sessions[0].created_by_device_id.as_deref(), Some("test-device"));
+
+    // The pool is topped back up in the background
+    wait_for_pool(2).await;
+
+    cancel.cancel();
+    sm.destroy_all();
+    assert_eq!(sm.pooled(), 0);
+    Ok(())
+}
+
+#[tokio::test]
+async fn memory_budget_rejects_and_compacts() -> Result<()> {
+    let mut config = phantom_daemon::config::DaemonConfig::default();
+    config.session.scrollback_bytes = 65536;
+    config.memory.max_bytes = 65536 * 2;
+    config.memory.pressure_percent = 50;
+    let sm = phantom_daemon::session::SessionManager::with_config(&config);
+
+    let first = sm.create_session(24, 80, None, phantom_daemon::terminal::Launch::default())?;
+    let second = sm.create_session(24, 80, None, phantom_daemon::terminal::Launch::default())?;
+    assert_eq!(sm.budget().used(), 65536 * 2);
+
+    // Full budget and nothing compactable: a third session is rejected clearly
+    let err = sm.create_session(24, 80, None, phantom_daemon::terminal::Launch::default()).unwrap_err();
+    assert!(format!("{err:#}").contains("memory budget exhausted"), "{err:#}");
+    assert_eq!(phantom_daemon::errors::ErrorCode::of(&err), phantom_daemon::errors::ErrorCode::LimitExceeded);
+
+    // Give both detached sessions some history, then relieve pressure
+    for id in [&first, &second] {
+        let session = sm.get_session(id).unwrap();
+        let scrollback = session.lock().unwrap().scrollback.clone();
+        scrollback.lock().unwrap().append(&b"compressible output\r\n".repeat(1000));
+    }
+    assert!(sm.budget().under_pressure());
+    sm.compact_detached(false);
+    assert!(!sm.budget().under_pressure());
+
+    // History survives compaction, and the freed memory admits a new session
+    let session = sm.get_session(&first).unwrap();
+    let scrollback = session.lock().unwrap().scrollback.clone();
+    let history = scrollback.lock().unwrap().read_from_clean_point();
+    assert!(history.ends_with(b"compressible output\r\n"));
+    let third = sm.create_session(24, 80, None, phantom_daemon::terminal::Launch::default())?;
+
+    for id in [first, second, third] {
+        sm.destroy_session(&id)?;
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn exec_streams_output_and_exit_status() -> Result<()> {
+    rustls::crypto::ring::default_provider()
+        .install_default()
+        .ok();
+
+    let harness = TestHarness::new().await?;
+    let conn = harness.connect_and_auth().await?;
+
+    let (mut send, mut recv) = conn.open_bi().await?;
+    send_json(&mut send, &serde_json::json!({
+        "type": "exec",
+        "request_id": "exec-1",
+        "command": "echo EXEC_OUT; exit 3",
+    })).await?;
+    let resp = recv_json(&mut recv).await?;
+    assert_eq!(resp["type"], "exec_started");
+    assert_eq!(resp["request_id"], "exec-1");
+
+    // Output frames, then a Close carrying the exit status, then end of stream
+    let mut decoder = FrameDecoder::new();
+    let mut output = Vec::new();
+    let mut close_reason = None;
+    tokio::time::timeout(Duration::from_secs(10), async {
+        let mut buf = [0u8; 4096];
+        while let Some(n) = recv.read(&mut buf).await? {
+            decoder.feed(&buf[..n]);
+            while let Some(frame) = decoder.decode_next()? {
+                match frame.frame_type {
+                    FrameType::Data => output.extend_from_slice(&frame.payload),
+                    FrameType::Close => close_reason = frame.parse_close_reason().map(str::to_string),
+                    _ => {}
+                }
+            }
+        }
+        anyhow::Ok(())
+    })
+    .await??;
+
+    assert!(String::from_utf8_lossy(&output).contains("EXEC_OUT"));
+    assert_eq!(close_reason.as_deref(), Some("exit:3"));
+    // No session is left behind
+    assert!(harness.session_manager().list_sessions().is_empty());
+
+    // Missing command is an error reply, and the stream stays in control mode
+    let (mut send, mut recv) = conn.open_bi().await?;
+    send_json(&mut send, &serde_json::json!({"type": "exec", "request_id": "exec-2"})).await?;
+    let resp = recv_json(&mut recv).await?;
+    assert_eq!(resp["type"], "error");
+    assert_eq!(resp["request_id"], "exec-2");
+
+    conn.close(quinn::VarInt::from_u32(0), b"done");
+    Ok(())
+}
+
+#[tokio::test]
+async fn hello_settles_the_wire_of_the_connections_streams() -> Result<()> {
+    rustls::crypto::ring::default_provider()
+        .install_default()
+        .ok();
+
+    let harness = TestHarness::new().await?;
+    let (conn, hello) = harness
+        .connect_with_hello(serde_json::json!({
+            "protocol_version": 1,
+            "frame_types": ["data", "resize", "heartbeat", "close", "scrollback", "window_update", "hello"],
+            "compression": [],
+            "max_payload": 1024,
+        }))
+        .await?;
+    assert_eq!(hello["request_id"], "test-auth-1");
+    assert_eq!(hello["protocol_version"], 1);
+    assert_eq!(hello["compression"], serde_json::Value::Null);
+    assert_eq!(hello["max_payload"], 1024);
+    let types = hello["frame_types"].as_array().unwrap();
+    assert!(types.contains(&"hello".into()) && !types.contains(&"paste".into()));
+
+    // A Hello frame of the same wire first, then small uncompressed output
+    let frames = run_in_session(&conn, "hello").await?;
+    assert_eq!(frames[0].0, FrameType::Hello.to_u8());
+    let wire: serde_json::Value = serde_json::from_slice(&frames[0].2)?;
+    assert_eq!(wire["max_payload"], 1024);
+    assert!(frames.iter().all(|(_, flags, payload)| flags & frame::FLAG_COMPRESSED == 0 && payload.len() <= 1024));
+    conn.close(quinn::VarInt::from_u32(0), b"done");
+
+    // Without hello: no Hello frame, and output compressed as before
+    let conn = harness.connect_and_auth().await?;
+    let frames = run_in_session(&conn, "legacy").await?;
+    assert!(frames.iter().all(|(frame_type, ..)| *frame_type != FrameType::Hello.to_u8()));
+    assert!(frames.iter().any(|(_, flags, _)| flags & frame::FLAG_COMPRESSED != 0));
+
+    conn.close(quinn::VarInt::from_u32(0), b"done");
+    Ok(())
+}
+
+/// Create a session, print a few KB of `x` in it and return the raw frames
+/// (type, flags, wire payload) up to the end of that output.
+async fn run_in_session(conn: &quinn::Connection, name: &str) -> Result<Vec<(u8, u16, Vec<u8>)>> {
+    let (mut send, mut recv) = conn.open_bi().await?;
+    send_json(&mut send, &serde_json::json!({
+        "type": "create_session",
+        "request_id": format!("create-{name}"),
+        "rows": 24,
+        "cols": 80,
+    })).await?;
+    let resp = recv_json(&mut recv).await?;
+    assert_eq!(resp["type"], "session_created");
+
+    let cmd = b"head -c 6000 /dev/zero | tr '\\0' x; echo; echo WIRE_$((1+1))DONE\n";
+    send.write_all(&frame::encode(&Frame::data(1, cmd.to_vec()), false)?).await?;
+
+    // Frames by hand: the decoder would hide the compressed flag
+    let mut wire = Vec::new();
+    let mut frames = Vec::new();
+    let mut output = Vec::new();
+    tokio::time::timeout(Duration::from_secs(10), async {
+        let mut buf = [0u8; 4096];
+        while !String::from_utf8_lossy(&output).contains("WIRE_2DONE") {
+            let n = recv.read(&mut buf).await?.expect("stream finished");
+            wire.extend_from_slice(&buf[..n]);
+            while wire.len() >= frame::HEADER_SIZE {
+                let len = u32::from_be_bytes(wire[1..5].try_into()?) as usize;
+                if wire.len() < frame::HEADER_SIZE + len {
+                    break;
+                }
+                let flags = u16::from_be_bytes(wire[13..15].try_into()?);
+                let frame: Vec<u8> = wire.drain(..frame::HEADER_SIZE + len).collect();
+                let mut decoder = FrameDecoder::new();
+                decoder.feed(&frame);
+                let decoded = decoder.decode_next()?.expect("a whole frame");
+                if decoded.frame_type == FrameType::Data {
+                    output.extend_from_slice(&decoded.payload);
+                }
+                frames.push((frame[0], flags, frame[frame::HEADER_SIZE..].to_vec()));
+            }
+        }
+        anyhow::Ok(())
+    })
+    .await??;
+    Ok(frames)
+}
+
+#[tokio::test]
+async fn stdio_relay_carries_streams_as_chunks() -> Result<()> {
+    use frame::stdio;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    rustls::crypto::ring::default_provider()
+        .install_default()
+        .ok();
+
+    let harness = TestHarness::new().await?;
+    let conn = harness.connect_and_auth().await?;
+    let (mut client, relay_end) = tokio::io::duplex(64 * 1024);
+    let (input, output) = tokio::io::split(relay_end);
+    let relay = tokio::spawn(phantom_daemon::stdio::relay(conn, input, output));
+
+    // Two requests on their own streams, the second's answer then its frames
+    let request = |stream, json: serde_json::Value| {
+        stdio::encode_chunk(stream, &frame::control::encode_message(json.to_string().as_bytes()))
+    };
+    client.write_all(&request(1, serde_json::json!({"type": "list_sessions", "request_id": "l-1"}))?).await?;
+    let exec = serde_json::json!({"type": "exec", "request_id": "e-1", "command": "echo STDIO_OUT"});
+    client.write_all(&request(2, exec)?).await?;
+
+    let mut streams: std::collections::HashMap<u32, Vec<u8>> = Default::default();
+    let mut finished = Vec::new();
+    let mut pending = Vec::new();
+    tokio::time::timeout(Duration::from_secs(10), async {
+        while !finished.contains(&2) {
+            client.read_buf(&mut pending).await?;
+            while let Some((stdio::Chunk { stream, bytes }, len)) = stdio::decode_chunk(&pending)? {
+                if bytes.is_empty() {
+                    finished.push(stream);
+                }
+                streams.entry(stream).or_default().extend_from_slice(bytes);
+                pending.drain(..len);
+            }
+        }
+        anyhow::Ok(())
+    })
+    .await??;
+
+    let (list, _) = frame::control::decode_message(&streams[&1]).expect("a reply on stream 1");
+    assert_eq!(serde_json::from_slice::<serde_json::Value>(list)?["type"], "session_list");
+    let (started, consumed) = frame::control::decode_message(&streams[&2]).expect("a reply on stream 2");
+    assert_eq!(serde_json::from_slice::<serde_json::Value>(started)?["type"], "exec_started");
+    let mut decoder = FrameDecoder::new();
+    decoder.feed(&streams[&2][consumed..]);
+    let mut output = Vec::new();
+    while let Some(frame) = decoder.decode_next()? {
+        if frame.frame_type == FrameType::Data {
+            output.extend_from_slice(&frame.payload);
+        }
+    }
+    assert!(String::from_utf8_lossy(&output).contains("STDIO_OUT"));
+
+    // The end of the input ends the relay
+    drop(client);
+    tokio::time::timeout(Duration::from_secs(5), relay).await???;
+    Ok(())
+}
+
+#[tokio::test]
+async fn guest_link_grants_single_use_read_only_access() -> Result<()> {
+    rustls::crypto::ring::default_provider()
+        .install_default()
+        .ok();
+
+    let harness = TestHarness::new().await?;
+    let session_id = harness.session_manager().create_session(24, 80, None, phantom_daemon::terminal::Launch::default())?;
+    let token = harness.device_store().create_guest_token(&session_id, Duration::from_secs(60));
+
+    let (conn, auth) = harness.connect_as_guest("colleague-laptop", &token).await?;
+    assert_eq!(auth["success"], true, "guest auth failed: {:?}", auth["error"]);
+    assert_eq!(auth["session_id"], session_id.as_str());
+
+    // Guests can't manage sessions
+    let (mut send, mut recv) = conn.open_bi().await?;
+    send_json(&mut send, &serde_json::json!({"type": "list_sessions", "request_id": "g-1"})).await?;
+    let resp = recv_json(&mut recv).await?;
+    assert_eq!(resp["type"], "error");
+
+    // ...but can watch their session; what they type never reaches the shell
+    send_json(&mut send, &serde_json::json!({
+        "type": "attach_session",
+        "request_id": "g-2",
+        "session_id": &session_id,
+    })).await?;
+    let resp = recv_json(&mut recv).await?;
+    assert_eq!(resp["type"], "session_attached");
+    let typed = Frame::data(1, b"echo GUEST_''TYPED\n".to_vec());
+    send.write_all(&frame::encode(&typed, false)?).await?;
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    send.write_all(&frame::encode(&Frame::close(2), false)?).await?;
+    tokio::time::timeout(Duration::from_secs(10), async {
+        let mut buf = [0u8; 4096];
+        while recv.read(&mut buf).await?.is_some() {}
+        anyhow::Ok(())
+    })
+    .await??;
+
+    let session = harness.session_manager().get_session(&session_id).expect("session");
+    let scrollback = session.lock().unwrap().scrollback.clone();
+    let output = scrollback.lock().unwrap().read_from_clean_point();
+    assert!(!String::from_utf8_lossy(&output).contains("GUEST_TYPED"));
+    conn.close(quinn::VarInt::from_u32(0), b"done");
+
+    // The link is single use (the daemon may close before the rejection arrives)
+    if let Ok((_, auth)) = harness.connect_as_guest("colleague-laptop", &token).await {
+        assert_eq!(auth["success"], false);
+    }
+    assert!(harness.device_store().redeem_guest_token(&token).is_none());
+
+    harness.session_manager().destroy_session(&session_id)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn resume_token_skips_the_challenge_and_lists_attached_sessions() -> Result<()> {
+    rustls::crypto::ring::default_provider()
+        .install_default()
+        .ok();
+
+    let harness = TestHarness::new().await?;
+    let (old_conn, auth) = harness.challenge_auth(harness.server_addr()).await?;
+    let token = auth["resume_token"].as_str().expect("resume token").to_string();
+
+    // Attach a session on the connection about to be "lost"
+    let (mut send, mut recv) = old_conn.open_bi().await?;
+    send_json(&mut send, &serde_json::json!({"type": "create_session", "request_id": "c-1"})).await?;
+    let created = recv_json(&mut recv).await?;
+    assert_eq!(created["type"], "session_created");
+    let session_id = created["session_id"].as_str().unwrap().to_string();
+
+    let (conn, _send, mut recv, auth) = harness.connect_with_resume_token(&token).await?;
+    assert_eq!(auth["type"], "auth_response");
+    assert_eq!((&auth["success"], &auth["resumed"]), (&true.into(), &true.into()));
+    assert_ne!(auth["resume_token"], token.as_str());
+    let resumable = recv_json(&mut recv).await?;
+    assert_eq!(resumable["type"], "resumable_sessions");
+    assert_eq!(resumable["request_id"], "resume-auth-1");
+    assert_eq!(resumable["session_ids"], serde_json::json!([session_id]));
+    conn.close(quinn::VarInt::from_u32(0), b"done");
+
+    // Spent: the same token is answered with a challenge
+    let (conn, _, _, auth) = harness.connect_with_resume_token(&token).await?;
+    assert_eq!(auth["type"], "auth_challenge");
+    conn.close(quinn::VarInt::from_u32(0), b"done");
+
+    harness.session_manager().destroy_session(&session_id)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn hardware_keys_are_challenged_instead_of_resuming() -> Result<()> {
+    use phantom_daemon::webauthn::WebAuthnCredential;
+
+    rustls::crypto::ring::default_provider()
+        .install_default()
+        .ok();
+
+    let harness = TestHarness::new().await?;
+    let (conn, auth) = harness.challenge_auth(harness.server_addr()).await?;
+    let token = auth["resume_token"].as_str().expect("resume token").to_string();
+    conn.close(quinn::VarInt::from_u32(0), b"done");
+
+    // Re-paired with a hardware key: the token left over asks for a touch
+    let store = harness.device_store();
+    let public_key = store.get_public_key(harness.device_id())?;
+    let credential = WebAuthnCredential::new("cred-1", "phantom.example")?;
+    store.add_device_with(harness.device_id(), &public_key, "yubikey", None, None, Some(credential))?;
+    let (conn, _, _, auth) = harness.connect_with_resume_token(&token).await?;
+    assert_eq!(auth["type"], "auth_challenge");
+    assert_eq!(auth["webauthn"]["credential_id"], "cred-1");
+    conn.close(quinn::VarInt::from_u32(0), b"done");
+    Ok(())
+}
+
+#[tokio::test]
+async fn paired_device_vouches_for_a_new_one() -> Result<()> {
+    rustls::crypto::ring::default_provider()
+        .install_default()
+        .ok();
+
+    let harness = TestHarness::new().await?;
+    let paired = harness.connect_and_auth().await?;
+    let (new_conn, _send, mut new_recv) = harness.request_vouched_pairing("new-phone-001").await?;
+
+    // The paired device is asked, on a stream of its own
+    let mut events = tokio::time::timeout(Duration::from_secs(5), paired.accept_uni()).await??;
+    let prompt = recv_json(&mut events).await?;
+    assert_eq!(prompt["type"], "pairing_request");
+    assert_eq!((&prompt["device_id"], &prompt["device_name"]), (&"new-phone-001".into(), &"New Phone".into()));
+    assert_eq!(prompt["verification_code"].as_str().unwrap().len(), 6);
+
+    let (mut send, mut recv) = paired.open_bi().await?;
+    send_json(&mut send, &serde_json::json!({
+        "type": "approve_pairing",
+        "request_id": "approve-1",
+        "pairing_id": prompt["pairing_id"],
+    })).await?;
+    let settled = recv_json(&mut recv).await?;
+    assert_eq!((&settled["type"], &settled["approved"]), (&"pairing_settled".into(), &true.into()));
+
+    let auth = recv_json(&mut new_recv).await?;
+    assert_eq!((&auth["request_id"], &auth["success"]), (&"vouch-auth-1".into(), &true.into()));
+    let device = harness
+        .device_store()
+        .list_devices()
+        .into_iter()
+        .find(|d| d.device_id == "new-phone-001")
+        .expect("new device paired");
+    assert_eq!(device.vouched_by.as_deref(), Some(harness.device_id()));
+
+    new_conn.close(quinn::VarInt::from_u32(0), b"done");
+    paired.close(quinn::VarInt::from_u32(0), b"done");
+    Ok(())
+}
+
+#[tokio::test]
+async fn repeated_auth_failures_are_answered_with_retry_after() -> Result<()> {
+    rustls::crypto::ring::default_provider()
+        .install_default()
+        .ok();
+
+    let harness = TestHarness::new().await?;
+    for _ in 0..3 {
+        let resp = harness.auth_with_bad_signature().await?;
+        assert_eq!((&resp["success"], &resp["code"]), (&false.into(), &"UNAUTHENTICATED".into()));
+        assert!(resp["retry_after_secs"].is_null());
+    }
+
+    // The device is locked out, without a challenge, until the oldest failure ages out
+    let resp = harness.auth_with_bad_signature().await?;
+    assert_eq!(resp["type"], "auth_response");
+    assert_eq!(resp["code"], "RATE_LIMITED");
+    let retry_after = resp["retry_after_secs"].as_u64().unwrap();
+    assert!(retry_after > 0 && retry_after <= 300, "{retry_after}");
+    Ok(())
+}
+
+#[tokio::test]
+#[ignore = "needs tmux"]
+async fn tmux_passthrough_attaches_existing_session() -> Result<()> {
+    rustls::crypto::ring::default_provider()
+        .install_default()
+        .ok();
+
+    // A private tmux server, so the user's own sessions are never touched
+    let tmux_dir = tempfile::TempDir::new()?;
+    let tmux = |args: &[&str]| {
+        std::process::Command::new("tmux")
+            .args(args)
+            .env_remove("TMUX")
+            .env("TMUX_TMPDIR", tmux_dir.path())
+            .status()
+    };
+    let status = tmux(&["new-session", "-d", "-s", "phantom-test", "-x", "80", "-y", "24", "sh"])?;
+    assert!(status.success(), "tmux new-session: {status}");
+
+    let harness = TestHarness::with_session_manager(|manager| manager.with_tmux_tmpdir(tmux_dir.path().to_path_buf())).await?;
+    let conn = harness.connect_and_auth().await?;
+
+    let (mut send, mut recv) = conn.open_bi().await?;
+    send_json(&mut send, &serde_json::json!({"type": "list_tmux_sessions", "request_id": "tmux-1"})).await?;
+    let resp = recv_json(&mut recv).await?;
+    assert_eq!(resp["type"], "tmux_session_list");
+    let sessions = resp["sessions"].as_array().unwrap();
+    assert_eq!(sessions.len(), 1, "{resp}");
+    assert_eq!(sessions[0]["name"], "phantom-test");
+    assert_eq!(sessions[0]["attached"], 0);
+
+    send_json(&mut send, &serde_json::json!({
+        "type": "create_session",
+        "request_id": "tmux-2",
+        "tmux_session": "missing",
+    })).await?;
+    let resp = recv_json(&mut recv).await?;
+    assert_eq!(resp["type"], "error");
+    assert_eq!(resp["error"], "tmux session not found: missing");
+
+    send_json(&mut send, &serde_json::json!({
+        "type": "create_session",
+        "request_id": "tmux-3",
+        "tmux_session": "phantom-test",
+        "rows": 24,
+        "cols": 80,
+    })).await?;
+    let resp = recv_json(&mut recv).await?;
+    assert_eq!(resp["type"], "session_created", "{resp}");
+    assert_eq!(resp["tmux_session"], "phantom-test");
+    let session_id = resp["session_id"].as_str().unwrap().to_string();
+    let info = &harness.session_manager().list_sessions()[0];
+    assert_eq!(info.tmux_session.as_deref(), Some("phantom-test"));
+
+    // Input reaches the shell inside tmux
+    let cmd = frame::encode(&Frame::data(1, b"echo TMUX_''OK\n".to_vec()), false)?;
+    send.write_all(&cmd).await?;
+    let mut decoder = FrameDecoder::new();
+    let mut output = Vec::new();
+    tokio::time::timeout(Duration::from_secs(10), async {
+        let mut buf = [0u8; 16384];
+        while !String::from_utf8_lossy(&output).contains("TMUX_OK") {
+            let n = recv.read(&mut buf).await?.expect("stream open");
+            decoder.feed(&buf[..n]);
+            while let Some(frame) = decoder.decode_next()? {
+                if frame.frame_type == FrameType::Data {
+                    output.extend_from_slice(&frame.payload);
+                }
+            }
+        }
+        anyhow::Ok(())
+    })
+    .await??;
+
+    // Destroying the Phantom session only detaches the tmux client
+    harness.session_manager().destroy_session(&session_id)?;
+    let detached = tokio::time::timeout(Duration::from_secs(10), async {
+        loop {
+            let sessions = harness.session_manager().tmux().list_sessions().await?;
+            if sessions.iter().any(|s| s.name == "phantom-test" && s.attached == 0) {
+                return anyhow::Ok(());
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await;
+    let _ = tmux(&["kill-server"]);
+    detached??;
+
+    conn.close(quinn::VarInt::from_u32(0), b"done");
+    Ok(())
+}
+
+#[tokio::test]
+async fn agent_forwarding_relays_to_permitted_device() -> Result<()> {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    rustls::crypto::ring::default_provider()
+        .install_default()
+        .ok();
+
+    let harness = TestHarness::new().await?;
+
+    // Off until the device is allowed
+    let conn = harness.connect_and_auth().await?;
+    let (mut send, mut recv) = conn.open_bi().await?;
+    send_json(&mut send, &serde_json::json!({
+        "type": "create_session",
+        "request_id": "agent-1",
+        "agent_forwarding": true,
+    })).await?;
+    let resp = recv_json(&mut recv).await?;
+    assert_eq!(resp["error"], "agent forwarding is not permitted for this device");
+    conn.close(quinn::VarInt::from_u32(0), b"done");
+
+    // Permission is read when the device connects
+    harness.device_store().set_agent_forwarding(harness.device_id(), true)?;
+    let conn = harness.connect_and_auth().await?;
+
+    // Device side: answer one agent request with an empty identity list
+    let device_conn = conn.clone();
+    let device = tokio::spawn(async move {
+        let (mut send, mut recv) = device_conn.accept_bi().await?;
+        let header = recv_json(&mut recv).await?;
+        let mut request = [0u8; 5];
+        recv.read_exact(&mut request).await?;
+        // SSH_AGENT_IDENTITIES_ANSWER with zero keys
+        send.write_all(&[0, 0, 0, 5, 12, 0, 0, 0, 0]).await?;
+        send.finish()?;
+        anyhow::Ok((header, request))
+    });
+
+    let (mut send, mut recv) = conn.open_bi().await?;
+    send_json(&mut send, &serde_json::json!({
+        "type": "create_session",
+        "request_id": "agent-2",
+        "agent_forwarding": true,
+    })).await?;
+    let resp = recv_json(&mut recv).await?;
+    assert_eq!(resp["type"], "session_created", "{resp}");
+    assert_eq!(resp["agent_forwarding"], true);
+    let session_id = resp["session_id"].as_str().unwrap().to_string();
+    assert!(harness.session_manager().list_sessions()[0].agent_forwarding);
+
+    // The shell sees the session's socket
+    let socket = harness.agent_dir().join(format!("{session_id}.sock"));
+    let cmd = frame::encode(&Frame::data(1, b"echo $SSH_AUTH_SOCK\n".to_vec()), false)?;
+    send.write_all(&cmd).await?;
+    let mut decoder = FrameDecoder::new();
+    let mut output = Vec::new();
+    let expected = socket.to_string_lossy().to_string();
+    tokio::time::timeout(Duration::from_secs(10), async {
+        let mut buf = [0u8; 16384];
+        while !String::from_utf8_lossy(&output).contains(&expected) {
+            let n = recv.read(&mut buf).await?.expect("stream open");
+            decoder.feed(&buf[..n]);
+            while let Some(frame) = decoder.decode_next()? {
+                if frame.frame_type == FrameType::Data {
+                    output.extend_from_slice(&frame.payload);
+                }
+            }
+        }
+        anyhow::Ok(())
+    })
+    .await??;
+
+    // SSH_AGENTC_REQUEST_IDENTITIES through the socket is answered by the device
+    let mut agent = tokio::net::UnixStream::connect(&socket).await?;
+    agent.write_all(&[0, 0, 0, 1, 11]).await?;
+    let mut answer = [0u8; 9];
+    tokio::time::timeout(Duration::from_secs(10), agent.read_exact(&mut answer)).await??;
+    assert_eq!(answer, [0, 0, 0, 5, 12, 0, 0, 0, 0]);
+
+    let (header, request) = device.await??;
+    assert_eq!(header["type"], "agent_forward");
+    assert_eq!(header["session_id"], session_id.as_str());
+    assert_eq!(request, [0, 0, 0, 1, 11]);
+
+    // Denying the device cuts off sessions it already has
+    harness.device_store().set_agent_forwarding(harness.device_id(), false)?;
+    let mut agent = tokio::net::UnixStream::connect(&socket).await?;
+    // The daemon hangs up, possibly before the request is even written
+    let _ = agent.write_all(&[0, 0, 0, 1, 11]).await;
+    let mut answer = Vec::new();
+    let _ = tokio::time::timeout(Duration::from_secs(10), agent.read_to_end(&mut answer)).await?;
+    assert!(answer.is_empty(), "{answer:?}");
+
+    // The socket goes away with the session
+    harness.session_manager().destroy_session(&session_id)?;
+    assert!(!socket.exists());
+
+    conn.close(quinn::VarInt::from_u32(0), b"done");
+    Ok(())
+}
+
+#[tokio::test]
+async fn upgrade_handoff_keeps_shell_running() -> Result<()> {
+    use std::io::{Read, Write};
+
+    let old = phantom_daemon::session::SessionManager::new();
+    let id = old.create_session(24, 80, Some("test-device"), phantom_daemon::terminal::Launch::default())?;
+    let scrollback = old.get_session(&id).unwrap().lock().unwrap().scrollback.clone();
+    scrollback.lock().unwrap().append(b"before upgrade\r\n");
+
+    let handed = old.handoff_sessions();
+    assert_eq!(handed.len(), 1);
+    let new = phantom_daemon::session::SessionManager::new();
+    for (handoff, master) in handed {
+        new.adopt_session(handoff, master)?;
+    }
+    // The old daemon goes away without hanging up its shells
+    drop(old);
+
+    let info = &new.list_sessions()[0];
+    assert_eq!(info.id, id);
+    assert!(info.alive);
+    assert_eq!(info.created_by_device_id.as_deref(), Some("test-device"));
+
+    let session = new.get_session(&id).unwrap();
+    let (mut reader, writer) = {
+        let mut s = session.lock().unwrap();
+        let history = s.scrollback.lock().unwrap().read_from_clean_point();
+        assert!(history.starts_with(b"before upgrade\r\n"));
+        (s.reader.take().unwrap(), s.writer.clone())
+    };
+    writer.lock().unwrap().write_all(b"echo HANDOFF_''OK\n")?;
+    let output = tokio::time::timeout(
+        Duration::from_secs(10),
+        tokio::task::spawn_blocking(move || {
+            let mut output = Vec::new();
+            let mut buf = [0u8; 4096];
+            while !String::from_utf8_lossy(&output).contains("HANDOFF_OK") {
+                let n = reader.read(&mut buf)?;
+                anyhow::ensure!(n > 0, "PTY closed");
+                output.extend_from_slice(&buf[..n]);
+            }
+            Ok(output)
+        }),
+    )
+    .await???;
+    assert!(String::from_utf8_lossy(&output).contains("HANDOFF_OK"));
+
+    session.lock().unwrap().resize(30, 100)?;
+    new.destroy_session(&id)?;
+    Ok(())
+}
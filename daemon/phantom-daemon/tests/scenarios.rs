@@ -0,0 +1,31 @@
+//! Protocol scenarios in `tests/scenarios/*.json`, each run against its own
+//! daemon. See `phantom_daemon::scenario` for the format; a bug reproduced as
+//! a scenario there is a regression test from then on.
+
+use anyhow::{Context, Result};
+use phantom_daemon::scenario::Scenario;
+use phantom_daemon::testing::TestHarness;
+use std::path::PathBuf;
+
+#[tokio::test]
+async fn protocol_scenarios() -> Result<()> {
+    rustls::crypto::ring::default_provider()
+        .install_default()
+        .ok();
+
+    let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/scenarios");
+    let mut paths: Vec<PathBuf> = std::fs::read_dir(&dir)
+        .with_context(|| format!("read {}", dir.display()))?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<std::io::Result<_>>()?;
+    paths.retain(|path| path.extension().is_some_and(|ext| ext == "json"));
+    paths.sort();
+    assert!(!paths.is_empty(), "no scenarios in {}", dir.display());
+
+    for path in paths {
+        let scenario = Scenario::load(&path)?;
+        let harness = TestHarness::new().await?;
+        scenario.run(&harness).await?;
+    }
+    Ok(())
+}
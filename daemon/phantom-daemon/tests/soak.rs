@@ -0,0 +1,323 @@
+//! Long-running soak test over an impaired network.
+//!
+//! Runs the daemon and a client in-process with a UDP proxy between them that
+//! adds latency, jitter (which reorders datagrams), and random loss. An
+//! interactive workload runs for `PHANTOM_SOAK_SECS` (default one hour),
+//! asserting frames arrive intact and in sequence, memory stays flat, and
+//! reattach replays the session's history.
+//!
+//! Ignored by default; run with:
+//!   PHANTOM_SOAK_SECS=3600 cargo test --release --test soak -- --ignored --nocapture
+
+use anyhow::{bail, Context, Result};
+use phantom_daemon::testing::{recv_json, send_json, TestHarness};
+use phantom_frame::{self as frame, FrameDecoder, FrameType};
+use rand::Rng;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+
+/// Output kept for marker matching; older output is discarded.
+const OUTPUT_WINDOW: usize = 64 * 1024;
+/// Allowed RSS growth between the warm-up sample and any later sample.
+const RSS_GROWTH_LIMIT: u64 = 64 * 1024 * 1024;
+
+#[derive(Clone, Copy)]
+struct LinkConditions {
+    latency: Duration,
+    /// Extra random delay per datagram; anything above the inter-packet gap reorders
+    jitter: Duration,
+    /// Probability of dropping a datagram
+    loss: f64,
+}
+
+impl LinkConditions {
+    /// Deliver `packet` with `send` after the link's delay, or drop it.
+    fn forward<F, Fut>(&self, packet: Vec<u8>, send: F)
+    where
+        F: FnOnce(Vec<u8>) -> Fut + Send + 'static,
+        Fut: std::future::Future<Output = ()> + Send,
+    {
+        let (lost, jitter) = {
+            let mut rng = rand::thread_rng();
+            (rng.gen_bool(self.loss), rng.gen_range(Duration::ZERO..=self.jitter))
+        };
+        if lost {
+            return;
+        }
+        let delay = self.latency + jitter;
+        tokio::spawn(async move {
+            tokio::time::sleep(delay).await;
+            send(packet).await;
+        });
+    }
+}
+
+/// Start a UDP proxy in front of `server` for a single client, impairing
+/// traffic in both directions. Returns the address clients should dial.
+async fn spawn_impaired_proxy(server: SocketAddr, link: LinkConditions) -> Result<SocketAddr> {
+    let front = Arc::new(UdpSocket::bind("127.0.0.1:0").await?);
+    let back = Arc::new(UdpSocket::bind("127.0.0.1:0").await?);
+    back.connect(server).await?;
+    let addr = front.local_addr()?;
+    let client: Arc<Mutex<Option<SocketAddr>>> = Arc::new(Mutex::new(None));
+
+    // Client → server
+    {
+        let front = front.clone();
+        let back = back.clone();
+        let client = client.clone();
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; 65536];
+            while let Ok((n, from)) = front.recv_from(&mut buf).await {
+                *client.lock().unwrap() = Some(from);
+                let back = back.clone();
+                link.forward(buf[..n].to_vec(), move |packet| async move {
+                    let _ = back.send(&packet).await;
+                });
+            }
+        });
+    }
+
+    // Server → client
+    tokio::spawn(async move {
+        let mut buf = vec![0u8; 65536];
+        while let Ok(n) = back.recv(&mut buf).await {
+            let Some(to) = *client.lock().unwrap() else {
+                continue;
+            };
+            let front = front.clone();
+            link.forward(buf[..n].to_vec(), move |packet| async move {
+                let _ = front.send_to(&packet, to).await;
+            });
+        }
+    });
+
+    Ok(addr)
+}
+
+/// A client attached to a session, checking every frame it receives.
+struct Attachment {
+    send: quinn::SendStream,
+    recv: quinn::RecvStream,
+    decoder: FrameDecoder,
+    seq_in: u64,
+    /// Sequence of the last Data frame from the daemon
+    last_seq_out: u64,
+    output: Vec<u8>,
+    scrollback: Vec<u8>,
+}
+
+impl Attachment {
+    fn new(send: quinn::SendStream, recv: quinn::RecvStream) -> Self {
+        Self {
+            send,
+            recv,
+            decoder: FrameDecoder::new(),
+            seq_in: 1,
+            last_seq_out: 0,
+            output: Vec::new(),
+            scrollback: Vec::new(),
+        }
+    }
+
+    async fn create(conn: &quinn::Connection) -> Result<(String, Self)> {
+        let (mut send, mut recv) = conn.open_bi().await?;
+        send_json(&mut send, &serde_json::json!({
+            "type": "create_session",
+            "request_id": "soak-create",
+            "rows": 24,
+            "cols": 80,
+        })).await?;
+        let resp = recv_json(&mut recv).await?;
+        assert_eq!(resp["type"], "session_created", "{resp}");
+        let session_id = resp["session_id"].as_str().context("session_id")?.to_string();
+        Ok((session_id, Self::new(send, recv)))
+    }
+
+    async fn attach(conn: &quinn::Connection, session_id: &str) -> Result<Self> {
+        let (mut send, mut recv) = conn.open_bi().await?;
+        send_json(&mut send, &serde_json::json!({
+            "type": "attach_session",
+            "request_id": "soak-attach",
+            "session_id": session_id,
+        })).await?;
+        let resp = recv_json(&mut recv).await?;
+        assert_eq!(resp["type"], "session_attached", "{resp}");
+        Ok(Self::new(send, recv))
+    }
+
+    /// Type `line` and Enter one keystroke per frame, like a phone keyboard.
+    async fn type_line(&mut self, line: &str) -> Result<()> {
+        for key in line.bytes().chain(std::iter::once(b'\n')) {
+            let keystroke = frame::encode_small(FrameType::Data, self.seq_in, &[key])
+                .expect("keystroke fits a small frame");
+            self.seq_in += 1;
+            self.send.write_all(&keystroke).await?;
+        }
+        Ok(())
+    }
+
+    /// Read one chunk from the stream and validate its frames. Returns false
+    /// once the daemon has finished the stream.
+    async fn pump(&mut self) -> Result<bool> {
+        let mut buf = [0u8; 16384];
+        let Some(n) = self.recv.read(&mut buf).await? else {
+            return Ok(false);
+        };
+        self.decoder.feed(&buf[..n]);
+        while let Some(frame) = self.decoder.decode_next().context("corrupt frame stream")? {
+            match frame.frame_type {
+                FrameType::Data => {
+                    if frame.sequence != self.last_seq_out + 1 {
+                        bail!("data frame {} after {}", frame.sequence, self.last_seq_out);
+                    }
+                    self.last_seq_out = frame.sequence;
+                    self.output.extend_from_slice(&frame.payload);
+                }
+                FrameType::Scrollback => self.scrollback.extend_from_slice(&frame.payload),
+                _ => {}
+            }
+        }
+        if self.output.len() > OUTPUT_WINDOW {
+            self.output.drain(..self.output.len() - OUTPUT_WINDOW);
+        }
+        Ok(true)
+    }
+
+    /// Wait for `needle` in the output, then discard output up to it.
+    async fn expect_output(&mut self, needle: &str, timeout: Duration) -> Result<()> {
+        let deadline = tokio::time::Instant::now() + timeout;
+        loop {
+            if let Some(pos) = find(&self.output, needle.as_bytes()) {
+                self.output.drain(..pos + needle.len());
+                return Ok(());
+            }
+            match tokio::time::timeout_at(deadline, self.pump()).await {
+                Ok(Ok(true)) => {}
+                Ok(Ok(false)) => bail!("stream finished while waiting for {needle}"),
+                Ok(Err(e)) => return Err(e),
+                Err(_) => bail!(
+                    "timed out waiting for {needle}; recent output: {}",
+                    String::from_utf8_lossy(&self.output[self.output.len().saturating_sub(512)..]),
+                ),
+            }
+        }
+    }
+
+    /// Close handshake: send Close and read until the daemon finishes the stream.
+    async fn detach(mut self) -> Result<()> {
+        let close = frame::encode_small(FrameType::Close, self.seq_in, &[]).expect("close frame");
+        self.send.write_all(&close).await?;
+        tokio::time::timeout(Duration::from_secs(30), async {
+            while self.pump().await? {}
+            Ok::<_, anyhow::Error>(())
+        })
+        .await
+        .context("daemon never finished the stream after Close")??;
+        Ok(())
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+/// Resident set size of this process, where the platform exposes it cheaply.
+fn rss_bytes() -> Option<u64> {
+    let statm = std::fs::read_to_string("/proc/self/statm").ok()?;
+    let pages: u64 = statm.split_whitespace().nth(1)?.parse().ok()?;
+    Some(pages * 4096)
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[ignore = "long-running soak; run explicitly with --ignored"]
+async fn soak_interactive_over_impaired_link() -> Result<()> {
+    rustls::crypto::ring::default_provider()
+        .install_default()
+        .ok();
+
+    let duration = Duration::from_secs(
+        std::env::var("PHANTOM_SOAK_SECS")
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(3600),
+    );
+    let link = LinkConditions {
+        latency: Duration::from_millis(20),
+        jitter: Duration::from_millis(30),
+        loss: 0.02,
+    };
+
+    let harness = TestHarness::new().await?;
+    let proxy = spawn_impaired_proxy(harness.server_addr(), link).await?;
+    let conn = harness.connect_and_auth_via(proxy).await?;
+
+    let (session_id, mut attachment) = Attachment::create(&conn).await?;
+    let expect_timeout = Duration::from_secs(30);
+
+    let start = tokio::time::Instant::now();
+    let mut baseline: Option<(u64, usize)> = None;
+    let mut round = 0u64;
+    while start.elapsed() < duration {
+        round += 1;
+
+        // Interactive command; '' keeps the typed echo from matching the marker
+        let marker = format!("SOAK_{round}_END");
+        attachment.type_line(&format!("echo SOAK_{round}_''END")).await?;
+        attachment.expect_output(&marker, expect_timeout).await?;
+
+        // Periodic bulk output
+        if round.is_multiple_of(20) {
+            attachment.type_line(&format!("seq 1 5000; echo BULK_{round}_''END")).await?;
+            attachment.expect_output(&format!("BULK_{round}_END"), expect_timeout).await?;
+        }
+
+        // Periodic detach/reattach: history must be replayed
+        if round.is_multiple_of(50) {
+            attachment.detach().await?;
+            let deadline = tokio::time::Instant::now() + expect_timeout;
+            while harness.session_manager().list_sessions().iter().any(|s| s.attached) {
+                assert!(tokio::time::Instant::now() < deadline, "session never detached");
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+
+            attachment = Attachment::attach(&conn, &session_id).await?;
+            let deadline = tokio::time::Instant::now() + expect_timeout;
+            while find(&attachment.scrollback, marker.as_bytes()).is_none() {
+                assert!(tokio::time::Instant::now() < deadline, "scrollback missing {marker}");
+                tokio::time::timeout_at(deadline, attachment.pump()).await.ok();
+            }
+        }
+
+        // Memory must stay flat once warmed up
+        if round.is_multiple_of(100) {
+            let budget = harness.session_manager().budget().used();
+            let rss = rss_bytes().unwrap_or(0);
+            match baseline {
+                None => baseline = Some((rss, budget)),
+                Some((base_rss, base_budget)) => {
+                    assert_eq!(budget, base_budget, "memory budget usage drifted");
+                    assert!(
+                        rss <= base_rss + RSS_GROWTH_LIMIT,
+                        "RSS grew from {base_rss} to {rss} bytes",
+                    );
+                }
+            }
+            eprintln!(
+                "[soak] round {round} at {:?}: rss {} KB, budget {budget} bytes",
+                start.elapsed(),
+                rss / 1024,
+            );
+        }
+    }
+
+    eprintln!("[soak] {round} rounds in {:?}", start.elapsed());
+    assert!(round > 0);
+
+    attachment.detach().await?;
+    harness.session_manager().destroy_session(&session_id)?;
+    conn.close(quinn::VarInt::from_u32(0), b"done");
+    Ok(())
+}
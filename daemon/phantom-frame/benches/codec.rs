@@ -0,0 +1,92 @@
+//! Frame codec hot path: every keystroke and output chunk goes through it.
+//! See README.md in this directory for the baseline workflow.
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use phantom_frame::{decode, encode, encode_small, Frame, FrameCompressor, FrameDecoder, FrameType};
+
+/// A keystroke, a prompt line, a screenful, a full `cat` chunk.
+const SIZES: [usize; 4] = [1, 128, 4096, 65536];
+
+/// Terminal-like output: repetitive enough to compress like the real thing.
+fn output(len: usize) -> Vec<u8> {
+    b"drwxr-xr-x  12 user  staff   384 Mar  3 09:14 \x1b[1;34msrc\x1b[0m\r\n"
+        .iter()
+        .copied()
+        .cycle()
+        .take(len)
+        .collect()
+}
+
+fn bench_encode(c: &mut Criterion) {
+    let mut group = c.benchmark_group("encode");
+    for size in SIZES {
+        let frame = Frame::data(1, output(size));
+        group.throughput(Throughput::Bytes(size as u64));
+        for compress in [false, true] {
+            let name = if compress { "compressed" } else { "plain" };
+            group.bench_with_input(BenchmarkId::new(name, size), &frame, |b, frame| {
+                b.iter(|| encode(black_box(frame), compress).unwrap())
+            });
+        }
+    }
+    group.finish();
+
+    c.bench_function("encode_small/keystroke", |b| {
+        b.iter(|| encode_small(FrameType::Data, black_box(7), black_box(b"a")).unwrap())
+    });
+
+    let mut group = c.benchmark_group("compressor");
+    for size in SIZES {
+        let payload = output(size);
+        let mut compressor = FrameCompressor::new().unwrap();
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(size), &payload, |b, payload| {
+            b.iter(|| compressor.compress(black_box(payload)).unwrap().map(<[u8]>::len))
+        });
+    }
+    group.finish();
+}
+
+fn bench_decode(c: &mut Criterion) {
+    let mut group = c.benchmark_group("decode");
+    for size in SIZES {
+        group.throughput(Throughput::Bytes(size as u64));
+        for compress in [false, true] {
+            let name = if compress { "compressed" } else { "plain" };
+            let wire = encode(&Frame::data(1, output(size)), compress).unwrap();
+            group.bench_with_input(BenchmarkId::new(name, size), &wire, |b, wire| {
+                b.iter(|| decode(black_box(wire)).unwrap().unwrap())
+            });
+        }
+    }
+    group.finish();
+}
+
+/// A burst of output frames arriving in network-sized pieces that split
+/// headers and payloads.
+fn bench_decoder(c: &mut Criterion) {
+    let wire: Vec<u8> = (0..64)
+        .flat_map(|seq| encode(&Frame::data(seq, output(1024 + seq as usize * 7)), true).unwrap())
+        .collect();
+    let mut group = c.benchmark_group("frame_decoder");
+    group.throughput(Throughput::Bytes(wire.len() as u64));
+    for chunk in [7, 1200, 16384] {
+        group.bench_with_input(BenchmarkId::new("chunk", chunk), &wire, |b, wire| {
+            b.iter(|| {
+                let mut decoder = FrameDecoder::new();
+                let mut frames = 0;
+                for piece in wire.chunks(chunk) {
+                    decoder.feed(piece);
+                    while decoder.decode_next().unwrap().is_some() {
+                        frames += 1;
+                    }
+                }
+                assert_eq!(frames, 64);
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_encode, bench_decode, bench_decoder);
+criterion_main!(benches);
@@ -12,34 +12,86 @@
 //!   0x02 = Resize (cols + rows)
 //!   0x03 = Heartbeat (keepalive)
 //!   0x04 = Close (session end)
-//!   0x05 = Scrollback (reattach replay)
+//!   0x05 = Scrollback (reattach replay, possibly over several frames; an
+//!                      empty frame ends it and live Data follows)
 //!   0x06 = WindowUpdate (flow control)
+//!   0x07 = Paste (client: pasted text, an empty frame ends the paste)
+//!   0x08 = PasteAck (server: bytes of the current paste written so far)
+//!   0x09 = Error (server: JSON `{"code", "error"}` about the stream, e.g.
+//!                 input dropped over a rate limit; the stream carries on)
+//!   0x0A = Hello (server: JSON of the wire the stream uses, first on it,
+//!                 for clients that negotiated one and decode Hello)
+//!   0x80-0xFF = extensions (see `register_extension`); passed through, not
+//!               rejected, so experimental types can cross older peers
 //!
 //! Flags:
 //!   bit 0 = compressed (zstd)
+//!   bit 1 = binary (Data payload isn't UTF-8; only set when the client
+//!           asked for codepoint-aligned Data frames)
+//!
+//! Connections are closed with a [`CloseCode`] as the QUIC application error
+//! code.
 
 pub const HEADER_SIZE: usize = 15;
 pub const MAX_PAYLOAD: usize = 65536;
 pub const MAX_FRAME: usize = HEADER_SIZE + MAX_PAYLOAD;
+/// Largest payload `encode_small` handles, keeping the frame within 64 bytes.
+pub const SMALL_PAYLOAD_MAX: usize = 64 - HEADER_SIZE;
 
 const COMPRESS_THRESHOLD: usize = 256;
-const FLAG_COMPRESSED: u16 = 0x0001;
+const COMPRESS_LEVEL: i32 = 3;
+/// Payloads whose sampled entropy is at least this many bits per byte look
+/// compressed already (gzip, PNG) and go uncompressed without a try. Terminal
+/// text is under 6, base64 about 6, which zstd still shrinks by a quarter.
+pub const DEFAULT_BYPASS_ENTROPY: f64 = 7.0;
+/// Bytes of a payload sampled for its entropy.
+const ENTROPY_SAMPLE: usize = 1024;
+pub const FLAG_COMPRESSED: u16 = 0x0001;
+pub const FLAG_BINARY: u16 = 0x0002;
+/// First frame type of the extension range.
+pub const EXTENSION_BASE: u8 = 0x80;
 
 // ── Frame types ──────────────────────────────────────────────────────────
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
-#[repr(u8)]
 pub enum FrameType {
-    Data = 0x01,
-    Resize = 0x02,
-    Heartbeat = 0x03,
-    Close = 0x04,
-    Scrollback = 0x05,
-    WindowUpdate = 0x06,
+    Data,
+    Resize,
+    Heartbeat,
+    Close,
+    Scrollback,
+    WindowUpdate,
+    Paste,
+    PasteAck,
+    Error,
+    Hello,
+    /// An extension type registered with `register_extension`.
+    Extension(u8),
+    /// An extension type nobody here registered, or (from a lenient
+    /// `FrameDecoder`) a core type newer than this build. The frame's
+    /// payload is kept as-is so it can be passed on or dropped, not fail
+    /// the stream.
+    Unknown(u8),
 }
 
 impl FrameType {
-    fn from_u8(v: u8) -> Result<Self, FrameError> {
+    /// The core types, in wire order.
+    pub const CORE: [FrameType; 10] = [
+        Self::Data,
+        Self::Resize,
+        Self::Heartbeat,
+        Self::Close,
+        Self::Scrollback,
+        Self::WindowUpdate,
+        Self::Paste,
+        Self::PasteAck,
+        Self::Error,
+        Self::Hello,
+    ];
+
+    /// The type for wire byte `v`. Extension-range bytes always succeed;
+    /// unassigned core bytes are `UnknownType`.
+    pub fn from_u8(v: u8) -> Result<Self, FrameError> {
         match v {
             0x01 => Ok(Self::Data),
             0x02 => Ok(Self::Resize),
@@ -47,9 +99,83 @@ impl FrameType {
             0x04 => Ok(Self::Close),
             0x05 => Ok(Self::Scrollback),
             0x06 => Ok(Self::WindowUpdate),
+            0x07 => Ok(Self::Paste),
+            0x08 => Ok(Self::PasteAck),
+            0x09 => Ok(Self::Error),
+            0x0A => Ok(Self::Hello),
+            EXTENSION_BASE..=u8::MAX if extension_name(v).is_some() => Ok(Self::Extension(v)),
+            EXTENSION_BASE..=u8::MAX => Ok(Self::Unknown(v)),
             _ => Err(FrameError::UnknownType(v)),
         }
     }
+
+    /// The wire byte.
+    pub fn to_u8(self) -> u8 {
+        match self {
+            Self::Data => 0x01,
+            Self::Resize => 0x02,
+            Self::Heartbeat => 0x03,
+            Self::Close => 0x04,
+            Self::Scrollback => 0x05,
+            Self::WindowUpdate => 0x06,
+            Self::Paste => 0x07,
+            Self::PasteAck => 0x08,
+            Self::Error => 0x09,
+            Self::Hello => 0x0A,
+            Self::Extension(v) | Self::Unknown(v) => v,
+        }
+    }
+
+    /// Name of the type: snake case for core types, the registered name for
+    /// extensions, `"unknown"` otherwise.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Data => "data",
+            Self::Resize => "resize",
+            Self::Heartbeat => "heartbeat",
+            Self::Close => "close",
+            Self::Scrollback => "scrollback",
+            Self::WindowUpdate => "window_update",
+            Self::Paste => "paste",
+            Self::PasteAck => "paste_ack",
+            Self::Error => "error",
+            Self::Hello => "hello",
+            Self::Extension(v) => extension_name(v).unwrap_or("unknown"),
+            Self::Unknown(_) => "unknown",
+        }
+    }
+
+    /// In the extension range, registered or not.
+    pub fn is_extension(self) -> bool {
+        matches!(self, Self::Extension(_) | Self::Unknown(_))
+    }
+}
+
+// ── Extension registry ───────────────────────────────────────────────────
+
+/// Names of registered extension types, indexed from `EXTENSION_BASE`.
+static EXTENSIONS: std::sync::RwLock<[Option<&'static str>; 128]> = std::sync::RwLock::new([None; 128]);
+
+/// Register extension frame type `code` (0x80-0xFF) under `name`, e.g.
+/// `register_extension(0x80, "file_transfer")`. Frames of that type then
+/// decode as `FrameType::Extension` instead of `FrameType::Unknown`.
+/// Registering the same name again is a no-op; another name is an error.
+pub fn register_extension(code: u8, name: &'static str) -> Result<FrameType, FrameError> {
+    let slot = code.checked_sub(EXTENSION_BASE).ok_or(FrameError::ReservedType(code))?;
+    let mut names = EXTENSIONS.write().unwrap_or_else(|e| e.into_inner());
+    match names[slot as usize] {
+        Some(existing) if existing != name => Err(FrameError::ExtensionTaken { code, name: existing }),
+        _ => {
+            names[slot as usize] = Some(name);
+            Ok(FrameType::Extension(code))
+        }
+    }
+}
+
+/// The name extension type `code` was registered under.
+pub fn extension_name(code: u8) -> Option<&'static str> {
+    let slot = code.checked_sub(EXTENSION_BASE)?;
+    EXTENSIONS.read().unwrap_or_else(|e| e.into_inner())[slot as usize]
 }
 
 // ── Errors ───────────────────────────────────────────────────────────────
@@ -58,6 +184,10 @@ impl FrameType {
 pub enum FrameError {
     #[error("unknown frame type: 0x{0:02x}")]
     UnknownType(u8),
+    #[error("frame type 0x{0:02x} is reserved for the core protocol")]
+    ReservedType(u8),
+    #[error("extension frame type 0x{code:02x} is already registered as {name}")]
+    ExtensionTaken { code: u8, name: &'static str },
     #[error("payload too large: {0} bytes (max {MAX_PAYLOAD})")]
     PayloadTooLarge(usize),
     #[error("incomplete header: need {HEADER_SIZE} bytes, got {0}")]
@@ -87,10 +217,7 @@ impl Frame {
     }
 
     pub fn resize(seq: u64, cols: u16, rows: u16) -> Self {
-        let mut payload = Vec::with_capacity(4);
-        payload.extend_from_slice(&cols.to_be_bytes());
-        payload.extend_from_slice(&rows.to_be_bytes());
-        Self { frame_type: FrameType::Resize, sequence: seq, payload }
+        Self { frame_type: FrameType::Resize, sequence: seq, payload: resize_payload(cols, rows).to_vec() }
     }
 
     pub fn heartbeat(seq: u64) -> Self {
@@ -101,10 +228,25 @@ impl Frame {
         Self { frame_type: FrameType::Close, sequence: seq, payload: Vec::new() }
     }
 
+    /// Close frame carrying a short UTF-8 reason (e.g. `client_close`).
+    pub fn close_with_reason(seq: u64, reason: &str) -> Self {
+        Self { frame_type: FrameType::Close, sequence: seq, payload: reason.as_bytes().to_vec() }
+    }
+
     pub fn scrollback(seq: u64, payload: Vec<u8>) -> Self {
         Self { frame_type: FrameType::Scrollback, sequence: seq, payload }
     }
 
+    /// Marks the end of a scrollback replay: what follows is live output.
+    pub fn replay_end(seq: u64) -> Self {
+        Self { frame_type: FrameType::Scrollback, sequence: seq, payload: Vec::new() }
+    }
+
+    /// Whether this is the marker ending a scrollback replay.
+    pub fn is_replay_end(&self) -> bool {
+        self.frame_type == FrameType::Scrollback && self.payload.is_empty()
+    }
+
     pub fn window_update(seq: u64, window: u64) -> Self {
         Self {
             frame_type: FrameType::WindowUpdate,
@@ -113,6 +255,26 @@ impl Frame {
         }
     }
 
+    /// A slice of pasted text; `payload` empty ends the paste.
+    pub fn paste(seq: u64, payload: Vec<u8>) -> Self {
+        Self { frame_type: FrameType::Paste, sequence: seq, payload }
+    }
+
+    /// Acknowledge `written` bytes of the current paste.
+    pub fn paste_ack(seq: u64, written: u64) -> Self {
+        Self { frame_type: FrameType::PasteAck, sequence: seq, payload: written.to_be_bytes().to_vec() }
+    }
+
+    /// An error report carrying `json`, e.g. `{"code":"RATE_LIMITED",...}`.
+    pub fn error(seq: u64, json: Vec<u8>) -> Self {
+        Self { frame_type: FrameType::Error, sequence: seq, payload: json }
+    }
+
+    /// The wire a stream uses, as `json` (`{"protocol_version", ...}`).
+    pub fn hello(seq: u64, json: Vec<u8>) -> Self {
+        Self { frame_type: FrameType::Hello, sequence: seq, payload: json }
+    }
+
     /// Parse resize payload into (cols, rows).
     pub fn parse_resize(&self) -> Option<(u16, u16)> {
         if self.frame_type != FrameType::Resize || self.payload.len() < 4 {
@@ -123,6 +285,22 @@ impl Frame {
         Some((cols, rows))
     }
 
+    /// Parse the reason of a Close frame. `None` for a bare close or a non-UTF-8 payload.
+    pub fn parse_close_reason(&self) -> Option<&str> {
+        if self.frame_type != FrameType::Close || self.payload.is_empty() {
+            return None;
+        }
+        std::str::from_utf8(&self.payload).ok()
+    }
+
+    /// Parse a paste acknowledgement into the bytes written so far.
+    pub fn parse_paste_ack(&self) -> Option<u64> {
+        if self.frame_type != FrameType::PasteAck {
+            return None;
+        }
+        Some(u64::from_be_bytes(self.payload.as_slice().try_into().ok()?))
+    }
+
     /// Parse window update payload into window size.
     pub fn parse_window_update(&self) -> Option<u64> {
         if self.frame_type != FrameType::WindowUpdate || self.payload.len() < 8 {
@@ -137,46 +315,251 @@ impl Frame {
 
 // ── Encoder ──────────────────────────────────────────────────────────────
 
-/// Encode a frame into a byte buffer, optionally compressing the payload.
-pub fn encode(frame: &Frame, compress: bool) -> Result<Vec<u8>, FrameError> {
+/// Compress a payload if requested and worthwhile. Returns None when the
+/// payload should go on the wire as-is.
+fn compress_payload(payload: &[u8], compress: bool) -> Result<Option<Vec<u8>>, FrameError> {
     // Try compression; use compressed data only if it's actually smaller
-    let compressed: Option<Vec<u8>> = if compress && frame.payload.len() > COMPRESS_THRESHOLD {
-        let c = zstd::bulk::compress(&frame.payload, 3)
+    if compress && payload.len() > COMPRESS_THRESHOLD && sampled_entropy(payload) < DEFAULT_BYPASS_ENTROPY {
+        let c = zstd::bulk::compress(payload, COMPRESS_LEVEL)
             .map_err(|e| FrameError::Compress(e.to_string()))?;
-        if c.len() < frame.payload.len() { Some(c) } else { None }
-    } else {
-        None
+        if c.len() < payload.len() {
+            return Ok(Some(c));
+        }
+    }
+    Ok(None)
+}
+
+/// Encode a frame header for an already-prepared wire payload of `payload_len`
+/// bytes (`flags` must describe that payload, e.g. `FLAG_COMPRESSED`).
+pub fn encode_header(
+    frame_type: FrameType,
+    sequence: u64,
+    flags: u16,
+    payload_len: usize,
+) -> Result<[u8; HEADER_SIZE], FrameError> {
+    if payload_len > MAX_PAYLOAD {
+        return Err(FrameError::PayloadTooLarge(payload_len));
+    }
+
+    let mut header = [0u8; HEADER_SIZE];
+    header[0] = frame_type.to_u8();
+    header[1..5].copy_from_slice(&(payload_len as u32).to_be_bytes());
+    header[5..13].copy_from_slice(&sequence.to_be_bytes());
+    header[13..15].copy_from_slice(&flags.to_be_bytes());
+    Ok(header)
+}
+
+/// Resize payload: cols then rows, big-endian.
+pub fn resize_payload(cols: u16, rows: u16) -> [u8; 4] {
+    let [c0, c1] = cols.to_be_bytes();
+    let [r0, r1] = rows.to_be_bytes();
+    [c0, c1, r0, r1]
+}
+
+/// Length of the UTF-8 codepoint cut off at the end of `data`: the bytes
+/// after its last lead byte, if that lead byte announces more. 0 when
+/// `data` ends on a codepoint boundary (or with bytes that aren't UTF-8).
+pub fn incomplete_utf8_tail(data: &[u8]) -> usize {
+    // A codepoint is at most 4 bytes, so only the last 3 can be unfinished
+    for back in 1..=data.len().min(3) {
+        let needed = match data[data.len() - back] {
+            0x80..=0xbf => continue,
+            0xc2..=0xdf => 2,
+            0xe0..=0xef => 3,
+            0xf0..=0xf4 => 4,
+            _ => return 0,
+        };
+        return if back < needed { back } else { 0 };
+    }
+    0
+}
+
+/// A frame encoded into a fixed inline buffer (see `encode_small`).
+#[derive(Clone, Copy)]
+pub struct SmallFrame {
+    buf: [u8; HEADER_SIZE + SMALL_PAYLOAD_MAX],
+    len: usize,
+}
+
+impl std::ops::Deref for SmallFrame {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+}
+
+impl AsRef<[u8]> for SmallFrame {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+/// Encode an uncompressed frame without touching the heap. Keystrokes,
+/// Heartbeat, Resize, WindowUpdate, and Close frames all fit; returns None
+/// when the payload exceeds `SMALL_PAYLOAD_MAX`.
+pub fn encode_small(frame_type: FrameType, sequence: u64, payload: &[u8]) -> Option<SmallFrame> {
+    if payload.len() > SMALL_PAYLOAD_MAX {
+        return None;
+    }
+    let header = encode_header(frame_type, sequence, 0, payload.len()).ok()?;
+    let mut frame = SmallFrame {
+        buf: [0u8; HEADER_SIZE + SMALL_PAYLOAD_MAX],
+        len: HEADER_SIZE + payload.len(),
     };
+    frame.buf[..HEADER_SIZE].copy_from_slice(&header);
+    frame.buf[HEADER_SIZE..frame.len].copy_from_slice(payload);
+    Some(frame)
+}
 
+/// Encode a frame into a byte buffer, optionally compressing the payload.
+pub fn encode(frame: &Frame, compress: bool) -> Result<Vec<u8>, FrameError> {
+    let compressed = compress_payload(&frame.payload, compress)?;
     let (payload_bytes, flags): (&[u8], u16) = match &compressed {
         Some(c) => (c.as_slice(), FLAG_COMPRESSED),
         None => (frame.payload.as_slice(), 0),
     };
 
-    if payload_bytes.len() > MAX_PAYLOAD {
-        return Err(FrameError::PayloadTooLarge(payload_bytes.len()));
-    }
+    let header = encode_header(frame.frame_type, frame.sequence, flags, payload_bytes.len())?;
 
     let mut buf = Vec::with_capacity(HEADER_SIZE + payload_bytes.len());
-    buf.push(frame.frame_type as u8);
-    buf.extend_from_slice(&(payload_bytes.len() as u32).to_be_bytes());
-    buf.extend_from_slice(&frame.sequence.to_be_bytes());
-    buf.extend_from_slice(&flags.to_be_bytes());
+    buf.extend_from_slice(&header);
     buf.extend_from_slice(payload_bytes);
 
     Ok(buf)
 }
 
+/// Encode a frame as a separate header and wire payload, for vectored writes.
+/// Takes the frame by value so an uncompressed payload is moved, not copied.
+pub fn encode_parts(frame: Frame, compress: bool) -> Result<([u8; HEADER_SIZE], Vec<u8>), FrameError> {
+    let (payload, flags) = match compress_payload(&frame.payload, compress)? {
+        Some(c) => (c, FLAG_COMPRESSED),
+        None => (frame.payload, 0),
+    };
+    let header = encode_header(frame.frame_type, frame.sequence, flags, payload.len())?;
+    Ok((header, payload))
+}
+
+/// Shannon entropy of `payload` in bits per byte, estimated from up to
+/// [`ENTROPY_SAMPLE`] bytes spread over it. Without allocating.
+pub fn sampled_entropy(payload: &[u8]) -> f64 {
+    if payload.is_empty() {
+        return 0.0;
+    }
+    let step = payload.len().div_ceil(ENTROPY_SAMPLE);
+    let mut counts = [0u32; 256];
+    let mut sampled = 0u32;
+    for &byte in payload.iter().step_by(step) {
+        counts[byte as usize] += 1;
+        sampled += 1;
+    }
+    let total = f64::from(sampled);
+    counts
+        .iter()
+        .filter(|&&count| count > 0)
+        .map(|&count| {
+            let p = f64::from(count) / total;
+            -p * p.log2()
+        })
+        .sum()
+}
+
+/// What became of the payloads given to a [`FrameCompressor`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CompressionStats {
+    /// Sent compressed
+    pub compressed: u64,
+    /// Not tried: they looked compressed already
+    pub bypassed: u64,
+    /// Tried, but compressing didn't make them smaller
+    pub incompressible: u64,
+    /// Size of the compressed payloads before compression
+    pub bytes_in: u64,
+    /// ... and after
+    pub bytes_out: u64,
+}
+
+/// Reusable compression context and output buffer for encoders that produce
+/// many frames, avoiding a zstd context and output allocation per frame.
+pub struct FrameCompressor {
+    ctx: zstd::bulk::Compressor<'static>,
+    out: Vec<u8>,
+    /// Entropy at which payloads go uncompressed untried; None tries all
+    bypass_entropy: Option<f64>,
+    stats: CompressionStats,
+}
+
+impl FrameCompressor {
+    pub fn new() -> Result<Self, FrameError> {
+        let ctx = zstd::bulk::Compressor::new(COMPRESS_LEVEL)
+            .map_err(|e| FrameError::Compress(e.to_string()))?;
+        Ok(Self {
+            ctx,
+            out: Vec::new(),
+            bypass_entropy: Some(DEFAULT_BYPASS_ENTROPY),
+            stats: CompressionStats::default(),
+        })
+    }
+
+    /// Skip compressing payloads with at least `bits` of entropy per byte
+    /// (see [`sampled_entropy`]), or, with None, try every payload.
+    pub fn with_bypass_entropy(mut self, bits: Option<f64>) -> Self {
+        self.bypass_entropy = bits;
+        self
+    }
+
+    /// Compress a payload using the same rules as `encode`, but with this
+    /// compressor's bypass entropy. Returns the compressed bytes (valid until
+    /// the next call), or None if the payload should go on the wire
+    /// uncompressed.
+    pub fn compress(&mut self, payload: &[u8]) -> Result<Option<&[u8]>, FrameError> {
+        if payload.len() <= COMPRESS_THRESHOLD {
+            return Ok(None);
+        }
+        if self.bypass_entropy.is_some_and(|bits| sampled_entropy(payload) >= bits) {
+            self.stats.bypassed += 1;
+            return Ok(None);
+        }
+        self.out.clear();
+        self.out.reserve(zstd::zstd_safe::compress_bound(payload.len()));
+        let n = self
+            .ctx
+            .compress_to_buffer(payload, &mut self.out)
+            .map_err(|e| FrameError::Compress(e.to_string()))?;
+        if n >= payload.len() {
+            self.stats.incompressible += 1;
+            return Ok(None);
+        }
+        self.stats.compressed += 1;
+        self.stats.bytes_in += payload.len() as u64;
+        self.stats.bytes_out += n as u64;
+        Ok(Some(&self.out[..n]))
+    }
+
+    /// What became of payloads since the last call.
+    pub fn take_stats(&mut self) -> CompressionStats {
+        std::mem::take(&mut self.stats)
+    }
+}
+
 // ── Decoder ──────────────────────────────────────────────────────────────
 
 /// Decode a frame from a byte slice. Returns the frame and the number of bytes consumed.
 /// Returns Ok(None) if the buffer doesn't contain a complete frame yet.
 pub fn decode(buf: &[u8]) -> Result<Option<(Frame, usize)>, FrameError> {
+    decode_with(buf, false)
+}
+
+/// `decode`, optionally taking unassigned core types as `FrameType::Unknown`.
+fn decode_with(buf: &[u8], lenient: bool) -> Result<Option<(Frame, usize)>, FrameError> {
     if buf.len() < HEADER_SIZE {
         return Ok(None);
     }
 
-    let frame_type = FrameType::from_u8(buf[0])?;
+    let frame_type = match FrameType::from_u8(buf[0]) {
+        Err(FrameError::UnknownType(v)) if lenient => FrameType::Unknown(v),
+        result => result?,
+    };
     let payload_len = u32::from_be_bytes([buf[1], buf[2], buf[3], buf[4]]) as usize;
     let sequence = u64::from_be_bytes([
         buf[5], buf[6], buf[7], buf[8], buf[9], buf[10], buf[11], buf[12],
@@ -215,11 +598,20 @@ pub struct FrameDecoder {
     buf: Vec<u8>,
     /// Read offset into buf — bytes before this have been consumed
     offset: usize,
+    /// Unassigned core types decode as `FrameType::Unknown`
+    lenient: bool,
 }
 
 impl FrameDecoder {
     pub fn new() -> Self {
-        Self { buf: Vec::with_capacity(MAX_FRAME), offset: 0 }
+        Self { buf: Vec::with_capacity(MAX_FRAME), offset: 0, lenient: false }
+    }
+
+    /// Decode frame types newer than this build as `FrameType::Unknown`,
+    /// using their declared length, instead of failing with `UnknownType`,
+    /// so a peer a protocol revision ahead doesn't end the stream.
+    pub fn lenient(self) -> Self {
+        Self { lenient: true, ..self }
     }
 
     /// Feed bytes into the decoder.
@@ -235,7 +627,7 @@ impl FrameDecoder {
     /// Try to decode the next complete frame.
     /// Returns None if more data is needed.
     pub fn decode_next(&mut self) -> Result<Option<Frame>, FrameError> {
-        match decode(&self.buf[self.offset..])? {
+        match decode_with(&self.buf[self.offset..], self.lenient)? {
             Some((frame, consumed)) => {
                 self.offset += consumed;
                 Ok(Some(frame))
@@ -243,6 +635,12 @@ impl FrameDecoder {
             None => Ok(None),
         }
     }
+
+    /// Bytes fed but not decoded yet, e.g. a control message that follows
+    /// the Close ending a detached bridge.
+    pub fn remaining(&self) -> &[u8] {
+        &self.buf[self.offset..]
+    }
 }
 
 impl Default for FrameDecoder {
@@ -253,8 +651,8 @@ impl Default for FrameDecoder {
 
 // ── Control messages (JSON) ──────────────────────────────────────────────
 
-/// Control stream wire format: [4B length BE][JSON payload]
-/// These go over QUIC stream 0, separate from per-session data streams.
+// Control stream wire format: [4B length BE][JSON payload]
+// These go over QUIC stream 0, separate from per-session data streams.
 
 pub mod control {
     /// Encode a JSON control message with length prefix.
@@ -280,12 +678,195 @@ pub mod control {
     }
 }
 
+// ── Stdio multiplexing ───────────────────────────────────────────────────
+
+// A connection's streams over one byte stream, such as `phantom stdio`'s
+// stdin/stdout: chunks of [4B stream ID BE][4B length BE][bytes].
+
+pub mod stdio {
+    use super::{CloseCode, FrameError, MAX_PAYLOAD};
+
+    pub const CHUNK_HEADER_SIZE: usize = 8;
+    /// Largest chunk payload; longer writes go in several chunks.
+    pub const MAX_CHUNK: usize = MAX_PAYLOAD;
+    /// Set in the IDs of streams the daemon opens (events, agent requests).
+    /// IDs below it are the client's: the first chunk on one opens a
+    /// bidirectional stream.
+    pub const DAEMON_STREAMS: u32 = 1 << 31;
+    /// Not a stream: the connection closed, with its code and reason.
+    pub const CONNECTION: u32 = u32::MAX;
+
+    /// Encode a chunk of `stream`. An empty one finishes the sender's side.
+    pub fn encode_chunk(stream: u32, bytes: &[u8]) -> Result<Vec<u8>, FrameError> {
+        if bytes.len() > MAX_CHUNK {
+            return Err(FrameError::PayloadTooLarge(bytes.len()));
+        }
+        let mut buf = Vec::with_capacity(CHUNK_HEADER_SIZE + bytes.len());
+        buf.extend_from_slice(&stream.to_be_bytes());
+        buf.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+        buf.extend_from_slice(bytes);
+        Ok(buf)
+    }
+
+    /// A decoded chunk, borrowing the buffer.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct Chunk<'a> {
+        pub stream: u32,
+        pub bytes: &'a [u8],
+    }
+
+    /// Try to decode a chunk from a buffer. Returns the chunk and total bytes
+    /// consumed, or None if incomplete.
+    pub fn decode_chunk(buf: &[u8]) -> Result<Option<(Chunk<'_>, usize)>, FrameError> {
+        if buf.len() < CHUNK_HEADER_SIZE {
+            return Ok(None);
+        }
+        let stream = u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]);
+        let len = u32::from_be_bytes([buf[4], buf[5], buf[6], buf[7]]) as usize;
+        if len > MAX_CHUNK {
+            return Err(FrameError::PayloadTooLarge(len));
+        }
+        let total = CHUNK_HEADER_SIZE + len;
+        if buf.len() < total {
+            return Ok(None);
+        }
+        Ok(Some((Chunk { stream, bytes: &buf[CHUNK_HEADER_SIZE..total] }, total)))
+    }
+
+    /// The [`CONNECTION`] chunk: [4B QUIC application error code BE][reason].
+    pub fn encode_close(code: u32, reason: &str) -> Vec<u8> {
+        let reason = &reason.as_bytes()[..reason.len().min(MAX_CHUNK - 4)];
+        let mut payload = code.to_be_bytes().to_vec();
+        payload.extend_from_slice(reason);
+        encode_chunk(CONNECTION, &payload).expect("close fits in a chunk")
+    }
+
+    /// Code and reason of a [`CONNECTION`] chunk's bytes; the code is `None`
+    /// if it isn't a [`CloseCode`].
+    pub fn parse_close(bytes: &[u8]) -> Option<(Option<CloseCode>, String)> {
+        let code = u32::from_be_bytes(bytes.get(..4)?.try_into().ok()?);
+        Some((CloseCode::from_code(code.into()), String::from_utf8_lossy(&bytes[4..]).into_owned()))
+    }
+}
+
+// ── Connection close codes ───────────────────────────────────────────────
+
+/// QUIC application error codes a connection is closed with, so a client can
+/// tell the user why it was disconnected. The reason phrase sent alongside is
+/// for logs; clients go by the code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloseCode {
+    /// Nothing wrong, e.g. the client is done
+    Normal,
+    /// The daemon is shutting down
+    Shutdown,
+    /// A newer connection from the same device took over
+    Replaced,
+    /// The device was revoked; it has to pair again
+    Revoked,
+    /// Authentication failed
+    AuthFailed,
+    /// Authentication didn't finish in time
+    AuthTimeout,
+    /// Too many connections or failed auths; retry later
+    RateLimited,
+    /// The negotiated protocol version is no longer supported
+    UnsupportedProtocol,
+    /// The daemon is being replaced by a new binary; reconnect
+    Upgrade,
+    /// The daemon was reset with a new identity; pair again
+    Reset,
+}
+
+impl CloseCode {
+    /// Every code, in wire order.
+    pub const ALL: [CloseCode; 10] = [
+        Self::Normal,
+        Self::Shutdown,
+        Self::Replaced,
+        Self::Revoked,
+        Self::AuthFailed,
+        Self::AuthTimeout,
+        Self::RateLimited,
+        Self::UnsupportedProtocol,
+        Self::Upgrade,
+        Self::Reset,
+    ];
+
+    /// The QUIC application error code.
+    pub fn code(self) -> u32 {
+        match self {
+            Self::Normal => 0,
+            Self::Shutdown => 1,
+            Self::Replaced => 2,
+            Self::Revoked => 3,
+            Self::AuthFailed => 4,
+            Self::AuthTimeout => 5,
+            Self::RateLimited => 6,
+            Self::UnsupportedProtocol => 7,
+            Self::Upgrade => 8,
+            Self::Reset => 9,
+        }
+    }
+
+    /// The close code for QUIC application error `code`, if it's one of ours.
+    pub fn from_code(code: u64) -> Option<Self> {
+        Self::ALL.into_iter().find(|c| u64::from(c.code()) == code)
+    }
+
+    /// Snake case name.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Normal => "normal",
+            Self::Shutdown => "shutdown",
+            Self::Replaced => "replaced",
+            Self::Revoked => "revoked",
+            Self::AuthFailed => "auth_failed",
+            Self::AuthTimeout => "auth_timeout",
+            Self::RateLimited => "rate_limited",
+            Self::UnsupportedProtocol => "unsupported_protocol",
+            Self::Upgrade => "upgrade",
+            Self::Reset => "reset",
+        }
+    }
+
+    /// What a client can tell the user.
+    pub fn description(self) -> &'static str {
+        match self {
+            Self::Normal => "Disconnected",
+            Self::Shutdown => "The host's daemon stopped",
+            Self::Replaced => "This device connected again elsewhere",
+            Self::Revoked => "This device's pairing was revoked",
+            Self::AuthFailed => "Authentication failed",
+            Self::AuthTimeout => "Authentication timed out",
+            Self::RateLimited => "Too many attempts, try again later",
+            Self::UnsupportedProtocol => "This app version is no longer supported, update it",
+            Self::Upgrade => "The host's daemon is restarting for an upgrade",
+            Self::Reset => "The host was reset, pair again",
+        }
+    }
+}
+
 // ── Tests ────────────────────────────────────────────────────────────────
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn finds_codepoints_cut_off_at_the_end() {
+        let text = "a€😀".as_bytes();
+        assert_eq!(incomplete_utf8_tail(text), 0);
+        for cut in 1..4 {
+            assert_eq!(incomplete_utf8_tail(&text[..text.len() - cut]), 4 - cut);
+        }
+        assert_eq!(incomplete_utf8_tail(&"a€".as_bytes()[..2]), 1);
+        assert_eq!(incomplete_utf8_tail(b""), 0);
+        // Stray continuation bytes and invalid bytes aren't a cut codepoint
+        assert_eq!(incomplete_utf8_tail(b"a\x80\x80\x80"), 0);
+        assert_eq!(incomplete_utf8_tail(b"a\xff"), 0);
+    }
+
     #[test]
     #[allow(unused_imports)]
     fn roundtrip_data_frame() {
@@ -333,6 +914,16 @@ mod tests {
         let encoded = encode(&frame, false).unwrap();
         let (decoded, _) = decode(&encoded).unwrap().unwrap();
         assert_eq!(decoded.frame_type, FrameType::Close);
+        assert_eq!(decoded.parse_close_reason(), None);
+    }
+
+    #[test]
+    fn roundtrip_close_with_reason() {
+        let frame = Frame::close_with_reason(101, "client_close");
+        let encoded = encode(&frame, false).unwrap();
+        let (decoded, _) = decode(&encoded).unwrap().unwrap();
+        assert_eq!(decoded.frame_type, FrameType::Close);
+        assert_eq!(decoded.parse_close_reason(), Some("client_close"));
     }
 
     #[test]
@@ -343,6 +934,23 @@ mod tests {
         assert_eq!(decoded.parse_window_update(), Some(262144));
     }
 
+    #[test]
+    fn roundtrip_paste() {
+        let (decoded, _) = decode(&encode(&Frame::paste(6, b"echo hi\n".to_vec()), false).unwrap()).unwrap().unwrap();
+        assert_eq!((decoded.frame_type, &decoded.payload[..]), (FrameType::Paste, &b"echo hi\n"[..]));
+        let (ack, _) = decode(&encode(&Frame::paste_ack(7, 8), false).unwrap()).unwrap().unwrap();
+        assert_eq!((ack.frame_type, ack.parse_paste_ack()), (FrameType::PasteAck, Some(8)));
+        assert_eq!(decoded.parse_paste_ack(), None);
+    }
+
+    #[test]
+    fn roundtrip_hello() {
+        let json = br#"{"protocol_version":1,"compression":null}"#.to_vec();
+        let (decoded, _) = decode(&encode(&Frame::hello(0, json.clone()), false).unwrap()).unwrap().unwrap();
+        assert_eq!((decoded.frame_type, decoded.payload), (FrameType::Hello, json));
+        assert_eq!(FrameType::Hello.name(), "hello");
+    }
+
     #[test]
     fn roundtrip_scrollback() {
         let frame = Frame::scrollback(10, b"terminal scrollback data".to_vec());
@@ -350,6 +958,9 @@ mod tests {
         let (decoded, _) = decode(&encoded).unwrap().unwrap();
         assert_eq!(decoded.frame_type, FrameType::Scrollback);
         assert_eq!(decoded.payload, b"terminal scrollback data");
+        assert!(!decoded.is_replay_end());
+        let (end, _) = decode(&encode(&Frame::replay_end(10), false).unwrap()).unwrap().unwrap();
+        assert!(end.is_replay_end());
     }
 
     #[test]
@@ -369,9 +980,54 @@ mod tests {
 
     #[test]
     fn decode_unknown_type() {
-        let buf = vec![0xFF; HEADER_SIZE];
+        let buf = vec![0x7F; HEADER_SIZE];
         let err = decode(&buf).unwrap_err();
-        assert!(matches!(err, FrameError::UnknownType(0xFF)));
+        assert!(matches!(err, FrameError::UnknownType(0x7F)));
+    }
+
+    #[test]
+    fn lenient_decoder_skips_unknown_core_types() {
+        let mut unknown = encode(&Frame::data(1, b"from the future".to_vec()), false).unwrap();
+        unknown[0] = 0x0B;
+        let mut wire = unknown.clone();
+        wire.extend(encode(&Frame::data(2, b"ls".to_vec()), false).unwrap());
+
+        let mut decoder = FrameDecoder::new();
+        decoder.feed(&wire);
+        assert!(matches!(decoder.decode_next(), Err(FrameError::UnknownType(0x0B))));
+
+        let mut decoder = FrameDecoder::new().lenient();
+        // Header first: the unknown frame still waits for its payload
+        decoder.feed(&wire[..HEADER_SIZE]);
+        assert!(decoder.decode_next().unwrap().is_none());
+        decoder.feed(&wire[HEADER_SIZE..]);
+        let skipped = decoder.decode_next().unwrap().unwrap();
+        assert_eq!((skipped.frame_type, skipped.payload.len()), (FrameType::Unknown(0x0B), 15));
+        assert_eq!(decoder.decode_next().unwrap().unwrap().payload, b"ls");
+    }
+
+    #[test]
+    fn extension_frames_pass_through() {
+        let frame = Frame { frame_type: FrameType::Unknown(0xF0), sequence: 4, payload: b"chunk".to_vec() };
+        let mut decoder = FrameDecoder::new();
+        decoder.feed(&encode(&frame, false).unwrap());
+        decoder.feed(&encode(&Frame::data(5, b"after".to_vec()), false).unwrap());
+        assert_eq!(decoder.decode_next().unwrap().unwrap(), frame);
+        assert_eq!(decoder.decode_next().unwrap().unwrap().payload, b"after");
+    }
+
+    #[test]
+    fn registers_extension_types() {
+        assert_eq!(register_extension(0xE1, "clipboard").unwrap(), FrameType::Extension(0xE1));
+        assert_eq!(register_extension(0xE1, "clipboard").unwrap(), FrameType::Extension(0xE1));
+        assert!(matches!(register_extension(0xE1, "files"), Err(FrameError::ExtensionTaken { name: "clipboard", .. })));
+        assert!(matches!(register_extension(0x09, "files"), Err(FrameError::ReservedType(0x09))));
+        assert_eq!(extension_name(0xE1), Some("clipboard"));
+
+        let encoded = encode(&Frame { frame_type: FrameType::Unknown(0xE1), sequence: 1, payload: vec![] }, false).unwrap();
+        let (decoded, _) = decode(&encoded).unwrap().unwrap();
+        assert_eq!(decoded.frame_type, FrameType::Extension(0xE1));
+        assert!(decoded.frame_type.is_extension());
     }
 
     #[test]
@@ -381,6 +1037,73 @@ mod tests {
         assert!(matches!(err, FrameError::PayloadTooLarge(_)));
     }
 
+    #[test]
+    fn encode_parts_matches_encode() {
+        for compress in [false, true] {
+            let frame = Frame::data(3, vec![b'Z'; 2048]);
+            let whole = encode(&frame, compress).unwrap();
+            let (header, payload) = encode_parts(frame, compress).unwrap();
+            assert_eq!(&whole[..HEADER_SIZE], &header);
+            assert_eq!(&whole[HEADER_SIZE..], payload.as_slice());
+        }
+    }
+
+    #[test]
+    fn frame_compressor_roundtrip() {
+        let mut compressor = FrameCompressor::new().unwrap();
+        assert!(compressor.compress(b"tiny").unwrap().is_none());
+        for _ in 0..3 {
+            let payload = vec![b'Q'; 4096];
+            let compressed = compressor.compress(&payload).unwrap().unwrap().to_vec();
+            let header = encode_header(FrameType::Data, 9, FLAG_COMPRESSED, compressed.len()).unwrap();
+            let mut wire = header.to_vec();
+            wire.extend_from_slice(&compressed);
+            let (decoded, _) = decode(&wire).unwrap().unwrap();
+            assert_eq!(decoded.payload, payload);
+        }
+    }
+
+    #[test]
+    fn compressed_looking_payloads_bypass_compression() {
+        // Deterministic noise, like gzip or PNG bytes
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        let noise: Vec<u8> = (0..4096)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect();
+        const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+        let base64: Vec<u8> = noise.iter().map(|&b| ALPHABET[(b & 63) as usize]).collect();
+        let text = b"drwxr-xr-x  5 user staff  160 Oct 16 09:12 src\n".repeat(64);
+        assert!(sampled_entropy(&noise) > DEFAULT_BYPASS_ENTROPY);
+        assert!(sampled_entropy(&base64) < DEFAULT_BYPASS_ENTROPY);
+        assert!(sampled_entropy(&text) < DEFAULT_BYPASS_ENTROPY);
+
+        let mut compressor = FrameCompressor::new().unwrap();
+        assert!(compressor.compress(&noise).unwrap().is_none());
+        assert!(compressor.compress(&base64).unwrap().is_some());
+        assert!(compressor.compress(&text).unwrap().is_some());
+        assert!(compressor.compress(b"tiny").unwrap().is_none());
+        let stats = compressor.take_stats();
+        assert_eq!((stats.compressed, stats.bypassed, stats.incompressible), (2, 1, 0));
+        assert!(stats.bytes_out < stats.bytes_in);
+        assert_eq!(compressor.take_stats(), CompressionStats::default());
+
+        // Without the bypass, the noise is tried, and found not to shrink
+        let mut compressor = FrameCompressor::new().unwrap().with_bypass_entropy(None);
+        assert!(compressor.compress(&noise).unwrap().is_none());
+        assert_eq!(compressor.take_stats().incompressible, 1);
+    }
+
+    #[test]
+    fn encode_parts_payload_too_large() {
+        let frame = Frame::data(1, vec![0; MAX_PAYLOAD + 1]);
+        assert!(matches!(encode_parts(frame, false), Err(FrameError::PayloadTooLarge(_))));
+    }
+
     #[test]
     fn streaming_decoder() {
         let f1 = Frame::data(1, b"first".to_vec());
@@ -425,6 +1148,25 @@ mod tests {
         assert!(control::decode_message(&encoded[..6]).is_none()); // partial payload
     }
 
+    #[test]
+    fn stdio_chunks_roundtrip() {
+        let mut wire = stdio::encode_chunk(3, b"{}").unwrap();
+        wire.extend_from_slice(&stdio::encode_chunk(3, b"").unwrap());
+        wire.extend_from_slice(&stdio::encode_close(CloseCode::Revoked.code(), "revoked"));
+        assert_eq!(stdio::decode_chunk(&wire[..9]).unwrap(), None);
+        let chunk = |stream, bytes| stdio::Chunk { stream, bytes };
+        assert_eq!(stdio::decode_chunk(&wire).unwrap(), Some((chunk(3, b"{}"), 10)));
+        assert_eq!(stdio::decode_chunk(&wire[10..]).unwrap(), Some((chunk(3, b""), 8)));
+        let (close, _) = stdio::decode_chunk(&wire[18..]).unwrap().unwrap();
+        assert_eq!(close.stream, stdio::CONNECTION);
+        assert_eq!(stdio::parse_close(close.bytes), Some((Some(CloseCode::Revoked), "revoked".to_string())));
+
+        assert!(stdio::encode_chunk(1, &vec![0; stdio::MAX_CHUNK + 1]).is_err());
+        let mut oversized = 1u32.to_be_bytes().to_vec();
+        oversized.extend_from_slice(&(stdio::MAX_CHUNK as u32 + 1).to_be_bytes());
+        assert!(stdio::decode_chunk(&oversized).is_err());
+    }
+
     #[test]
     fn compression_not_used_for_small_payloads() {
         let frame = Frame::data(1, b"tiny".to_vec());
@@ -447,6 +1189,16 @@ mod tests {
         assert_eq!(d2.payload, payload);
     }
 
+    #[test]
+    fn decoder_keeps_bytes_after_the_last_frame() {
+        let mut decoder = FrameDecoder::new();
+        let mut wire = encode(&Frame::close_with_reason(3, "detached"), false).unwrap();
+        wire.extend_from_slice(&control::encode_message(b"{}"));
+        decoder.feed(&wire);
+        assert_eq!(decoder.decode_next().unwrap().unwrap().parse_close_reason(), Some("detached"));
+        assert_eq!(control::decode_message(decoder.remaining()), Some((&b"{}"[..], 6)));
+    }
+
     #[test]
     fn throughput_encode_decode_4k() {
         let payload = vec![b'X'; 4096];
@@ -465,6 +1217,52 @@ mod tests {
         assert!(throughput_mb > 100.0, "encode+decode throughput too low: {throughput_mb:.1} MB/s");
     }
 
+    #[test]
+    fn encode_small_matches_encode() {
+        let frames = [
+            Frame::data(1, b"l".to_vec()),
+            Frame::data(2, b"\x1b[A".to_vec()),
+            Frame::data(3, vec![b'k'; SMALL_PAYLOAD_MAX]),
+            Frame::resize(4, 120, 40),
+            Frame::heartbeat(5),
+            Frame::window_update(6, 262144),
+            Frame::close_with_reason(7, "client_close"),
+        ];
+        for frame in frames {
+            let small = encode_small(frame.frame_type, frame.sequence, &frame.payload).unwrap();
+            assert_eq!(&small[..], encode(&frame, true).unwrap());
+        }
+        assert!(encode_small(FrameType::Data, 8, &[0; SMALL_PAYLOAD_MAX + 1]).is_none());
+    }
+
+    #[test]
+    fn throughput_keystroke_encode() {
+        use std::hint::black_box;
+
+        // Keystroke-heavy workload: single-byte Data frames
+        let iterations = 1_000_000u64;
+
+        let start = std::time::Instant::now();
+        let mut total = 0usize;
+        for i in 0..iterations {
+            let encoded = encode(&Frame::data(black_box(i), vec![b'a']), false).unwrap();
+            total += black_box(encoded).len();
+        }
+        let heap = start.elapsed();
+
+        let start = std::time::Instant::now();
+        let mut total_small = 0usize;
+        for i in 0..iterations {
+            let encoded = encode_small(FrameType::Data, black_box(i), black_box(b"a")).unwrap();
+            total_small += black_box(encoded).len();
+        }
+        let inline = start.elapsed();
+
+        assert_eq!(total, total_small);
+        let speedup = heap.as_secs_f64() / inline.as_secs_f64();
+        eprintln!("[bench] keystroke encode x {iterations}: Vec {heap:?}, inline {inline:?} ({speedup:.1}x)");
+    }
+
     #[test]
     fn throughput_streaming_decoder() {
         // Simulate sustained output: 1000 frames of 4KB fed through streaming decoder
@@ -507,6 +1305,12 @@ mod proptests {
             Just(FrameType::Close),
             Just(FrameType::Scrollback),
             Just(FrameType::WindowUpdate),
+            Just(FrameType::Paste),
+            Just(FrameType::PasteAck),
+            Just(FrameType::Error),
+            Just(FrameType::Hello),
+            // Below the types tests register
+            (EXTENSION_BASE..0xE0).prop_map(FrameType::Unknown),
         ]
     }
 
@@ -587,4 +1391,14 @@ mod proptests {
             prop_assert_eq!(decoded.sequence, seq);
         }
     }
+
+    #[test]
+    fn close_codes_roundtrip() {
+        for (i, code) in CloseCode::ALL.into_iter().enumerate() {
+            assert_eq!(code.code(), i as u32);
+            assert_eq!(CloseCode::from_code(code.code().into()), Some(code));
+        }
+        assert_eq!(CloseCode::from_code(10), None);
+        assert_eq!(CloseCode::Revoked.name(), "revoked");
+    }
 }
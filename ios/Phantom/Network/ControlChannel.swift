@@ -10,6 +10,8 @@ struct SessionInfo: Codable, Identifiable {
     let alive: Bool
     let createdAt: String
     let shell: String?
+    /// Given at creation, or derived by the daemon from the first command
+    let name: String?
     let attached: Bool
     let createdByDeviceId: String?
     let lastAttachedAt: String?
@@ -21,6 +23,7 @@ struct SessionInfo: Codable, Identifiable {
         case alive
         case createdAt = "created_at"
         case shell
+        case name
         case attached
         case createdByDeviceId = "created_by_device_id"
         case lastAttachedAt = "last_attached_at"
@@ -28,13 +31,14 @@ struct SessionInfo: Codable, Identifiable {
         case lastActivityAt = "last_activity_at"
     }
 
-    init(id: String, alive: Bool, createdAt: String, shell: String?, attached: Bool,
+    init(id: String, alive: Bool, createdAt: String, shell: String?, name: String? = nil, attached: Bool,
          createdByDeviceId: String? = nil, lastAttachedAt: String? = nil,
          lastAttachedBy: String? = nil, lastActivityAt: String? = nil) {
         self.id = id
         self.alive = alive
         self.createdAt = createdAt
         self.shell = shell
+        self.name = name
         self.attached = attached
         self.createdByDeviceId = createdByDeviceId
         self.lastAttachedAt = lastAttachedAt
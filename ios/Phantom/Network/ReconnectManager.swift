@@ -19,6 +19,8 @@ final class ReconnectManager: ObservableObject {
 
     private var client: QUICClient?
     private var controlStream: NWConnection?
+    /// From the last auth response; skips the challenge on the next reconnect
+    private var resumeToken: String?
     private var dataStream: NWConnection?
     private var connectTimeoutTask: Task<Void, Never>?
     private var pathMonitor: NWPathMonitor?
@@ -152,12 +154,17 @@ final class ReconnectManager: ObservableObject {
         let deviceId = deviceStore.deviceId
         let requestId = generateRequestId()
 
-        // Send auth_request with device_id
-        let request: [String: Any] = [
+        // Send auth_request with device_id, and the resume token if we hold one
+        // (single use: a stale one is answered with a challenge)
+        var request: [String: Any] = [
             "type": "auth_request",
             "request_id": requestId,
             "device_id": deviceId,
         ]
+        if let token = resumeToken {
+            request["resume_token"] = token
+            resumeToken = nil
+        }
 
         guard let json = try? JSONSerialization.data(withJSONObject: request) else { return }
 
@@ -277,30 +284,60 @@ final class ReconnectManager: ObservableObject {
 
     private func handleAuthResult(_ json: [String: Any]) {
         let success = json["success"] as? Bool ?? false
-        if success {
-            state = .connected
-            authError = nil
-            reconnectAttempt = 0
-            isBuffering = false
-            sessionsLoadedOnce = false
-            replayBufferedKeystrokes()
-            startPathMonitor()
-
-            // If we had an active session, reattach; otherwise refresh session list
-            if let sessionId = activeSessionId {
-                attachSession(sessionId)
-            } else {
-                listSessions()
+        resumeToken = json["resume_token"] as? String
+        if success, json["resumed"] as? Bool == true, let stream = controlStream {
+            // Resumed without a challenge: `resumable_sessions` follows
+            stream.receiveControlMessage { [weak self] result in
+                var sessionIds: [String] = []
+                if case .success(let data) = result,
+                   let message = try? JSONSerialization.jsonObject(with: data) as? [String: Any] {
+                    sessionIds = message["session_ids"] as? [String] ?? []
+                }
+                Task { @MainActor in
+                    self?.finishAuth(resumedSessionIds: sessionIds)
+                }
             }
+        } else if success {
+            finishAuth(resumedSessionIds: [])
         } else {
             let error = json["error"] as? String ?? "unknown"
             Logger.auth.error("Auth failed: \(error)")
-            authError = error
+            authError = Self.authFailureMessage(json) ?? error
             state = .disconnected
             // Don't auto-reconnect on auth failure — user must re-pair or fix the issue
         }
     }
 
+    /// What to tell the user about a failed auth, for failures with a code
+    /// worth spelling out.
+    static func authFailureMessage(_ json: [String: Any]) -> String? {
+        guard json["code"] as? String == "RATE_LIMITED" else { return nil }
+        guard let secs = json["retry_after_secs"] as? Int, secs > 0 else {
+            return "Too many attempts, try again later"
+        }
+        let wait = secs < 60 ? "\(secs)s" : "\((secs + 59) / 60)m"
+        return "Too many attempts, retry in \(wait)"
+    }
+
+    /// `resumedSessionIds`: sessions still attached through the lost
+    /// connection, reattached with takeover.
+    private func finishAuth(resumedSessionIds: [String]) {
+        state = .connected
+        authError = nil
+        reconnectAttempt = 0
+        isBuffering = false
+        sessionsLoadedOnce = false
+        replayBufferedKeystrokes()
+        startPathMonitor()
+
+        // If we had an active session, reattach; otherwise refresh session list
+        if let sessionId = activeSessionId {
+            attachSession(sessionId, takeover: resumedSessionIds.contains(sessionId))
+        } else {
+            listSessions()
+        }
+    }
+
     // MARK: - Pairing
 
     private func performPairing(host: String, port: UInt16, fingerprint: String, token: String, serverName: String) {
@@ -465,14 +502,19 @@ final class ReconnectManager: ObservableObject {
         }
     }
 
-    func attachSession(_ sessionId: String) {
+    /// `takeover` replaces this device's own bridge that hasn't noticed its
+    /// connection is gone yet.
+    func attachSession(_ sessionId: String, takeover: Bool = false) {
         guard let stream = controlStream else { return }
         let requestId = generateRequestId()
-        let request: [String: Any] = [
+        var request: [String: Any] = [
             "type": "attach_session",
             "request_id": requestId,
             "session_id": sessionId,
         ]
+        if takeover {
+            request["takeover"] = true
+        }
         guard let json = try? JSONSerialization.data(withJSONObject: request) else { return }
         stream.sendControlMessage(json) { [weak self] error in
             if let error = error {
@@ -633,7 +675,10 @@ final class ReconnectManager: ObservableObject {
                     bytesTracked += UInt64(frame.payload.count)
 
                 case .heartbeat:
-                    break // keepalive handled by QUIC
+                    // Keepalive is handled by QUIC; echo daemon pings so it can measure round trips
+                    if !frame.payload.isEmpty {
+                        sendHeartbeatEcho(frame.payload)
+                    }
 
                 case .close:
                     gotClose = true
@@ -685,6 +730,14 @@ final class ReconnectManager: ObservableObject {
         stream.send(content: encoded, completion: .contentProcessed { _ in })
     }
 
+    private func sendHeartbeatEcho(_ payload: Data) {
+        guard let stream = dataStream else { return }
+        let frame = Frame(type_: .heartbeat, sequence: seqOut, payload: payload)
+        seqOut += 1
+        guard let encoded = try? encodeFrame(frame) else { return }
+        stream.send(content: encoded, completion: .contentProcessed { _ in })
+    }
+
     private func replayBufferedKeystrokes() {
         let buffered = keystrokeBuffer
         keystrokeBuffer.removeAll()
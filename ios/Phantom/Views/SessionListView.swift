@@ -176,7 +176,7 @@ struct SessionsSheet: View {
                 )
 
             VStack(alignment: .leading, spacing: 2) {
-                Text(session.shell.map { ($0 as NSString).lastPathComponent } ?? "shell")
+                Text(session.name ?? session.shell.map { ($0 as NSString).lastPathComponent } ?? "shell")
                     .font(.system(.subheadline, design: .monospaced, weight: .medium))
                     .foregroundStyle(colors.textPrimary)
                 Text(sessionSubtitle(session))
@@ -47,6 +47,9 @@ struct SessionPill: View {
               let session = sessions.first(where: { $0.id == activeId }) else {
             return "No Session"
         }
+        if let name = session.name {
+            return name
+        }
         if let shell = session.shell {
             let name = (shell as NSString).lastPathComponent
             return name.isEmpty ? String(session.id.prefix(8)) : name